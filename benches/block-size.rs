@@ -0,0 +1,57 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{BatchSize, Criterion, Throughput};
+use ufs::{BenchFileAccess, BlockSize, BlockStorage, OpenFileMode, UberFileSystem};
+
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+const IO_SIZE: usize = 128 * 1024;
+
+/// Write the file, in `IO_SIZE` chunks, to a new path
+///
+fn write_big<B: BlockStorage + Sync>(ufs: &mut UberFileSystem<B>, data: &[u8]) {
+    let h = ufs.create_path("/big").unwrap();
+    for (n, chunk) in data.chunks(IO_SIZE).enumerate() {
+        ufs.write_file(h, chunk, (n * IO_SIZE) as u64).unwrap();
+    }
+    ufs.close_file(h).unwrap();
+}
+
+/// Write the file front to back, and then read it back the same way
+///
+fn criterion_benchmark(c: &mut Criterion) {
+    let data = (0..FILE_SIZE).map(|_| rand::random()).collect::<Vec<u8>>();
+
+    let mut group = c.benchmark_group("sequential");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    for size in &[BlockSize::TwentyFortyEight, BlockSize::OneMeg] {
+        // Room for the file, and then some for metadata.
+        let count = FILE_SIZE as u64 / *size as u64 + 64;
+        let memory_fs = || UberFileSystem::new_memory("test", "foobar", "bench", *size, count);
+
+        group.bench_function(format!("write {} blocks", size), |b| {
+            b.iter_batched(
+                memory_fs,
+                |mut ufs| write_big(&mut ufs, &data),
+                BatchSize::LargeInput,
+            )
+        });
+
+        let mut ufs = memory_fs();
+        write_big(&mut ufs, &data);
+        group.bench_function(format!("read {} blocks", size), |b| {
+            b.iter(|| {
+                let h = ufs.open_path("/big", OpenFileMode::Read).unwrap();
+                for offset in (0..FILE_SIZE).step_by(IO_SIZE) {
+                    ufs.read_file(h, offset as u64, IO_SIZE as u32).unwrap();
+                }
+                ufs.close_file(h).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, Throughput};
+use ufs::{BenchFileAccess, BlockSize, OpenFileMode, UberFileSystem, UfsMounter};
+
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+const READ_SIZE: u32 = 64 * 1024;
+const READERS: usize = 4;
+
+/// Read the file front to back, on a thread for each handle
+///
+fn readers<F: Fn(u64, u64) + Sync>(handles: &[u64], read: F) {
+    crossbeam::scope(|s| {
+        for h in handles {
+            let read = &read;
+            s.spawn(move |_| {
+                for offset in (0..FILE_SIZE as u64).step_by(READ_SIZE as usize) {
+                    read(*h, offset);
+                }
+            });
+        }
+    })
+    .unwrap();
+}
+
+/// Read a file from several threads at once
+///
+/// Reading under the write lock is how every read was done when the file system was behind a
+/// `Mutex`.
+fn criterion_benchmark(c: &mut Criterion) {
+    let ufs = UberFileSystem::new_memory(
+        "test",
+        "foobar",
+        "bench",
+        BlockSize::TwentyFortyEight,
+        2 * (FILE_SIZE / 2048) as u64,
+    );
+    let mut mounter = UfsMounter::new(ufs, None).unwrap();
+
+    let data = (0..FILE_SIZE).map(|_| rand::random()).collect::<Vec<u8>>();
+    let handles = {
+        let mut guard = mounter.write().unwrap();
+        let h = guard.create_path("/big").unwrap();
+        guard.write_file(h, &data, 0).unwrap();
+        guard.close_file(h).unwrap();
+        (0..READERS)
+            .map(|_| guard.open_path("/big", OpenFileMode::Read).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let mut group = c.benchmark_group("concurrent read");
+    group.throughput(Throughput::Bytes((READERS * FILE_SIZE) as u64));
+    group.sample_size(10);
+    group.bench_function("write lock", |b| {
+        b.iter(|| {
+            readers(&handles, |h, offset| {
+                mounter
+                    .write()
+                    .unwrap()
+                    .read_file(h, offset, READ_SIZE)
+                    .unwrap();
+            })
+        })
+    });
+    group.bench_function("read lock", |b| {
+        b.iter(|| {
+            readers(&handles, |h, offset| {
+                mounter.read_file(h, offset, READ_SIZE).unwrap();
+            })
+        })
+    });
+    group.finish();
+
+    for h in handles {
+        mounter.write().unwrap().close_file(h).unwrap();
+    }
+    mounter.shutdown().unwrap();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
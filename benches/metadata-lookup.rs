@@ -0,0 +1,73 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::Criterion;
+use ufs::{BenchFileAccess, BlockSize, OpenFileMode, UberFileSystem};
+
+const ENTRIES: usize = 10_000;
+const FANOUT: usize = 100;
+
+/// Open and close files in a directory of `ENTRIES` files
+///
+/// Opening a file looks it up by id, once its path has been resolved, which used to mean
+/// searching the directory tree.
+fn open_files(c: &mut Criterion) {
+    let mut ufs = UberFileSystem::new_memory(
+        "test",
+        "foobar",
+        "bench",
+        BlockSize::TwentyFortyEight,
+        4 * ENTRIES as u64,
+    );
+    for n in 0..ENTRIES {
+        let h = ufs.create_path(format!("/file{}", n)).unwrap();
+        ufs.close_file(h).unwrap();
+    }
+
+    let mut n = 0;
+    c.bench_function("open 10k entries", |b| {
+        b.iter(|| {
+            let h = ufs
+                .open_path(format!("/file{}", n), OpenFileMode::Read)
+                .unwrap();
+            ufs.close_file(h).unwrap();
+            n = (n + 7919) % ENTRIES;
+        })
+    });
+}
+
+/// Create files throughout a tree of `ENTRIES` directories
+///
+/// The directory that a file goes in is looked up by id, which without the index means searching
+/// the tree, in the worst case visiting every directory.
+fn create_in_directories(c: &mut Criterion) {
+    let mut ufs = UberFileSystem::new_memory(
+        "test",
+        "foobar",
+        "bench",
+        BlockSize::TwentyFortyEight,
+        4 * ENTRIES as u64,
+    );
+    for i in 0..FANOUT {
+        ufs.create_directory_path(format!("/d{}", i)).unwrap();
+        for j in 0..ENTRIES / FANOUT {
+            ufs.create_directory_path(format!("/d{}/e{}", i, j))
+                .unwrap();
+        }
+    }
+
+    let mut n = 0;
+    c.bench_function("create in 10k directories", |b| {
+        b.iter(|| {
+            let dir = (n * 7919) % ENTRIES;
+            let h = ufs
+                .create_path(format!("/d{}/e{}/f{}", dir / FANOUT, dir % FANOUT, n))
+                .unwrap();
+            ufs.close_file(h).unwrap();
+            n += 1;
+        })
+    });
+}
+
+criterion_group!(benches, open_files, create_in_directories);
+criterion_main!(benches);
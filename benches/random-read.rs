@@ -0,0 +1,49 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, Throughput};
+use ufs::{BenchFileAccess, BlockSize, OpenFileMode, UberFileSystem};
+
+const FILE_SIZE: usize = 100 * 1024 * 1024;
+const IO_SIZE: usize = 1024 * 1024;
+const READ_SIZE: u32 = 4096;
+const READS: usize = 1000;
+
+/// Read 4k pages from all over a 100MB file, the way a database does
+///
+/// With 2k blocks the file is 51,200 blocks long, so anything that walks the block list from the
+/// start of the file on each read shows up here.
+fn criterion_benchmark(c: &mut Criterion) {
+    let size = BlockSize::TwentyFortyEight;
+    // Room for the file, and then some for metadata.
+    let count = FILE_SIZE as u64 / size as u64 + 64;
+    let mut ufs = UberFileSystem::new_memory("test", "foobar", "bench", size, count);
+
+    let data = (0..IO_SIZE).map(|_| rand::random()).collect::<Vec<u8>>();
+    let h = ufs.create_path("/big").unwrap();
+    for offset in (0..FILE_SIZE).step_by(IO_SIZE) {
+        ufs.write_file(h, &data, offset as u64).unwrap();
+    }
+    ufs.close_file(h).unwrap();
+
+    let pages = (FILE_SIZE / READ_SIZE as usize) as u64;
+    let offsets = (0..READS)
+        .map(|_| rand::random::<u64>() % pages * READ_SIZE as u64)
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("random read");
+    group.throughput(Throughput::Bytes(READS as u64 * READ_SIZE as u64));
+    group.bench_function("4k pages of 100MB", |b| {
+        let h = ufs.open_path("/big", OpenFileMode::Read).unwrap();
+        b.iter(|| {
+            for offset in &offsets {
+                ufs.read_file(h, *offset, READ_SIZE).unwrap();
+            }
+        });
+        ufs.close_file(h).unwrap();
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Criterion, Throughput};
+use std::{env, fs};
+use ufs::{BenchFileAccess, BlockSize, FileStore, OpenFileMode, UberFileSystem, UfsMounter};
+
+// The file is twice the size of the block cache, so every pass reads it from storage.
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+const READ_SIZE: u32 = 4096;
+
+fn mount(read_ahead: usize) -> UfsMounter<FileStore> {
+    let path = env::temp_dir().join(format!("ufs-read-ahead-{}", read_ahead));
+    let _ = fs::remove_dir_all(&path);
+    let mut ufs = UberFileSystem::format_file_backed(
+        &path,
+        "master",
+        "bench",
+        "foobar",
+        BlockSize::TwentyFortyEight,
+        4096,
+    )
+    .unwrap();
+    ufs.set_read_ahead(read_ahead);
+
+    let data = (0..FILE_SIZE).map(|_| rand::random()).collect::<Vec<u8>>();
+    let h = ufs.create_path("/big").unwrap();
+    ufs.write_file(h, &data, 0).unwrap();
+    ufs.close_file(h).unwrap();
+
+    UfsMounter::new(ufs, None).unwrap()
+}
+
+/// Read the file the way `cat` does
+///
+/// The lock is taken for each read, as it is by the FUSE implementation, which gives the
+/// read-ahead thread a chance to get in between reads.
+fn cat(mounter: &UfsMounter<FileStore>) {
+    let h = mounter
+        .write()
+        .unwrap()
+        .open_path("/big", OpenFileMode::Read)
+        .unwrap();
+    for offset in (0..FILE_SIZE as u64).step_by(READ_SIZE as usize) {
+        mounter.read_file(h, offset, READ_SIZE).unwrap();
+    }
+    mounter.write().unwrap().close_file(h).unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential read");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    for read_ahead in &[0, 8] {
+        let mut mounter = mount(*read_ahead);
+        group.bench_function(format!("read-ahead {}", read_ahead), |b| {
+            b.iter(|| cat(&mounter))
+        });
+        mounter.shutdown().unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -15,7 +15,7 @@ use {
     dotenv::dotenv,
     futures::future,
     hyper::{
-        header::{HeaderValue, CONTENT_TYPE},
+        header::{HeaderValue, CONTENT_TYPE, RANGE},
         rt::{Future, Stream},
         service::service_fn,
         Body, Method, Request, Response, Server, StatusCode,
@@ -25,7 +25,7 @@ use {
     pretty_env_logger,
 };
 
-use ufs::{make_fs_key, BlockNumber, BlockReader, BlockWriter, FileStore, UfsUuid};
+use ufs::{parse_range, BlockNumber, BlockReader, BlockWriter, FileStore};
 
 // Just a simple type alias
 type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
@@ -52,9 +52,7 @@ impl BlockStores {
             rpassword::read_password_from_tty(Some(&format!("master password for {}: ", fs_name)))
                 .unwrap();
 
-        let key = make_fs_key(&password, &UfsUuid::new_root_fs(fs_name));
-
-        match FileStore::load(key, bundle_path.clone()) {
+        match FileStore::load(&password, bundle_path.clone()) {
             Ok(bs) => {
                 debug!("loaded file store {:?}", bundle_path);
                 Some(bs)
@@ -104,7 +102,7 @@ fn block_manager(req: Request<Body>, store_map: &Arc<RwLock<BlockStores>>) -> Bo
         // Read a block
         //
         // The path component specifies the file system UUID, and the sole query component the
-        // block number.
+        // block number. A `Range` header may be used to read part of the block.
         (&Method::GET, path, Some(query)) => {
             if let Some((bundle, store)) = store_map.write().unwrap().get_store(path) {
                 // FIXME:
@@ -112,7 +110,16 @@ fn block_manager(req: Request<Body>, store_map: &Arc<RwLock<BlockStores>>) -> Bo
                 // * Allow a range of blocks, e.g., 5-9
                 if let Ok(block) = query.parse::<BlockNumber>() {
                     debug!("Request to read {}:0x{:x?}", bundle, block);
-                    if let Ok(data) = store.read_block(block) {
+                    let range = req
+                        .headers()
+                        .get(RANGE)
+                        .and_then(|header| header.to_str().ok())
+                        .and_then(parse_range);
+                    let result = match range {
+                        Some((start, end)) => store.read_block_range(block, start, end - start + 1),
+                        None => store.read_block(block),
+                    };
+                    if let Ok(data) = result {
                         trace!("Read {} bytes", data.len());
 
                         response.headers_mut().insert(
@@ -122,8 +129,12 @@ fn block_manager(req: Request<Body>, store_map: &Arc<RwLock<BlockStores>>) -> Bo
                         // response
                         //     .headers_mut()
                         //     .insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+                        *response.status_mut() = match range {
+                            Some(_) if data.is_empty() => StatusCode::RANGE_NOT_SATISFIABLE,
+                            Some(_) => StatusCode::PARTIAL_CONTENT,
+                            None => StatusCode::OK,
+                        };
                         *response.body_mut() = Body::from(data);
-                        *response.status_mut() = StatusCode::OK;
                     } else {
                         error!("Problem reading block {}:0x{:x?}", bundle, block);
                     }
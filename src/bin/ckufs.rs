@@ -22,6 +22,12 @@ struct Opt {
     /// File system master password
     #[structopt(short = "p", long = "password")]
     password: Option<String>,
+    /// User with which to decrypt blocks; without one, only the header is checked
+    #[structopt(short = "u", long = "user")]
+    user: Option<String>,
+    /// Free blocks that belong to no file, and re-link files that are in no directory
+    #[structopt(long = "repair", requires = "user")]
+    repair: bool,
 }
 
 fn main() -> Result<(), failure::Error> {
@@ -36,5 +42,10 @@ fn main() -> Result<(), failure::Error> {
         rpassword::read_password_from_tty(Some("master password: ")).unwrap()
     };
 
-    FileStore::check(password, &opt.bundle_path, opt.show_map)
+    let user = opt.user.map(|user| {
+        let user_password = rpassword::read_password_from_tty(Some("user password: ")).unwrap();
+        (user, user_password)
+    });
+
+    FileStore::check(password, &opt.bundle_path, user, opt.show_map, opt.repair)
 }
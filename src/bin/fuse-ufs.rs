@@ -1,13 +1,16 @@
 use std::{
+    ffi::OsStr,
     fs,
     io::{self, Write},
+    path::PathBuf,
+    time::Duration,
 };
 
 use ::fuse::mount;
 use clap::{App, AppSettings, Arg};
 use pretty_env_logger;
 use reqwest::Url;
-use ufs::{UberFSFuse, UberFileSystem, UfsMounter};
+use ufs::{ServerTls, UberFSFuse, UberFileSystem, UfsMounter};
 
 fn main() -> Result<(), failure::Error> {
     let opts = App::new("fuse-ufs")
@@ -57,6 +60,47 @@ fn main() -> Result<(), failure::Error> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .value_name("CERT_FILE")
+                .help("PEM encoded TLS certificate for the remote server")
+                .requires("key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("KEY_FILE")
+                .help("PEM encoded TLS private key for the remote server")
+                .requires("cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("Mount the file system read-only, and don't run programs that write to it"),
+        )
+        .arg(
+            Arg::with_name("flush-interval")
+                .long("flush-interval")
+                .value_name("SECONDS")
+                .help("How often changed metadata is written in the background, 0 to never")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coalesce-window")
+                .long("coalesce-window")
+                .value_name("MILLISECONDS")
+                .help("Report rapid reads, or writes, of a file within this window as one event")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .long("insecure")
+                .help("Serve plain HTTP from the remote server, for local development")
+                .conflicts_with_all(&["cert", "key"]),
+        )
         .setting(AppSettings::ColoredHelp)
         .get_matches();
 
@@ -68,6 +112,41 @@ fn main() -> Result<(), failure::Error> {
         None
     };
 
+    // Without a certificate of its own, the server uses the one from the source tree.
+    let tls = if opts.is_present("insecure") {
+        ServerTls::Disabled
+    } else {
+        ServerTls::Pem {
+            cert: PathBuf::from(opts.value_of("cert").unwrap_or("src/certs/cert.pem")),
+            key: PathBuf::from(opts.value_of("key").unwrap_or("src/certs/key.rsa")),
+        }
+    };
+    let remote = port.map(|port| (port, tls));
+
+    let read_only = opts.is_present("read-only");
+    let flush_interval = match opts.value_of("flush-interval").map(str::parse::<u64>) {
+        Some(Ok(0)) => Some(None),
+        Some(Ok(seconds)) => Some(Some(Duration::from_secs(seconds))),
+        Some(Err(e)) => {
+            eprintln!("invalid flush interval: {}", e);
+            std::process::exit(-3);
+        }
+        None => None,
+    };
+    let coalesce_window = match opts.value_of("coalesce-window").map(str::parse::<u64>) {
+        Some(Ok(millis)) => Some(Duration::from_millis(millis)),
+        Some(Err(e)) => {
+            eprintln!("invalid coalesce window: {}", e);
+            std::process::exit(-4);
+        }
+        None => None,
+    };
+    let mount_options = if read_only {
+        vec![OsStr::new("-o"), OsStr::new("ro")]
+    } else {
+        vec![]
+    };
+
     match opts.value_of("bundle") {
         Some(path) => match fs::read_dir(&path) {
             Ok(_) => {
@@ -84,15 +163,22 @@ fn main() -> Result<(), failure::Error> {
                 let user = user.trim();
                 let password = rpassword::read_password_from_tty(Some("password: ")).unwrap();
 
-                let ufs = UberFileSystem::load_file_backed(
+                let mut ufs = UberFileSystem::load_file_backed(
                     master_password,
                     user.to_string(),
                     password,
                     &path,
                 )?;
-                let mounter = UfsMounter::new(ufs, port);
+                ufs.set_read_only(read_only);
+                if let Some(interval) = flush_interval {
+                    ufs.set_flush_interval(interval);
+                }
+                if let Some(window) = coalesce_window {
+                    ufs.set_coalesce_window(window);
+                }
+                let mounter = UfsMounter::new(ufs, remote)?;
                 let ufs_fuse = UberFSFuse::new(mounter);
-                mount(ufs_fuse, &opts.value_of("mnt").unwrap(), &[])?;
+                mount(ufs_fuse, &opts.value_of("mnt").unwrap(), &mount_options)?;
             }
             Err(e) => {
                 eprintln!("error reading bundle: {}", e);
@@ -111,15 +197,23 @@ fn main() -> Result<(), failure::Error> {
                     let password = rpassword::read_password_from_tty(Some("password: ")).unwrap();
 
                     let fs_name = url.path_segments().unwrap().last().unwrap();
-                    let ufs = UberFileSystem::new_networked(
+                    let mut ufs = UberFileSystem::new_networked(
                         user.to_string(),
                         password,
                         fs_name.to_string(),
                         url,
+                        None,
                     )?;
-                    let mounter = UfsMounter::new(ufs, port);
+                    ufs.set_read_only(read_only);
+                    if let Some(interval) = flush_interval {
+                        ufs.set_flush_interval(interval);
+                    }
+                    if let Some(window) = coalesce_window {
+                        ufs.set_coalesce_window(window);
+                    }
+                    let mounter = UfsMounter::new(ufs, remote)?;
                     let ufs_fuse = UberFSFuse::new(mounter);
-                    mount(ufs_fuse, &opts.value_of("mnt").unwrap(), &[])?;
+                    mount(ufs_fuse, &opts.value_of("mnt").unwrap(), &mount_options)?;
                 }
                 Err(e) => {
                     eprintln!("invalid URL: {}", e);
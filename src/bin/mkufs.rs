@@ -7,7 +7,7 @@ use log::debug;
 use pretty_env_logger;
 use structopt::StructOpt;
 
-use ufs::{BlockCardinality, BlockManager, BlockMap, BlockSize, FileStore, UfsUuid};
+use ufs::{BlockCardinality, BlockSize, Cipher, FormatOptions, UberFileSystem};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -19,7 +19,7 @@ struct Opt {
     /// File system bundle directory
     #[structopt(parse(from_os_str))]
     bundle_path: PathBuf,
-    /// Block size
+    /// Block size, in bytes: 512, 1024, 2048, 4096, 65536, or 1048576
     #[structopt(short = "s", long = "block-size", default_value = "2048")]
     block_size: BlockSize,
     /// Number of blocks
@@ -28,6 +28,19 @@ struct Opt {
     /// File system master password
     #[structopt(short = "p", long = "password")]
     password: Option<String>,
+    /// Compress file data
+    #[structopt(short = "z", long = "compress")]
+    compress: bool,
+    /// Store identical blocks of file data only once
+    #[structopt(short = "d", long = "dedup")]
+    dedup: bool,
+    /// Block cipher: xchacha20-hmac-sha256, or aes256ctr-hmac-sha256 for processors with AES
+    /// instructions
+    #[structopt(short = "e", long = "cipher", default_value = "xchacha20-hmac-sha256")]
+    cipher: Cipher,
+    /// Create block files as they are written, rather than all up front
+    #[structopt(long = "sparse")]
+    sparse: bool,
 }
 
 fn main() -> Result<(), failure::Error> {
@@ -59,15 +72,21 @@ fn main() -> Result<(), failure::Error> {
         panic!("Passwords do not match.")
     }
 
-    let map = BlockMap::new(
-        UfsUuid::new_root_fs(opt.bundle_path.file_name().unwrap().to_str().unwrap()),
+    match UberFileSystem::format_file_backed_with_options(
+        &opt.bundle_path,
+        master_password.as_str(),
+        user,
+        password.as_str(),
         opt.block_size,
         opt.block_count,
-    );
-
-    match FileStore::new(&master_password, &opt.bundle_path, map) {
-        Ok(store) => {
-            BlockManager::new(user, &password, store);
+        FormatOptions {
+            cipher: opt.cipher,
+            sparse: opt.sparse,
+            compress: opt.compress,
+        },
+    ) {
+        Ok(mut ufs) => {
+            ufs.set_block_dedup(opt.dedup);
             println!(
                 "Created new ufs file system with {} {} blocks at {:?}.",
                 opt.block_count, opt.block_size, opt.bundle_path
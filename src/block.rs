@@ -13,8 +13,11 @@
 //! determined like the block size: when the file system is created.
 //!
 //! FIXME: BlockLists should serialize when dropped.
+mod cache;
+mod compress;
 mod hash;
 
+pub(crate) mod chunk;
 pub(crate) mod manager;
 pub(crate) mod map;
 pub(crate) mod storage;
@@ -29,7 +32,18 @@ pub(crate) use {
     self::hash::BlockHash, self::storage::memory::MemoryStore, self::storage::network::NetworkStore,
 };
 
-pub use self::storage::{file::FileStore, BlockReader, BlockStorage, BlockWriter};
+#[cfg(feature = "s3")]
+pub use self::storage::s3::S3Store;
+
+pub use self::{
+    chunk::ChunkingMode,
+    storage::{
+        file::FileStore,
+        mirror::MirrorStore,
+        network::{parse_range, RetryPolicy},
+        BlockReader, BlockStorage, BlockWriter,
+    },
+};
 
 use self::map::BlockType;
 use crate::UfsUuid;
@@ -43,7 +57,7 @@ pub struct BlockAddress(UfsUuid, BlockNumber);
 /// The number of blocks in a file system.
 pub type BlockCardinality = u64;
 /// The size of a block, in bytes.
-pub type BlockSizeType = u16;
+pub type BlockSizeType = u32;
 
 /// Available Block Sizes
 ///
@@ -64,6 +78,34 @@ pub enum BlockSize {
     /// 2048 byte block size
     ///
     TwentyFortyEight = 2048,
+    /// 4096 byte block size
+    ///
+    FortyNinetySix = 4096,
+    /// 64 KiB block size
+    ///
+    /// Large files, e.g., media, need far fewer blocks, and much smaller block lists.
+    SixtyFourK = 65536,
+    /// 1 MiB block size
+    ///
+    OneMeg = 1_048_576,
+}
+
+impl BlockSize {
+    /// The smallest block size that holds `bytes`
+    ///
+    pub(crate) fn fitting(bytes: u64) -> Self {
+        [
+            BlockSize::FiveTwelve,
+            BlockSize::TenTwentyFour,
+            BlockSize::TwentyFortyEight,
+            BlockSize::FortyNinetySix,
+            BlockSize::SixtyFourK,
+        ]
+        .iter()
+        .cloned()
+        .find(|size| bytes <= *size as u64)
+        .unwrap_or(BlockSize::OneMeg)
+    }
 }
 
 impl fmt::Display for BlockSize {
@@ -72,6 +114,9 @@ impl fmt::Display for BlockSize {
             BlockSize::FiveTwelve => "512-byte".fmt(f),
             BlockSize::TenTwentyFour => "1k".fmt(f),
             BlockSize::TwentyFortyEight => "2k".fmt(f),
+            BlockSize::FortyNinetySix => "4k".fmt(f),
+            BlockSize::SixtyFourK => "64k".fmt(f),
+            BlockSize::OneMeg => "1M".fmt(f),
         }
     }
 }
@@ -109,6 +154,9 @@ impl From<u32> for BlockSize {
             512 => BlockSize::FiveTwelve,
             1024 => BlockSize::TenTwentyFour,
             2048 => BlockSize::TwentyFortyEight,
+            4096 => BlockSize::FortyNinetySix,
+            65536 => BlockSize::SixtyFourK,
+            1_048_576 => BlockSize::OneMeg,
             _ => panic!("Invalid Block Size"),
         }
     }
@@ -120,6 +168,9 @@ impl From<u64> for BlockSize {
             512 => BlockSize::FiveTwelve,
             1024 => BlockSize::TenTwentyFour,
             2048 => BlockSize::TwentyFortyEight,
+            4096 => BlockSize::FortyNinetySix,
+            65536 => BlockSize::SixtyFourK,
+            1_048_576 => BlockSize::OneMeg,
             _ => panic!("Invalid Block Size"),
         }
     }
@@ -131,6 +182,9 @@ impl From<BlockSize> for BlockSizeType {
             BlockSize::FiveTwelve => 512,
             BlockSize::TenTwentyFour => 1024,
             BlockSize::TwentyFortyEight => 2048,
+            BlockSize::FortyNinetySix => 4096,
+            BlockSize::SixtyFourK => 65536,
+            BlockSize::OneMeg => 1_048_576,
         }
     }
 }
@@ -141,6 +195,9 @@ impl From<BlockSize> for usize {
             BlockSize::FiveTwelve => 512,
             BlockSize::TenTwentyFour => 1024,
             BlockSize::TwentyFortyEight => 2048,
+            BlockSize::FortyNinetySix => 4096,
+            BlockSize::SixtyFourK => 65536,
+            BlockSize::OneMeg => 1_048_576,
         }
     }
 }
@@ -154,6 +211,9 @@ impl FromStr for BlockSize {
                 512 => Ok(BlockSize::FiveTwelve),
                 1024 => Ok(BlockSize::TenTwentyFour),
                 2048 => Ok(BlockSize::TwentyFortyEight),
+                4096 => Ok(BlockSize::FortyNinetySix),
+                65536 => Ok(BlockSize::SixtyFourK),
+                1_048_576 => Ok(BlockSize::OneMeg),
                 _ => Err(ParseBlockSizeError {
                     kind: BlockSizeErrorKind::InvalidBlockSize,
                 }),
@@ -171,6 +231,7 @@ impl FromStr for BlockSize {
 /// This is the record keeping associated with a physical block on some media. It does not contain
 /// any data. It contains the number of bytes in the block, the number of the block from the
 /// perspective of the media, the SHA-256 hash of the block's data, and the type of block.
+/// If the data was compressed, the compressed length is kept too.
 ///
 /// This is stored in the `BlockMap`.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -179,6 +240,7 @@ pub(crate) struct Block {
     number: BlockNumber,
     hash: Option<BlockHash>,
     block_type: BlockType,
+    compressed: Option<BlockSizeType>,
 }
 
 impl Block {
@@ -188,6 +250,7 @@ impl Block {
             number: number,
             hash: None,
             block_type: BlockType::new_free(),
+            compressed: None,
         }
     }
 
@@ -212,7 +275,6 @@ impl Block {
     }
 
     /// Check if a block is free
-    #[allow(dead_code)]
     pub(in crate::block) fn is_free(&self) -> bool {
         self.block_type.is_free()
     }
@@ -230,7 +292,6 @@ impl Block {
     }
 
     /// Check if a block contains metadata
-    #[allow(dead_code)]
     pub(in crate::block) fn is_metadata(&self) -> bool {
         self.block_type.is_metadata()
     }
@@ -259,16 +320,54 @@ impl Block {
         self.byte_count = size
     }
 
+    /// Return the number of bytes stored, if the data was compressed
+    ///
+    /// `None` means that the data was stored as is.
+    pub(crate) fn compressed_size(&self) -> Option<BlockSizeType> {
+        self.compressed
+    }
+
+    /// Set the number of compressed bytes in this block
+    ///
+    pub(in crate::block) fn set_compressed_size(&mut self, size: Option<BlockSizeType>) {
+        self.compressed = size
+    }
+
     /// Set the SHA-256 hash of this block
     ///
-    pub(in crate::block) fn set_hash(&mut self, hash: BlockHash) {
-        self.hash = Some(hash);
+    /// `None` records that the block was written without hashing.
+    pub(in crate::block) fn set_hash(&mut self, hash: Option<BlockHash>) {
+        self.hash = hash;
     }
 
     /// Return the SHA-256 hash of this block
     ///
-    #[allow(dead_code)]
+    /// `None` means that the block was never written, or that it was written without hashing.
     pub(in crate) fn hash(&self) -> Option<BlockHash> {
         self.hash
     }
 }
+
+/// A `Block`, as stored in format version 1
+///
+/// The byte count was a `u16`, which doesn't hold the larger block sizes, and data wasn't
+/// compressed.
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::block) struct BlockV1 {
+    byte_count: u16,
+    number: BlockNumber,
+    hash: Option<BlockHash>,
+    block_type: BlockType,
+}
+
+impl From<BlockV1> for Block {
+    fn from(block: BlockV1) -> Self {
+        Block {
+            byte_count: BlockSizeType::from(block.byte_count),
+            number: block.number,
+            hash: block.hash,
+            block_type: block.block_type,
+            compressed: None,
+        }
+    }
+}
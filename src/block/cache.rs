@@ -0,0 +1,245 @@
+//! Block Cache
+//!
+//! A cache of raw block contents that sits between the `BlockManager` and its `BlockStorage`. The
+//! bytes are stored as they come from storage, i.e., encrypted. Once the cache is full the least
+//! recently used block is evicted to make room.
+//!
+use {
+    crate::block::{BlockNumber, BlockSize},
+    std::{
+        collections::{HashMap, VecDeque},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex, MutexGuard,
+        },
+    },
+};
+
+/// The default number of blocks to cache
+///
+pub(crate) const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+/// The most memory that the default cache may use, in bytes
+///
+const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The default number of blocks to cache, given the block size
+///
+/// This is `DEFAULT_CACHE_BLOCKS`, unless the blocks are so large that they would take more than
+/// `DEFAULT_CACHE_BYTES`.
+pub(crate) fn default_capacity(size: BlockSize) -> usize {
+    DEFAULT_CACHE_BLOCKS.min(DEFAULT_CACHE_BYTES / usize::from(size))
+}
+
+/// The cached blocks, and the order in which they are evicted
+///
+/// Each block carries the stamp it was last used at. Using a block again pushes it onto the back
+/// of `order` with a fresh stamp, rather than digging out its old entry; entries whose stamp is
+/// out of date are skipped as they come off the front.
+#[derive(Debug, Default, PartialEq)]
+struct Cached {
+    blocks: HashMap<BlockNumber, (Vec<u8>, u64)>,
+    order: VecDeque<(BlockNumber, u64)>,
+    stamp: u64,
+}
+
+impl Cached {
+    /// Mark a cached block as the most recently used
+    ///
+    fn touch(&mut self, bn: BlockNumber) {
+        self.stamp += 1;
+        let stamp = self.stamp;
+        if let Some((_, used)) = self.blocks.get_mut(&bn) {
+            *used = stamp;
+            self.order.push_back((bn, stamp));
+        }
+    }
+
+    /// Drop the least recently used block
+    ///
+    /// Returns false if there was nothing to drop.
+    fn evict(&mut self) -> bool {
+        while let Some((bn, stamp)) = self.order.pop_front() {
+            if self.blocks.get(&bn).map(|(_, used)| *used) == Some(stamp) {
+                self.blocks.remove(&bn);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Drop the out of date entries from `order`, once they outnumber the blocks
+    ///
+    /// This keeps `order` to twice the number of blocks cached, at a cost that is spread over the
+    /// uses that made the entries.
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.blocks.len() + 1 {
+            let blocks = &self.blocks;
+            self.order
+                .retain(|(bn, stamp)| blocks.get(bn).map(|(_, used)| used) == Some(stamp));
+        }
+    }
+}
+
+/// A least-recently-used cache of block contents
+///
+/// Blocks are read through a shared reference, possibly from several threads at once, so the
+/// cache locks itself.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    capacity: usize,
+    cached: Mutex<Cached>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PartialEq for BlockCache {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+            || (self.capacity == other.capacity
+                && self.hits() == other.hits()
+                && self.misses() == other.misses()
+                && *self.cached() == *other.cached())
+    }
+}
+
+impl BlockCache {
+    /// Create a cache that holds up to `capacity` blocks
+    ///
+    /// A capacity of zero disables the cache.
+    pub(crate) fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            cached: Mutex::new(Cached::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn cached(&self) -> MutexGuard<'_, Cached> {
+        self.cached.lock().expect("poisoned cache lock")
+    }
+
+    /// Return a copy of a cached block's bytes
+    ///
+    /// Hits and misses are counted, see `hits` and `misses`.
+    pub(crate) fn get(&self, bn: BlockNumber) -> Option<Vec<u8>> {
+        let mut cached = self.cached();
+        match cached.blocks.get(&bn).map(|(bytes, _)| bytes.clone()) {
+            Some(bytes) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                cached.touch(bn);
+                cached.compact();
+                Some(bytes)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Check if a block is cached, without counting a miss
+    ///
+    pub(crate) fn contains(&self, bn: BlockNumber) -> bool {
+        self.cached().blocks.contains_key(&bn)
+    }
+
+    /// Add a block to the cache, evicting the least recently used block(s) if necessary
+    ///
+    pub(crate) fn insert(&self, bn: BlockNumber, bytes: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut cached = self.cached();
+        if cached.blocks.insert(bn, (bytes, 0)).is_none() {
+            while cached.blocks.len() > self.capacity && cached.evict() {}
+        }
+        cached.touch(bn);
+        cached.compact();
+    }
+
+    /// Drop a block from the cache
+    ///
+    /// This needs to happen whenever a block is rewritten, or freed.
+    pub(crate) fn remove(&self, bn: BlockNumber) {
+        let mut cached = self.cached();
+        if cached.blocks.remove(&bn).is_some() {
+            cached.compact();
+        }
+    }
+
+    /// The number of times `get` found a block
+    ///
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// The number of times `get` did not find a block
+    ///
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evict_oldest() {
+        let cache = BlockCache::new(2);
+
+        cache.insert(1, vec![1]);
+        cache.insert(2, vec![2]);
+        cache.insert(3, vec![3]);
+
+        assert!(!cache.contains(1));
+        assert_eq!(Some(vec![2]), cache.get(2));
+        assert_eq!(Some(vec![3]), cache.get(3));
+        assert_eq!(0, cache.misses());
+
+        cache.remove(2);
+        assert_eq!(None, cache.get(2));
+        assert_eq!(1, cache.misses());
+
+        // Removing 2 made room, so 3 stays put.
+        cache.insert(4, vec![4]);
+        assert!(cache.contains(3));
+        assert!(cache.contains(4));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = BlockCache::new(2);
+
+        cache.insert(1, vec![1]);
+        cache.insert(2, vec![2]);
+        assert_eq!(Some(vec![1]), cache.get(1));
+        cache.insert(3, vec![3]);
+
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+        assert_eq!(1, cache.hits());
+        assert_eq!(0, cache.misses());
+
+        // Reading a block over and over doesn't grow the eviction order without bound.
+        for _ in 0..100 {
+            cache.get(3);
+        }
+        assert!(cache.cached().order.len() <= 5);
+        cache.insert(4, vec![4]);
+        assert!(cache.contains(3));
+        assert!(!cache.contains(1));
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = BlockCache::new(0);
+
+        cache.insert(1, vec![1]);
+        assert_eq!(None, cache.get(1));
+    }
+}
@@ -0,0 +1,129 @@
+//! Content Defined Chunking
+//!
+//! Normally file data is chopped into block-sized pieces. That's fine, until a byte is inserted
+//! near the start of a file: every block after the insertion shifts, and nothing lines up with the
+//! previous version. When chunking is content defined, the boundaries are picked by a rolling hash
+//! over the data itself, so an edit only disturbs the chunks around it.
+//!
+//! We use a "gear" hash, as in FastCDC. Each byte shifts the hash left and adds a random value from
+//! a table. Since the hash is 64 bits wide, and we look at the high bits, a boundary decision
+//! depends only on the last 64 bytes seen.
+use {
+    lazy_static::lazy_static,
+    serde_derive::{Deserialize, Serialize},
+};
+
+lazy_static! {
+    static ref GEAR: [u64; 256] = {
+        // splitmix64, with a fixed seed so that boundaries are stable across runs.
+        let mut table = [0; 256];
+        let mut state: u64 = 0x7566_735f_6765_6172;
+        for entry in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// How file data is split into blocks
+///
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ChunkingMode {
+    /// Fill each block before moving on to the next
+    ///
+    Fixed,
+    /// Split data on boundaries determined by the content
+    ///
+    /// Chunks are variable length, but never larger than a block.
+    ContentDefined,
+}
+
+impl Default for ChunkingMode {
+    fn default() -> Self {
+        ChunkingMode::Fixed
+    }
+}
+
+/// Split `data` into content defined chunks, none of which is larger than `max`.
+///
+/// Chunks are at least a quarter of `max` (save the last one), and average about half of `max`.
+pub(crate) fn content_defined_chunks(data: &[u8], max: usize) -> Vec<&[u8]> {
+    let min = max / 4;
+    let bits = (max / 4).next_power_of_two().trailing_zeros();
+    let mask = if bits == 0 {
+        0
+    } else {
+        ((1u64 << bits) - 1) << (64 - bits)
+    };
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let remaining = &data[start..];
+        let mut end = remaining.len().min(max);
+        let mut hash: u64 = 0;
+        for (i, byte) in remaining.iter().enumerate().take(end) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if i + 1 >= min && hash & mask == 0 {
+                end = i + 1;
+                break;
+            }
+        }
+
+        chunks.push(&remaining[..end]);
+        start += end;
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        // xorshift -- we just need something that looks random, and is repeatable.
+        let mut x: u32 = 2_463_534_242;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_cover_data() {
+        let data = random_bytes(100_000);
+        let chunks = content_defined_chunks(&data, 2048);
+
+        assert_eq!(data, chunks.concat());
+        assert!(chunks.iter().all(|c| c.len() <= 2048));
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() >= 512));
+        assert!(content_defined_chunks(&[], 2048).is_empty());
+    }
+
+    #[test]
+    fn insert_shifts_few_chunks() {
+        let data = random_bytes(100_000);
+        let mut edited = b"a few extra bytes".to_vec();
+        edited.extend_from_slice(&data);
+
+        let before = content_defined_chunks(&data, 2048);
+        let after = content_defined_chunks(&edited, 2048);
+
+        let same = after.iter().filter(|c| before.contains(c)).count();
+        assert!(
+            same * 10 >= after.len() * 9,
+            "only {} of {} chunks unchanged",
+            same,
+            after.len()
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Block Compression
+//!
+//! When a volume is compressing blocks, each block of file data is run through zstd before it's
+//! encrypted -- once encrypted, there's nothing left to squeeze. Data that doesn't shrink, e.g.
+//! media that's already compressed, is stored as is. The `Block` in the `BlockMap` records the
+//! compressed length, if there is one, so that we know to decompress the block after it's
+//! decrypted.
+use {failure::format_err, log::trace};
+
+/// The zstd compression level
+///
+/// Blocks are small, and written often, so we favor speed.
+const LEVEL: i32 = 1;
+
+/// Compress a block of data
+///
+/// `None` is returned if the compressed data is no smaller than the original.
+pub(in crate::block) fn compress(bytes: &[u8]) -> Option<Vec<u8>> {
+    match zstd::block::compress(bytes, LEVEL) {
+        Ok(compressed) if compressed.len() < bytes.len() => {
+            trace!("compressed {} bytes to {}", bytes.len(), compressed.len());
+            Some(compressed)
+        }
+        _ => None,
+    }
+}
+
+/// Decompress a block of data
+///
+/// `len` is the length of the original data.
+pub(in crate::block) fn decompress(bytes: &[u8], len: usize) -> Result<Vec<u8>, failure::Error> {
+    let data = zstd::block::decompress(bytes, len)?;
+    if data.len() == len {
+        Ok(data)
+    } else {
+        Err(format_err!(
+            "decompressed {} bytes, but expected {}",
+            data.len(),
+            len
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"All work and no play makes Jack a dull boy. ".repeat(40);
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(data, decompress(&compressed, data.len()).unwrap());
+    }
+
+    #[test]
+    fn incompressible() {
+        assert_eq!(None, compress(&[0x42]));
+    }
+}
@@ -3,13 +3,13 @@ use std::fmt;
 use ring::digest;
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Deserialize, PartialEq, Serialize)]
+#[derive(Copy, Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub(crate) struct BlockHash {
     inner: [u8; 32],
 }
 
 impl BlockHash {
-    pub(in crate::block) fn new<T>(data: T) -> Self
+    pub(crate) fn new<T>(data: T) -> Self
     where
         T: AsRef<[u8]>,
     {
@@ -30,6 +30,35 @@ impl BlockHash {
     {
         self == &BlockHash::new(data.as_ref())
     }
+
+    /// Compute the Merkle root of a list of hashes
+    ///
+    /// Adjacent pairs of hashes are concatenated and hashed, a level at a time, until a single
+    /// hash remains. An odd hash at the end of a level is carried up unchanged. The root of an
+    /// empty list is the hash of nothing at all.
+    pub(crate) fn merkle_root(hashes: &[BlockHash]) -> Self {
+        if hashes.is_empty() {
+            return BlockHash::new(b"");
+        }
+
+        let mut level = hashes.to_vec();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut bytes = left.inner.to_vec();
+                        bytes.extend_from_slice(&right.inner);
+                        BlockHash::new(bytes)
+                    }
+                    [odd] => *odd,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level[0]
+    }
 }
 
 impl AsRef<[u8]> for BlockHash {
@@ -70,4 +99,21 @@ mod test {
         let hash = BlockHash::new(b"uberfoo");
         assert_eq!(true, hash.validate(b"uberfoo"));
     }
+
+    #[test]
+    fn merkle_root() {
+        let a = BlockHash::new(b"a");
+        let b = BlockHash::new(b"b");
+        let c = BlockHash::new(b"c");
+
+        assert_eq!(BlockHash::new(b""), BlockHash::merkle_root(&[]));
+        assert_eq!(a, BlockHash::merkle_root(&[a]));
+
+        let ab = BlockHash::new([a.as_ref(), b.as_ref()].concat());
+        assert_eq!(ab, BlockHash::merkle_root(&[a, b]));
+
+        let abc = BlockHash::new([ab.as_ref(), c.as_ref()].concat());
+        assert_eq!(abc, BlockHash::merkle_root(&[a, b, c]));
+        assert_ne!(abc, BlockHash::merkle_root(&[c, b, a]));
+    }
 }
@@ -5,20 +5,75 @@
 
 use {
     failure::format_err,
-    log::{debug, error},
+    log::{debug, error, warn},
+    std::{
+        collections::{HashMap, HashSet},
+        fmt,
+        time::{Duration, SystemTime},
+    },
 };
 
 use crate::{
     block::{
-        map::BlockMap,
+        cache::{self, BlockCache},
+        compress::{compress, decompress},
+        map::{BlockMap, SharedBlock, VolumeRoot},
         wrapper::{read_metadata, write_metadata},
-        Block, BlockCardinality, BlockHash, BlockNumber, BlockSize, BlockStorage,
+        Block, BlockCardinality, BlockHash, BlockNumber, BlockSize, BlockSizeType, BlockStorage,
+        ChunkingMode,
     },
-    crypto::{decrypt, encrypt, make_fs_key},
-    metadata::Metadata,
+    crypto::{sign, verify},
+    metadata::{DirectoryEntry, Metadata},
     uuid::UfsUuid,
+    IOFSErrorKind,
 };
 
+/// A problem found by a consistency check
+///
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum BlockProblem {
+    /// A file refers to a block that isn't in the block map
+    Missing(BlockNumber),
+    /// A file refers to a block that is on the free list
+    Free(BlockNumber),
+    /// A block can't be read, or it doesn't hold what the block map says that it does
+    Corrupt(BlockNumber, String),
+    /// More than one file refers to a block that isn't shared
+    DoubleReferenced(BlockNumber),
+    /// A data block that no file refers to
+    Orphaned(BlockNumber),
+    /// A file that no directory links to
+    Unlinked(UfsUuid),
+}
+
+impl fmt::Display for BlockProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockProblem::Missing(bn) => write!(f, "block 0x{:x?} is not in the block map", bn),
+            BlockProblem::Free(bn) => write!(f, "block 0x{:x?} is in use, but free", bn),
+            BlockProblem::Corrupt(bn, e) => write!(f, "block 0x{:x?} is corrupt: {}", bn, e),
+            BlockProblem::DoubleReferenced(bn) => {
+                write!(f, "block 0x{:x?} belongs to more than one file", bn)
+            }
+            BlockProblem::Orphaned(bn) => write!(f, "block 0x{:x?} belongs to no file", bn),
+            BlockProblem::Unlinked(id) => write!(f, "file {} is in no directory", id),
+        }
+    }
+}
+
+/// The findings of a consistency check
+///
+#[derive(Debug, Default)]
+pub(crate) struct CheckReport {
+    /// The number of blocks that were read
+    pub(crate) blocks_scanned: BlockCardinality,
+    /// Everything that was found to be wrong
+    pub(crate) problems: Vec<BlockProblem>,
+    /// The number of orphaned blocks that were returned to the free list, plus the number of
+    /// unlinked files that were put back in the directory tree
+    pub(crate) repairs: BlockCardinality,
+}
+
 /// Manager of Blocks
 ///
 /// This sits atop a `BlockStorage` and provides higher-level operations over blocks.  For example,
@@ -46,6 +101,12 @@ where
     user: UfsUuid,
     /// File system key for the current user
     key: [u8; 32],
+    /// Source of the current time, for block access times
+    clock: fn() -> SystemTime,
+    /// Recently read blocks
+    cache: BlockCache,
+    /// Nothing is written to the BlockStorage
+    read_only: bool,
 }
 
 impl<'a, BS> BlockManager<BS>
@@ -53,17 +114,27 @@ where
     BS: BlockStorage,
 {
     /// Layer metadata atop a block storage
+    ///
+    /// The user is added to the new file system, and data is encrypted with their key, just as it
+    /// will be once the file system is loaded again.
     pub fn new<S: AsRef<str>>(user: S, password: S, store: BS) -> Self {
         let user_id = UfsUuid::new_user(user.as_ref());
         let mut metadata = Metadata::new(*store.id(), user_id);
         metadata.add_user(user.as_ref().to_owned(), password.as_ref().to_owned());
+        let (_, key) = metadata
+            .get_user(user.as_ref(), password.as_ref())
+            .expect("the user was just added");
+        let capacity = cache::default_capacity(store.block_size());
 
         BlockManager {
             id: store.id().clone(),
             metadata,
             user: user_id,
-            key: make_fs_key(password.as_ref(), &store.id()),
+            key,
             store,
+            clock: SystemTime::now,
+            cache: BlockCache::new(capacity),
+            read_only: false,
         }
     }
 
@@ -73,22 +144,27 @@ where
     pub(crate) fn load<S: AsRef<str>>(
         user: S,
         password: S,
-        mut store: BS,
+        store: BS,
     ) -> Result<Self, failure::Error> {
         match store.map().root_block() {
             Some(root_block) => {
                 debug!("Reading root directory from block {}", root_block);
-                match read_metadata(&mut store, root_block) {
+                let block_size = store.block_size();
+                match read_metadata(&store, root_block, block_size) {
                     Ok(metadata) => {
                         debug!("loaded metadata");
                         let md: &Metadata = &metadata;
                         if let Some((user_id, key)) = md.get_user(&user, &password) {
+                            let capacity = cache::default_capacity(store.block_size());
                             Ok(BlockManager {
                                 id: store.id().clone(),
                                 metadata,
                                 user: user_id,
                                 key,
                                 store,
+                                clock: SystemTime::now,
+                                cache: BlockCache::new(capacity),
+                                read_only: false,
                             })
                         } else {
                             Err(format_err!("Invalid user id or password."))
@@ -115,6 +191,10 @@ where
         &self.store
     }
 
+    pub(crate) fn store_mut(&mut self) -> &mut BS {
+        &mut self.store
+    }
+
     pub(crate) fn map(&self) -> &BlockMap {
         &self.store.map()
     }
@@ -139,12 +219,39 @@ where
         self.store.map().get(number)
     }
 
+    /// Add Blocks to the BlockStorage
+    ///
+    /// The new blocks are free, and the block map is committed straight away.
+    pub(crate) fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+        self.store.grow(additional)?;
+        self.store.commit_map();
+        Ok(())
+    }
+
     /// The number of available, un-allocated Blocks.
     ///
     pub(crate) fn free_block_count(&self) -> BlockCardinality {
         self.store.map().free_blocks().len() as BlockCardinality
     }
 
+    /// The number of Blocks holding the metadata
+    ///
+    pub(crate) fn metadata_block_count(&self) -> BlockCardinality {
+        let map = self.store.map();
+        (0..map.block_count())
+            .filter(|bn| map.get(*bn).map_or(false, |b| b.is_metadata()))
+            .count() as BlockCardinality
+    }
+
+    /// The number of free Blocks that may be written with data
+    ///
+    /// A new copy of the metadata is written before the old one is freed, so room is left for it
+    /// out of the free blocks. The metadata is assumed to stay about the size that it is now.
+    pub(crate) fn available_block_count(&self) -> BlockCardinality {
+        self.free_block_count()
+            .saturating_sub(self.metadata_block_count())
+    }
+
     /// Request a Block
     ///
     /// The implementor maintains a pool of available blocks, and if there is one available, this
@@ -155,26 +262,448 @@ where
 
     /// Recycle a Block
     ///
-    /// The block is no longer being used, and may be returned to the free block pool.
+    /// The block is no longer being used, and may be returned to the free block pool. A shared
+    /// block is only freed once the last reference to it is recycled.
     pub(crate) fn recycle_block(&mut self, bn: BlockNumber) {
+        self.recycle_blocks(&[bn]);
+    }
+
+    /// Recycle a batch of Blocks
+    ///
+    /// This is the same as calling `recycle_block` for each block, but the block map is only
+    /// visited once, and the free list is extended in one go.
+    ///
+    /// A block is never freed twice: blocks that are already free are passed over, as are repeats
+    /// within the batch once the block has been freed.
+    pub(crate) fn recycle_blocks(&mut self, blocks: &[BlockNumber]) {
+        let map = self.store.map_mut();
+        let mut freed = HashSet::new();
+        let blocks = blocks
+            .iter()
+            .cloned()
+            .filter(|bn| {
+                if freed.contains(bn) || map.get(*bn).map_or(true, |block| block.is_free()) {
+                    warn!("not recycling block {}, it's already free", bn);
+                    false
+                } else {
+                    map.drop_reference(*bn) && freed.insert(*bn)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for bn in &blocks {
+            self.cache.remove(*bn);
+        }
+
+        let map = self.store.map_mut();
+        for bn in &blocks {
+            map.forget_access(*bn);
+            map.get_mut(*bn).unwrap().tag_free();
+        }
+        map.free_blocks_mut().extend(&blocks);
+
+        debug!("Freed {} blocks: {:x?}", blocks.len(), blocks);
+    }
+
+    /// Stop, or resume, writing to the BlockStorage
+    ///
+    /// While read-only, block writes fail, and the metadata and block map are left as they are in
+    /// storage, however much has changed in memory.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Enable, or disable, tracking of block access times
+    ///
+    /// This is off by default: the times are stored in the `BlockMap`, so keeping them means
+    /// writing the map more often.
+    pub(crate) fn set_access_tracking(&mut self, enable: bool) {
+        self.store.map_mut().set_access_tracking(enable);
+    }
+
+    /// Enable, or disable, hashing of data blocks as they are written
+    ///
+    /// This is on by default. The setting is stored in the `BlockMap`, and so belongs to the
+    /// volume. Blocks written while it's off have no hash, and are not validated when read.
+    pub(crate) fn set_block_hashing(&mut self, enable: bool) {
+        self.store.map_mut().set_block_hashing(enable);
+    }
+
+    /// Enable, or disable, deduplication of data blocks as they are written
+    ///
+    /// This is off by default. While it's on, a block with the same plaintext as one that's
+    /// already stored isn't written again; the existing block is shared instead. The index of
+    /// block contents is kept in the `BlockMap`, and only covers blocks written while it's on.
+    pub(crate) fn set_block_dedup(&mut self, enable: bool) {
+        self.store.map_mut().set_block_dedup(enable);
+    }
+
+    /// Set the manner in which file data is split into blocks
+    ///
+    /// The mode is stored in the `BlockMap`, so that a volume is always chunked the same way.
+    pub(crate) fn set_chunking_mode(&mut self, mode: ChunkingMode) {
+        self.store.map_mut().set_chunking_mode(mode);
+    }
+
+    /// Return the manner in which file data is split into blocks
+    ///
+    pub(crate) fn chunking_mode(&self) -> ChunkingMode {
+        self.store.map().chunking_mode()
+    }
+
+    /// Return true if more than one file refers to a block
+    ///
+    pub(crate) fn is_shared(&self, bn: BlockNumber) -> bool {
+        self.store
+            .map()
+            .shared(bn)
+            .map_or(false, |shared| shared.refs > 1)
+    }
+
+    /// Share a block with another file
+    ///
+    /// The block was encrypted with `nonce`, at `offset`, by the file version that wrote it. Those
+    /// are kept with the block, so that the other file is able to decrypt it. A block that's
+    /// already shared gains a reference, otherwise it starts out with two: one for the file that
+    /// has it, and one for the file that it's being shared with.
+    pub(crate) fn share_block(
+        &mut self,
+        bn: BlockNumber,
+        nonce: Vec<u8>,
+        offset: u64,
+    ) -> Result<(), failure::Error> {
+        if self.store.map().shared(bn).is_some() {
+            self.store.map_mut().add_reference(bn);
+            return Ok(());
+        }
+
+        let block = match self.store.map().get(bn) {
+            Some(block) if block.is_data() => block.clone(),
+            _ => return Err(format_err!("block 0x{:x?} isn't a data block", bn)),
+        };
+        let content = self.content_hash(&self.read(nonce.clone(), offset, &block)?);
+        self.store.map_mut().share(
+            bn,
+            SharedBlock {
+                content,
+                nonce,
+                offset,
+                refs: 2,
+            },
+        );
+        debug!("sharing block 0x{:x?}", bn);
+        Ok(())
+    }
+
+    /// The hash by which a shared block is found
+    ///
+    /// It's keyed with the file system key, so that the content index can't be used to tell
+    /// whether a file system holds some known data, nor matched against another file system's.
+    fn content_hash(&self, data: &[u8]) -> BlockHash {
+        BlockHash::from(sign(&self.key, data).as_slice())
+    }
+
+    /// Find cold blocks
+    ///
+    /// Return the data blocks that have not been read, or written, within `age`. Blocks for which
+    /// we have no record are considered cold. If access tracking is off, nothing is returned --
+    /// we simply don't know.
+    pub(crate) fn blocks_older_than(&self, age: Duration) -> Vec<BlockNumber> {
+        let map = self.store.map();
+        if !map.is_tracking_access() {
+            return vec![];
+        }
+
+        let cutoff = match (self.clock)().checked_sub(age) {
+            Some(cutoff) => cutoff,
+            None => return vec![],
+        };
+
+        (0..self.store.block_count())
+            .filter(|bn| map.get(*bn).map_or(false, |b| b.is_data()))
+            .filter(|bn| map.last_access(*bn).map_or(true, |t| t < cutoff))
+            .collect()
+    }
+
+    /// Read a block into the cache
+    ///
+    /// The block is fetched from storage, and its hash checked, so that a subsequent `read` need
+    /// not go to storage. Blocks that are already cached are left alone.
+    pub(crate) fn prefetch(&self, bn: BlockNumber) -> Result<(), failure::Error> {
+        if self.cache.contains(bn) {
+            return Ok(());
+        }
+
+        match self.store.map().get(bn) {
+            Some(block) if !block.is_free() => {
+                let bytes = self.store.read_block(bn)?;
+                if block.hash().map_or(true, |hash| hash.validate(&bytes)) {
+                    debug!("prefetched block 0x{:x?}", bn);
+                    self.cache.insert(bn, bytes);
+                    Ok(())
+                } else {
+                    Err(format_err!("hash mismatch prefetching block 0x{:x?}", bn))
+                }
+            }
+            _ => Err(format_err!("cannot prefetch null Block")),
+        }
+    }
+
+    /// Check a block in storage against its hash
+    ///
+    /// The block is read straight from storage, bypassing the cache, so that damage done to the
+    /// backing store since the block was cached is still caught. Blocks written without a hash
+    /// are taken on faith, and reported as sound. A block that storage won't return, e.g., because
+    /// it failed authentication, is reported as unsound.
+    pub(crate) fn verify_block(&self, bn: BlockNumber) -> Result<bool, failure::Error> {
+        match self.store.map().get(bn) {
+            Some(block) if !block.is_free() => match self.store.read_block(bn) {
+                Ok(bytes) => Ok(block.hash().map_or(true, |hash| hash.validate(&bytes))),
+                Err(e) => {
+                    warn!("unable to read block 0x{:x?}: {}", bn, e);
+                    Ok(false)
+                }
+            },
+            _ => Err(format_err!("cannot verify null Block")),
+        }
+    }
+
+    /// Read a block exactly as it's stored
+    ///
+    /// Nothing is decrypted, or checked against its hash. This is for serving blocks to remote
+    /// block storage.
+    pub(crate) fn read_raw_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error> {
+        if bn >= self.block_count() {
+            return Err(format_err!("no such block 0x{:x?}", bn));
+        }
+        self.store.read_block(bn)
+    }
+
+    /// Write a block exactly as it's given
+    ///
+    /// The data is expected to be encrypted already, and no larger than a block. Any cached copy
+    /// of the block is dropped. This is for remote block storage, which keeps its own block map.
+    /// Blocks that are in use by this file system are never overwritten, and the hash in our map
+    /// is updated to match what was written.
+    pub(crate) fn write_raw_block(
+        &mut self,
+        bn: BlockNumber,
+        data: &[u8],
+    ) -> Result<BlockSizeType, failure::Error> {
+        if self.read_only {
+            return Err(IOFSErrorKind::ReadOnly.into());
+        }
+        match self.store.map().get(bn) {
+            Some(block) if block.is_free() => (),
+            Some(_) => return Err(format_err!("block 0x{:x?} is in use", bn)),
+            None => return Err(format_err!("no such block 0x{:x?}", bn)),
+        }
+        if data.len() > BlockSizeType::from(self.block_size()) as usize {
+            return Err(format_err!(
+                "{} bytes won't fit in block 0x{:x?}",
+                data.len(),
+                bn
+            ));
+        }
+
+        self.cache.remove(bn);
+        let byte_count = self.store.write_block(bn, data)?;
+
         let block = self.store.map_mut().get_mut(bn).unwrap();
-        block.tag_free();
-        self.store.map_mut().free_blocks_mut().push_back(bn);
-        debug!("Freed block 0x{:x?}", bn);
+        block.set_size(byte_count);
+        block.set_compressed_size(None);
+        block.set_hash(Some(BlockHash::new(data)));
+        self.store.commit_map();
+
+        Ok(byte_count)
+    }
+
+    /// Set the number of blocks to cache
+    ///
+    /// The cache is emptied, and its counters reset. A capacity of zero disables the cache.
+    pub(crate) fn set_cache_capacity(&mut self, blocks: usize) {
+        self.cache = BlockCache::new(blocks);
+    }
+
+    /// The number of block reads that were served from the cache
+    ///
+    pub(crate) fn cache_hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    /// The number of block reads that had to go to storage
+    ///
+    pub(crate) fn cache_misses(&self) -> u64 {
+        self.cache.misses()
+    }
+
+    /// Store a volume root hash in the block map
+    ///
+    /// If `signed`, the hash is signed with the file system key. The block map is committed to
+    /// storage straight away.
+    pub(crate) fn set_volume_root(&mut self, hash: BlockHash, signed: bool) {
+        let signature = if signed {
+            Some(sign(&self.key, hash.as_ref()))
+        } else {
+            None
+        };
+
+        self.store
+            .map_mut()
+            .set_volume_root(VolumeRoot { hash, signature });
+        self.store.commit_map();
+    }
+
+    /// Check a volume root hash against the one stored in the block map
+    ///
+    /// The hashes must match, and if the stored hash was signed, the signature must be valid.
+    /// Returns `None` if there is no stored hash.
+    pub(crate) fn check_volume_root(&self, hash: &BlockHash) -> Option<bool> {
+        self.store.map().volume_root().map(|root| {
+            root.hash == *hash
+                && root
+                    .signature
+                    .as_ref()
+                    .map_or(true, |sig| verify(&self.key, root.hash.as_ref(), sig))
+        })
+    }
+
+    /// Check the blocks against the metadata
+    ///
+    /// Every block of every version of every file is read from storage, which checks it against
+    /// its hash, and then decrypted. The plaintext of a shared block is checked against the hash
+    /// of its content, and that of any other block against the size in the block map. Blocks that
+    /// belong to more than one file, but aren't shared, are reported, as are data blocks that
+    /// belong to no file, and files that no directory links to. If `repair`, the orphaned blocks
+    /// are returned to the free list, and the files are put back in the tree by
+    /// `Metadata::reconstruct_tree`.
+    pub(crate) fn check(&mut self, repair: bool) -> CheckReport {
+        let mut report = CheckReport::default();
+        let map = self.store.map();
+        let free = map.free_blocks().iter().cloned().collect::<HashSet<_>>();
+
+        let mut owners = HashMap::<BlockNumber, HashSet<UfsUuid>>::new();
+        for file in self.metadata.files() {
+            for version in file.get_versions().values() {
+                for (bn, block_offset) in version.blocks().iter().zip(version.offsets()) {
+                    let seen = owners.contains_key(bn);
+                    owners.entry(*bn).or_default().insert(file.id());
+
+                    let block = match map.get(*bn) {
+                        Some(block) => block,
+                        None => {
+                            if !seen {
+                                report.problems.push(BlockProblem::Missing(*bn));
+                            }
+                            continue;
+                        }
+                    };
+                    let size = block.byte_count;
+
+                    // Versions share blocks, so each is only read the first time it turns up.
+                    if seen {
+                        continue;
+                    }
+                    if free.contains(bn) {
+                        report.problems.push(BlockProblem::Free(*bn));
+                    }
+
+                    // Read from storage, not from the cache.
+                    report.blocks_scanned += 1;
+                    self.cache.remove(*bn);
+                    let bytes = match self.read(version.nonce(), *block_offset, block) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            report
+                                .problems
+                                .push(BlockProblem::Corrupt(*bn, e.to_string()));
+                            continue;
+                        }
+                    };
+                    let problem = match map.shared(*bn) {
+                        Some(shared) if self.content_hash(&bytes) != shared.content => {
+                            Some("content doesn't match its hash".to_string())
+                        }
+                        None if bytes.len() != size as usize => {
+                            Some(format!("expected {} bytes, but read {}", size, bytes.len()))
+                        }
+                        _ => None,
+                    };
+                    if let Some(problem) = problem {
+                        report.problems.push(BlockProblem::Corrupt(*bn, problem));
+                    }
+                }
+            }
+        }
+
+        for (bn, files) in &owners {
+            if files.len() > 1 && map.shared(*bn).is_none() {
+                report.problems.push(BlockProblem::DoubleReferenced(*bn));
+            }
+        }
+
+        let orphans = (0..map.block_count())
+            .filter(|bn| map.get(*bn).map_or(false, |b| b.is_data()))
+            .filter(|bn| !owners.contains_key(bn) && !free.contains(bn))
+            .collect::<Vec<_>>();
+        report
+            .problems
+            .extend(orphans.iter().map(|bn| BlockProblem::Orphaned(*bn)));
+
+        if repair && !orphans.is_empty() {
+            for bn in &orphans {
+                self.store.map_mut().unshare(*bn);
+            }
+            self.recycle_blocks(&orphans);
+            self.store.commit_map();
+            report.repairs = orphans.len() as BlockCardinality;
+        }
+
+        let unlinked = self.metadata.unlinked_files();
+        report.problems.extend(
+            unlinked
+                .iter()
+                .map(|file| BlockProblem::Unlinked(file.id())),
+        );
+
+        if repair && !unlinked.is_empty() {
+            let nodes = unlinked.into_iter().map(DirectoryEntry::File).collect();
+            match self.metadata.reconstruct_tree(nodes) {
+                Ok(count) => {
+                    report.repairs += count as BlockCardinality;
+                    self.serialize();
+                }
+                Err(e) => error!("unable to re-link files: {}", e),
+            }
+        }
+
+        report
     }
 
     /// Save the state of the BlockManager
     ///
-    /// This method stores the metadata in the [BlockStorage], starting at block 0.
+    /// This method stores the metadata in the [BlockStorage], starting at block 0. The blocks that
+    /// held the previous copy of the metadata are freed once the new copy is in place. Nothing is
+    /// stored while the BlockManager is read-only.
     ///
     /// FIXME: If this fails, then what?
     pub(crate) fn serialize(&mut self) {
+        if self.read_only {
+            debug!("not storing metadata on a read-only file system");
+            return;
+        }
         if self.metadata.is_dirty() {
+            let map = self.store.map();
+            let stale = (0..map.block_count())
+                .filter(|bn| map.get(*bn).map_or(false, |b| b.is_metadata()))
+                .collect::<Vec<_>>();
+
             match write_metadata(&mut self.store, &mut self.metadata) {
                 Ok(block) => {
                     self.store.map_mut().set_root_block(block);
                     self.store.commit_map();
                     debug!("Stored new root block {}", block);
+                    self.recycle_blocks(&stale);
                 }
                 Err(e) => {
                     error!("error writing metadata: {}", e);
@@ -184,35 +713,101 @@ where
         }
     }
 
+    /// Write everything out to the BlockStorage
+    ///
+    /// Dirty metadata is serialized, which also commits the block map. Otherwise the block map is
+    /// committed on its own, as blocks may have been written without the metadata changing.
+    pub(crate) fn sync(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if self.metadata.is_dirty() {
+            self.serialize();
+        } else {
+            self.store.commit_map();
+        }
+    }
+
     /// Write a slice to a Block Storage
     ///
     /// This function will write up to `self.store.block_size()` bytes from the given slice to a
     /// free block.  A new [Block] is returned.
+    ///
+    /// When deduplicating, a block that holds the same bytes is returned instead, if there is one,
+    /// and the caller is given a reference to it. It's the caller's to recycle, as with a new
+    /// block.
     pub(crate) fn write<T: AsRef<[u8]>>(
         &mut self,
         nonce: Vec<u8>,
         offset: u64,
         data: T,
     ) -> Result<&Block, failure::Error> {
+        if self.read_only {
+            return Err(IOFSErrorKind::ReadOnly.into());
+        }
         let data = data.as_ref();
+        let end = data.len().min(self.store.block_size() as usize);
+
+        let content = if self.store.map().is_deduplicating_blocks() {
+            Some(self.content_hash(&data[..end]))
+        } else {
+            None
+        };
+        if let Some(number) = content.and_then(|c| self.store.map().find_content(&c)) {
+            debug!("sharing block 0x{:x?}", number);
+            self.store.map_mut().add_reference(number);
+            self.store.map().touch(number, (self.clock)());
+            return Ok(self.store.map().get(number).unwrap());
+        }
+
         if let Some(number) = self.get_free_block() {
-            let end = data.len().min(self.store.block_size() as usize);
-            let mut bytes = data[..end].to_vec();
-            encrypt(&self.key, &nonce, offset, &mut bytes);
+            let compressed = if self.store.map().is_compressing_blocks() {
+                compress(&data[..end])
+            } else {
+                None
+            };
+            let is_compressed = compressed.is_some();
+            let mut bytes = compressed.unwrap_or_else(|| data[..end].to_vec());
+            self.crypt(&nonce, offset, &mut bytes);
 
+            self.cache.remove(number);
             let byte_count = self.store.write_block(number, &bytes)?;
             debug!("wrote block 0x{:x?}", number);
+            self.store.map().touch(number, (self.clock)());
+
+            let hash = if self.store.map().is_hashing_blocks() {
+                Some(BlockHash::new(bytes))
+            } else {
+                None
+            };
 
             let block = self.store.map_mut().get_mut(number).unwrap();
-            block.set_size(byte_count);
-            block.set_hash(BlockHash::new(bytes));
+            if is_compressed {
+                block.set_size(end as BlockSizeType);
+                block.set_compressed_size(Some(byte_count));
+            } else {
+                block.set_size(byte_count);
+                block.set_compressed_size(None);
+            }
+            block.set_hash(hash);
             block.tag_data();
 
-            Ok(block)
+            if let Some(content) = content {
+                self.store.map_mut().share(
+                    number,
+                    SharedBlock {
+                        content,
+                        nonce,
+                        offset,
+                        refs: 1,
+                    },
+                );
+            }
+
+            Ok(self.store.map().get(number).unwrap())
         } else {
-            Err(format_err!(
-                "I was unable to complete the write operation.  I could not find a free block!"
-            ))
+            error!("unable to write block: no free blocks");
+            Err(IOFSErrorKind::NoSpace.into())
         }
     }
 
@@ -229,29 +824,96 @@ where
         offset: u64,
         block: &Block,
     ) -> Result<Vec<u8>, failure::Error> {
-        if let Block {
-            number: block_number,
-            hash: Some(block_hash),
-            byte_count: _,
-            block_type: _,
-        } = block
-        {
-            let mut bytes = self.store.read_block(*block_number)?;
+        if block.is_free() {
+            return Err(format_err!("cannot read null Block"));
+        }
 
+        let block_number = block.number();
+        let mut bytes = match self.cache.get(block_number) {
+            Some(bytes) => bytes,
+            None => self.store.read_block(block_number)?,
+        };
+
+        // Blocks written without a hash are taken on faith.
+        if let Some(block_hash) = block.hash() {
             let hash = BlockHash::new(&bytes);
-            if hash == *block_hash {
-                debug!("read block 0x{:x?}", *block_number);
-                decrypt(&self.key, &nonce, offset, &mut bytes);
-                Ok(bytes)
-            } else {
-                Err(format_err!(
-                    "hash mismatch: expected {:?}, but calculated {:?}",
-                    block.hash,
+            if hash != block_hash {
+                return Err(format_err!(
+                    "hash mismatch reading block 0x{:x?}: expected {:?}, but calculated {:?}",
+                    block_number,
+                    block_hash,
                     hash
-                ))
+                ));
             }
-        } else {
-            Err(format_err!("cannot read null Block"))
+        }
+
+        debug!("read block 0x{:x?}", block_number);
+        self.store.map().touch(block_number, (self.clock)());
+        self.cache.insert(block_number, bytes.clone());
+        let (nonce, offset) = self.sealed_with(block_number, nonce, offset);
+        self.crypt(&nonce, offset, &mut bytes);
+        match block.compressed_size() {
+            Some(_) => decompress(&bytes, block.size() as usize),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Read part of a Block
+    ///
+    /// Only `len` bytes, starting at `start` within the block, are fetched from storage and
+    /// decrypted. `offset` is the position of the block within the file, as with `read`. The hash
+    /// can't be checked without all of the block's data, so blocks with a hash are read in full,
+    /// as are compressed and cached blocks.
+    pub(crate) fn read_range(
+        &self,
+        nonce: Vec<u8>,
+        offset: u64,
+        block: &Block,
+        start: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        if block.is_free() {
+            return Err(format_err!("cannot read null Block"));
+        }
+
+        // A hash covers the whole block, and there's no starting in the middle of compressed data.
+        if block.hash().is_some() || block.compressed.is_some() || self.cache.contains(block.number)
+        {
+            let bytes = self.read(nonce, offset, block)?;
+            let end = (start + len).min(bytes.len());
+            return Ok(bytes[start.min(end)..end].to_vec());
+        }
+
+        let mut bytes = self.store.read_block_range(block.number, start, len)?;
+        debug!(
+            "read 0x{:x} bytes at 0x{:x} from block 0x{:x?}",
+            bytes.len(),
+            start,
+            block.number
+        );
+        self.store.map().touch(block.number, (self.clock)());
+        let (nonce, offset) = self.sealed_with(block.number, nonce, offset);
+        self.crypt(&nonce, offset + start as u64, &mut bytes);
+        Ok(bytes)
+    }
+
+    /// Encrypt, or decrypt, block data with the store's cipher
+    ///
+    /// Only the key stream is applied here. Authenticated ciphers are checked by the store, as it
+    /// reads the block.
+    fn crypt(&self, nonce: &[u8], offset: u64, bytes: &mut [u8]) {
+        let cipher = self.store.map().cipher();
+        cipher.apply(&self.key, nonce, offset, bytes);
+    }
+
+    /// The nonce, and offset, with which a block was encrypted
+    ///
+    /// A shared block may have been written by another file, so the reader's own nonce and offset
+    /// are only used for blocks that aren't shared.
+    fn sealed_with(&self, bn: BlockNumber, nonce: Vec<u8>, offset: u64) -> (Vec<u8>, u64) {
+        match self.store.map().shared(bn) {
+            Some(shared) => (shared.nonce.clone(), shared.offset),
+            None => (nonce, offset),
         }
     }
 }
@@ -275,6 +937,7 @@ mod test {
     use super::*;
     use crate::{
         block::{map::BlockMap, storage::BlockReader, BlockSize, MemoryStore},
+        crypto::Cipher,
         UfsUuid,
     };
 
@@ -287,6 +950,54 @@ mod test {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    #[test]
+    fn content_hash_is_keyed() {
+        init();
+
+        let new = || {
+            BlockManager::new(
+                "test",
+                "foobar",
+                MemoryStore::new(BlockMap::new(
+                    UfsUuid::new_root_fs("test"),
+                    BlockSize::FiveTwelve,
+                    100,
+                )),
+            )
+        };
+        let (bm, other) = (new(), new());
+
+        let hash = bm.content_hash(b"uberfoo");
+        assert_eq!(hash, bm.content_hash(b"uberfoo"));
+        assert_ne!(hash, BlockHash::new(b"uberfoo"));
+        assert_ne!(hash, other.content_hash(b"uberfoo"));
+    }
+
+    #[test]
+    fn serialize_frees_old_metadata() {
+        init();
+
+        let mut bm = BlockManager::new(
+            "test",
+            "foobar",
+            MemoryStore::new(BlockMap::new(
+                UfsUuid::new_root_fs("test"),
+                BlockSize::FiveTwelve,
+                100,
+            )),
+        );
+
+        bm.metadata_mut().set_label("first".to_string());
+        bm.serialize();
+        let free_blocks = bm.free_block_count();
+        let root_block = bm.root_block();
+
+        bm.metadata_mut().set_label("again".to_string());
+        bm.serialize();
+        assert_ne!(root_block, bm.root_block());
+        assert_eq!(free_blocks, bm.free_block_count());
+    }
+
     #[test]
     fn not_enough_free_blocks_error() {
         let mut bm = BlockManager::new(
@@ -305,6 +1016,10 @@ mod test {
             true,
             "verify that more blocks are needed for write"
         );
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            blocks.unwrap_err().downcast_ref::<IOFSErrorKind>()
+        );
     }
 
     #[test]
@@ -407,6 +1122,98 @@ mod test {
         );
     }
 
+    #[test]
+    fn read_partial_block() {
+        let mut bm = BlockManager::new(
+            "test",
+            "foobar",
+            MemoryStore::new(BlockMap::new(
+                UfsUuid::new_root_fs("test"),
+                BlockSize::FiveTwelve,
+                4,
+            )),
+        );
+
+        let data = (0..512).map(|b| b as u8).collect::<Vec<_>>();
+        let block = bm.write(NONCE.to_vec(), 1024, &data).unwrap().clone();
+
+        let whole = bm.read(NONCE.to_vec(), 1024, &block).unwrap();
+        assert_eq!(whole, data);
+        assert_eq!(
+            bm.read_range(NONCE.to_vec(), 1024, &block, 37, 100)
+                .unwrap(),
+            &whole[37..137]
+        );
+        assert_eq!(
+            bm.read_range(NONCE.to_vec(), 1024, &block, 500, 100)
+                .unwrap(),
+            &whole[500..]
+        );
+
+        // The hash is still checked.
+        let mut bad = block.clone();
+        bad.hash.replace(BlockHash::new("abcd"));
+        assert!(bm.read_range(NONCE.to_vec(), 1024, &bad, 37, 100).is_err());
+
+        // Only blocks without a hash are read in part.
+        bm.set_block_hashing(false);
+        let block = bm.write(NONCE.to_vec(), 2048, &data).unwrap().clone();
+        assert!(block.hash().is_none());
+        assert_eq!(
+            bm.read_range(NONCE.to_vec(), 2048, &block, 37, 100)
+                .unwrap(),
+            &data[37..137]
+        );
+    }
+
+    #[test]
+    fn cold_blocks() {
+        use std::{
+            sync::atomic::{AtomicU64, Ordering},
+            time::UNIX_EPOCH,
+        };
+
+        static NOW: AtomicU64 = AtomicU64::new(1000);
+        fn test_clock() -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(NOW.load(Ordering::SeqCst))
+        }
+
+        let mut bm = BlockManager::new(
+            "test",
+            "foobar",
+            MemoryStore::new(BlockMap::new(
+                UfsUuid::new_root_fs("test"),
+                BlockSize::FiveTwelve,
+                10,
+            )),
+        );
+        bm.clock = test_clock;
+
+        // Nothing is known until tracking is turned on.
+        bm.write(NONCE.to_vec(), 0, b"abc").unwrap();
+        assert!(bm.blocks_older_than(Duration::from_secs(0)).is_empty());
+
+        bm.set_access_tracking(true);
+        let blocks = (0..4)
+            .map(|i| bm.write(NONCE.to_vec(), i * 512, b"abc").unwrap().clone())
+            .collect::<Vec<_>>();
+
+        NOW.store(1100, Ordering::SeqCst);
+        bm.read(NONCE.to_vec(), 0, &blocks[0]).unwrap();
+        bm.read(NONCE.to_vec(), 512, &blocks[1]).unwrap();
+
+        NOW.store(1200, Ordering::SeqCst);
+        let cold = bm.blocks_older_than(Duration::from_secs(150));
+        // The block written before tracking was enabled has never been seen, so it's cold too.
+        assert_eq!(cold.len(), 3);
+        assert!(cold.contains(&blocks[2].number));
+        assert!(cold.contains(&blocks[3].number));
+        assert!(!cold.contains(&blocks[0].number));
+
+        bm.recycle_block(blocks[2].number);
+        assert_eq!(bm.blocks_older_than(Duration::from_secs(150)).len(), 2);
+    }
+
     #[test]
     fn recycle_blocks() {
         let mut bm = BlockManager::new(
@@ -434,6 +1241,16 @@ mod test {
         bm.recycle_block(block.number);
         assert_eq!(bm.free_block_count(), 9);
         assert!(bm.store.map().get(block.number).unwrap().is_free());
+
+        // A block is only ever freed once, however often it's recycled.
+        let block = bm
+            .write(NONCE.to_vec(), 0, &vec![0x39; 512][..])
+            .unwrap()
+            .clone();
+        bm.recycle_blocks(&[block.number, block.number]);
+        assert_eq!(bm.free_block_count(), 9);
+        bm.recycle_block(block.number);
+        assert_eq!(bm.free_block_count(), 9);
     }
 
     #[test]
@@ -468,4 +1285,33 @@ mod test {
 
         assert_eq!(data_1, data_2, "decrypted blocks should be identical");
     }
+
+    #[test]
+    fn chosen_cipher() {
+        let cipher = Cipher::Aes256CtrHmacSha256;
+        let mut bm = BlockManager::new(
+            "test",
+            "foobar",
+            MemoryStore::new(
+                BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 10)
+                    .with_cipher(cipher),
+            ),
+        );
+
+        let block = bm
+            .write(NONCE.to_vec(), 512, &vec![0x38; 512][..])
+            .unwrap()
+            .clone();
+        let mut bytes = bm.store.read_block(block.number).unwrap();
+        cipher.apply(&bm.key, &NONCE, 512, &mut bytes);
+        assert_eq!(
+            vec![0x38; 512],
+            bytes,
+            "data is encrypted with the store's cipher"
+        );
+        assert_eq!(
+            vec![0x38; 512],
+            bm.read(NONCE.to_vec(), 512, &block).unwrap()
+        );
+    }
 }
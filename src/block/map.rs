@@ -9,7 +9,11 @@
 //! At this time block 0 is reserved as the starting place for the block map.  Blocks are then
 //! dynamically allocated, and written with the Block Map as necessary.
 //!
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
 
 use failure::format_err;
 use log::{debug, error, info, trace};
@@ -17,12 +21,19 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::{
     block::{
-        Block, BlockCardinality, BlockHash, BlockNumber, BlockReader, BlockSize, BlockSizeType,
-        BlockWriter,
+        storage::FORMAT_VERSION, Block, BlockCardinality, BlockHash, BlockNumber, BlockReader,
+        BlockSize, BlockSizeType, BlockV1, BlockWriter, ChunkingMode,
     },
+    crypto::Cipher,
     UfsUuid,
 };
 
+/// Marks a block map serialized with its format version
+///
+/// Block maps written in format version 1 were bare `bincode`, which begins with the length of the
+/// file system id, so they never start with these bytes.
+const MAP_MAGIC: &[u8; 4] = b"UFSM";
+
 /// Block Map Wrapper Type
 ///
 /// The size of the block map changes over time, and while a maximum  _could_ be determined at
@@ -39,6 +50,91 @@ struct BlockMapWrapper {
     next_block: Option<BlockNumber>,
 }
 
+/// Volume Root Hash
+///
+/// A hash of the entire file system, and optionally a signature over the hash, made with the file
+/// system key.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct VolumeRoot {
+    pub(crate) hash: BlockHash,
+    pub(crate) signature: Option<Vec<u8>>,
+}
+
+/// A Block that may be shared by files
+///
+/// A block is encrypted with the nonce of the file version that wrote it, at its offset within
+/// that version. Other files that share the block need those to decrypt it, so they're kept here,
+/// along with the hash of the block's plaintext, and the number of files that refer to it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(in crate::block) struct SharedBlock {
+    pub(in crate::block) content: BlockHash,
+    pub(in crate::block) nonce: Vec<u8>,
+    pub(in crate::block) offset: u64,
+    pub(in crate::block) refs: u32,
+}
+
+/// The last time each block was accessed
+///
+/// `Mutex` is neither `Clone`, nor `PartialEq`, so those are done by hand.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+struct AccessTimes(Mutex<HashMap<BlockNumber, SystemTime>>);
+
+impl AccessTimes {
+    fn lock(&self) -> MutexGuard<'_, HashMap<BlockNumber, SystemTime>> {
+        self.0.lock().expect("poisoned access time lock")
+    }
+}
+
+impl Clone for AccessTimes {
+    fn clone(&self) -> Self {
+        AccessTimes(Mutex::new(self.lock().clone()))
+    }
+}
+
+impl PartialEq for AccessTimes {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other) || *self.lock() == *other.lock()
+    }
+}
+
+/// A `BlockMap`, as stored in format version 1
+///
+/// This predates the larger block sizes, and everything that followed the map itself.
+#[derive(Debug, Deserialize, Serialize)]
+struct BlockMapV1 {
+    id: UfsUuid,
+    size: BlockSize,
+    count: BlockCardinality,
+    root_block: Option<BlockNumber>,
+    block_map_blocks: Vec<BlockNumber>,
+    free_blocks: VecDeque<BlockNumber>,
+    map: Vec<BlockV1>,
+}
+
+impl From<BlockMapV1> for BlockMap {
+    fn from(map: BlockMapV1) -> Self {
+        BlockMap {
+            id: map.id,
+            size: map.size,
+            count: map.count,
+            root_block: map.root_block,
+            block_map_blocks: map.block_map_blocks,
+            free_blocks: map.free_blocks,
+            map: map.map.into_iter().map(Block::from).collect(),
+            access_times: None,
+            volume_root: None,
+            hash_blocks: true,
+            compress_blocks: false,
+            dedup_blocks: false,
+            chunking: ChunkingMode::Fixed,
+            cipher: Cipher::XChaCha20,
+            shared_blocks: HashMap::new(),
+            content_index: HashMap::new(),
+        }
+    }
+}
+
 /// Block Map
 ///
 /// A mapping from block number to Blocks.  Each block is one of several block types, where each
@@ -67,6 +163,35 @@ pub struct BlockMap {
     /// The map itself
     ///
     map: Vec<Block>,
+    /// The last time each block was accessed, if we are keeping track
+    ///
+    /// Blocks are read through a shared reference, possibly from several threads at once, thus the
+    /// `Mutex` inside `AccessTimes`.
+    access_times: Option<AccessTimes>,
+    /// The most recently stored volume root hash
+    ///
+    volume_root: Option<VolumeRoot>,
+    /// Whether data blocks are hashed when they are written
+    ///
+    hash_blocks: bool,
+    /// Whether data blocks are compressed when they are written
+    ///
+    compress_blocks: bool,
+    /// Whether data blocks with the same content are stored only once
+    ///
+    dedup_blocks: bool,
+    /// How file data is split into blocks
+    ///
+    chunking: ChunkingMode,
+    /// The cipher used to encrypt blocks in storage, including those holding the block map
+    ///
+    cipher: Cipher,
+    /// Data blocks written while deduplicating, which may be shared
+    ///
+    shared_blocks: HashMap<BlockNumber, SharedBlock>,
+    /// Shared blocks, by the hash of their plaintext
+    ///
+    content_index: HashMap<BlockHash, BlockNumber>,
 }
 
 impl BlockMap {
@@ -86,9 +211,40 @@ impl BlockMap {
             free_blocks: (1..count).collect(),
             root_block: None,
             map,
+            access_times: None,
+            volume_root: None,
+            hash_blocks: true,
+            compress_blocks: false,
+            dedup_blocks: false,
+            chunking: ChunkingMode::default(),
+            cipher: Cipher::default(),
+            shared_blocks: HashMap::new(),
+            content_index: HashMap::new(),
         }
     }
 
+    /// Choose the cipher used to encrypt blocks
+    ///
+    /// This must be done before anything is written, as blocks aren't re-encrypted.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Choose whether data blocks are compressed when they are written
+    ///
+    /// Like the cipher, this is fixed when the store is created, so that the only uncompressed
+    /// data blocks in a compressing store are those that didn't shrink.
+    pub fn with_compression(mut self, enable: bool) -> Self {
+        self.compress_blocks = enable;
+        self
+    }
+
+    /// Return the cipher used to encrypt blocks
+    pub(crate) fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
     /// Return the file system id
     pub(in crate::block) fn id(&self) -> &UfsUuid {
         &self.id
@@ -104,6 +260,16 @@ impl BlockMap {
         self.count
     }
 
+    /// Add blocks to the file system
+    ///
+    /// The new blocks are numbered after the existing ones, and are added to the free list.
+    pub(in crate::block) fn grow(&mut self, additional: BlockCardinality) {
+        let count = self.count + additional;
+        self.map.extend((self.count..count).map(|b| Block::new(b)));
+        self.free_blocks.extend(self.count..count);
+        self.count = count;
+    }
+
     /// Return a reference to the list of free blocks in the file system
     pub(crate) fn free_blocks(&self) -> &VecDeque<BlockNumber> {
         &self.free_blocks
@@ -122,6 +288,148 @@ impl BlockMap {
         self.root_block
     }
 
+    /// Turn block access time tracking on, or off
+    ///
+    /// Turning it off discards any access times collected so far.
+    pub(in crate::block) fn set_access_tracking(&mut self, enable: bool) {
+        match (enable, &self.access_times) {
+            (true, None) => self.access_times = Some(AccessTimes::default()),
+            (false, Some(_)) => self.access_times = None,
+            _ => (),
+        }
+    }
+
+    /// Return true if block access times are being recorded
+    pub(in crate::block) fn is_tracking_access(&self) -> bool {
+        self.access_times.is_some()
+    }
+
+    /// Record an access to a block, if we are tracking such things
+    pub(in crate::block) fn touch(&self, number: BlockNumber, time: SystemTime) {
+        if let Some(times) = &self.access_times {
+            times.lock().insert(number, time);
+        }
+    }
+
+    /// Forget the access time of a block
+    pub(in crate::block) fn forget_access(&self, number: BlockNumber) {
+        if let Some(times) = &self.access_times {
+            times.lock().remove(&number);
+        }
+    }
+
+    /// Return the last time a block was accessed, if known
+    pub(in crate::block) fn last_access(&self, number: BlockNumber) -> Option<SystemTime> {
+        match &self.access_times {
+            Some(times) => times.lock().get(&number).cloned(),
+            None => None,
+        }
+    }
+
+    /// Store the volume root hash
+    pub(in crate::block) fn set_volume_root(&mut self, root: VolumeRoot) {
+        self.volume_root = Some(root);
+    }
+
+    /// Return the stored volume root hash, if there is one
+    pub(in crate::block) fn volume_root(&self) -> Option<&VolumeRoot> {
+        self.volume_root.as_ref()
+    }
+
+    /// Turn hashing of data blocks on, or off
+    pub(in crate::block) fn set_block_hashing(&mut self, enable: bool) {
+        self.hash_blocks = enable;
+    }
+
+    /// Return true if data blocks are hashed when written
+    pub(in crate::block) fn is_hashing_blocks(&self) -> bool {
+        self.hash_blocks
+    }
+
+    /// Return true if data blocks are compressed when written
+    pub(in crate::block) fn is_compressing_blocks(&self) -> bool {
+        self.compress_blocks
+    }
+
+    /// Turn deduplication of data blocks on, or off
+    pub(in crate::block) fn set_block_dedup(&mut self, enable: bool) {
+        self.dedup_blocks = enable;
+    }
+
+    /// Return true if data blocks are deduplicated when written
+    pub(in crate::block) fn is_deduplicating_blocks(&self) -> bool {
+        self.dedup_blocks
+    }
+
+    /// Set the manner in which file data is split into blocks
+    pub(in crate::block) fn set_chunking_mode(&mut self, mode: ChunkingMode) {
+        self.chunking = mode;
+    }
+
+    /// Return the manner in which file data is split into blocks
+    pub(in crate::block) fn chunking_mode(&self) -> ChunkingMode {
+        self.chunking
+    }
+
+    /// Find a shared block by the hash of its plaintext
+    pub(in crate::block) fn find_content(&self, content: &BlockHash) -> Option<BlockNumber> {
+        self.content_index.get(content).cloned()
+    }
+
+    /// Return the sharing information for a block, if it may be shared
+    pub(in crate::block) fn shared(&self, number: BlockNumber) -> Option<&SharedBlock> {
+        self.shared_blocks.get(&number)
+    }
+
+    /// Make a block available for sharing
+    ///
+    /// Should another block already hold the same content, it stays the one that's found by it.
+    pub(in crate::block) fn share(&mut self, number: BlockNumber, shared: SharedBlock) {
+        self.content_index.entry(shared.content).or_insert(number);
+        self.shared_blocks.insert(number, shared);
+    }
+
+    /// Forget a shared block's content, if it's the block that's found by it
+    fn forget_content(&mut self, number: BlockNumber, content: &BlockHash) {
+        if self.content_index.get(content) == Some(&number) {
+            self.content_index.remove(content);
+        }
+    }
+
+    /// Add a reference to a shared block
+    pub(in crate::block) fn add_reference(&mut self, number: BlockNumber) {
+        if let Some(shared) = self.shared_blocks.get_mut(&number) {
+            shared.refs += 1;
+        }
+    }
+
+    /// Drop a reference to a block
+    ///
+    /// Returns true if nothing refers to the block any longer, and it may be freed. Blocks that
+    /// aren't shared only ever have the one reference.
+    pub(in crate::block) fn drop_reference(&mut self, number: BlockNumber) -> bool {
+        match self.shared_blocks.get_mut(&number) {
+            Some(shared) if shared.refs > 1 => {
+                shared.refs -= 1;
+                false
+            }
+            Some(shared) => {
+                let content = shared.content;
+                self.shared_blocks.remove(&number);
+                self.forget_content(number, &content);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Stop sharing a block, whatever its reference count
+    pub(in crate::block) fn unshare(&mut self, number: BlockNumber) {
+        if let Some(shared) = self.shared_blocks.remove(&number) {
+            self.forget_content(number, &shared.content);
+        }
+    }
+
     pub(crate) fn get(&self, number: BlockNumber) -> Option<&Block> {
         self.map.get(number as usize)
     }
@@ -150,7 +458,7 @@ impl BlockMap {
             - bincode::serialized_size(&zero_wrapper).unwrap();
 
         // Determine the number of blocks we need.
-        let mut bytes = bincode::serialize(&self).unwrap();
+        let mut bytes = self.to_bytes()?;
         let mut block_count = bytes.len() as u64 / chunk_size
             + if bytes.len() as u64 % chunk_size > 0 {
                 1
@@ -183,7 +491,7 @@ impl BlockMap {
 
             // Grab a fresh version of ourself to serialize since we converted free blocks to
             // metadata blocks
-            bytes = bincode::serialize(&self)?;
+            bytes = self.to_bytes()?;
 
             block_count = bytes.len() as u64 / chunk_size
                 + if bytes.len() as u64 % chunk_size > 0 {
@@ -245,7 +553,7 @@ impl BlockMap {
             map.append(&mut block.data);
         }
 
-        match bincode::deserialize::<BlockMap>(&map) {
+        match BlockMap::from_bytes(&map) {
             Ok(map) => {
                 info!("Loaded BlockMap:");
                 info!("\tid: {}", map.id);
@@ -260,10 +568,45 @@ impl BlockMap {
             }
             Err(e) => {
                 error!("Failed to deserialize block map.");
-                Err(e.into())
+                Err(e)
             }
         }
     }
+
+    /// Serialize the map, behind its format version
+    ///
+    pub(in crate::block) fn to_bytes(&self) -> Result<Vec<u8>, failure::Error> {
+        let mut bytes = MAP_MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.append(&mut bincode::serialize(self)?);
+        Ok(bytes)
+    }
+
+    /// Deserialize a map written by `to_bytes`
+    ///
+    /// Maps from format version 1 have no version, and are converted as they are read. They are
+    /// written in the current format the next time the map is serialized.
+    pub(in crate::block) fn from_bytes(bytes: &[u8]) -> Result<Self, failure::Error> {
+        if !bytes.starts_with(MAP_MAGIC) {
+            info!("Converting block map from format version 1");
+            return Ok(bincode::deserialize::<BlockMapV1>(bytes)?.into());
+        }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(
+            bytes
+                .get(4..8)
+                .ok_or_else(|| format_err!("The block map is truncated"))?,
+        );
+        match u32::from_le_bytes(version) {
+            FORMAT_VERSION => Ok(bincode::deserialize(&bytes[8..])?),
+            version => Err(format_err!(
+                "The block map is in format version {}, but version {} is required",
+                version,
+                FORMAT_VERSION
+            )),
+        }
+    }
 }
 
 fn read_wrapper_block<BS: BlockReader>(
@@ -468,8 +811,8 @@ mod test {
             );
         }
 
-        // Four, 512-byte blocks are needed for 100 blocks.
-        for x in 8..12 {
+        // Five more 512-byte blocks are needed for 100 blocks.
+        for x in 8..13 {
             assert!(
                 map_2.get(x).unwrap().is_map(),
                 "block {} should be BlockType::Map",
@@ -477,7 +820,7 @@ mod test {
             );
         }
 
-        for x in 12..100 {
+        for x in 13..100 {
             assert!(
                 map_2.get(x).unwrap().is_free(),
                 "block {} should be BlockType::Free",
@@ -485,4 +828,68 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn format_version() {
+        init();
+        let id = UfsUuid::new_root_fs("test");
+        let mut map = BlockMap::new(id, BlockSize::OneMeg, 10);
+        map.set_chunking_mode(ChunkingMode::ContentDefined);
+
+        let mut bytes = map.to_bytes().unwrap();
+        assert_eq!(BlockMap::from_bytes(&bytes).unwrap(), map);
+
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(BlockMap::from_bytes(&bytes).is_err());
+        assert!(BlockMap::from_bytes(&bytes[..6]).is_err());
+    }
+
+    #[test]
+    fn format_version_1() {
+        init();
+        let id = UfsUuid::new_root_fs("test");
+        let block = |number, byte_count, block_type| BlockV1 {
+            byte_count,
+            number,
+            hash: if byte_count > 0 {
+                Some(BlockHash::new(&[0x42; 512][..byte_count as usize]))
+            } else {
+                None
+            },
+            block_type,
+        };
+        let v1 = BlockMapV1 {
+            id,
+            size: BlockSize::FiveTwelve,
+            count: 4,
+            root_block: Some(2),
+            block_map_blocks: vec![0],
+            free_blocks: vec![3].into(),
+            map: vec![
+                block(0, 0, BlockType::Map),
+                block(1, 512, BlockType::Data),
+                block(2, 100, BlockType::Metadata),
+                block(3, 0, BlockType::Free),
+            ],
+        };
+
+        let map = BlockMap::from_bytes(&bincode::serialize(&v1).unwrap()).unwrap();
+        assert_eq!(map.id(), &id);
+        assert_eq!(map.block_size(), BlockSize::FiveTwelve);
+        assert_eq!(map.root_block(), Some(2));
+        assert_eq!(map.free_blocks, VecDeque::from(vec![3]));
+        assert_eq!(map.cipher, Cipher::XChaCha20);
+        assert_eq!(map.chunking_mode(), ChunkingMode::Fixed);
+        assert!(map.get(0).unwrap().is_map());
+        assert!(map.get(3).unwrap().is_free());
+
+        let data = map.get(1).unwrap();
+        assert!(data.is_data());
+        assert_eq!(data.size(), 512);
+        assert_eq!(data.compressed_size(), None);
+        assert_eq!(data.hash(), Some(BlockHash::new(&[0x42; 512][..])));
+
+        // Once converted, it's written in the current format.
+        assert_eq!(&map.to_bytes().unwrap()[..4], MAP_MAGIC);
+    }
 }
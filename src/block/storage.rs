@@ -1,12 +1,171 @@
 pub mod file;
 pub mod memory;
+pub mod mirror;
 pub mod network;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+use {
+    failure::format_err,
+    rand::prelude::*,
+    serde_derive::{Deserialize, Serialize},
+};
 
 use crate::{
     block::{map::BlockMap, BlockCardinality, BlockNumber, BlockSize, BlockSizeType},
+    crypto::{hash_password, sign, verify, Cipher, KdfParams},
     uuid::UfsUuid,
 };
 
+/// The format in which stores are written
+///
+/// This covers how blocks are laid out in storage, along with the block map and file system
+/// metadata within them. It goes up whenever any of those change.
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// Store Header
+///
+/// This is stored in the clear, beside the blocks. It records the format of the store, whether it's
+/// sparse, the cipher that blocks are encrypted with, and what's needed to derive the master key
+/// from the master password: the parameters, and a random salt. Stores written before there was a
+/// header are format version 1, and derived their key with PBKDF2, salted with the file system id.
+///
+/// Being in the clear, the header is signed with the master key, so that nobody may swap in weaker
+/// parameters, or a weaker cipher, without it being noticed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct StoreHeader {
+    version: u32,
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    /// Blocks are only stored once they're written
+    ///
+    /// A block that's free, and missing from storage, was never written, and reads as zeros. In
+    /// any other store, a missing block is an error.
+    #[serde(default)]
+    sparse: bool,
+    /// The cipher that blocks, including those of the block map, are encrypted with
+    ///
+    /// The block map records it too, but it must be known before the block map can be read. A
+    /// block map that disagrees is refused.
+    cipher: Cipher,
+    /// An HMAC-SHA256 of the rest of the header, under a key derived from the master key
+    ///
+    #[serde(default)]
+    mac: Vec<u8>,
+}
+
+impl StoreHeader {
+    /// The header for a new store
+    ///
+    /// It must be signed, with the key derived from it, before it's stored.
+    pub(crate) fn new(sparse: bool, cipher: Cipher) -> Self {
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        StoreHeader {
+            version: FORMAT_VERSION,
+            kdf: KdfParams::default(),
+            salt,
+            sparse,
+            cipher,
+            mac: vec![],
+        }
+    }
+
+    /// The header implied by a store that doesn't have one
+    ///
+    pub(crate) fn legacy(id: &UfsUuid) -> Self {
+        StoreHeader {
+            version: 1,
+            kdf: KdfParams::legacy(),
+            salt: id.as_bytes().to_vec(),
+            sparse: false,
+            cipher: Cipher::XChaCha20,
+            mac: vec![],
+        }
+    }
+
+    /// Return true if blocks are only stored once they're written
+    ///
+    pub(crate) fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// Return the cipher that blocks are encrypted with
+    ///
+    pub(crate) fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Sign the header with the master key
+    ///
+    pub(crate) fn sign(&mut self, key: &[u8]) -> Result<(), failure::Error> {
+        self.mac = sign(&header_mac_key(key), &self.signed_bytes()?);
+        Ok(())
+    }
+
+    /// Check the signature made by `sign`
+    ///
+    /// A header that's been tampered with fails, as does one checked with the wrong key, i.e., one
+    /// derived from the wrong password.
+    pub(crate) fn verify(&self, key: &[u8]) -> Result<(), failure::Error> {
+        if verify(&header_mac_key(key), &self.signed_bytes()?, &self.mac) {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "the store header failed authentication -- possibly incorrect master password?"
+            ))
+        }
+    }
+
+    /// The bytes covered by the signature: the header, without it
+    ///
+    fn signed_bytes(&self) -> Result<Vec<u8>, failure::Error> {
+        let mut unsigned = self.clone();
+        unsigned.mac.clear();
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+
+    /// Parse a stored header
+    ///
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, failure::Error> {
+        serde_json::from_slice(bytes).map_err(|e| format_err!("unreadable store header: {}", e))
+    }
+
+    /// Serialize the header for storage
+    ///
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, failure::Error> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Check that the store is in the format that we write
+    ///
+    pub(crate) fn check_version(&self) -> Result<(), failure::Error> {
+        if self.version == FORMAT_VERSION {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "the store is in format version {}, but version {} is required",
+                self.version,
+                FORMAT_VERSION
+            ))
+        }
+    }
+
+    /// Derive the master key from the master password
+    ///
+    pub(crate) fn key<S: AsRef<str>>(&self, password: S) -> Result<[u8; 32], failure::Error> {
+        hash_password(password, &self.salt, &self.kdf)
+    }
+}
+
+/// The key with which the store header is signed
+///
+/// It's derived from the master key, so that the master key is used only for encryption.
+fn header_mac_key(key: &[u8]) -> Vec<u8> {
+    sign(key, b"ufs store header")
+}
+
 /// Persistent Storage for Blocks
 ///
 /// This trait is an abstraction for the underlying block storage.  An implementor is taking
@@ -14,6 +173,7 @@ use crate::{
 /// able to read and write data to blocks.
 ///
 /// Finally, the block storage provides access to metadata, stored as blocks,
+///
 pub trait BlockStorage: BlockWriter + BlockReader + Send {
     /// Uuid of BlockStorage
     ///
@@ -38,6 +198,14 @@ pub trait BlockStorage: BlockWriter + BlockReader + Send {
     /// The number of Blocks in this file System
     ///
     fn block_count(&self) -> BlockCardinality;
+
+    /// Add Blocks to this file system
+    ///
+    /// The new blocks are added to the block map, as free blocks. It's up to the caller to commit
+    /// the map. By default, storage may not be grown.
+    fn grow(&mut self, _additional: BlockCardinality) -> Result<(), failure::Error> {
+        Err(format_err!("block storage {} may not be grown", self.id()))
+    }
 }
 
 /// Writer of Blocks
@@ -68,4 +236,21 @@ pub trait BlockReader {
     ///
     /// Return a fresh copy of the bytes contained in the specified block, as a `Vec<u8>`.
     fn read_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error>;
+
+    /// Read part of a Block
+    ///
+    /// Return up to `len` bytes, starting `offset` bytes into the specified block. The range is
+    /// clipped to the end of the block. By default the whole block is read, and then sliced;
+    /// implementors that can do better, should.
+    fn read_block_range(
+        &self,
+        bn: BlockNumber,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let data = self.read_block(bn)?;
+        let start = offset.min(data.len());
+        let end = offset.saturating_add(len).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
 }
@@ -3,29 +3,127 @@
 //! Blocks are stored as regular files.  The files are nested in directories as the following
 //! example: block `f03da2` would be stored as `root_dir/f/0/3/d/a/2.ufsb`.
 //!
+//! A store may be created "sparse", in which case neither the files nor the directories are
+//! created until a block is first written. A block that has never been written reads as zeros.
+//!
+//! Each time a block is written it's encrypted with a fresh, random nonce. Blocks are rewritten
+//! as they are recycled, and the block map is rewritten in place, so a nonce derived from the
+//! block number would be reused with the same key. The nonce is stored at the start of the block
+//! file, as the blocks that hold the block map can't look theirs up in it.
+//!
+//! The cipher is chosen when the store is created, and recorded in the block map. The block map
+//! is encrypted with it too, so it's also recorded in the store header, as it must be known before
+//! the block map can be read. A block map that disagrees with the header is refused. An
+//! authenticated cipher keeps a tag after the nonce, which is checked before the block is
+//! decrypted.
+//!
+//! The master key is derived from the master password with the parameters, and salt, kept in the
+//! store header, `header.json`. The header is in the clear, and also records the format version of
+//! the store. It's signed with the master key, so none of this may be changed unnoticed.
+//!
+//! The master key may be rotated in place. Every block is re-encrypted into a file alongside the
+//! original, and once they have all been written, along with a new header, a marker is left in the
+//! root directory. The new files are then moved over the originals. Should that be interrupted,
+//! it's finished the next time that the store is loaded, so the store is only ever readable with
+//! one key or the other.
+//!
+//! Stores in format version 1 had no header, and encrypted every block under the same nonce. They
+//! are upgraded as they are loaded, in the same way that the key is rotated.
+//!
 //! ## FIXME
 //! * It might be better to build a more shallow directory tree: `root_dir/f0/3d/a2.ufsb`?
-//! * Optionally don't create files for every block.
 use std::{
-    fs, io,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 
 use {
     failure::format_err,
-    log::{debug, error, trace},
+    log::{debug, error, info, trace},
+    rand::prelude::*,
 };
 
 use crate::{
     block::{
-        map::BlockMap, BlockCardinality, BlockNumber, BlockReader, BlockSize, BlockSizeType,
-        BlockStorage, BlockWriter,
+        manager::BlockManager, map::BlockMap, storage::StoreHeader, BlockCardinality, BlockNumber,
+        BlockReader, BlockSize, BlockSizeType, BlockStorage, BlockWriter,
     },
-    crypto::{decrypt, encrypt, make_fs_key},
+    crypto::Cipher,
     uuid::UfsUuid,
 };
 
 const BLOCK_EXT: &str = "ufsb";
+const HEADER_FILE: &str = "header.json";
+const ROTATE_EXT: &str = "ufsr";
+const ROTATE_MARKER: &str = "rotating";
+const NONCE_SIZE: usize = 24;
+
+/// Encrypt a block under a fresh nonce
+///
+/// The nonce, and then the tag, if the cipher is authenticated, are returned at the head of the
+/// encrypted block, ready to be written to the file.
+pub(super) fn seal(
+    cipher: Cipher,
+    key: &[u8],
+    bn: BlockNumber,
+    block_size: BlockSize,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut sealed = data.to_vec();
+    cipher.apply(key, &nonce, bn * block_size as u64, &mut sealed);
+    let mut tag = cipher.tag(key, bn, &nonce, &sealed);
+    nonce.append(&mut tag);
+    nonce.append(&mut sealed);
+    nonce
+}
+
+/// Decrypt the contents of a block file written by `seal`
+///
+/// An empty file, created up front for a block that has yet to be written, is an empty block. It's
+/// an error if the block's tag doesn't match its contents.
+pub(super) fn unseal(
+    cipher: Cipher,
+    key: &[u8],
+    bn: BlockNumber,
+    block_size: BlockSize,
+    mut data: Vec<u8>,
+) -> Result<Vec<u8>, failure::Error> {
+    if data.is_empty() {
+        return Ok(data);
+    }
+    if data.len() < NONCE_SIZE + cipher.tag_size() {
+        return Err(format_err!("block 0x{:x?} is missing its nonce", bn));
+    }
+
+    let mut plain = data.split_off(NONCE_SIZE + cipher.tag_size());
+    let (nonce, tag) = data.split_at(NONCE_SIZE);
+    if !cipher.verify_tag(key, bn, nonce, &plain, tag) {
+        return Err(format_err!("block 0x{:x?} failed authentication", bn));
+    }
+    cipher.apply(key, nonce, bn * block_size as u64, &mut plain);
+    Ok(plain)
+}
+
+/// Encrypt, or decrypt, a block as format version 1 did
+///
+/// Every block was encrypted with XChaCha20, under one nonce made from the file system id, and
+/// nothing was stored beside the data.
+fn crypt_v1(
+    key: &[u8],
+    id: &UfsUuid,
+    bn: BlockNumber,
+    block_size: BlockSize,
+    mut data: Vec<u8>,
+) -> Vec<u8> {
+    let mut nonce = id.as_bytes().to_vec();
+    nonce.extend_from_slice(&id.as_bytes()[0..8]);
+    Cipher::XChaCha20.apply(key, &nonce, bn * block_size as u64, &mut data);
+    data
+}
 
 /// Internal-only block writing implementation.
 ///
@@ -34,7 +132,7 @@ const BLOCK_EXT: &str = "ufsb";
 ///
 struct FileWriter {
     key: [u8; 32],
-    nonce: Vec<u8>,
+    cipher: Cipher,
     block_size: BlockSize,
     block_count: BlockCardinality,
     root_path: PathBuf,
@@ -47,13 +145,7 @@ impl BlockWriter for FileWriter {
     where
         T: AsRef<[u8]>,
     {
-        let mut data = data.as_ref().to_vec();
-        encrypt(
-            &self.key,
-            &self.nonce,
-            bn * self.block_size as u64,
-            &mut data,
-        );
+        let data = data.as_ref();
 
         if bn > self.block_count {
             Err(format_err!("request for bogus block {}", bn))
@@ -62,8 +154,8 @@ impl BlockWriter for FileWriter {
                 return Err(format_err!("data is larger than block size"));
             }
 
-            let path = path_for_block(&self.root_path, bn);
-            fs::write(path, &data)?;
+            let sealed = seal(self.cipher, &self.key, bn, self.block_size, data);
+            write_block_file(&self.root_path, bn, &sealed)?;
 
             debug!("wrote {} bytes to block 0x{:x?}", data.len(), bn);
             trace!("{:?}", data);
@@ -79,29 +171,25 @@ impl BlockWriter for FileWriter {
 ///
 struct FileReader {
     key: [u8; 32],
-    nonce: Vec<u8>,
+    cipher: Cipher,
     block_size: BlockSize,
     root_path: PathBuf,
 }
 
 impl FileReader {
-    pub(crate) fn new<P: AsRef<Path>>(key: [u8; 32], path: P) -> Self {
+    pub(crate) fn new<P: AsRef<Path>>(key: [u8; 32], cipher: Cipher, path: P) -> Self {
         let root_path: PathBuf = path.as_ref().into();
 
-        // Note that the id of the file system is the last element in the path
-        let id = UfsUuid::new_root_fs(root_path.file_name().unwrap().to_str().unwrap());
-        let mut nonce = Vec::with_capacity(24);
-        // FIXME: Is this nonce sufficient?
-        nonce.extend_from_slice(&id.as_bytes()[..]);
-        nonce.extend_from_slice(&id.as_bytes()[0..8]);
-
-        // Infer the block size from the size of the 0-block file.
+        // Infer the block size from the size of the 0-block file, less its nonce and tag. The block
+        // map only continues past block 0 once it's filled it, otherwise it's all in block 0, and
+        // any size that holds it will do.
         let metadata = fs::metadata(path_for_block(&root_path, 0)).unwrap();
+        let overhead = (NONCE_SIZE + cipher.tag_size()) as u64;
 
         FileReader {
             key,
-            nonce,
-            block_size: metadata.len().into(),
+            cipher,
+            block_size: BlockSize::fitting(metadata.len().saturating_sub(overhead)),
             root_path,
         }
     }
@@ -115,15 +203,7 @@ impl BlockReader for FileReader {
         let path = path_for_block(&self.root_path, bn);
         debug!("reading block from {:?}", path);
         let data = match fs::read(&path) {
-            Ok(mut data) => {
-                decrypt(
-                    &self.key,
-                    &self.nonce,
-                    bn * self.block_size as u64,
-                    &mut data,
-                );
-                data
-            }
+            Ok(data) => unseal(self.cipher, &self.key, bn, self.block_size, data)?,
             Err(_) => {
                 error!("error reading file {:?}", path);
                 panic!();
@@ -136,6 +216,152 @@ impl BlockReader for FileReader {
     }
 }
 
+/// Reads the block map of a store in format version 1
+///
+struct FileReaderV1 {
+    key: [u8; 32],
+    id: UfsUuid,
+    block_size: BlockSize,
+    root_path: PathBuf,
+}
+
+impl FileReaderV1 {
+    /// As with `FileReader`, the block size is inferred from the 0-block file, which had no nonce.
+    fn new(key: [u8; 32], id: UfsUuid, root_path: &PathBuf) -> Result<Self, failure::Error> {
+        let metadata = fs::metadata(path_for_block(root_path, 0))?;
+
+        Ok(FileReaderV1 {
+            key,
+            id,
+            block_size: BlockSize::fitting(metadata.len()),
+            root_path: root_path.clone(),
+        })
+    }
+}
+
+impl BlockReader for FileReaderV1 {
+    fn read_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error> {
+        let data = fs::read(path_for_block(&self.root_path, bn))?;
+        Ok(crypt_v1(&self.key, &self.id, bn, self.block_size, data))
+    }
+}
+
+/// Write the contents of a block file
+///
+/// The directories leading to the file are created if they don't exist, as is the case for a
+/// block in a sparse store that has never been written.
+fn write_block_file(root: &PathBuf, bn: BlockNumber, data: &[u8]) -> io::Result<()> {
+    let path = path_for_block(root, bn);
+    match fs::write(&path, data) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(dir) = path.parent() {
+                trace!("creating directory {:?}", dir);
+                fs::DirBuilder::new().recursive(true).create(dir)?;
+            }
+            fs::write(&path, data)
+        }
+        result => result,
+    }
+}
+
+/// Read the store header
+///
+/// `None` is returned if there isn't one.
+fn read_header(root: &PathBuf) -> Result<Option<StoreHeader>, failure::Error> {
+    match fs::read(root.join(HEADER_FILE)) {
+        Ok(bytes) => Ok(Some(StoreHeader::from_bytes(&bytes)?)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write a store header to `path`
+///
+/// The header is written beside `path`, and moved into place, so that it's never half written.
+fn write_header(path: &Path, header: &StoreHeader) -> Result<(), failure::Error> {
+    let staged = path.with_extension("tmp");
+    fs::write(&staged, header.to_bytes()?)?;
+    fs::rename(&staged, path)?;
+    Ok(())
+}
+
+/// Finish an interrupted key rotation
+///
+/// If the marker exists, then every block has been re-encrypted under the new key, and all that
+/// remains is to move the new block files, and header, over the old ones. This may be done any
+/// number of times.
+fn finish_rotation(root: &PathBuf) -> Result<(), failure::Error> {
+    let marker = root.join(ROTATE_MARKER);
+    let count = match fs::read_to_string(&marker) {
+        Ok(count) => count.trim().parse::<BlockCardinality>()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    info!("finishing master key rotation of {:?}", root);
+    for bn in 0..count {
+        let path = path_for_block(root, bn);
+        match fs::rename(path.with_extension(ROTATE_EXT), &path) {
+            Ok(_) => trace!("rotated block 0x{:x?}", bn),
+            // Never written, or already moved.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let header = root.join(HEADER_FILE);
+    match fs::rename(header.with_extension(ROTATE_EXT), &header) {
+        Ok(_) => trace!("rotated header"),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    fs::remove_file(&marker)?;
+    Ok(())
+}
+
+/// Upgrade a store from format version 1
+///
+/// The store had no header, and its key was derived with PBKDF2, salted with the file system id,
+/// which also named the store's directory. Every block is decrypted, and sealed under a fresh
+/// nonce, with a key derived from a new header, just as when the key is rotated. Nothing is
+/// changed unless the block map can be read with `password`. The block map, and the metadata, are
+/// converted as they are read, and written in the current format the next time they are stored.
+fn upgrade_v1(password: &str, root: &PathBuf) -> Result<(), failure::Error> {
+    let id = UfsUuid::new_root_fs(
+        root.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("no file system name in {:?}", root))?,
+    );
+    let legacy = StoreHeader::legacy(&id);
+    let reader = FileReaderV1::new(legacy.key(password)?, id, root)?;
+    let map = BlockMap::deserialize(&reader).map_err(|e| {
+        error!(
+            "Unable to load version 1 block map -- possibly incorrect master password?\nError: {}",
+            e
+        );
+        format_err!("Unable to load block map -- possibly incorrect master password?")
+    })?;
+
+    info!("upgrading {:?} from format version 1", root);
+    let store = FileStore {
+        id,
+        header: legacy,
+        key: reader.key,
+        block_size: map.block_size(),
+        block_count: map.block_count(),
+        root_path: root.clone(),
+        map,
+    };
+    let mut header = StoreHeader::new(false, store.map.cipher());
+    let key = header.key(password)?;
+    header.sign(&key)?;
+    store.stage_rotation(&header, &key, |bn, data| {
+        Ok(crypt_v1(&store.key, &store.id, bn, store.block_size, data))
+    })?;
+    finish_rotation(root)
+}
+
 /// It'd be cool to impl From<BlockNumber> for PathBuf
 fn path_for_block(root: &PathBuf, block: BlockNumber) -> PathBuf {
     let mut path = root.clone();
@@ -165,8 +391,8 @@ fn path_for_block(root: &PathBuf, block: BlockNumber) -> PathBuf {
 #[derive(Clone, Debug, PartialEq)]
 pub struct FileStore {
     id: UfsUuid,
+    header: StoreHeader,
     key: [u8; 32],
-    nonce: Vec<u8>,
     block_size: BlockSize,
     block_count: BlockCardinality,
     root_path: PathBuf,
@@ -176,24 +402,53 @@ pub struct FileStore {
 impl FileStore {
     /// FileStore Constructor
     ///
-    /// Note that block 0 is reserved to store block-level metadata.
-    pub fn new<S, P>(password: S, path: P, mut map: BlockMap) -> Result<Self, failure::Error>
+    /// Note that block 0 is reserved to store block-level metadata. A file is created for every
+    /// block up front.
+    pub fn new<S, P>(password: S, path: P, map: BlockMap) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        FileStore::create(password, path, map, false)
+    }
+
+    /// Sparse FileStore Constructor
+    ///
+    /// As with `new`, but block files are created as blocks are written. This is much quicker for
+    /// large stores, and leaves the host's inodes alone.
+    pub fn new_sparse<S, P>(password: S, path: P, map: BlockMap) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        FileStore::create(password, path, map, true)
+    }
+
+    fn create<S, P>(
+        password: S,
+        path: P,
+        mut map: BlockMap,
+        sparse: bool,
+    ) -> Result<Self, failure::Error>
     where
         S: AsRef<str>,
         P: AsRef<Path>,
     {
         let root_path: PathBuf = path.as_ref().into();
-        FileStore::init(&root_path, map.block_size(), map.block_count())?;
+        if sparse {
+            fs::DirBuilder::new().recursive(true).create(&root_path)?;
+        } else {
+            FileStore::init(&root_path, map.block_size(), map.block_count())?;
+        }
 
-        let key = make_fs_key(password.as_ref(), &map.id());
-        let mut nonce = Vec::with_capacity(24);
-        // FIXME: Is this nonce sufficient?
-        nonce.extend_from_slice(&map.id().as_bytes()[..]);
-        nonce.extend_from_slice(&map.id().as_bytes()[0..8]);
+        let mut header = StoreHeader::new(sparse, map.cipher());
+        let key = header.key(password.as_ref())?;
+        header.sign(&key)?;
+        write_header(&root_path.join(HEADER_FILE), &header)?;
 
         let mut writer = FileWriter {
             key,
-            nonce,
+            cipher: header.cipher(),
             block_size: map.block_size(),
             block_count: map.block_count(),
             root_path: root_path.clone(),
@@ -203,8 +458,8 @@ impl FileStore {
 
         Ok(FileStore {
             id: map.id().clone(),
+            header,
             key,
-            nonce: writer.nonce,
             block_size: map.block_size(),
             block_count: map.block_count(),
             root_path,
@@ -214,33 +469,34 @@ impl FileStore {
 
     /// Consistency Check
     ///
-    /// FIXME: Actually check consistency?
-    pub fn check<S, P>(password: S, path: P, show_map: bool) -> Result<(), failure::Error>
+    /// After the header is shown, every block of every file is checked, as described by
+    /// `BlockManager::check`, unless the store holds no file system. That takes a user, and their
+    /// password, in order to decrypt the blocks; without them only the header is shown. If
+    /// `repair`, blocks that belong to no file are returned to the free list, and files that are in
+    /// no directory are re-linked. It's an error if anything is found that wasn't repaired.
+    pub fn check<S, P>(
+        password: S,
+        path: P,
+        user: Option<(S, S)>,
+        show_map: bool,
+        repair: bool,
+    ) -> Result<(), failure::Error>
     where
         S: AsRef<str>,
         P: AsRef<Path>,
     {
         println!("Running consistency check on {:?}", path.as_ref());
 
-        let key = make_fs_key(
-            password.as_ref(),
-            &UfsUuid::new_root_fs(
-                path.as_ref()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .as_bytes(),
-            ),
-        );
-
-        let fs = FileStore::load(key, path)?;
+        let fs = FileStore::load(password, path)?;
 
         println!("File-based Block Storage:");
         println!("\tID: {}", fs.id);
         println!("\tblock count: {}", fs.block_count);
         println!("\tblock size: {}", fs.block_size);
-        println!("\tfree blocks: {}", fs.map.free_blocks().len());
+        let free = fs.map.free_blocks().len() as BlockCardinality;
+        println!("\tfree blocks: {}", free);
+        println!("\tused blocks: {}", fs.block_count - free);
+        println!("\tblock files: {}", fs.block_files());
         match fs.map.root_block() {
             Some(block) => println!("\troot block number: {}", block),
             None => (),
@@ -251,19 +507,63 @@ impl FileStore {
             println!("{:#?}", fs.map);
         }
 
-        Ok(())
+        // A bare block store, such as one behind a block server, has no files to check against.
+        if fs.map.root_block().is_none() {
+            println!("\nNo file system metadata: blocks not checked");
+            return Ok(());
+        }
+
+        let (user, user_password) = match user {
+            Some(user) => user,
+            None => {
+                println!("\nNo user given: blocks not checked");
+                return Ok(());
+            }
+        };
+        let mut manager = BlockManager::load(user, user_password, fs)?;
+        let report = manager.check(repair);
+        println!("\nBlock Check:");
+        println!("\tblocks scanned: {}", report.blocks_scanned);
+        println!("\terrors found: {}", report.problems.len());
+        for problem in &report.problems {
+            println!("\t\t{}", problem);
+        }
+        println!("\trepairs made: {}", report.repairs);
+
+        let unrepaired = report.problems.len() as BlockCardinality - report.repairs;
+        if unrepaired > 0 {
+            Err(format_err!("{} errors were not repaired", unrepaired))
+        } else {
+            Ok(())
+        }
     }
 
     /// Construct Existing
     ///
-    /// Load an existing file store from disk.
-    pub fn load<P>(key: [u8; 32], path: P) -> Result<Self, failure::Error>
+    /// Load an existing file store from disk. A key rotation that was interrupted after all of the
+    /// blocks were re-encrypted is finished first, in which case `password` must be the new one.
+    /// A store in format version 1 is upgraded in place. It's an error if the store is in any
+    /// other format.
+    pub fn load<S, P>(password: S, path: P) -> Result<Self, failure::Error>
     where
+        S: AsRef<str>,
         P: AsRef<Path>,
     {
         let root_path: PathBuf = path.as_ref().into();
+        finish_rotation(&root_path)?;
+
+        let header = match read_header(&root_path)? {
+            Some(header) => header,
+            None => {
+                upgrade_v1(password.as_ref(), &root_path)?;
+                return FileStore::load(password, path);
+            }
+        };
+        header.check_version()?;
+        let key = header.key(password)?;
+        header.verify(&key)?;
 
-        let reader = FileReader::new(key, &path);
+        let reader = FileReader::new(key, header.cipher(), &path);
 
         let map = match BlockMap::deserialize(&reader) {
             Ok(map) => map,
@@ -278,10 +578,18 @@ impl FileStore {
             }
         };
 
+        if map.cipher() != header.cipher() {
+            return Err(format_err!(
+                "the block map's cipher, {:?}, isn't the store's, {:?}",
+                map.cipher(),
+                header.cipher()
+            ));
+        }
+
         Ok(FileStore {
             id: map.id().clone(),
+            header,
             key: reader.key,
-            nonce: reader.nonce,
             block_size: map.block_size(),
             block_count: map.block_count(),
             root_path,
@@ -289,6 +597,112 @@ impl FileStore {
         })
     }
 
+    /// Change the master password
+    ///
+    /// Every block is decrypted with the key derived from `old_password`, and encrypted with one
+    /// derived from `new_password`. It's an error if `old_password` isn't the one in use. The new
+    /// blocks are written beside the old, so there must be room for a second copy of the store.
+    pub fn rotate_key<S: AsRef<str>>(
+        &mut self,
+        old_password: S,
+        new_password: S,
+    ) -> Result<(), failure::Error> {
+        if self.header.key(old_password.as_ref())? != self.key {
+            return Err(format_err!("incorrect master password"));
+        }
+
+        let mut header = StoreHeader::new(self.header.is_sparse(), self.header.cipher());
+        let key = header.key(new_password.as_ref())?;
+        header.sign(&key)?;
+        self.stage_rotation(&header, &key, |bn, data| {
+            unseal(self.cipher_for(bn), &self.key, bn, self.block_size, data)
+        })?;
+        finish_rotation(&self.root_path)?;
+        self.header = header;
+        self.key = key;
+
+        Ok(())
+    }
+
+    /// Re-encrypt every block under `key`, beside the original
+    ///
+    /// Each block file is decrypted with `open`. `header` is the one from which `key` was derived,
+    /// and it's staged along with the blocks. The marker that commits the rotation is written last,
+    /// and renamed into place so that it's either complete or missing. Until then, the store is
+    /// untouched.
+    fn stage_rotation<F>(
+        &self,
+        header: &StoreHeader,
+        key: &[u8; 32],
+        open: F,
+    ) -> Result<(), failure::Error>
+    where
+        F: Fn(BlockNumber, Vec<u8>) -> Result<Vec<u8>, failure::Error>,
+    {
+        debug!("re-encrypting {} blocks", self.block_count);
+        for bn in 0..self.block_count {
+            let path = path_for_block(&self.root_path, bn);
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                // Never written, in a sparse store.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let data = open(bn, data)?;
+            let sealed = seal(self.cipher_for(bn), key, bn, self.block_size, &data);
+
+            let mut file = fs::File::create(path.with_extension(ROTATE_EXT))?;
+            file.write_all(&sealed)?;
+            file.sync_all()?;
+        }
+
+        write_header(
+            &self.root_path.join(HEADER_FILE).with_extension(ROTATE_EXT),
+            header,
+        )?;
+
+        let marker = self.root_path.join(ROTATE_MARKER);
+        let staged = marker.with_extension("tmp");
+        fs::write(&staged, self.block_count.to_string())?;
+        fs::rename(&staged, &marker)?;
+
+        Ok(())
+    }
+
+    /// The cipher that a block is encrypted with
+    ///
+    fn cipher_for(&self, bn: BlockNumber) -> Cipher {
+        match self.map.get(bn) {
+            Some(block) if block.is_map() => self.header.cipher(),
+            _ => self.map.cipher(),
+        }
+    }
+
+    /// Return true if a block has never been written
+    ///
+    /// Only a sparse store may be missing block files, and then only for blocks that aren't
+    /// allocated. Any other missing block has been lost, and reading it is an error.
+    fn is_unwritten(&self, bn: BlockNumber) -> bool {
+        self.header.is_sparse() && self.map.get(bn).map_or(true, |block| block.is_free())
+    }
+
+    /// The file in which a block is stored
+    ///
+    #[cfg(test)]
+    pub(crate) fn block_path(&self, bn: BlockNumber) -> PathBuf {
+        path_for_block(&self.root_path, bn)
+    }
+
+    /// Count the block files on disk
+    ///
+    /// For a sparse store, this is the number of blocks that have ever been written.
+    fn block_files(&self) -> BlockCardinality {
+        (0..self.block_count)
+            .filter(|bn| path_for_block(&self.root_path, *bn).is_file())
+            .count() as BlockCardinality
+    }
+
     fn init(
         path: &PathBuf,
         size: BlockSize,
@@ -357,7 +771,7 @@ impl BlockStorage for FileStore {
 
         let mut writer = FileWriter {
             key: self.key,
-            nonce: self.nonce.clone(),
+            cipher: self.header.cipher(),
             block_size: self.block_size,
             block_count: self.block_count,
             root_path: self.root_path.clone(),
@@ -385,6 +799,26 @@ impl BlockStorage for FileStore {
     fn block_size(&self) -> BlockSize {
         self.block_size
     }
+
+    /// The files for the new blocks are created, along with any directories they need, unless the
+    /// store is sparse, in which case they're created as they are written.
+    fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+        let count = self.block_count + additional;
+        debug!(
+            "growing FileStore from {} to {} blocks",
+            self.block_count, count
+        );
+        if !self.header.is_sparse() {
+            for block in self.block_count..count {
+                trace!("creating block file {:x?}", block);
+                write_block_file(&self.root_path, block, &[])?;
+            }
+        }
+
+        self.block_count = count;
+        self.map.grow(additional);
+        Ok(())
+    }
 }
 
 impl BlockWriter for FileStore {
@@ -392,13 +826,7 @@ impl BlockWriter for FileStore {
     where
         D: AsRef<[u8]>,
     {
-        let mut data = data.as_ref().to_vec();
-        encrypt(
-            &self.key,
-            &self.nonce,
-            bn * self.block_size as u64,
-            &mut data,
-        );
+        let data = data.as_ref();
 
         if bn > self.block_count {
             Err(format_err!("request for bogus block {}", bn))
@@ -407,8 +835,8 @@ impl BlockWriter for FileStore {
                 return Err(format_err!("data is larger than block size"));
             }
 
-            let path = path_for_block(&self.root_path, bn);
-            fs::write(path, &data)?;
+            let sealed = seal(self.cipher_for(bn), &self.key, bn, self.block_size, data);
+            write_block_file(&self.root_path, bn, &sealed)?;
 
             debug!("wrote {} bytes to block 0x{:x?}", data.len(), bn);
             trace!("{:?}", data);
@@ -425,21 +853,78 @@ impl BlockReader for FileStore {
             let path = path_for_block(&self.root_path, bn);
             debug!("reading block from {:?}", path);
             let data = match fs::read(&path) {
-                Ok(mut data) => {
-                    decrypt(
-                        &self.key,
-                        &self.nonce,
-                        bn * self.block_size as u64,
-                        &mut data,
-                    );
-                    data
+                Ok(data) => unseal(self.cipher_for(bn), &self.key, bn, self.block_size, data)?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.is_unwritten(bn) => {
+                    vec![0; self.block_size as usize]
+                }
+                Err(e) => {
+                    error!("error reading file {:?}: {}", path, e);
+                    return Err(e.into());
                 }
-                Err(_) => {
-                    error!("error reading file {:?}", path);
-                    panic!();
+            };
+
+            debug!("read {} bytes from block 0x{:x?}", data.len(), bn);
+            trace!("{:?}", data);
+
+            Ok(data)
+        }
+    }
+
+    fn read_block_range(
+        &self,
+        bn: BlockNumber,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        if bn > self.block_count {
+            Err(format_err!("request for bogus block {}", bn))
+        } else {
+            let path = path_for_block(&self.root_path, bn);
+            debug!("reading {} bytes at {} from {:?}", len, offset, path);
+
+            let mut file = match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.is_unwritten(bn) => {
+                    let size = self.block_size as usize;
+                    return Ok(vec![0; len.min(size.saturating_sub(offset))]);
                 }
+                Err(e) => return Err(e.into()),
             };
 
+            // The tag covers the whole block, so the whole block is read to check it.
+            let cipher = self.cipher_for(bn);
+            if cipher.tag_size() > 0 {
+                let mut sealed = vec![];
+                file.read_to_end(&mut sealed)?;
+                let data = unseal(cipher, &self.key, bn, self.block_size, sealed)?;
+                let start = offset.min(data.len());
+                let end = offset.saturating_add(len).min(data.len());
+                return Ok(data[start..end].to_vec());
+            }
+
+            let mut nonce = Vec::with_capacity(NONCE_SIZE);
+            (&mut file)
+                .take(NONCE_SIZE as u64)
+                .read_to_end(&mut nonce)?;
+            if nonce.is_empty() {
+                return Ok(nonce);
+            }
+            if nonce.len() < NONCE_SIZE {
+                return Err(format_err!("block 0x{:x?} is missing its nonce", bn));
+            }
+
+            file.seek(SeekFrom::Start((NONCE_SIZE + offset) as u64))?;
+            let mut data = Vec::with_capacity(len);
+            file.take(len as u64).read_to_end(&mut data)?;
+
+            // The cipher is seekable, so we can decrypt from the middle of the block.
+            cipher.apply(
+                &self.key,
+                &nonce,
+                bn * self.block_size as u64 + offset as u64,
+                &mut data,
+            );
+
             debug!("read {} bytes from block 0x{:x?}", data.len(), bn);
             trace!("{:?}", data);
 
@@ -452,7 +937,11 @@ impl BlockReader for FileStore {
 mod test {
     use hex_literal::hex;
 
-    use crate::UfsUuid;
+    use crate::{
+        block::storage::FORMAT_VERSION,
+        crypto::{KdfParams, TAG_SIZE},
+        UfsUuid,
+    };
 
     use super::*;
 
@@ -503,12 +992,6 @@ mod test {
             7a13479a462d71b56c19a74a40b655c58edfe0a188ad2cf46cbf30524f65d423c837dd1ff2bf462ac4198007
             345bb44dbb7b1c861298cdf61982a833afc728fae1eda2f87aa2c9480858bec"
         );
-        let enciphered = hex!(
-            "6a1a099ad6c2d71922c32c892fe694e47dd60dadde08e5f9393e41f1aa57534f39b72c0d5e08af1c3155564
-            b247499a0327773baa0a4515ee18996b660c7d84b36aaf4d6b585cd0da20e1a383588d8e9040d8748746f121
-            0a73c71107033efab4d23ebc841f3f738dfeaa1192d97ca2b8f7f49d100b8c785d3adb2c1a45d00c7b335c4c
-            6d8296ca9550fe0c01254599bc499b1890cbd63462647bbc1075547011b3bf7"
-        );
 
         fs::remove_dir_all(&test_dir).unwrap_or_default();
         let mut fs = FileStore::new(
@@ -524,13 +1007,47 @@ mod test {
         path.push("0");
         path.push("7");
         path.set_extension(BLOCK_EXT);
+        let mut enciphered = fs::read(path).unwrap();
+        let header = NONCE_SIZE + TAG_SIZE;
+        assert_eq!(header + data.len(), enciphered.len());
+        assert_ne!(&data[..], &enciphered[header..]);
+
+        let nonce = enciphered[..NONCE_SIZE].to_vec();
+        Cipher::XChaCha20HmacSha256.apply(&fs.key, &nonce, 7 * 512, &mut enciphered[header..]);
         assert_eq!(
-            fs::read(path).unwrap(),
-            &enciphered[..],
+            &enciphered[header..],
+            &data[..],
             "API write to block, and compare directly"
         );
     }
 
+    #[test]
+    fn rewrite_block() {
+        let test_dir = [TEST_ROOT, "rewrite_block"].concat();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let mut fs = FileStore::new(
+            "foobar",
+            &test_dir,
+            BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 0x10),
+        )
+        .unwrap();
+
+        fs.write_block(7, &[0x42; 512][..]).unwrap();
+        let first = fs::read(fs.block_path(7)).unwrap();
+        assert_eq!(vec![0x42; 512], fs.read_block(7).unwrap());
+
+        fs.write_block(7, &[0x43; 512][..]).unwrap();
+        let second = fs::read(fs.block_path(7)).unwrap();
+        assert_eq!(vec![0x43; 512], fs.read_block(7).unwrap());
+        assert_eq!(vec![0x43; 12], fs.read_block_range(7, 500, 42).unwrap());
+
+        assert_ne!(
+            &first[..NONCE_SIZE],
+            &second[..NONCE_SIZE],
+            "each write gets a fresh nonce"
+        );
+    }
+
     #[test]
     fn read_block() {
         let test_dir = [TEST_ROOT, "read_block"].concat();
@@ -548,19 +1065,27 @@ mod test {
         );
 
         fs::remove_dir_all(&test_dir).unwrap_or_default();
-        let fs = FileStore::new(
+        let mut fs = FileStore::new(
             "foobar",
             &test_dir,
-            BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 0x10),
+            BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 0x10)
+                .with_cipher(Cipher::XChaCha20),
         )
         .unwrap();
+        // The block was enciphered with this key, which is what PBKDF2 made of "foobar".
+        fs.key = hex!("1001c46bd12c22cf5991f1b4b2f70cd416e20a1312961ff958989f6f8f424dbe");
+
+        // Manually write the block to the file system, behind the nonce that it was sealed with.
+        let id = UfsUuid::new_root_fs("test");
+        let mut sealed = id.as_bytes().to_vec();
+        sealed.extend_from_slice(&id.as_bytes()[0..8]);
+        sealed.extend_from_slice(&enciphered[..]);
 
-        // Manually write the block to the file system
         let mut path = PathBuf::from(&test_dir);
         path.push("0");
         path.push("7");
         path.set_extension(BLOCK_EXT);
-        fs::write(path, &enciphered[..]).unwrap();
+        fs::write(path, &sealed).unwrap();
 
         assert_eq!(
             fs.read_block(7).unwrap(),
@@ -569,6 +1094,357 @@ mod test {
         );
     }
 
+    #[test]
+    fn aes_cipher() {
+        let test_dir = [TEST_ROOT, "aes_cipher"].concat();
+        let data = [0x42; BlockSize::FiveTwelve as usize];
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let map = BlockMap::new(
+            UfsUuid::new_root_fs("aes_cipher"),
+            BlockSize::FiveTwelve,
+            0x10,
+        );
+        let cipher = Cipher::Aes256CtrHmacSha256;
+        let mut fs = FileStore::new_sparse("foobar", &test_dir, map.with_cipher(cipher)).unwrap();
+        fs.write_block(7, &data[..]).unwrap();
+
+        let mut sealed = fs::read(fs.block_path(7)).unwrap();
+        let header = NONCE_SIZE + TAG_SIZE;
+        let nonce = sealed[..NONCE_SIZE].to_vec();
+        cipher.apply(&fs.key, &nonce, 7 * 512, &mut sealed[header..]);
+        assert_eq!(&data[..], &sealed[header..]);
+
+        // The choice is found in the block map when the store is loaded.
+        fs.commit_map();
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(cipher, fs.map().cipher());
+        assert_eq!(&data[..], &fs.read_block(7).unwrap()[..]);
+        assert_eq!(vec![0x42; 12], fs.read_block_range(7, 500, 42).unwrap());
+    }
+
+    #[test]
+    fn tampered_block() {
+        let test_dir = [TEST_ROOT, "tampered_block"].concat();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let mut fs = FileStore::new_sparse(
+            "foobar",
+            &test_dir,
+            BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 0x10),
+        )
+        .unwrap();
+        fs.write_block(7, &[0x42; 512][..]).unwrap();
+        fs.write_block(8, &[0x42; 512][..]).unwrap();
+
+        // Flip a bit of ciphertext.
+        let mut sealed = fs::read(fs.block_path(7)).unwrap();
+        sealed[NONCE_SIZE + TAG_SIZE + 100] ^= 1;
+        fs::write(fs.block_path(7), &sealed).unwrap();
+        assert!(fs.read_block(7).is_err());
+        assert!(fs.read_block_range(7, 0, 10).is_err());
+
+        // A block moved to another number doesn't pass either.
+        fs::copy(fs.block_path(8), fs.block_path(9)).unwrap();
+        assert!(fs.read_block(9).is_err());
+        assert_eq!(vec![0x42; 512], fs.read_block(8).unwrap());
+    }
+
+    #[test]
+    fn large_blocks() {
+        let test_dir = [TEST_ROOT, "large_blocks"].concat();
+        let data = (0..BlockSize::OneMeg as usize)
+            .map(|b| b as u8)
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let mut fs = FileStore::new_sparse(
+            "foobar",
+            &test_dir,
+            BlockMap::new(
+                UfsUuid::new_root_fs("large_blocks"),
+                BlockSize::OneMeg,
+                0x10,
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            BlockSize::OneMeg as BlockSizeType,
+            fs.write_block(3, &data[..]).unwrap()
+        );
+
+        // The block map doesn't fill block 0, which is how the block size is usually inferred.
+        fs.commit_map();
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(BlockSize::OneMeg, fs.block_size());
+        assert_eq!(data, fs.read_block(3).unwrap());
+        assert_eq!(
+            &data[0xfff00..0xfff10],
+            &fs.read_block_range(3, 0xfff00, 0x10).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn read_block_range() {
+        let test_dir = [TEST_ROOT, "read_block_range"].concat();
+        let data = (0..512).map(|b| b as u8).collect::<Vec<_>>();
+
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let mut fs = FileStore::new(
+            "foobar",
+            &test_dir,
+            BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 0x10),
+        )
+        .unwrap();
+        fs.write_block(7, &data[..]).unwrap();
+
+        let block = fs.read_block(7).unwrap();
+        assert_eq!(fs.read_block_range(7, 100, 42).unwrap(), &block[100..142]);
+        assert_eq!(fs.read_block_range(7, 500, 42).unwrap(), &block[500..]);
+        assert!(fs.read_block_range(7, 600, 42).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sparse() {
+        let test_dir = [TEST_ROOT, "sparse"].concat();
+        let data = [0x42; BlockSize::FiveTwelve as usize];
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let mut fs = FileStore::new_sparse(
+            "foobar",
+            &test_dir,
+            // The id must match the directory name, for `load`.
+            BlockMap::new(UfsUuid::new_root_fs("sparse"), BlockSize::FiveTwelve, 0x100),
+        )
+        .unwrap();
+
+        // Only the block map has been written.
+        assert_eq!(1, fs.block_files());
+        assert!(!path_for_block(&fs.root_path, 0x42).exists());
+        assert_eq!(vec![0; 512], fs.read_block(0x42).unwrap());
+        assert_eq!(vec![0; 12], fs.read_block_range(0x42, 500, 42).unwrap());
+
+        fs.write_block(0x42, &data[..]).unwrap();
+        assert_eq!(2, fs.block_files());
+        assert_eq!(&data[..], &fs.read_block(0x42).unwrap()[..]);
+
+        // The counts come from the block map, not the files.
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(0x100 - 1, fs.map().free_blocks().len());
+        assert_eq!(2, fs.block_files());
+        assert!(FileStore::check("foobar", &test_dir, None, false, false).is_ok());
+        let user = Some(("test", "foobar"));
+        assert!(FileStore::check("foobar", &test_dir, user, false, false).is_ok());
+
+        // Growing doesn't write anything.
+        let mut fs = fs;
+        fs.grow(0x100).unwrap();
+        assert_eq!(0x200, fs.block_count());
+        assert_eq!(2, fs.block_files());
+        assert_eq!(vec![0; 512], fs.read_block(0x1ff).unwrap());
+
+        // An allocated block that's gone missing was lost, not unwritten.
+        fs.map_mut().get_mut(0x42).unwrap().tag_data();
+        fs::remove_file(fs.block_path(0x42)).unwrap();
+        assert!(fs.read_block(0x42).is_err());
+        assert!(fs.read_block_range(0x42, 500, 42).is_err());
+    }
+
+    #[test]
+    fn missing_block() {
+        let test_dir = [TEST_ROOT, "missing_block"].concat();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let fs = FileStore::new(
+            "foobar",
+            &test_dir,
+            BlockMap::new(
+                UfsUuid::new_root_fs("missing_block"),
+                BlockSize::FiveTwelve,
+                0x10,
+            ),
+        )
+        .unwrap();
+
+        // Every block of a store that isn't sparse exists, even if it's free.
+        fs::remove_file(fs.block_path(7)).unwrap();
+        assert!(fs.read_block(7).is_err());
+        assert!(fs.read_block_range(7, 0, 42).is_err());
+        assert_eq!(Vec::<u8>::new(), fs.read_block(8).unwrap());
+    }
+
+    #[test]
+    fn interrupted_rotation() {
+        let test_dir = [TEST_ROOT, "interrupted_rotation"].concat();
+        let data = [0x42; BlockSize::FiveTwelve as usize];
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let id = UfsUuid::new_root_fs("interrupted_rotation");
+        let mut fs = FileStore::new_sparse(
+            "foobar",
+            &test_dir,
+            BlockMap::new(id, BlockSize::FiveTwelve, 8),
+        )
+        .unwrap();
+        fs.write_block(3, &data[..]).unwrap();
+
+        // Nothing changes until the rotation is committed.
+        let mut header = StoreHeader::new(true, fs.header.cipher());
+        let key = header.key("barfoo").unwrap();
+        header.sign(&key).unwrap();
+        fs.stage_rotation(&header, &key, |bn, data| {
+            unseal(fs.cipher_for(bn), &fs.key, bn, fs.block_size, data)
+        })
+        .unwrap();
+        fs::remove_file(Path::new(&test_dir).join(ROTATE_MARKER)).unwrap();
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(&data[..], &fs.read_block(3).unwrap()[..]);
+
+        // Once it is, loading finishes the job.
+        fs.stage_rotation(&header, &key, |bn, data| {
+            unseal(fs.cipher_for(bn), &fs.key, bn, fs.block_size, data)
+        })
+        .unwrap();
+        fs::rename(
+            path_for_block(&fs.root_path, 3).with_extension(ROTATE_EXT),
+            fs.block_path(3),
+        )
+        .unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+        assert!(!Path::new(&test_dir).join(ROTATE_MARKER).exists());
+        let mut fs = FileStore::load("barfoo", &test_dir).unwrap();
+        assert_eq!(&data[..], &fs.read_block(3).unwrap()[..]);
+
+        assert!(fs.rotate_key("foobar", "baz").is_err());
+        fs.rotate_key("barfoo", "baz").unwrap();
+        let fs = FileStore::load("baz", &test_dir).unwrap();
+        assert_eq!(&data[..], &fs.read_block(3).unwrap()[..]);
+    }
+
+    /// Writes blocks as format version 1 did
+    ///
+    struct FileWriterV1 {
+        key: [u8; 32],
+        id: UfsUuid,
+        block_size: BlockSize,
+        root_path: PathBuf,
+    }
+
+    impl BlockWriter for FileWriterV1 {
+        fn write_block<T>(
+            &mut self,
+            bn: BlockNumber,
+            data: T,
+        ) -> Result<BlockSizeType, failure::Error>
+        where
+            T: AsRef<[u8]>,
+        {
+            let data = data.as_ref().to_vec();
+            let len = data.len() as BlockSizeType;
+            let data = crypt_v1(&self.key, &self.id, bn, self.block_size, data);
+            write_block_file(&self.root_path, bn, &data)?;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn upgrade_v1() {
+        let test_dir = [TEST_ROOT, "upgrade_v1"].concat();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        let root_path = PathBuf::from(&test_dir);
+        let id = UfsUuid::new_root_fs("upgrade_v1");
+        let key = StoreHeader::legacy(&id).key("foobar").unwrap();
+
+        FileStore::init(&root_path, BlockSize::FiveTwelve, 0x10).unwrap();
+        let mut writer = FileWriterV1 {
+            key,
+            id,
+            block_size: BlockSize::FiveTwelve,
+            root_path: root_path.clone(),
+        };
+        let data = [0x42; 100];
+        writer.write_block(5, &data[..]).unwrap();
+        let mut map = BlockMap::new(id, BlockSize::FiveTwelve, 0x10);
+        map.serialize(&mut writer).unwrap();
+
+        // Nothing is touched without the password.
+        assert!(FileStore::load("barfoo", &test_dir).is_err());
+        assert_eq!(None, read_header(&root_path).unwrap());
+
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(FORMAT_VERSION, fs.header.version);
+        assert_eq!(KdfParams::default(), fs.header.kdf);
+        assert_ne!(key, fs.key);
+        assert_eq!(map, fs.map);
+        assert_eq!(data.to_vec(), fs.read_block(5).unwrap());
+
+        // Once upgraded, it loads as is.
+        let fs = FileStore::load("foobar", &test_dir).unwrap();
+        assert_eq!(data.to_vec(), fs.read_block(5).unwrap());
+        assert!(!root_path.join(ROTATE_MARKER).exists());
+    }
+
+    #[test]
+    fn header() {
+        let test_dir = [TEST_ROOT, "header"].concat();
+        let other_dir = [TEST_ROOT, "header_other"].concat();
+        fs::remove_dir_all(&test_dir).unwrap_or_default();
+        fs::remove_dir_all(&other_dir).unwrap_or_default();
+        let id = UfsUuid::new_root_fs("header");
+        let fs = FileStore::new_sparse(
+            "foobar",
+            &test_dir,
+            BlockMap::new(id, BlockSize::FiveTwelve, 8),
+        )
+        .unwrap();
+        let other = FileStore::new_sparse(
+            "foobar",
+            &other_dir,
+            BlockMap::new(id, BlockSize::FiveTwelve, 8),
+        )
+        .unwrap();
+
+        // The same name, and password, don't make the same key.
+        assert_ne!(fs.header.salt, other.header.salt);
+        assert_ne!(fs.key, other.key);
+        assert_eq!(Some(fs.header.clone()), read_header(&fs.root_path).unwrap());
+
+        // The key is derived with the parameters in the header.
+        let mut header = fs.header.clone();
+        header.kdf = KdfParams::Argon2id {
+            mem_cost: 8,
+            time_cost: 1,
+            lanes: 1,
+        };
+        write_header(&fs.root_path.join(HEADER_FILE), &header).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+        write_header(&fs.root_path.join(HEADER_FILE), &fs.header).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_ok());
+
+        // The header may not be downgraded to a weaker cipher.
+        header = fs.header.clone();
+        header.cipher = Cipher::XChaCha20;
+        write_header(&fs.root_path.join(HEADER_FILE), &header).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+        write_header(&fs.root_path.join(HEADER_FILE), &fs.header).unwrap();
+
+        // Nor may the block map disagree with it.
+        let mut writer = FileWriter {
+            key: fs.key,
+            cipher: fs.header.cipher(),
+            block_size: fs.block_size,
+            block_count: fs.block_count,
+            root_path: fs.root_path.clone(),
+        };
+        let mut map = fs.map.clone().with_cipher(Cipher::XChaCha20);
+        map.serialize(&mut writer).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+        fs.map.clone().serialize(&mut writer).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_ok());
+
+        // Nor are stores in other formats loaded.
+        header = fs.header.clone();
+        header.version += 1;
+        write_header(&fs.root_path.join(HEADER_FILE), &header).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+        fs::remove_file(fs.root_path.join(HEADER_FILE)).unwrap();
+        assert!(FileStore::load("foobar", &test_dir).is_err());
+    }
+
     #[test]
     fn construction_sanity() {
         let test_dir = [TEST_ROOT, "construction_sanity"].concat();
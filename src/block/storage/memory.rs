@@ -2,11 +2,15 @@
 //!
 //! This is a toy that is useful for testing.  The blocks are stored in a Vec.
 //!
+//! The store holds no more than `block_count` blocks. Memory for a block is only taken when it's
+//! first written, and a rewrite replaces what was there, so a store never uses more than
+//! `block_count` blocks' worth of memory. Writing past the end fails as the disk being full.
+//!
 //! FIXME: Note that using serde, I could serialize this entire memory structure, and get a bundle
 //! implementation, albeit one that would be memory constrained.
 
 use failure::format_err;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 
 use crate::{
     block::{
@@ -14,6 +18,7 @@ use crate::{
         BlockWriter,
     },
     uuid::UfsUuid,
+    IOFSErrorKind,
 };
 
 /// An in-memory [BlockStorage]
@@ -42,9 +47,7 @@ impl MemoryStore {
             id: map.id().clone(),
             block_size: map.block_size(),
             block_count: map.block_count(),
-            blocks: (0..map.block_count())
-                .map(|_| Vec::with_capacity(map.block_size() as usize))
-                .collect(),
+            blocks: vec![Vec::new(); map.block_count() as usize],
             map,
         }
     }
@@ -72,6 +75,14 @@ impl BlockStorage for MemoryStore {
     fn block_size(&self) -> BlockSize {
         self.block_size
     }
+
+    fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+        self.blocks
+            .resize(self.blocks.len() + additional as usize, Vec::new());
+        self.block_count += additional;
+        self.map.grow(additional);
+        Ok(())
+    }
 }
 
 impl BlockWriter for MemoryStore {
@@ -92,13 +103,18 @@ impl BlockWriter for MemoryStore {
         }
 
         if let Some(memory) = self.blocks.get_mut(bn as usize) {
+            memory.clear();
             memory.extend_from_slice(data);
 
             debug!("wrote {} bytes to block {}", data.len(), bn);
             trace!("{:#?}", data);
             Ok(data.len() as BlockSizeType)
         } else {
-            Err(format_err!("request for bogus block {}", bn))
+            warn!(
+                "write to block {}, beyond the {} in the store",
+                bn, self.block_count
+            );
+            Err(IOFSErrorKind::NoSpace.into())
         }
     }
 }
@@ -139,6 +155,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn honors_block_count() {
+        let map = BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 3);
+        let mut ms = MemoryStore::new(map);
+
+        for bn in 0..3 {
+            ms.write_block(bn, &[0x42; BlockSize::FiveTwelve as usize][..])
+                .unwrap();
+        }
+        let e = ms.write_block(3, &[0x42; 4][..]).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        assert_eq!(3, ms.blocks.len());
+
+        // Rewriting a block replaces it.
+        ms.write_block(1, &[0x43; 4][..]).unwrap();
+        assert_eq!(vec![0x43; 4], ms.read_block(1).unwrap());
+
+        ms.grow(1).unwrap();
+        ms.write_block(3, &[0x44; 4][..]).unwrap();
+        assert_eq!(vec![0x44; 4], ms.read_block(3).unwrap());
+    }
+
     #[test]
     fn block_too_bukoo() {
         let map = BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, 3);
@@ -0,0 +1,395 @@
+//! Mirrored Block Storage
+//!
+//! Every block is written to two stores, the primary and the secondary. Blocks are read from the
+//! primary, and should that fail, or should the block not match the hash in the block map, the
+//! secondary is tried. A block that is read from the secondary is written back to the primary, so
+//! that it's repaired.
+//!
+//! A write only fails if the primary can't be written. Blocks that the secondary couldn't take are
+//! remembered, and copied over from the primary when the block map is next committed. Until then,
+//! they aren't read from the secondary.
+//!
+//! Reading only needs an immutable reference, so repairs are held until the store is next written
+//! to. Until then, the repaired block is served from memory.
+//!
+//! The block map belongs to the primary. It's copied to the secondary whenever it's committed.
+use {
+    failure::format_err,
+    log::{debug, error, warn},
+    std::{
+        collections::{HashMap, HashSet},
+        sync::Mutex,
+    },
+};
+
+use crate::{
+    block::{
+        map::BlockMap, BlockCardinality, BlockNumber, BlockReader, BlockSize, BlockSizeType,
+        BlockStorage, BlockWriter,
+    },
+    uuid::UfsUuid,
+};
+
+/// A [BlockStorage] that mirrors blocks across two others
+///
+/// A natural arrangement is a `FileStore` as the primary, and a remote store as the secondary, so
+/// that there is always a replica of the file system someplace else.
+#[derive(Debug)]
+pub struct MirrorStore<A: BlockStorage, B: BlockStorage> {
+    primary: A,
+    secondary: B,
+    repairs: Mutex<HashMap<BlockNumber, Vec<u8>>>,
+    stale: HashSet<BlockNumber>,
+}
+
+impl<A: BlockStorage, B: BlockStorage> MirrorStore<A, B> {
+    /// Create a new MirrorStore
+    ///
+    /// It's an error if the stores don't agree on their block size and block count.
+    pub fn new(primary: A, secondary: B) -> Result<Self, failure::Error> {
+        if primary.block_size() != secondary.block_size() {
+            return Err(format_err!(
+                "mirrored stores have different block sizes: {:?} and {:?}",
+                primary.block_size(),
+                secondary.block_size()
+            ));
+        }
+        if primary.block_count() != secondary.block_count() {
+            return Err(format_err!(
+                "mirrored stores have different block counts: {} and {}",
+                primary.block_count(),
+                secondary.block_count()
+            ));
+        }
+
+        Ok(MirrorStore {
+            primary,
+            secondary,
+            repairs: Mutex::new(HashMap::new()),
+            stale: HashSet::new(),
+        })
+    }
+
+    /// Return true if `data` matches the hash of block `bn` in the block map
+    ///
+    /// Blocks written without a hash are taken on faith.
+    fn is_sound(&self, bn: BlockNumber, data: &[u8]) -> bool {
+        self.map()
+            .get(bn)
+            .and_then(|block| block.hash())
+            .map_or(true, |hash| hash.validate(data))
+    }
+
+    /// Copy blocks that the secondary missed over from the primary
+    ///
+    fn resync(&mut self) {
+        let stale = self.stale.drain().collect::<Vec<_>>();
+        for bn in stale {
+            let copied = self
+                .primary
+                .read_block(bn)
+                .and_then(|data| self.secondary.write_block(bn, &data));
+            match copied {
+                Ok(_) => debug!("resynchronized block {}", bn),
+                Err(e) => {
+                    warn!("unable to resynchronize block {}: {}", bn, e);
+                    self.stale.insert(bn);
+                }
+            }
+        }
+    }
+
+    /// Write blocks read from the secondary back to the primary
+    ///
+    fn repair(&mut self) {
+        let repairs = self
+            .repairs
+            .get_mut()
+            .expect("poisoned repair lock")
+            .drain()
+            .collect::<Vec<_>>();
+        for (bn, data) in repairs {
+            match self.primary.write_block(bn, &data) {
+                Ok(_) => debug!("repaired block {}", bn),
+                Err(e) => error!("unable to repair block {}: {}", bn, e),
+            }
+        }
+    }
+}
+
+impl<A: BlockStorage, B: BlockStorage> BlockStorage for MirrorStore<A, B> {
+    fn id(&self) -> &UfsUuid {
+        self.primary.id()
+    }
+
+    fn commit_map(&mut self) {
+        self.repair();
+        self.resync();
+        *self.secondary.map_mut() = self.primary.map().clone();
+        self.primary.commit_map();
+        self.secondary.commit_map();
+    }
+
+    fn map(&self) -> &BlockMap {
+        self.primary.map()
+    }
+
+    fn map_mut(&mut self) -> &mut BlockMap {
+        self.primary.map_mut()
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.primary.block_size()
+    }
+
+    fn block_count(&self) -> BlockCardinality {
+        self.primary.block_count()
+    }
+
+    fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+        self.primary.grow(additional)?;
+        self.secondary.grow(additional)
+    }
+}
+
+impl<A: BlockStorage, B: BlockStorage> BlockWriter for MirrorStore<A, B> {
+    fn write_block<T>(&mut self, bn: BlockNumber, data: T) -> Result<BlockSizeType, failure::Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.repairs
+            .get_mut()
+            .expect("poisoned repair lock")
+            .remove(&bn);
+        self.repair();
+
+        let data = data.as_ref();
+        let written = self.primary.write_block(bn, data)?;
+        match self.secondary.write_block(bn, data) {
+            Ok(_) => {
+                self.stale.remove(&bn);
+            }
+            Err(e) => {
+                warn!("unable to write block {} to secondary: {}", bn, e);
+                self.stale.insert(bn);
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<A: BlockStorage, B: BlockStorage> BlockReader for MirrorStore<A, B> {
+    fn read_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error> {
+        if let Some(data) = self.repairs.lock().expect("poisoned repair lock").get(&bn) {
+            return Ok(data.clone());
+        }
+
+        let e = match self.primary.read_block(bn) {
+            Ok(data) => {
+                if self.is_sound(bn, &data) {
+                    return Ok(data);
+                }
+                format_err!("hash mismatch")
+            }
+            Err(e) => e,
+        };
+
+        warn!("unable to read block {} from primary: {}", bn, e);
+        if self.stale.contains(&bn) {
+            return Err(e);
+        }
+        let data = self.secondary.read_block(bn)?;
+        if !self.is_sound(bn, &data) {
+            return Err(format_err!("block {} is damaged in both stores", bn));
+        }
+        self.repairs
+            .lock()
+            .expect("poisoned repair lock")
+            .insert(bn, data.clone());
+        Ok(data)
+    }
+
+    fn read_block_range(
+        &self,
+        bn: BlockNumber,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let repairing = self
+            .repairs
+            .lock()
+            .expect("poisoned repair lock")
+            .contains_key(&bn);
+        // Only whole blocks can be checked against their hash.
+        let hashed = self
+            .map()
+            .get(bn)
+            .map_or(false, |block| block.hash().is_some());
+        if !repairing && !hashed {
+            if let Ok(data) = self.primary.read_block_range(bn, offset, len) {
+                return Ok(data);
+            }
+        }
+
+        let data = self.read_block(bn)?;
+        let start = offset.min(data.len());
+        let end = offset.saturating_add(len).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::block::{BlockHash, MemoryStore},
+    };
+
+    /// A MemoryStore with blocks that can't be read, until they are rewritten
+    ///
+    /// When `broken`, it can't be written either.
+    struct FlakyStore {
+        store: MemoryStore,
+        bad: Mutex<HashSet<BlockNumber>>,
+        broken: bool,
+    }
+
+    impl FlakyStore {
+        fn new(count: BlockCardinality) -> Self {
+            FlakyStore {
+                store: memory_store(count),
+                bad: Mutex::new(HashSet::new()),
+                broken: false,
+            }
+        }
+    }
+
+    impl BlockStorage for FlakyStore {
+        fn id(&self) -> &UfsUuid {
+            self.store.id()
+        }
+
+        fn commit_map(&mut self) {}
+
+        fn map(&self) -> &BlockMap {
+            self.store.map()
+        }
+
+        fn map_mut(&mut self) -> &mut BlockMap {
+            self.store.map_mut()
+        }
+
+        fn block_size(&self) -> BlockSize {
+            self.store.block_size()
+        }
+
+        fn block_count(&self) -> BlockCardinality {
+            self.store.block_count()
+        }
+
+        fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+            self.store.grow(additional)
+        }
+    }
+
+    impl BlockWriter for FlakyStore {
+        fn write_block<T>(
+            &mut self,
+            bn: BlockNumber,
+            data: T,
+        ) -> Result<BlockSizeType, failure::Error>
+        where
+            T: AsRef<[u8]>,
+        {
+            if self.broken {
+                return Err(format_err!("block {} is unwritable", bn));
+            }
+            self.bad.lock().unwrap().remove(&bn);
+            self.store.write_block(bn, data)
+        }
+    }
+
+    impl BlockReader for FlakyStore {
+        fn read_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error> {
+            if self.bad.lock().unwrap().contains(&bn) {
+                Err(format_err!("block {} is unreadable", bn))
+            } else {
+                self.store.read_block(bn)
+            }
+        }
+    }
+
+    fn memory_store(count: BlockCardinality) -> MemoryStore {
+        let map = BlockMap::new(UfsUuid::new_root_fs("test"), BlockSize::FiveTwelve, count);
+        MemoryStore::new(map)
+    }
+
+    #[test]
+    fn mismatched_stores() {
+        assert!(MirrorStore::new(memory_store(3), memory_store(4)).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_secondary() {
+        let mut mirror = MirrorStore::new(FlakyStore::new(3), memory_store(3)).unwrap();
+
+        mirror.write_block(1, &[0x42; 4][..]).unwrap();
+        assert_eq!(vec![0x42; 4], mirror.secondary.read_block(1).unwrap());
+
+        // The primary can't read the block, but the secondary has it.
+        mirror.primary.bad.lock().unwrap().insert(1);
+        assert_eq!(vec![0x42; 4], mirror.read_block(1).unwrap());
+        assert_eq!(vec![0x42, 0x42], mirror.read_block_range(1, 1, 2).unwrap());
+        assert!(mirror.primary.read_block(1).is_err());
+
+        // The next write repairs the primary.
+        mirror.write_block(2, &[0x43; 4][..]).unwrap();
+        assert_eq!(vec![0x42; 4], mirror.primary.read_block(1).unwrap());
+        assert!(mirror.repairs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn damaged_primary() {
+        let mut mirror = MirrorStore::new(memory_store(3), memory_store(3)).unwrap();
+        let data = [0x42; 4];
+        mirror.write_block(1, &data[..]).unwrap();
+        mirror
+            .map_mut()
+            .get_mut(1)
+            .unwrap()
+            .set_hash(Some(BlockHash::new(&data)));
+
+        // The primary reads without error, but the block doesn't match its hash.
+        mirror.primary.write_block(1, &[0x43; 4][..]).unwrap();
+        assert_eq!(vec![0x42; 4], mirror.read_block(1).unwrap());
+        assert_eq!(vec![0x42, 0x42], mirror.read_block_range(1, 1, 2).unwrap());
+
+        // If neither copy is any good, it's an error.
+        mirror.repairs.lock().unwrap().clear();
+        mirror.secondary.write_block(1, &[0x43; 4][..]).unwrap();
+        assert!(mirror.read_block(1).is_err());
+    }
+
+    #[test]
+    fn secondary_write_fails() {
+        let mut mirror = MirrorStore::new(FlakyStore::new(3), FlakyStore::new(3)).unwrap();
+
+        // The write succeeds, because the primary has the block.
+        mirror.secondary.broken = true;
+        mirror.write_block(1, &[0x42; 4][..]).unwrap();
+        assert!(mirror.stale.contains(&1));
+
+        // The secondary's copy is out of date, so it's no help.
+        mirror.primary.bad.lock().unwrap().insert(1);
+        assert!(mirror.read_block(1).is_err());
+
+        // It's brought up to date when the map is committed.
+        mirror.primary.bad.lock().unwrap().clear();
+        mirror.commit_map();
+        assert!(mirror.stale.contains(&1));
+        mirror.secondary.broken = false;
+        mirror.commit_map();
+        assert!(mirror.stale.is_empty());
+        assert_eq!(vec![0x42; 4], mirror.secondary.read_block(1).unwrap());
+    }
+}
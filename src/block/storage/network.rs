@@ -1,13 +1,22 @@
 //! Network based Block Storage
 //!
-//! This is how we fetch blocks from the network.
+//! This is how we fetch blocks from the network. Block requests may be retried when they fail, see
+//! `RetryPolicy`.
 //!
 use {
     failure::format_err,
-    log::{debug, error, trace},
-    reqwest::{header::CONTENT_TYPE, Client, IntoUrl, Url},
+    log::{debug, error, trace, warn},
+    rand::{thread_rng, Rng},
+    reqwest::{
+        header::{CONTENT_TYPE, RANGE},
+        Client, IntoUrl, Response, StatusCode, Url,
+    },
+    std::{thread::sleep, time::Duration},
 };
 
+#[cfg(test)]
+pub(crate) mod mock;
+
 use crate::{
     block::{
         map::BlockMap, BlockCardinality, BlockNumber, BlockReader, BlockSize, BlockSizeType,
@@ -16,6 +25,44 @@ use crate::{
     uuid::UfsUuid,
 };
 
+/// The longest that we'll wait between attempts at a block request
+///
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Retry Policy for Block Requests
+///
+/// A block request that fails, with a network error or an error from the server, is tried again
+/// after a delay. The first delay is `backoff`, and it doubles with each attempt. Up to half as
+/// much again is added at random, so that clients that failed together don't retry together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Make up to `attempts` attempts at each request, waiting `backoff` before the first retry
+    ///
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        RetryPolicy {
+            attempts: attempts.max(1),
+            backoff,
+        }
+    }
+
+    /// How long to wait after a failed `attempt`
+    ///
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .unwrap_or(MAX_RETRY_BACKOFF)
+            .min(MAX_RETRY_BACKOFF);
+        let jitter = thread_rng().gen_range(0, delay.as_millis() as u64 / 2 + 1);
+        delay + Duration::from_millis(jitter)
+    }
+}
+
 /// Network-based Block Storage
 ///
 pub struct NetworkStore {
@@ -25,10 +72,15 @@ pub struct NetworkStore {
     block_size: BlockSize,
     block_count: BlockCardinality,
     map: BlockMap,
+    retry: Option<RetryPolicy>,
 }
 
 impl NetworkStore {
-    pub fn new<S, U>(name: S, url: U) -> Result<Self, failure::Error>
+    /// Open a NetworkStore
+    ///
+    /// Failed block requests are retried according to `retry`. Without one, the first failure is
+    /// returned straight away.
+    pub fn new<S, U>(name: S, url: U, retry: Option<RetryPolicy>) -> Result<Self, failure::Error>
     where
         S: AsRef<str>,
         U: IntoUrl,
@@ -60,11 +112,119 @@ impl NetworkStore {
                     block_size: metadata.block_size(),
                     block_count: metadata.block_count(),
                     map: metadata,
+                    retry,
+                })
+            }
+            Err(e) => Err(format_err!("Bad URL: {}", e)),
+        }
+    }
+}
+
+impl NetworkStore {
+    /// Create a new NetworkStore
+    ///
+    /// An empty block map is written to the server, so that the store may later be opened with
+    /// `new`.
+    pub fn create<S, U>(
+        name: S,
+        url: U,
+        size: BlockSize,
+        count: BlockCardinality,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+        U: IntoUrl,
+    {
+        match url.into_url() {
+            Ok(u) => {
+                let url = u.join(name.as_ref())?;
+                let client = Client::builder().gzip(true).build()?;
+
+                let mut map = BlockMap::new(UfsUuid::new_root_fs(name.as_ref()), size, count);
+                let mut writer = NetworkWriter {
+                    url: url.clone(),
+                    client: client.clone(),
+                };
+                map.serialize(&mut writer)?;
+
+                Ok(NetworkStore {
+                    id: map.id().clone(),
+                    url,
+                    client,
+                    block_size: size,
+                    block_count: count,
+                    map,
+                    retry: None,
                 })
             }
             Err(e) => Err(format_err!("Bad URL: {}", e)),
         }
     }
+
+    /// Make a block request, retrying according to our `RetryPolicy`, if we have one
+    ///
+    /// `what` describes the request, for the logs, and for the error returned when we give up.
+    fn with_retry<T, F>(&self, what: &str, mut request: F) -> Result<T, failure::Error>
+    where
+        F: FnMut() -> Result<T, failure::Error>,
+    {
+        let policy = match self.retry {
+            Some(policy) => policy,
+            None => return request(),
+        };
+
+        let mut attempt = 1;
+        loop {
+            match request() {
+                Ok(result) => return Ok(result),
+                Err(e) if attempt < policy.attempts => {
+                    let delay = policy.delay(attempt);
+                    warn!(
+                        "{} failed, attempt {} of {}, retrying in {:?}: {}",
+                        what, attempt, policy.attempts, delay, e
+                    );
+                    sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(format_err!(
+                        "{} failed after {} attempts: {}",
+                        what,
+                        attempt,
+                        e
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Turn a server error into an `Err`
+///
+/// These are worth retrying, where client errors, e.g., asking for a block that doesn't exist, are
+/// not.
+fn check_status(resp: Response) -> Result<Response, failure::Error> {
+    if resp.status().is_server_error() {
+        Err(format_err!("server error {}", resp.status()))
+    } else {
+        Ok(resp)
+    }
+}
+
+/// Parse a `Range` header
+///
+/// Only a single range, with both ends specified, is supported: `bytes=<start>-<end>`. It's what
+/// `read_block_range` sends, and so all that a block server need answer.
+pub fn parse_range(header: &str) -> Option<(usize, usize)> {
+    let range = header.trim().trim_start_matches("bytes=");
+    let mut parts = range.splitn(2, '-');
+    let start = parts.next()?.parse::<usize>().ok()?;
+    let end = parts.next()?.parse::<usize>().ok()?;
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
 }
 
 impl BlockStorage for NetworkStore {
@@ -121,17 +281,20 @@ impl BlockWriter for NetworkStore {
         let mut url = self.url.clone();
         url.set_query(Some(&bn.to_string()));
 
-        let mut resp = self
-            .client
-            .post(url.as_str())
-            .header(CONTENT_TYPE, "application/octet-stream")
-            .body(data.to_vec())
-            .send()?;
-
-        match resp.text()?.parse::<BlockSizeType>() {
-            Ok(bytes_written) => Ok(bytes_written),
-            Err(e) => Err(format_err!("Could not parse result as BlockSize: {}", e)),
-        }
+        self.with_retry(&format!("writing block {} to {}", bn, self.url), || {
+            let mut resp = check_status(
+                self.client
+                    .post(url.as_str())
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body(data.to_vec())
+                    .send()?,
+            )?;
+
+            match resp.text()?.parse::<BlockSizeType>() {
+                Ok(bytes_written) => Ok(bytes_written),
+                Err(e) => Err(format_err!("Could not parse result as BlockSize: {}", e)),
+            }
+        })
     }
 }
 
@@ -142,11 +305,56 @@ impl BlockReader for NetworkStore {
         let mut url = self.url.clone();
         url.set_query(Some(&bn.to_string()));
 
-        let mut resp = self.client.get(url.as_str()).send()?;
-        let mut data: Vec<u8> = vec![];
-        resp.copy_to(&mut data)?;
+        self.with_retry(&format!("reading block {} from {}", bn, self.url), || {
+            let mut resp = check_status(self.client.get(url.as_str()).send()?)?;
+            let mut data: Vec<u8> = vec![];
+            resp.copy_to(&mut data)?;
+            Ok(data)
+        })
+    }
 
-        Ok(data)
+    fn read_block_range(
+        &self,
+        bn: BlockNumber,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, failure::Error> {
+        trace!(
+            "Reading {} bytes at {} of block number {} from {}.",
+            len,
+            offset,
+            bn,
+            &self.url.as_str()
+        );
+
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut url = self.url.clone();
+        url.set_query(Some(&bn.to_string()));
+
+        self.with_retry(&format!("reading block {} from {}", bn, self.url), || {
+            let mut resp = check_status(
+                self.client
+                    .get(url.as_str())
+                    .header(RANGE, format!("bytes={}-{}", offset, offset + len - 1))
+                    .send()?,
+            )?;
+            let mut data: Vec<u8> = vec![];
+            resp.copy_to(&mut data)?;
+
+            match resp.status() {
+                StatusCode::PARTIAL_CONTENT => Ok(data),
+                StatusCode::RANGE_NOT_SATISFIABLE => Ok(vec![]),
+                // The server ignored the range, and sent the entire block.
+                _ => {
+                    let start = offset.min(data.len());
+                    let end = offset.saturating_add(len).min(data.len());
+                    Ok(data[start..end].to_vec())
+                }
+            }
+        })
     }
 }
 
@@ -209,11 +417,23 @@ impl BlockReader for NetworkReader {
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    use super::{mock::MockBlockServer, *};
+
+    #[test]
+    fn range_header() {
+        assert_eq!(Some((37, 136)), parse_range("bytes=37-136"));
+        assert_eq!(Some((0, 0)), parse_range(" bytes=0-0 "));
+        assert_eq!(None, parse_range("bytes=136-37"));
+        assert_eq!(None, parse_range("bytes=37-"));
+        assert_eq!(None, parse_range("bytes=0-10,20-30"));
+    }
 
     #[test]
     fn read_and_write_block() {
-        let mut bs = NetworkStore::new("test", "http://localhost:8888").unwrap();
+        let server = MockBlockServer::start();
+        NetworkStore::create("test", server.url(), BlockSize::TwentyFortyEight, 100).unwrap();
+
+        let mut bs = NetworkStore::new("test", server.url(), None).unwrap();
         let block_number = 88;
         let expected = r#"ion<BlockCardinality>,
    pub directory: HashMap<String, Block>,
@@ -229,4 +449,40 @@ dictionary. Instead, it's legacy code that needs to be updated.
         let data = bs.read_block(block_number).unwrap();
         assert_eq!(data, expected.as_bytes());
     }
+
+    #[test]
+    fn retry() {
+        let server = MockBlockServer::start();
+        NetworkStore::create("test", server.url(), BlockSize::FiveTwelve, 100).unwrap();
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let mut bs = NetworkStore::new("test", server.url(), Some(policy)).unwrap();
+
+        server.fail_next(2);
+        bs.write_block(7, &[0x42; 512]).unwrap();
+        server.fail_next(2);
+        assert_eq!(vec![0x42; 512], bs.read_block(7).unwrap());
+
+        server.fail_next(3);
+        let e = bs.write_block(8, &[0x24; 512]).unwrap_err();
+        assert!(e.to_string().contains("after 3 attempts"), "{}", e);
+
+        // Without a policy, the first failure is the last.
+        let bs = NetworkStore::new("test", server.url(), None).unwrap();
+        server.fail_next(1);
+        assert!(bs.read_block(7).is_err());
+        assert_eq!(vec![0x42; 512], bs.read_block(7).unwrap());
+    }
+
+    #[test]
+    fn read_block_range() {
+        let server = MockBlockServer::start();
+        let mut bs =
+            NetworkStore::create("test", server.url(), BlockSize::FiveTwelve, 100).unwrap();
+        let data = (0..512).map(|b| b as u8).collect::<Vec<_>>();
+        bs.write_block(42, &data).unwrap();
+
+        assert_eq!(bs.read_block_range(42, 100, 42).unwrap(), &data[100..142]);
+        assert_eq!(bs.read_block_range(42, 500, 42).unwrap(), &data[500..]);
+        assert!(bs.read_block_range(42, 600, 42).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,150 @@
+//! Mock Block Server
+//!
+//! An in-process stand-in for `block-server`, so that the networked code paths may be tested
+//! without any external infrastructure. It speaks the same protocol: the path is the file system,
+//! and the query is the block number. Blocks live in memory, and vanish when the server is dropped.
+//! The server may be told to fail requests, see `fail_next`.
+use {
+    futures::{future, sync::oneshot, Future, Stream},
+    hyper::{
+        header::RANGE, service::service_fn, Body, Method, Request, Response, Server, StatusCode,
+    },
+    log::{debug, error},
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        thread::spawn,
+    },
+};
+
+use super::parse_range;
+use crate::block::BlockNumber;
+
+type Blocks = Arc<Mutex<HashMap<(String, BlockNumber), Vec<u8>>>>;
+type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// A Block Server Running on a Background Thread
+///
+/// The server listens on an ephemeral localhost port, and is shut down when dropped.
+pub(crate) struct MockBlockServer {
+    addr: SocketAddr,
+    stop_signal: Option<oneshot::Sender<()>>,
+    failures: Arc<Mutex<usize>>,
+}
+
+impl MockBlockServer {
+    pub(crate) fn start() -> Self {
+        let blocks: Blocks = Arc::new(Mutex::new(HashMap::new()));
+        let failures = Arc::new(Mutex::new(0));
+
+        let failing = failures.clone();
+        let new_service = move || {
+            let blocks = blocks.clone();
+            let failing = failing.clone();
+            service_fn(move |req| handle_request(req, &blocks, &failing))
+        };
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(new_service);
+        let addr = server.local_addr();
+
+        let (stop_signal, stop) = oneshot::channel::<()>();
+        let server = server
+            .with_graceful_shutdown(stop)
+            .map_err(|e| error!("mock block server error: {}", e));
+
+        spawn(move || hyper::rt::run(server));
+        debug!("mock block server listening on {}", addr);
+
+        MockBlockServer {
+            addr,
+            stop_signal: Some(stop_signal),
+            failures,
+        }
+    }
+
+    /// Answer the next `count` requests with `503 Service Unavailable`
+    ///
+    pub(crate) fn fail_next(&self, count: usize) {
+        *self.failures.lock().unwrap() = count;
+    }
+
+    /// The base URL of the server
+    ///
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+}
+
+impl Drop for MockBlockServer {
+    fn drop(&mut self) {
+        if let Some(stop_signal) = self.stop_signal.take() {
+            let _ = stop_signal.send(());
+        }
+    }
+}
+
+fn handle_request(req: Request<Body>, blocks: &Blocks, failures: &Mutex<usize>) -> BoxFut {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+
+    {
+        let mut failures = failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Box::new(future::ok(response));
+        }
+    }
+
+    let bundle = req.uri().path().to_owned();
+    let block = match req.uri().query().map(|q| q.parse::<BlockNumber>()) {
+        Some(Ok(block)) => block,
+        _ => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Box::new(future::ok(response));
+        }
+    };
+
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|header| header.to_str().ok())
+        .and_then(parse_range);
+
+    match req.method() {
+        &Method::GET => {
+            if let Some(data) = blocks.lock().unwrap().get(&(bundle, block)) {
+                match range {
+                    Some((start, _)) if start >= data.len() => {
+                        *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                    }
+                    Some((start, end)) => {
+                        let end = (end + 1).min(data.len());
+                        *response.body_mut() = Body::from(data[start..end].to_vec());
+                        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                    }
+                    None => {
+                        *response.body_mut() = Body::from(data.clone());
+                        *response.status_mut() = StatusCode::OK;
+                    }
+                }
+            }
+        }
+        &Method::POST => {
+            let blocks = blocks.clone();
+            let bytes_written = req.into_body().concat2().map(move |chunk| {
+                let data = chunk.iter().cloned().collect::<Vec<u8>>();
+                *response.body_mut() = Body::from(data.len().to_string());
+                *response.status_mut() = StatusCode::OK;
+                blocks.lock().unwrap().insert((bundle, block), data);
+                response
+            });
+
+            return Box::new(bytes_written);
+        }
+        _ => (),
+    }
+
+    Box::new(future::ok(response))
+}
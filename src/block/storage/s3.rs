@@ -0,0 +1,319 @@
+//! S3-based Block Storage
+//!
+//! Blocks are stored as objects in an S3 bucket, keyed by the file system id and the block number:
+//! block `42` of file system `fs` is the object `blocks/fs/42`. The block map is stored as a single
+//! object alongside them, `blocks/fs/map`, rather than chunked across blocks. The store header,
+//! from which the master key is derived, and which records the cipher that the block map is
+//! encrypted with, is the object `blocks/fs/header`.
+//!
+//! Blocks are encrypted before they are stored, just as with the `FileStore`, so the bucket never
+//! sees plain text. A block that has never been written reads as zeros.
+//!
+//! The requests are made synchronously, as are those of the `NetworkStore`.
+use {
+    failure::format_err,
+    log::{debug, error, trace},
+    rusoto_core::{Region, RusotoError},
+    rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3},
+    std::io::Read,
+};
+
+use crate::{
+    block::{
+        map::BlockMap,
+        storage::{
+            file::{seal, unseal},
+            StoreHeader,
+        },
+        BlockCardinality, BlockNumber, BlockReader, BlockSize, BlockSizeType, BlockStorage,
+        BlockWriter,
+    },
+    uuid::UfsUuid,
+};
+
+/// The name of the object holding the block map
+///
+const MAP_OBJECT: &str = "map";
+
+/// The name of the object holding the store header
+///
+const HEADER_OBJECT: &str = "header";
+
+/// The key of the object named `name`, belonging to the file system `id`
+///
+fn object_key(id: &UfsUuid, name: &str) -> String {
+    format!("blocks/{}/{}", id, name)
+}
+
+/// S3-based Block Storage
+///
+pub struct S3Store {
+    id: UfsUuid,
+    header: StoreHeader,
+    key: [u8; 32],
+    block_size: BlockSize,
+    block_count: BlockCardinality,
+    bucket: String,
+    client: S3Client,
+    map: BlockMap,
+}
+
+impl S3Store {
+    /// S3Store Constructor
+    ///
+    /// A new store, for the file system described by `map`, is created in `bucket`. Only the block
+    /// map is written up front.
+    pub fn new<S>(
+        password: S,
+        region: Region,
+        bucket: S,
+        map: BlockMap,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+    {
+        let mut header = StoreHeader::new(true, map.cipher());
+        let key = header.key(password.as_ref())?;
+        header.sign(&key)?;
+        let mut store = S3Store {
+            id: map.id().clone(),
+            header,
+            key,
+            block_size: map.block_size(),
+            block_count: map.block_count(),
+            bucket: bucket.as_ref().to_owned(),
+            client: S3Client::new(region),
+            map,
+        };
+
+        put_object(
+            &store.client,
+            &store.bucket,
+            object_key(&store.id, HEADER_OBJECT),
+            store.header.to_bytes()?,
+        )?;
+        store.write_map()?;
+        Ok(store)
+    }
+
+    /// Load an existing S3Store
+    ///
+    /// The file system id is derived from `name`, as it is for the other stores.
+    pub fn load<S>(password: S, name: S, region: Region, bucket: S) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+    {
+        let id = UfsUuid::new_root_fs(name.as_ref());
+        let client = S3Client::new(region);
+        let bucket = bucket.as_ref().to_owned();
+
+        let header = get_object(&client, &bucket, object_key(&id, HEADER_OBJECT))?
+            .ok_or_else(|| format_err!("no file system {} in bucket {}", name.as_ref(), bucket))?;
+        let header = StoreHeader::from_bytes(&header)?;
+        header.check_version()?;
+        let key = header.key(password.as_ref())?;
+        header.verify(&key)?;
+        let cipher = header.cipher();
+
+        let sealed = get_object(&client, &bucket, object_key(&id, MAP_OBJECT))?
+            .ok_or_else(|| format_err!("no file system {} in bucket {}", name.as_ref(), bucket))?;
+        let map: BlockMap = unseal(cipher, &key, 0, BlockSize::TwentyFortyEight, sealed)
+            .and_then(|data| BlockMap::from_bytes(&data))
+            .map_err(|e| {
+                error!(
+                    "Unable to load block map -- possibly incorrect master password?\nError: {}",
+                    e
+                );
+                format_err!("Unable to load block map -- possibly incorrect master password?")
+            })?;
+
+        if map.cipher() != cipher {
+            return Err(format_err!(
+                "the block map's cipher, {:?}, isn't the store's, {:?}",
+                map.cipher(),
+                cipher
+            ));
+        }
+
+        Ok(S3Store {
+            id: map.id().clone(),
+            header,
+            key,
+            block_size: map.block_size(),
+            block_count: map.block_count(),
+            bucket,
+            client,
+            map,
+        })
+    }
+
+    /// Write the block map to its object
+    ///
+    fn write_map(&mut self) -> Result<(), failure::Error> {
+        let data = self.map.to_bytes()?;
+        let sealed = seal(self.header.cipher(), &self.key, 0, self.block_size, &data);
+        put_object(
+            &self.client,
+            &self.bucket,
+            object_key(&self.id, MAP_OBJECT),
+            sealed,
+        )
+    }
+}
+
+/// Fetch an object from the bucket
+///
+/// `None` is returned if there is no such object.
+fn get_object(
+    client: &S3Client,
+    bucket: &str,
+    key: String,
+) -> Result<Option<Vec<u8>>, failure::Error> {
+    let request = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.clone(),
+        ..Default::default()
+    };
+
+    match client.get_object(request).sync() {
+        Ok(output) => {
+            let mut data = vec![];
+            if let Some(body) = output.body {
+                body.into_blocking_read().read_to_end(&mut data)?;
+            }
+            Ok(Some(data))
+        }
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Ok(None),
+        Err(e) => Err(format_err!(
+            "unable to get {} from bucket {}: {}",
+            key,
+            bucket,
+            e
+        )),
+    }
+}
+
+/// Store an object in the bucket
+///
+fn put_object(
+    client: &S3Client,
+    bucket: &str,
+    key: String,
+    data: Vec<u8>,
+) -> Result<(), failure::Error> {
+    let request = PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.clone(),
+        content_length: Some(data.len() as i64),
+        body: Some(data.into()),
+        ..Default::default()
+    };
+
+    client
+        .put_object(request)
+        .sync()
+        .map(|_| ())
+        .map_err(|e| format_err!("unable to put {} to bucket {}: {}", key, bucket, e))
+}
+
+impl BlockStorage for S3Store {
+    fn id(&self) -> &UfsUuid {
+        &self.id
+    }
+
+    fn commit_map(&mut self) {
+        debug!("writing BlockMap");
+        match self.write_map() {
+            Ok(_) => debug!("wrote BlockMap"),
+            Err(e) => error!("error writing BlockMap: {}", e),
+        }
+    }
+
+    fn map(&self) -> &BlockMap {
+        &self.map
+    }
+
+    fn map_mut(&mut self) -> &mut BlockMap {
+        &mut self.map
+    }
+
+    fn block_count(&self) -> BlockCardinality {
+        self.block_count
+    }
+
+    fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// Objects are created as they are written, so there's nothing to do but record the blocks
+    ///
+    fn grow(&mut self, additional: BlockCardinality) -> Result<(), failure::Error> {
+        self.block_count += additional;
+        self.map.grow(additional);
+        Ok(())
+    }
+}
+
+impl BlockWriter for S3Store {
+    fn write_block<T>(&mut self, bn: BlockNumber, data: T) -> Result<BlockSizeType, failure::Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let data = data.as_ref();
+        if bn >= self.block_count {
+            return Err(format_err!("request for bogus block {}", bn));
+        }
+        if data.len() > self.block_size as usize {
+            return Err(format_err!("data is larger than block size"));
+        }
+
+        let sealed = seal(self.map.cipher(), &self.key, bn, self.block_size, data);
+        put_object(
+            &self.client,
+            &self.bucket,
+            object_key(&self.id, &bn.to_string()),
+            sealed,
+        )?;
+
+        debug!("wrote {} bytes to block 0x{:x?}", data.len(), bn);
+        trace!("{:?}", data);
+        Ok(data.len() as BlockSizeType)
+    }
+}
+
+impl BlockReader for S3Store {
+    fn read_block(&self, bn: BlockNumber) -> Result<Vec<u8>, failure::Error> {
+        if bn >= self.block_count {
+            return Err(format_err!("request for bogus block {}", bn));
+        }
+
+        let key = object_key(&self.id, &bn.to_string());
+        let data = match get_object(&self.client, &self.bucket, key)? {
+            Some(sealed) => unseal(self.map.cipher(), &self.key, bn, self.block_size, sealed)?,
+            // Objects are only created as blocks are written.
+            None if self.map.get(bn).map_or(true, |block| block.is_free()) => {
+                vec![0; self.block_size as usize]
+            }
+            None => return Err(format_err!("block 0x{:x?} is missing from the bucket", bn)),
+        };
+
+        debug!("read {} bytes from block 0x{:x?}", data.len(), bn);
+        trace!("{:?}", data);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn object_keys() {
+        let id = UfsUuid::new_root_fs("test");
+        assert_eq!(
+            format!("blocks/{}/42", id),
+            object_key(&id, &42.to_string())
+        );
+        assert_eq!(format!("blocks/{}/map", id), object_key(&id, MAP_OBJECT));
+    }
+}
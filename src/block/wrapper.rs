@@ -10,7 +10,7 @@ use log::{debug, error, trace};
 use serde_derive::{Deserialize, Serialize};
 
 use crate::block::{
-    BlockCardinality, BlockHash, BlockNumber, BlockReader, BlockSizeType, BlockStorage,
+    BlockCardinality, BlockHash, BlockNumber, BlockReader, BlockSize, BlockSizeType, BlockStorage,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -32,7 +32,8 @@ pub(crate) trait MetadataSerialize {
 
 pub(crate) trait MetadataDeserialize: Sized {
     // type Meta;
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, failure::Error>;
+    /// `block_size` is that of the store, which older formats may need to be converted.
+    fn deserialize(bytes: Vec<u8>, block_size: BlockSize) -> Result<Self, failure::Error>;
 }
 
 /// Serialize Metadata
@@ -148,6 +149,7 @@ where
 pub(in crate::block) fn read_metadata<R, M>(
     store: &R,
     start_block: BlockNumber,
+    block_size: BlockSize,
 ) -> Result<M, failure::Error>
 where
     R: BlockReader,
@@ -166,7 +168,7 @@ where
         bytes.append(&mut block.data);
     }
 
-    M::deserialize(bytes)
+    M::deserialize(bytes, block_size)
 }
 
 fn read_wrapper_block<BS: BlockReader>(
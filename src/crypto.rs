@@ -1,48 +1,317 @@
 ///! Cryptographic Helpers, etc.
 use {
+    aes_ctr::Aes256Ctr,
+    argon2::{Config, ThreadMode, Variant, Version},
     c2_chacha::{
         stream_cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek},
         XChaCha20,
     },
-    hmac::Hmac,
+    failure::format_err,
+    hmac::{Hmac, Mac},
+    serde_derive::{Deserialize, Serialize},
     sha2::Sha256,
+    std::{fmt, str::FromStr},
 };
 
-use crate::uuid::UfsUuid;
+/// The number of PBKDF2 iterations used before Argon2 was adopted
+///
+const LEGACY_ITERATION_COUNT: u32 = 271828;
+
+/// Key derivation parameters
+///
+/// These are stored with whatever they were used to derive, be it a user's credentials, or a
+/// store's master key, so that the defaults may be raised without locking anyone out. The default
+/// is Argon2id, with the parameters recommended by OWASP: 19 MiB of memory, and two passes.
+/// PBKDF2 is only for credentials that predate Argon2, and are still in use.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) enum KdfParams {
+    /// PBKDF2-HMAC-SHA256
+    Pbkdf2 { iterations: u32 },
+    /// Argon2id
+    Argon2id {
+        /// Memory, in KiB
+        mem_cost: u32,
+        time_cost: u32,
+        lanes: u32,
+    },
+}
+
+impl KdfParams {
+    /// The parameters used before Argon2 was adopted
+    ///
+    pub(crate) fn legacy() -> Self {
+        KdfParams::Pbkdf2 {
+            iterations: LEGACY_ITERATION_COUNT,
+        }
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams::Argon2id {
+            mem_cost: 19 * 1024,
+            time_cost: 2,
+            lanes: 1,
+        }
+    }
+}
+
+/// The length of the tag that authenticates a block
+///
+pub(crate) const TAG_SIZE: usize = 32;
+
+/// The cipher used to encrypt blocks in storage
+///
+/// Each is a seekable stream cipher, so that part of a block may be decrypted on its own. The
+/// authenticated ciphers encrypt, and then MAC: a tag is kept with each block, and checked before
+/// the block is decrypted. AES is the faster of the two on processors with AES instructions, and
+/// ChaCha on those without. Plain XChaCha20 is how stores were encrypted before the cipher could
+/// be chosen, and remains so that they may still be read.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Cipher {
+    /// XChaCha20, with a 24 byte nonce, and no authentication
+    XChaCha20,
+    /// XChaCha20, authenticated with HMAC-SHA256
+    XChaCha20HmacSha256,
+    /// AES-256 in counter mode, with the first 16 bytes of the nonce as the initial counter,
+    /// authenticated with HMAC-SHA256
+    Aes256CtrHmacSha256,
+}
+
+impl Cipher {
+    /// Encrypt, or decrypt, `data`, starting `offset` bytes into the key stream
+    ///
+    /// Nothing is authenticated here, see `tag`.
+    pub(crate) fn apply(&self, key: &[u8], nonce: &[u8], offset: u64, data: &mut [u8]) {
+        match self {
+            Cipher::XChaCha20 | Cipher::XChaCha20HmacSha256 => {
+                let mut cipher = XChaCha20::new_var(key, nonce).unwrap();
+                cipher.seek(offset);
+                cipher.apply_keystream(data);
+            }
+            Cipher::Aes256CtrHmacSha256 => {
+                let mut cipher = Aes256Ctr::new_var(key, &nonce[..16]).unwrap();
+                cipher.seek(offset);
+                cipher.apply_keystream(data);
+            }
+        }
+    }
+
+    /// The length of the tag kept with each block, zero if blocks aren't authenticated
+    ///
+    pub(crate) fn tag_size(&self) -> usize {
+        match self {
+            Cipher::XChaCha20 => 0,
+            Cipher::XChaCha20HmacSha256 | Cipher::Aes256CtrHmacSha256 => TAG_SIZE,
+        }
+    }
+
+    /// Make the tag for block `bn`, once it's been encrypted under `nonce`
+    ///
+    /// The tag is an HMAC-SHA256 of the block number, the nonce, and the ciphertext, under a key
+    /// of its own derived from `key`. Including the block number means that a block can't be
+    /// swapped for another. Unauthenticated blocks have an empty tag.
+    pub(crate) fn tag(&self, key: &[u8], bn: u64, nonce: &[u8], data: &[u8]) -> Vec<u8> {
+        match self.tag_size() {
+            0 => vec![],
+            _ => block_mac(key, bn, nonce, data).result().code().to_vec(),
+        }
+    }
+
+    /// Check a tag made by `tag`
+    ///
+    pub(crate) fn verify_tag(
+        &self,
+        key: &[u8],
+        bn: u64,
+        nonce: &[u8],
+        data: &[u8],
+        tag: &[u8],
+    ) -> bool {
+        match self.tag_size() {
+            0 => tag.is_empty(),
+            _ => block_mac(key, bn, nonce, data).verify(tag).is_ok(),
+        }
+    }
+}
+
+/// The MAC of an encrypted block, ready to be finished, or verified
+///
+fn block_mac(key: &[u8], bn: u64, nonce: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mac_key = sign(key, b"ufs block authentication");
+    let mut mac = Hmac::<Sha256>::new_varkey(&mac_key).unwrap();
+    mac.input(&bn.to_le_bytes());
+    mac.input(nonce);
+    mac.input(data);
+    mac
+}
+
+impl Default for Cipher {
+    fn default() -> Self {
+        Cipher::XChaCha20HmacSha256
+    }
+}
 
-pub(crate) const ITERATION_COUNT: usize = 271828;
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cipher::XChaCha20 => write!(f, "xchacha20"),
+            Cipher::XChaCha20HmacSha256 => write!(f, "xchacha20-hmac-sha256"),
+            Cipher::Aes256CtrHmacSha256 => write!(f, "aes256ctr-hmac-sha256"),
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = failure::Error;
 
-/// Generate a file system key
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "xchacha20" => Ok(Cipher::XChaCha20),
+            "xchacha20-hmac-sha256" | "chacha" => Ok(Cipher::XChaCha20HmacSha256),
+            "aes256ctr-hmac-sha256" | "aes" => Ok(Cipher::Aes256CtrHmacSha256),
+            _ => Err(format_err!("unknown cipher {:?}", s)),
+        }
+    }
+}
+
+/// Wrap a secret with a key derived from a user's password
 ///
-/// Given a password, and a UUID generate a key using HMAC-SHA256.
-pub fn make_fs_key<S: AsRef<str>>(password: S, id: &UfsUuid) -> [u8; 32] {
-    hash_password(password, id.as_bytes())
+/// This is how a user's key, and their validation string, are kept in the metadata. It's
+/// deliberately plain XChaCha20, whichever cipher the store uses for its blocks: the metadata is
+/// itself stored in blocks, and so authenticated by the store's cipher, and users shouldn't be
+/// locked out when a store is created with a different one.
+pub(crate) fn wrap_key(key: &[u8], nonce: &[u8], data: &mut [u8]) {
+    Cipher::XChaCha20.apply(key, nonce, 0, data);
 }
 
-/// Encrypt a block of data
+/// Unwrap a secret wrapped by `wrap_key`
 ///
-pub(crate) fn encrypt(key: &[u8], nonce: &Vec<u8>, offset: u64, mut data: &mut [u8]) {
-    let mut cipher = XChaCha20::new_var(key, nonce).unwrap();
-    cipher.seek(offset);
-    cipher.apply_keystream(&mut data);
+/// This is the same as wrapping, as XChaCha20 is a stream cipher, but exists for symmetry.
+pub(crate) fn unwrap_key(key: &[u8], nonce: &[u8], data: &mut [u8]) {
+    Cipher::XChaCha20.apply(key, nonce, 0, data);
 }
 
-/// Encrypt a block of data
+/// Sign some data
 ///
-/// Note that this is exactly the same as encryption, but exists for symmetry.
-pub(crate) fn decrypt(key: &[u8], nonce: &Vec<u8>, offset: u64, mut data: &mut [u8]) {
-    let mut cipher = XChaCha20::new_var(key, nonce).unwrap();
-    cipher.seek(offset);
-    cipher.apply_keystream(&mut data);
+/// The signature is an HMAC-SHA256 of the data.
+pub(crate) fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(data);
+    mac.result().code().to_vec()
 }
 
-pub(crate) fn hash_password<S: AsRef<str>, V: AsRef<[u8]>>(password: S, nonce: V) -> [u8; 32] {
+/// Verify a signature made with `sign`
+///
+pub(crate) fn verify(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(data);
+    mac.verify(signature).is_ok()
+}
+
+/// Derive a key from a password
+///
+/// The parameters may have been read from storage, so they're checked rather than trusted: it's
+/// an error if Argon2 won't accept them, or if PBKDF2 is asked for no iterations at all. Argon2
+/// also insists that the salt be at least eight bytes long.
+pub(crate) fn hash_password<S: AsRef<str>, V: AsRef<[u8]>>(
+    password: S,
+    salt: V,
+    params: &KdfParams,
+) -> Result<[u8; 32], failure::Error> {
     let mut key = [0; 32];
-    pbkdf2::pbkdf2::<Hmac<Sha256>>(
-        password.as_ref().as_bytes(),
-        nonce.as_ref(),
-        ITERATION_COUNT,
-        &mut key,
-    );
-    key
+    match *params {
+        KdfParams::Pbkdf2 { iterations } => {
+            if iterations == 0 {
+                return Err(format_err!("PBKDF2 needs at least one iteration"));
+            }
+            pbkdf2::pbkdf2::<Hmac<Sha256>>(
+                password.as_ref().as_bytes(),
+                salt.as_ref(),
+                iterations as usize,
+                &mut key,
+            );
+        }
+        KdfParams::Argon2id {
+            mem_cost,
+            time_cost,
+            lanes,
+        } => {
+            let config = Config {
+                variant: Variant::Argon2id,
+                version: Version::Version13,
+                mem_cost,
+                time_cost,
+                lanes,
+                thread_mode: ThreadMode::Sequential,
+                secret: &[],
+                ad: &[],
+                hash_length: 32,
+            };
+
+            let hash = argon2::hash_raw(password.as_ref().as_bytes(), salt.as_ref(), &config)
+                .map_err(|e| format_err!("invalid Argon2 parameters: {}", e))?;
+            key.copy_from_slice(&hash);
+        }
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+
+    use crate::uuid::UfsUuid;
+
+    use super::*;
+
+    #[test]
+    fn legacy_key() {
+        // The key that PBKDF2 made of "foobar", for the file system "test".
+        assert_eq!(
+            hex!("1001c46bd12c22cf5991f1b4b2f70cd416e20a1312961ff958989f6f8f424dbe"),
+            hash_password(
+                "foobar",
+                UfsUuid::new_root_fs("test").as_bytes(),
+                &KdfParams::legacy()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_params() {
+        let salt = [0x42; 16];
+        assert!(hash_password("foobar", salt, &KdfParams::Pbkdf2 { iterations: 0 }).is_err());
+        assert!(hash_password(
+            "foobar",
+            salt,
+            &KdfParams::Argon2id {
+                mem_cost: 0,
+                time_cost: 0,
+                lanes: 0,
+            }
+        )
+        .is_err());
+        assert!(hash_password("foobar", [0x42; 4], &KdfParams::default()).is_err());
+        assert!(hash_password("foobar", salt, &KdfParams::default()).is_ok());
+    }
+
+    #[test]
+    fn block_tag() {
+        let key = [0x42; 32];
+        let nonce = [0x24; 24];
+        let data = b"some encrypted block";
+
+        let cipher = Cipher::default();
+        let tag = cipher.tag(&key, 7, &nonce, data);
+        assert_eq!(TAG_SIZE, tag.len());
+        assert!(cipher.verify_tag(&key, 7, &nonce, data, &tag));
+        assert!(!cipher.verify_tag(&key, 8, &nonce, data, &tag));
+        assert!(!cipher.verify_tag(&key, 7, &nonce, b"some encrypted blocK", &tag));
+        assert!(!cipher.verify_tag(&[0x43; 32], 7, &nonce, data, &tag));
+
+        assert!(Cipher::XChaCha20.tag(&key, 7, &nonce, data).is_empty());
+        assert!(Cipher::XChaCha20.verify_tag(&key, 7, &nonce, data, &[]));
+    }
 }
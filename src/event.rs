@@ -0,0 +1,114 @@
+//! Exporting File System Events
+//!
+//! The file system emits a stream of events as files and directories are created, opened, written,
+//! etc. WASM programs receive these via the runtime manager. An `EventSink` is handed the very same
+//! stream, so that it may be forwarded someplace outside of the file system -- a message queue, a
+//! log file, whatever.
+//!
+use {
+    crate::wasm::IofsMessage,
+    failure,
+    log::error,
+    serde_json,
+    std::{
+        fs::{File, OpenOptions},
+        io::Write,
+        path::Path,
+    },
+};
+
+/// A Consumer of File System Events
+///
+/// Sinks are registered with the `UfsMounter`, and run on the runtime manager's thread. Emitting
+/// an event should therefore be quick -- anything slow ought to be handed off to another thread.
+pub trait EventSink: Send {
+    /// Handle a file system event
+    ///
+    fn emit(&mut self, msg: &IofsMessage);
+}
+
+/// An `EventSink` that appends events to a file
+///
+/// Each event is written as a single line of JSON.
+pub struct FileEventSink {
+    file: File,
+}
+
+impl FileEventSink {
+    /// Constructor
+    ///
+    /// The file is created if it does not already exist, otherwise events are appended to it.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+
+        Ok(FileEventSink { file })
+    }
+}
+
+impl EventSink for FileEventSink {
+    fn emit(&mut self, msg: &IofsMessage) {
+        match serde_json::to_string(msg) {
+            Ok(json) => {
+                if let Err(e) = writeln!(self.file, "{}", json) {
+                    error!("unable to write event to log: {}", e);
+                }
+            }
+            Err(e) => error!("unable to serialize event {:?}: {}", msg, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            wasm::{IofsFileMessage, IofsMessagePayload},
+            UfsUuid,
+        },
+        std::{fs, path::PathBuf},
+    };
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn file_sink_writes_json_lines() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/file_sink_writes_json_lines.log");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let _ = fs::remove_file(path);
+
+        let root = UfsUuid::new_root_fs("test");
+        let payload = IofsMessagePayload {
+            target_id: root.new("foo"),
+            target_path: PathBuf::from("/foo"),
+            parent_id: root,
+        };
+
+        let mut sink = FileEventSink::new(path).unwrap();
+        sink.emit(&IofsMessage::FileMessage(IofsFileMessage::Create(
+            payload.clone(),
+        )));
+        sink.emit(&IofsMessage::FileMessage(IofsFileMessage::Close(payload)));
+
+        let log = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(2, lines.len());
+
+        let event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            "/foo",
+            event["FileMessage"]["Create"]["target_path"]
+                .as_str()
+                .unwrap()
+        );
+        let event: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert!(event["FileMessage"]["Close"].is_object());
+    }
+}
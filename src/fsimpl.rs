@@ -1,19 +1,23 @@
 use {
     crate::{
         block::{
-            manager::BlockManager, map::BlockMap, BlockCardinality, BlockSize, BlockStorage,
-            FileStore, MemoryStore, NetworkStore,
+            chunk::content_defined_chunks, manager::BlockManager, map::BlockMap, Block,
+            BlockCardinality, BlockHash, BlockNumber, BlockSize, BlockStorage, ChunkingMode,
+            FileStore, MemoryStore, MirrorStore, NetworkStore, RetryPolicy,
         },
-        crypto::make_fs_key,
+        crypto::Cipher,
+        event::EventSink,
         jwt::{decode_jwt, new_jwt, UserClaims, JWT},
         metadata::{
-            DirectoryEntry, DirectoryMetadata, File, FileHandle, FileMetadata, FileSize, Metadata,
-            WASM_EXT,
+            quota::QuotaHold, DirectoryEntry, DirectoryMetadata, File, FileHandle, FileMetadata,
+            FileSize, Metadata, QuotaTarget, Reservation, Retention, SortOrder, SymlinkMetadata,
+            WASM_CONFIG_EXT, WASM_EXT,
         },
-        server::UfsRemoteServer,
+        server::{ServerTls, UfsRemoteServer},
+        time::{Timestamps, UfsTime},
         wasm::{
-            IofsDirMessage, IofsFileMessage, IofsMessage, IofsMessagePayload, ProtoWasmProgram,
-            RuntimeManager, RuntimeManagerMsg,
+            manager::DEFAULT_COALESCE_WINDOW, IofsDirMessage, IofsFileMessage, IofsMessage,
+            IofsMessagePayload, ProtoWasmProgram, RestartStatus, RuntimeManager, RuntimeManagerMsg,
         },
         IOFSErrorKind, UfsUuid,
     },
@@ -25,18 +29,25 @@ use {
     rand::{distributions::Alphanumeric, thread_rng, Rng},
     reqwest::IntoUrl,
     std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         ops::{Deref, DerefMut},
         path::{Path, PathBuf},
-        sync::{Arc, Mutex},
-        thread::JoinHandle,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        },
+        thread::{spawn, JoinHandle},
+        time::SystemTime,
     },
     time::Duration,
 };
 
+#[cfg(feature = "s3")]
+use {crate::block::S3Store, rusoto_core::Region};
+
 /// File mode for `open` call.
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpenFileMode {
     /// Open file for reading
     ///
@@ -47,47 +58,158 @@ pub enum OpenFileMode {
     /// Open file for reading and writing
     ///
     ReadWrite,
+    /// Open file for appending
+    ///
+    /// Writes always go to the end of the file, regardless of the offset given.
+    Append,
+    /// Open file for reading and appending
+    ///
+    /// As `Append`, but the file may be read as well.
+    ReadAppend,
+}
+
+/// The default number of events that may be waiting for the WASM runtime
+///
+const DEFAULT_EVENT_QUEUE_SIZE: usize = 1024;
+
+/// The default lifetime of an authentication token, in minutes
+///
+const DEFAULT_TOKEN_TTL: i64 = 5;
+
+/// The default number of points a WASM program may spend handling a single event
+///
+/// Points are roughly one per instruction executed, so this allows for a second or so of work.
+const DEFAULT_WASM_EXECUTION_LIMIT: u64 = 1_000_000_000;
+
+/// The default size, in bytes, at which a WASM program's log file is rotated
+///
+const DEFAULT_WASM_LOG_SIZE: FileSize = 1024 * 1024;
+
+/// The default number of blocks read ahead of a sequential reader
+///
+const DEFAULT_READ_AHEAD: usize = 8;
+
+/// The default number of seconds between writes of changed metadata, when mounted
+///
+const DEFAULT_FLUSH_INTERVAL: u64 = 30;
+
+/// Event Priority
+///
+/// Every file system operation generates an event for the WASM runtime, which passes it along to
+/// interested programs. With many programs running the runtime may fall behind, and something has
+/// to give.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventPriority {
+    /// File system operations come first
+    ///
+    /// Events are queued, up to the configured size, and dropped when the queue is full. File
+    /// system operations never wait for the runtime. Dropped events are logged, and counted in
+    /// `UberFileSystem::dropped_event_count` and the `/metrics` endpoint.
+    Foreground,
+    /// Events come first
+    ///
+    /// The queue is unbounded, so that every event is delivered, at the expense of memory.
+    Events,
+}
+
+/// Format Options
+///
+/// The choices made when a file system is formatted. They're stored with the file system, so
+/// `load_file_backed`, and `load_s3`, needn't be told.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FormatOptions {
+    /// The cipher that blocks are encrypted with
+    ///
+    pub cipher: Cipher,
+    /// Create block files as they are written, rather than all up front
+    ///
+    /// A sparse store takes room on disk as it's used. Otherwise, a missing block file is taken
+    /// as lost. An S3-backed store is always sparse.
+    pub sparse: bool,
+    /// Compress file data
+    ///
+    /// File data is compressed a block at a time, before it's encrypted. Blocks that don't shrink
+    /// are stored as is.
+    pub compress: bool,
 }
 
 /// File System integration with WASM interpreter
 ///
 /// This struct contains the file system implementation, and a WASM runtime implementation.
-/// The former is wrapped in a `Mutex`, wrapped in an `Arc`, which is passed to WASM programs so
+/// The former is wrapped in an `RwLock`, wrapped in an `Arc`, which is passed to WASM programs so
 /// that they may invoke callbacks to the file system. The runtime manages the WASM threads.
 ///
+/// Operations that only look at the file system, e.g. reading a file, or listing a directory,
+/// take the read lock, and so don't wait on each other. FUSE hands us requests one at a time, so
+/// the readers that share the lock are the HTTP server, the WASM programs, and the read-ahead
+/// thread. As the storage is read from all of them at once, it must be `Sync`.
+///
 /// The two communicate via a channel. When a .wasm file is found on the file system, it uses the
 /// channel to have the runtime create a thread for the wasm program.
-pub struct UfsMounter<B: BlockStorage + 'static> {
-    // FIXME: I think that the Mutex can be an RwLock...
-    inner: Arc<Mutex<UberFileSystem<B>>>,
+pub struct UfsMounter<B: BlockStorage + Sync + 'static> {
+    inner: Arc<RwLock<UberFileSystem<B>>>,
     remote_stop_signal: Option<oneshot::Sender<()>>,
     remote_thread: Option<JoinHandle<Result<(), failure::Error>>>,
     runtime_mgr_channel: crossbeam_channel::Sender<RuntimeManagerMsg>,
     runtime_mgr_thread: Option<JoinHandle<Result<(), failure::Error>>>,
+    prefetch_thread: Option<JoinHandle<()>>,
+    flush_stop_signal: Option<crossbeam_channel::Sender<()>>,
+    flush_thread: Option<JoinHandle<()>>,
 }
 
-impl<B: BlockStorage> UfsMounter<B> {
+impl<B: BlockStorage + Sync> UfsMounter<B> {
     /// Constructor
     ///
-    pub fn new(mut ufs: UberFileSystem<B>, remote_port: Option<u16>) -> Self {
+    /// Given a port, and TLS configuration, the remote server is started too. It's an error if
+    /// the TLS certificate or key can't be read.
+    pub fn new(
+        mut ufs: UberFileSystem<B>,
+        remote: Option<(u16, ServerTls)>,
+    ) -> Result<Self, failure::Error> {
+        if let Some((_, tls)) = &remote {
+            tls.check()?;
+        }
+
         let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        let (event_sender, event_receiver) = ufs.new_event_queue();
 
         // Initialize the UFS
         info!("Initializing file system");
-        ufs.init_runtime(sender.clone());
-        let inner = Arc::new(Mutex::new(ufs));
+        ufs.init_runtime(sender.clone(), event_sender);
+        let (prefetch_sender, prefetch_receiver) = crossbeam_channel::unbounded();
+        ufs.prefetcher = Some(prefetch_sender);
+        // A read-only file system has nothing to write.
+        let flush_interval = ufs.flush_interval.filter(|_| !ufs.read_only);
+        let coalesce_window = ufs.coalesce_window;
+        let inner = Arc::new(RwLock::new(ufs));
+
+        // Start reading ahead
+        let prefetch_thread = Self::start_prefetcher(inner.clone(), prefetch_receiver);
+
+        // Start writing metadata in the background
+        let (flush_stop_signal, flush_thread) = match flush_interval {
+            Some(interval) => {
+                let (tx, rx) = crossbeam_channel::bounded(1);
+                let thread = Self::start_flusher(inner.clone(), interval, rx);
+                (Some(tx), Some(thread))
+            }
+            None => (None, None),
+        };
 
         // Start the Runtime
         info!("Initializing Wasm runtime");
-        let mut runtime_mgr = RuntimeManager::new(inner.clone(), receiver);
+        let mut runtime_mgr = RuntimeManager::new(inner.clone(), receiver, event_receiver);
+        runtime_mgr.set_coalesce_window(coalesce_window);
 
         // Start the remote FS listener
-        let (remote_stop_signal, remote_thread) = match remote_port {
-            Some(port) => {
+        let (remote_stop_signal, remote_thread) = match remote {
+            Some((port, tls)) => {
                 info!("Initializing Web Server");
                 let (tx, rx) = oneshot::channel();
-                let remote = UfsRemoteServer::new(inner.clone(), port);
+                let mut remote = UfsRemoteServer::new(inner.clone(), port, tls);
                 runtime_mgr.set_http_receiver(remote.get_http_receiver());
+                remote.set_program_count(runtime_mgr.program_count());
+                runtime_mgr.add_event_sink(remote.event_sink());
 
                 let remote_thread = UfsRemoteServer::start(remote, rx);
                 (Some(tx), Some(remote_thread))
@@ -103,13 +225,97 @@ impl<B: BlockStorage> UfsMounter<B> {
             remote_thread,
             runtime_mgr_channel: sender,
             runtime_mgr_thread: Some(runtime_mgr_thread),
+            prefetch_thread: Some(prefetch_thread),
+            flush_stop_signal,
+            flush_thread,
         };
 
-        mounter
+        Ok(mounter)
+    }
+
+    /// Start the read-ahead thread
+    ///
+    /// Blocks requested by `UberFileSystem::read_ahead` are read into the block cache, one at a
+    /// time, so that the lock is held no longer than it takes to fetch a block. The thread exits
+    /// when the file system drops its end of the channel.
+    fn start_prefetcher(
+        ufs: Arc<RwLock<UberFileSystem<B>>>,
+        receiver: crossbeam_channel::Receiver<Vec<BlockNumber>>,
+    ) -> JoinHandle<()> {
+        spawn(move || {
+            for blocks in receiver.iter() {
+                for bn in blocks {
+                    let guard = read_ufs(&ufs);
+                    if let Err(e) = guard.block_manager.prefetch(bn) {
+                        debug!("unable to read ahead block {}: {}", bn, e);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Start the metadata flush thread
+    ///
+    /// Every `interval`, the metadata is checked under the read lock, and only if it's changed is
+    /// the write lock taken to write it out. The write lock is held for as long as it takes to
+    /// write the metadata, and the block map, and no longer. Open files aren't committed, so their
+    /// changes since they were last flushed aren't written. The thread exits when it's signalled,
+    /// or the other end of the channel goes away.
+    ///
+    /// The block map is written as it stands, so it includes blocks that open files have written,
+    /// but that no committed version refers to yet. Should the system go down before those files
+    /// are closed, the blocks are reported as orphaned by `check`, and freed by a repair.
+    fn start_flusher(
+        ufs: Arc<RwLock<UberFileSystem<B>>>,
+        interval: std::time::Duration,
+        stop: crossbeam_channel::Receiver<()>,
+    ) -> JoinHandle<()> {
+        spawn(move || {
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                stop.recv_timeout(interval)
+            {
+                if read_ufs(&ufs).block_manager.metadata().is_dirty() {
+                    debug!("writing changed metadata");
+                    write_ufs(&ufs).block_manager.serialize();
+                }
+            }
+        })
+    }
+
+    /// Read bytes from a file, under the read lock where possible
+    ///
+    /// Unlike `UberFileSystem::read_file`, the write lock isn't held while the blocks are read, so
+    /// that readers on other threads aren't kept waiting.
+    pub fn read_file(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, failure::Error> {
+        read_file_shared(&self.inner, handle, offset, size)
+    }
+
+    /// Register an EventSink
+    ///
+    /// File system events are forwarded to the sink, alongside any interested WASM programs. Only
+    /// events that happen after registration are seen by the sink.
+    pub fn add_event_sink(&self, sink: Box<dyn EventSink>) {
+        self.runtime_mgr_channel
+            .send(RuntimeManagerMsg::AddEventSink(sink))
+            .unwrap();
     }
 
     /// Shutdown
     ///
+    /// Things are taken down in order, so that nothing is lost:
+    ///  1. The WASM programs are stopped. They are sent a `Shutdown` message first, and may write
+    ///     to the file system in response.
+    ///  2. The read-ahead, and metadata flush, threads are stopped.
+    ///  3. Open files, the metadata, and the block map are written to storage.
+    ///  4. The HTTP server is stopped.
+    ///
+    /// We don't rely on `Drop` to write the metadata, as there may be other references to the
+    /// file system keeping it alive.
     pub fn shutdown(&mut self) -> Result<(), failure::Error> {
         self.runtime_mgr_channel
             .send(RuntimeManagerMsg::Shutdown)
@@ -122,6 +328,23 @@ impl<B: BlockStorage> UfsMounter<B> {
                 .expect("error running RuntimeManager thread");
         }
 
+        write_ufs(&self.inner).prefetcher = None;
+        if let Some(thread) = self.prefetch_thread.take() {
+            info!("Waiting for read-ahead thread to shutdown.");
+            thread.join().expect("unable to join read-ahead thread");
+        }
+
+        if let Some(stop) = self.flush_stop_signal.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.flush_thread.take() {
+            info!("Waiting for metadata flush thread to shutdown.");
+            thread.join().expect("unable to join metadata flush thread");
+        }
+
+        info!("Writing file system to storage.");
+        write_ufs(&self.inner).sync()?;
+
         if let Some(oneshot) = self.remote_stop_signal.take() {
             oneshot.send(()).unwrap();
         }
@@ -137,46 +360,262 @@ impl<B: BlockStorage> UfsMounter<B> {
     }
 }
 
-impl<B: BlockStorage> Deref for UfsMounter<B> {
-    type Target = Arc<Mutex<UberFileSystem<B>>>;
+/// Lock the file system for writing, recovering it if the lock is poisoned
+///
+/// A panic while the lock is held poisons it, which would otherwise take every later FUSE
+/// operation, HTTP request, and WASM callback down with it. Instead, the panic is logged, the
+/// poison cleared, and the file system carries on as the panicking thread left it.
+pub(crate) fn write_ufs<B: BlockStorage + Sync>(
+    ufs: &RwLock<UberFileSystem<B>>,
+) -> RwLockWriteGuard<'_, UberFileSystem<B>> {
+    ufs.write().unwrap_or_else(|poisoned| {
+        error!("recovering the file system from a panic while it was locked");
+        ufs.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Lock the file system for reading, recovering it if the lock is poisoned
+///
+/// See `write_ufs`.
+pub(crate) fn read_ufs<B: BlockStorage + Sync>(
+    ufs: &RwLock<UberFileSystem<B>>,
+) -> RwLockReadGuard<'_, UberFileSystem<B>> {
+    ufs.read().unwrap_or_else(|poisoned| {
+        error!("recovering the file system from a panic while it was locked");
+        ufs.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Read bytes from a file, under the read lock where possible
+///
+/// The blocks are read and decrypted under the read lock, so that readers don't wait on each
+/// other. The write lock is taken afterwards, briefly, for the read-ahead and access time
+/// bookkeeping. A file with buffered writes needs those flushed first, so it's read under the
+/// write lock, by `read_file`.
+pub(crate) fn read_file_shared<B: BlockStorage + Sync>(
+    ufs: &RwLock<UberFileSystem<B>>,
+    handle: FileHandle,
+    offset: u64,
+    size: u32,
+) -> Result<Vec<u8>, failure::Error> {
+    let bytes = {
+        let guard = read_ufs(ufs);
+        if guard.write_buffers.contains_key(&handle) {
+            drop(guard);
+            return write_ufs(ufs).read_file(handle, offset, size);
+        }
+        guard.check_readable(handle)?;
+        guard.read_handle(handle, offset, size)?
+    };
+    write_ufs(ufs).finish_read(handle, offset, offset + bytes.len() as u64);
+
+    Ok(bytes)
+}
+
+impl<B: BlockStorage + Sync> Deref for UfsMounter<B> {
+    type Target = Arc<RwLock<UberFileSystem<B>>>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
 }
 
-impl<B: BlockStorage> DerefMut for UfsMounter<B> {
+impl<B: BlockStorage + Sync> DerefMut for UfsMounter<B> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.inner
     }
 }
 
+/// File access for the benchmarks
+///
+/// The file methods on `UberFileSystem` are crate private. Enabling the `bench` feature exposes
+/// them, through this trait, to the benches, which live outside the crate.
+#[cfg(feature = "bench")]
+pub trait BenchFileAccess {
+    fn create_path<P: AsRef<Path>>(&mut self, path: P) -> Result<FileHandle, failure::Error>;
+    fn open_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: OpenFileMode,
+    ) -> Result<FileHandle, failure::Error>;
+    fn write_file(
+        &mut self,
+        handle: FileHandle,
+        bytes: &[u8],
+        offset: u64,
+    ) -> Result<usize, failure::Error>;
+    fn read_file(
+        &mut self,
+        handle: FileHandle,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, failure::Error>;
+    fn close_file(&mut self, handle: FileHandle) -> Result<(), failure::Error>;
+    fn create_directory_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), failure::Error>;
+}
+
+#[cfg(feature = "bench")]
+impl<B: BlockStorage + Sync> BenchFileAccess for UberFileSystem<B> {
+    fn create_path<P: AsRef<Path>>(&mut self, path: P) -> Result<FileHandle, failure::Error> {
+        UberFileSystem::create_path(self, path)
+    }
+
+    fn open_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: OpenFileMode,
+    ) -> Result<FileHandle, failure::Error> {
+        UberFileSystem::open_path(self, path, mode)
+    }
+
+    fn write_file(
+        &mut self,
+        handle: FileHandle,
+        bytes: &[u8],
+        offset: u64,
+    ) -> Result<usize, failure::Error> {
+        UberFileSystem::write_file(self, handle, bytes, offset)
+    }
+
+    fn read_file(
+        &mut self,
+        handle: FileHandle,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, failure::Error> {
+        UberFileSystem::read_file(self, handle, offset, size)
+    }
+
+    fn close_file(&mut self, handle: FileHandle) -> Result<(), failure::Error> {
+        UberFileSystem::close_file(self, handle)
+    }
+
+    fn create_directory_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), failure::Error> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("invalid directory name {:?}", path))?;
+        let parent_id = path
+            .parent()
+            .and_then(|dir| self.block_manager.metadata().id_from_path(dir))
+            .ok_or(IOFSErrorKind::NotFound)?;
+
+        self.create_directory(parent_id, name).map(|_| ())
+    }
+}
+
 struct TokenRegistration {
     user: UfsUuid,
+    jti: UfsUuid,
+    issued: SystemTime,
     secret: String,
     key: [u8; 32],
 }
 
+/// Read-ahead bookkeeping for an open file
+///
+/// A read that starts where the previous one ended is sequential, and the blocks following it are
+/// read into the block cache before they are asked for. Blocks aren't rewritten in place -- writes
+/// go to fresh blocks, and recycled blocks are dropped from the cache -- so nothing cached goes
+/// stale. A write to the file does change its list of blocks though, so it starts things over.
+struct ReadAhead {
+    /// The offset at which the next sequential read starts
+    next_offset: u64,
+    /// The index of the first of the file's blocks that hasn't been read ahead
+    fetched: usize,
+}
+
+/// Bytes written to an open file that don't yet fill a block
+///
+/// Small writes are gathered here, and written a block at a time, so that a file written a few
+/// bytes at a time doesn't end up as a long list of mostly-empty blocks. See `write_file`.
+struct WriteBuffer {
+    /// The file offset of the first buffered byte
+    offset: u64,
+    bytes: Vec<u8>,
+}
+
 /// Main File System Implementation
 ///
-pub struct UberFileSystem<B: BlockStorage> {
+pub struct UberFileSystem<B: BlockStorage + Sync> {
     /// The ID of the file system
     id: UfsUuid,
     /// JWTs are passed out as authentication tokens. This is a mapping from token string to data
     /// needed for token validation and user access.
     tokens: HashMap<String, TokenRegistration>,
+    /// How long a token is good for, once issued
+    token_ttl: Duration,
+    /// How many points a WASM program may spend on each function invocation
+    wasm_execution_limit: u64,
+    /// How many times in a row a crashed WASM program is restarted -- zero disables restarts
+    wasm_restart_limit: u32,
+    /// Restart bookkeeping for WASM programs that have crashed
+    wasm_restarts: HashMap<PathBuf, RestartStatus>,
+    /// The size at which a WASM program's log file is rotated -- zero disables the log files
+    wasm_log_size: FileSize,
+    /// Handles on the log files of running WASM programs
+    ///
+    /// A log stays open while its program runs, so that a new version isn't made for every line.
+    wasm_logs: HashMap<PathBuf, FileHandle>,
     /// The ID of the user that mounted the file system
     user: UfsUuid,
     /// The block manager -- where all the magic happens
     block_manager: BlockManager<B>,
-    /// A mapping of file handles to File structures
-    open_files: HashMap<FileHandle, File>,
+    /// A mapping of file handles to File structures, and the mode in which they were opened
+    open_files: HashMap<FileHandle, (File, OpenFileMode)>,
     /// A mapping of file handles to DirectoryMetadata structures
     open_dirs: HashMap<FileHandle, DirectoryMetadata>,
+    /// The names in each open directory, in the order that they are listed
+    dir_listings: HashMap<FileHandle, Vec<String>>,
+    /// Open files with changes that were committed by `flush_file`
+    flushed: HashSet<FileHandle>,
+    /// Small writes to open files, waiting to fill a block
+    write_buffers: HashMap<FileHandle, WriteBuffer>,
+    /// The number of open handles for each open file
+    open_counts: HashMap<UfsUuid, usize>,
+    /// The blocks of files that were removed while open
+    ///
+    /// These are recycled when the last handle to the file is closed.
+    unlinked: HashMap<UfsUuid, Vec<BlockNumber>>,
+    /// Blocks of pruned versions that open files were still using, and the file that they're from
+    ///
+    /// These are recycled when the last handle to them is closed.
+    pruned_in_use: HashMap<BlockNumber, UfsUuid>,
+    /// The bytes that each handle's writes hold against quotas, until the file is committed
+    quota_holds: HashMap<FileHandle, Vec<QuotaHold>>,
     /// A counter so that we know what the next file handle should be
     open_file_counter: FileHandle,
     /// The Wasm program manager
     program_mgr: Option<crossbeam_channel::Sender<RuntimeManagerMsg>>,
+    /// The queue of file system events bound for the Wasm program manager
+    event_sender: Option<crossbeam_channel::Sender<IofsMessage>>,
+    /// The size of the event queue, see `EventPriority`
+    event_queue_size: usize,
+    /// Whether file system operations, or the events that they generate, come first
+    event_priority: EventPriority,
+    /// The number of events dropped because the event queue was full, or the runtime went away
+    dropped_events: AtomicU64,
+    /// Whether events are being dropped, so that it's reported once rather than for each event
+    dropping_events: AtomicBool,
+    /// The programs that we've asked the program manager to run
+    programs: HashSet<PathBuf>,
+    /// Files, and directories, whose blocks are read into the cache when mounted
+    hot_paths: Vec<PathBuf>,
+    /// How many blocks are read ahead of a sequential reader -- zero disables read-ahead
+    read_ahead: usize,
+    /// Read-ahead bookkeeping for each open file
+    read_positions: HashMap<FileHandle, ReadAhead>,
+    /// The channel to the read-ahead thread, if there is one
+    prefetcher: Option<crossbeam_channel::Sender<Vec<BlockNumber>>>,
+    /// How often changed metadata is written to storage, when mounted -- `None` disables it
+    flush_interval: Option<std::time::Duration>,
+    /// The window within which rapid reads, or writes, of a file are reported as one event
+    coalesce_window: std::time::Duration,
+    /// Whether the file system is mounted read-only
+    read_only: bool,
 }
 
 impl UberFileSystem<MemoryStore> {
@@ -197,20 +636,80 @@ impl UberFileSystem<MemoryStore> {
         let mem_store = MemoryStore::new(BlockMap::new(id, size, count));
         let block_manager = BlockManager::new(&user, &password, mem_store);
 
-        UberFileSystem {
-            id: block_manager.id().clone(),
-            tokens: HashMap::new(),
-            user: UfsUuid::new_user(user.as_ref()),
-            block_manager,
-            open_files: HashMap::new(),
-            open_dirs: HashMap::new(),
-            open_file_counter: 0,
-            program_mgr: None,
-        }
+        UberFileSystem::from_block_manager(block_manager, user.as_ref())
     }
 }
 
 impl UberFileSystem<FileStore> {
+    /// Format a new file-backed File System
+    ///
+    /// This is our `mkfs`. The block storage is created at `path`, the metadata initialized, and
+    /// the admin user added, all in one go. As with `load_file_backed`, the file system ID is
+    /// derived from the last component of `path`. Every block file is created up front.
+    pub fn format_file_backed<S, P>(
+        path: P,
+        master_password: S,
+        admin_user: S,
+        admin_password: S,
+        size: BlockSize,
+        count: BlockCardinality,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        UberFileSystem::format_file_backed_with_options(
+            path,
+            master_password,
+            admin_user,
+            admin_password,
+            size,
+            count,
+            FormatOptions::default(),
+        )
+    }
+
+    /// Format a new file-backed File System, with options
+    ///
+    /// As `format_file_backed`, but the cipher, whether the store is sparse, and whether file data
+    /// is compressed, are taken from `options`.
+    pub fn format_file_backed_with_options<S, P>(
+        path: P,
+        master_password: S,
+        admin_user: S,
+        admin_password: S,
+        size: BlockSize,
+        count: BlockCardinality,
+        options: FormatOptions,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        let name = match path.as_ref().file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return Err(format_err!("invalid bundle path {:?}", path.as_ref())),
+        };
+        let id = UfsUuid::new_root_fs(name.as_bytes());
+
+        let map = BlockMap::new(id, size, count)
+            .with_cipher(options.cipher)
+            .with_compression(options.compress);
+        let file_store = if options.sparse {
+            FileStore::new_sparse(master_password.as_ref(), path.as_ref(), map)?
+        } else {
+            FileStore::new(master_password.as_ref(), path.as_ref(), map)?
+        };
+        let mut block_manager = BlockManager::new(&admin_user, &admin_password, file_store);
+        // Write out the metadata now, so that the volume is loadable even if we crash.
+        block_manager.serialize();
+
+        Ok(UberFileSystem::from_block_manager(
+            block_manager,
+            admin_user.as_ref(),
+        ))
+    }
+
     /// Load an existing file-backed File System
     ///
     pub fn load_file_backed<S, P>(
@@ -223,88 +722,408 @@ impl UberFileSystem<FileStore> {
         S: AsRef<str>,
         P: AsRef<Path>,
     {
-        let key = make_fs_key(
-            master_password.as_ref(),
-            &UfsUuid::new_root_fs(
-                path.as_ref()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .as_bytes(),
-            ),
-        );
-        let file_store = FileStore::load(key.clone(), path.as_ref())?;
+        let file_store = FileStore::load(master_password.as_ref(), path.as_ref())?;
         let block_manager = BlockManager::load(user.as_ref(), password.as_ref(), file_store)?;
 
-        Ok(UberFileSystem {
-            id: block_manager.id().clone(),
-            tokens: HashMap::new(),
-            user: UfsUuid::new_user(user.as_ref()),
+        Ok(UberFileSystem::from_block_manager(
             block_manager,
-            open_files: HashMap::new(),
-            open_dirs: HashMap::new(),
-            open_file_counter: 0,
-            program_mgr: None,
-        })
+            user.as_ref(),
+        ))
+    }
+
+    /// Change the master password
+    ///
+    /// Everything is written out, and then every block in the store is re-encrypted with a key
+    /// derived from `new_password`, as described by `FileStore::rotate_key`. From then on, the
+    /// file system must be loaded with `new_password`.
+    pub fn rotate_master_key<S: AsRef<str>>(
+        &mut self,
+        old_password: S,
+        new_password: S,
+    ) -> Result<(), failure::Error> {
+        self.sync()?;
+        self.block_manager
+            .store_mut()
+            .rotate_key(old_password, new_password)
     }
 }
 
 impl UberFileSystem<NetworkStore> {
     /// Load blocks from a remote block server
     ///
+    /// Block requests that fail are retried according to `retry`, if given.
     pub fn new_networked<S, U>(
         user: S,
         password: S,
         name: S,
         url: U,
+        retry: Option<RetryPolicy>,
     ) -> Result<Self, failure::Error>
     where
         S: AsRef<str>,
         U: IntoUrl,
     {
-        let net_store = NetworkStore::new(name, url)?;
+        let net_store = NetworkStore::new(name, url, retry)?;
         let block_manager = BlockManager::load(&user, &password, net_store)?;
 
-        Ok(UberFileSystem {
+        Ok(UberFileSystem::from_block_manager(
+            block_manager,
+            user.as_ref(),
+        ))
+    }
+}
+
+impl<P: BlockStorage + Sync, S: BlockStorage + Sync> UberFileSystem<MirrorStore<P, S>> {
+    /// Load a File System that's mirrored across two stores
+    ///
+    /// The stores must hold the same file system, as described by `MirrorStore::new`. The
+    /// metadata is read through the mirror, so a damaged primary is repaired from the secondary.
+    pub fn load_mirrored<T>(
+        user: T,
+        password: T,
+        primary: P,
+        secondary: S,
+    ) -> Result<Self, failure::Error>
+    where
+        T: AsRef<str>,
+    {
+        let mirror_store = MirrorStore::new(primary, secondary)?;
+        let block_manager = BlockManager::load(user.as_ref(), password.as_ref(), mirror_store)?;
+
+        Ok(UberFileSystem::from_block_manager(
+            block_manager,
+            user.as_ref(),
+        ))
+    }
+}
+
+#[cfg(feature = "s3")]
+impl UberFileSystem<S3Store> {
+    /// Format a new S3-backed File System
+    ///
+    /// The block map, and the metadata, are written to `bucket`, and the admin user added. The
+    /// file system ID is derived from `name`, which is then used to load it. The cipher, and
+    /// whether file data is compressed, are taken from `options`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn format_s3<S>(
+        master_password: S,
+        admin_user: S,
+        admin_password: S,
+        name: S,
+        region: Region,
+        bucket: S,
+        size: BlockSize,
+        count: BlockCardinality,
+        options: FormatOptions,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+    {
+        let id = UfsUuid::new_root_fs(name.as_ref().as_bytes());
+        let map = BlockMap::new(id, size, count)
+            .with_cipher(options.cipher)
+            .with_compression(options.compress);
+        let s3_store = S3Store::new(master_password.as_ref(), region, bucket.as_ref(), map)?;
+        let mut block_manager = BlockManager::new(&admin_user, &admin_password, s3_store);
+        // Write out the metadata now, so that the volume is loadable even if we crash.
+        block_manager.serialize();
+        if block_manager.root_block().is_none() {
+            return Err(format_err!(
+                "unable to write the file system metadata to bucket {}",
+                bucket.as_ref()
+            ));
+        }
+
+        Ok(UberFileSystem::from_block_manager(
+            block_manager,
+            admin_user.as_ref(),
+        ))
+    }
+
+    /// Load an existing S3-backed File System
+    ///
+    pub fn load_s3<S>(
+        master_password: S,
+        user: S,
+        password: S,
+        name: S,
+        region: Region,
+        bucket: S,
+    ) -> Result<Self, failure::Error>
+    where
+        S: AsRef<str>,
+    {
+        let s3_store = S3Store::load(
+            master_password.as_ref(),
+            name.as_ref(),
+            region,
+            bucket.as_ref(),
+        )?;
+        let block_manager = BlockManager::load(user.as_ref(), password.as_ref(), s3_store)?;
+
+        Ok(UberFileSystem::from_block_manager(
+            block_manager,
+            user.as_ref(),
+        ))
+    }
+}
+
+impl<B: BlockStorage + Sync> UberFileSystem<B> {
+    /// Wrap a block manager in a file system
+    ///
+    /// Everything but the block storage, and the user it's opened as, starts out at its default.
+    /// The constructors for each kind of block storage finish with this.
+    fn from_block_manager(block_manager: BlockManager<B>, user: &str) -> Self {
+        UberFileSystem {
             id: block_manager.id().clone(),
             tokens: HashMap::new(),
-            user: UfsUuid::new_user(user.as_ref()),
+            token_ttl: Duration::minutes(DEFAULT_TOKEN_TTL),
+            wasm_execution_limit: DEFAULT_WASM_EXECUTION_LIMIT,
+            wasm_restart_limit: 0,
+            wasm_restarts: HashMap::new(),
+            wasm_log_size: DEFAULT_WASM_LOG_SIZE,
+            wasm_logs: HashMap::new(),
+            user: UfsUuid::new_user(user),
             block_manager,
             open_files: HashMap::new(),
             open_dirs: HashMap::new(),
+            dir_listings: HashMap::new(),
+            flushed: HashSet::new(),
+            write_buffers: HashMap::new(),
+            open_counts: HashMap::new(),
+            unlinked: HashMap::new(),
+            pruned_in_use: HashMap::new(),
+            quota_holds: HashMap::new(),
             open_file_counter: 0,
             program_mgr: None,
-        })
+            event_sender: None,
+            event_queue_size: DEFAULT_EVENT_QUEUE_SIZE,
+            event_priority: EventPriority::Foreground,
+            dropped_events: AtomicU64::new(0),
+            dropping_events: AtomicBool::new(false),
+            programs: HashSet::new(),
+            hot_paths: Vec::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            read_positions: HashMap::new(),
+            prefetcher: None,
+            flush_interval: Some(std::time::Duration::from_secs(DEFAULT_FLUSH_INTERVAL)),
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            read_only: false,
+        }
     }
-}
 
-impl<B: BlockStorage> UberFileSystem<B> {
-    /// Log a user into the file system
-    pub fn login(&mut self, user: String, password: String) -> Option<JWT> {
-        if let Some(user) = self.block_manager.metadata().get_user(user, password) {
-            // let token_id = user.0.new_with_timestamp();
-            let expiration = Utc::now() + Duration::minutes(5);
-            let secret: String = thread_rng().sample_iter(&Alphanumeric).take(20).collect();
+    /// Set the maximum directory depth
+    ///
+    /// Directories may not be created, or moved, any deeper than this. The depth is kept with the
+    /// file system's metadata.
+    pub fn set_max_path_depth(&mut self, depth: usize) {
+        self.block_manager.metadata_mut().set_max_depth(depth);
+    }
 
-            let tr = TokenRegistration {
-                user: user.0,
-                secret: secret.clone(),
-                key: user.1,
-            };
+    /// Return the file system's label
+    ///
+    /// This is `None` until `set_label` is called.
+    pub fn label(&self) -> Option<String> {
+        self.block_manager.metadata().label().map(|l| l.to_owned())
+    }
 
-            debug!("token secret {}", secret);
+    /// Set the file system's label
+    ///
+    /// The label is a display name, and is stored with the metadata. Changing it does not change
+    /// the file system's id.
+    pub fn set_label<S: AsRef<str>>(&mut self, label: S) {
+        self.block_manager
+            .metadata_mut()
+            .set_label(label.as_ref().to_owned());
+    }
 
-            let token = new_jwt(
-                UserClaims {
-                    iss: self.id,
-                    sub: user.0,
-                    exp: expiration.timestamp() as usize,
-                },
-                secret.as_bytes(),
-            );
-            // Insert the TokenRegistration into our map.
-            self.tokens.entry(token.clone()).or_insert(tr);
+    /// Return the percentage of the volume reserved for the latest version of each file
+    ///
+    pub fn reservation(&self) -> Option<u8> {
+        self.block_manager
+            .metadata()
+            .reservation()
+            .map(|r| r.percent())
+    }
+
+    /// Reserve a percentage of the volume for the latest version of each file
+    ///
+    /// When a write would leave less than this free, the oldest versions of files are pruned to
+    /// make room. With `None`, versions are kept until the volume is full, and writes fail from
+    /// there on. The setting is stored with the metadata.
+    pub fn set_reservation(&mut self, percent: Option<u8>) {
+        self.block_manager
+            .metadata_mut()
+            .set_reservation(percent.map(Reservation::new));
+    }
+
+    /// Return the file system's version retention policy
+    ///
+    pub fn retention(&self) -> Option<Retention> {
+        self.block_manager.metadata().retention()
+    }
+
+    /// Limit the versions kept of each file
+    ///
+    /// Versions beyond the limit are pruned whenever a new version of a file is committed. With
+    /// `None`, every version is kept. The setting is stored with the metadata, and may be
+    /// overridden for the files in a directory with `set_directory_retention`.
+    pub fn set_retention(&mut self, retention: Option<Retention>) {
+        self.block_manager.metadata_mut().set_retention(retention);
+    }
+
+    /// Limit the versions kept of the files in a directory
+    ///
+    /// This takes precedence over the file system's policy. With `None`, the directory goes back
+    /// to following the file system's policy.
+    pub fn set_directory_retention<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        retention: Option<Retention>,
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!(
+            "`set_directory_retention`: {:?}, {:?}",
+            path.as_ref(),
+            retention
+        );
+        let metadata = self.block_manager.metadata_mut();
+        match metadata.id_from_path(&path) {
+            Some(id) => metadata.set_directory_retention(id, retention),
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Limit the bytes used by a user, or a directory and those below it
+    ///
+    /// What's counted is the latest version of each file. A write, or a new file, that would go
+    /// over the limit fails, while others carry on. With `None`, there's no limit. The quota is
+    /// stored with the metadata.
+    pub fn set_quota(
+        &mut self,
+        target: QuotaTarget,
+        limit: Option<u64>,
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!("`set_quota`: {:?}, {:?}", target, limit);
+        self.block_manager.metadata_mut().set_quota(&target, limit)
+    }
+
+    /// Return the bytes used by a user, or a directory and those below it, and their quota
+    ///
+    pub fn get_usage(&self, target: QuotaTarget) -> Result<(u64, Option<u64>), failure::Error> {
+        self.block_manager.metadata().usage(&target)
+    }
+
+    /// Mark a path as hot
+    ///
+    /// The blocks of hot files are pre-read into the block cache when the file system is mounted,
+    /// so that the first access is quick. A hot directory warms the files within it, but not its
+    /// sub-directories. Note that WASM programs are read when mounted regardless.
+    pub fn add_hot_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.hot_paths.push(path.as_ref().to_path_buf());
+    }
+
+    /// Set the number of blocks that are kept in memory once read
+    ///
+    /// The least recently used blocks are dropped first. The cache is emptied, and its counters
+    /// reset. Zero disables the cache.
+    pub fn set_block_cache_size(&mut self, blocks: usize) {
+        self.block_manager.set_cache_capacity(blocks);
+    }
+
+    /// Return the number of block reads that the block cache saved, and didn't save
+    ///
+    pub fn block_cache_stats(&self) -> (u64, u64) {
+        (
+            self.block_manager.cache_hits(),
+            self.block_manager.cache_misses(),
+        )
+    }
+
+    /// Set how many blocks are read ahead of a sequential reader
+    ///
+    /// Once a file is being read sequentially, the next `blocks` blocks are fetched into the block
+    /// cache before they are asked for. Mounted, the blocks are fetched in the background. Zero
+    /// disables read-ahead.
+    pub fn set_read_ahead(&mut self, blocks: usize) {
+        self.read_ahead = blocks;
+        self.read_positions.clear();
+    }
+
+    /// Set how often changed metadata is written to storage, when mounted
+    ///
+    /// Otherwise the metadata is only written when a file is flushed, or the file system is shut
+    /// down, and a crash loses everything since. This must be set before mounting. With `None`,
+    /// or on a read-only file system, there's no background writing.
+    pub fn set_flush_interval(&mut self, interval: Option<std::time::Duration>) {
+        self.flush_interval = interval;
+    }
+
+    /// Set the window within which rapid reads, or writes, of a file are reported as one event
+    ///
+    /// The first event is delivered as it happens, and the last once the window has passed. This
+    /// must be set before mounting. A zero window reports every event.
+    pub fn set_coalesce_window(&mut self, window: std::time::Duration) {
+        self.coalesce_window = window;
+    }
+
+    /// Mount the file system read-only
+    ///
+    /// This must be set before mounting. Anything that would change the file system then fails,
+    /// and nothing is written to storage, not even access times. WASM programs that may write
+    /// aren't started.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        self.block_manager.set_read_only(read_only);
+    }
+
+    /// Return true if the file system is mounted read-only
+    ///
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fail if the file system is mounted read-only
+    ///
+    /// Everything that changes the file system checks this first.
+    pub(crate) fn check_writable(&self) -> Result<(), failure::Error> {
+        if self.read_only {
+            warn!("asked to change a read-only file system");
+            return Err(IOFSErrorKind::ReadOnly.into());
+        }
+
+        Ok(())
+    }
+
+    /// Log a user into the file system
+    pub fn login(&mut self, user: String, password: String) -> Option<JWT> {
+        if let Some(user) = self.block_manager.metadata().get_user(user, password) {
+            let jti = user.0.new_with_timestamp();
+            let expiration = Utc::now() + self.token_ttl;
+            let secret: String = thread_rng().sample_iter(&Alphanumeric).take(20).collect();
+
+            let tr = TokenRegistration {
+                user: user.0,
+                jti,
+                issued: SystemTime::now(),
+                secret: secret.clone(),
+                key: user.1,
+            };
+
+            debug!("token secret {}", secret);
+
+            let token = new_jwt(
+                UserClaims {
+                    iss: self.id,
+                    sub: user.0,
+                    jti,
+                    exp: expiration.timestamp() as usize,
+                },
+                secret.as_bytes(),
+            );
+            // Insert the TokenRegistration into our map.
+            self.tokens.entry(token.clone()).or_insert(tr);
 
             Some(token)
         } else {
@@ -312,10 +1131,86 @@ impl<B: BlockStorage> UberFileSystem<B> {
         }
     }
 
+    /// Set how long tokens issued by `login` are good for
+    ///
+    /// Tokens that have already been issued keep the lifetime they were issued with.
+    pub fn set_token_ttl(&mut self, ttl: Duration) {
+        self.token_ttl = ttl;
+    }
+
+    /// Set the execution limit for WASM programs
+    ///
+    /// Each time a program is invoked, be it to handle a file system event, an HTTP request, or a
+    /// message, it may spend at most `limit` points. A program that exceeds the limit is
+    /// terminated. The limit applies to programs started after it's set.
+    ///
+    /// Programs are only metered on x86_64, elsewhere the limit isn't enforced.
+    pub fn set_wasm_execution_limit(&mut self, limit: u64) {
+        self.wasm_execution_limit = limit;
+    }
+
+    pub(crate) fn wasm_execution_limit(&self) -> u64 {
+        self.wasm_execution_limit
+    }
+
+    /// Set how many times a crashed WASM program is restarted
+    ///
+    /// A program that panics, or returns an error, is restarted after a short delay, which doubles
+    /// with each consecutive attempt. Once `attempts` restarts have failed the program is given up
+    /// on, until it's written anew. Restarts are disabled by default.
+    pub fn set_wasm_restart_limit(&mut self, attempts: u32) {
+        self.wasm_restart_limit = attempts;
+    }
+
+    pub(crate) fn wasm_restart_limit(&self) -> u32 {
+        self.wasm_restart_limit
+    }
+
+    /// Set the size at which WASM program log files are rotated
+    ///
+    /// Whatever a program prints is appended to a log file next to the program, so that
+    /// `/.wasm/foo.wasm` logs to `/.wasm/foo.log`. Once the log reaches `size` bytes it's moved to
+    /// `foo.log.1`, replacing the one before it, and a new log is started. A size of zero turns the
+    /// log files off.
+    pub fn set_wasm_log_size(&mut self, size: FileSize) {
+        self.wasm_log_size = size;
+    }
+
+    pub(crate) fn wasm_restarts(&self) -> &HashMap<PathBuf, RestartStatus> {
+        &self.wasm_restarts
+    }
+
+    pub(crate) fn wasm_restarts_mut(&mut self) -> &mut HashMap<PathBuf, RestartStatus> {
+        &mut self.wasm_restarts
+    }
+
     /// Validate a previously issued token
+    ///
+    /// Expired tokens are rejected with `TokenExpired`, and forgotten, after which they are
+    /// rejected as `UnknownToken`, as are tokens that we never issued.
     pub fn validate_token(&mut self, token: JWT) -> Result<(), failure::Error> {
-        if let Some(tr) = self.tokens.get(&token) {
+        let result = self.check_token(&token);
+        if let Err(e) = &result {
+            if let Some(IOFSErrorKind::TokenExpired) = e.downcast_ref::<IOFSErrorKind>() {
+                debug!("removed token: {}", token);
+                self.tokens.remove(&token);
+            }
+        }
+
+        result
+    }
+
+    /// Validate a token, without forgetting it if it has expired
+    ///
+    /// This only needs the read lock. An expired token is forgotten the next time that it's passed
+    /// to `validate_token`.
+    pub(crate) fn check_token(&self, token: &JWT) -> Result<(), failure::Error> {
+        if let Some(tr) = self.tokens.get(token) {
             match decode_jwt(token.clone(), &tr.secret) {
+                Ok(ref claims) if claims.jti != tr.jti => {
+                    error!("access attempt with token: {}", token);
+                    Err(IOFSErrorKind::UnknownToken.into())
+                }
                 Ok(_) => {
                     debug!("validated token: {}", token);
                     Ok(())
@@ -323,11 +1218,7 @@ impl<B: BlockStorage> UberFileSystem<B> {
                 // Decoding will check the expiration
                 Err(e) => match e.as_fail().downcast_ref::<IOFSErrorKind>() {
                     Some(e) => match e {
-                        IOFSErrorKind::TokenExpired => {
-                            debug!("removed token: {}", token);
-                            self.tokens.remove(&token);
-                            Err(IOFSErrorKind::TokenExpired.into())
-                        }
+                        IOFSErrorKind::TokenExpired => Err(IOFSErrorKind::TokenExpired.into()),
                         _ => {
                             error!("access attempt with token: {}", token);
                             Err(IOFSErrorKind::TokenError.into())
@@ -344,6 +1235,181 @@ impl<B: BlockStorage> UberFileSystem<B> {
         }
     }
 
+    /// Validate a token, and check that it was issued to the owner of the file system
+    ///
+    /// Operations that go around the file system, such as writing raw blocks, are only for the
+    /// user that owns it.
+    pub(crate) fn check_admin(&self, token: &JWT) -> Result<(), failure::Error> {
+        self.check_token(token)?;
+        let owner = self.block_manager.metadata().root_directory().owner();
+        match self.tokens.get(token) {
+            Some(tr) if tr.user == owner => Ok(()),
+            _ => Err(IOFSErrorKind::NotAdmin.into()),
+        }
+    }
+
+    /// Return true if the holder of `token` may watch events on `id`, in `parent_id`
+    ///
+    /// The owner of the file system sees everything. Anyone else sees events on what they own. Once
+    /// the subject of an event is gone, e.g. it was deleted, the owner of its directory stands in.
+    pub(crate) fn may_watch(&self, token: &JWT, id: UfsUuid, parent_id: UfsUuid) -> bool {
+        if self.check_admin(token).is_ok() {
+            return true;
+        }
+
+        let user = match self.tokens.get(token) {
+            Some(tr) => tr.user,
+            None => return false,
+        };
+        let metadata = self.block_manager.metadata();
+        metadata
+            .owner_of(id)
+            .or_else(|| metadata.owner_of(parent_id))
+            .map_or(false, |owner| owner == user)
+    }
+
+    /// Turn hashing of file data on, or off
+    ///
+    /// Each block of file data is hashed as it's written, and the hash checked when the block is
+    /// read. Volumes that don't need that protection, e.g. ephemeral memory volumes, may save the
+    /// CPU. Content hashes, and thus the volume root hash, are unavailable for files written
+    /// while hashing is off.
+    pub fn set_block_hashing(&mut self, enable: bool) {
+        self.block_manager.set_block_hashing(enable);
+    }
+
+    /// Turn deduplication of file data on, or off
+    ///
+    /// While it's on, a block of file data that's already stored, in this file or any other, is
+    /// shared rather than written again. Shared blocks are freed once nothing refers to them.
+    pub fn set_block_dedup(&mut self, enable: bool) {
+        self.block_manager.set_block_dedup(enable);
+    }
+
+    /// Turn tracking of block access times on, or off
+    ///
+    /// When each block of file data was last read, or written, is kept in the block map, so that
+    /// cold blocks may be moved to cheaper storage. It's off by default, as the map is then
+    /// written more often.
+    pub fn set_access_tracking(&mut self, enable: bool) {
+        self.block_manager.set_access_tracking(enable);
+    }
+
+    /// Return the blocks of file data that haven't been read, or written, within `age`
+    ///
+    /// Nothing is returned while access tracking is off.
+    pub fn cold_blocks(&self, age: Duration) -> Vec<BlockNumber> {
+        self.block_manager.blocks_older_than(age)
+    }
+
+    /// Add capacity to the file system
+    ///
+    /// `additional_blocks` free blocks are added to the end of the block storage. Not every kind
+    /// of storage may be grown: network storage belongs to the server.
+    pub fn grow(&mut self, additional_blocks: BlockCardinality) -> Result<(), failure::Error> {
+        info!("growing file system by {} blocks", additional_blocks);
+        self.block_manager.grow(additional_blocks)
+    }
+
+    /// Configure the queue of events bound for WASM programs
+    ///
+    /// With `EventPriority::Foreground`, at most `size` events wait for the WASM runtime, and any
+    /// more are dropped. The size is ignored with `EventPriority::Events`. This must be set before
+    /// the file system is mounted.
+    pub fn set_event_queue(&mut self, size: usize, priority: EventPriority) {
+        self.event_queue_size = size;
+        self.event_priority = priority;
+    }
+
+    /// The number of events dropped because the event queue was full, or the runtime went away
+    ///
+    pub fn dropped_event_count(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Create the event queue, per the configuration
+    ///
+    fn new_event_queue(
+        &self,
+    ) -> (
+        crossbeam_channel::Sender<IofsMessage>,
+        crossbeam_channel::Receiver<IofsMessage>,
+    ) {
+        match self.event_priority {
+            EventPriority::Foreground => crossbeam_channel::bounded(self.event_queue_size),
+            EventPriority::Events => crossbeam_channel::unbounded(),
+        }
+    }
+
+    /// Queue an event for the WASM runtime
+    ///
+    /// This never waits for the runtime: if the queue is full, or the runtime has gone away, the
+    /// event is dropped. Dropped events are counted, see `dropped_event_count`, and the first of a
+    /// run of them is logged as an error. When an event is queued again, the count so far is
+    /// logged.
+    fn send_event(&self, msg: IofsMessage) {
+        if let Some(events) = &self.event_sender {
+            let reason = match events.try_send(msg) {
+                Ok(_) => {
+                    if self.dropping_events.swap(false, Ordering::Relaxed) {
+                        warn!(
+                            "event queue accepting events again, {} dropped so far",
+                            self.dropped_event_count()
+                        );
+                    }
+                    return;
+                }
+                Err(crossbeam_channel::TrySendError::Full(msg)) => {
+                    debug!("event queue full, dropping {:?}", msg);
+                    "the event queue is full"
+                }
+                Err(crossbeam_channel::TrySendError::Disconnected(msg)) => {
+                    debug!("Wasm runtime went away, dropping {:?}", msg);
+                    "the Wasm runtime went away"
+                }
+            };
+
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+            if !self.dropping_events.swap(true, Ordering::Relaxed) {
+                error!("dropping events for Wasm programs: {}", reason);
+            }
+        }
+    }
+
+    /// Set the manner in which file data is split into blocks
+    ///
+    /// Content defined chunking only applies to the data passed to a single `write_file`, so it's
+    /// most effective when files are written in large pieces. The mode is stored with the block
+    /// map, and so belongs to the volume.
+    pub fn set_chunking_mode(&mut self, mode: ChunkingMode) {
+        self.block_manager.set_chunking_mode(mode);
+    }
+
+    /// Log out of the file system
+    ///
+    /// The token is forgotten, and may no longer be used.
+    pub fn logout(&mut self, token: JWT) -> Result<(), failure::Error> {
+        match self.tokens.remove(&token) {
+            Some(tr) => {
+                debug!("logged out session {}", tr.jti);
+                Ok(())
+            }
+            None => Err(IOFSErrorKind::UnknownToken.into()),
+        }
+    }
+
+    /// List the active sessions
+    ///
+    /// Each session is a tuple of the user's id, the token's id, and the time that the token was
+    /// issued. Note that expired tokens are only removed when they are next used, so they may be
+    /// included here.
+    pub fn active_sessions(&self) -> Vec<(UfsUuid, UfsUuid, SystemTime)> {
+        self.tokens
+            .values()
+            .map(|tr| (tr.user, tr.jti, tr.issued))
+            .collect()
+    }
+
     /// Add a user to the file system
     pub fn add_user(&mut self, user: String, password: String) {
         self.block_manager.metadata_mut().add_user(user, password);
@@ -354,6 +1420,45 @@ impl<B: BlockStorage> UberFileSystem<B> {
         self.block_manager.metadata().get_users()
     }
 
+    /// Remove a user
+    ///
+    /// The user's files, and directories, are given to the owner of the file system, and any
+    /// tokens issued to the user are revoked. The owner of the file system may not be removed.
+    pub fn remove_user(&mut self, user: String) -> Result<(), failure::Error> {
+        let id = self.block_manager.metadata_mut().remove_user(user)?;
+        self.revoke_tokens(id);
+        Ok(())
+    }
+
+    /// Change a user's password
+    ///
+    /// The user's key doesn't change, so their data remains readable. Any tokens issued to the
+    /// user are revoked, and they must log in again.
+    pub fn change_password(
+        &mut self,
+        user: String,
+        old: String,
+        new: String,
+    ) -> Result<(), failure::Error> {
+        let id = self
+            .block_manager
+            .metadata_mut()
+            .change_password(user, old, new)?;
+        self.revoke_tokens(id);
+        Ok(())
+    }
+
+    /// Forget every token issued to a user
+    ///
+    fn revoke_tokens(&mut self, user: UfsUuid) {
+        self.tokens.retain(|_, tr| {
+            if tr.user == user {
+                debug!("revoked session {}", tr.jti);
+            }
+            tr.user != user
+        });
+    }
+
     /// This is used by the fuse implementation as an inode ID.
     pub(crate) fn get_root_directory_id(&self) -> UfsUuid {
         self.block_manager.metadata().root_directory().id()
@@ -363,9 +1468,100 @@ impl<B: BlockStorage> UberFileSystem<B> {
     ///
     /// We setup our channel to the `RuntimeManager`. Then we search for any .wasm files in .wasm
     /// directories, and create runtimes for them.
-    fn init_runtime(&mut self, mgr: crossbeam_channel::Sender<RuntimeManagerMsg>) {
+    fn init_runtime(
+        &mut self,
+        mgr: crossbeam_channel::Sender<RuntimeManagerMsg>,
+        events: crossbeam_channel::Sender<IofsMessage>,
+    ) {
         self.program_mgr = Some(mgr);
+        self.event_sender = Some(events);
+
+        self.warm_cache();
+
+        for (path, file) in self.find_wasm_programs() {
+            info!("Adding existing program {:?} to runtime.", path);
+            self.start_program(path, &file);
+        }
+    }
+
+    /// Read the blocks of the hot paths into the block cache
+    ///
+    fn warm_cache(&self) {
+        let metadata = self.block_manager.metadata();
+        for path in &self.hot_paths {
+            let files = match metadata.id_from_path(path) {
+                Some(id) => match metadata.lookup_file(id) {
+                    Some(file) => vec![file.clone()],
+                    None => match metadata.get_directory(id) {
+                        Ok(dir) => dir
+                            .entries()
+                            .values()
+                            .filter_map(|entry| match entry {
+                                DirectoryEntry::File(file) => Some(file.clone()),
+                                _ => None,
+                            })
+                            .collect(),
+                        Err(_) => vec![],
+                    },
+                },
+                None => {
+                    warn!("hot path {:?} does not exist", path);
+                    vec![]
+                }
+            };
 
+            for file in files {
+                for bn in file.get_latest().blocks() {
+                    if let Err(e) = self.block_manager.prefetch(*bn) {
+                        warn!("unable to warm block {} of {:?}: {}", bn, path, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Re-scan the file system for WASM programs
+    ///
+    /// Programs are normally noticed when they are written, or when the file system is mounted.
+    /// Something that side-steps both, e.g., importing a file, can call this to get the runtime
+    /// back in sync: new programs are started, and programs that no longer exist are stopped.
+    pub fn rescan_programs(&mut self) {
+        if self.program_mgr.is_none() {
+            return;
+        }
+
+        let found = self.find_wasm_programs();
+        let found_paths = found
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<HashSet<_>>();
+
+        for (path, file) in found {
+            if !self.programs.contains(&path) {
+                info!("Adding program {:?} to runtime", path);
+                self.block_manager
+                    .metadata_mut()
+                    .add_wasm_program_grants(path.clone());
+                self.start_program(path, &file);
+            }
+        }
+
+        let removed = self
+            .programs
+            .difference(&found_paths)
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in removed {
+            self.stop_program(&path);
+            let metadata = self.block_manager.metadata_mut();
+            metadata.remove_wasm_program_grants(&path);
+            metadata.remove_wasm_program_values(&path);
+        }
+    }
+
+    /// Find all .wasm files in .wasm directories
+    ///
+    fn find_wasm_programs(&self) -> Vec<(PathBuf, FileMetadata)> {
         fn find_wasm_pgms(
             programs: &mut Vec<(PathBuf, FileMetadata)>,
             metadata: &Metadata,
@@ -376,14 +1572,16 @@ impl<B: BlockStorage> UberFileSystem<B> {
                 if let DirectoryEntry::Directory(dir) = d {
                     if dir.is_wasm_dir() {
                         for (f_name, e) in dir.entries() {
-                            if let DirectoryEntry::File(file) = e {
+                            if let DirectoryEntry::FileLink(id) = e {
                                 let path = Path::new(f_name);
                                 if let Some(ext) = path.extension() {
                                     if ext == WASM_EXT {
-                                        programs.push((
-                                            metadata.path_from_file_id(file.id()),
-                                            file.clone(),
-                                        ));
+                                        if let Some(file) = metadata.lookup_file(*id) {
+                                            programs.push((
+                                                metadata.path_from_file_id(*id),
+                                                file.clone(),
+                                            ));
+                                        }
                                     }
                                 }
                             }
@@ -402,25 +1600,91 @@ impl<B: BlockStorage> UberFileSystem<B> {
             self.block_manager.metadata().root_directory(),
         );
 
-        // Broken out to make borrowing happy.
-        if let Some(program_mgr) = self.program_mgr.clone() {
-            for (path, file) in programs {
-                if let Ok(fh) = self.open_file(file.id(), OpenFileMode::Read) {
-                    let version = file.get_latest();
-                    let size = version.size();
-                    if let Ok(program) = self.read_file(fh, 0, size as u32) {
-                        info!("Adding existing program {:?} to runtime.", path);
-                        program_mgr
-                            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
-                                path, program,
-                            )))
-                            .unwrap()
-                    }
+        programs
+    }
+
+    /// Read a WASM program, and it's config, and send it to the runtime
+    ///
+    fn start_program(&mut self, path: PathBuf, file: &FileMetadata) {
+        if self.read_only && self.block_manager.metadata().wasm_program_may_write(&path) {
+            warn!(
+                "not starting {:?}, which may write to a read-only file system",
+                path
+            );
+            return;
+        }
+
+        if let Ok(fh) = self.open_file(file.id(), OpenFileMode::Read) {
+            let size = file.get_latest().size();
+            let program = self.read_file(fh, 0, size as u32);
+            let _ = self.close_file(fh);
+
+            if let Ok(program) = program {
+                let config = self.read_wasm_program_config(&path);
+                if let Some(program_mgr) = &self.program_mgr {
+                    program_mgr
+                        .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                            path.clone(),
+                            program,
+                            config,
+                        )))
+                        .unwrap();
+                    self.programs.insert(path);
                 }
             }
         }
     }
 
+    /// Ask the runtime to stop a WASM program
+    ///
+    fn stop_program(&mut self, path: &Path) {
+        if let Some(program_mgr) = &self.program_mgr {
+            program_mgr
+                .send(RuntimeManagerMsg::Stop(path.to_path_buf()))
+                .expect("unable to send message to Runtime Manager");
+        }
+        self.programs.remove(path);
+    }
+
+    /// Read the configuration for a WASM program
+    ///
+    /// The configuration lives next to the program in the `.wasm` directory, with the same name
+    /// and a `.config` extension. So `/.wasm/foo.wasm` is configured by `/.wasm/foo.config`. The
+    /// contents are passed, as is, to the program when it's started.
+    pub(crate) fn read_wasm_program_config<P: AsRef<Path>>(&mut self, path: P) -> Option<String> {
+        let config_path = path.as_ref().with_extension(WASM_CONFIG_EXT);
+        let id = self.block_manager.metadata().id_from_path(&config_path)?;
+        let size = match self.block_manager.metadata().get_file_metadata(id) {
+            Ok(file) => file.get_latest().size(),
+            Err(_) => return None,
+        };
+
+        let fh = self.open_file(id, OpenFileMode::Read).ok()?;
+        let config = match self.read_file(fh, 0, size as u32) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    error!("invalid UTF-8 in WASM config {:?}: {}", config_path, e);
+                    None
+                }
+            },
+            Err(e) => {
+                error!("unable to read WASM config {:?}: {}", config_path, e);
+                None
+            }
+        };
+        let _ = self.close_file(fh);
+
+        debug!("config for {:?}: {:?}", path.as_ref(), config);
+        config
+    }
+
+    /// The number of files that are currently open
+    ///
+    pub(crate) fn open_file_count(&self) -> usize {
+        self.open_files.len()
+    }
+
     /// Return a reference to the `BlockManager`
     ///
     pub(crate) fn block_manager(&self) -> &BlockManager<B> {
@@ -453,6 +1717,78 @@ impl<B: BlockStorage> UberFileSystem<B> {
         }
     }
 
+    /// Return a page of the entries in an open directory, and the number of entries in all
+    ///
+    /// Entries are listed directories first, and otherwise in the directory's listing order. The
+    /// order is worked out when the directory is opened, so that paging through a large directory
+    /// doesn't sort it over again for each page.
+    pub(crate) fn list_files_paged(
+        &self,
+        handle: FileHandle,
+        offset: usize,
+        limit: usize,
+    ) -> Option<(Vec<(&str, &DirectoryEntry)>, usize)> {
+        debug!("-------");
+        debug!(
+            "`list_files_paged`: {}, offset: {}, limit: {}",
+            handle, offset, limit
+        );
+        match (self.open_dirs.get(&handle), self.dir_listings.get(&handle)) {
+            (Some(dir), Some(listing)) => {
+                let page = listing[offset.min(listing.len())..]
+                    .iter()
+                    .take(limit)
+                    .filter_map(|name| dir.entries().get(name).map(|e| (name.as_str(), e)))
+                    .collect();
+                Some((page, listing.len()))
+            }
+            _ => {
+                warn!("\tdirectory not opened");
+                None
+            }
+        }
+    }
+
+    /// Return a page of the entries in a directory, and the number of entries in all
+    ///
+    /// This is `list_files_paged` for a directory that isn't open, and so only needs the read
+    /// lock. The directory is sorted on each call.
+    pub(crate) fn list_directory_paged(
+        &self,
+        id: UfsUuid,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<(String, DirectoryEntry)>, usize), failure::Error> {
+        let dir = self.block_manager.metadata().get_directory(id)?;
+        let listing = listing_order(&dir);
+        let page = listing[offset.min(listing.len())..]
+            .iter()
+            .take(limit)
+            .filter_map(|name| dir.entries().get(name).map(|e| (name.clone(), e.clone())))
+            .collect();
+
+        Ok((page, listing.len()))
+    }
+
+    /// Set the order in which a directory's entries are listed
+    ///
+    /// The order is stored with the directory, and is honored by both FUSE and the HTTP view.
+    /// Directories are listed by name, ascending, unless set otherwise.
+    pub fn set_sort_order<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        order: SortOrder,
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!("`set_sort_order`: {:?}, {:?}", path.as_ref(), order);
+        self.check_writable()?;
+        let metadata = self.block_manager.metadata_mut();
+        match metadata.id_from_path(&path) {
+            Some(id) => metadata.set_sort_order(id, order),
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
+
     /// Create a directory
     ///
     pub(crate) fn create_directory(
@@ -462,22 +1798,21 @@ impl<B: BlockStorage> UberFileSystem<B> {
     ) -> Result<DirectoryMetadata, failure::Error> {
         debug!("--------");
         debug!("`create_directory`: {}", name);
+        self.check_writable()?;
 
         let dir = self
             .block_manager
             .metadata_mut()
             .new_directory(parent_id, name, self.user)?;
 
-        if let Some(program_mgr) = &self.program_mgr {
-            program_mgr
-                .send(RuntimeManagerMsg::IofsMessage(IofsMessage::DirMessage(
-                    IofsDirMessage::Create(IofsMessagePayload {
-                        target_path: self.block_manager.metadata().path_from_dir_id(dir.id()),
-                        target_id: dir.id(),
-                        parent_id,
-                    }),
-                )))
-                .expect("Wasm Runtime went away");
+        if self.event_sender.is_some() {
+            self.send_event(IofsMessage::DirMessage(IofsDirMessage::Create(
+                IofsMessagePayload {
+                    target_path: self.block_manager.metadata().path_from_dir_id(dir.id()),
+                    target_id: dir.id(),
+                    parent_id,
+                },
+            )));
         }
 
         // self.notify_listeners(UfsMessage::DirCreate(
@@ -488,6 +1823,60 @@ impl<B: BlockStorage> UberFileSystem<B> {
         Ok(dir)
     }
 
+    /// Create a symbolic link
+    ///
+    /// The target isn't checked; it's resolved when the link is followed.
+    pub(crate) fn create_symlink<P: AsRef<Path>>(
+        &mut self,
+        dir_id: UfsUuid,
+        name: &str,
+        target: P,
+    ) -> Result<SymlinkMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`create_symlink`: {} -> {:?}", name, target.as_ref());
+
+        self.check_writable()?;
+        self.block_manager
+            .metadata_mut()
+            .new_symlink(dir_id, name, target)
+    }
+
+    /// Create a hard link to a file
+    ///
+    /// The file `id` also appears as `name` in the directory `dir_id`. The file's blocks are
+    /// recycled only once every link to it has been removed.
+    pub(crate) fn link(
+        &mut self,
+        id: UfsUuid,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<FileMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`link`: {:?} as {}, dir: {:?}", id, name, dir_id);
+
+        self.check_writable()?;
+        let file = self
+            .block_manager
+            .metadata_mut()
+            .link_file(id, dir_id, name)?;
+
+        if self.event_sender.is_some() {
+            self.send_event(IofsMessage::FileMessage(IofsFileMessage::Create(
+                IofsMessagePayload {
+                    target_path: self
+                        .block_manager
+                        .metadata()
+                        .path_from_dir_id(dir_id)
+                        .join(name),
+                    target_id: id,
+                    parent_id: dir_id,
+                },
+            )));
+        }
+
+        Ok(file)
+    }
+
     /// Create a file
     ///
     pub(crate) fn create_file(
@@ -497,25 +1886,29 @@ impl<B: BlockStorage> UberFileSystem<B> {
     ) -> Result<(FileHandle, File), failure::Error> {
         debug!("--------");
 
+        self.check_writable()?;
+        self.block_manager
+            .metadata()
+            .check_new_file_quota(dir_id, 0)?;
         let file = self.block_manager.metadata_mut().new_file(dir_id, name)?;
 
         let fh = self.open_file_counter;
         self.open_file_counter = self.open_file_counter.wrapping_add(1);
-        self.open_files.insert(fh, file.clone());
-
-        if let Some(program_mgr) = &self.program_mgr {
-            program_mgr
-                .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                    IofsFileMessage::Create(IofsMessagePayload {
-                        target_path: self
-                            .block_manager
-                            .metadata()
-                            .path_from_file_id(file.file_id),
-                        target_id: file.file_id,
-                        parent_id: dir_id,
-                    }),
-                )))
-                .expect("Wasm Runtime went away");
+        self.open_files
+            .insert(fh, (file.clone(), OpenFileMode::ReadWrite));
+        *self.open_counts.entry(file.file_id).or_insert(0) += 1;
+
+        if self.event_sender.is_some() {
+            self.send_event(IofsMessage::FileMessage(IofsFileMessage::Create(
+                IofsMessagePayload {
+                    target_path: self
+                        .block_manager
+                        .metadata()
+                        .path_from_file_id(file.file_id),
+                    target_id: file.file_id,
+                    parent_id: dir_id,
+                },
+            )));
         }
 
         // self.notify_listeners(UfsMessage::FileCreate(
@@ -528,6 +1921,101 @@ impl<B: BlockStorage> UberFileSystem<B> {
         Ok((fh, file))
     }
 
+    /// Copy a file, without copying its data
+    ///
+    /// The copy, `name` in `dir_id`, starts out with the blocks of the latest version of
+    /// `file_id`, which the two files then share. Writes to either go to new blocks, so neither
+    /// sees the other's changes. Older versions aren't copied.
+    pub(crate) fn copy_file(
+        &mut self,
+        file_id: UfsUuid,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<File, failure::Error> {
+        debug!("--------");
+        debug!("`copy_file`: {:?} to {:?} in {:?}", file_id, name, dir_id);
+
+        self.check_writable()?;
+        let source = self.block_manager.metadata().get_file_read_only(file_id)?;
+        self.block_manager
+            .metadata()
+            .check_new_file_quota(dir_id, source.version.size())?;
+
+        let mut shared = vec![];
+        let copy = match self.share_blocks(&source, &mut shared) {
+            Ok(blocks) => self
+                .block_manager
+                .metadata_mut()
+                .new_file(dir_id, name)
+                .map(|copy| (copy, blocks)),
+            Err(e) => Err(e),
+        };
+        let (mut copy, blocks) = match copy {
+            Ok(copy) => copy,
+            Err(e) => {
+                // Give back the references taken so far.
+                self.block_manager.recycle_blocks(&shared);
+                return Err(e);
+            }
+        };
+
+        for (block, offset) in blocks.iter().zip(source.version.offsets()) {
+            copy.version.append_block(block, *offset);
+        }
+        // The last block holds more than the file does, if the file was truncated within it.
+        copy.version.truncate(blocks.len(), source.version.size());
+        let pruned = self
+            .block_manager
+            .metadata_mut()
+            .commit_file(copy.clone())?;
+        self.recycle_pruned(copy.file_id, &pruned);
+
+        if self.event_sender.is_some() {
+            self.send_event(IofsMessage::FileMessage(IofsFileMessage::Create(
+                IofsMessagePayload {
+                    target_path: self
+                        .block_manager
+                        .metadata()
+                        .path_from_file_id(copy.file_id),
+                    target_id: copy.file_id,
+                    parent_id: dir_id,
+                },
+            )));
+        }
+
+        self.block_manager
+            .metadata()
+            .get_file_read_only(copy.file_id)
+    }
+
+    /// Share the blocks of a file's latest version, so that another file may use them
+    ///
+    /// The blocks are returned in order. Those that have been shared so far are added to `shared`,
+    /// whether or not it all works out.
+    fn share_blocks(
+        &mut self,
+        file: &File,
+        shared: &mut Vec<BlockNumber>,
+    ) -> Result<Vec<Block>, failure::Error> {
+        let nonce = file.version.nonce();
+        let mut blocks = vec![];
+        for (number, offset) in file.version.blocks().iter().zip(file.version.offsets()) {
+            let block = match self.block_manager.get_block(*number) {
+                Some(block) => block.clone(),
+                None => return Err(format_err!("missing block {}", number)),
+            };
+            // A file holds one reference to a block, however many times it uses it.
+            if !shared.contains(number) {
+                self.block_manager
+                    .share_block(*number, nonce.clone(), *offset)?;
+                shared.push(*number);
+            }
+            blocks.push(block);
+        }
+
+        Ok(blocks)
+    }
+
     /// Open a directory
     ///
     pub(crate) fn open_directory(&mut self, id: UfsUuid) -> Result<FileHandle, failure::Error> {
@@ -538,6 +2026,7 @@ impl<B: BlockStorage> UberFileSystem<B> {
         self.open_file_counter = self.open_file_counter.wrapping_add(1);
 
         trace!("\t{:#?}", dir);
+        self.dir_listings.insert(fh, listing_order(&dir));
         self.open_dirs.insert(fh, dir);
 
         debug!("`open_directory`: {:?}, handle: {}", id, fh);
@@ -551,6 +2040,7 @@ impl<B: BlockStorage> UberFileSystem<B> {
     pub(crate) fn close_directory(&mut self, handle: FileHandle) {
         debug!("--------");
 
+        self.dir_listings.remove(&handle);
         match self.open_dirs.remove(&handle) {
             Some(dir) => {
                 debug!("`close_directory`: handle: {}", handle);
@@ -560,38 +2050,126 @@ impl<B: BlockStorage> UberFileSystem<B> {
         }
     }
 
+    /// Move, and/or rename, a file, directory, or symbolic link
+    ///
+    /// An existing entry at the destination is replaced, as described by
+    /// `Metadata::rename_entry`, and the blocks of a replaced file are recycled. As with
+    /// `remove_file`, a replaced file that is still open keeps its blocks until the last handle is
+    /// closed. WASM programs are sent the entry's new path.
+    pub(crate) fn rename_entry(
+        &mut self,
+        parent_id: UfsUuid,
+        name: &str,
+        new_parent_id: UfsUuid,
+        new_name: &str,
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!(
+            "`rename_entry`: {} to {}, parent: {:?}",
+            name, new_name, new_parent_id
+        );
+        self.check_writable()?;
+
+        let open_id = self
+            .block_manager
+            .metadata()
+            .get_file_metadata_from_dir_and_name(new_parent_id, new_name)
+            .ok()
+            .map(|file| file.id())
+            .filter(|id| self.open_counts.contains_key(id));
+
+        let (entry, free_blocks) = self.block_manager.metadata_mut().rename_entry(
+            parent_id,
+            name,
+            new_parent_id,
+            new_name,
+        )?;
+
+        // Only the last link matters.
+        let open_id = open_id.filter(|id| self.block_manager.metadata().lookup_file(*id).is_none());
+        match open_id {
+            Some(id) => {
+                debug!("\tdeferring recycling of open file {:?}", id);
+                self.unlinked.insert(id, free_blocks);
+            }
+            None => self.block_manager.recycle_blocks(&free_blocks),
+        }
+
+        self.refresh_open_dirs(&[entry.id(), parent_id, new_parent_id]);
+
+        if self.event_sender.is_some() {
+            let payload = IofsMessagePayload {
+                target_path: self
+                    .block_manager
+                    .metadata()
+                    .path_from_dir_id(new_parent_id)
+                    .join(new_name),
+                target_id: entry.id(),
+                parent_id: new_parent_id,
+            };
+            let msg = if entry.is_dir() {
+                IofsMessage::DirMessage(IofsDirMessage::Rename(payload))
+            } else {
+                IofsMessage::FileMessage(IofsFileMessage::Rename(payload))
+            };
+            self.send_event(msg);
+        }
+
+        Ok(())
+    }
+
+    /// Refresh cached directories
+    ///
+    /// Open directory handles hold a copy of the `DirectoryMetadata`, which goes stale when an
+    /// entry is moved in, or out, of the directory.
+    fn refresh_open_dirs(&mut self, affected: &[UfsUuid]) {
+        for (handle, cached) in self.open_dirs.iter_mut() {
+            if affected.contains(&cached.id()) {
+                if let Ok(fresh) = self.block_manager.metadata().get_directory(cached.id()) {
+                    self.dir_listings.insert(*handle, listing_order(&fresh));
+                    *cached = fresh;
+                }
+            }
+        }
+    }
+
     /// Remove a directory
     ///
+    /// Only empty directories may be removed, and never the special ".wasm" and ".vers"
+    /// directories.
     pub(crate) fn remove_directory(
         &mut self,
         parent_id: UfsUuid,
         name: &str,
     ) -> Result<(), failure::Error> {
-        if let Ok(dir) = self
+        self.check_writable()?;
+
+        // The path has to be found before the directory is gone.
+        let payload = self
             .block_manager
             .metadata()
             .get_dir_metadata_from_dir_and_name(parent_id, name)
-        {
-            if let Some(program_mgr) = &self.program_mgr {
-                program_mgr
-                    .send(RuntimeManagerMsg::IofsMessage(IofsMessage::DirMessage(
-                        IofsDirMessage::Delete(IofsMessagePayload {
-                            target_path: self.block_manager.metadata().path_from_dir_id(dir.id()),
-                            target_id: dir.id(),
-                            parent_id,
-                        }),
-                    )))
-                    .expect("Wasm Runtime went away");
-            }
-        }
+            .map(|dir| IofsMessagePayload {
+                target_path: self.block_manager.metadata().path_from_dir_id(dir.id()),
+                target_id: dir.id(),
+                parent_id,
+            });
 
         self.block_manager
             .metadata_mut()
-            .remove_directory(parent_id, name)
+            .remove_directory(parent_id, name)?;
+
+        if let Ok(payload) = payload {
+            self.send_event(IofsMessage::DirMessage(IofsDirMessage::Delete(payload)));
+        }
+
+        Ok(())
     }
 
     /// Remove a file
     ///
+    /// As with POSIX, a file that is open remains readable, and writable, through its open handles.
+    /// Its blocks are recycled when the last of them is closed, rather than straight away.
     pub(crate) fn remove_file(
         &mut self,
         dir_id: UfsUuid,
@@ -599,6 +2177,20 @@ impl<B: BlockStorage> UberFileSystem<B> {
     ) -> Result<(), failure::Error> {
         debug!("--------");
         debug!("`remove_file`: {}, dir: {:?}", name, dir_id);
+        self.check_writable()?;
+
+        // Removing a version of an open file could pull its blocks out from under the reader.
+        let metadata = self.block_manager.metadata();
+        if metadata
+            .lookup_dir(dir_id)
+            .map_or(false, |dir| dir.is_vers_dir())
+        {
+            if let Some(entry) = metadata.get_directory(dir_id)?.entries().get(name) {
+                if self.open_counts.contains_key(&entry.id()) {
+                    return Err(IOFSErrorKind::FileOpen.into());
+                }
+            }
+        }
 
         // It seems reasonable to allow the WASM program an opportunity to do something with the
         // soon-to-be-deleted file, prior to it being relegated to the bit-bucket.
@@ -607,49 +2199,91 @@ impl<B: BlockStorage> UberFileSystem<B> {
             .metadata()
             .get_file_metadata_from_dir_and_name(dir_id, name)
         {
-            if let Some(program_mgr) = &self.program_mgr {
-                program_mgr
-                    .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                        IofsFileMessage::Delete(IofsMessagePayload {
-                            target_path: self.block_manager.metadata().path_from_file_id(file.id()),
-                            target_id: file.id(),
-                            parent_id: dir_id,
-                        }),
-                    )))
-                    .expect("Wasm Runtime went away");
+            if self.event_sender.is_some() {
+                self.send_event(IofsMessage::FileMessage(IofsFileMessage::Delete(
+                    IofsMessagePayload {
+                        target_path: self.block_manager.metadata().path_from_file_id(file.id()),
+                        target_id: file.id(),
+                        parent_id: dir_id,
+                    },
+                )));
             }
 
             // self.notify_listeners(UfsMessage::FileRemove(
             //     self.block_manager.metadata().path_from_file_id(file.id()),
             // ));
 
-            if let Some(program_mgr) = &self.program_mgr {
+            if self.program_mgr.is_some() {
                 if let Ok(dir) = self.block_manager.metadata().get_directory(dir_id) {
                     if dir.is_wasm_dir() {
                         let path = self.block_manager.metadata().path_from_file_id(file.id());
-                        program_mgr
-                            .send(RuntimeManagerMsg::Stop(path.clone()))
-                            .expect("unable to send message to Runtime Manager");
-                        self.block_manager
-                            .metadata_mut()
-                            .remove_wasm_program_grants(&path)
+                        self.stop_program(&path);
+                        let metadata = self.block_manager.metadata_mut();
+                        metadata.remove_wasm_program_grants(&path);
+                        metadata.remove_wasm_program_values(&path);
                     }
                 }
             }
         }
 
+        let open_id = self
+            .block_manager
+            .metadata()
+            .get_file_metadata_from_dir_and_name(dir_id, name)
+            .ok()
+            .map(|file| file.id())
+            .filter(|id| self.open_counts.contains_key(id));
+
         let free_blocks = self
             .block_manager
             .metadata_mut()
             .unlink_file(dir_id, name)?;
 
-        for b in free_blocks {
-            self.block_manager.recycle_block(b)
+        // Only the last link matters.
+        let open_id = open_id.filter(|id| self.block_manager.metadata().lookup_file(*id).is_none());
+        match open_id {
+            Some(id) => {
+                debug!("\tdeferring recycling of open file {:?}", id);
+                self.unlinked.insert(id, free_blocks);
+            }
+            None => self.block_manager.recycle_blocks(&free_blocks),
         }
 
         Ok(())
     }
 
+    /// Create a file by path
+    ///
+    /// The parent directory must exist, and symbolic links on the way to it are followed. The file
+    /// is opened for reading and writing.
+    pub(crate) fn create_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<FileHandle, failure::Error> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("invalid file name {:?}", path))?;
+        let dir = path.parent().ok_or(IOFSErrorKind::NotFound)?;
+        let dir_id = self.block_manager.metadata().resolve_path(dir)?;
+
+        self.create_file(dir_id, name).map(|(handle, _)| handle)
+    }
+
+    /// Open a file by path
+    ///
+    /// Symbolic links are followed, including one naming the file itself.
+    pub(crate) fn open_path<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: OpenFileMode,
+    ) -> Result<FileHandle, failure::Error> {
+        let id = self.block_manager.metadata().resolve_path(path)?;
+
+        self.open_file(id, mode)
+    }
+
     /// Open a file
     ///
     pub(crate) fn open_file(
@@ -658,33 +2292,36 @@ impl<B: BlockStorage> UberFileSystem<B> {
         mode: OpenFileMode,
     ) -> Result<FileHandle, failure::Error> {
         debug!("--------");
+        if mode != OpenFileMode::Read {
+            self.check_writable()?;
+        }
         let file = match mode {
             OpenFileMode::Write => self.block_manager.metadata_mut().get_file_write_only(id)?,
             OpenFileMode::Read => self.block_manager.metadata().get_file_read_only(id)?,
-            OpenFileMode::ReadWrite => self.block_manager.metadata_mut().get_file_read_write(id)?,
+            OpenFileMode::ReadWrite | OpenFileMode::Append | OpenFileMode::ReadAppend => {
+                self.block_manager.metadata_mut().get_file_read_write(id)?
+            }
         };
 
         let fh = self.open_file_counter;
         self.open_file_counter = self.open_file_counter.wrapping_add(1);
 
-        if let Some(program_mgr) = &self.program_mgr {
-            program_mgr
-                .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                    IofsFileMessage::Open(IofsMessagePayload {
-                        target_path: self
-                            .block_manager
-                            .metadata()
-                            .path_from_file_id(file.file_id),
-                        target_id: file.file_id,
-                        parent_id: self
-                            .block_manager
-                            .metadata()
-                            .get_file_metadata(file.file_id)
-                            .expect("should not fail in open_file")
-                            .dir_id(),
-                    }),
-                )))
-                .expect("Wasm Runtime went away");
+        if self.event_sender.is_some() {
+            self.send_event(IofsMessage::FileMessage(IofsFileMessage::Open(
+                IofsMessagePayload {
+                    target_path: self
+                        .block_manager
+                        .metadata()
+                        .path_from_file_id(file.file_id),
+                    target_id: file.file_id,
+                    parent_id: self
+                        .block_manager
+                        .metadata()
+                        .get_file_metadata(file.file_id)
+                        .expect("should not fail in open_file")
+                        .dir_id(),
+                },
+            )));
         }
 
         // self.notify_listeners(UfsMessage::FileOpen(
@@ -693,34 +2330,122 @@ impl<B: BlockStorage> UberFileSystem<B> {
         //         .path_from_file_id(file.file_id),
         // ));
 
-        self.open_files.insert(fh, file);
+        *self.open_counts.entry(file.file_id).or_insert(0) += 1;
+        self.open_files.insert(fh, (file, mode));
 
         debug!("`open_file` {:?}, mode: {:?}, handle: {}", id, mode, fh);
         Ok(fh)
     }
 
+    /// Commit the changes to an open file
+    ///
+    /// The file's current version is committed, and the metadata written to storage, so that the
+    /// changes survive a crash. There's then nothing for `close_file` to commit, unless the file
+    /// is written to again.
+    pub(crate) fn flush_file(&mut self, handle: FileHandle) -> Result<(), failure::Error> {
+        debug!("-------");
+        debug!("`flush_file`: {}", handle);
+
+        self.flush_write_buffer(handle)?;
+
+        let file = match self.open_files.get(&handle) {
+            Some((file, _)) => file,
+            None => return Err(format_err!("File not open {}", handle)),
+        };
+
+        // There's nowhere to commit a removed file to.
+        if self.unlinked.contains_key(&file.file_id) {
+            return Ok(());
+        }
+
+        if file.version.is_dirty() {
+            let file = file.clone();
+            let file_id = file.file_id;
+            self.release_quota(handle);
+            let pruned = self.block_manager.metadata_mut().commit_file(file)?;
+            if let Some((file, _)) = self.open_files.get_mut(&handle) {
+                file.version.clean();
+            }
+            self.flushed.insert(handle);
+            self.recycle_pruned(file_id, &pruned);
+            self.block_manager.serialize();
+        }
+
+        Ok(())
+    }
+
+    /// Write everything to storage
+    ///
+    /// Open files are committed, as with `flush_file`, and then the metadata and block map are
+    /// written. Files remain open.
+    pub(crate) fn sync(&mut self) -> Result<(), failure::Error> {
+        debug!("-------");
+        debug!("`sync`");
+
+        let handles = self.open_files.keys().cloned().collect::<Vec<_>>();
+        for handle in handles {
+            self.flush_write_buffer(handle)?;
+            let file = match self.open_files.get(&handle) {
+                Some((file, _))
+                    if file.version.is_dirty() && !self.unlinked.contains_key(&file.file_id) =>
+                {
+                    file.clone()
+                }
+                _ => continue,
+            };
+            let file_id = file.file_id;
+            self.release_quota(handle);
+            let pruned = self.block_manager.metadata_mut().commit_file(file)?;
+            if let Some((file, _)) = self.open_files.get_mut(&handle) {
+                file.version.clean();
+            }
+            self.flushed.insert(handle);
+            self.recycle_pruned(file_id, &pruned);
+        }
+
+        self.block_manager.sync();
+
+        Ok(())
+    }
+
     /// Close a file
     ///
-    pub(crate) fn close_file(&mut self, handle: FileHandle) -> Result<(), ()> {
+    /// If the file's buffered bytes can't be written, the error is returned, and the file is left
+    /// open, less those bytes.
+    pub(crate) fn close_file(&mut self, handle: FileHandle) -> Result<(), failure::Error> {
         debug!("-------");
         debug!("`close_file`: {}", handle);
 
+        self.flush_write_buffer(handle)?;
+
         // Commit the file first, so that we can read it's contents if it's a program file to run.
-        if let Some(file) = self.open_files.get(&handle) {
+        // Files that have been removed have nothing to commit to. Either way, what the handle held
+        // against quotas is given back, and a commit charges for it.
+        self.release_quota(handle);
+        let mut pruned = vec![];
+        if let Some((file, _)) = self.open_files.get(&handle) {
             debug!("\t{:?}", file);
-            if let Err(e) = self.block_manager.metadata_mut().commit_file(file.clone()) {
-                error!("{}", e);
+            if !self.read_only && !self.unlinked.contains_key(&file.file_id) {
+                match self.block_manager.metadata_mut().commit_file(file.clone()) {
+                    Ok(blocks) => pruned = blocks,
+                    Err(e) => error!("{}", e),
+                }
             }
+            let file_id = file.file_id;
+            self.recycle_pruned(file_id, &pruned);
         }
 
         // Add any .wasm files, located in a .wasm directory, to the runtime.
-        if let Some(program_mgr) = &self.program_mgr {
-            if let Some(file) = self.open_files.get(&handle) {
+        let mut new_program = None;
+        if self.program_mgr.is_some() {
+            if let Some((file, _)) = self.open_files.get(&handle) {
                 // This check is a bit of a hack. Basically, we only want to load the program if
                 // it's new. For some reason FUSE will open and close a newly created file after the
                 // new file is closed. So we check to see if the FileVersion is dirty here, since it
-                // will only be so if we haven't already written it.
-                if file.version.is_dirty() {
+                // will only be so if we haven't already written it. Unless it was flushed.
+                if (file.version.is_dirty() || self.flushed.contains(&handle))
+                    && !self.unlinked.contains_key(&file.file_id)
+                {
                     // Check to see if this file is in the special ".wasm" directory.
                     let file_id = file.file_id;
                     let file_a = self
@@ -747,20 +2472,13 @@ impl<B: BlockStorage> UberFileSystem<B> {
                                             info!("Adding program {:?} to runtime", name);
                                             let size = file.version.size();
                                             if let Ok(program) =
-                                                self.read_file(handle, 0, size as u32)
+                                                self.read_handle(handle, 0, size as u32)
                                             {
                                                 // Add the Wasm program to the runtime
                                                 self.block_manager
                                                     .metadata_mut()
                                                     .add_wasm_program_grants(path.to_path_buf());
-                                                program_mgr
-                                                    .send(RuntimeManagerMsg::Start(
-                                                        ProtoWasmProgram::new(
-                                                            path.to_path_buf(),
-                                                            program,
-                                                        ),
-                                                    ))
-                                                    .unwrap()
+                                                new_program = Some((path.to_path_buf(), program));
                                             }
                                         }
                                     }
@@ -772,28 +2490,33 @@ impl<B: BlockStorage> UberFileSystem<B> {
             }
         }
 
+        // Broken out to make borrowing happy -- reading the config opens another file.
+        if let Some((path, program)) = new_program {
+            let config = self.read_wasm_program_config(&path);
+            if let Some(program_mgr) = &self.program_mgr {
+                program_mgr
+                    .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                        path.clone(),
+                        program,
+                        config,
+                    )))
+                    .unwrap();
+                self.programs.insert(path);
+            }
+        }
+
+        self.flushed.remove(&handle);
+        self.read_positions.remove(&handle);
         match self.open_files.remove(&handle) {
-            Some(file) => {
-                if let Some(program_mgr) = &self.program_mgr {
-                    program_mgr
-                        .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                            IofsFileMessage::Close(IofsMessagePayload {
-                                target_path: self
-                                    .block_manager
-                                    .metadata()
-                                    .path_from_file_id(file.file_id),
-                                target_id: file.file_id,
-                                parent_id: self
-                                    .block_manager
-                                    .metadata()
-                                    .get_file_metadata(file.file_id)
-                                    .expect("should not fail in close_file")
-                                    .dir_id(),
-                            }),
-                        )))
-                        .expect("Wasm Runtime went away");
+            Some((file, _)) => {
+                if self.event_sender.is_some() {
+                    if let Some(payload) = self.file_payload(file.file_id) {
+                        self.send_event(IofsMessage::FileMessage(IofsFileMessage::Close(payload)));
+                    }
                 }
 
+                self.release_file(&file);
+
                 Ok(())
 
                 // self.notify_listeners(UfsMessage::FileClose(
@@ -804,13 +2527,92 @@ impl<B: BlockStorage> UberFileSystem<B> {
             }
             None => {
                 warn!("asked to close a file not in the map {}", handle);
-                Err(())
+                Err(format_err!("File not open {}", handle))
+            }
+        }
+    }
+
+    /// Drop a handle's claim on a file
+    ///
+    /// When the last handle to a removed file goes, the file's blocks are recycled, along with any
+    /// that were written through the handle since it was removed.
+    fn release_file(&mut self, file: &File) {
+        let remaining = match self.open_counts.get_mut(&file.file_id) {
+            Some(count) => {
+                *count -= 1;
+                *count
             }
+            None => 0,
+        };
+
+        if let Some(blocks) = self.unlinked.get_mut(&file.file_id) {
+            for b in file.version.blocks() {
+                if !blocks.contains(b) {
+                    blocks.push(*b);
+                }
+            }
+        }
+
+        if remaining == 0 {
+            self.open_counts.remove(&file.file_id);
+            if let Some(blocks) = self.unlinked.remove(&file.file_id) {
+                debug!("\trecycling removed file {:?}", file.file_id);
+                self.block_manager.recycle_blocks(&blocks);
+            }
+        }
+
+        // Pruned blocks that this handle was holding on to may now be free, unless a version
+        // committed since has taken them up again.
+        if !self.pruned_in_use.is_empty() {
+            let in_use = self.blocks_in_use();
+            let released = self
+                .pruned_in_use
+                .iter()
+                .filter(|(b, _)| !in_use.contains(b))
+                .map(|(b, id)| (*b, *id))
+                .collect::<Vec<_>>();
+            let mut freed = vec![];
+            for (b, id) in released {
+                self.pruned_in_use.remove(&b);
+                let committed = self
+                    .block_manager
+                    .metadata()
+                    .lookup_file(id)
+                    .map_or(false, |f| {
+                        f.get_versions().values().any(|v| v.blocks().contains(&b))
+                    });
+                if !committed {
+                    freed.push(b);
+                }
+            }
+            self.block_manager.recycle_blocks(&freed);
         }
     }
 
+    /// Build an event payload for a file
+    ///
+    /// Returns `None` if the file has been removed, in which case there's no path to report.
+    fn file_payload(&self, file_id: UfsUuid) -> Option<IofsMessagePayload> {
+        let metadata = self.block_manager.metadata();
+        metadata
+            .lookup_file(file_id)
+            .map(|file| IofsMessagePayload {
+                target_path: metadata.path_from_file_id(file_id),
+                target_id: file_id,
+                parent_id: file.dir_id(),
+            })
+    }
+
     /// Write bytes to a file.
     ///
+    /// Writes smaller than a block are buffered until there's a block's worth, and the rest is
+    /// written when the file is read, flushed, or closed. A write of a block or more goes straight
+    /// to storage, along with anything buffered ahead of it. Either way, as with any change to an
+    /// open file, other handles, and `stat_file`, don't see the bytes until the file is flushed or
+    /// closed. An error writing out buffered bytes may only surface then.
+    ///
+    /// Blocks only go on the end of a file, so a write may start at, or past, the end of the file,
+    /// but not before it. Writing past the end leaves a hole.
     pub(crate) fn write_file(
         &mut self,
         handle: FileHandle,
@@ -819,58 +2621,92 @@ impl<B: BlockStorage> UberFileSystem<B> {
     ) -> Result<usize, failure::Error> {
         debug!("-------");
         debug!("`write_file`: handle: {}", handle);
+        self.check_writable()?;
 
-        let result = match &mut self.open_files.get_mut(&handle) {
-            Some(file) => {
-                let mut written = 0;
-                while written < bytes.len() {
-                    match self.block_manager.write(
-                        file.version.nonce(),
-                        offset + written as u64,
-                        &bytes[written..],
-                    ) {
-                        Ok(block) => {
-                            written += block.size() as usize;
-                            file.version.append_block(&block);
-                        }
-                        Err(e) => {
-                            error!("problem writing data to file: {}", e);
-                        }
-                    }
-                }
-                debug!("wrote {} bytes", written,);
-
-                Ok(written)
+        let offset = match self.open_files.get(&handle) {
+            Some((_, OpenFileMode::Read)) => {
+                warn!("asked to write file opened read-only {}", handle);
+                return Err(IOFSErrorKind::NotOpenForWriting.into());
+            }
+            Some((file, OpenFileMode::Append)) | Some((file, OpenFileMode::ReadAppend)) => {
+                let buffered = self
+                    .write_buffers
+                    .get(&handle)
+                    .map_or(0, |buffer| buffer.bytes.len());
+                file.version.size() + buffered as u64
             }
+            Some(_) => offset,
             None => {
                 warn!("asked to write file not in the map {}", handle);
-                Ok(0)
+                return Ok(0);
             }
         };
 
-        // Down here to appease the Borrow Checker Gods
-        if let Some(file) = self.open_files.get(&handle) {
-            if let Some(program_mgr) = &self.program_mgr {
-                program_mgr
-                    .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                        IofsFileMessage::Write(IofsMessagePayload {
-                            target_path: self
-                                .block_manager
-                                .metadata()
-                                .path_from_file_id(file.file_id),
-                            target_id: file.file_id,
-                            parent_id: self
-                                .block_manager
-                                .metadata()
-                                .get_file_metadata(file.file_id)
-                                .expect("should not fail in write_file")
-                                .dir_id(),
-                        }),
-                    )))
-                    .expect("Wasm Runtime went away");
+        if let Some((file, _)) = self.open_files.get(&handle) {
+            let buffered = self
+                .write_buffers
+                .get(&handle)
+                .map_or(0, |buffer| buffer.offset + buffer.bytes.len() as u64);
+            if !bytes.is_empty() && offset < buffered.max(file.version.size()) {
+                warn!("asked to write over the contents of {}", handle);
+                return Err(IOFSErrorKind::Overwrite.into());
             }
+            let end = (offset + bytes.len() as u64)
+                .max(buffered)
+                .max(file.version.size());
+            self.check_quota(handle, end)?;
+        }
 
-            // self.notify_listeners(UfsMessage::FileWrite(
+        // The file's blocks are about to change, so read-ahead starts over.
+        self.read_positions.remove(&handle);
+
+        // Buffered bytes that don't lead up to this write go out on their own.
+        if let Some(buffer) = self.write_buffers.get(&handle) {
+            if buffer.offset + buffer.bytes.len() as u64 != offset {
+                self.flush_write_buffer(handle)?;
+            }
+        }
+
+        let block_size: usize = self.block_manager.block_size().into();
+        let mut buffer = self.write_buffers.remove(&handle).unwrap_or(WriteBuffer {
+            offset,
+            bytes: Vec::new(),
+        });
+        buffer.bytes.extend_from_slice(bytes);
+
+        let len = if bytes.len() >= block_size {
+            buffer.bytes.len()
+        } else {
+            buffer.bytes.len() / block_size * block_size
+        };
+        if len > 0 {
+            if let Err(e) = self.write_blocks(handle, &buffer.bytes[..len], buffer.offset) {
+                // What was buffered before this write is kept for next time.
+                buffer.bytes.truncate(buffer.bytes.len() - bytes.len());
+                if !buffer.bytes.is_empty() {
+                    self.write_buffers.insert(handle, buffer);
+                }
+                return Err(e);
+            }
+        }
+        if len < buffer.bytes.len() {
+            self.write_buffers.insert(
+                handle,
+                WriteBuffer {
+                    offset: buffer.offset + len as u64,
+                    bytes: buffer.bytes.split_off(len),
+                },
+            );
+        }
+
+        if let Some((file, _)) = self.open_files.get(&handle) {
+            if self.event_sender.is_some() {
+                if let Some(payload) = self.file_payload(file.file_id) {
+                    self.send_event(IofsMessage::FileMessage(IofsFileMessage::Write(payload)));
+                }
+            }
+
+            // self.notify_listeners(UfsMessage::FileWrite(
             //     self.block_manager
             //         .metadata()
             //         .path_from_file_id(file.file_id),
@@ -878,14 +2714,517 @@ impl<B: BlockStorage> UberFileSystem<B> {
             // ));
         }
 
+        Ok(bytes.len())
+    }
+
+    /// Fail if an open file would go over quota at `end` bytes long
+    ///
+    /// Quotas count the latest committed version of the file, so that's what the growth is
+    /// measured from. The growth is held against the quotas until the file is committed, so that
+    /// other handles see it. A file that's been removed counts against nothing.
+    fn check_quota(&mut self, handle: FileHandle, end: u64) -> Result<(), failure::Error> {
+        let file_id = match self.open_files.get(&handle) {
+            Some((file, _)) if !self.unlinked.contains_key(&file.file_id) => file.file_id,
+            _ => return Ok(()),
+        };
+
+        let committed = self
+            .block_manager
+            .metadata()
+            .lookup_file(file_id)
+            .map_or(0, |f| f.latest_size());
+        let held = self
+            .quota_holds
+            .get(&handle)
+            .map_or(0, |holds| holds.iter().map(|hold| hold.bytes()).sum());
+        let wanted = end.saturating_sub(committed);
+        if wanted > held {
+            let hold = self
+                .block_manager
+                .metadata_mut()
+                .hold_quota(file_id, wanted - held)?;
+            self.quota_holds.entry(handle).or_default().push(hold);
+        }
+        Ok(())
+    }
+
+    /// Give back what a handle holds against quotas
+    ///
+    fn release_quota(&mut self, handle: FileHandle) {
+        if let Some(holds) = self.quota_holds.remove(&handle) {
+            for hold in &holds {
+                self.block_manager.metadata_mut().release_quota(hold);
+            }
+        }
+    }
+
+    /// Write out a file's buffered bytes
+    ///
+    fn flush_write_buffer(&mut self, handle: FileHandle) -> Result<(), failure::Error> {
+        if let Some(buffer) = self.write_buffers.remove(&handle) {
+            debug!("flushing {} buffered bytes", buffer.bytes.len());
+            self.write_blocks(handle, &buffer.bytes, buffer.offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write bytes to new blocks, and append them to a file
+    ///
+    fn write_blocks(
+        &mut self,
+        handle: FileHandle,
+        bytes: &[u8],
+        offset: u64,
+    ) -> Result<usize, failure::Error> {
+        let block_size: usize = self.block_manager.block_size().into();
+        let chunks = match self.block_manager.chunking_mode() {
+            ChunkingMode::Fixed => vec![bytes],
+            ChunkingMode::ContentDefined => content_defined_chunks(bytes, block_size),
+        };
+
+        let needed = chunks
+            .iter()
+            .map(|c| (c.len() + block_size - 1) / block_size)
+            .sum();
+        self.make_room(needed)?;
+
+        match &mut self.open_files.get_mut(&handle) {
+            Some((file, _)) => {
+                let mut written = 0;
+                for chunk in chunks {
+                    let mut chunk_written = 0;
+                    while chunk_written < chunk.len() {
+                        // Writing past the end of the file leaves a hole.
+                        let at = offset + written as u64;
+                        match self.block_manager.write(
+                            file.version.nonce(),
+                            at,
+                            &chunk[chunk_written..],
+                        ) {
+                            Ok(block) => {
+                                let number = block.number();
+                                chunk_written += block.size() as usize;
+                                written += block.size() as usize;
+                                let unlinked = self.unlinked.get(&file.file_id);
+                                Self::append_block(
+                                    &mut self.block_manager,
+                                    file,
+                                    unlinked,
+                                    number,
+                                    at,
+                                );
+                            }
+                            Err(e) => {
+                                error!("problem writing data to file: {}", e);
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                debug!("wrote {} bytes", written,);
+
+                Ok(written)
+            }
+            None => {
+                warn!("asked to write file not in the map {}", handle);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Recycle the blocks of versions of `file_id` pruned by the retention policy
+    ///
+    /// As when pruning to make room, blocks that belong to open files are left alone. They're
+    /// recycled by `release_file`, once the last handle using them is closed.
+    fn recycle_pruned(&mut self, file_id: UfsUuid, pruned: &[BlockNumber]) {
+        if pruned.is_empty() {
+            return;
+        }
+
+        let in_use = self.blocks_in_use();
+        let (kept, freed): (Vec<BlockNumber>, Vec<BlockNumber>) =
+            pruned.iter().cloned().partition(|b| in_use.contains(b));
+        self.pruned_in_use
+            .extend(kept.into_iter().map(|b| (b, file_id)));
+        self.block_manager.recycle_blocks(&freed);
+    }
+
+    /// The blocks that open files, including those that were removed, are using
+    ///
+    fn blocks_in_use(&self) -> HashSet<BlockNumber> {
+        self.open_files
+            .values()
+            .flat_map(|(file, _)| file.version.blocks().iter())
+            .chain(self.unlinked.values().flatten())
+            .cloned()
+            .collect()
+    }
+
+    /// Make room to write `needed` blocks
+    ///
+    /// If the write would eat into the space reservation, old versions of files are pruned to
+    /// make up the difference. Blocks belonging to open files are left alone. Reservation or not,
+    /// it's an error if there aren't `needed` available blocks, i.e., free blocks beyond those
+    /// left for the next copy of the metadata.
+    fn make_room(&mut self, needed: usize) -> Result<(), failure::Error> {
+        let needed = needed as BlockCardinality;
+
+        if let Some(reservation) = self.block_manager.metadata().reservation() {
+            let shortfall = reservation.shortfall(
+                self.block_manager.block_count(),
+                self.block_manager.available_block_count(),
+                needed,
+            );
+            if shortfall > 0 {
+                let in_use = self.blocks_in_use();
+                let freed = self
+                    .block_manager
+                    .metadata_mut()
+                    .prune_versions(shortfall as usize, &in_use);
+                info!(
+                    "pruned versions, freeing {} of {} blocks",
+                    freed.len(),
+                    shortfall
+                );
+                self.block_manager.recycle_blocks(&freed);
+            }
+        }
+
+        if self.block_manager.available_block_count() < needed {
+            warn!(
+                "{} blocks needed, but only {} are available",
+                needed,
+                self.block_manager.available_block_count()
+            );
+            return Err(IOFSErrorKind::NoSpace.into());
+        }
+
+        Ok(())
+    }
+
+    /// Add a freshly written block to an open file
+    ///
+    /// A file holds a single reference to each of its blocks, however many times it uses them.
+    /// When deduplicating, `BlockManager::write` may hand back a shared block that the file
+    /// already holds, in which case the extra reference is given back. `unlinked` are the blocks
+    /// of a removed file's committed versions. The block starts at `offset` in the file.
+    fn append_block(
+        block_manager: &mut BlockManager<B>,
+        file: &mut File,
+        unlinked: Option<&Vec<BlockNumber>>,
+        number: BlockNumber,
+        offset: FileSize,
+    ) {
+        if block_manager.is_shared(number) {
+            let held = file.version.blocks().contains(&number)
+                || match block_manager.metadata().get_file_metadata(file.file_id) {
+                    Ok(metadata) => metadata
+                        .get_versions()
+                        .values()
+                        .any(|v| v.blocks().contains(&number)),
+                    Err(_) => unlinked.map_or(false, |blocks| blocks.contains(&number)),
+                };
+            if held {
+                block_manager.recycle_block(number);
+            }
+        }
+
+        let block = block_manager
+            .get_block(number)
+            .expect("block doesn't exist in append_block");
+        file.version.append_block(block, offset);
+    }
+
+    /// Compute a file's content hash
+    ///
+    /// This is the Merkle root of the block hashes that make up the latest committed version of
+    /// the file. Each leaf is the hash of a block's offset in the file, and the block's hash, so
+    /// that moving a hole changes the content hash. A last leaf, the hash of the file's size,
+    /// covers a hole at the end of the file. Note that the block hashes are taken over encrypted
+    /// data. The hash is computed on demand, and never stored.
+    pub(crate) fn content_hash(&self, file_id: UfsUuid) -> Result<BlockHash, failure::Error> {
+        let file = self.block_manager.metadata().get_file_metadata(file_id)?;
+        let latest = file.get_latest();
+        let mut leaves = latest
+            .blocks()
+            .iter()
+            .zip(latest.offsets())
+            .map(|(bn, offset)| {
+                let hash = self
+                    .block_manager
+                    .get_block(*bn)
+                    .and_then(|block| block.hash())
+                    .ok_or_else(|| format_err!("missing hash for block {}", bn))?;
+                let mut leaf = offset.to_le_bytes().to_vec();
+                leaf.extend_from_slice(hash.as_ref());
+                Ok(BlockHash::new(leaf))
+            })
+            .collect::<Result<Vec<_>, failure::Error>>()?;
+        leaves.push(BlockHash::new(latest.size().to_le_bytes()));
+
+        Ok(BlockHash::merkle_root(&leaves))
+    }
+
+    /// Verify the blocks of a file against their hashes
+    ///
+    /// Each block of the latest version of the file is read from storage and checked against the
+    /// hash in the `BlockMap`. The number of the first block that fails is returned, or `None` if
+    /// the file is sound. As with `content_hash`, the hashes are over the encrypted data, so no key
+    /// is needed to find corruption.
+    pub fn verify_file(&self, file_id: UfsUuid) -> Result<Option<BlockNumber>, failure::Error> {
+        let file = self.block_manager.metadata().get_file_metadata(file_id)?;
+        for bn in file.get_latest().blocks() {
+            if !self.block_manager.verify_block(*bn)? {
+                warn!("file {} has corrupt block 0x{:x?}", file_id, bn);
+                return Ok(Some(*bn));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Compute, and store, the volume root hash
+    ///
+    /// This is the Merkle root of two hashes: one over the metadata, and one over the contents of
+    /// the files. The former has a leaf per file and directory, made from its path and ID, and
+    /// the latter has each file's `content_hash` as a leaf. Entries are visited in path order, so
+    /// that the root doesn't depend on the whims of a `HashMap`.
+    ///
+    /// The root is stored in the `BlockMap`, signed with the file system key if `signed` is true,
+    /// so that `verify_volume_root` may later attest that nothing has changed.
+    pub fn volume_root_hash(&mut self, signed: bool) -> Result<Vec<u8>, failure::Error> {
+        let root = self.compute_volume_root()?;
+        self.block_manager.set_volume_root(root, signed);
+
+        Ok(root.as_ref().to_vec())
+    }
+
+    /// Check the volume against the stored volume root hash
+    ///
+    /// Returns `false` if the volume has changed since the root was stored, or the signature on
+    /// the root is invalid. It's an error if there is no stored root.
+    pub fn verify_volume_root(&self) -> Result<bool, failure::Error> {
+        let root = self.compute_volume_root()?;
+        self.block_manager
+            .check_volume_root(&root)
+            .ok_or_else(|| format_err!("no volume root hash has been stored"))
+    }
+
+    fn compute_volume_root(&self) -> Result<BlockHash, failure::Error> {
+        fn leaf(path: &Path, id: UfsUuid) -> BlockHash {
+            let mut bytes = path.to_string_lossy().as_bytes().to_vec();
+            bytes.extend_from_slice(id.as_bytes());
+            BlockHash::new(bytes)
+        }
+
+        fn walk<B: BlockStorage + Sync>(
+            ufs: &UberFileSystem<B>,
+            path: &Path,
+            dir: &DirectoryMetadata,
+            metadata_leaves: &mut Vec<BlockHash>,
+            content_leaves: &mut Vec<BlockHash>,
+        ) -> Result<(), failure::Error> {
+            metadata_leaves.push(leaf(path, dir.id()));
+
+            let mut names = dir.entries().keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let path = path.join(name);
+                match &dir.entries()[name] {
+                    DirectoryEntry::Directory(d) => {
+                        walk(ufs, &path, d, metadata_leaves, content_leaves)?
+                    }
+                    DirectoryEntry::Symlink(s) => metadata_leaves.push(leaf(&path, s.id())),
+                    file => {
+                        metadata_leaves.push(leaf(&path, file.id()));
+                        content_leaves.push(ufs.content_hash(file.id())?);
+                    }
+                }
+            }
+
+            Ok(())
+        }
+
+        let mut metadata_leaves = vec![];
+        let mut content_leaves = vec![];
+        walk(
+            self,
+            Path::new("/"),
+            self.block_manager.metadata().root_directory(),
+            &mut metadata_leaves,
+            &mut content_leaves,
+        )?;
+
+        Ok(BlockHash::merkle_root(&[
+            BlockHash::merkle_root(&metadata_leaves),
+            BlockHash::merkle_root(&content_leaves),
+        ]))
+    }
+
+    /// Set the size of a file
+    ///
+    /// This is `truncate_file` for a file that isn't open. The file is opened, resized, and closed,
+    /// which commits a new version.
+    pub(crate) fn set_file_size(
+        &mut self,
+        id: UfsUuid,
+        size: FileSize,
+    ) -> Result<(), failure::Error> {
+        debug!("-------");
+        debug!("`set_file_size`: {:?}, size: {}", id, size);
+        self.check_writable()?;
+
+        let fh = self.open_file(id, OpenFileMode::ReadWrite)?;
+        let result = self.truncate_file(fh, size);
+        if self.close_file(fh).is_err() {
+            return Err(format_err!("unable to close file {}", fh));
+        }
+
         result
     }
 
-    /// Read bytes from a file
+    /// Truncate an open file
+    ///
+    /// The file, as seen through `handle`, is cut down to `size` bytes. Blocks that are no longer
+    /// needed are recycled, unless the file, or a committed version of it, still refers to them.
+    /// Making a file longer leaves a hole, which reads as zeros.
+    pub(crate) fn truncate_file(
+        &mut self,
+        handle: FileHandle,
+        size: FileSize,
+    ) -> Result<(), failure::Error> {
+        debug!("-------");
+        debug!("`truncate_file`: handle: {}, size: {}", handle, size);
+        self.check_writable()?;
+
+        self.flush_write_buffer(handle)?;
+
+        // Making a file longer counts against quotas, hole or not.
+        let grows = match self.open_files.get(&handle) {
+            Some((file, mode)) => *mode != OpenFileMode::Read && size > file.version.size(),
+            None => false,
+        };
+        if grows {
+            self.check_quota(handle, size)?;
+        }
+
+        let file = match self.open_files.get_mut(&handle) {
+            Some((_, OpenFileMode::Read)) => return Err(IOFSErrorKind::NotOpenForWriting.into()),
+            Some((file, _)) => file,
+            None => return Err(format_err!("File not open {}", handle)),
+        };
+
+        if size >= file.version.size() {
+            let count = file.version.blocks().len();
+            file.version.truncate(count, size);
+            return Ok(());
+        }
+
+        // Find the blocks that fit entirely within the new size.
+        let mut kept = 0;
+        for (block_number, offset) in file.version.blocks().iter().zip(file.version.offsets()) {
+            let block_size = self
+                .block_manager
+                .get_block(*block_number)
+                .expect("block doesn't exist in truncate_file")
+                .size() as FileSize;
+            if offset + block_size > size {
+                break;
+            }
+            kept += 1;
+        }
+
+        // The new end of the file may fall in a hole, rather than a block.
+        let straddling = file
+            .version
+            .offsets()
+            .get(kept)
+            .cloned()
+            .filter(|offset| *offset < size);
+        let mut dropped = file.version.truncate(kept, straddling.unwrap_or(size));
+
+        // The block straddling the new end of the file is re-written with just the bytes we keep.
+        if let Some(offset) = straddling {
+            let block = self
+                .block_manager
+                .get_block(dropped[0])
+                .expect("block doesn't exist in truncate_file");
+            let bytes = self
+                .block_manager
+                .read(file.version.nonce(), offset, block)?;
+            let number = self
+                .block_manager
+                .write(
+                    file.version.nonce(),
+                    offset,
+                    &bytes[..(size - offset) as usize],
+                )?
+                .number();
+            let unlinked = self.unlinked.get(&file.file_id);
+            Self::append_block(&mut self.block_manager, file, unlinked, number, offset);
+        }
+
+        // A shared block may still be in use further up the file.
+        dropped.retain(|b| !file.version.blocks().contains(b));
+
+        let file_id = file.file_id;
+        if let Ok(metadata) = self.block_manager.metadata().get_file_metadata(file_id) {
+            dropped.retain(|b| {
+                !metadata
+                    .get_versions()
+                    .values()
+                    .any(|v| v.blocks().contains(b))
+            });
+        } else if let Some(blocks) = self.unlinked.get(&file_id) {
+            dropped.retain(|b| !blocks.contains(b));
+        }
+        self.block_manager.recycle_blocks(&dropped);
+
+        Ok(())
+    }
+
+    /// Read the first `n` bytes of a file
     ///
+    /// This is for peeking at a file, e.g., to work out what sort of file it is. The latest
+    /// committed version is read straight from its blocks, without opening the file, and only as
+    /// many blocks as needed. A file shorter than `n` bytes is returned whole.
+    pub(crate) fn read_head(&self, file_id: UfsUuid, n: usize) -> Result<Vec<u8>, failure::Error> {
+        debug!("-------");
+        debug!("`read_head`: {:?}, {} bytes", file_id, n);
+
+        let file = self.block_manager.metadata().get_file_read_only(file_id)?;
+
+        let n = n.min(file.version.size() as usize);
+        let mut head = Vec::with_capacity(n);
+        for (block_number, offset) in file.version.blocks().iter().zip(file.version.offsets()) {
+            if *offset as usize >= n {
+                break;
+            }
+
+            let block = match self.block_manager.get_block(*block_number) {
+                Some(block) => block,
+                None => return Err(format_err!("missing block {}", block_number)),
+            };
+            let bytes = self
+                .block_manager
+                .read(file.version.nonce(), *offset, block)?;
+
+            // A hole reads as zeros.
+            head.resize(*offset as usize, 0);
+            let wanted = (n - head.len()).min(bytes.len());
+            head.extend_from_slice(&bytes[..wanted]);
+        }
+        head.resize(n, 0);
+
+        Ok(head)
+    }
+
+    /// Read bytes from a file
     ///
+    /// The file must not have been opened write-only. The file's access time is updated.
     pub(crate) fn read_file(
-        &self,
+        &mut self,
         handle: FileHandle,
         offset: u64,
         size: u32,
@@ -896,177 +3235,2877 @@ impl<B: BlockStorage> UberFileSystem<B> {
             handle, offset, size
         );
 
-        if let Some(file) = self.open_files.get(&handle) {
-            let blocks = file.version.blocks().clone();
-            // This is the index into the file version's blocks from which we're reading.
-            let mut read_block = 0;
-            // This offset is the length of the blocks skipped over to get to the file offset.
-            let mut block_length_offset: u64 = 0;
-            for block_number in &blocks {
+        self.check_readable(handle)?;
+        self.flush_write_buffer(handle)?;
+        let bytes = self.read_handle(handle, offset, size)?;
+        self.finish_read(handle, offset, offset + bytes.len() as u64);
+
+        Ok(bytes)
+    }
+
+    /// Fail if the file was opened write-only
+    ///
+    fn check_readable(&self, handle: FileHandle) -> Result<(), failure::Error> {
+        if let Some((_, OpenFileMode::Write)) | Some((_, OpenFileMode::Append)) =
+            self.open_files.get(&handle)
+        {
+            warn!("asked to read file opened write-only {}", handle);
+            return Err(IOFSErrorKind::NotOpenForReading.into());
+        }
+
+        Ok(())
+    }
+
+    /// Bookkeeping after a read: reading ahead, and updating the file's access time
+    ///
+    /// `offset` and `end` bound the read that just happened. Access times aren't kept on a
+    /// read-only file system.
+    fn finish_read(&mut self, handle: FileHandle, offset: u64, end: u64) {
+        self.read_ahead(handle, offset, end);
+
+        if self.read_only {
+            return;
+        }
+        if let Some((file, _)) = self.open_files.get(&handle) {
+            self.block_manager
+                .metadata_mut()
+                .touch_file_access(file.file_id);
+        }
+    }
+
+    /// Fetch blocks ahead of a sequential reader
+    ///
+    /// `offset` and `end` bound the read that just happened. If it picked up where the last read
+    /// left off, the blocks following `end` that haven't already been asked for are sent to the
+    /// read-ahead thread. Without a read-ahead thread they are read into the cache straight away.
+    fn read_ahead(&mut self, handle: FileHandle, offset: u64, end: u64) {
+        if self.read_ahead == 0 {
+            return;
+        }
+
+        let version = match self.open_files.get(&handle) {
+            Some((file, _)) => &file.version,
+            None => return,
+        };
+        let (blocks, offsets) = (version.blocks(), version.offsets());
+
+        let position = self.read_positions.entry(handle).or_insert(ReadAhead {
+            next_offset: 0,
+            fetched: 0,
+        });
+        let sequential = offset == position.next_offset;
+        position.next_offset = end;
+        if !sequential {
+            position.fetched = 0;
+            return;
+        }
+
+        // Find the block holding, or following, the next byte to be read.
+        let mut current = version.block_index(end);
+        for (bn, offset) in blocks[current..].iter().zip(&offsets[current..]) {
+            match self.block_manager.get_block(*bn) {
+                Some(block) => {
+                    if offset + block.size() as u64 > end {
+                        break;
+                    }
+                }
+                None => break,
+            }
+            current += 1;
+        }
+
+        let first = current.max(position.fetched);
+        let last = (current + self.read_ahead).min(blocks.len());
+        if first >= last {
+            return;
+        }
+        position.fetched = last;
+
+        let wanted = blocks[first..last].to_vec();
+        trace!("reading ahead blocks {:?} for handle {}", wanted, handle);
+        match &self.prefetcher {
+            Some(prefetcher) => {
+                if prefetcher.send(wanted).is_err() {
+                    warn!("read-ahead thread has gone away");
+                }
+            }
+            None => {
+                for bn in wanted {
+                    if let Err(e) = self.block_manager.prefetch(bn) {
+                        debug!("unable to read ahead block {}: {}", bn, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read bytes from a file, regardless of the mode it was opened in
+    ///
+    /// `close_file` uses this to load WASM programs, which may well have been opened write-only.
+    fn read_handle(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, failure::Error> {
+        if let Some((file, _)) = self.open_files.get(&handle) {
+            let end = offset + size as u64;
+            // Whatever isn't covered by a block is a hole, and stays zero.
+            let mut buffer = vec![0; size as usize];
+            // Skip straight to the block holding `offset`, rather than walking the file from the
+            // start -- random reads of a large file would otherwise pay for every block before.
+            let first = file.version.block_index(offset);
+            for (block_number, block_offset) in file.version.blocks()[first..]
+                .iter()
+                .zip(&file.version.offsets()[first..])
+            {
+                if *block_offset >= end {
+                    break;
+                }
                 let block = self
                     .block_manager
                     .get_block(*block_number)
                     .expect("block doesn't exist in read_file");
-                if (block_length_offset + block.size() as u64) < offset {
-                    block_length_offset += block.size() as u64;
-                    read_block += 1;
-                } else {
-                    break;
+                if block_offset + (block.size() as u64) <= offset {
+                    continue;
+                }
+
+                // Only the part of the block that's wanted is read, where the block allows it.
+                let from = offset.max(*block_offset);
+                let to = end.min(block_offset + block.size() as u64);
+                if from >= to {
+                    continue;
                 }
+
+                // A block that fails to read, e.g. because its hash doesn't match, fails the
+                // whole read. Skipping it would hand back garbage.
+                let bytes = self
+                    .block_manager
+                    .read_range(
+                        file.version.nonce(),
+                        *block_offset,
+                        block,
+                        (from - block_offset) as usize,
+                        (to - from) as usize,
+                    )
+                    .map_err(|e| {
+                        error!("failed reading file {}: {}", file.file_id, e);
+                        e
+                    })?;
+                let start = (from - offset) as usize;
+                buffer[start..start + bytes.len()].copy_from_slice(&bytes);
             }
 
-            let mut read: u32 = 0;
-            let mut block_read_offset = (offset - block_length_offset) as u32;
-            let mut buffer = vec![0; size as usize];
-            while read < size {
-                if let Some(block) = self.block_manager.get_block(blocks[read_block]) {
-                    if let Ok(bytes) =
-                        self.block_manager
-                            .read(file.version.nonce(), block_length_offset, block)
-                    {
-                        let block_len = bytes.len() as u32;
-                        let bytes_to_read =
-                            std::cmp::min(size - read, block_len - block_read_offset);
-
-                        buffer[read as usize..(read + bytes_to_read) as usize].copy_from_slice(
-                            &bytes[block_read_offset as usize
-                                ..(block_read_offset + bytes_to_read) as usize],
-                        );
-                        read += bytes_to_read;
-                        if read < size {
-                            assert!(read_block + 1 < blocks.len());
-                            read_block += 1;
-                            block_length_offset += block_len as u64;
-                        }
+            if buffer.len() == size as usize {
+                if self.event_sender.is_some() {
+                    if let Some(payload) = self.file_payload(file.file_id) {
+                        self.send_event(IofsMessage::FileMessage(IofsFileMessage::Read(payload)));
                     }
                 }
-                block_read_offset = 0;
+
+                // self.notify_listeners(UfsMessage::FileRead(
+                //     self.block_manager
+                //         .metadata()
+                //         .path_from_file_id(file.file_id),
+                //     buffer.clone(),
+                // ));
+
+                debug!("read_file success");
+
+                Ok(buffer)
+            } else {
+                Err(format_err!("Error reading file {}", handle))
             }
+        } else {
+            Err(format_err!("File not open {}", handle))
+        }
+    }
+
+    /// Set the permissions of a file or directory
+    ///
+    pub(crate) fn set_permissions(
+        &mut self,
+        id: UfsUuid,
+        perms: u16,
+    ) -> Result<(), failure::Error> {
+        self.check_writable()?;
+        self.block_manager
+            .metadata_mut()
+            .set_unix_permissions(id, perms);
+        Ok(())
+    }
+
+    /// Return the permissions of a file or directory
+    ///
+    pub(crate) fn get_permissions(&self, id: UfsUuid) -> Option<u16> {
+        self.block_manager.metadata().unix_permissions(id)
+    }
+
+    /// Return the time stamps of a file or directory
+    ///
+    pub(crate) fn get_times(&self, id: UfsUuid) -> Option<Timestamps> {
+        self.block_manager.metadata().times(id)
+    }
+
+    /// Set the access and modification times of a file or directory
+    ///
+    pub(crate) fn set_times(
+        &mut self,
+        id: UfsUuid,
+        access: Option<UfsTime>,
+        write: Option<UfsTime>,
+    ) -> Result<(), failure::Error> {
+        self.check_writable()?;
+        self.block_manager
+            .metadata_mut()
+            .set_times(id, access, write)
+    }
+
+    /// Return the value of an extended attribute of a file or directory
+    ///
+    pub(crate) fn get_xattr(&self, id: UfsUuid, name: &str) -> Result<Vec<u8>, failure::Error> {
+        match self.block_manager.metadata().xattrs(id) {
+            Some(xattrs) => match xattrs.get(name) {
+                Some(value) => Ok(value.to_vec()),
+                None => Err(IOFSErrorKind::NoAttribute.into()),
+            },
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Return the names of the extended attributes of a file or directory
+    ///
+    pub(crate) fn list_xattrs(&self, id: UfsUuid) -> Result<Vec<String>, failure::Error> {
+        match self.block_manager.metadata().xattrs(id) {
+            Some(xattrs) => Ok(xattrs.names().into_iter().map(String::from).collect()),
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Set an extended attribute of a file or directory
+    ///
+    /// An existing value is replaced.
+    pub(crate) fn set_xattr(
+        &mut self,
+        id: UfsUuid,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!("`set_xattr`: {:?}, {}, {} bytes", id, name, value.len());
+        self.check_writable()?;
+        self.block_manager.metadata_mut().set_xattr(id, name, value)
+    }
+
+    /// Remove an extended attribute from a file or directory
+    ///
+    pub(crate) fn remove_xattr(&mut self, id: UfsUuid, name: &str) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!("`remove_xattr`: {:?}, {}", id, name);
+        self.check_writable()?;
+        self.block_manager.metadata_mut().remove_xattr(id, name)
+    }
+
+    //
+    //
+    // Functions specifically for Rust-side WASM related use.
+    //
+    //
+
+    /// Return file size
+    ///
+    /// Used in the WASM file read implementation in order to know how many bytes to read.
+    ///
+    pub(crate) fn get_file_size(&self, handle: FileHandle) -> Result<FileSize, failure::Error> {
+        if let Some((file, _)) = self.open_files.get(&handle) {
+            let size = file.version.size();
+            Ok(self.write_buffers.get(&handle).map_or(size, |buffer| {
+                size.max(buffer.offset + buffer.bytes.len() as FileSize)
+            }))
+        } else {
+            Err(format_err!("File not open {}", handle))
+        }
+    }
+
+    /// Open a sub-directory
+    ///
+    pub(crate) fn open_sub_directory(
+        &mut self,
+        pid: UfsUuid,
+        name: &str,
+    ) -> Result<UfsUuid, failure::Error> {
+        match self
+            .block_manager
+            .metadata()
+            .get_dir_metadata_from_dir_and_name(pid, name)
+        {
+            Ok(dir_meta) => Ok(dir_meta.id()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// List the entries of a directory
+    ///
+    /// Each entry is returned as its name, its ID, and whether or not it's a directory, in the
+    /// directory's listing order.
+    pub(crate) fn read_sub_directory(
+        &self,
+        id: UfsUuid,
+    ) -> Result<Vec<(String, UfsUuid, bool)>, failure::Error> {
+        let dir = self.block_manager.metadata().get_directory(id)?;
+        Ok(dir
+            .sorted_entries()
+            .into_iter()
+            .map(|(name, entry)| (name.clone(), entry.id(), entry.is_dir()))
+            .collect())
+    }
+
+    /// Append lines to a WASM program's log file
+    ///
+    /// The log is opened on the first write, and stays open until `close_wasm_log`, so that the
+    /// program's output goes into a single version of the file.
+    ///
+    /// No events are sent for writes to the log. Otherwise a program that prints from an event
+    /// handler would be chasing its own tail.
+    pub(crate) fn append_wasm_log(
+        &mut self,
+        program: &Path,
+        lines: &str,
+    ) -> Result<(), failure::Error> {
+        if self.wasm_log_size == 0 || self.read_only || lines.is_empty() {
+            return Ok(());
+        }
+
+        let events = self.event_sender.take();
+        let result = self.write_wasm_log(program, lines);
+        self.event_sender = events;
+        result
+    }
+
+    /// Close a WASM program's log file, if it's open
+    ///
+    pub(crate) fn close_wasm_log(&mut self, program: &Path) -> Result<(), failure::Error> {
+        match self.wasm_logs.remove(program) {
+            Some(handle) => {
+                let events = self.event_sender.take();
+                let result = self.close_file(handle);
+                self.event_sender = events;
+                result
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn write_wasm_log(&mut self, program: &Path, lines: &str) -> Result<(), failure::Error> {
+        let dir_id = program
+            .parent()
+            .and_then(|dir| self.block_manager.metadata().id_from_path(dir))
+            .ok_or_else(|| format_err!("unable to find the directory of {:?}", program))?;
+        let name = program
+            .with_extension("log")
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_owned())
+            .ok_or_else(|| format_err!("invalid program name {:?}", program))?;
+
+        let size = match self.wasm_logs.get(program) {
+            Some(&handle) => Some(self.get_file_size(handle)?),
+            None => self
+                .block_manager
+                .metadata()
+                .get_file_metadata_from_dir_and_name(dir_id, &name)
+                .map(|log| log.get_latest().size())
+                .ok(),
+        };
+
+        if let Some(size) = size {
+            if size > 0 && size + lines.len() as FileSize > self.wasm_log_size {
+                if let Some(handle) = self.wasm_logs.remove(program) {
+                    self.close_file(handle)?;
+                }
+                let rotated = format!("{}.1", name);
+                if self
+                    .block_manager
+                    .metadata()
+                    .get_file_metadata_from_dir_and_name(dir_id, &rotated)
+                    .is_ok()
+                {
+                    self.remove_file(dir_id, &rotated)?;
+                }
+                self.rename_entry(dir_id, &name, dir_id, &rotated)?;
+            }
+        }
+
+        let handle = match self.wasm_logs.get(program) {
+            Some(&handle) => handle,
+            None => {
+                let existing = self
+                    .block_manager
+                    .metadata()
+                    .get_file_metadata_from_dir_and_name(dir_id, &name)
+                    .map(|log| log.id());
+                let handle = match existing {
+                    Ok(id) => self.open_file(id, OpenFileMode::Append)?,
+                    Err(_) => self.create_file(dir_id, &name)?.0,
+                };
+                self.wasm_logs.insert(program.to_owned(), handle);
+                handle
+            }
+        };
+
+        self.write_file(handle, lines.as_bytes(), 0).map(|_| ())
+    }
+
+    /// Return the size, unix permissions, number of versions, and time stamps of a file
+    ///
+    /// This is for WASM programs, which would otherwise have to read a file to learn its size.
+    pub(crate) fn stat_file(
+        &self,
+        id: UfsUuid,
+    ) -> Result<(FileSize, u16, usize, Timestamps), failure::Error> {
+        let file = self.block_manager.metadata().get_file_metadata(id)?;
+        Ok((
+            file.get_latest().size(),
+            file.unix_perms(),
+            file.get_versions().len(),
+            file.times(),
+        ))
+    }
+
+    /// Return the block count, free block count, block size, and number of open files
+    ///
+    /// This is for WASM programs, so that they may react to the file system filling up. As with
+    /// FUSE `statfs`, the free blocks don't include those left for the next copy of the metadata.
+    pub(crate) fn statfs(&self) -> (BlockCardinality, BlockCardinality, BlockSize, usize) {
+        (
+            self.block_manager.block_count(),
+            self.block_manager.available_block_count(),
+            self.block_manager.block_size(),
+            self.open_files.len(),
+        )
+    }
+}
+
+/// The names in a directory, directories first, and otherwise in the directory's listing order
+///
+fn listing_order(dir: &DirectoryMetadata) -> Vec<String> {
+    let mut entries = dir.sorted_entries();
+    entries.sort_by_key(|(_, entry)| !entry.is_dir());
+    entries.into_iter().map(|(name, _)| name.clone()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{convert::TryFrom, sync::Mutex};
+
+    use crate::{
+        block::{storage::network::mock::MockBlockServer, BlockReader},
+        metadata::{Grant, GrantType},
+    };
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn permissions() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+
+        let (h, file) = ufs.create_file(root_id, "artifact").unwrap();
+        ufs.close_file(h).unwrap();
+        let dir = ufs.create_directory(root_id, "build").unwrap();
+
+        ufs.set_permissions(file.file_id, 0o444).unwrap();
+        ufs.set_permissions(dir.id(), 0o555).unwrap();
+        assert_eq!(Some(0o444), ufs.get_permissions(file.file_id));
+        assert_eq!(Some(0o555), ufs.get_permissions(dir.id()));
+        assert_eq!(None, ufs.get_permissions(root_id.new("nothing")));
+    }
+
+    #[test]
+    fn wasm_log() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let wasm_dir = ufs.create_directory(root_id, ".wasm").unwrap();
+        let program = PathBuf::from("/.wasm/chatty.wasm");
+
+        let read_log = |ufs: &mut UberFileSystem<MemoryStore>, name: &str| {
+            let log = ufs
+                .block_manager
+                .metadata()
+                .get_file_metadata_from_dir_and_name(wasm_dir.id(), name)
+                .unwrap();
+            let h = ufs.open_file(log.id(), OpenFileMode::Read).unwrap();
+            let size = ufs.get_file_size(h).unwrap();
+            let contents = ufs.read_file(h, 0, size as u32).unwrap();
+            ufs.close_file(h).unwrap();
+            String::from_utf8(contents).unwrap()
+        };
+
+        // The log stays open, so both lines go into one version, after the empty one that it was
+        // created with.
+        ufs.append_wasm_log(&program, "one\n").unwrap();
+        ufs.append_wasm_log(&program, "two\n").unwrap();
+        ufs.close_wasm_log(&program).unwrap();
+        let log = read_log(&mut ufs, "chatty.log");
+        assert_eq!("one\ntwo\n", log);
+        let versions = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata_from_dir_and_name(wasm_dir.id(), "chatty.log")
+            .unwrap()
+            .get_versions()
+            .len();
+        assert_eq!(2, versions);
+
+        // The next line doesn't fit, so the log is rotated.
+        ufs.set_wasm_log_size(log.len() as FileSize + 4);
+        ufs.append_wasm_log(&program, "three\n").unwrap();
+        assert_eq!(log, read_log(&mut ufs, "chatty.log.1"));
+
+        // Including while the log is open.
+        ufs.append_wasm_log(&program, "four\n").unwrap();
+        ufs.close_wasm_log(&program).unwrap();
+        assert_eq!("three\n", read_log(&mut ufs, "chatty.log.1"));
+        assert_eq!("four\n", read_log(&mut ufs, "chatty.log"));
+    }
+
+    #[test]
+    fn stat_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+
+        let (h, file) = ufs.create_file(root_id, "stat").unwrap();
+        ufs.write_file(h, b"Hello World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let (size, perms, versions, times) = ufs.stat_file(file.file_id).unwrap();
+        assert_eq!(12, size);
+        assert_eq!(file.perms, perms);
+        assert_eq!(ufs.get_times(file.file_id).unwrap(), times);
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, b"Goodbye World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let (size, _, more_versions, _) = ufs.stat_file(file.file_id).unwrap();
+        assert_eq!(14, size);
+        assert!(more_versions > versions);
+
+        assert!(ufs.stat_file(root_id).is_err());
+    }
+
+    #[test]
+    fn statfs() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (count, free, size, open) = ufs.statfs();
+        assert_eq!(100, count);
+        assert_eq!(ufs.block_manager.available_block_count(), free);
+        assert!(free < ufs.block_manager.free_block_count());
+        assert_eq!(BlockSize::TwentyFortyEight, size);
+        assert_eq!(0, open);
+
+        let (h, _) = ufs.create_file(root_id, "statfs").unwrap();
+        ufs.write_file(h, &[0x42; 4096], 0).unwrap();
+        let (_, fewer, _, open) = ufs.statfs();
+        assert!(fewer < free);
+        assert_eq!(1, open);
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn file_times() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+
+        let (h, file) = ufs.create_file(root_id, "times").unwrap();
+        ufs.write_file(h, b"Hello World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let created = ufs.get_times(file.file_id).unwrap();
+        assert!(created.write >= created.birth);
+
+        let then = UfsTime::try_from(time::Timespec::new(1_000_000, 0)).unwrap();
+        let later = UfsTime::try_from(time::Timespec::new(2_000_000, 0)).unwrap();
+        ufs.set_times(file.file_id, Some(then), Some(later))
+            .unwrap();
+        let times = ufs.get_times(file.file_id).unwrap();
+        assert_eq!(then, times.access);
+        assert_eq!(later, times.write);
+        assert_eq!(created.birth, times.birth);
+        assert!(times.change >= created.change);
+
+        // Reading only changes the access time.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        ufs.read_file(h, 0, 12).unwrap();
+        ufs.close_file(h).unwrap();
+        let read = ufs.get_times(file.file_id).unwrap();
+        assert!(read.access > later);
+        assert_eq!(later, read.write);
+
+        // Reading it again is no reason to write the metadata out.
+        ufs.sync().unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        ufs.read_file(h, 0, 12).unwrap();
+        ufs.close_file(h).unwrap();
+        assert!(!ufs.block_manager.metadata().is_dirty());
+        assert_eq!(read, ufs.get_times(file.file_id).unwrap());
+
+        // Writing changes the modification time, and keeps the access time.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, b"Goodbye World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let written = ufs.get_times(file.file_id).unwrap();
+        assert!(written.write > later);
+        assert_eq!(read.access, written.access);
+        assert_eq!(created.birth, written.birth);
+
+        let e = ufs
+            .set_times(file.file_id.random(), None, None)
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn open_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (h0, file) = ufs.create_file(root_id, "test_open_file").unwrap();
+
+        let h1 = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert!(
+            h0 != h1,
+            "two open calls to the same file should return different handles"
+        );
+    }
+
+    #[test]
+    fn remove_user_and_change_password() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/remove_user");
+        let _ = std::fs::remove_dir_all(path);
+
+        {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                64,
+            )
+            .unwrap();
+            ufs.add_user("other".to_string(), "barfoo".to_string());
+        }
+
+        let dir_id = {
+            let mut ufs =
+                UberFileSystem::load_file_backed("master", "other", "barfoo", path).unwrap();
+            let root_id = ufs.get_root_directory_id();
+            let dir = ufs.create_directory(root_id, "mine").unwrap();
+            assert_eq!(UfsUuid::new_user("other"), dir.owner());
+
+            let token = ufs
+                .login("other".to_string(), "barfoo".to_string())
+                .unwrap();
+            assert!(ufs
+                .change_password(
+                    "other".to_string(),
+                    "wrong".to_string(),
+                    "bazqux".to_string()
+                )
+                .is_err());
+            assert!(ufs.validate_token(token.clone()).is_ok());
+
+            ufs.change_password(
+                "other".to_string(),
+                "barfoo".to_string(),
+                "bazqux".to_string(),
+            )
+            .unwrap();
+            assert!(ufs.validate_token(token).is_err());
+            assert!(ufs
+                .login("other".to_string(), "barfoo".to_string())
+                .is_none());
+            assert!(ufs
+                .login("other".to_string(), "bazqux".to_string())
+                .is_some());
+
+            dir.id()
+        };
+
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        let token = ufs
+            .login("other".to_string(), "bazqux".to_string())
+            .unwrap();
+        assert!(ufs.remove_user("admin".to_string()).is_err());
+        assert!(ufs.remove_user("nobody".to_string()).is_err());
+
+        ufs.remove_user("other".to_string()).unwrap();
+        assert!(ufs.validate_token(token).is_err());
+        assert!(ufs
+            .login("other".to_string(), "bazqux".to_string())
+            .is_none());
+        assert_eq!(vec!["admin".to_string()], ufs.get_users());
+
+        // The directory went to the owner of the file system.
+        let dir = ufs.block_manager.metadata().lookup_dir(dir_id).unwrap();
+        assert_eq!(UfsUuid::new_user("admin"), dir.owner());
+    }
+
+    #[test]
+    fn active_sessions() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.add_user("other".to_string(), "barfoo".to_string());
+
+        let t0 = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let t1 = ufs
+            .login("other".to_string(), "barfoo".to_string())
+            .unwrap();
+
+        let sessions = ufs.active_sessions();
+        assert_eq!(2, sessions.len());
+        for (token, user) in vec![(&t0, "test"), (&t1, "other")] {
+            let jti = ufs.tokens.get(token).unwrap().jti;
+            assert!(sessions
+                .iter()
+                .any(|(u, j, _)| *u == UfsUuid::new_user(user) && *j == jti));
+        }
+
+        ufs.logout(t0.clone()).unwrap();
+        let sessions = ufs.active_sessions();
+        assert_eq!(1, sessions.len());
+        assert_eq!(UfsUuid::new_user("other"), sessions[0].0);
+        assert!(ufs.validate_token(t0.clone()).is_err());
+        assert!(ufs.logout(t0).is_err());
+    }
+
+    #[test]
+    fn token_expiration() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        assert!(ufs.validate_token(token).is_ok());
+
+        ufs.set_token_ttl(Duration::zero());
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let e = ufs.validate_token(token.clone()).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::TokenExpired),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // It's forgotten once it's expired.
+        let e = ufs.validate_token(token).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::UnknownToken),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn open_truncate() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = include_str!("lib.rs").as_bytes();
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (h, file) = ufs.create_file(root_id, "lib.rs").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, test, 0).unwrap());
+        ufs.close_file(h).unwrap();
+        let free_blocks = ufs.block_manager.free_block_count();
+
+        // Truncate to zero, as with O_RDWR | O_TRUNC
+        let h = ufs
+            .open_file(file.file_id, OpenFileMode::ReadWrite)
+            .unwrap();
+        ufs.truncate_file(h, 0).unwrap();
+        assert_eq!(0, ufs.get_file_size(h).unwrap());
+        assert!(ufs.read_file(h, 0, 0).unwrap().is_empty());
+        ufs.close_file(h).unwrap();
+
+        // The previous version still owns its blocks, so nothing was recycled.
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(0, ufs.get_file_size(h).unwrap());
+    }
+
+    #[test]
+    fn truncate_mid_block() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = include_str!("fuse.rs").as_bytes();
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (h, _) = ufs.create_file(root_id, "fuse.rs").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, test, 0).unwrap());
+        let free_blocks = ufs.block_manager.free_block_count();
+
+        // These blocks were never committed, so they are recycled.
+        let size = 3000;
+        ufs.truncate_file(h, size as FileSize).unwrap();
+        assert_eq!(size as FileSize, ufs.get_file_size(h).unwrap());
+        assert_eq!(test[..size], ufs.read_file(h, 0, size as u32).unwrap()[..]);
+        // Two blocks are needed for the first 3000 bytes, and the second is rewritten.
+        let blocks = (test.len() + 2047) / 2048;
+        assert_eq!(
+            free_blocks + blocks as u64 - 2,
+            ufs.block_manager.free_block_count()
+        );
+    }
+
+    #[test]
+    fn flush_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+
+        let (h, file) = ufs.create_file(root_id, "flush").unwrap();
+        assert_eq!(5, ufs.write_file(h, b"Hello", 0).unwrap());
+        let root_block = ufs.block_manager.root_block();
+        ufs.flush_file(h).unwrap();
+        assert_ne!(root_block, ufs.block_manager.root_block());
+
+        // The flushed data is visible before the file is closed.
+        let h2 = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(b"Hello", &ufs.read_file(h2, 0, 5).unwrap()[..]);
+        ufs.close_file(h2).unwrap();
+
+        // Flushing, and closing, without further writes commits nothing new.
+        let versions = |ufs: &UberFileSystem<MemoryStore>| {
+            ufs.block_manager
+                .metadata()
+                .get_file_metadata(file.file_id)
+                .unwrap()
+                .get_versions()
+                .len()
+        };
+        let count = versions(&ufs);
+        ufs.flush_file(h).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(count, versions(&ufs));
+    }
+
+    #[test]
+    fn read_head() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+
+        let large = include_str!("fuse.rs").as_bytes();
+        let (h, large_file) = ufs.create_file(root_id, "large").unwrap();
+        assert_eq!(large.len(), ufs.write_file(h, large, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let (h, tiny_file) = ufs.create_file(root_id, "tiny").unwrap();
+        assert_eq!(2, ufs.write_file(h, b"Hi", 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // Spanning the first two blocks
+        assert_eq!(
+            large[..3000],
+            ufs.read_head(large_file.file_id, 3000).unwrap()[..]
+        );
+        assert!(ufs.read_head(large_file.file_id, 0).unwrap().is_empty());
+
+        assert_eq!(b"Hi", &ufs.read_head(tiny_file.file_id, 512).unwrap()[..]);
+
+        let dir = ufs.create_directory(root_id, "dir").unwrap();
+        assert!(ufs.read_head(dir.id(), 512).is_err());
+    }
+
+    #[test]
+    fn open_append() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "log").unwrap();
+        assert_eq!(7, ufs.write_file(h, b"Hello, ", 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // Both writes claim offset 0, but land at the end.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Append).unwrap();
+        assert_eq!(6, ufs.write_file(h, b"World!", 0).unwrap());
+        assert_eq!(2, ufs.write_file(h, b"!!", 0).unwrap());
+        let e = ufs.read_file(h, 0, 7).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotOpenForReading),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(15, ufs.get_file_size(h).unwrap());
+        assert_eq!(b"Hello, World!!!", &ufs.read_file(h, 0, 15).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+
+        // Opened for reading too, the file can be read back.
+        let h = ufs
+            .open_file(file.file_id, OpenFileMode::ReadAppend)
+            .unwrap();
+        assert_eq!(1, ufs.write_file(h, b"?", 0).unwrap());
+        ufs.close_file(h).unwrap();
+        let h = ufs
+            .open_file(file.file_id, OpenFileMode::ReadAppend)
+            .unwrap();
+        assert_eq!(b"Hello, World!!!?", &ufs.read_file(h, 0, 16).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn write_beyond_eof() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = b"after the hole";
+        let offset = 1024 * 1024;
+
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "sparse").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, test, offset).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        let size = offset + test.len() as u64;
+        assert_eq!(size, ufs.get_file_size(h).unwrap());
+        let bytes = ufs.read_file(h, 0, size as u32).unwrap();
+        assert!(bytes[..offset as usize].iter().all(|b| *b == 0));
+        assert_eq!(test[..], bytes[offset as usize..]);
+
+        // The hole takes up no space.
+        let (open, _) = ufs.open_files.get(&h).unwrap();
+        assert_eq!(&vec![offset], open.version.offsets());
+        ufs.close_file(h).unwrap();
+
+        // There's no writing into the hole, or over what's already there.
+        let h = ufs
+            .open_file(file.file_id, OpenFileMode::ReadWrite)
+            .unwrap();
+        for at in &[0, offset, size - 1] {
+            let e = ufs.write_file(h, b"over", *at).unwrap_err();
+            assert_eq!(
+                Some(&IOFSErrorKind::Overwrite),
+                e.downcast_ref::<IOFSErrorKind>()
+            );
+        }
+        assert_eq!(4, ufs.write_file(h, b"more", size).unwrap());
+        let bytes = ufs.read_file(h, offset, size as u32).unwrap();
+        assert_eq!(b"after the holemore", &bytes[..]);
+
+        // The content hash takes in a hole at the end of the file.
+        ufs.flush_file(h).unwrap();
+        let before = ufs.content_hash(file.file_id).unwrap();
+        ufs.truncate_file(h, size + 4096).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_ne!(before, ufs.content_hash(file.file_id).unwrap());
+    }
+
+    #[test]
+    fn recover_poisoned_lock() {
+        init();
+
+        let ufs = Arc::new(RwLock::new(UberFileSystem::new_memory(
+            "test",
+            "foobar",
+            "test",
+            BlockSize::TwentyFortyEight,
+            100,
+        )));
+        let poisoner = ufs.clone();
+        spawn(move || {
+            let _guard = write_ufs(&poisoner);
+            panic!("poisoning the lock");
+        })
+        .join()
+        .unwrap_err();
+        assert!(ufs.is_poisoned());
+
+        let mut guard = write_ufs(&ufs);
+        let root_id = guard.get_root_directory_id();
+        let (h, _) = guard.create_file(root_id, "survivor").unwrap();
+        guard.close_file(h).unwrap();
+        drop(guard);
+        assert!(!ufs.is_poisoned());
+    }
+
+    #[test]
+    fn large_blocks() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::SixtyFourK, 32);
+        let test = (0..200_000).map(|b| (b % 251) as u8).collect::<Vec<_>>();
+
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "large").unwrap();
+        // Small writes are buffered until there's a block's worth.
+        for (n, chunk) in test.chunks(4000).enumerate() {
+            assert_eq!(
+                chunk.len(),
+                ufs.write_file(h, chunk, n as u64 * 4000).unwrap()
+            );
+        }
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(test.len() as u64, ufs.get_file_size(h).unwrap());
+        assert_eq!(test, ufs.read_file(h, 0, test.len() as u32).unwrap());
+        let (file, _) = ufs.open_files.get(&h).unwrap();
+        assert_eq!(4, file.version.offsets().len());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn random_reads() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = (0..100_000).map(|b| (b % 251) as u8).collect::<Vec<_>>();
+
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "random").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, &test, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // Backwards through the file, straddling block boundaries, and off the end.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        for offset in (0..test.len() + 4096).step_by(3001).rev() {
+            let end = (offset + 4096).min(test.len());
+            let start = offset.min(end);
+            let bytes = ufs.read_file(h, offset as u64, 4096).unwrap();
+            assert_eq!(
+                &test[start..end],
+                &bytes[..end - start],
+                "read at {}",
+                offset
+            );
+            assert!(
+                bytes[end - start..].iter().all(|b| *b == 0),
+                "read at {}",
+                offset
+            );
+        }
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn quotas() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.add_user("other".to_string(), "barfoo".to_string());
+        let root_id = ufs.get_root_directory_id();
+        let test = QuotaTarget::User("test".to_string());
+        let other = QuotaTarget::Directory(PathBuf::from("/other"));
+
+        // The files in a directory belong to its owner.
+        ufs.user = UfsUuid::new_user("other");
+        let other_id = ufs.create_directory(root_id, "other").unwrap().id();
+        ufs.user = UfsUuid::new_user("test");
+
+        let (h, _) = ufs.create_file(root_id, "a").unwrap();
+        ufs.write_file(h, &[1; 8000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!((8000, None), ufs.get_usage(test.clone()).unwrap());
+
+        ufs.set_quota(test.clone(), Some(10_000)).unwrap();
+        assert_eq!((8000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+
+        let (h, _) = ufs.create_file(root_id, "b").unwrap();
+        assert_eq!(
+            ufs.write_file(h, &[2; 4000], 0)
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+        assert_eq!(2000, ufs.write_file(h, &[2; 2000], 0).unwrap());
+        assert_eq!(
+            ufs.write_file(h, &[2; 1], 2000)
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+        ufs.close_file(h).unwrap();
+        assert_eq!((10_000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+        assert_eq!(
+            ufs.create_file(root_id, "c")
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+
+        // Another user carries on, until their directory is full.
+        let (h, _) = ufs.create_file(other_id, "big").unwrap();
+        ufs.write_file(h, &[3; 20_000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        ufs.set_quota(other.clone(), Some(25_000)).unwrap();
+        assert_eq!(
+            (20_000, Some(25_000)),
+            ufs.get_usage(other.clone()).unwrap()
+        );
+
+        let (h, _) = ufs.create_file(other_id, "small").unwrap();
+        assert_eq!(
+            ufs.write_file(h, &[4; 6000], 0)
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+        ufs.write_file(h, &[4; 5000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(
+            (25_000, Some(25_000)),
+            ufs.get_usage(other.clone()).unwrap()
+        );
+        assert_eq!((10_000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+
+        // Moving a file out of the directory, and removing one, gives the space back.
+        ufs.rename_entry(other_id, "big", root_id, "big").unwrap();
+        assert_eq!((5000, Some(25_000)), ufs.get_usage(other.clone()).unwrap());
+        ufs.remove_file(root_id, "a").unwrap();
+        assert_eq!((2000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+        let (h, _) = ufs.create_file(root_id, "c").unwrap();
+        ufs.write_file(h, &[5; 8000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        // Rewriting a file counts what it is now, not what it was.
+        let h = ufs.open_path("/b", OpenFileMode::Write).unwrap();
+        ufs.write_file(h, &[6; 1000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!((9000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+
+        ufs.set_quota(test.clone(), None).unwrap();
+        assert_eq!((9000, None), ufs.get_usage(test).unwrap());
+        assert_eq!(
+            ufs.get_usage(QuotaTarget::User("nobody".to_string()))
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::NotFound),
+        );
+    }
+
+    #[test]
+    fn quotas_across_handles() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let test = QuotaTarget::User("test".to_string());
+        ufs.set_quota(test.clone(), Some(10_000)).unwrap();
+
+        // What's written through one handle counts against the quota for the other, before either
+        // is committed.
+        let (a, _) = ufs.create_file(root_id, "a").unwrap();
+        let (b, _) = ufs.create_file(root_id, "b").unwrap();
+        ufs.write_file(a, &[1; 6000], 0).unwrap();
+        assert_eq!(
+            ufs.write_file(b, &[2; 6000], 0)
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+        ufs.write_file(b, &[2; 4000], 0).unwrap();
+        ufs.close_file(a).unwrap();
+        ufs.close_file(b).unwrap();
+        assert_eq!((10_000, Some(10_000)), ufs.get_usage(test.clone()).unwrap());
+
+        // A copy counts in full.
+        ufs.remove_file(root_id, "b").unwrap();
+        let a_id = ufs.block_manager.metadata().id_from_path("/a").unwrap();
+        assert_eq!(
+            ufs.copy_file(a_id, root_id, "c")
+                .unwrap_err()
+                .downcast_ref::<IOFSErrorKind>(),
+            Some(&IOFSErrorKind::QuotaExceeded),
+        );
+        assert!(ufs.block_manager.metadata().id_from_path("/c").is_none());
+        assert_eq!((6000, Some(10_000)), ufs.get_usage(test).unwrap());
+    }
+
+    #[test]
+    fn set_file_size() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = include_str!("fsimpl.rs").as_bytes();
+
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "fsimpl.rs").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, test, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // In the middle of the second block
+        ufs.set_file_size(file.file_id, 3000).unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(3000, ufs.get_file_size(h).unwrap());
+        assert_eq!(test[..3000], ufs.read_file(h, 0, 3000).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+
+        // Longer, with zeros from the end of the partial last block on. The zeros are a hole, and
+        // take no blocks.
+        let free = ufs.block_manager.free_block_count();
+        ufs.set_file_size(file.file_id, 5000).unwrap();
+        assert_eq!(free, ufs.block_manager.free_block_count());
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(5000, ufs.get_file_size(h).unwrap());
+        let bytes = ufs.read_file(h, 0, 5000).unwrap();
+        assert_eq!(test[..3000], bytes[..3000]);
+        assert!(bytes[3000..].iter().all(|b| *b == 0));
+        ufs.close_file(h).unwrap();
+
+        // What's written after the hole lands after it.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Append).unwrap();
+        ufs.write_file(h, b"tail", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(5004, ufs.get_file_size(h).unwrap());
+        let bytes = ufs.read_file(h, 0, 5004).unwrap();
+        assert_eq!(test[..3000], bytes[..3000]);
+        assert!(bytes[3000..5000].iter().all(|b| *b == 0));
+        assert_eq!(b"tail", &bytes[5000..]);
+        ufs.close_file(h).unwrap();
+
+        ufs.set_file_size(file.file_id, 0).unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(0, ufs.get_file_size(h).unwrap());
+        assert!(ufs.read_file(h, 0, 0).unwrap().is_empty());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn remove_large_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+
+        let (h, file) = ufs.create_file(root_id, "large").unwrap();
+        let data = vec![0x42u8; 2048 * 40];
+        assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let blocks = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file.file_id)
+            .unwrap()
+            .get_latest()
+            .blocks()
+            .clone();
+        assert_eq!(40, blocks.len());
+
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.remove_file(root_id, "large").unwrap();
+        assert_eq!(
+            free_blocks + blocks.len() as u64,
+            ufs.block_manager.free_block_count()
+        );
+    }
+
+    #[test]
+    fn remove_open_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let data = vec![0x42u8; 5000];
+
+        let (h, file) = ufs.create_file(root_id, "doomed").unwrap();
+        assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.remove_file(root_id, "doomed").unwrap();
+        assert_eq!(None, ufs.block_manager.metadata().id_from_path("/doomed"));
+
+        // The data is still there for the open handle, but nobody else.
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        let e = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        ufs.close_file(h).unwrap();
+        assert_eq!(free_blocks + 3, ufs.block_manager.free_block_count());
+    }
+
+    #[test]
+    fn hard_links() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+
+        let (h, file) = ufs.create_file(root_id, "first").unwrap();
+        ufs.write_file(h, b"Hello, link!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let link = ufs.link(file.file_id, a.id(), "second").unwrap();
+        assert_eq!(2, link.links());
+        assert_eq!(file.file_id, link.id());
+        let e = ufs.link(file.file_id, a.id(), "second").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::FileExists),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Writing through one name shows up in the other.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, b"Goodbye, link!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let metadata = ufs.block_manager.metadata();
+        let first = metadata
+            .get_file_metadata_from_dir_and_name(root_id, "first")
+            .unwrap();
+        let second = metadata
+            .get_file_metadata_from_dir_and_name(a.id(), "second")
+            .unwrap();
+        assert_eq!(first.get_latest(), second.get_latest());
+        assert_eq!(root_id, first.dir_id());
+        assert_eq!(a.id(), second.dir_id());
+
+        // Removing one link leaves the data alone.
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.remove_file(root_id, "first").unwrap();
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        let id = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/a/second")
+            .unwrap();
+        assert_eq!(
+            1,
+            ufs.block_manager
+                .metadata()
+                .lookup_file(id)
+                .unwrap()
+                .links()
+        );
+        assert_eq!(b"Goodbye, link!", &ufs.read_head(id, 512).unwrap()[..]);
+
+        // And removing the last one recycles it.
+        ufs.remove_file(a.id(), "second").unwrap();
+        assert!(ufs.block_manager.free_block_count() > free_blocks);
+    }
+
+    #[test]
+    fn copy_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let dir = ufs.create_directory(root_id, "copies").unwrap();
+        let block = 2048;
+
+        let original = (0..block * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (h, file) = ufs.create_file(root_id, "original").unwrap();
+        ufs.write_file(h, &original, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        // The copy takes no blocks of its own.
+        let free_blocks = ufs.block_manager.free_block_count();
+        let copy = ufs.copy_file(file.file_id, dir.id(), "copy").unwrap();
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        assert_eq!(original.len() as FileSize, copy.version.size());
+        assert_eq!(
+            original,
+            ufs.read_head(copy.file_id, original.len()).unwrap()
+        );
+
+        let e = ufs.copy_file(file.file_id, dir.id(), "copy").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::FileExists),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Changing the copy leaves the original alone.
+        let h = ufs.open_file(copy.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, &vec![0x42; block], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(
+            original,
+            ufs.read_head(file.file_id, original.len()).unwrap()
+        );
+        let changed = ufs.read_head(copy.file_id, original.len()).unwrap();
+        assert_eq!(vec![0x42; block], &changed[..block]);
+        assert!(ufs.block_manager.check(false).problems.is_empty());
+
+        // The shared blocks outlive the original.
+        ufs.remove_file(root_id, "original").unwrap();
+        let h = ufs.open_file(copy.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(
+            original[block..],
+            ufs.read_file(h, block as u64, original.len() as u32)
+                .unwrap()[..]
+        );
+        ufs.close_file(h).unwrap();
+
+        // And are freed along with the copy.
+        ufs.remove_file(dir.id(), "copy").unwrap();
+        assert!(ufs.block_manager.free_block_count() > free_blocks);
+        assert!(ufs.block_manager.check(false).problems.is_empty());
+    }
+
+    #[test]
+    fn fill_store() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 10);
+        let root_id = ufs.get_root_directory_id();
+        let block = 2048;
+
+        let (h, file) = ufs.create_file(root_id, "full").unwrap();
+        ufs.close_file(h).unwrap();
+        ufs.block_manager.serialize();
+        let metadata_blocks = ufs.block_manager.metadata_block_count();
+        assert!(metadata_blocks > 0);
+        let free = ufs.block_manager.free_block_count();
+        let available = ufs.block_manager.available_block_count();
+        assert_eq!(free - metadata_blocks, available);
+
+        // Asking for more than there is fails up front, without writing anything.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        let e = ufs
+            .write_file(h, &vec![1; block * (available as usize + 1)], 0)
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        assert_eq!(free, ufs.block_manager.free_block_count());
+
+        // Then fill it, and write one more block.
+        let data = vec![2; block * available as usize];
+        assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+        assert_eq!(metadata_blocks, ufs.block_manager.free_block_count());
+        assert_eq!(0, ufs.block_manager.available_block_count());
+        let e = ufs
+            .write_file(h, &vec![3; block], data.len() as u64)
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // A byte more is buffered, and it's closing the file that finds there's no room for it.
+        // The file is left open, less the byte.
+        assert_eq!(1, ufs.write_file(h, &[4], data.len() as u64).unwrap());
+        let e = ufs.close_file(h).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // There's still room to write the metadata.
+        let root_block = ufs.block_manager.store().map().root_block();
+        ufs.close_file(h).unwrap();
+        ufs.block_manager.serialize();
+        assert!(!ufs.block_manager.metadata().is_dirty());
+        assert_ne!(root_block, ufs.block_manager.store().map().root_block());
+
+        // What did fit is still there.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(
+            data[..block],
+            ufs.read_file(h, 0, block as u32).unwrap()[..]
+        );
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn prune_versions_for_space() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 40);
+        let root_id = ufs.get_root_directory_id();
+        let block = 2048;
+
+        // Four versions of eight blocks apiece, leaving seven of 39 blocks free.
+        let (h, file) = ufs.create_file(root_id, "versions").unwrap();
+        ufs.write_file(h, &vec![0; block * 8], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        for i in 1..4 {
+            let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+            ufs.write_file(h, &vec![i; block * 8], 0).unwrap();
+            ufs.close_file(h).unwrap();
+        }
+        assert_eq!(7, ufs.block_manager.free_block_count());
+
+        let data = vec![4; block * 8];
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        let e = ufs.write_file(h, &data, 0).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoSpace),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Keeping 10 blocks in reserve means that 11 have to go: the two oldest versions.
+        ufs.set_reservation(Some(25));
+        assert_eq!(Some(25), ufs.reservation());
+        assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+        ufs.close_file(h).unwrap();
+        assert_eq!(7 + 16 - 8, ufs.block_manager.free_block_count());
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let metadata = ufs.block_manager.metadata().get_file_metadata(file.file_id);
+        let written = metadata
+            .unwrap()
+            .get_versions()
+            .values()
+            .filter(|v| v.size() > 0)
+            .count();
+        assert_eq!(3, written);
+    }
+
+    #[test]
+    fn remove_version() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 40);
+        let root_id = ufs.get_root_directory_id();
+        let block = 2048;
+
+        // The second version rewrites the first block, and shares the second.
+        let (h, file) = ufs.create_file(root_id, "doc").unwrap();
+        ufs.write_file(h, &vec![1; block * 2], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, &vec![2; block], 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let metadata = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file.file_id)
+            .unwrap();
+        let latest = metadata.latest_version();
+        let old_blocks = metadata.get_versions()[&(latest - 1)].blocks().clone();
+        let new_blocks = metadata.get_versions()[&latest].blocks().clone();
+        let unique = old_blocks
+            .iter()
+            .filter(|b| !new_blocks.contains(b))
+            .count();
+        assert_eq!(1, unique);
+
+        let vers_id = ufs.block_manager.metadata().id_from_path("/.vers").unwrap();
+
+        // Not while the file is open
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        let e = ufs
+            .remove_file(vers_id, &format!("doc@{}", latest - 1))
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::FileOpen),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        ufs.close_file(h).unwrap();
+
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.remove_file(vers_id, &format!("doc@{}", latest - 1))
+            .unwrap();
+        assert_eq!(
+            free_blocks + unique as BlockCardinality,
+            ufs.block_manager.free_block_count()
+        );
+        let versions = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file.file_id)
+            .unwrap()
+            .get_versions()
+            .len();
+        assert_eq!(metadata.get_versions().len() - 1, versions);
+
+        // The latest version stays, and is still readable.
+        let e = ufs
+            .remove_file(vers_id, &format!("doc@{}", latest))
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::LatestVersion),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        let e = ufs.remove_file(vers_id, "doc@99").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        let mut expected = vec![2; block];
+        expected.extend(vec![1; block]);
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(
+            expected,
+            ufs.read_file(h, 0, expected.len() as u32).unwrap()
+        );
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn version_retention() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 40);
+        let root_id = ufs.get_root_directory_id();
+        let block = 2048;
+        ufs.set_retention(Some(Retention::Versions(2)));
+        assert_eq!(Some(Retention::Versions(2)), ufs.retention());
+
+        let write = |ufs: &mut UberFileSystem<MemoryStore>, id, byte| {
+            let h = ufs.open_file(id, OpenFileMode::Write).unwrap();
+            ufs.write_file(h, &vec![byte; block], 0).unwrap();
+            ufs.close_file(h).unwrap();
+        };
+        let versions = |ufs: &UberFileSystem<MemoryStore>, id| {
+            ufs.block_manager
+                .metadata()
+                .get_file_metadata(id)
+                .unwrap()
+                .get_versions()
+                .len()
+        };
+
+        let (h, file) = ufs.create_file(root_id, "doc").unwrap();
+        ufs.close_file(h).unwrap();
+        write(&mut ufs, file.file_id, 1);
+        write(&mut ufs, file.file_id, 2);
+        let free_blocks = ufs.block_manager.free_block_count();
+
+        // Each new version pushes the oldest one out, and its block is freed.
+        for byte in 3..6 {
+            write(&mut ufs, file.file_id, byte);
+            assert_eq!(2, versions(&ufs, file.file_id));
+            assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        }
+
+        // A version pruned while it's open keeps its block until it's closed.
+        let reader = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        write(&mut ufs, file.file_id, 6);
+        write(&mut ufs, file.file_id, 7);
+        assert_eq!(free_blocks - 1, ufs.block_manager.free_block_count());
+        assert_eq!(
+            vec![5; block],
+            ufs.read_file(reader, 0, block as u32).unwrap()
+        );
+        ufs.close_file(reader).unwrap();
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+
+        // The directory's policy takes precedence.
+        ufs.create_directory(root_id, "history").unwrap();
+        ufs.set_directory_retention("/history", Some(Retention::Versions(4)))
+            .unwrap();
+        let dir_id = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/history")
+            .unwrap();
+        let (h, kept) = ufs.create_file(dir_id, "doc").unwrap();
+        ufs.close_file(h).unwrap();
+        for byte in 1..7 {
+            write(&mut ufs, kept.file_id, byte);
+        }
+        assert_eq!(4, versions(&ufs, kept.file_id));
+
+        let e = ufs
+            .set_directory_retention("/missing", Some(Retention::Versions(1)))
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn corrupt_block() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/corrupt_block");
+        let _ = std::fs::remove_dir_all(path);
+
+        let (file_id, bn) = {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            let (h, file) = ufs.create_file(root_id, "bitrot").unwrap();
+            ufs.write_file(h, &[0x42; 1536], 0).unwrap();
+            ufs.close_file(h).unwrap();
+            assert_eq!(None, ufs.verify_file(file.file_id).unwrap());
+
+            let bn = ufs
+                .block_manager
+                .metadata()
+                .get_file_metadata(file.file_id)
+                .unwrap()
+                .get_latest()
+                .blocks()[1];
+            let block_path = ufs.block_manager.store().block_path(bn);
+
+            // Flip some bits behind the file system's back.
+            let mut bytes = std::fs::read(&block_path).unwrap();
+            bytes[42] ^= 0xff;
+            std::fs::write(&block_path, bytes).unwrap();
+
+            (file.file_id, bn)
+        };
+
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert_eq!(Some(bn), ufs.verify_file(file_id).unwrap());
+
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert!(ufs.read_file(h, 0, 1536).is_err());
+        assert!(ufs.read_file(h, 1024, 512).is_err());
+        assert_eq!(vec![0x42; 512], ufs.read_file(h, 0, 512).unwrap());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn aes_file_backed() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/aes_file_backed");
+        let _ = std::fs::remove_dir_all(path);
+
+        let file_id = {
+            let mut ufs = UberFileSystem::format_file_backed_with_options(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+                FormatOptions {
+                    cipher: Cipher::Aes256CtrHmacSha256,
+                    sparse: true,
+                    ..FormatOptions::default()
+                },
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            let (h, file) = ufs.create_file(root_id, "aes").unwrap();
+            ufs.write_file(h, &[0x42; 1536], 0).unwrap();
+            ufs.close_file(h).unwrap();
+            file.file_id
+        };
+
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert_eq!(
+            Cipher::Aes256CtrHmacSha256,
+            ufs.block_manager.store().map().cipher()
+        );
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(vec![0x42; 1536], ufs.read_file(h, 0, 1536).unwrap());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn rotate_master_key() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/rotate_master_key");
+        let _ = std::fs::remove_dir_all(path);
+
+        let file_id = {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            let (h, file) = ufs.create_file(root_id, "secret").unwrap();
+            ufs.write_file(h, &[0x42; 1536], 0).unwrap();
+            ufs.close_file(h).unwrap();
+
+            assert!(ufs.rotate_master_key("wrong", "retsam").is_err());
+            ufs.rotate_master_key("master", "retsam").unwrap();
+
+            // Blocks written after the rotation use the new key too.
+            let (h, _) = ufs.create_file(root_id, "later").unwrap();
+            ufs.write_file(h, b"after", 0).unwrap();
+            ufs.close_file(h).unwrap();
+
+            file.file_id
+        };
+
+        assert!(UberFileSystem::load_file_backed("master", "admin", "foobar", path).is_err());
+
+        let mut ufs = UberFileSystem::load_file_backed("retsam", "admin", "foobar", path).unwrap();
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(vec![0x42; 1536], ufs.read_file(h, 0, 1536).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_path("/later", OpenFileMode::Read).unwrap();
+        assert_eq!(b"after".to_vec(), ufs.read_file(h, 0, 5).unwrap());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn load_mirrored() {
+        init();
+
+        fn copy_dir(from: &Path, to: &Path) {
+            std::fs::create_dir_all(to).unwrap();
+            for entry in std::fs::read_dir(from).unwrap() {
+                let entry = entry.unwrap();
+                let to = to.join(entry.file_name());
+                if entry.file_type().unwrap().is_dir() {
+                    copy_dir(&entry.path(), &to);
+                } else {
+                    std::fs::copy(entry.path(), to).unwrap();
+                }
+            }
+        }
+
+        let path = Path::new("/tmp/ufs_test/load_mirrored");
+        let _ = std::fs::remove_dir_all(path);
+        let primary = path.join("primary");
+        let secondary = path.join("secondary");
+
+        let (file_id, blocks) = {
+            let mut ufs = UberFileSystem::format_file_backed(
+                &primary,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            let (h, file) = ufs.create_file(root_id, "mirrored").unwrap();
+            ufs.write_file(h, &[0x42; 1536], 0).unwrap();
+            ufs.close_file(h).unwrap();
+
+            let metadata = ufs.block_manager.metadata();
+            let file_metadata = metadata.get_file_metadata(file.file_id).unwrap();
+            (file.file_id, file_metadata.get_latest().blocks().clone())
+        };
+        copy_dir(&primary, &secondary);
+
+        // Damage the primary's copy of the file.
+        let store = FileStore::load("master", &primary).unwrap();
+        for bn in blocks {
+            std::fs::write(store.block_path(bn), b"damaged").unwrap();
+        }
+
+        let mut ufs = UberFileSystem::load_mirrored(
+            "admin",
+            "foobar",
+            FileStore::load("master", &primary).unwrap(),
+            FileStore::load("master", &secondary).unwrap(),
+        )
+        .unwrap();
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(vec![0x42; 1536], ufs.read_file(h, 0, 1536).unwrap());
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn dedup_blocks() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::FiveTwelve, 100);
+        ufs.set_block_dedup(true);
+        let root_id = ufs.get_root_directory_id();
+        let data = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let free = ufs.block_manager.free_block_count();
+        let (h, one) = ufs.create_file(root_id, "one").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let (h, two) = ufs.create_file(root_id, "two").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        // The second file shares the first file's blocks.
+        assert_eq!(free - 8, ufs.block_manager.free_block_count());
+        let blocks = |ufs: &UberFileSystem<MemoryStore>, id| {
+            ufs.block_manager
+                .metadata()
+                .get_file_metadata(id)
+                .unwrap()
+                .get_latest()
+                .blocks()
+                .clone()
+        };
+        assert_eq!(blocks(&ufs, one.file_id), blocks(&ufs, two.file_id));
+
+        // The blocks were encrypted for the first file, but read just the same in the second.
+        let h = ufs.open_file(two.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, 4096).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // The blocks outlive the first file, and go with the second.
+        ufs.remove_file(root_id, "one").unwrap();
+        assert_eq!(free - 8, ufs.block_manager.free_block_count());
+        let h = ufs.open_file(two.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, 4096).unwrap());
+        ufs.close_file(h).unwrap();
+        ufs.remove_file(root_id, "two").unwrap();
+        assert_eq!(free, ufs.block_manager.free_block_count());
+
+        // Repeats within a file are shared too.
+        let (h, three) = ufs.create_file(root_id, "three").unwrap();
+        ufs.write_file(h, &[0x42; 2048], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(free - 1, ufs.block_manager.free_block_count());
+        let h = ufs.open_file(three.file_id, OpenFileMode::Write).unwrap();
+        ufs.truncate_file(h, 1024).unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(free - 1, ufs.block_manager.free_block_count());
+        ufs.remove_file(root_id, "three").unwrap();
+        assert_eq!(free, ufs.block_manager.free_block_count());
+    }
+
+    #[test]
+    fn wasm_store_remount() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/wasm_store_remount");
+        let _ = std::fs::remove_dir_all(path);
+        let program = PathBuf::from("/.wasm/word-count.wasm");
+
+        {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::TwentyFortyEight,
+                64,
+            )
+            .unwrap();
+            ufs.block_manager
+                .metadata_mut()
+                .put_wasm_program_value(&program, "total".to_string(), "42".to_string())
+                .unwrap();
+        }
+
+        let ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert_eq!(
+            Some("42".to_string()),
+            ufs.block_manager
+                .metadata()
+                .get_wasm_program_value(&program, "total")
+        );
+    }
+
+    #[test]
+    fn compressed_blocks() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/compressed_blocks");
+        let _ = std::fs::remove_dir_all(path);
+
+        let data = b"All work and no play makes Jack a dull boy. ".repeat(200);
+        let file_id = {
+            let mut ufs = UberFileSystem::format_file_backed_with_options(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::TwentyFortyEight,
+                64,
+                FormatOptions {
+                    compress: true,
+                    ..FormatOptions::default()
+                },
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            let (h, file) = ufs.create_file(root_id, "dull").unwrap();
+            assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+            ufs.close_file(h).unwrap();
+            file.file_id
+        };
+
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        let blocks = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file_id)
+            .unwrap()
+            .get_latest()
+            .blocks()
+            .clone();
+        assert_eq!(5, blocks.len());
+        for bn in &blocks {
+            let block = ufs.block_manager.get_block(*bn).unwrap();
+            let stored = block.compressed_size().unwrap();
+            assert!(stored < block.size());
+            let on_disk = ufs.block_manager.store().read_block(*bn).unwrap();
+            assert_eq!(stored as usize, on_disk.len());
+        }
+
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        assert_eq!(
+            b"Jack".to_vec(),
+            ufs.read_head(file_id, 31).unwrap()[27..].to_vec()
+        );
+        ufs.close_file(h).unwrap();
+
+        // The setting belongs to the volume. Data that doesn't shrink is stored as is.
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "mixed").unwrap();
+        let noise = (0..2048).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+        ufs.write_file(h, &data[..2048], 0).unwrap();
+        ufs.write_file(h, &noise, 2048).unwrap();
+        ufs.close_file(h).unwrap();
+        let blocks = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file.file_id)
+            .unwrap()
+            .get_latest()
+            .blocks()
+            .clone();
+        let block = ufs.block_manager.get_block(blocks[0]).unwrap();
+        assert!(block.compressed_size().is_some());
+        let block = ufs.block_manager.get_block(blocks[1]).unwrap();
+        assert_eq!(None, block.compressed_size());
+        assert_eq!(2048, block.size());
+    }
+
+    #[test]
+    fn unhashed_blocks() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.set_block_hashing(false);
+        let root_id = ufs.get_root_directory_id();
+
+        let (h, file) = ufs.create_file(root_id, "unhashed").unwrap();
+        let data = vec![0x42u8; 2048 * 4];
+        assert_eq!(data.len(), ufs.write_file(h, &data, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let blocks = ufs
+            .block_manager
+            .metadata()
+            .get_file_metadata(file.file_id)
+            .unwrap()
+            .get_latest()
+            .blocks()
+            .clone();
+        for bn in &blocks {
+            assert_eq!(None, ufs.block_manager.get_block(*bn).unwrap().hash());
+        }
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // Without block hashes there's nothing to build a content hash from.
+        assert!(ufs.content_hash(file.file_id).is_err());
+
+        // Turning hashing back on only affects subsequent writes.
+        ufs.set_block_hashing(true);
+        let (h, file) = ufs.create_file(root_id, "hashed").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+        assert!(ufs.content_hash(file.file_id).is_ok());
+    }
+
+    #[test]
+    fn rename_open_directory() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        let b = ufs.create_directory(root_id, "b").unwrap();
+        ufs.create_directory(a.id(), "sub").unwrap();
+
+        let root_h = ufs.open_directory(root_id).unwrap();
+        let a_h = ufs.open_directory(a.id()).unwrap();
+        let b_h = ufs.open_directory(b.id()).unwrap();
+
+        ufs.rename_entry(root_id, "a", b.id(), "c").unwrap();
+
+        let root = ufs.list_files(root_h).unwrap();
+        assert!(!root.contains_key("a"));
+        assert!(root.contains_key("b"));
+        assert!(ufs.list_files(b_h).unwrap().contains_key("c"));
+
+        // The handle to the moved directory still works, and knows where it lives now.
+        assert!(ufs.list_files(a_h).unwrap().contains_key("sub"));
+        assert_eq!(Some(b.id()), ufs.open_dirs.get(&a_h).unwrap().parent_id());
+        assert_eq!(
+            Some(a.id()),
+            ufs.block_manager.metadata().id_from_path("/b/c")
+        );
+
+        // Moving a directory inside itself is not allowed.
+        assert!(ufs.rename_entry(b.id(), "c", a.id(), "d").is_err());
+        let sub = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/b/c/sub")
+            .unwrap();
+        assert!(ufs.rename_entry(root_id, "b", sub, "b").is_err());
+    }
+
+    #[test]
+    fn rename_entry() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        let b = ufs.create_directory(root_id, "b").unwrap();
+        ufs.create_directory(b.id(), "sub").unwrap();
+
+        let (h, foo) = ufs.create_file(root_id, "foo").unwrap();
+        ufs.write_file(h, &[0x42u8; 3000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let (h, bar) = ufs.create_file(a.id(), "bar").unwrap();
+        ufs.write_file(h, &[0x24u8; 3000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        // Rename in place.
+        ufs.rename_entry(root_id, "foo", root_id, "baz").unwrap();
+        let metadata = ufs.block_manager.metadata();
+        assert_eq!(Some(foo.file_id), metadata.id_from_path("/baz"));
+        assert_eq!(None, metadata.id_from_path("/foo"));
+
+        // Moving onto an existing file replaces it, and recycles its blocks.
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.rename_entry(root_id, "baz", a.id(), "bar").unwrap();
+        assert_eq!(free_blocks + 2, ufs.block_manager.free_block_count());
+        let metadata = ufs.block_manager.metadata();
+        assert_eq!(Some(foo.file_id), metadata.id_from_path("/a/bar"));
+        assert!(metadata.lookup_file(bar.file_id).is_none());
+        assert_eq!(a.id(), metadata.lookup_file(foo.file_id).unwrap().dir_id());
+
+        // A replaced file that's open stays readable until it's closed.
+        let (h, qux) = ufs.create_file(root_id, "qux").unwrap();
+        ufs.write_file(h, &[0x17u8; 3000], 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let h = ufs.open_file(qux.file_id, OpenFileMode::Read).unwrap();
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.rename_entry(a.id(), "bar", root_id, "qux").unwrap();
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        assert_eq!(vec![0x17u8; 3000], ufs.read_file(h, 0, 3000).unwrap());
+        ufs.close_file(h).unwrap();
+        assert_eq!(free_blocks + 2, ufs.block_manager.free_block_count());
+        ufs.rename_entry(root_id, "qux", a.id(), "bar").unwrap();
+
+        // A file can't replace a directory, and a directory can only replace an empty one.
+        let err = ufs.rename_entry(a.id(), "bar", root_id, "b").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::IsADirectory),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+        let err = ufs.rename_entry(root_id, "a", root_id, "b").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::DirectoryNotEmpty),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+        ufs.rename_entry(b.id(), "sub", root_id, "a").unwrap_err();
+        ufs.rename_entry(root_id, "b", a.id(), "sub").unwrap();
+
+        let metadata = ufs.block_manager.metadata();
+        assert_eq!(Some(b.id()), metadata.id_from_path("/a/sub"));
+        assert!(metadata.id_from_path("/a/sub/sub").is_some());
+        assert_eq!(None, metadata.id_from_path("/b"));
+    }
+
+    #[test]
+    fn symlink() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        let (h, bar) = ufs.create_file(a.id(), "bar").unwrap();
+        ufs.write_file(h, b"Hello, link!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let link = ufs.create_symlink(root_id, "link", "a/bar").unwrap();
+        assert_eq!(Path::new("a/bar"), link.target());
+        assert!(ufs.create_symlink(root_id, "link", "a").is_err());
+
+        // Reading through the link gets the target's contents.
+        let id = ufs.block_manager.metadata().resolve_path("/link").unwrap();
+        assert_eq!(bar.file_id, id);
+        assert_eq!(b"Hello, link!", &ufs.read_head(id, 512).unwrap()[..]);
+        let h = ufs.open_path("/link", OpenFileMode::Read).unwrap();
+        assert_eq!(b"Hello, link!".to_vec(), ufs.read_file(h, 0, 12).unwrap());
+        ufs.close_file(h).unwrap();
+
+        // Files may be created through a link to a directory.
+        ufs.create_symlink(root_id, "dir", "/a").unwrap();
+        let h = ufs.create_path("/dir/baz").unwrap();
+        ufs.close_file(h).unwrap();
+        let metadata = ufs.block_manager.metadata();
+        assert!(metadata.id_from_path("/a/baz").is_some());
+
+        // Removing the link leaves the target alone.
+        let free_blocks = ufs.block_manager.free_block_count();
+        ufs.remove_file(root_id, "link").unwrap();
+        assert_eq!(free_blocks, ufs.block_manager.free_block_count());
+        assert_eq!(None, ufs.block_manager.metadata().id_from_path("/link"));
+        assert_eq!(
+            Some(bar.file_id),
+            ufs.block_manager.metadata().id_from_path("/a/bar")
+        );
+    }
+
+    #[test]
+    fn remove_directory() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        ufs.create_directory(a.id(), "b").unwrap();
+        let c = ufs.create_directory(root_id, "c").unwrap();
+        let wasm_id = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/c/.wasm")
+            .unwrap();
+        ufs.create_file(wasm_id, "test.wasm").unwrap();
+
+        let err = ufs.remove_directory(root_id, "a").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::DirectoryNotEmpty),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // A program in ".wasm" counts as content, and ".wasm" itself is off limits.
+        let err = ufs.remove_directory(root_id, "c").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::DirectoryNotEmpty),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+        let err = ufs.remove_directory(c.id(), ".wasm").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::SpecialDirectory),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+        let err = ufs.remove_directory(a.id(), ".vers").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::SpecialDirectory),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+
+        ufs.remove_directory(a.id(), "b").unwrap();
+        ufs.remove_directory(root_id, "a").unwrap();
+        assert_eq!(None, ufs.block_manager.metadata().id_from_path("/a"));
+        let err = ufs.remove_directory(root_id, "a").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn max_path_depth() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.set_max_path_depth(8);
+
+        let mut dir_id = ufs.block_manager.metadata().root_directory().id();
+        let mut path = PathBuf::from("/");
+        for depth in 1..=8 {
+            let name = format!("d{}", depth);
+            dir_id = ufs.create_directory(dir_id, &name).unwrap().id();
+            path.push(name);
+        }
+
+        let e = ufs.create_directory(dir_id, "too_deep").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::PathTooDeep),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Everything at the limit is still reachable.
+        let (h, file) = ufs.create_file(dir_id, "file").unwrap();
+        ufs.close_file(h).unwrap();
+        let metadata = ufs.block_manager.metadata();
+        assert_eq!(path, metadata.path_from_dir_id(dir_id));
+        assert_eq!(path.join("file"), metadata.path_from_file_id(file.file_id));
+        assert_eq!(Some(dir_id), metadata.id_from_path(&path));
+        assert_eq!(Some(file.file_id), metadata.id_from_path(path.join("file")));
+        let wasm_dir = metadata.id_from_path(path.join(".wasm")).unwrap();
+        assert_eq!(path.join(".wasm"), metadata.path_from_dir_id(wasm_dir));
+
+        // Moving a subtree such that it ends up too deep isn't allowed either.
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        ufs.create_directory(a.id(), "b").unwrap();
+        assert!(ufs.rename_entry(root_id, "a", dir_id, "a").is_err());
+    }
+
+    #[test]
+    fn create_under_a_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (_, file) = ufs.create_file(root_id, "not_a_dir").unwrap();
+
+        let e = ufs.create_file(file.file_id, "foo").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotADirectory),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        let e = ufs.create_directory(file.file_id, "foo").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotADirectory),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        let e = ufs.create_file(root_id.new("missing"), "foo").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn open_a_directory_as_file() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let dir = ufs.create_directory(root_id, "not_a_file").unwrap();
+
+        for mode in vec![
+            OpenFileMode::Read,
+            OpenFileMode::Write,
+            OpenFileMode::ReadWrite,
+            OpenFileMode::Append,
+        ] {
+            let e = ufs.open_file(dir.id(), mode).unwrap_err();
+            assert_eq!(
+                Some(&IOFSErrorKind::NotAFile),
+                e.downcast_ref::<IOFSErrorKind>()
+            );
+        }
+
+        let e = ufs
+            .open_file(root_id.new("missing"), OpenFileMode::Read)
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn content_defined_chunking() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 1000);
+        ufs.set_chunking_mode(ChunkingMode::ContentDefined);
+
+        // Return the plaintext of each of the blocks in a file.
+        fn block_contents(ufs: &UberFileSystem<MemoryStore>, handle: FileHandle) -> Vec<Vec<u8>> {
+            let (file, _) = ufs.open_files.get(&handle).unwrap();
+            let mut offset = 0;
+            file.version
+                .blocks()
+                .iter()
+                .map(|b| {
+                    let block = ufs.block_manager.get_block(*b).unwrap();
+                    let bytes = ufs
+                        .block_manager
+                        .read(file.version.nonce(), offset, block)
+                        .unwrap();
+                    offset += bytes.len() as u64;
+                    bytes
+                })
+                .collect()
+        }
+
+        let test = include_str!("fuse.rs").as_bytes();
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let (h, file) = ufs.create_file(root_id, "fuse.rs").unwrap();
+        assert_eq!(test.len(), ufs.write_file(h, test, 0).unwrap());
+        let before = block_contents(&ufs, h);
+        ufs.close_file(h).unwrap();
+
+        // Insert a few bytes at the start of the file, and write a new version.
+        let mut edited = b"// A new first line\n".to_vec();
+        edited.extend_from_slice(test);
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        assert_eq!(edited.len(), ufs.write_file(h, &edited, 0).unwrap());
+        let after = block_contents(&ufs, h);
+        assert_eq!(edited, after.concat());
+
+        let same = after.iter().filter(|b| before.contains(b)).count();
+        assert!(
+            same * 10 >= after.len() * 8,
+            "only {} of {} blocks would dedup",
+            same,
+            after.len()
+        );
+    }
+
+    #[test]
+    fn wasm_program_config() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let config = r#"{"watch":"/foo/bar"}"#;
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let wasm_dir = ufs.open_sub_directory(root_id, ".wasm").unwrap();
+        let (h, _) = ufs.create_file(wasm_dir, "test.config").unwrap();
+        assert_eq!(
+            config.len(),
+            ufs.write_file(h, config.as_bytes(), 0).unwrap()
+        );
+        ufs.close_file(h).unwrap();
+
+        assert_eq!(
+            Some(config.to_string()),
+            ufs.read_wasm_program_config("/.wasm/test.wasm")
+        );
+        assert_eq!(None, ufs.read_wasm_program_config("/.wasm/other.wasm"));
+    }
+
+    #[test]
+    fn rescan_programs() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let program = b"\0asm\x01\0\0\0";
+
+        // Without a runtime, writing the program doesn't start it.
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let wasm_dir = ufs.open_sub_directory(root_id, ".wasm").unwrap();
+        let (h, _) = ufs.create_file(wasm_dir, "test.wasm").unwrap();
+        assert_eq!(program.len(), ufs.write_file(h, program, 0).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        ufs.program_mgr = Some(sender);
+
+        let started = |receiver: &crossbeam_channel::Receiver<RuntimeManagerMsg>| {
+            receiver
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    RuntimeManagerMsg::Start(p) => Some(p.name().to_path_buf()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        ufs.rescan_programs();
+        assert_eq!(vec![PathBuf::from("/.wasm/test.wasm")], started(&receiver));
+
+        // Nothing new the second time around.
+        ufs.rescan_programs();
+        assert!(started(&receiver).is_empty());
+
+        // Remove the program behind the runtime's back, and it should be stopped.
+        let sender = ufs.program_mgr.take();
+        ufs.remove_file(wasm_dir, "test.wasm").unwrap();
+        ufs.program_mgr = sender;
+
+        ufs.rescan_programs();
+        let stopped = receiver
+            .try_iter()
+            .filter_map(|msg| match msg {
+                RuntimeManagerMsg::Stop(path) => Some(path),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![PathBuf::from("/.wasm/test.wasm")], stopped);
+    }
+
+    #[test]
+    fn read_only() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let (h, file) = ufs.create_file(root_id, "foo").unwrap();
+        ufs.write_file(h, b"bar", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        ufs.sync().unwrap();
+
+        ufs.set_read_only(true);
+        let root_block = ufs.block_manager.root_block();
+
+        let read_only = |result: Result<(), failure::Error>| {
+            assert_eq!(
+                Some(&IOFSErrorKind::ReadOnly),
+                result.err().unwrap().downcast_ref::<IOFSErrorKind>()
+            )
+        };
+        read_only(ufs.create_file(root_id, "baz").map(|_| ()));
+        read_only(ufs.create_directory(root_id, "baz").map(|_| ()));
+        read_only(ufs.copy_file(file.file_id, root_id, "baz").map(|_| ()));
+        read_only(ufs.open_file(file.file_id, OpenFileMode::Write).map(|_| ()));
+        read_only(ufs.set_file_size(file.file_id, 0));
+        read_only(ufs.set_permissions(file.file_id, 0o600));
+        read_only(ufs.set_xattr(file.file_id, "user.foo", b"bar"));
+        read_only(ufs.rename_entry(root_id, "foo", root_id, "baz"));
+        read_only(ufs.remove_file(root_id, "foo"));
+
+        // Reading doesn't touch the access time.
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(b"bar".to_vec(), ufs.read_file(h, 0, 3).unwrap());
+        ufs.close_file(h).unwrap();
+        assert!(!ufs.block_manager.metadata().is_dirty());
+
+        // Metadata that's changed anyway, as it is when converted from an older format, isn't
+        // stored.
+        ufs.block_manager
+            .metadata_mut()
+            .touch_file_access(file.file_id);
+        ufs.sync().unwrap();
+        assert_eq!(root_block, ufs.block_manager.root_block());
+    }
+
+    #[test]
+    fn read_only_programs() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let program = b"\0asm\x01\0\0\0";
+
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let wasm_dir = ufs.open_sub_directory(root_id, ".wasm").unwrap();
+        for name in &["reader.wasm", "writer.wasm", "asker.wasm", "denied.wasm"] {
+            let (h, _) = ufs.create_file(wasm_dir, name).unwrap();
+            assert_eq!(program.len(), ufs.write_file(h, program, 0).unwrap());
+            ufs.close_file(h).unwrap();
+        }
+
+        // The writer has been allowed to write, and the asker could be, once the user is asked.
+        let writer = PathBuf::from("/.wasm/writer.wasm");
+        let asker = PathBuf::from("/.wasm/asker.wasm");
+        let denied = PathBuf::from("/.wasm/denied.wasm");
+        let metadata = ufs.block_manager.metadata_mut();
+        metadata.add_wasm_program_grants(writer.clone());
+        metadata.set_wasm_program_grant(&writer, GrantType::WriteFileInvocation, Grant::Allow);
+        metadata.add_wasm_program_grants(asker);
+        metadata.add_wasm_program_grants(denied.clone());
+        for grant in &[
+            GrantType::WriteFileInvocation,
+            GrantType::CreateFileInvocation,
+            GrantType::CreateDirectoryInvocation,
+            GrantType::ChmodInvocation,
+            GrantType::KeyValueInvocation,
+        ] {
+            metadata.set_wasm_program_grant(&denied, *grant, Grant::Deny);
+        }
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        ufs.program_mgr = Some(sender);
+        ufs.set_read_only(true);
+        ufs.rescan_programs();
+
+        let mut started = receiver
+            .try_iter()
+            .filter_map(|msg| match msg {
+                RuntimeManagerMsg::Start(p) => Some(p.name().to_path_buf()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        started.sort();
+        assert_eq!(
+            vec![
+                PathBuf::from("/.wasm/denied.wasm"),
+                PathBuf::from("/.wasm/reader.wasm")
+            ],
+            started
+        );
+    }
 
-            if buffer.len() == size as usize {
-                if let Some(program_mgr) = &self.program_mgr {
-                    program_mgr
-                        .send(RuntimeManagerMsg::IofsMessage(IofsMessage::FileMessage(
-                            IofsFileMessage::Read(IofsMessagePayload {
-                                target_path: self
-                                    .block_manager
-                                    .metadata()
-                                    .path_from_file_id(file.file_id),
-                                target_id: file.file_id,
-                                parent_id: self
-                                    .block_manager
-                                    .metadata()
-                                    .get_file_metadata(file.file_id)
-                                    .expect("should not fail in write_file")
-                                    .dir_id(),
-                            }),
-                        )))
-                        .expect("Wasm Runtime went away");
-                }
+    #[test]
+    fn event_queue() {
+        init();
 
-                // self.notify_listeners(UfsMessage::FileRead(
-                //     self.block_manager
-                //         .metadata()
-                //         .path_from_file_id(file.file_id),
-                //     buffer.clone(),
-                // ));
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
 
-                debug!("read_file success");
+        // Nothing reads the events, as though the runtime were swamped by programs. Writes carry on
+        // regardless, without waiting.
+        ufs.set_event_queue(4, EventPriority::Foreground);
+        let (events, receiver) = ufs.new_event_queue();
+        ufs.event_sender = Some(events);
 
-                Ok(buffer)
-            } else {
-                Err(format_err!("Error reading file {}", handle))
-            }
-        } else {
-            Err(format_err!("File not open {}", handle))
+        let (h, _) = ufs.create_file(root_id, "foreground").unwrap();
+        for i in 0..20 {
+            assert_eq!(1, ufs.write_file(h, b"x", i).unwrap());
         }
-    }
+        ufs.close_file(h).unwrap();
 
-    pub(crate) fn set_permissions(&mut self, id: UfsUuid, perms: u16) {
-        self.block_manager
-            .metadata_mut()
-            .set_unix_permissions(id, perms);
-    }
+        // Create, 20 writes, and close
+        assert_eq!(4, receiver.len());
+        assert_eq!(22 - 4, ufs.dropped_event_count());
 
-    //
-    //
-    // Functions specifically for Rust-side WASM related use.
-    //
-    //
+        // Every event is kept when they come first.
+        ufs.set_event_queue(4, EventPriority::Events);
+        let (events, receiver) = ufs.new_event_queue();
+        ufs.event_sender = Some(events);
 
-    /// Return file size
-    ///
-    /// Used in the WASM file read implementation in order to know how many bytes to read.
-    ///
-    pub(crate) fn get_file_size(&self, handle: FileHandle) -> Result<FileSize, failure::Error> {
-        if let Some(file) = self.open_files.get(&handle) {
-            Ok(file.version.size())
-        } else {
-            Err(format_err!("File not open {}", handle))
+        let (h, _) = ufs.create_file(root_id, "events").unwrap();
+        for i in 0..20 {
+            assert_eq!(1, ufs.write_file(h, b"x", i).unwrap());
         }
+        ufs.close_file(h).unwrap();
+
+        assert_eq!(22, receiver.len());
+        assert_eq!(22 - 4, ufs.dropped_event_count());
+
+        // Nor do they wait, or fail, once the runtime has gone away.
+        drop(receiver);
+        let (h, _) = ufs.create_file(root_id, "orphaned").unwrap();
+        ufs.close_file(h).unwrap();
+        assert_eq!(22 - 4 + 2, ufs.dropped_event_count());
     }
 
-    /// Open a sub-directory
-    ///
-    pub(crate) fn open_sub_directory(
-        &mut self,
-        pid: UfsUuid,
-        name: &str,
-    ) -> Result<UfsUuid, failure::Error> {
-        match self
-            .block_manager
-            .metadata()
-            .get_dir_metadata_from_dir_and_name(pid, name)
+    #[test]
+    fn format_file_backed() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/format_file_backed");
+        let _ = std::fs::remove_dir_all(path);
+
         {
-            Ok(dir_meta) => Ok(dir_meta.id()),
-            Err(e) => Err(e),
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
+
+            assert_eq!(vec!["admin".to_string()], ufs.get_users());
+            assert!(ufs
+                .login("admin".to_string(), "foobar".to_string())
+                .is_some());
+            assert!(ufs
+                .login("admin".to_string(), "wrong".to_string())
+                .is_none());
+
+            let root_id = ufs.get_root_directory_id();
+            assert!(ufs.open_directory(root_id).is_ok());
         }
+
+        // And it's a real file system that may be loaded.
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert!(ufs
+            .login("admin".to_string(), "foobar".to_string())
+            .is_some());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn grow() {
+        init();
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+        let path = Path::new("/tmp/ufs_test/grow");
+        let _ = std::fs::remove_dir_all(path);
+
+        let (file_id, data) = {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
+            let root_id = ufs.get_root_directory_id();
+
+            // Fill it up.
+            let (h, file) = ufs.create_file(root_id, "big").unwrap();
+            let mut data = vec![0x42; ufs.block_manager.available_block_count() as usize * 512];
+            ufs.write_file(h, &data, 0).unwrap();
+            assert_eq!(0, ufs.block_manager.available_block_count());
+            let e = ufs
+                .write_file(h, &[0x43; 512], data.len() as u64)
+                .unwrap_err();
+            assert_eq!(
+                Some(&IOFSErrorKind::NoSpace),
+                e.downcast_ref::<IOFSErrorKind>()
+            );
+
+            ufs.grow(32).unwrap();
+            assert_eq!(64, ufs.block_manager.block_count());
+            let more = vec![0x43; 1024];
+            ufs.write_file(h, &more, data.len() as u64).unwrap();
+            ufs.close_file(h).unwrap();
+            data.extend_from_slice(&more);
+
+            (file.file_id, data)
+        };
+
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert_eq!(64, ufs.block_manager.block_count());
+        let h = ufs.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        ufs.close_file(h).unwrap();
     }
 
     #[test]
-    fn open_file() {
+    fn label() {
         init();
 
-        let mut ufs =
-            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let path = Path::new("/tmp/ufs_test/label");
+        let _ = std::fs::remove_dir_all(path);
+
+        let id = {
+            let mut ufs = UberFileSystem::format_file_backed(
+                path,
+                "master",
+                "admin",
+                "foobar",
+                BlockSize::FiveTwelve,
+                32,
+            )
+            .unwrap();
 
-        let root_id = ufs.block_manager.metadata().root_directory().id();
-        let (h0, file) = ufs.create_file(root_id, "test_open_file").unwrap();
+            assert_eq!(None, ufs.label());
+            ufs.set_label("Vacation Photos");
+            assert_eq!(Some("Vacation Photos".to_string()), ufs.label());
+            ufs.id
+        };
 
-        let h1 = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
-        assert!(
-            h0 != h1,
-            "two open calls to the same file should return different handles"
-        );
+        let ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert_eq!(Some("Vacation Photos".to_string()), ufs.label());
+        assert_eq!(id, ufs.id);
     }
 
     #[test]
     fn read_and_write_file_networked() {
         init();
 
+        let server = MockBlockServer::start();
+        {
+            // Format the remote file system -- dropping the BlockManager writes the metadata.
+            let store =
+                NetworkStore::create("test", server.url(), BlockSize::TwentyFortyEight, 100)
+                    .unwrap();
+            BlockManager::new("", "", store);
+        }
+
         // User and password on test file system are both empty
-        let mut ufs =
-            UberFileSystem::new_networked("", "", "test", "http://localhost:8888").unwrap();
+        let mut ufs = UberFileSystem::new_networked("", "", "test", server.url(), None).unwrap();
         let test = include_str!("wasm.rs").as_bytes();
 
         let root_id = ufs.block_manager.metadata().root_directory().id();
-        let (h, _) = ufs.create_file(root_id, "lib.rs").unwrap();
+        let (h, file) = ufs.create_file(root_id, "wasm.rs").unwrap();
 
         assert_eq!(test.len(), ufs.write_file(h, test, 0).unwrap());
         let bytes = ufs.read_file(h, 0, test.len() as u32).unwrap();
         assert_eq!(test, bytes.as_slice());
+        ufs.close_file(h).unwrap();
+        drop(ufs);
 
-        // If we don't remove the file, the test fails on the next run.
-        ufs.remove_file(root_id, "lib.rs");
+        // Everything should have made it to the server.
+        let mut ufs = UberFileSystem::new_networked("", "", "test", server.url(), None).unwrap();
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        let bytes = ufs.read_file(h, 0, test.len() as u32).unwrap();
+        assert_eq!(test, bytes.as_slice());
     }
 
     #[test]
@@ -1174,6 +6213,34 @@ mod test {
         });
     }
 
+    #[test]
+    fn coalesce_small_writes() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let test = include_str!("fuse.rs").as_bytes();
+
+        let h = ufs.create_path("/fuse.rs").unwrap();
+        let mut offset = 0;
+        for chunk in test.chunks(77) {
+            assert_eq!(chunk.len(), ufs.write_file(h, chunk, offset).unwrap());
+            offset += chunk.len() as u64;
+        }
+        assert_eq!(test.len() as FileSize, ufs.get_file_size(h).unwrap());
+
+        // Everything but the tail is written a whole block at a time.
+        let (file, _) = ufs.open_files.get(&h).unwrap();
+        assert_eq!(test.len() / 2048, file.version.blocks().len());
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_path("/fuse.rs", OpenFileMode::Read).unwrap();
+        let (file, _) = ufs.open_files.get(&h).unwrap();
+        assert_eq!((test.len() + 2047) / 2048, file.version.blocks().len());
+        assert_eq!(test, &ufs.read_file(h, 0, test.len() as u32).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+    }
+
     #[test]
     fn large_chunks() {
         init();
@@ -1208,4 +6275,484 @@ mod test {
             offset += len as u64;
         });
     }
+
+    struct CapturingSink(Arc<Mutex<Vec<IofsMessage>>>);
+
+    impl EventSink for CapturingSink {
+        fn emit(&mut self, msg: &IofsMessage) {
+            self.0.lock().unwrap().push(msg.clone());
+        }
+    }
+
+    #[test]
+    fn read_under_read_lock() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let mut mounter = UfsMounter::new(ufs, None).unwrap();
+
+        let h = mounter.write().unwrap().create_path("/shared").unwrap();
+        // A small write is buffered, so this read falls back to the write lock.
+        mounter.write().unwrap().write_file(h, b"hello", 0).unwrap();
+        assert_eq!(b"hello".to_vec(), mounter.read_file(h, 0, 5).unwrap());
+        assert_eq!(b"ell".to_vec(), mounter.read_file(h, 1, 3).unwrap());
+        mounter.write().unwrap().close_file(h).unwrap();
+
+        let h = mounter
+            .write()
+            .unwrap()
+            .open_path("/shared", OpenFileMode::Write)
+            .unwrap();
+        assert!(mounter.read_file(h, 0, 5).is_err());
+        mounter.write().unwrap().close_file(h).unwrap();
+
+        mounter.shutdown().unwrap();
+    }
+
+    #[test]
+    fn event_sink() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let mut mounter = UfsMounter::new(ufs, None).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        mounter.add_event_sink(Box::new(CapturingSink(events.clone())));
+
+        let file_id = {
+            let mut guard = mounter.write().unwrap();
+            let root_id = guard.block_manager.metadata().root_directory().id();
+            let (h, file) = guard.create_file(root_id, "event_sink").unwrap();
+            guard.write_file(h, b"hello", 0).unwrap();
+            guard.close_file(h).unwrap();
+            file.file_id
+        };
+
+        // Shutting down drains the runtime's channel, so all events have been emitted.
+        mounter.shutdown().unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|e| match e {
+            IofsMessage::FileMessage(IofsFileMessage::Create(p)) => p.target_id == file_id,
+            _ => false,
+        }));
+        assert!(events.iter().any(|e| match e {
+            IofsMessage::FileMessage(IofsFileMessage::Write(p)) => p.target_id == file_id,
+            _ => false,
+        }));
+        assert!(events.iter().any(|e| match e {
+            IofsMessage::FileMessage(IofsFileMessage::Close(p)) => p.target_id == file_id,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn shutdown_writes_storage() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/shutdown_writes_storage");
+        let _ = std::fs::remove_dir_all(path);
+
+        let ufs = UberFileSystem::format_file_backed(
+            path,
+            "master",
+            "admin",
+            "foobar",
+            BlockSize::FiveTwelve,
+            64,
+        )
+        .unwrap();
+        let mut mounter = UfsMounter::new(ufs, None).unwrap();
+
+        let (closed, open) = {
+            let mut guard = mounter.write().unwrap();
+            let root_id = guard.get_root_directory_id();
+            guard.create_directory(root_id, "dir").unwrap();
+
+            let (h, closed) = guard.create_file(root_id, "closed").unwrap();
+            guard.write_file(h, b"closed file", 0).unwrap();
+            guard.close_file(h).unwrap();
+
+            // This one is still open when we shut down.
+            let (h, open) = guard.create_file(root_id, "open").unwrap();
+            guard.write_file(h, b"open file", 0).unwrap();
+
+            (closed.file_id, open.file_id)
+        };
+
+        mounter.shutdown().unwrap();
+
+        // The mounter is still alive, so nothing has been dropped.
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert!(ufs.block_manager.metadata().id_from_path("/dir").is_some());
+
+        let h = ufs.open_file(closed, OpenFileMode::Read).unwrap();
+        assert_eq!(b"closed file".to_vec(), ufs.read_file(h, 0, 11).unwrap());
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(open, OpenFileMode::Read).unwrap();
+        assert_eq!(b"open file".to_vec(), ufs.read_file(h, 0, 9).unwrap());
+        ufs.close_file(h).unwrap();
+
+        drop(mounter);
+    }
+
+    #[test]
+    fn flush_metadata() {
+        init();
+
+        let path = Path::new("/tmp/ufs_test/flush_metadata");
+        let _ = std::fs::remove_dir_all(path);
+
+        let mut ufs = UberFileSystem::format_file_backed(
+            path,
+            "master",
+            "admin",
+            "foobar",
+            BlockSize::FiveTwelve,
+            64,
+        )
+        .unwrap();
+        ufs.set_flush_interval(Some(std::time::Duration::from_millis(10)));
+        let mounter = UfsMounter::new(ufs, None).unwrap();
+
+        let closed = {
+            let mut guard = mounter.write().unwrap();
+            let root_id = guard.get_root_directory_id();
+            guard.create_directory(root_id, "dir").unwrap();
+
+            let (h, closed) = guard.create_file(root_id, "closed").unwrap();
+            guard.write_file(h, b"closed file", 0).unwrap();
+            guard.close_file(h).unwrap();
+
+            closed.file_id
+        };
+
+        // Wait for the timer to write the metadata out.
+        let start = std::time::Instant::now();
+        while mounter.read().unwrap().block_manager.metadata().is_dirty() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // Nothing has been shut down, or dropped, as if the mounter had crashed.
+        let mut ufs = UberFileSystem::load_file_backed("master", "admin", "foobar", path).unwrap();
+        assert!(ufs.block_manager.metadata().id_from_path("/dir").is_some());
+
+        let h = ufs.open_file(closed, OpenFileMode::Read).unwrap();
+        assert_eq!(b"closed file".to_vec(), ufs.read_file(h, 0, 11).unwrap());
+        ufs.close_file(h).unwrap();
+
+        drop(mounter);
+    }
+
+    #[test]
+    fn no_flush_when_read_only() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.set_read_only(true);
+        let mounter = UfsMounter::new(ufs, None).unwrap();
+        assert!(mounter.flush_thread.is_none());
+
+        drop(mounter);
+    }
+
+    #[test]
+    fn warm_hot_paths() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let data = vec![0x42u8; 5000];
+
+        let (h, hot) = ufs.create_file(root_id, "hot").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let (h, cold) = ufs.create_file(root_id, "cold").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        ufs.add_hot_path("/hot");
+        let mut mounter = UfsMounter::new(ufs, None).unwrap();
+
+        {
+            let mut guard = mounter.write().unwrap();
+            let misses = guard.block_manager.cache_misses();
+
+            let h = guard.open_file(hot.file_id, OpenFileMode::Read).unwrap();
+            assert_eq!(data, guard.read_file(h, 0, data.len() as u32).unwrap());
+            guard.close_file(h).unwrap();
+            assert_eq!(misses, guard.block_manager.cache_misses());
+
+            let h = guard.open_file(cold.file_id, OpenFileMode::Read).unwrap();
+            assert_eq!(data, guard.read_file(h, 0, data.len() as u32).unwrap());
+            guard.close_file(h).unwrap();
+            assert!(guard.block_manager.cache_misses() > misses);
+        }
+
+        mounter.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_ahead() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let data = (0..40_960).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let h = ufs.create_path("/big").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        ufs.set_read_ahead(4);
+        let h = ufs.open_path("/big", OpenFileMode::Read).unwrap();
+
+        // The first read misses, and the next four blocks are read ahead.
+        assert_eq!(&data[..2048], ufs.read_file(h, 0, 2048).unwrap().as_slice());
+        let misses = ufs.block_manager.cache_misses();
+        for offset in (2048..10_240).step_by(1024) {
+            let bytes = ufs.read_file(h, offset as u64, 1024).unwrap();
+            assert_eq!(&data[offset..offset + 1024], bytes.as_slice());
+        }
+        assert_eq!(misses, ufs.block_manager.cache_misses());
+
+        // A random read isn't followed up.
+        ufs.read_file(h, 30_720, 1024).unwrap();
+        let misses = ufs.block_manager.cache_misses();
+        ufs.read_file(h, 32_768, 1024).unwrap();
+        assert!(ufs.block_manager.cache_misses() > misses);
+
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn block_cache() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let data = vec![0x42u8; 5000];
+        let h = ufs.create_path("/cached").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        ufs.set_block_cache_size(16);
+        let h = ufs.open_path("/cached", OpenFileMode::Read).unwrap();
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        let (hits, misses) = ufs.block_cache_stats();
+        assert!(misses > 0);
+
+        // The second time around every block comes from the cache.
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        let (more_hits, more_misses) = ufs.block_cache_stats();
+        assert!(more_hits > hits);
+        assert_eq!(misses, more_misses);
+
+        ufs.set_block_cache_size(0);
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        assert_eq!(data, ufs.read_file(h, 0, data.len() as u32).unwrap());
+        let (hits, misses) = ufs.block_cache_stats();
+        assert_eq!(0, hits);
+        assert!(misses > 0);
+
+        ufs.close_file(h).unwrap();
+    }
+
+    #[test]
+    fn check_blocks() {
+        use crate::block::manager::BlockProblem;
+
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let data = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let h = ufs.create_path("/checked").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let report = ufs.block_manager.check(false);
+        assert_eq!(3, report.blocks_scanned);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+        // A block that was written, but never made it into a file
+        let orphan = ufs
+            .block_manager
+            .write(vec![0; 24], 0, b"orphan")
+            .unwrap()
+            .number();
+        let free_blocks = ufs.block_manager.free_block_count();
+        let report = ufs.block_manager.check(false);
+        assert_eq!(vec![BlockProblem::Orphaned(orphan)], report.problems);
+        assert_eq!(0, report.repairs);
+
+        let report = ufs.block_manager.check(true);
+        assert_eq!(1, report.repairs);
+        assert_eq!(free_blocks + 1, ufs.block_manager.free_block_count());
+        assert!(ufs.block_manager.check(false).problems.is_empty());
+
+        // Damage done to a file's block in storage
+        let id = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/checked")
+            .unwrap();
+        let damaged = ufs
+            .block_manager
+            .metadata()
+            .lookup_file(id)
+            .unwrap()
+            .get_latest()
+            .blocks()[1];
+        ufs.block_manager
+            .write_raw_block(damaged, &[0; 2048])
+            .unwrap();
+        let problems = ufs.block_manager.check(false).problems;
+        assert_eq!(1, problems.len());
+        match &problems[0] {
+            BlockProblem::Corrupt(bn, _) => assert_eq!(damaged, *bn),
+            problem => panic!("unexpected problem: {}", problem),
+        }
+    }
+
+    #[test]
+    fn check_unlinked_files() {
+        use crate::block::manager::BlockProblem;
+
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+        let a = ufs.create_directory(root_id, "a").unwrap();
+        let h = ufs.create_path("/a/kept").unwrap();
+        ufs.write_file(h, b"kept", 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let h = ufs.create_path("/lost").unwrap();
+        ufs.write_file(h, b"lost", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        // Lose the links to both files, leaving them in the file table.
+        let kept = ufs
+            .block_manager
+            .metadata()
+            .id_from_path("/a/kept")
+            .unwrap();
+        let lost = ufs.block_manager.metadata().id_from_path("/lost").unwrap();
+        let metadata = ufs.block_manager.metadata_mut();
+        metadata
+            .lookup_dir_mut(a.id())
+            .unwrap()
+            .entries_mut()
+            .remove("kept");
+        metadata
+            .lookup_dir_mut(root_id)
+            .unwrap()
+            .entries_mut()
+            .remove("lost");
+
+        let mut problems = ufs.block_manager.check(false).problems;
+        problems.sort_by_key(|problem| problem.to_string());
+        let mut expected = vec![BlockProblem::Unlinked(kept), BlockProblem::Unlinked(lost)];
+        expected.sort_by_key(|problem| problem.to_string());
+        assert_eq!(expected, problems);
+
+        // The file whose directory is still there goes back in it, and the other is lost+found.
+        let report = ufs.block_manager.check(true);
+        assert_eq!(2, report.repairs);
+        let metadata = ufs.block_manager.metadata();
+        assert_eq!(
+            Path::new("/a").join(kept.to_string()),
+            metadata.path_from_file_id(kept)
+        );
+        assert_eq!(
+            Path::new("/lost+found").join(lost.to_string()),
+            metadata.path_from_file_id(lost)
+        );
+        assert!(ufs.block_manager.check(false).problems.is_empty());
+    }
+
+    #[test]
+    fn volume_root_hash() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+
+        let (h, file) = ufs.create_file(root_id, "attest").unwrap();
+        ufs.write_file(h, b"Hello World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let e = ufs.verify_volume_root().unwrap_err();
+        assert_eq!("no volume root hash has been stored", e.to_string());
+
+        let root = ufs.volume_root_hash(true).unwrap();
+        assert_eq!(root, ufs.volume_root_hash(true).unwrap());
+        assert!(ufs.verify_volume_root().unwrap());
+
+        // Change the file's contents
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        ufs.write_file(h, b"Goodbye World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        assert!(!ufs.verify_volume_root().unwrap());
+        let changed = ufs.volume_root_hash(true).unwrap();
+        assert_ne!(root, changed);
+
+        // Metadata changes count too
+        ufs.create_directory(root_id, "attest_dir").unwrap();
+        assert!(!ufs.verify_volume_root().unwrap());
+        assert_ne!(changed, ufs.volume_root_hash(false).unwrap());
+        assert!(ufs.verify_volume_root().unwrap());
+    }
+
+    #[test]
+    fn open_mode_guards() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.block_manager.metadata().root_directory().id();
+
+        let (h, file) = ufs.create_file(root_id, "guarded").unwrap();
+        ufs.write_file(h, b"Hello World!", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Read).unwrap();
+        let e = ufs.write_file(h, b"Goodbye", 0).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotOpenForWriting),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        let e = ufs.truncate_file(h, 0).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotOpenForWriting),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        assert_eq!(b"Hello World!", &ufs.read_file(h, 0, 12).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+
+        let h = ufs.open_file(file.file_id, OpenFileMode::Write).unwrap();
+        let e = ufs.read_file(h, 0, 12).unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotOpenForReading),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+        ufs.write_file(h, b"Goodbye", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let h = ufs
+            .open_file(file.file_id, OpenFileMode::ReadWrite)
+            .unwrap();
+        assert_eq!(b"Goodbye", &ufs.read_file(h, 0, 7).unwrap()[..]);
+        ufs.close_file(h).unwrap();
+    }
 }
@@ -1,29 +1,85 @@
 //! FUSE Interface for uberFS
 //!
-use std::{collections::HashMap, ffi::OsStr};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+};
+#[cfg(target_os = "macos")]
+use libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+use libc::ENODATA as ENOATTR;
+use libc::{
+    c_int, E2BIG, EACCES, EBUSY, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOSYS,
+    ENOTDIR, ENOTEMPTY, ENOTSUP, EPERM, ERANGE, EROFS, O_ACCMODE, O_APPEND, O_RDONLY, O_RDWR,
+    O_TRUNC, O_WRONLY, R_OK, S_IFBLK, S_IFCHR, S_IFMT, S_IFREG, W_OK, XATTR_CREATE, XATTR_REPLACE,
+    X_OK,
 };
-use libc::{c_int, ENOENT, O_RDONLY, O_RDWR, O_WRONLY};
 use log::{debug, error, trace, warn};
 use time::Timespec;
 
 use crate::{
-    block::BlockStorage, metadata::DirectoryEntry, uuid::UfsUuid, OpenFileMode, UfsMounter,
+    block::BlockStorage,
+    fsimpl::{read_file_shared, read_ufs, write_ufs},
+    metadata::{DirectoryEntry, PermissionGroups},
+    time::{Timestamps, UfsTime},
+    uuid::UfsUuid,
+    IOFSErrorKind, OpenFileMode, UfsMounter,
 };
 
+/// A synthetic extended attribute, containing the file's content hash
+///
+const CONTENT_HASH_XATTR: &str = "user.ufs.content_hash";
+
+/// The owner reported for every inode
+///
+/// This is whoever mounted the file system, so that their access is checked against the owner's
+/// permission bits.
+fn owner() -> (u32, u32) {
+    unsafe { (libc::getuid(), libc::getgid()) }
+}
+
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 const TIME: Timespec = Timespec {
     sec: 10634562,
     nsec: 0,
 };
 
+/// Map a file system error onto an errno
+///
+fn errno(e: &failure::Error) -> c_int {
+    match e.downcast_ref::<IOFSErrorKind>() {
+        Some(IOFSErrorKind::NotFound) => ENOENT,
+        Some(IOFSErrorKind::NotADirectory) => ENOTDIR,
+        Some(IOFSErrorKind::IsADirectory) => EISDIR,
+        Some(IOFSErrorKind::DirectoryNotEmpty) => ENOTEMPTY,
+        Some(IOFSErrorKind::SpecialDirectory) => EPERM,
+        Some(IOFSErrorKind::DirectoryExists) => EEXIST,
+        Some(IOFSErrorKind::FileExists) => EEXIST,
+        Some(IOFSErrorKind::NoSpace) => ENOSPC,
+        Some(IOFSErrorKind::QuotaExceeded) => EDQUOT,
+        Some(IOFSErrorKind::LatestVersion) => EPERM,
+        Some(IOFSErrorKind::FileOpen) => EBUSY,
+        Some(IOFSErrorKind::NoAttribute) => ENOATTR,
+        Some(IOFSErrorKind::AttributesTooLarge) => E2BIG,
+        Some(IOFSErrorKind::Overwrite) => ENOTSUP,
+        Some(IOFSErrorKind::ReadOnly) => EROFS,
+        _ => EIO,
+    }
+}
+
 #[derive(Clone, Debug)]
 enum Inode {
     Dir(DirInode),
     File(FileInode),
+    Symlink(SymlinkInode),
 }
 
 impl Inode {
@@ -31,6 +87,7 @@ impl Inode {
         match self {
             Inode::Dir(i) => i.id,
             Inode::File(i) => i.id,
+            Inode::Symlink(i) => i.id,
         }
     }
 
@@ -38,6 +95,7 @@ impl Inode {
         match self {
             Inode::Dir(i) => i.file_attr(),
             Inode::File(i) => i.file_attr(),
+            Inode::Symlink(i) => i.file_attr(),
         }
     }
 
@@ -45,6 +103,17 @@ impl Inode {
         match self {
             Inode::Dir(i) => i.set_perm(perm),
             Inode::File(i) => i.set_perm(perm),
+            // The permissions of a link are never used.
+            Inode::Symlink(_) => (),
+        }
+    }
+
+    fn set_times(&mut self, times: Timestamps) {
+        match self {
+            Inode::Dir(i) => i.times = times,
+            Inode::File(i) => i.times = times,
+            // Links don't keep time.
+            Inode::Symlink(_) => (),
         }
     }
 }
@@ -53,26 +122,27 @@ impl Inode {
 struct DirInode {
     number: u64,
     id: UfsUuid,
-    time: Timespec,
+    times: Timestamps,
     files: HashMap<String, u64>,
     perm: u16,
 }
 
 impl DirInode {
     fn file_attr(&self) -> FileAttr {
+        let (uid, gid) = owner();
         FileAttr {
             ino: self.number,
             size: 0,
             blocks: 0,
-            atime: self.time,
-            mtime: self.time,
-            ctime: self.time,
-            crtime: self.time,
+            atime: self.times.access.into(),
+            mtime: self.times.write.into(),
+            ctime: self.times.change.into(),
+            crtime: self.times.birth.into(),
             kind: FileType::Directory,
             perm: self.perm,
             nlink: 2,
-            uid: 501,
-            gid: 20,
+            uid,
+            gid,
             rdev: 0,
             flags: 0,
         }
@@ -87,26 +157,28 @@ impl DirInode {
 struct FileInode {
     number: u64,
     id: UfsUuid,
-    time: Timespec,
+    times: Timestamps,
     size: u64,
     perm: u16,
+    nlink: u32,
 }
 
 impl FileInode {
     fn file_attr(&self) -> FileAttr {
+        let (uid, gid) = owner();
         FileAttr {
             ino: self.number,
             size: self.size,
             blocks: 1,
-            atime: self.time,
-            mtime: self.time,
-            ctime: self.time,
-            crtime: self.time,
+            atime: self.times.access.into(),
+            mtime: self.times.write.into(),
+            ctime: self.times.change.into(),
+            crtime: self.times.birth.into(),
             kind: FileType::RegularFile,
             perm: self.perm,
-            nlink: 1,
-            uid: 501,
-            gid: 20,
+            nlink: self.nlink,
+            uid,
+            gid,
             rdev: 0,
             flags: 0,
         }
@@ -117,16 +189,47 @@ impl FileInode {
     }
 }
 
+#[derive(Clone, Debug)]
+struct SymlinkInode {
+    number: u64,
+    id: UfsUuid,
+    target: PathBuf,
+}
+
+impl SymlinkInode {
+    fn file_attr(&self) -> FileAttr {
+        let (uid, gid) = owner();
+        FileAttr {
+            ino: self.number,
+            size: self.target.as_os_str().len() as u64,
+            blocks: 0,
+            atime: TIME,
+            mtime: TIME,
+            ctime: TIME,
+            crtime: TIME,
+            kind: FileType::Symlink,
+            perm: 0o777,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
 /// FUSE integration
 ///
-pub struct UberFSFuse<B: BlockStorage + 'static> {
+pub struct UberFSFuse<B: BlockStorage + Sync + 'static> {
     file_system: UfsMounter<B>,
     // `inodes` is a mapping from "inode" number to an Inode
     inodes: HashMap<u64, Inode>,
     inode_number: u64,
+    // Taken from the file system when mounted, `read_only` turns away anything that would change it
+    read_only: bool,
 }
 
-impl<B: BlockStorage> UberFSFuse<B> {
+impl<B: BlockStorage + Sync> UberFSFuse<B> {
     /// Create a new file system
     ///
     pub fn new(file_system: UfsMounter<B>) -> Self {
@@ -134,18 +237,23 @@ impl<B: BlockStorage> UberFSFuse<B> {
             file_system,
             inodes: HashMap::new(),
             inode_number: 2,
+            read_only: false,
         };
 
         {
-            let guard = fs.file_system.lock().expect("poisoned ufs lock");
+            let guard = read_ufs(&fs.file_system);
+            fs.read_only = guard.read_only();
             let root_id = guard.get_root_directory_id();
+            let root_times = guard
+                .get_times(root_id)
+                .expect("root directory has no times");
             // The first inode is always the root of the file system.  The zeroith is well, a hack.
             fs.inodes.insert(
                 0,
                 Inode::Dir(DirInode {
                     number: 0,
                     id: UfsUuid::new_root_fs("hack"),
-                    time: TIME,
+                    times: Timestamps::new(UfsTime::try_from(TIME).expect("TIME is in range")),
                     files: HashMap::new(),
                     perm: 0o755,
                 }),
@@ -155,7 +263,7 @@ impl<B: BlockStorage> UberFSFuse<B> {
                 Inode::Dir(DirInode {
                     number: 1,
                     id: root_id,
-                    time: TIME,
+                    times: root_times,
                     files: HashMap::new(),
                     perm: 0o755,
                 }),
@@ -165,9 +273,122 @@ impl<B: BlockStorage> UberFSFuse<B> {
         fs
     }
 
+    /// Return the value of a synthetic extended attribute
+    ///
+    /// These are computed when asked for, rather than stored. Currently the only one is the
+    /// content hash of a file, as a hex string.
+    fn synthetic_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        match self.inodes.get(&ino) {
+            Some(Inode::File(inode)) if name == OsStr::new(CONTENT_HASH_XATTR) => {
+                let guard = read_ufs(&self.file_system);
+                match guard.content_hash(inode.id) {
+                    Ok(hash) => Ok(format!("{:?}", hash).into_bytes()),
+                    Err(e) => {
+                        error!("unable to compute content hash: {}", e);
+                        Err(EIO)
+                    }
+                }
+            }
+            Some(_) => Err(ENOATTR),
+            None => Err(ENOENT),
+        }
+    }
+
+    /// Return the value of an extended attribute, synthetic or stored
+    ///
+    fn xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        match self.synthetic_xattr(ino, name) {
+            Err(ENOATTR) => {
+                let id = self.inodes[&ino].id();
+                let name = name.to_str().ok_or(ENOATTR)?;
+                let guard = read_ufs(&self.file_system);
+                guard.get_xattr(id, name).map_err(|e| errno(&e))
+            }
+            result => result,
+        }
+    }
+
+    /// Check that `uid` and `gid` may access `ino`, as for `access(2)`
+    ///
+    fn check_access(&self, ino: u64, uid: u32, gid: u32, mask: u32) -> Result<(), c_int> {
+        if self.read_only && mask & W_OK as u32 != 0 {
+            return Err(EROFS);
+        }
+
+        let (id, dir) = match self.inodes.get(&ino) {
+            Some(Inode::Dir(inode)) => (inode.id, true),
+            Some(Inode::File(inode)) => (inode.id, false),
+            // The permissions of a link are never used.
+            Some(Inode::Symlink(_)) => return Ok(()),
+            None => {
+                warn!("`access` can't find requested inode {}", ino);
+                return Err(ENOENT);
+            }
+        };
+
+        let perms = {
+            let guard = read_ufs(&self.file_system);
+            guard.get_permissions(id)
+        };
+        match perms {
+            Some(perms) if allowed(perms, dir, uid, gid, mask) => Ok(()),
+            Some(_) => Err(EACCES),
+            None => Err(ENOENT),
+        }
+    }
+
     // fn file_system(&self) ->
 }
 
+/// Return true if `perms` allow `uid` and `gid` the access in `mask`
+///
+/// `mask` is as for `access(2)`. As usual, root may read and write anything, and execute anything
+/// that's executable by someone, or any directory.
+fn allowed(perms: u16, dir: bool, uid: u32, gid: u32, mask: u32) -> bool {
+    let mask = (mask & (R_OK | W_OK | X_OK) as u32) as u16;
+    if uid == 0 {
+        return dir || mask & X_OK as u16 == 0 || perms & 0o111 != 0;
+    }
+
+    let (owner_uid, owner_gid) = owner();
+    PermissionGroups::from(perms).allows(uid == owner_uid, gid == owner_gid, mask)
+}
+
+/// Return true if opening a file with `flags` may change it
+///
+fn writes(flags: u32) -> bool {
+    let flags = flags as i32;
+    flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0
+}
+
+/// Check that `mknod` is asked for a regular file
+///
+/// Device nodes need privileges that aren't honored here, so they aren't permitted. FIFOs and
+/// sockets can't be stored. Either way, a clean error lets `tar`, or `rsync`, carry on with the
+/// rest of the archive.
+fn mknod_regular(mode: u32) -> Result<(), c_int> {
+    match mode & S_IFMT as u32 {
+        // A file type of zero is taken to mean a regular file, as it is by mknod(2).
+        0 => Ok(()),
+        kind if kind == S_IFREG as u32 => Ok(()),
+        kind if kind == S_IFCHR as u32 || kind == S_IFBLK as u32 => Err(EPERM),
+        _ => Err(ENOSYS),
+    }
+}
+
+/// Reply with an extended attribute value, or list of names
+///
+/// A `size` of zero is a request for the size of the value, rather than the value itself.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if (size as usize) < value.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
 /// Talking nice with the kernel...
 ///
 /// When mounted, the following methods are invoked (in order).  Note that with the exception  of
@@ -183,7 +404,7 @@ impl<B: BlockStorage> UberFSFuse<B> {
 ///  * `flush`
 ///  * `release`
 ///
-impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
+impl<B: BlockStorage + Sync> Filesystem for UberFSFuse<B> {
     /// Start-up
     ///
     fn init(&mut self, _req: &Request) -> Result<(), c_int> {
@@ -198,9 +419,19 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
 
     /// Return inode attributes
     ///
+    ///
+    /// The time stamps are refreshed from the file system, as reads and writes change them. So are
+    /// the permissions, which WASM programs may change.
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.inodes.get(&ino) {
+        match self.inodes.get_mut(&ino) {
             Some(inode) => {
+                let guard = read_ufs(&self.file_system);
+                if let Some(times) = guard.get_times(inode.id()) {
+                    inode.set_times(times);
+                }
+                if let Some(perm) = guard.get_permissions(inode.id()) {
+                    inode.set_perm(perm);
+                }
                 trace!("getattr {:#?}", inode);
                 reply.attr(&TTL, &inode.file_attr())
             }
@@ -241,10 +472,10 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         mode: Option<u32>,
         _uid: Option<u32>,
         _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
-        _fh: Option<u64>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
         _crtime: Option<Timespec>,
         _chgtime: Option<Timespec>,
         _bkuptime: Option<Timespec>,
@@ -252,28 +483,91 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         reply: ReplyAttr,
     ) {
         debug!("--------");
-        debug!("setattr inode: {}\nmode: {:x?}, flags: {:#x?}, uid: {:?}, gid: {:?}, size: {:?}, atime: {:?}, mtime: {:?}, fh: {:?}, crtime: {:?}, chgtime: {:?}, bkuptime: {:?}",ino, mode, _flags, _uid, _gid, _size, _atime, _mtime, _fh, _crtime, _chgtime, _bkuptime);
+        debug!("setattr inode: {}\nmode: {:x?}, flags: {:#x?}, uid: {:?}, gid: {:?}, size: {:?}, atime: {:?}, mtime: {:?}, fh: {:?}, crtime: {:?}, chgtime: {:?}, bkuptime: {:?}",ino, mode, _flags, _uid, _gid, size, atime, mtime, fh, _crtime, _chgtime, _bkuptime);
+
+        let changes = mode.is_some() || size.is_some() || atime.is_some() || mtime.is_some();
+        if self.read_only && changes {
+            reply.error(EROFS);
+            return;
+        }
+
+        // Check the times before changing anything, so that a bad one changes nothing.
+        let (atime, mtime) = match (
+            atime.map(UfsTime::try_from).transpose(),
+            mtime.map(UfsTime::try_from).transpose(),
+        ) {
+            (Ok(atime), Ok(mtime)) => (atime, mtime),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("unable to set times: {}", e);
+                reply.error(EINVAL);
+                return;
+            }
+        };
 
         if let Some(inode) = self.inodes.get_mut(&ino) {
             if let Some(mode) = mode {
                 // First off, the `perms` field in the `FileAttr` struct is only a u16, so let's
-                // truncate the mode.
-                let mode: u16 = mode as u16;
-                let mut guard = self.file_system.lock().expect("poisoned ufs lock");
-                guard.set_permissions(inode.id(), mode);
+                // truncate the mode. Only the permission bits, including setuid, setgid, and
+                // sticky, are kept -- the file type isn't ours to change.
+                let mode: u16 = (mode & 0o7777) as u16;
+                let mut guard = write_ufs(&self.file_system);
+                if let Err(e) = guard.set_permissions(inode.id(), mode) {
+                    error!("unable to set permissions: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
                 inode.set_perm(mode);
                 debug!("mode {:#05o}", mode);
             }
+
+            if let Some(size) = size {
+                if let Inode::File(inode) = inode {
+                    // `ftruncate` hands us the open file, `truncate` does not.
+                    let mut guard = write_ufs(&self.file_system);
+                    let result = match fh {
+                        Some(fh) => guard.truncate_file(fh, size),
+                        None => guard.set_file_size(inode.id, size),
+                    };
+                    match result {
+                        Ok(_) => inode.size = size,
+                        Err(e) => {
+                            error!("unable to truncate file: {}", e);
+                            reply.error(EIO);
+                            return;
+                        }
+                    }
+                } else {
+                    reply.error(EISDIR);
+                    return;
+                }
+            }
+
+            if atime.is_some() || mtime.is_some() {
+                let mut guard = write_ufs(&self.file_system);
+                if let Err(e) = guard.set_times(inode.id(), atime, mtime) {
+                    error!("unable to set times: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            }
         }
 
         self.getattr(_req, ino, reply);
     }
 
-    // fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-    //     debug!("--------");
-    //     debug!("access: {}, mask: {:x?}", _ino, _mask);
-    //     reply.ok();
-    // }
+    /// Check the caller's access to an inode
+    ///
+    /// This is for `access(2)`, as the kernel otherwise does its own checks. The stored permissions
+    /// are checked against the caller's uid and gid, compared with the owner that we report.
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        debug!("--------");
+        debug!("`access`: ino: {}, mask: {:#o}", ino, mask);
+
+        match self.check_access(ino, req.uid(), req.gid(), mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
 
     /// Open a directory
     fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
@@ -296,7 +590,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             //     panic!("opendir called with something not a directory");
             // };
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+            let mut guard = write_ufs(&self.file_system);
             match &mut guard.open_directory(inode.id) {
                 Ok(fh) => {
                     debug!("handle: {}", fh);
@@ -316,7 +610,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
                                     let inode = DirInode {
                                         number,
                                         id: d.id().clone(),
-                                        time: d.write_time().into(),
+                                        times: d.times(),
                                         files: HashMap::new(),
                                         perm: d.unix_perms(),
                                     };
@@ -336,13 +630,32 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
                                     let inode = FileInode {
                                         number,
                                         id: file.file_id().clone(),
-                                        time: file.write_time().into(),
+                                        times: f.times(),
                                         size: file.size(),
                                         perm: f.unix_perms(),
+                                        nlink: f.links(),
                                     };
                                     inodes.push(Inode::File(inode));
                                     dir_file_map.insert(name.clone(), number);
                                 }
+                                DirectoryEntry::Symlink(s) => {
+                                    debug!(
+                                        "\tadding symlink: ino: {}, target: {:?}, id: {}",
+                                        number,
+                                        s.target(),
+                                        s.id()
+                                    );
+                                    let inode = SymlinkInode {
+                                        number,
+                                        id: s.id(),
+                                        target: s.target().to_path_buf(),
+                                    };
+                                    inodes.push(Inode::Symlink(inode));
+                                    dir_file_map.insert(name.clone(), number);
+                                }
+                                DirectoryEntry::FileLink(id) => {
+                                    warn!("\tskipping unresolved link {:?} to {}", name, id);
+                                }
                             };
                         }
 
@@ -351,6 +664,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
                             match i.clone() {
                                 Inode::Dir(d) => self.inodes.insert(d.number, i),
                                 Inode::File(f) => self.inodes.insert(f.number, i),
+                                Inode::Symlink(s) => self.inodes.insert(s.number, i),
                             };
                         }
 
@@ -386,34 +700,59 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         debug!("`readdir`: ino: {}, fh: {}, offset: {}", ino, fh, offset);
 
         if let Some(Inode::Dir(dir_ino)) = self.inodes.get(&ino) {
-            for (i, (name, index)) in dir_ino.files.iter().enumerate().skip(offset as usize) {
-                if let Some(inode) = self.inodes.get(index) {
+            // List the entries from where the last call left off, until the reply is full.
+            let guard = read_ufs(&self.file_system);
+            let offset = offset as usize;
+            let page = match guard.list_files_paged(fh, offset, usize::max_value()) {
+                Some((page, _)) => page,
+                None => {
+                    warn!("`readdir`: directory handle {} not open", fh);
+                    reply.error(ENOENT);
+                    return;
+                }
+            };
+
+            for (i, (name, _)) in page.into_iter().enumerate() {
+                // i + 1 means the index of the next entry
+                let next = (offset + i + 1) as i64;
+                // Skip anything created since the directory was opened.
+                let index = match dir_ino.files.get(name) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let full = if let Some(inode) = self.inodes.get(index) {
                     match inode {
                         Inode::Dir(dir) => {
                             debug!(
                                 "adding to reply: inode {}, offset {}, Directory, name {}",
-                                dir.number,
-                                i + 1,
-                                name
+                                dir.number, next, name
                             );
-                            // i + 1 means the index of the next entry
-                            reply.add(dir.number, (i + 1) as i64, FileType::Directory, name);
+                            reply.add(dir.number, next, FileType::Directory, name)
                         }
                         Inode::File(file) => {
                             debug!(
                                 "adding to reply: inode {}, offset {}, File, name {}",
-                                file.number,
-                                i + 1,
-                                name
+                                file.number, next, name
                             );
-                            // i + 1 means the index of the next entry
-                            reply.add(file.number, (i + 1) as i64, FileType::RegularFile, name);
+                            reply.add(file.number, next, FileType::RegularFile, name)
+                        }
+                        Inode::Symlink(link) => {
+                            debug!(
+                                "adding to reply: inode {}, offset {}, Symlink, name {}",
+                                link.number, next, name
+                            );
+                            reply.add(link.number, next, FileType::Symlink, name)
                         }
                     }
                 } else {
                     warn!("\t can't find inode {}", index);
                     reply.error(ENOENT);
                     return;
+                };
+
+                // The rest will be asked for, starting at the next entry.
+                if full {
+                    break;
                 }
             }
             reply.ok();
@@ -428,7 +767,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         debug!("--------");
         debug!("`releasedir` ino: {}, fh: {}, flags: {:#x}", ino, fh, flags);
 
-        let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+        let mut guard = write_ufs(&self.file_system);
         &mut guard.close_directory(fh);
         reply.ok();
     }
@@ -438,9 +777,16 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         debug!("open ino: {}, flags {:x}", ino, flags);
 
         if let Some(Inode::File(inode)) = self.inodes.get_mut(&ino) {
+            if self.read_only && writes(flags) {
+                reply.error(EROFS);
+                return;
+            }
+
             let open_flags = flags as i32;
-            let mode = match open_flags {
+            let mode = match open_flags & O_ACCMODE {
                 O_RDONLY => OpenFileMode::Read,
+                O_WRONLY if open_flags & O_APPEND != 0 => OpenFileMode::Append,
+                O_RDWR if open_flags & O_APPEND != 0 => OpenFileMode::ReadAppend,
                 O_WRONLY => {
                     inode.size = 0;
                     OpenFileMode::Write
@@ -449,9 +795,23 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
                 _ => unreachable!(),
             };
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
-            match &mut guard.open_file(inode.id, mode) {
-                Ok(fh) => reply.opened(*fh as u64, 0),
+            let mut guard = write_ufs(&self.file_system);
+            match guard.open_file(inode.id, mode) {
+                Ok(fh) => {
+                    if open_flags & O_TRUNC != 0 {
+                        if let Err(e) = guard.truncate_file(fh, 0) {
+                            error!("unable to truncate file: {}", e);
+                            // The kernel never hears of the handle, so it's closed here.
+                            if let Err(close_error) = guard.close_file(fh) {
+                                error!("unable to close file: {}", close_error);
+                            }
+                            reply.error(errno(&e));
+                            return;
+                        }
+                        inode.size = 0;
+                    }
+                    reply.opened(fh as u64, 0)
+                }
                 _ => reply.error(ENOENT),
             }
         } else {
@@ -470,19 +830,25 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             "`mkdir`: {:?}, parent: {}, mode: {:#05o}",
             name, parent, _mode
         );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
         let new_inode_number = self.inode_number;
         self.inode_number = new_inode_number.wrapping_add(1);
 
         if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&parent) {
             let name = String::from(name.to_str().unwrap());
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+            let mut guard = write_ufs(&self.file_system);
             let inode = match &mut guard.create_directory(parent_ino.id, &name) {
                 Ok(dir) => {
                     let inode = DirInode {
                         id: dir.id().clone(),
                         number: new_inode_number,
-                        time: TIME,
+                        times: dir.times(),
                         files: HashMap::new(),
                         perm: dir.unix_perms(),
                     };
@@ -507,6 +873,146 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         }
     }
 
+    // Make a symbolic link
+    // parent is the inode of the directory to hold the link, and link is the target path
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        debug!("--------");
+        debug!(
+            "`symlink`: {:?}, parent: {}, link: {:?}",
+            name, parent, link
+        );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                warn!("invalid UTF-8 in {:?}", name);
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let new_inode_number = self.inode_number;
+        self.inode_number = new_inode_number.wrapping_add(1);
+
+        if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&parent) {
+            let mut guard = write_ufs(&self.file_system);
+            match guard.create_symlink(parent_ino.id, name, link) {
+                Ok(symlink) => {
+                    let inode = SymlinkInode {
+                        number: new_inode_number,
+                        id: symlink.id(),
+                        target: symlink.target().to_path_buf(),
+                    };
+
+                    reply.entry(&TTL, &inode.file_attr(), 0);
+
+                    parent_ino.files.insert(name.to_owned(), new_inode_number);
+                    self.inodes.insert(new_inode_number, Inode::Symlink(inode));
+                }
+                Err(e) => {
+                    error!("Unable to create symlink {}: {}", name, e);
+                    reply.error(errno(&e));
+                }
+            }
+        } else {
+            warn!("\tcan't find parent inode {}", parent);
+            reply.error(ENOENT);
+        }
+    }
+
+    // Return the target of a symbolic link
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        debug!("--------");
+        debug!("`readlink`: ino: {}", ino);
+
+        match self.inodes.get(&ino) {
+            Some(Inode::Symlink(link)) => reply.data(link.target.as_os_str().as_bytes()),
+            Some(_) => reply.error(EINVAL),
+            None => {
+                warn!("`readlink`: can't find inode {}", ino);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    // Make a hard link
+    // ino is the file being linked to, and newparent is the inode of the directory to hold the link
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        debug!("--------");
+        debug!(
+            "`link`: ino: {}, newparent: {}, newname: {:?}",
+            ino, newparent, newname
+        );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let newname = match newname.to_str() {
+            Some(newname) => newname,
+            None => {
+                warn!("invalid UTF-8 in {:?}", newname);
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let (id, parent_id) = match (self.inodes.get(&ino), self.inodes.get(&newparent)) {
+            (Some(Inode::File(f)), Some(Inode::Dir(p))) => (f.id, p.id),
+            (Some(_), Some(Inode::Dir(_))) => {
+                reply.error(EPERM);
+                return;
+            }
+            _ => {
+                warn!("can't find inode {} or parent inode {}", ino, newparent);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let result = {
+            let mut guard = write_ufs(&self.file_system);
+            guard.link(id, parent_id, newname)
+        };
+
+        match result {
+            Ok(file) => {
+                // The new name shares the inode.
+                if let Some(Inode::File(inode)) = self.inodes.get_mut(&ino) {
+                    inode.nlink = file.links();
+                    reply.entry(&TTL, &inode.file_attr(), 0);
+                }
+                if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&newparent) {
+                    parent_ino.files.insert(newname.to_owned(), ino);
+                }
+            }
+            Err(e) => {
+                warn!("unable to link {}: {}", newname, e);
+                reply.error(errno(&e));
+            }
+        }
+    }
+
     // Create and open a file
     // parent is the inode of the parent directory
     fn create(
@@ -523,21 +1029,28 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             "`create`: {:?}, parent: {}, mode: {:#05o}, flags: {:#x}",
             name, parent, _mode, flags
         );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
         let new_inode_number = self.inode_number;
         self.inode_number = new_inode_number.wrapping_add(1);
 
         if let Some(Inode::Dir(ref mut parent_ino)) = self.inodes.get_mut(&parent) {
             let name = String::from(name.to_str().unwrap());
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+            let mut guard = write_ufs(&self.file_system);
             let inode = match &mut guard.create_file(parent_ino.id, &name) {
                 Ok((fh, file)) => {
                     let inode = FileInode {
                         id: file.file_id.clone(),
                         number: new_inode_number,
-                        time: file.version.write_time().into(),
+                        times: Timestamps::new(file.version.write_time()),
                         size: 0,
                         perm: file.perms,
+                        nlink: 1,
                     };
                     debug!("inode: {}", inode.number);
 
@@ -548,6 +1061,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
                 }
                 Err(e) => {
                     error!("Unable to create file {}: {}", name, e);
+                    reply.error(errno(e));
                     None
                 }
             };
@@ -561,20 +1075,107 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         }
     }
 
+    // Make a file system node
+    // Only regular files are supported, see `mknod_regular`. They're made as by `create`, but not
+    // left open.
+    fn mknod(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("--------");
+        debug!("`mknod`: {:?}, parent: {}, mode: {:#o}", name, parent, mode);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        if let Err(e) = mknod_regular(mode) {
+            warn!("unsupported `mknod` of {:?}, mode {:#o}", name, mode);
+            reply.error(e);
+            return;
+        }
+
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                warn!("invalid UTF-8 in {:?}", name);
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let new_inode_number = self.inode_number;
+        self.inode_number = new_inode_number.wrapping_add(1);
+
+        if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&parent) {
+            let mut guard = write_ufs(&self.file_system);
+            match guard.create_file(parent_ino.id, name) {
+                Ok((fh, file)) => {
+                    if guard.close_file(fh).is_err() {
+                        warn!("unable to close new file {}", name);
+                    }
+
+                    let inode = FileInode {
+                        id: file.file_id,
+                        number: new_inode_number,
+                        times: Timestamps::new(file.version.write_time()),
+                        size: 0,
+                        perm: file.perms,
+                        nlink: 1,
+                    };
+
+                    reply.entry(&TTL, &inode.file_attr(), 0);
+
+                    parent_ino.files.insert(name.to_owned(), new_inode_number);
+                    self.inodes.insert(new_inode_number, Inode::File(inode));
+                }
+                Err(e) => {
+                    error!("Unable to create file {}: {}", name, e);
+                    reply.error(errno(&e));
+                }
+            }
+        } else {
+            warn!("\tcan't find parent inode {}", parent);
+            reply.error(ENOENT);
+        }
+    }
+
     // Remove a file from the file system
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("--------");
         debug!("`unlink`: {:?}, parent: {}", name, parent);
 
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
         if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&parent) {
             let name = name.to_str().unwrap();
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+            let mut guard = write_ufs(&self.file_system);
             match guard.remove_file(parent_ino.id, name) {
-                Ok(_) => reply.ok(),
+                Ok(_) => {
+                    if let Some(number) = parent_ino.files.remove(name) {
+                        // The inode may live on under another name.
+                        match self.inodes.get_mut(&number) {
+                            Some(Inode::File(inode)) if inode.nlink > 1 => inode.nlink -= 1,
+                            _ => {
+                                self.inodes.remove(&number);
+                            }
+                        }
+                    }
+                    reply.ok()
+                }
                 Err(e) => {
                     error!("unlinking file {}", e);
-                    reply.error(ENOENT);
+                    reply.error(errno(&e));
                 }
             }
         } else {
@@ -588,15 +1189,25 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         debug!("--------");
         debug!("`rmdir`: {:?}, parent: {}", name, parent);
 
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
         if let Some(Inode::Dir(parent_ino)) = self.inodes.get_mut(&parent) {
             let name = name.to_str().unwrap();
 
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
+            let mut guard = write_ufs(&self.file_system);
             match guard.remove_directory(parent_ino.id, name) {
-                Ok(_) => reply.ok(),
+                Ok(_) => {
+                    if let Some(number) = parent_ino.files.remove(name) {
+                        self.inodes.remove(&number);
+                    }
+                    reply.ok()
+                }
                 Err(e) => {
-                    error!("unlinking file {}", e);
-                    reply.error(ENOENT);
+                    warn!("unable to remove directory {}: {}", name, e);
+                    reply.error(errno(&e));
                 }
             }
         } else {
@@ -605,6 +1216,83 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         }
     }
 
+    // Move, and/or rename, a file or directory
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        debug!("--------");
+        debug!(
+            "`rename`: {:?}, parent: {} to {:?}, parent: {}",
+            name, parent, newname, newparent
+        );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let (name, newname) = match (name.to_str(), newname.to_str()) {
+            (Some(name), Some(newname)) => (name, newname),
+            _ => {
+                warn!("invalid UTF-8 in {:?} or {:?}", name, newname);
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let (parent_id, new_parent_id) =
+            match (self.inodes.get(&parent), self.inodes.get(&newparent)) {
+                (Some(Inode::Dir(p)), Some(Inode::Dir(np))) => (p.id, np.id),
+                _ => {
+                    warn!("can't find parent inode {} or {}", parent, newparent);
+                    reply.error(ENOENT);
+                    return;
+                }
+            };
+
+        let result = {
+            let mut guard = write_ufs(&self.file_system);
+            guard.rename_entry(parent_id, name, new_parent_id, newname)
+        };
+
+        match result {
+            Ok(_) => {
+                // Move the inode from the old parent's map to the new parent's, dropping the inode
+                // of anything that was replaced.
+                let number = match self.inodes.get_mut(&parent) {
+                    Some(Inode::Dir(parent_ino)) => parent_ino.files.remove(name),
+                    _ => None,
+                };
+                let replaced = match (self.inodes.get_mut(&newparent), number) {
+                    (Some(Inode::Dir(new_parent_ino)), Some(number)) => {
+                        new_parent_ino.files.insert(newname.to_owned(), number)
+                    }
+                    (Some(Inode::Dir(new_parent_ino)), None) => {
+                        new_parent_ino.files.remove(newname)
+                    }
+                    _ => None,
+                };
+                if let Some(replaced) = replaced {
+                    if Some(replaced) != number {
+                        self.inodes.remove(&replaced);
+                    }
+                }
+
+                reply.ok();
+            }
+            Err(e) => {
+                warn!("unable to rename {}: {}", name, e);
+                reply.error(errno(&e));
+            }
+        }
+    }
+
     fn release(
         &mut self,
         _req: &Request,
@@ -621,9 +1309,50 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             ino, fh, flags, flush
         );
 
-        let mut guard = self.file_system.lock().expect("poisoned ufs lock");
-        &mut guard.close_file(fh).unwrap();
-        reply.ok();
+        let mut guard = write_ufs(&self.file_system);
+        match guard.close_file(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("unable to close file: {}", e);
+                // The bytes that couldn't be written are gone; the handle needn't be kept too.
+                let _ = guard.close_file(fh);
+                reply.error(errno(&e));
+            }
+        }
+    }
+
+    /// Commit a file's changes, when a file descriptor is closed
+    ///
+    /// This may be called more than once per open, as file descriptors are duplicated.
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("--------");
+        debug!("`flush`: ino: {}, fh: {}", ino, fh);
+
+        let mut guard = write_ufs(&self.file_system);
+        match guard.flush_file(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("unable to flush file: {}", e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    /// Commit a file's changes to storage
+    ///
+    /// Data and metadata are always written together, so `datasync` makes no difference.
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("--------");
+        debug!("`fsync`: ino: {}, fh: {}, datasync: {}", ino, fh, datasync);
+
+        let mut guard = write_ufs(&self.file_system);
+        match guard.flush_file(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("unable to sync file: {}", e);
+                reply.error(EIO);
+            }
+        }
     }
 
     fn read(
@@ -640,8 +1369,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             ino, offset, size
         );
 
-        let guard = self.file_system.lock().expect("poisoned ufs lock");
-        match &mut guard.read_file(fh, offset as u64, size) {
+        match read_file_shared(&self.file_system, fh, offset as u64, size) {
             Ok(buffer) => {
                 debug!("read {} bytes", buffer.len());
                 trace!("{:?}", &buffer);
@@ -649,7 +1377,7 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             }
             Err(e) => {
                 error!("{}", e);
-                reply.error(ENOENT)
+                reply.error(errno(&e))
             }
         }
     }
@@ -671,23 +1399,178 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
             data.len()
         );
 
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
         if let Some(Inode::File(inode)) = self.inodes.get_mut(&ino) {
-            let mut guard = self.file_system.lock().expect("poisoned ufs lock");
-            if let Ok(len) = &mut guard.write_file(fh, data, offset as u64) {
-                debug!("wrote {} bytes", len);
-                trace!("{:?}", &data[..*len]);
+            let mut guard = write_ufs(&self.file_system);
+            match &mut guard.write_file(fh, data, offset as u64) {
+                Ok(len) => {
+                    debug!("wrote {} bytes", len);
+                    trace!("{:?}", &data[..*len]);
 
-                inode.size = inode.size + *len as u64;
+                    inode.size = inode.size.max(offset as u64 + *len as u64);
 
-                reply.written(*len as u32);
-            } else {
-                reply.error(ENOENT);
+                    reply.written(*len as u32);
+                }
+                Err(e) => {
+                    error!("unable to write file: {}", e);
+                    reply.error(errno(e));
+                }
             }
         } else {
             reply.error(ENOENT);
         }
     }
 
+    /// Return an extended attribute
+    ///
+    /// A `size` of zero is a request for the size of the value, rather than the value itself.
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr ino: {}, name: {:?}, size: {}", ino, name, size);
+
+        match self.xattr(ino, name) {
+            Ok(value) => reply_xattr(&value, size, reply),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Set an extended attribute
+    ///
+    /// `position` is only used for macOS resource forks, which aren't supported. The synthetic
+    /// attributes may not be set.
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            "setxattr ino: {}, name: {:?}, {} bytes, flags: {:#x}, position: {}",
+            ino,
+            name,
+            value.len(),
+            flags,
+            position
+        );
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let id = match self.inodes.get(&ino) {
+            Some(inode) => inode.id(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name = match name.to_str() {
+            Some(CONTENT_HASH_XATTR) => {
+                reply.error(EPERM);
+                return;
+            }
+            Some(name) if position == 0 => name,
+            _ => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let mut guard = write_ufs(&self.file_system);
+        let exists = guard.get_xattr(id, name).is_ok();
+        let flags = flags as c_int;
+        if exists && flags & XATTR_CREATE != 0 {
+            reply.error(EEXIST);
+        } else if !exists && flags & XATTR_REPLACE != 0 {
+            reply.error(ENOATTR);
+        } else {
+            match guard.set_xattr(id, name, value) {
+                Ok(_) => reply.ok(),
+                Err(e) => {
+                    error!("unable to set xattr: {}", e);
+                    reply.error(errno(&e));
+                }
+            }
+        }
+    }
+
+    /// List the names of the extended attributes
+    ///
+    /// The names are returned as one buffer, each name terminated by a nul. Files include the
+    /// synthetic attributes.
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr ino: {}, size: {}", ino, size);
+
+        let (id, is_file) = match self.inodes.get(&ino) {
+            Some(Inode::File(inode)) => (inode.id, true),
+            Some(inode) => (inode.id(), false),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let guard = read_ufs(&self.file_system);
+        match guard.list_xattrs(id) {
+            Ok(mut names) => {
+                if is_file {
+                    names.push(CONTENT_HASH_XATTR.to_string());
+                }
+                let mut list = vec![];
+                for name in names {
+                    list.extend_from_slice(name.as_bytes());
+                    list.push(0);
+                }
+                reply_xattr(&list, size, reply);
+            }
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    /// Remove an extended attribute
+    ///
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr ino: {}, name: {:?}", ino, name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let id = match self.inodes.get(&ino) {
+            Some(inode) => inode.id(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let name = match name.to_str() {
+            Some(CONTENT_HASH_XATTR) => {
+                reply.error(EPERM);
+                return;
+            }
+            Some(name) => name,
+            None => {
+                reply.error(ENOATTR);
+                return;
+            }
+        };
+
+        let mut guard = write_ufs(&self.file_system);
+        match guard.remove_xattr(id, name) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
     /// Return File System Statistics
     ///
     /// Given an inode, return statistics in the `ReplyStatfs` struct, which is poorly documented.
@@ -709,21 +1592,25 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
     /// * Why pass an inode?  The libfuse `passthrough_ll.c` impl uses the inode to lookup a file
     /// descriptor so that it may then call `fstatvfs`.
     ///
+    /// The free block counts leave out the blocks needed to write the next copy of the metadata,
+    /// as they aren't there to be written with file data.
+    ///
     /// FIXME: What to do about maximum file name length?
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         trace!("statfs ino {}", _ino);
-        let guard = self.file_system.lock().expect("poisoned ufs lock");
+        let guard = read_ufs(&self.file_system);
         let block_manager = &guard.block_manager();
         trace!(
-            "blocks: {}, free blocks: {}, block size: {}",
+            "blocks: {}, free blocks: {}, available blocks: {}, block size: {}",
             block_manager.block_count(),
             block_manager.free_block_count(),
+            block_manager.available_block_count(),
             block_manager.block_size()
         );
         reply.statfs(
             block_manager.block_count(),
-            block_manager.free_block_count(),
-            block_manager.free_block_count(),
+            block_manager.available_block_count(),
+            block_manager.available_block_count(),
             // I'm using i64 below, because it's consistent with what I'm seeing from APFS.
             i64::max_value() as u64,
             // i64::max_value() as u64 - self.files.len() as u64,
@@ -734,3 +1621,232 @@ impl<B: BlockStorage> Filesystem for UberFSFuse<B> {
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{BlockSize, UberFileSystem};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn content_hash_xattr() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let mut fs = UberFSFuse::new(UfsMounter::new(ufs, None).unwrap());
+
+        let file_id = {
+            let mut guard = fs.file_system.write().unwrap();
+            let root_id = guard.get_root_directory_id();
+            let (h, file) = guard.create_file(root_id, "hash").unwrap();
+            guard.write_file(h, b"Hello World!", 0).unwrap();
+            guard.close_file(h).unwrap();
+            file.file_id
+        };
+        fs.inodes.insert(
+            2,
+            Inode::File(FileInode {
+                number: 2,
+                id: file_id,
+                times: Timestamps::new(UfsTime::try_from(TIME).unwrap()),
+                size: 12,
+                perm: 0o644,
+                nlink: 1,
+            }),
+        );
+
+        let name = OsStr::new(CONTENT_HASH_XATTR);
+        let before = fs.synthetic_xattr(2, name).unwrap();
+        {
+            let guard = fs.file_system.read().unwrap();
+            let hash = guard.content_hash(file_id).unwrap();
+            assert_eq!(format!("{:?}", hash).into_bytes(), before);
+        }
+
+        {
+            let mut guard = fs.file_system.write().unwrap();
+            let h = guard.open_file(file_id, OpenFileMode::Write).unwrap();
+            guard.write_file(h, b"Goodbye World!", 0).unwrap();
+            guard.close_file(h).unwrap();
+        }
+
+        let after = fs.synthetic_xattr(2, name).unwrap();
+        assert_ne!(before, after);
+        {
+            let guard = fs.file_system.read().unwrap();
+            let hash = guard.content_hash(file_id).unwrap();
+            assert_eq!(format!("{:?}", hash).into_bytes(), after);
+        }
+
+        assert_eq!(Err(ENOATTR), fs.synthetic_xattr(2, OsStr::new("user.foo")));
+        assert_eq!(Err(ENOATTR), fs.synthetic_xattr(1, name));
+
+        fs.file_system.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stored_xattr() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let mut fs = UberFSFuse::new(UfsMounter::new(ufs, None).unwrap());
+
+        let (root_id, file_id) = {
+            let mut guard = fs.file_system.write().unwrap();
+            let root_id = guard.get_root_directory_id();
+            let (h, file) = guard.create_file(root_id, "tagged").unwrap();
+            guard.close_file(h).unwrap();
+            guard.set_xattr(file.file_id, "user.tag", b"red").unwrap();
+            guard.set_xattr(root_id, "user.tag", b"blue").unwrap();
+            (root_id, file.file_id)
+        };
+        fs.inodes.insert(
+            2,
+            Inode::File(FileInode {
+                number: 2,
+                id: file_id,
+                times: Timestamps::new(UfsTime::try_from(TIME).unwrap()),
+                size: 0,
+                perm: 0o644,
+                nlink: 1,
+            }),
+        );
+
+        let name = OsStr::new("user.tag");
+        assert_eq!(Ok(b"red".to_vec()), fs.xattr(2, name));
+        assert_eq!(Ok(b"blue".to_vec()), fs.xattr(1, name));
+        assert!(fs.xattr(2, OsStr::new(CONTENT_HASH_XATTR)).is_ok());
+        assert_eq!(Err(ENOATTR), fs.xattr(2, OsStr::new("user.other")));
+        assert_eq!(Err(ENOENT), fs.xattr(3, name));
+
+        {
+            let mut guard = fs.file_system.write().unwrap();
+            assert_eq!(vec!["user.tag"], guard.list_xattrs(file_id).unwrap());
+            guard.remove_xattr(file_id, "user.tag").unwrap();
+            let e = guard.remove_xattr(file_id, "user.tag").unwrap_err();
+            assert_eq!(ENOATTR, errno(&e));
+            assert!(guard.list_xattrs(file_id).unwrap().is_empty());
+            assert_eq!(
+                b"blue".to_vec(),
+                guard.get_xattr(root_id, "user.tag").unwrap()
+            );
+        }
+        assert_eq!(Err(ENOATTR), fs.xattr(2, name));
+
+        fs.file_system.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.set_read_only(true);
+        let mut fs = UberFSFuse::new(UfsMounter::new(ufs, None).unwrap());
+        assert!(fs.read_only);
+
+        assert!(!writes(O_RDONLY as u32));
+        assert!(writes(O_WRONLY as u32));
+        assert!(writes(O_RDWR as u32));
+        assert!(writes((O_RDONLY | O_TRUNC) as u32));
+
+        fs.file_system.shutdown().unwrap();
+    }
+
+    #[test]
+    fn rmdir_errors() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let dir = ufs.create_directory(root_id, "full").unwrap();
+        let (h, _) = ufs.create_file(dir.id(), "file").unwrap();
+        ufs.close_file(h).unwrap();
+
+        let e = ufs.remove_directory(root_id, "full").unwrap_err();
+        assert_eq!(ENOTEMPTY, errno(&e));
+        let e = ufs.remove_directory(dir.id(), ".vers").unwrap_err();
+        assert_eq!(EPERM, errno(&e));
+        let e = ufs.remove_directory(root_id, "missing").unwrap_err();
+        assert_eq!(ENOENT, errno(&e));
+    }
+
+    #[test]
+    fn mknod_kinds() {
+        assert_eq!(Ok(()), mknod_regular(S_IFREG as u32 | 0o644));
+        assert_eq!(Ok(()), mknod_regular(0o644));
+        assert_eq!(Err(EPERM), mknod_regular(S_IFCHR as u32 | 0o600));
+        assert_eq!(Err(EPERM), mknod_regular(S_IFBLK as u32 | 0o600));
+        assert_eq!(Err(ENOSYS), mknod_regular(libc::S_IFIFO as u32 | 0o644));
+        assert_eq!(Err(ENOSYS), mknod_regular(libc::S_IFSOCK as u32 | 0o644));
+    }
+
+    #[test]
+    fn access_masks() {
+        let (r, w, x) = (R_OK as u32, W_OK as u32, X_OK as u32);
+        let (uid, gid) = owner();
+        let other = uid + 1;
+
+        // rw-------
+        assert!(allowed(0o600, false, uid, gid, r | w));
+        assert!(!allowed(0o600, false, uid, gid, x));
+        assert!(!allowed(0o600, false, other, gid, r));
+        assert!(!allowed(0o600, false, other, gid + 1, w));
+        assert!(allowed(0o600, false, other, gid, 0));
+        assert!(allowed(0o600, false, 0, 0, r | w));
+        assert!(!allowed(0o600, false, 0, 0, x));
+        assert!(allowed(0o600, true, 0, 0, x));
+
+        // rwxr-xr-x
+        assert!(allowed(0o755, false, uid, gid, r | w | x));
+        assert!(allowed(0o755, false, other, gid, r | x));
+        assert!(!allowed(0o755, false, other, gid, w));
+        assert!(allowed(0o755, true, other, gid + 1, r | x));
+        assert!(!allowed(0o755, true, other, gid + 1, w));
+        assert!(allowed(0o755, false, 0, 0, r | w | x));
+    }
+
+    #[test]
+    fn access_as_owner() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let mut fs = UberFSFuse::new(UfsMounter::new(ufs, None).unwrap());
+
+        let file_id = {
+            let mut guard = fs.file_system.write().unwrap();
+            let root_id = guard.get_root_directory_id();
+            let (h, file) = guard.create_file(root_id, "secret").unwrap();
+            guard.close_file(h).unwrap();
+            guard.set_permissions(file.file_id, 0o600).unwrap();
+            file.file_id
+        };
+        fs.inodes.insert(
+            2,
+            Inode::File(FileInode {
+                number: 2,
+                id: file_id,
+                times: Timestamps::new(UfsTime::try_from(TIME).unwrap()),
+                size: 0,
+                perm: 0o600,
+                nlink: 1,
+            }),
+        );
+
+        let (uid, gid) = owner();
+        let (r, w) = (R_OK as u32, W_OK as u32);
+        assert_eq!(Ok(()), fs.check_access(2, uid, gid, r | w));
+        assert_eq!(Ok(()), fs.check_access(1, uid, gid, r | X_OK as u32));
+        assert_eq!(Err(EACCES), fs.check_access(2, uid + 1, gid + 1, r));
+        assert_eq!(Err(ENOENT), fs.check_access(3, uid, gid, r));
+    }
+}
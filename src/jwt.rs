@@ -1,5 +1,6 @@
 use {
     crate::{IOFSErrorKind, UfsUuid},
+    chrono::prelude::*,
     jsonwebtoken as jwt,
     jwt::{decode, encode, errors::ErrorKind, Header, Validation},
     log::error,
@@ -12,6 +13,7 @@ pub(crate) type JWT = String;
 pub(crate) struct UserClaims {
     pub(crate) iss: UfsUuid,
     pub(crate) sub: UfsUuid,
+    pub(crate) jti: UfsUuid,
     pub(crate) exp: usize,
 }
 
@@ -19,8 +21,15 @@ pub(crate) fn new_jwt(claims: UserClaims, secret: &[u8]) -> JWT {
     encode(&Header::default(), &claims, secret).expect("unable to create JWT")
 }
 
+/// Decode, and validate, a token
+///
+/// A token is expired from the second in which it expires, rather than the second after, which
+/// is when `jsonwebtoken` would have it.
 pub(crate) fn decode_jwt(token: JWT, secret: &String) -> Result<UserClaims, failure::Error> {
     match decode::<UserClaims>(&token, secret.as_bytes(), &Validation::default()) {
+        Ok(ref decoded) if decoded.claims.exp as i64 <= Utc::now().timestamp() => {
+            Err(IOFSErrorKind::TokenExpired.into())
+        }
         Ok(decoded) => Ok(decoded.claims),
         Err(e) => match e.kind() {
             ErrorKind::InvalidToken => Err(IOFSErrorKind::InvalidToken.into()),
@@ -45,6 +54,7 @@ mod test {
             UserClaims {
                 iss: UfsUuid::new_root_fs("foo"),
                 sub: UfsUuid::new_user("foo"),
+                jti: UfsUuid::new_user("foo").new_with_timestamp(),
                 exp: exp.timestamp() as usize,
             },
             "secret".as_bytes(),
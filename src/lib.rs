@@ -113,6 +113,7 @@
 
 mod block;
 mod crypto;
+mod event;
 mod fsimpl;
 mod fuse;
 mod jwt;
@@ -128,14 +129,29 @@ use {
 };
 
 pub use {
-    crate::{crypto::make_fs_key, fuse::UberFSFuse, uuid::UfsUuid},
+    crate::{
+        crypto::Cipher,
+        event::{EventSink, FileEventSink},
+        fuse::UberFSFuse,
+        uuid::UfsUuid,
+    },
     block::{
-        manager::BlockManager, map::BlockMap, BlockAddress, BlockCardinality, BlockNumber,
-        BlockReader, BlockSize, BlockStorage, BlockWriter, FileStore,
+        manager::BlockManager, map::BlockMap, parse_range, BlockAddress, BlockCardinality,
+        BlockNumber, BlockReader, BlockSize, BlockStorage, BlockWriter, ChunkingMode, FileStore,
+        MirrorStore, RetryPolicy,
     },
-    fsimpl::{OpenFileMode, UberFileSystem, UfsMounter},
+    fsimpl::{EventPriority, FormatOptions, OpenFileMode, UberFileSystem, UfsMounter},
+    metadata::{QuotaTarget, Retention, SortOrder},
+    server::ServerTls,
+    wasm::{IofsDirMessage, IofsFileMessage, IofsMessage, IofsMessagePayload, IofsSystemMessage},
 };
 
+#[cfg(feature = "s3")]
+pub use {block::S3Store, rusoto_core::Region};
+
+#[cfg(feature = "bench")]
+pub use fsimpl::BenchFileAccess;
+
 #[derive(Debug)]
 pub(crate) struct IOFSError {
     ctx: Context<IOFSErrorKind>,
@@ -177,6 +193,48 @@ enum IOFSErrorKind {
     InvalidSignature,
     #[fail(display = "Unknown token error")]
     TokenError,
+    #[fail(display = "No such file or directory")]
+    NotFound,
+    #[fail(display = "Not a directory")]
+    NotADirectory,
+    #[fail(display = "Not a file")]
+    NotAFile,
+    #[fail(display = "Path too deep")]
+    PathTooDeep,
+    #[fail(display = "File not open for writing")]
+    NotOpenForWriting,
+    #[fail(display = "File not open for reading")]
+    NotOpenForReading,
+    #[fail(display = "Too many levels of symbolic links")]
+    TooManySymlinks,
+    #[fail(display = "Directory not empty")]
+    DirectoryNotEmpty,
+    #[fail(display = "Is a directory")]
+    IsADirectory,
+    #[fail(display = "Special directories may not be removed")]
+    SpecialDirectory,
+    #[fail(display = "File exists")]
+    FileExists,
+    #[fail(display = "No space left on device")]
+    NoSpace,
+    #[fail(display = "Disk quota exceeded")]
+    QuotaExceeded,
+    #[fail(display = "The latest version of a file may not be removed")]
+    LatestVersion,
+    #[fail(display = "File is open")]
+    FileOpen,
+    #[fail(display = "No such attribute")]
+    NoAttribute,
+    #[fail(display = "Extended attributes too large")]
+    AttributesTooLarge,
+    #[fail(display = "Wasm program store too large")]
+    StoreTooLarge,
+    #[fail(display = "Writing over the contents of a file is not supported")]
+    Overwrite,
+    #[fail(display = "Only the owner of the file system may do that")]
+    NotAdmin,
+    #[fail(display = "Read-only file system")]
+    ReadOnly,
 }
 
 impl From<IOFSErrorKind> for IOFSError {
@@ -14,38 +14,76 @@
 use {
     crate::{
         block::{
+            storage::FORMAT_VERSION,
             wrapper::{MetadataDeserialize, MetadataSerialize},
-            BlockNumber,
+            BlockNumber, BlockSize,
         },
+        time::{Timestamps, UfsTime},
         uuid::UfsUuid,
+        IOFSErrorKind,
     },
     failure::format_err,
-    log::{debug, trace, warn},
+    log::{debug, error, trace, warn},
     serde_derive::{Deserialize, Serialize},
     std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         path::{Component, Components, Path, PathBuf},
     },
 };
 
 pub(crate) mod dir;
 pub(crate) mod file;
+pub(crate) mod index;
 pub(crate) mod permissions;
+pub(crate) mod quota;
+pub(crate) mod reserve;
+pub(crate) mod retain;
+pub(crate) mod store;
+pub(crate) mod symlink;
 pub(crate) mod user;
+pub(crate) mod xattr;
 
 pub(crate) type FileSize = u64;
 
+/// The deepest that directories may be nested, unless configured otherwise
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// The most symbolic links that will be followed when resolving a path
+pub(crate) const MAX_SYMLINK_HOPS: usize = 40;
+
+/// The directory, under the root, where recovered metadata goes when its parent is missing
+pub(crate) const LOST_FOUND_DIR: &str = "lost+found";
+
 /// The size of a FileHandle
 pub type FileHandle = u64;
 
+/// Marks metadata serialized with its format version
+///
+/// Metadata written in format version 1 was bare `bincode`, which begins with the length of the
+/// file system id, so it never starts with these bytes.
+const METADATA_MAGIC: &[u8; 4] = b"UFSD";
+
 pub(crate) use {
     dir::DirectoryMetadata,
-    dir::WASM_EXT,
+    dir::{WASM_CONFIG_EXT, WASM_EXT},
     file::{FileMetadata, FileVersion},
+    index::TreeIndex,
     permissions::{Grant, GrantType, WasmPermissions},
+    quota::{Quota, QuotaHold},
+    reserve::Reservation,
+    store::WasmStore,
+    symlink::SymlinkMetadata,
     user::UserMetadata,
+    xattr::Xattrs,
+};
+
+use {
+    dir::DirectoryMetadataV1, file::FileMetadataV1, permissions::WasmPermissionsV1,
+    user::UserMetadataV1,
 };
 
+pub use {dir::SortOrder, quota::QuotaTarget, retain::Retention};
+
 /// UFS internal definition of a File
 ///
 /// This structure is used by the file system implementation as a file handle. It is a watered-down
@@ -126,6 +164,43 @@ impl From<u16> for Permission {
     }
 }
 
+/// Special Permission Bits
+///
+/// These are the top three bits of a 12-bit unix mode: set-user-ID (0o4000), set-group-ID
+/// (0o2000), and sticky (0o1000).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SpecialBits {
+    setuid: bool,
+    setgid: bool,
+    sticky: bool,
+}
+
+impl SpecialBits {
+    pub fn as_u16(&self) -> u16 {
+        let mut bits = 0;
+        if self.setuid {
+            bits |= 0o4;
+        }
+        if self.setgid {
+            bits |= 0o2;
+        }
+        if self.sticky {
+            bits |= 0o1;
+        }
+        bits
+    }
+}
+
+impl From<u16> for SpecialBits {
+    fn from(p: u16) -> Self {
+        SpecialBits {
+            setuid: p & 0o4 != 0,
+            setgid: p & 0o2 != 0,
+            sticky: p & 0o1 != 0,
+        }
+    }
+}
+
 /// File Permission Groups
 ///
 /// Basic organization of file and directory permissions, that align with unix permissions.
@@ -133,6 +208,7 @@ impl From<u16> for Permission {
 /// needs of the full-blown file system.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct PermissionGroups {
+    special: SpecialBits,
     user: Permission,
     group: Permission,
     other: Permission,
@@ -140,18 +216,36 @@ pub struct PermissionGroups {
 
 impl PermissionGroups {
     fn as_u16(&self) -> u16 {
-        let mut perms = self.user.as_u16();
+        let mut perms = self.special.as_u16();
+        perms <<= 3;
+        perms += self.user.as_u16();
         perms <<= 3;
         perms += self.group.as_u16();
         perms <<= 3;
         perms += self.other.as_u16();
         perms
     }
+
+    /// Return true if the read, write, and execute bits in `mask` are allowed
+    ///
+    /// The owner's permissions apply if `owner`, failing that the group's if `group`, and otherwise
+    /// everyone else's.
+    pub(crate) fn allows(&self, owner: bool, group: bool, mask: u16) -> bool {
+        let granted = if owner {
+            self.user.as_u16()
+        } else if group {
+            self.group.as_u16()
+        } else {
+            self.other.as_u16()
+        };
+        granted & mask == mask
+    }
 }
 
 impl From<u16> for PermissionGroups {
     fn from(p: u16) -> Self {
         PermissionGroups {
+            special: ((p & 0xe00) >> 9).into(),
             user: ((p & 0x1c0) >> 6).into(),
             group: ((p & 0x38) >> 3).into(),
             other: (p & 0x07).into(),
@@ -159,31 +253,60 @@ impl From<u16> for PermissionGroups {
     }
 }
 
+/// `PermissionGroups`, as stored in format version 1, before the special bits
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct PermissionGroupsV1 {
+    user: Permission,
+    group: Permission,
+    other: Permission,
+}
+
+impl From<PermissionGroupsV1> for PermissionGroups {
+    fn from(perms: PermissionGroupsV1) -> Self {
+        PermissionGroups {
+            special: SpecialBits::default(),
+            user: perms.user,
+            group: perms.group,
+            other: perms.other,
+        }
+    }
+}
+
 /// Entries in [`DirectoryMetadata`] structures
 ///
-/// A directory may contain files, or other directories. Here we capture that dualism.
+/// A directory may contain files, other directories, or symbolic links to either.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum DirectoryEntry {
     /// A directory
     ///
     Directory(DirectoryMetadata),
-    /// A file
+    /// A file, as listed by [`Metadata::get_directory`]
     ///
     File(FileMetadata),
+    /// A symbolic link
+    ///
+    Symlink(SymlinkMetadata),
+    /// A hard link to a file
+    ///
+    /// This is how the directory tree holds a file: by id, with the file itself kept once, in the
+    /// metadata's file table, however many links it has. Listings from `get_directory` hold the
+    /// file in its place.
+    FileLink(UfsUuid),
 }
 
 impl DirectoryEntry {
     pub(crate) fn is_dir(&self) -> bool {
         match self {
             DirectoryEntry::Directory(_) => true,
-            DirectoryEntry::File(_) => false,
+            _ => false,
         }
     }
 
     pub(crate) fn is_file(&self) -> bool {
         match self {
-            DirectoryEntry::Directory(_) => false,
-            DirectoryEntry::File(_) => true,
+            DirectoryEntry::File(_) | DirectoryEntry::FileLink(_) => true,
+            _ => false,
         }
     }
 
@@ -191,20 +314,87 @@ impl DirectoryEntry {
         match self {
             DirectoryEntry::Directory(d) => d.id(),
             DirectoryEntry::File(f) => f.id(),
+            DirectoryEntry::Symlink(s) => s.id(),
+            DirectoryEntry::FileLink(id) => *id,
         }
     }
 
+    /// The directory holding the entry
+    ///
+    /// A hard link doesn't know where it is.
     pub(crate) fn parent_id(&self) -> Option<UfsUuid> {
         match self {
             DirectoryEntry::Directory(d) => d.parent_id(),
             DirectoryEntry::File(f) => Some(f.dir_id()),
+            DirectoryEntry::Symlink(s) => Some(s.dir_id()),
+            DirectoryEntry::FileLink(_) => None,
+        }
+    }
+
+    /// The owner of the entry
+    ///
+    /// A hard link belongs to its file, which it doesn't hold.
+    pub(crate) fn owner(&self) -> Option<UfsUuid> {
+        match self {
+            DirectoryEntry::Directory(d) => Some(d.owner()),
+            DirectoryEntry::File(f) => Some(f.owner()),
+            DirectoryEntry::Symlink(s) => Some(s.owner()),
+            DirectoryEntry::FileLink(_) => None,
+        }
+    }
+
+    /// The last time the entry was written
+    ///
+    /// Symbolic links don't keep time stamps, and hard links leave them to their file.
+    pub(crate) fn write_time(&self) -> Option<UfsTime> {
+        match self {
+            DirectoryEntry::Directory(d) => Some(d.write_time()),
+            DirectoryEntry::File(f) => Some(f.get_latest().write_time()),
+            DirectoryEntry::Symlink(_) | DirectoryEntry::FileLink(_) => None,
         }
     }
 
-    pub(crate) fn owner(&self) -> UfsUuid {
+    /// The size of the entry
+    ///
+    /// Directories have no size, and a symbolic link is as long as its target. A hard link leaves
+    /// its size to its file, and counts as empty.
+    pub(crate) fn size(&self) -> FileSize {
         match self {
-            DirectoryEntry::Directory(d) => d.owner(),
-            DirectoryEntry::File(f) => f.owner(),
+            DirectoryEntry::Directory(_) | DirectoryEntry::FileLink(_) => 0,
+            DirectoryEntry::File(f) => f.get_latest().size(),
+            DirectoryEntry::Symlink(s) => s.target().as_os_str().len() as FileSize,
+        }
+    }
+}
+
+/// A `DirectoryEntry`, as stored in format version 1, before symbolic links
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) enum DirectoryEntryV1 {
+    Directory(DirectoryMetadataV1),
+    File(FileMetadataV1),
+}
+
+impl DirectoryEntry {
+    /// Convert an entry from format version 1
+    ///
+    /// File blocks weren't given offsets, and were laid end to end, so the block size is needed.
+    /// Files were kept in their directory entries, and now go in `files`, leaving a link behind.
+    pub(in crate::metadata) fn from_v1(
+        entry: DirectoryEntryV1,
+        block_size: FileSize,
+        files: &mut HashMap<UfsUuid, FileMetadata>,
+    ) -> Self {
+        match entry {
+            DirectoryEntryV1::Directory(d) => {
+                DirectoryEntry::Directory(DirectoryMetadata::from_v1(d, block_size, files))
+            }
+            DirectoryEntryV1::File(f) => {
+                let file = FileMetadata::from_v1(f, block_size);
+                let id = file.id();
+                files.insert(id, file);
+                DirectoryEntry::FileLink(id)
+            }
         }
     }
 }
@@ -219,15 +409,93 @@ pub(crate) struct Metadata {
     /// The UUID of the File System
     ///
     id: UfsUuid,
+    /// The File System Label
+    ///
+    /// A human readable name for display. Unlike the name used to create the file system, which is
+    /// baked into `id`, and hence the keys, this may be changed at will.
+    label: Option<String>,
+    /// The share of the volume reserved for the latest version of each file
+    ///
+    /// `None` means that versions are never pruned to make room.
+    reservation: Option<Reservation>,
+    /// How many versions of each file are kept, unless a directory says otherwise
+    ///
+    /// `None` means that they are all kept.
+    retention: Option<Retention>,
     /// The Root Directory
     ///
     root_directory: DirectoryMetadata,
+    /// Every file in the file system, by id
+    ///
+    /// The directory tree holds a `DirectoryEntry::FileLink` for each of a file's names, so a file
+    /// with several hard links is still kept just once.
+    files: HashMap<UfsUuid, FileMetadata>,
     /// File system user information
     ///
     users: UserMetadata,
     /// File system permissions for Wasm programs
     ///
     grants: WasmPermissions,
+    /// Values that Wasm programs have stored for safe keeping
+    ///
+    store: WasmStore,
+    /// The deepest that directories may be nested
+    ///
+    /// The root directory is at depth 0. This also bounds the recursive walks over the directory
+    /// tree, so that a pathological tree can't overflow the stack.
+    max_depth: usize,
+    /// Where each entry is in the directory tree, by id
+    ///
+    #[serde(skip)]
+    index: TreeIndex,
+}
+
+/// `Metadata`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataV1 {
+    id: UfsUuid,
+    root_directory: DirectoryMetadataV1,
+    users: UserMetadataV1,
+    grants: WasmPermissionsV1,
+}
+
+/// Return the blocks of `removed` versions that no remaining version of `file` uses
+///
+fn unique_blocks(file: &FileMetadata, removed: &[FileVersion]) -> Vec<BlockNumber> {
+    let mut freed = vec![];
+    for b in removed.iter().flat_map(|v| v.blocks().iter()) {
+        let shared = file.get_versions().values().any(|v| v.blocks().contains(b));
+        if !shared && !freed.contains(b) {
+            freed.push(*b);
+        }
+    }
+    freed
+}
+
+/// Return the size of the latest version of each file in `dir`, and the directories below it
+///
+/// The files are found in `files`. Those in `seen` aren't counted, and those that are counted are
+/// added, so that a file with more than one link is only counted once.
+fn live_size(
+    dir: &DirectoryMetadata,
+    files: &HashMap<UfsUuid, FileMetadata>,
+    depth: usize,
+    seen: &mut HashSet<UfsUuid>,
+) -> FileSize {
+    if depth == 0 {
+        return 0;
+    }
+    dir.entries()
+        .values()
+        .map(|entry| match entry {
+            DirectoryEntry::FileLink(id) if seen.insert(*id) => {
+                files.get(id).map_or(0, |f| f.latest_size())
+            }
+            DirectoryEntry::Directory(d) => live_size(d, files, depth - 1, seen),
+            _ => 0,
+        })
+        .sum()
 }
 
 impl Metadata {
@@ -236,410 +504,1682 @@ impl Metadata {
     /// The UUID of the file system is saved with the metadata.
     /// A new root directory is initialized.
     pub(crate) fn new(file_system_id: UfsUuid, owner: UfsUuid) -> Self {
+        let root_directory = DirectoryMetadata::new(file_system_id.new("/"), None, owner);
+        let index = TreeIndex::build(&root_directory, DEFAULT_MAX_DEPTH + 2);
+
         Metadata {
             dirty: true,
             id: file_system_id.clone(),
-            root_directory: DirectoryMetadata::new(file_system_id.new("/"), None, owner),
+            label: None,
+            reservation: None,
+            retention: None,
+            root_directory,
+            files: HashMap::new(),
             users: UserMetadata::new(),
             grants: WasmPermissions::new(),
+            store: WasmStore::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            index,
         }
     }
 
-    /// Create a new user
+    /// Convert metadata from format version 1
     ///
-    pub(crate) fn add_user(&mut self, user: String, password: String) {
-        debug!("-------");
-        debug!("`new_user`: {}", user);
+    /// The result is dirty, so that it's written in the current format.
+    fn from_v1(metadata: MetadataV1, block_size: FileSize) -> Self {
+        let mut files = HashMap::new();
+        let root_directory =
+            DirectoryMetadata::from_v1(metadata.root_directory, block_size, &mut files);
+        let index = TreeIndex::build(&root_directory, DEFAULT_MAX_DEPTH + 2);
 
-        self.dirty = true;
-        self.users.new_user(user, password);
+        Metadata {
+            dirty: true,
+            id: metadata.id,
+            label: None,
+            reservation: None,
+            retention: None,
+            root_directory,
+            files,
+            users: metadata.users.into(),
+            grants: metadata.grants.into(),
+            store: WasmStore::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            index,
+        }
     }
 
-    /// Get a user
+    /// Set the maximum directory depth
     ///
-    /// If successful, return a tuple of the user's id and their key.
+    /// Existing directories deeper than this become unreachable, so be careful shrinking it. The
+    /// depth is stored with the metadata.
+    pub(crate) fn set_max_depth(&mut self, depth: usize) {
+        self.dirty = true;
+        self.max_depth = depth;
+        self.index = TreeIndex::build(&self.root_directory, depth + 2);
+    }
+
+    /// Return the file system label, if one has been set
     ///
-    pub(crate) fn get_user<S: AsRef<str>>(
-        &self,
-        user: S,
-        password: S,
-    ) -> Option<(UfsUuid, [u8; 32])> {
-        self.users.get_user(&user, &password)
+    pub(crate) fn label(&self) -> Option<&str> {
+        self.label.as_ref().map(|l| l.as_str())
     }
 
-    /// Return a list of existing users
+    /// Set the file system label
     ///
-    pub(crate) fn get_users(&self) -> Vec<String> {
-        self.users.get_users()
+    pub(crate) fn set_label(&mut self, label: String) {
+        self.dirty = true;
+        self.label = Some(label);
     }
 
-    /// Add a Wasm program to the grants
+    /// Return the space reservation, if one has been set
     ///
-    pub(crate) fn add_wasm_program_grants(&mut self, program: PathBuf) {
-        self.grants.add_program(program);
+    pub(crate) fn reservation(&self) -> Option<Reservation> {
+        self.reservation
     }
 
-    /// Remove the grants for a Wasm program
+    /// Set, or clear, the space reservation
     ///
-    pub(crate) fn remove_wasm_program_grants(&mut self, program: &PathBuf) {
-        self.grants.remove_program(program);
+    pub(crate) fn set_reservation(&mut self, reservation: Option<Reservation>) {
+        self.dirty = true;
+        self.reservation = reservation;
     }
 
-    /// Check Wasm program grant
+    /// Return the file system's version retention policy, if one has been set
     ///
-    pub(crate) fn check_wasm_program_grant(
-        &mut self,
-        program: &PathBuf,
-        grant_type: GrantType,
-    ) -> Option<Grant> {
-        self.grants.check_grant(program, grant_type)
+    pub(crate) fn retention(&self) -> Option<Retention> {
+        self.retention
     }
 
-    /// Check Wasm program HTTP grant
+    /// Set, or clear, the file system's version retention policy
     ///
-    pub(crate) fn check_wasm_program_http_grant(
-        &mut self,
-        program: &PathBuf,
-        grant_type: GrantType,
-        route: &str,
-    ) -> Option<Grant> {
-        self.grants.check_http_grant(program, grant_type, route)
+    pub(crate) fn set_retention(&mut self, retention: Option<Retention>) {
+        self.dirty = true;
+        self.retention = retention;
     }
 
-    /// Create a new directory
+    /// Set, or clear, a directory's version retention policy
     ///
-    pub(crate) fn new_directory(
+    /// This overrides the file system's policy for the files in the directory.
+    pub(crate) fn set_directory_retention(
         &mut self,
         dir_id: UfsUuid,
-        name: &str,
-        owner: UfsUuid,
-    ) -> Result<DirectoryMetadata, failure::Error> {
-        debug!("--------");
-        debug!("`new_directory`: {}", name);
+        retention: Option<Retention>,
+    ) -> Result<(), failure::Error> {
+        match self.lookup_dir_mut(dir_id) {
+            Some(dir) => {
+                dir.set_retention(retention);
+                self.dirty = true;
+                Ok(())
+            }
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
 
-        if let Some(root) = self.lookup_dir_mut(dir_id) {
-            let new_dir = root.new_subdirectory(name.to_owned(), owner)?;
-            self.dirty = true;
-            debug!("\tcreated directory with id {:?}", dir_id);
-            Ok(new_dir)
-        } else {
-            Err(format_err!("unable to find directory with id {:?}", dir_id))
+    /// Set, or clear, a quota
+    ///
+    /// The bytes already in use are counted when the quota is set, and tracked from then on.
+    pub(crate) fn set_quota(
+        &mut self,
+        target: &QuotaTarget,
+        limit: Option<FileSize>,
+    ) -> Result<(), failure::Error> {
+        let quota = match limit {
+            Some(limit) => Some(Quota::new(limit, self.usage(target)?.0)),
+            None => None,
+        };
+        match target {
+            QuotaTarget::User(user) => {
+                self.users
+                    .set_quota(user, quota)
+                    .ok_or(IOFSErrorKind::NotFound)?;
+            }
+            QuotaTarget::Directory(path) => {
+                let id = self.id_from_path(path).ok_or(IOFSErrorKind::NotFound)?;
+                match self.lookup_dir_mut(id) {
+                    Some(dir) => dir.set_quota(quota),
+                    None => return Err(self.not_a_directory(id)),
+                }
+            }
         }
+        self.dirty = true;
+        Ok(())
     }
 
-    /// Retrieve a directory
+    /// Return the bytes used by a user, or directory, and their quota, if they have one
     ///
-    pub(crate) fn get_directory(
+    /// Usage is tracked for those with a quota. For anything else, it's counted up on the spot.
+    pub(crate) fn usage(
         &self,
-        dir_id: UfsUuid,
-    ) -> Result<DirectoryMetadata, failure::Error> {
-        debug!("--------");
-        debug!("`get_directory`: {:?}", dir_id);
-        if let Some(dir) = self.lookup_dir(dir_id) {
-            let mut dir = dir.clone();
-
-            // Populate the special "versions" directory.
-            if dir.is_vers_dir() {
-                let mut files = HashMap::<String, DirectoryEntry>::new();
-                if let Some(parent_dir_id) = dir.parent_id() {
-                    if let Some(parent_dir) = self.lookup_dir(parent_dir_id) {
-                        for (name, entry) in parent_dir.entries() {
-                            if let DirectoryEntry::File(file) = entry {
-                                for (index, version) in file.get_versions().iter() {
-                                    let mut name = name.clone();
-                                    name.push('@');
-                                    name.push_str(&index.to_string());
-                                    trace!("\tfound version {}", name);
-                                    // We want to create a new file that only consists of a single
-                                    // version, which is why we create a new one using
-                                    // as_file_metadata().
-                                    files.insert(
-                                        name,
-                                        DirectoryEntry::File(version.as_file_metadata(&file)),
-                                    );
-                                }
-                            }
-                        }
-
-                        dir.set_entries(files);
-                    }
+        target: &QuotaTarget,
+    ) -> Result<(FileSize, Option<FileSize>), failure::Error> {
+        let tracked = |quota: Quota| (quota.used(), Some(quota.limit()));
+        match target {
+            QuotaTarget::User(user) => {
+                if !self.users.get_users().contains(user) {
+                    return Err(IOFSErrorKind::NotFound.into());
                 }
+                let id = UfsUuid::new_user(user);
+                Ok(self.users.quota(id).map(tracked).unwrap_or_else(|| {
+                    let used: FileSize = self
+                        .files()
+                        .iter()
+                        .filter(|f| f.owner() == id)
+                        .map(|f| f.latest_size())
+                        .sum();
+                    (used, None)
+                }))
+            }
+            QuotaTarget::Directory(path) => {
+                let id = self.id_from_path(path).ok_or(IOFSErrorKind::NotFound)?;
+                let dir = self
+                    .lookup_dir(id)
+                    .ok_or_else(|| self.not_a_directory(id))?;
+                Ok(dir.quota().map(tracked).unwrap_or_else(|| {
+                    let depth = self.max_depth + 1;
+                    (
+                        live_size(dir, &self.files, depth, &mut HashSet::new()),
+                        None,
+                    )
+                }))
             }
-
-            trace!("\treturning {:#?}", dir);
-            Ok(dir)
-        } else {
-            Err(format_err!("unable to find directory with id {:?}", dir_id))
         }
     }
 
-    /// Create a new file
+    /// Hold `bytes` more against the quotas of a file's owner, and the directories holding it
     ///
-    pub(crate) fn new_file(&mut self, dir_id: UfsUuid, name: &str) -> Result<File, failure::Error> {
-        debug!("--------");
-        debug!("`new_file`: {}", name);
+    /// This is for bytes written to an open file, before it's committed. It fails if they would
+    /// put any of the quotas over, counting what's already held. The hold is returned, to be given
+    /// back with `release_quota` once the file is committed, and its bytes charged.
+    pub(crate) fn hold_quota(
+        &mut self,
+        id: UfsUuid,
+        bytes: FileSize,
+    ) -> Result<QuotaHold, failure::Error> {
+        let owner = match self.lookup_file(id) {
+            Some(file) => file.owner(),
+            None => return Err(self.not_a_file(id)),
+        };
+        let dirs = self.quota_dirs(id);
+        self.check_quota(owner, &dirs, bytes)?;
 
-        if let Some(root) = self.lookup_dir_mut(dir_id) {
-            let new_file = root.new_file(name.to_owned())?;
-            self.dirty = true;
-            Ok(File {
-                file_id: new_file.id(),
-                perms: new_file.unix_perms(),
-                version: new_file.get_latest(),
-            })
-        } else {
-            Err(format_err!("unable to find directory with id {:?}", dir_id))
-        }
+        let hold = QuotaHold { owner, dirs, bytes };
+        self.apply_hold(&hold, bytes as i64);
+        Ok(hold)
     }
 
-    /// Get FileMetadata
+    /// Give back bytes held by `hold_quota`
     ///
-    pub(crate) fn get_file_metadata(&self, id: UfsUuid) -> Result<FileMetadata, failure::Error> {
-        if let Some(file) = self.lookup_file(id) {
-            Ok(file.clone())
-        } else {
-            Err(format_err!("unable to find file with id {:?}", id))
+    pub(crate) fn release_quota(&mut self, hold: &QuotaHold) {
+        self.apply_hold(hold, -(hold.bytes as i64));
+    }
+
+    /// Hold `delta` bytes against the quotas named by `hold`
+    ///
+    fn apply_hold(&mut self, hold: &QuotaHold, delta: i64) {
+        self.users.hold(hold.owner, delta);
+        for id in &hold.dirs {
+            if self.lookup_dir(*id).and_then(|dir| dir.quota()).is_some() {
+                if let Some(dir) = self.lookup_dir_mut(*id) {
+                    dir.hold(delta);
+                }
+            }
         }
     }
 
-    /// Get DirectoryMetadata given a parent directory, and a name
+    /// Fail if a new file in `dir_id`, `bytes` long, would put it over quota
     ///
-    pub(crate) fn get_dir_metadata_from_dir_and_name(
+    /// A file belongs to the owner of its directory. The directory, or its owner, must have room
+    /// for at least one byte, even for an empty file.
+    pub(crate) fn check_new_file_quota(
         &self,
         dir_id: UfsUuid,
-        name: &str,
-    ) -> Result<DirectoryMetadata, failure::Error> {
-        if let Some(dir) = self.lookup_dir(dir_id) {
-            match dir.entries().get(name) {
-                Some(DirectoryEntry::Directory(d)) => Ok(d.clone()),
-                _ => Err(format_err!(
-                    "unable to find directory {} under directory {}",
-                    name,
-                    dir_id
-                )),
+        bytes: FileSize,
+    ) -> Result<(), failure::Error> {
+        match self.lookup_dir(dir_id) {
+            Some(dir) => {
+                let mut dirs = HashSet::new();
+                self.ancestors(dir_id, &mut dirs);
+                self.check_quota(dir.owner(), &dirs, bytes.max(1))
             }
-        } else {
-            Err(format_err!("unable to find directory with id {}", dir_id))
+            None => Ok(()),
         }
     }
 
-    /// Get FileMetadata given a parent directory, and a name
+    /// Fail if `bytes` more would put `owner`, or any of `dirs`, over quota
     ///
-    pub(crate) fn get_file_metadata_from_dir_and_name(
+    fn check_quota(
         &self,
-        dir_id: UfsUuid,
-        name: &str,
-    ) -> Result<FileMetadata, failure::Error> {
-        if let Some(dir) = self.lookup_dir(dir_id) {
-            match dir.entries().get(name) {
-                Some(DirectoryEntry::File(f)) => Ok(f.clone()),
-                _ => Err(format_err!(
-                    "unable to find file {} under directory {}",
+        owner: UfsUuid,
+        dirs: &HashSet<UfsUuid>,
+        bytes: FileSize,
+    ) -> Result<(), failure::Error> {
+        let quotas = dirs
+            .iter()
+            .filter_map(|id| self.lookup_dir(*id).and_then(|dir| dir.quota()))
+            .chain(self.users.quota(owner));
+        for quota in quotas {
+            if !quota.allows(bytes) {
+                warn!(
+                    "{} more bytes would exceed quota of {}, with {} used, and {} held",
+                    bytes,
+                    quota.limit(),
+                    quota.used(),
+                    quota.held()
+                );
+                return Err(IOFSErrorKind::QuotaExceeded.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `dir_id` and the directories above it to `dirs`
+    ///
+    fn ancestors(&self, dir_id: UfsUuid, dirs: &mut HashSet<UfsUuid>) {
+        let mut next = Some(dir_id);
+        let mut depth = 0;
+        while let Some(id) = next {
+            // Once we reach a directory that's already there, so are those above it.
+            if depth > self.max_depth + 1 || !dirs.insert(id) {
+                break;
+            }
+            next = self.lookup_dir(id).and_then(|dir| dir.parent_id());
+            depth += 1;
+        }
+    }
+
+    /// Return the directories that a file counts against
+    ///
+    /// These are the directories that hold the file, by any of its links, and those above them.
+    fn quota_dirs(&self, id: UfsUuid) -> HashSet<UfsUuid> {
+        let mut dirs = HashSet::new();
+        if let Some(file) = self.lookup_file(id) {
+            for dir_id in file.link_dirs() {
+                self.ancestors(*dir_id, &mut dirs);
+            }
+        }
+        dirs
+    }
+
+    /// Count `delta` bytes against the quotas of `dirs`
+    ///
+    fn charge_dirs<'a, I: IntoIterator<Item = &'a UfsUuid>>(&mut self, dirs: I, delta: i64) {
+        for id in dirs {
+            if self.lookup_dir(*id).and_then(|dir| dir.quota()).is_some() {
+                if let Some(dir) = self.lookup_dir_mut(*id) {
+                    dir.charge(delta);
+                }
+            }
+        }
+    }
+
+    /// Move `size` bytes from the quotas of the directories in `before`, to those in `after`
+    ///
+    /// Directories in both are left as they are.
+    fn recharge_dirs(
+        &mut self,
+        before: &HashSet<UfsUuid>,
+        after: &HashSet<UfsUuid>,
+        size: FileSize,
+    ) {
+        if size == 0 {
+            return;
+        }
+        self.charge_dirs(before.difference(after), -(size as i64));
+        self.charge_dirs(after.difference(before), size as i64);
+    }
+
+    /// Count `delta` bytes against the quotas of a file's owner, and the directories holding it
+    ///
+    fn charge_file(&mut self, id: UfsUuid, delta: i64) {
+        if delta == 0 {
+            return;
+        }
+        if let Some(owner) = self.lookup_file(id).map(|file| file.owner()) {
+            let dirs = self.quota_dirs(id);
+            self.users.charge(owner, delta);
+            self.charge_dirs(&dirs, delta);
+            self.dirty = true;
+        }
+    }
+
+    /// Forget a hard link to the file `id`, in `dir_id`, that's been taken out of the tree
+    ///
+    /// `before` are the directories that the file counted against while it had the link. Once the
+    /// last link is gone, so is the file, and its owner gets its bytes back. Its blocks are then
+    /// returned, for the caller to recycle.
+    fn drop_link(
+        &mut self,
+        id: UfsUuid,
+        dir_id: UfsUuid,
+        before: &HashSet<UfsUuid>,
+    ) -> Vec<BlockNumber> {
+        self.dirty = true;
+        if self.lookup_file(id).map_or(false, |file| file.links() == 1) {
+            return match self.files.remove(&id) {
+                Some(file) => {
+                    let size = file.latest_size() as i64;
+                    self.users.charge(file.owner(), -size);
+                    self.charge_dirs(before, -size);
+                    file.all_blocks()
+                }
+                None => vec![],
+            };
+        }
+
+        let size = match self.files.get_mut(&id) {
+            Some(file) => {
+                file.remove_link(dir_id);
+                debug!("\t{} links remain", file.links());
+                file.latest_size()
+            }
+            None => return vec![],
+        };
+
+        let after = self.quota_dirs(id);
+        self.recharge_dirs(before, &after, size);
+        vec![]
+    }
+
+    /// Return the directories that an entry in `parent_id` counts against, and its size
+    ///
+    fn entry_charge(
+        &self,
+        entry: &DirectoryEntry,
+        parent_id: UfsUuid,
+    ) -> (HashSet<UfsUuid>, FileSize) {
+        let mut dirs = HashSet::new();
+        match entry {
+            DirectoryEntry::File(_) | DirectoryEntry::FileLink(_) => {
+                let id = entry.id();
+                let size = self.lookup_file(id).map_or(0, |f| f.latest_size());
+                (self.quota_dirs(id), size)
+            }
+            DirectoryEntry::Directory(d) => {
+                self.ancestors(parent_id, &mut dirs);
+                let depth = self.max_depth + 1;
+                (dirs, live_size(d, &self.files, depth, &mut HashSet::new()))
+            }
+            DirectoryEntry::Symlink(_) => (dirs, 0),
+        }
+    }
+
+    /// Return the depth of a directory
+    ///
+    /// The root is at depth 0. `None` is returned if the directory doesn't exist, or if it's
+    /// deeper than we are willing to look.
+    fn depth_of(&self, id: UfsUuid) -> Option<usize> {
+        let mut depth = 0;
+        let mut dir = self.lookup_dir(id)?;
+        while let Some(parent_id) = dir.parent_id() {
+            depth += 1;
+            if depth > self.max_depth + 1 {
+                return None;
+            }
+            dir = self.lookup_dir(parent_id)?;
+        }
+        Some(depth)
+    }
+
+    /// Create a new user
+    ///
+    pub(crate) fn add_user(&mut self, user: String, password: String) {
+        debug!("-------");
+        debug!("`new_user`: {}", user);
+
+        self.dirty = true;
+        self.users.new_user(user, password);
+    }
+
+    /// Get a user
+    ///
+    /// If successful, return a tuple of the user's id and their key.
+    ///
+    pub(crate) fn get_user<S: AsRef<str>>(
+        &self,
+        user: S,
+        password: S,
+    ) -> Option<(UfsUuid, [u8; 32])> {
+        self.users.get_user(&user, &password)
+    }
+
+    /// Return a list of existing users
+    ///
+    pub(crate) fn get_users(&self) -> Vec<String> {
+        self.users.get_users()
+    }
+
+    /// Remove a user
+    ///
+    /// Everything the user owns is given to the owner of the root directory, who may not be
+    /// removed. The removed user's id is returned.
+    pub(crate) fn remove_user<S: AsRef<str>>(
+        &mut self,
+        user: S,
+    ) -> Result<UfsUuid, failure::Error> {
+        debug!("-------");
+        debug!("`remove_user`: {}", user.as_ref());
+
+        fn chown(dir: &mut DirectoryMetadata, from: UfsUuid, to: UfsUuid) {
+            if dir.owner() == from {
+                dir.set_owner(to);
+            }
+            for entry in dir.entries_mut().values_mut() {
+                match entry {
+                    DirectoryEntry::Directory(d) => chown(d, from, to),
+                    DirectoryEntry::Symlink(s) if s.owner() == from => s.set_owner(to),
+                    _ => (),
+                }
+            }
+        }
+
+        let owner = self.root_directory.owner();
+        if UfsUuid::new_user(user.as_ref()) == owner {
+            return Err(format_err!(
+                "the owner of the file system may not be removed"
+            ));
+        }
+
+        match self.users.remove_user(&user) {
+            Some(id) => {
+                let given: FileSize = self
+                    .files()
+                    .iter()
+                    .filter(|f| f.owner() == id)
+                    .map(|f| f.latest_size())
+                    .sum();
+                self.users.charge(owner, given as i64);
+                chown(&mut self.root_directory, id, owner);
+                for file in self.files.values_mut().filter(|f| f.owner() == id) {
+                    file.set_owner(owner);
+                }
+                self.dirty = true;
+                Ok(id)
+            }
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Change a user's password
+    ///
+    /// The user's id is returned.
+    pub(crate) fn change_password<S: AsRef<str>>(
+        &mut self,
+        user: S,
+        old: S,
+        new: S,
+    ) -> Result<UfsUuid, failure::Error> {
+        debug!("-------");
+        debug!("`change_password`: {}", user.as_ref());
+
+        match self.users.change_password(user, old, new) {
+            Some(id) => {
+                self.dirty = true;
+                Ok(id)
+            }
+            None => Err(format_err!("Invalid user id or password.")),
+        }
+    }
+
+    /// Add a Wasm program to the grants
+    ///
+    pub(crate) fn add_wasm_program_grants(&mut self, program: PathBuf) {
+        self.grants.add_program(program);
+    }
+
+    /// Remove the grants for a Wasm program
+    ///
+    pub(crate) fn remove_wasm_program_grants(&mut self, program: &PathBuf) {
+        self.grants.remove_program(program);
+    }
+
+    /// Check Wasm program grant
+    ///
+    pub(crate) fn check_wasm_program_grant(
+        &mut self,
+        program: &PathBuf,
+        grant_type: GrantType,
+    ) -> Option<Grant> {
+        self.grants.check_grant(program, grant_type)
+    }
+
+    /// Look up a Wasm program grant, without asking the user
+    ///
+    /// `Unknown` is returned for a grant that `check_wasm_program_grant` would ask about.
+    pub(crate) fn peek_wasm_program_grant(
+        &self,
+        program: &Path,
+        grant_type: GrantType,
+    ) -> Option<Grant> {
+        self.grants.peek_grant(program, grant_type)
+    }
+
+    /// Check whether a Wasm program may change the file system, now or once the user is asked
+    ///
+    pub(crate) fn wasm_program_may_write(&self, program: &Path) -> bool {
+        self.grants.may_write(program)
+    }
+
+    /// Set Wasm program grant
+    ///
+    /// This bypasses asking the user, and has no effect if the program has no grants.
+    #[cfg(test)]
+    pub(crate) fn set_wasm_program_grant(
+        &mut self,
+        program: &Path,
+        grant_type: GrantType,
+        grant: Grant,
+    ) {
+        self.grants.set_grant(program, grant_type, grant);
+    }
+
+    /// Check Wasm program HTTP grant
+    ///
+    pub(crate) fn check_wasm_program_http_grant(
+        &mut self,
+        program: &PathBuf,
+        grant_type: GrantType,
+        route: &str,
+    ) -> Option<Grant> {
+        self.grants.check_http_grant(program, grant_type, route)
+    }
+
+    /// Return a value stored by a Wasm program
+    ///
+    pub(crate) fn get_wasm_program_value(&self, program: &Path, key: &str) -> Option<String> {
+        self.store.get(program, key).map(|value| value.to_owned())
+    }
+
+    /// Store a value on behalf of a Wasm program
+    ///
+    pub(crate) fn put_wasm_program_value(
+        &mut self,
+        program: &Path,
+        key: String,
+        value: String,
+    ) -> Result<(), failure::Error> {
+        self.store.put(program, key, value)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Remove the values stored by a Wasm program
+    ///
+    pub(crate) fn remove_wasm_program_values(&mut self, program: &Path) {
+        self.dirty = true;
+        self.store.remove_program(program);
+    }
+
+    /// Create a new directory
+    ///
+    pub(crate) fn new_directory(
+        &mut self,
+        dir_id: UfsUuid,
+        name: &str,
+        owner: UfsUuid,
+    ) -> Result<DirectoryMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`new_directory`: {}", name);
+
+        match self.depth_of(dir_id) {
+            Some(depth) if depth >= self.max_depth => {
+                warn!(
+                    "{} would be deeper than {} directories",
+                    name, self.max_depth
+                );
+                return Err(IOFSErrorKind::PathTooDeep.into());
+            }
+            _ => (),
+        }
+
+        if let Some(root) = self.lookup_dir_mut(dir_id) {
+            let new_dir = root.new_subdirectory(name.to_owned(), owner)?;
+            self.index
+                .insert_dir(&new_dir, dir_id, name, self.max_depth + 2);
+            self.dirty = true;
+            debug!("\tcreated directory with id {:?}", dir_id);
+            Ok(new_dir)
+        } else {
+            Err(self.not_a_directory(dir_id))
+        }
+    }
+
+    /// Retrieve a directory
+    ///
+    pub(crate) fn get_directory(
+        &self,
+        dir_id: UfsUuid,
+    ) -> Result<DirectoryMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`get_directory`: {:?}", dir_id);
+        if let Some(dir) = self.lookup_dir(dir_id) {
+            let mut dir = dir.clone();
+
+            // Populate the special "versions" directory.
+            if dir.is_vers_dir() {
+                let mut files = HashMap::<String, DirectoryEntry>::new();
+                if let Some(parent_dir_id) = dir.parent_id() {
+                    if let Some(parent_dir) = self.lookup_dir(parent_dir_id) {
+                        for (name, entry) in parent_dir.entries() {
+                            if let Some(file) = self.linked_file(entry) {
+                                for (index, version) in file.get_versions().iter() {
+                                    let mut name = name.clone();
+                                    name.push('@');
+                                    name.push_str(&index.to_string());
+                                    trace!("\tfound version {}", name);
+                                    // We want to create a new file that only consists of a single
+                                    // version, which is why we create a new one using
+                                    // as_file_metadata().
+                                    files.insert(
+                                        name,
+                                        DirectoryEntry::File(version.as_file_metadata(&file)),
+                                    );
+                                }
+                            }
+                        }
+
+                        dir.set_entries(files);
+                    }
+                }
+            } else {
+                // List the files themselves, rather than the links to them.
+                let entries = dir
+                    .entries()
+                    .iter()
+                    .map(|(name, entry)| match self.linked_file(entry) {
+                        Some(file) => (name.clone(), DirectoryEntry::File(file.clone())),
+                        None => (name.clone(), entry.clone()),
+                    })
+                    .collect();
+                dir.set_entries(entries);
+            }
+
+            trace!("\treturning {:#?}", dir);
+            Ok(dir)
+        } else {
+            Err(format_err!("unable to find directory with id {:?}", dir_id))
+        }
+    }
+
+    /// Return the file that a hard link refers to
+    ///
+    fn linked_file(&self, entry: &DirectoryEntry) -> Option<&FileMetadata> {
+        match entry {
+            DirectoryEntry::FileLink(id) => {
+                let file = self.lookup_file(*id);
+                if file.is_none() {
+                    warn!("link to missing file {:?}", id);
+                }
+                file
+            }
+            _ => None,
+        }
+    }
+
+    /// Create a new file
+    ///
+    pub(crate) fn new_file(&mut self, dir_id: UfsUuid, name: &str) -> Result<File, failure::Error> {
+        debug!("--------");
+        debug!("`new_file`: {}", name);
+
+        if let Some(root) = self.lookup_dir_mut(dir_id) {
+            let new_file = root.new_file(name.to_owned())?;
+            let file = File {
+                file_id: new_file.id(),
+                perms: new_file.unix_perms(),
+                version: new_file.get_latest(),
+            };
+            self.files.insert(new_file.id(), new_file);
+            self.dirty = true;
+            Ok(file)
+        } else {
+            Err(self.not_a_directory(dir_id))
+        }
+    }
+
+    /// Create a new symbolic link
+    ///
+    pub(crate) fn new_symlink<P: AsRef<Path>>(
+        &mut self,
+        dir_id: UfsUuid,
+        name: &str,
+        target: P,
+    ) -> Result<SymlinkMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`new_symlink`: {} -> {:?}", name, target.as_ref());
+
+        if let Some(root) = self.lookup_dir_mut(dir_id) {
+            let link = root.new_symlink(name.to_owned(), target.as_ref().to_path_buf())?;
+            self.index.insert(link.id(), dir_id, name);
+            self.dirty = true;
+            Ok(link)
+        } else {
+            Err(self.not_a_directory(dir_id))
+        }
+    }
+
+    /// Get FileMetadata
+    ///
+    pub(crate) fn get_file_metadata(&self, id: UfsUuid) -> Result<FileMetadata, failure::Error> {
+        if let Some(file) = self.lookup_file(id) {
+            Ok(file.clone())
+        } else {
+            Err(format_err!("unable to find file with id {:?}", id))
+        }
+    }
+
+    /// Get DirectoryMetadata given a parent directory, and a name
+    ///
+    pub(crate) fn get_dir_metadata_from_dir_and_name(
+        &self,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<DirectoryMetadata, failure::Error> {
+        if let Some(dir) = self.lookup_dir(dir_id) {
+            match dir.entries().get(name) {
+                Some(DirectoryEntry::Directory(d)) => Ok(d.clone()),
+                _ => Err(format_err!(
+                    "unable to find directory {} under directory {}",
                     name,
                     dir_id
                 )),
             }
         } else {
-            Err(format_err!("unable to find directory with id {}", dir_id))
+            Err(format_err!("unable to find directory with id {}", dir_id))
+        }
+    }
+
+    /// Get FileMetadata given a parent directory, and a name
+    ///
+    pub(crate) fn get_file_metadata_from_dir_and_name(
+        &self,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<FileMetadata, failure::Error> {
+        if let Some(dir) = self.lookup_dir(dir_id) {
+            match dir.entries().get(name).and_then(|entry| self.linked_file(entry)) {
+                Some(f) => Ok(f.clone()),
+                None => Err(format_err!(
+                    "unable to find file {} under directory {}",
+                    name,
+                    dir_id
+                )),
+            }
+        } else {
+            Err(format_err!("unable to find directory with id {}", dir_id))
+        }
+    }
+
+    /// Get a file for read-only access
+    ///
+    pub(crate) fn get_file_read_only(&self, id: UfsUuid) -> Result<File, failure::Error> {
+        debug!("--------");
+        debug!("`get_file_read_only: {:?}", id);
+
+        if let Some(file) = self.lookup_file(id) {
+            Ok(File {
+                file_id: file.id(),
+                perms: file.unix_perms(),
+                version: file.get_latest(),
+            })
+        } else {
+            Err(self.not_a_file(id))
+        }
+    }
+
+    /// Get a file for read-write access
+    ///
+    pub(crate) fn get_file_read_write(&mut self, id: UfsUuid) -> Result<File, failure::Error> {
+        debug!("--------");
+        debug!("`get_file_read_write: {:?}", id);
+
+        if let Some(file) = self.lookup_file_mut(id) {
+            Ok(File {
+                file_id: file.id(),
+                perms: file.unix_perms(),
+                version: file.get_latest(),
+            })
+        } else {
+            Err(self.not_a_file(id))
+        }
+    }
+
+    /// Get a file for write-only access
+    ///
+    pub(crate) fn get_file_write_only(&mut self, id: UfsUuid) -> Result<File, failure::Error> {
+        debug!("--------");
+        debug!("`get_file_write_only: {:?}", id);
+
+        let (file, was) = if let Some(file) = self.lookup_file_mut(id) {
+            let was = file.latest_size();
+            let file = File {
+                file_id: file.id(),
+                perms: file.unix_perms(),
+                version: file.new_version(),
+            };
+            (file, was)
+        } else {
+            return Err(self.not_a_file(id));
+        };
+
+        // The file starts over, empty.
+        self.charge_file(id, -(was as i64));
+        Ok(file)
+    }
+
+    /// Build the error for an id that was expected to be a directory
+    ///
+    /// It's more helpful to know that the id is a file, rather than that it just wasn't found.
+    fn not_a_directory(&self, id: UfsUuid) -> failure::Error {
+        if self.lookup_file(id).is_some() {
+            warn!("{:?} is a file, not a directory", id);
+            IOFSErrorKind::NotADirectory.into()
+        } else {
+            warn!("unable to find directory with id {:?}", id);
+            IOFSErrorKind::NotFound.into()
+        }
+    }
+
+    /// Build the error for an id that was expected to be a file
+    ///
+    fn not_a_file(&self, id: UfsUuid) -> failure::Error {
+        if self.lookup_dir(id).is_some() {
+            warn!("{:?} is a directory, not a file", id);
+            IOFSErrorKind::NotAFile.into()
+        } else {
+            warn!("unable to find file with id {:?}", id);
+            IOFSErrorKind::NotFound.into()
+        }
+    }
+
+    /// Commit changes to an open file
+    ///
+    /// Versions that fall outside of the retention policy, the directory's, or failing that, the
+    /// file system's, are pruned. The blocks used only by those versions are returned, for the
+    /// caller to recycle.
+    pub(crate) fn commit_file(&mut self, f: File) -> Result<Vec<BlockNumber>, failure::Error> {
+        debug!("--------");
+        debug!("`commit_file`: {:?}", f);
+
+        if f.version.is_dirty() {
+            let default = self.retention;
+            let retention = match self.lookup_file(f.file_id) {
+                Some(file) => self
+                    .lookup_dir(file.dir_id())
+                    .and_then(|dir| dir.retention())
+                    .or(default),
+                None => return Err(format_err!("unable to find file {:#?}", f)),
+            };
+
+            let file = self.lookup_file_mut(f.file_id).unwrap();
+            let was = file.latest_size();
+            file.commit_version(f.version.clone())?;
+            let pruned = match retention {
+                Some(retention) => file.apply_retention(retention),
+                None => vec![],
+            };
+            let freed = unique_blocks(file, &pruned);
+            if !pruned.is_empty() {
+                debug!("\tpruned {} versions of {:?}", pruned.len(), f.file_id);
+            }
+
+            self.charge_file(f.file_id, f.version.size() as i64 - was as i64);
+            self.dirty = true;
+            Ok(freed)
+        } else {
+            Ok(vec![])
         }
     }
 
-    /// Get a file for read-only access
+    /// Add a hard link to a file
     ///
-    pub(crate) fn get_file_read_only(&self, id: UfsUuid) -> Result<File, failure::Error> {
+    /// The file `id` gains an entry called `name` in the directory `dir_id`. Both entries refer to
+    /// the same file, and its link count is bumped.
+    pub(crate) fn link_file(
+        &mut self,
+        id: UfsUuid,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<FileMetadata, failure::Error> {
         debug!("--------");
-        debug!("`get_file_read_only: {:?}", id);
+        debug!("`link_file`: {:?} as {}, dir: {:?}", id, name, dir_id);
 
-        if let Some(file) = self.lookup_file(id) {
-            Ok(File {
-                file_id: file.id(),
-                perms: file.unix_perms(),
-                version: file.get_latest(),
-            })
+        if self.lookup_file(id).is_none() {
+            return Err(self.not_a_file(id));
+        }
+
+        match self.lookup_dir(dir_id) {
+            Some(dir) => {
+                // The contents of ".vers" are made up on the fly.
+                if dir.is_vers_dir() {
+                    return Err(IOFSErrorKind::SpecialDirectory.into());
+                }
+                if dir.entries().contains_key(name) {
+                    return Err(IOFSErrorKind::FileExists.into());
+                }
+            }
+            None => return Err(self.not_a_directory(dir_id)),
+        }
+
+        let before = self.quota_dirs(id);
+        if let Some(dir) = self.lookup_dir_mut(dir_id) {
+            dir.entries_mut()
+                .insert(name.to_owned(), DirectoryEntry::FileLink(id));
+        }
+        let file = match self.files.get_mut(&id) {
+            Some(file) => {
+                file.add_link(dir_id);
+                file.clone()
+            }
+            None => return Err(IOFSErrorKind::NotFound.into()),
+        };
+        self.dirty = true;
+
+        let after = self.quota_dirs(id);
+        self.recharge_dirs(&before, &after, file.latest_size());
+
+        Ok(file)
+    }
+
+    /// Remove a directory
+    ///
+    pub(crate) fn remove_directory(
+        &mut self,
+        parent_id: UfsUuid,
+        name: &str,
+    ) -> Result<(), failure::Error> {
+        debug!("--------");
+        debug!("`remove_directory`: {}, parent: {:#?}", name, parent_id);
+
+        if let Some(parent) = self.lookup_dir_mut(parent_id) {
+            match parent.entries().get(name) {
+                Some(DirectoryEntry::Directory(dir)) => {
+                    // The special directories go away with their parent, and not before.
+                    if dir.is_wasm_dir() || dir.is_vers_dir() {
+                        return Err(IOFSErrorKind::SpecialDirectory.into());
+                    }
+                    if !dir.is_empty() {
+                        return Err(IOFSErrorKind::DirectoryNotEmpty.into());
+                    }
+                }
+                Some(_) => return Err(IOFSErrorKind::NotADirectory.into()),
+                None => return Err(IOFSErrorKind::NotFound.into()),
+            }
+
+            let dir = parent.entries_mut().remove(name);
+            debug!("\tremoved {:#?}\n\tfrom {:#?}", dir, parent);
+            if let Some(dir) = dir {
+                self.index.remove_entry(&dir, parent_id, name);
+            }
+            self.dirty = true;
+            Ok(())
         } else {
-            Err(format_err!("unable to find file with id {:?}", id))
+            Err(format_err!("unable to find directory {:#?}", parent_id))
         }
     }
 
-    /// Get a file for read-write access
+    /// Check that a directory may be moved under `new_parent_id`
     ///
-    pub(crate) fn get_file_read_write(&mut self, id: UfsUuid) -> Result<File, failure::Error> {
+    /// A directory may not be moved inside itself, nor may it end up too deep.
+    fn check_directory_move(
+        &self,
+        name: &str,
+        dir: &DirectoryMetadata,
+        new_parent_id: UfsUuid,
+    ) -> Result<(), failure::Error> {
+        if dir.id() == new_parent_id || dir.lookup_dir(new_parent_id, self.max_depth + 1).is_some()
+        {
+            return Err(format_err!("cannot move {} inside itself", name));
+        }
+
+        match self.depth_of(new_parent_id) {
+            Some(depth) if depth + dir.height(self.max_depth + 1) <= self.max_depth => Ok(()),
+            _ => Err(IOFSErrorKind::PathTooDeep.into()),
+        }
+    }
+
+    /// Move, and/or rename, a directory entry
+    ///
+    /// The entry `name` in `parent_id` becomes `new_name` in `new_parent_id`, and keeps its id.
+    /// An existing `new_name` is replaced, so long as it's a file being replaced by a file, or an
+    /// empty directory being replaced by a directory. Like `unlink_file`, the blocks of a replaced
+    /// file are returned, so that the caller may recycle them.
+    pub(crate) fn rename_entry(
+        &mut self,
+        parent_id: UfsUuid,
+        name: &str,
+        new_parent_id: UfsUuid,
+        new_name: &str,
+    ) -> Result<(DirectoryEntry, Vec<BlockNumber>), failure::Error> {
         debug!("--------");
-        debug!("`get_file_read_write: {:?}", id);
+        debug!(
+            "`rename_entry`: {}, parent: {:?} to {}, parent: {:?}",
+            name, parent_id, new_name, new_parent_id
+        );
 
-        if let Some(file) = self.lookup_file_mut(id) {
-            Ok(File {
-                file_id: file.id(),
-                perms: file.unix_perms(),
-                version: file.get_latest(),
-            })
+        let entry = match self.lookup_dir(parent_id) {
+            Some(parent) => match parent.entries().get(name) {
+                Some(entry) => entry.clone(),
+                None => return Err(IOFSErrorKind::NotFound.into()),
+            },
+            None => return Err(self.not_a_directory(parent_id)),
+        };
+
+        if parent_id == new_parent_id && name == new_name {
+            return Ok((entry, vec![]));
+        }
+
+        if let DirectoryEntry::Directory(dir) = &entry {
+            if dir.is_wasm_dir() || dir.is_vers_dir() {
+                return Err(IOFSErrorKind::SpecialDirectory.into());
+            }
+            self.check_directory_move(name, dir, new_parent_id)?;
+        }
+
+        let existing = match self.lookup_dir(new_parent_id) {
+            Some(new_parent) => new_parent.entries().get(new_name),
+            None => return Err(self.not_a_directory(new_parent_id)),
+        };
+        match (&entry, existing) {
+            (_, None) => (),
+            (DirectoryEntry::Directory(_), Some(DirectoryEntry::Directory(d))) => {
+                if d.is_wasm_dir() || d.is_vers_dir() {
+                    return Err(IOFSErrorKind::SpecialDirectory.into());
+                }
+                if !d.is_empty() {
+                    return Err(IOFSErrorKind::DirectoryNotEmpty.into());
+                }
+            }
+            (DirectoryEntry::Directory(_), Some(_)) => {
+                return Err(IOFSErrorKind::NotADirectory.into())
+            }
+            (_, Some(DirectoryEntry::Directory(_))) => {
+                return Err(IOFSErrorKind::IsADirectory.into())
+            }
+            // Two links to the same file: there's nothing to do.
+            (DirectoryEntry::FileLink(f), Some(DirectoryEntry::FileLink(g))) if f == g => {
+                return Ok((entry.clone(), vec![]))
+            }
+            (_, Some(_)) => (),
+        }
+
+        // Where the entry, and any file that it replaces, count against quotas before the move
+        let (before, size) = self.entry_charge(&entry, parent_id);
+        let replaced_before = match existing {
+            Some(DirectoryEntry::FileLink(id)) => self.quota_dirs(*id),
+            _ => HashSet::new(),
+        };
+
+        let mut entry = match self.lookup_dir_mut(parent_id) {
+            Some(parent) => match parent.entries_mut().remove(name) {
+                Some(entry) => entry,
+                None => return Err(format_err!("did not find {} in {:?}", name, parent_id)),
+            },
+            None => return Err(self.not_a_directory(parent_id)),
+        };
+        match &mut entry {
+            DirectoryEntry::Directory(d) => d.set_parent_id(new_parent_id),
+            DirectoryEntry::Symlink(s) => s.set_dir_id(new_parent_id),
+            DirectoryEntry::File(_) | DirectoryEntry::FileLink(_) => (),
+        }
+        if let DirectoryEntry::FileLink(id) = &entry {
+            if let Some(file) = self.files.get_mut(id) {
+                file.move_link(parent_id, new_parent_id);
+            }
+        }
+
+        let replaced = match self.lookup_dir_mut(new_parent_id) {
+            Some(new_parent) => new_parent
+                .entries_mut()
+                .insert(new_name.to_owned(), entry.clone()),
+            None => None,
+        };
+        if let Some(replaced) = &replaced {
+            self.index.remove_entry(replaced, new_parent_id, new_name);
+        }
+        // Files are found in the file table, rather than through the index.
+        if !entry.is_file() {
+            self.index.insert(entry.id(), new_parent_id, new_name);
+        }
+        self.dirty = true;
+
+        let (after, _) = self.entry_charge(&entry, new_parent_id);
+        self.recharge_dirs(&before, &after, size);
+
+        let mut blocks = vec![];
+        if let Some(DirectoryEntry::FileLink(id)) = replaced {
+            debug!("\treplaced {:?}", id);
+            blocks = self.drop_link(id, new_parent_id, &replaced_before);
+        }
+
+        Ok((entry, blocks))
+    }
+
+    /// Remove a file from a directory
+    ///
+    /// The file's blocks are returned, for the caller to recycle, once the last link to it is
+    /// removed.
+    pub(crate) fn unlink_file(
+        &mut self,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<Vec<BlockNumber>, failure::Error> {
+        debug!("--------");
+        debug!("`unlink_file`: {}, dir: {:#?}", name, dir_id);
+
+        // Where the file counts against quotas while it's still here
+        let before = match self
+            .lookup_dir(dir_id)
+            .and_then(|dir| dir.entries().get(name))
+        {
+            Some(DirectoryEntry::FileLink(id)) => self.quota_dirs(*id),
+            _ => HashSet::new(),
+        };
+
+        if let Some(dir) = self.lookup_dir_mut(dir_id) {
+            // If this is a file in the special versions directory, then we are removing a version
+            // from the parent.
+            if dir.is_vers_dir() {
+                match dir.parent_id() {
+                    Some(parent_id) => self.remove_file_version(parent_id, name),
+                    None => Err(format_err!("versions directory {:?} has no parent", dir_id)),
+                }
+            } else {
+                match dir.entries_mut().remove(name) {
+                    Some(DirectoryEntry::FileLink(id)) => {
+                        debug!("\tremoved {:?}\n\tfrom {:#?}", id, dir);
+                        // Once the last link is gone, all of the blocks, for all of the versions of
+                        // the file, are returned as a single list to be deleted by the caller.
+                        Ok(self.drop_link(id, dir_id, &before))
+                    }
+                    Some(DirectoryEntry::Symlink(link)) => {
+                        debug!("\tremoved {:#?}\n\tfrom {:#?}", link, dir);
+                        self.index.remove(link.id(), dir_id, name);
+                        self.dirty = true;
+                        Ok(vec![])
+                    }
+                    _ => Err(format_err!("did not find {} in {:#?}", name, dir)),
+                }
+            }
         } else {
-            Err(format_err!("unable to find file with id {:?}", id))
+            Err(format_err!("unable to find directory {:#?}", dir_id))
+        }
+    }
+
+    /// Remove one version of a file
+    ///
+    /// The version is named as it is in the versions directory, e.g., "notes.txt@2", and the file
+    /// is found in `dir_id`. The latest version of a file may not be removed. The blocks used by no
+    /// other version are returned, for the caller to recycle.
+    fn remove_file_version(
+        &mut self,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<Vec<BlockNumber>, failure::Error> {
+        let mut parts = name.rsplitn(2, '@');
+        let (index, file_name) = match (parts.next().map(|i| i.parse::<usize>()), parts.next()) {
+            (Some(Ok(index)), Some(file_name)) => (index, file_name),
+            _ => return Err(IOFSErrorKind::NotFound.into()),
+        };
+        let id = match self
+            .lookup_dir(dir_id)
+            .and_then(|dir| dir.entries().get(file_name))
+        {
+            Some(DirectoryEntry::FileLink(id)) => *id,
+            _ => return Err(IOFSErrorKind::NotFound.into()),
+        };
+
+        let file = self.lookup_file_mut(id).ok_or(IOFSErrorKind::NotFound)?;
+        if index == file.latest_version() {
+            return Err(IOFSErrorKind::LatestVersion.into());
+        }
+        let version = file.remove_version(index).ok_or(IOFSErrorKind::NotFound)?;
+        debug!("\tremoved version {} of {:?}", index, id);
+
+        let freed = unique_blocks(file, &[version]);
+        self.dirty = true;
+
+        Ok(freed)
+    }
+
+    /// Prune old versions of files, oldest first, to free up `wanted` blocks
+    ///
+    /// The latest version of a file is never pruned. Blocks that are shared with a remaining
+    /// version, or that are in `in_use`, e.g. by an open file, are not freed, but the version is
+    /// still removed. Pruning stops as soon as enough blocks are freed, or there's nothing left to
+    /// prune. The freed blocks are returned, for the caller to recycle.
+    pub(crate) fn prune_versions(
+        &mut self,
+        wanted: usize,
+        in_use: &HashSet<BlockNumber>,
+    ) -> Vec<BlockNumber> {
+        debug!("--------");
+        debug!("`prune_versions`: {} blocks wanted", wanted);
+
+        let mut versions = vec![];
+        for f in self.files.values() {
+            for (index, v) in f.get_versions() {
+                if *index != f.latest_version() {
+                    versions.push((v.write_time(), f.id(), *index));
+                }
+            }
+        }
+        versions.sort_by_key(|(time, _, index)| (*time, *index));
+
+        let mut freed = vec![];
+        for (_, id, index) in versions {
+            if freed.len() >= wanted {
+                break;
+            }
+
+            let file = match self.lookup_file_mut(id) {
+                Some(file) => file,
+                None => continue,
+            };
+            if let Some(version) = file.remove_version(index) {
+                debug!("\tpruned version {} of {:?}", index, id);
+                for b in version.blocks() {
+                    let shared = file.get_versions().values().any(|v| v.blocks().contains(b));
+                    if !shared && !in_use.contains(b) && !freed.contains(b) {
+                        freed.push(*b);
+                    }
+                }
+                self.dirty = true;
+            }
+        }
+
+        freed
+    }
+
+    /// Return the files that no directory in the tree links to
+    ///
+    /// Such a file is still in the file table, and its blocks are still in use, but it can't be
+    /// reached by name. It may be given back to `reconstruct_tree`.
+    pub(crate) fn unlinked_files(&self) -> Vec<FileMetadata> {
+        fn collect(dir: &DirectoryMetadata, depth: usize, linked: &mut HashSet<UfsUuid>) {
+            if depth == 0 {
+                return;
+            }
+            for entry in dir.entries().values() {
+                match entry {
+                    DirectoryEntry::FileLink(id) => {
+                        linked.insert(*id);
+                    }
+                    DirectoryEntry::Directory(d) => collect(d, depth - 1, linked),
+                    _ => (),
+                }
+            }
+        }
+
+        let mut linked = HashSet::new();
+        collect(&self.root_directory, self.max_depth + 2, &mut linked);
+        self.files
+            .values()
+            .filter(|file| !linked.contains(&file.id()))
+            .cloned()
+            .collect()
+    }
+
+    /// Re-link recovered metadata into the directory tree
+    ///
+    /// The `nodes` have been salvaged from storage, or from the file table, but are no longer
+    /// reachable from the root directory. Each still knows the id of its parent, via `dir_id` or
+    /// `parent_id`, so it's put back in its parent as soon as the parent is in the tree.
+    /// Re-linking a directory brings along everything under it. A node's name lived in its
+    /// parent's entries, so it's named after its id. Nodes whose parent can't be found are placed
+    /// in `/lost+found`.
+    ///
+    /// Nodes that are already in the tree are ignored. The number of nodes re-linked is returned.
+    pub(crate) fn reconstruct_tree(
+        &mut self,
+        nodes: Vec<DirectoryEntry>,
+    ) -> Result<usize, failure::Error> {
+        debug!("--------");
+        debug!("`reconstruct_tree`: {} nodes", nodes.len());
+
+        // A file is linked if one of the directories it lists holds a link to it.
+        fn is_linked(metadata: &Metadata, id: UfsUuid) -> bool {
+            let link = DirectoryEntry::FileLink(id);
+            metadata.lookup_dir(id).is_some()
+                || metadata.lookup_file(id).map_or(false, |file| {
+                    file.link_dirs().iter().any(|dir_id| {
+                        metadata
+                            .lookup_dir(*dir_id)
+                            .map_or(false, |dir| dir.entries().values().any(|e| *e == link))
+                    })
+                })
+        }
+
+        let mut count = 0;
+        let mut pending = nodes;
+        loop {
+            // Link everything with a parent in the tree, until there's nothing more to link.
+            loop {
+                pending.retain(|n| !is_linked(self, n.id()));
+                let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|n| {
+                    n.parent_id()
+                        .map_or(false, |p| self.lookup_dir(p).is_some())
+                });
+                pending = rest;
+
+                if ready.is_empty() {
+                    break;
+                }
+                for node in ready {
+                    if !is_linked(self, node.id()) {
+                        let parent_id = node.parent_id().expect("no parent in reconstruct_tree");
+                        self.link_recovered(parent_id, node);
+                        count += 1;
+                    }
+                }
+            }
+
+            if pending.is_empty() {
+                break;
+            }
+
+            // The nodes left over are in trees whose root is missing its parent. Those roots go in
+            // lost+found, and the rest of the trees follow on the next pass. If there are no such
+            // roots, the parents form a cycle, and everything goes in lost+found.
+            let pending_ids = pending.iter().map(|n| n.id()).collect::<HashSet<_>>();
+            let (orphans, rest): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|n| n.parent_id().map_or(true, |p| !pending_ids.contains(&p)));
+            let (orphans, rest) = if orphans.is_empty() {
+                (rest, vec![])
+            } else {
+                (orphans, rest)
+            };
+            pending = rest;
+
+            let lost_found_id = self.lost_found_dir()?;
+            for node in orphans {
+                if !is_linked(self, node.id()) {
+                    warn!("placing {:?} in {}", node.id(), LOST_FOUND_DIR);
+                    self.link_recovered(lost_found_id, node);
+                    count += 1;
+                }
+            }
+        }
+
+        // Recovered directories bring their contents with them.
+        self.index = TreeIndex::build(&self.root_directory, self.max_depth + 2);
+        Ok(count)
+    }
+
+    /// Insert a recovered node into a directory, named after its id
+    ///
+    fn link_recovered(&mut self, parent_id: UfsUuid, node: DirectoryEntry) {
+        debug!("\tlinking {:?} under {:?}", node.id(), parent_id);
+        let node = match node {
+            DirectoryEntry::Directory(mut d) => {
+                d.set_parent_id(parent_id);
+                DirectoryEntry::Directory(d)
+            }
+            DirectoryEntry::Symlink(mut s) => {
+                s.set_dir_id(parent_id);
+                DirectoryEntry::Symlink(s)
+            }
+            // A recovered file goes back in the file table, and the directory links to it.
+            DirectoryEntry::File(mut f) => {
+                let id = f.id();
+                f.relink(parent_id);
+                self.files.insert(id, f);
+                DirectoryEntry::FileLink(id)
+            }
+            DirectoryEntry::FileLink(id) => DirectoryEntry::FileLink(id),
+        };
+
+        if let Some(parent) = self.lookup_dir_mut(parent_id) {
+            parent.entries_mut().insert(node.id().to_string(), node);
+            self.dirty = true;
+        }
+    }
+
+    /// Return the id of `/lost+found`, creating it if necessary
+    ///
+    fn lost_found_dir(&mut self) -> Result<UfsUuid, failure::Error> {
+        match self.root_directory.entries().get(LOST_FOUND_DIR) {
+            Some(DirectoryEntry::Directory(d)) => Ok(d.id()),
+            Some(_) => Err(IOFSErrorKind::NotADirectory.into()),
+            None => {
+                let root_id = self.root_directory.id();
+                let owner = self.root_directory.owner();
+                Ok(self.new_directory(root_id, LOST_FOUND_DIR, owner)?.id())
+            }
+        }
+    }
+
+    /// Return a reference to the root directory.
+    ///
+    pub(crate) fn root_directory(&self) -> &DirectoryMetadata {
+        &self.root_directory
+    }
+
+    /// Indicator that the metedata needs to be written.
+    ///
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty || self.grants.is_dirty()
+    }
+
+    /// Set the permissions on a Metadata node
+    ///
+    /// `perms` is a 12-bit unix mode, including the setuid, setgid, and sticky bits.
+    pub(crate) fn set_unix_permissions(&mut self, id: UfsUuid, perms: u16) {
+        if let Some(d) = self.lookup_dir_mut(id) {
+            d.set_unix_perms(perms);
+            self.dirty = true;
+        } else {
+            if let Some(f) = self.lookup_file_mut(id) {
+                f.set_unix_perms(perms);
+                self.dirty = true;
+            }
         }
     }
 
-    /// Get a file for write-only access
+    /// Return the permissions of a file or directory
     ///
-    pub(crate) fn get_file_write_only(&mut self, id: UfsUuid) -> Result<File, failure::Error> {
-        debug!("--------");
-        debug!("`get_file_write_only: {:?}", id);
-
-        if let Some(file) = self.lookup_file_mut(id) {
-            Ok(File {
-                file_id: file.id(),
-                perms: file.unix_perms(),
-                version: file.new_version(),
-            })
+    pub(crate) fn unix_permissions(&self, id: UfsUuid) -> Option<u16> {
+        if let Some(d) = self.lookup_dir(id) {
+            Some(d.unix_perms())
         } else {
-            Err(format_err!("unable to find file with id {:?}", id))
+            self.lookup_file(id).map(|f| f.unix_perms())
         }
     }
 
-    /// Commit changes to an open file
+    /// Return the owner of a file or directory
     ///
-    pub(crate) fn commit_file(&mut self, f: File) -> Result<(), failure::Error> {
-        debug!("--------");
-        debug!("`commit_file`: {:?}", f);
+    pub(crate) fn owner_of(&self, id: UfsUuid) -> Option<UfsUuid> {
+        if let Some(d) = self.lookup_dir(id) {
+            Some(d.owner())
+        } else {
+            self.lookup_file(id).map(|f| f.owner())
+        }
+    }
 
-        if f.version.is_dirty() {
-            if let Some(file) = self.lookup_file_mut(f.file_id) {
-                file.commit_version(f.version.clone())?;
-                Ok(())
-            } else {
-                Err(format_err!("unable to find file {:#?}", f))
-            }
+    /// Return the time stamps of a file or directory
+    ///
+    pub(crate) fn times(&self, id: UfsUuid) -> Option<Timestamps> {
+        if let Some(d) = self.lookup_dir(id) {
+            Some(d.times())
         } else {
-            Ok(())
+            self.lookup_file(id).map(|f| f.times())
         }
     }
 
-    /// Remove a directory
+    /// Set the access and modification times of a file or directory
     ///
-    pub(crate) fn remove_directory(
+    /// `None` leaves that time stamp as it is.
+    pub(crate) fn set_times(
         &mut self,
-        parent_id: UfsUuid,
-        name: &str,
+        id: UfsUuid,
+        access: Option<UfsTime>,
+        write: Option<UfsTime>,
     ) -> Result<(), failure::Error> {
-        debug!("--------");
-        debug!("`remove_directory`: {}, parent: {:#?}", name, parent_id);
+        if let Some(d) = self.lookup_dir_mut(id) {
+            d.set_times(access, write);
+        } else if let Some(f) = self.lookup_file_mut(id) {
+            f.set_times(access, write);
+        } else {
+            return Err(IOFSErrorKind::NotFound.into());
+        }
+        self.dirty = true;
+        Ok(())
+    }
 
-        if let Some(parent) = self.lookup_dir_mut(parent_id) {
-            match parent.entries_mut().remove(name) {
-                Some(DirectoryEntry::Directory(dir)) => {
-                    debug!("\tremoved {:#?}\n\tfrom {:#?}", dir, parent);
-                    Ok(())
-                }
-                _ => Err(format_err!("did not find {} in {:#?}", name, parent)),
+    /// Update the access time of a file
+    ///
+    pub(crate) fn touch_file_access(&mut self, id: UfsUuid) {
+        if let Some(f) = self.lookup_file_mut(id) {
+            if f.touch_access() {
+                self.dirty = true;
             }
+        }
+    }
+
+    /// Return the extended attributes of a file or directory
+    ///
+    pub(crate) fn xattrs(&self, id: UfsUuid) -> Option<&Xattrs> {
+        if let Some(d) = self.lookup_dir(id) {
+            Some(d.xattrs())
         } else {
-            Err(format_err!("unable to find directory {:#?}", parent_id))
+            self.lookup_file(id).map(|f| f.xattrs())
         }
     }
 
-    /// Remove a file from a directory
+    /// Set an extended attribute of a file or directory
     ///
-    pub(crate) fn unlink_file(
+    pub(crate) fn set_xattr(
         &mut self,
-        dir_id: UfsUuid,
+        id: UfsUuid,
         name: &str,
-    ) -> Result<Vec<BlockNumber>, failure::Error> {
-        debug!("--------");
-        debug!("`unlink_file`: {}, dir: {:#?}", name, dir_id);
-
-        if let Some(dir) = self.lookup_dir_mut(dir_id) {
-            // If this is a file in the special versions directory, then we are removing a version
-            // from the parent.
-            if dir.is_vers_dir() {
-                debug!("\teventually, we'll be able to remove specific versions of the file");
-                debug!("\tsomeday, I'd even like to make removing the root file save it");
-                debug!("\tsomeplace until all of the versions are removed");
-                Ok(vec![])
-            } else {
-                match dir.entries_mut().remove(name) {
-                    Some(DirectoryEntry::File(file)) => {
-                        debug!("\tremoved {:#?}\n\tfrom {:#?}", file, dir);
-                        self.dirty = true;
-                        // We need to collect all of the blocks, for all of the versions of the file
-                        // and return them as a single list to be deleted by the caller
-                        let mut blocks = vec![];
-                        for v in file.get_versions().values() {
-                            for b in v.blocks() {
-                                blocks.push(*b);
-                            }
-                            // blocks.append(v.blocks());
-                        }
-                        Ok(blocks)
-                    }
-                    _ => Err(format_err!("did not find {} in {:#?}", name, dir)),
-                }
-            }
+        value: &[u8],
+    ) -> Result<(), failure::Error> {
+        if let Some(d) = self.lookup_dir_mut(id) {
+            d.xattrs_mut().set(name, value)?;
+        } else if let Some(f) = self.lookup_file_mut(id) {
+            f.xattrs_mut().set(name, value)?;
         } else {
-            Err(format_err!("unable to find directory {:#?}", dir_id))
+            return Err(IOFSErrorKind::NotFound.into());
         }
+        self.dirty = true;
+        Ok(())
     }
 
-    /// Return a reference to the root directory.
+    /// Remove an extended attribute from a file or directory
     ///
-    pub(crate) fn root_directory(&self) -> &DirectoryMetadata {
-        &self.root_directory
+    pub(crate) fn remove_xattr(&mut self, id: UfsUuid, name: &str) -> Result<(), failure::Error> {
+        if let Some(d) = self.lookup_dir_mut(id) {
+            d.xattrs_mut().remove(name)?;
+        } else if let Some(f) = self.lookup_file_mut(id) {
+            f.xattrs_mut().remove(name)?;
+        } else {
+            return Err(IOFSErrorKind::NotFound.into());
+        }
+        self.dirty = true;
+        Ok(())
     }
 
-    /// Indicator that the metedata needs to be written.
+    /// Set the order in which a directory's entries are listed
     ///
-    pub(crate) fn is_dirty(&self) -> bool {
-        self.dirty || self.grants.is_dirty()
+    pub(crate) fn set_sort_order(
+        &mut self,
+        dir_id: UfsUuid,
+        order: SortOrder,
+    ) -> Result<(), failure::Error> {
+        match self.lookup_dir_mut(dir_id) {
+            Some(dir) => {
+                dir.set_sort_order(order);
+                self.dirty = true;
+                Ok(())
+            }
+            None => Err(IOFSErrorKind::NotFound.into()),
+        }
     }
 
-    /// Set the permissions on a Metadata node
+    /// Return the names leading from the root to `id`, according to the index
     ///
-    pub(crate) fn set_unix_permissions(&mut self, id: UfsUuid, perms: u16) {
-        if let Some(d) = self.lookup_dir_mut(id) {
-            d.set_unix_perms(perms);
-            self.dirty = true;
-        } else {
-            if let Some(f) = self.lookup_file_mut(id) {
-                f.set_unix_perms(perms);
-                self.dirty = true;
+    /// `None` is returned unless the index is borne out by the tree.
+    fn indexed_path(&self, id: UfsUuid) -> Option<Vec<String>> {
+        let root_id = self.root_directory.id();
+        let names = self.index.path(id, root_id, self.max_depth + 2)?;
+        match index::entry(&self.root_directory, &names) {
+            Some(entry) if entry.id() == id => Some(names),
+            _ => {
+                debug!("\t{:?} isn't indexed", id);
+                None
             }
         }
     }
 
     /// Return the DirectoryMetadata corresponding to the given UfsUuid.
-    /// FIXME: Maintain a cache.
+    ///
+    /// The index is used to find the directory, and failing that the tree is searched.
     pub(crate) fn lookup_dir(&self, id: UfsUuid) -> Option<&DirectoryMetadata> {
         debug!("--------");
         debug!("`lookup_dir`: {:?}", id);
         trace!("{:#?}", self);
 
         if self.root_directory.id() == id {
-            Some(&self.root_directory)
-        } else {
-            self.root_directory.lookup_dir(id)
+            return Some(&self.root_directory);
+        }
+
+        if let Some(names) = self.indexed_path(id) {
+            if let Some(DirectoryEntry::Directory(d)) = index::entry(&self.root_directory, &names) {
+                return Some(d);
+            }
         }
+        self.root_directory.lookup_dir(id, self.max_depth + 1)
     }
 
+    /// As `lookup_dir`, but mutable
+    ///
+    /// The directory, and those above it, are marked dirty.
     pub(crate) fn lookup_dir_mut(&mut self, id: UfsUuid) -> Option<&mut DirectoryMetadata> {
         debug!("--------");
         debug!("`lookup_dir_mut`: {:?}", id);
         trace!("{:#?}", self);
 
-        self.root_directory.lookup_dir_mut(id)
+        // Should the index lead somewhere other than a directory, the tree is searched instead.
+        let root = &self.root_directory;
+        let indexed = self
+            .indexed_path(id)
+            .filter(|names| match index::entry(root, names) {
+                Some(DirectoryEntry::Directory(_)) => true,
+                _ => false,
+            });
+        match indexed {
+            Some(names) => match index::entry_mut(&mut self.root_directory, &names) {
+                Some(DirectoryEntry::Directory(d)) => {
+                    d.dirty();
+                    Some(d)
+                }
+                _ => None,
+            },
+            None => self.root_directory.lookup_dir_mut(id, self.max_depth + 1),
+        }
     }
 
+    /// Return the FileMetadata corresponding to the given UfsUuid
+    ///
+    /// Files are kept in a table of their own, so there's no need to search the tree.
     pub(crate) fn lookup_file(&self, id: UfsUuid) -> Option<&FileMetadata> {
         debug!("--------");
         debug!("`lookup_file`: {:?}", id);
-        trace!("{:#?}", self);
 
-        self.root_directory.lookup_file(id)
+        self.files.get(&id)
     }
 
     pub(crate) fn lookup_file_mut(&mut self, id: UfsUuid) -> Option<&mut FileMetadata> {
         debug!("--------");
         debug!("`lookup_file_mut`: {:?}", id);
-        trace!("{:#?}", self);
 
-        self.root_directory.lookup_file_mut(id)
+        self.files.get_mut(&id)
+    }
+
+    /// Return every file in the file system
+    ///
+    pub(crate) fn files(&self) -> Vec<&FileMetadata> {
+        self.files.values().collect()
     }
 
     pub(crate) fn id_from_path<P: AsRef<Path>>(&self, path: P) -> Option<UfsUuid> {
@@ -653,7 +2193,7 @@ impl Metadata {
                     Some(name) => match dir.entries().get(name) {
                         Some(entry) => match entry {
                             DirectoryEntry::Directory(d) => from_path_r(components, d),
-                            DirectoryEntry::File(f) => Some(DirectoryEntry::File(f.clone())),
+                            _ => Some(entry.clone()),
                         },
                         None => None,
                     },
@@ -670,11 +2210,84 @@ impl Metadata {
             }
         }
 
-        match from_path_r(&mut path.as_ref().components(), &self.root_directory) {
-            Some(DirectoryEntry::File(f)) => Some(f.id()),
-            Some(DirectoryEntry::Directory(d)) => Some(d.id()),
-            None => None,
+        // The root, plus a special directory below the deepest directory, plus a file.
+        if path.as_ref().components().count() > self.max_depth + 3 {
+            warn!("path is too deep: {:?}", path.as_ref());
+            return None;
+        }
+
+        from_path_r(&mut path.as_ref().components(), &self.root_directory).map(|entry| entry.id())
+    }
+
+    /// Resolve a path to an id, following symbolic links
+    ///
+    /// Unlike `id_from_path`, links are dereferenced wherever they appear in the path, including
+    /// the last component. Relative link targets are resolved against the directory containing
+    /// the link. Following more than `MAX_SYMLINK_HOPS` links is an error, as it's most likely a
+    /// cycle.
+    pub(crate) fn resolve_path<P: AsRef<Path>>(&self, path: P) -> Result<UfsUuid, failure::Error> {
+        debug!("--------");
+        debug!("`resolve_path`: {:?}", path.as_ref());
+
+        let mut hops = 0;
+        // The components left to resolve, in reverse order.
+        let mut components = path.as_ref().components().rev().collect::<Vec<_>>();
+        // The directories that we've walked through, starting at the root.
+        let mut dirs = vec![&self.root_directory];
+
+        while let Some(component) = components.pop() {
+            match component {
+                Component::RootDir => dirs.truncate(1),
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    }
+                }
+                Component::Normal(name) => {
+                    let name = match name.to_str() {
+                        Some(name) => name,
+                        None => {
+                            warn!("invalid UTF-8 in path: {:?}", name);
+                            return Err(IOFSErrorKind::NotFound.into());
+                        }
+                    };
+                    let dir = dirs[dirs.len() - 1];
+                    match dir.entries().get(name) {
+                        Some(DirectoryEntry::Directory(d)) => {
+                            if dirs.len() > self.max_depth + 1 {
+                                warn!("path is too deep: {:?}", path.as_ref());
+                                return Err(IOFSErrorKind::PathTooDeep.into());
+                            }
+                            dirs.push(d);
+                        }
+                        Some(DirectoryEntry::Symlink(s)) => {
+                            hops += 1;
+                            if hops > MAX_SYMLINK_HOPS {
+                                warn!("too many symbolic links in {:?}", path.as_ref());
+                                return Err(IOFSErrorKind::TooManySymlinks.into());
+                            }
+                            trace!("\tfollowing {} -> {:?}", name, s.target());
+                            components.extend(s.target().components().rev());
+                        }
+                        Some(file) => {
+                            if components.is_empty() {
+                                return Ok(file.id());
+                            } else {
+                                return Err(IOFSErrorKind::NotADirectory.into());
+                            }
+                        }
+                        None => return Err(IOFSErrorKind::NotFound.into()),
+                    }
+                }
+                Component::Prefix(_) => {
+                    warn!("malformed path: {:?}", path.as_ref());
+                    return Err(IOFSErrorKind::NotFound.into());
+                }
+            }
         }
+
+        Ok(dirs[dirs.len() - 1].id())
     }
 
     pub(crate) fn path_from_file_id(&self, id: UfsUuid) -> PathBuf {
@@ -686,6 +2299,7 @@ impl Metadata {
                 metadata.lookup_dir(f.dir_id()).unwrap(),
                 f.id(),
                 metadata,
+                metadata.max_depth + 2,
             );
         }
 
@@ -694,25 +2308,27 @@ impl Metadata {
             d: &DirectoryMetadata,
             id: UfsUuid,
             metadata: &Metadata,
+            depth: usize,
         ) {
+            if depth == 0 {
+                error!("directory {:?} is too deep to make a path", d.id());
+                return;
+            }
+
             if let Some(parent_id) = d.parent_id() {
                 make_path_dir(
                     path,
                     metadata.lookup_dir(parent_id).unwrap(),
                     d.id(),
                     metadata,
+                    depth - 1,
                 );
             } else {
                 path.push("/");
             }
 
             for (name, entry) in d.entries() {
-                if id
-                    == match entry {
-                        DirectoryEntry::Directory(d) => d.id(),
-                        DirectoryEntry::File(f) => f.id(),
-                    }
-                {
+                if id == entry.id() {
                     path.push(name);
                     break;
                 }
@@ -724,6 +2340,12 @@ impl Metadata {
     }
 
     pub(crate) fn path_from_dir_id(&self, id: UfsUuid) -> PathBuf {
+        if let Some(names) = self.indexed_path(id) {
+            return names
+                .iter()
+                .fold(PathBuf::from("/"), |path, name| path.join(name));
+        }
+
         let mut path = PathBuf::new();
 
         fn make_path_dir(
@@ -731,44 +2353,57 @@ impl Metadata {
             d: &DirectoryMetadata,
             id: UfsUuid,
             metadata: &Metadata,
+            depth: usize,
         ) {
+            if depth == 0 {
+                error!("directory {:?} is too deep to make a path", d.id());
+                return;
+            }
+
             if let Some(parent_id) = d.parent_id() {
                 make_path_dir(
                     path,
                     metadata.lookup_dir(parent_id).unwrap(),
                     d.id(),
                     metadata,
+                    depth - 1,
                 );
             } else {
                 path.push("/");
             }
 
             for (name, entry) in d.entries() {
-                if id
-                    == match entry {
-                        DirectoryEntry::Directory(d) => d.id(),
-                        DirectoryEntry::File(f) => f.id(),
-                    }
-                {
+                if id == entry.id() {
                     path.push(name);
                     break;
                 }
             }
         }
 
-        make_path_dir(&mut path, self.lookup_dir(id).unwrap(), id, &self);
+        make_path_dir(
+            &mut path,
+            self.lookup_dir(id).unwrap(),
+            id,
+            &self,
+            self.max_depth + 2,
+        );
         path
     }
 }
 
 impl MetadataSerialize for Metadata {
+    /// Serialize the metadata, behind its format version
+    ///
     fn serialize(&mut self) -> Result<Vec<u8>, failure::Error> {
         match bincode::serialize(&self) {
-            Ok(r) => {
+            Ok(mut r) => {
                 debug!("--------");
                 debug!("`serialize: {:#?}", self);
                 self.dirty = false;
-                Ok(r)
+                let mut bytes = METADATA_MAGIC.to_vec();
+                bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+                bytes.append(&mut r);
+                Ok(bytes)
             }
             Err(e) => Err(format_err!("unable to serialize directory metadata {}", e)),
         }
@@ -776,11 +2411,41 @@ impl MetadataSerialize for Metadata {
 }
 
 impl MetadataDeserialize for Metadata {
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, failure::Error> {
-        match bincode::deserialize(&bytes) {
-            Ok(r) => {
+    /// Deserialize metadata written by `serialize`
+    ///
+    /// Metadata from format version 1 has no version, and is converted as it's read.
+    fn deserialize(bytes: Vec<u8>, block_size: BlockSize) -> Result<Self, failure::Error> {
+        if !bytes.starts_with(METADATA_MAGIC) {
+            debug!("converting metadata from format version 1");
+            return match bincode::deserialize::<MetadataV1>(&bytes) {
+                Ok(r) => Ok(Metadata::from_v1(r, block_size as FileSize)),
+                Err(e) => Err(format_err!(
+                    "unable to deserialize version 1 directory metadata {}",
+                    e
+                )),
+            };
+        }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(
+            bytes
+                .get(4..8)
+                .ok_or_else(|| format_err!("truncated directory metadata"))?,
+        );
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(format_err!(
+                "the directory metadata is in format version {}, but version {} is required",
+                version,
+                FORMAT_VERSION
+            ));
+        }
+
+        match bincode::deserialize::<Metadata>(&bytes[8..]) {
+            Ok(mut r) => {
                 debug!("--------");
                 debug!("`deserialize`: {:#?}", r);
+                r.index = TreeIndex::build(&r.root_directory, r.max_depth + 2);
                 Ok(r)
             }
             Err(e) => Err(format_err!(
@@ -885,9 +2550,207 @@ pub mod test {
         assert_eq!(Path::new("/foo/.wasm"), m.path_from_dir_id(wasm_id));
     }
 
+    #[test]
+    fn reconstruct_tree() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+        let a = m.new_directory(root_id, "a", user).unwrap();
+        let b = m.new_directory(a.id(), "b", user).unwrap();
+        let c = m.new_directory(b.id(), "c", user).unwrap();
+        let file = m.new_file(b.id(), "file").unwrap();
+        let x = m.new_directory(root_id, "x", user).unwrap();
+        let y = m.new_directory(x.id(), "y", user).unwrap();
+
+        // Detach b from a, and lose x entirely, except for y.
+        let b_entry = m
+            .lookup_dir_mut(a.id())
+            .unwrap()
+            .entries_mut()
+            .remove("b")
+            .unwrap();
+        let x_entry = m.root_directory.entries_mut().remove("x").unwrap();
+        let y_entry = match x_entry {
+            DirectoryEntry::Directory(x) => x.entries().get("y").unwrap().clone(),
+            _ => panic!("x is not a directory"),
+        };
+        assert_eq!(None, m.lookup_dir(c.id()));
+        assert_eq!(
+            None,
+            m.lookup_dir(m.lookup_file(file.file_id).unwrap().dir_id())
+        );
+
+        // c comes along with b, so it's not counted.
+        let c_entry = match &b_entry {
+            DirectoryEntry::Directory(b) => b.entries().get("c").unwrap().clone(),
+            _ => panic!("b is not a directory"),
+        };
+        assert_eq!(
+            2,
+            m.reconstruct_tree(vec![c_entry, y_entry, b_entry]).unwrap()
+        );
+
+        // b is back in a, and y is lost.
+        let b_path = Path::new("/a").join(b.id().to_string());
+        assert_eq!(b_path, m.path_from_dir_id(b.id()));
+        assert_eq!(b_path.join("c"), m.path_from_dir_id(c.id()));
+        assert_eq!(b_path.join("file"), m.path_from_file_id(file.file_id));
+        assert_eq!(
+            Path::new("/lost+found").join(y.id().to_string()),
+            m.path_from_dir_id(y.id())
+        );
+
+        // Nothing more to do
+        let b_entry = DirectoryEntry::Directory(m.lookup_dir(b.id()).unwrap().clone());
+        assert_eq!(0, m.reconstruct_tree(vec![b_entry]).unwrap());
+    }
+
+    #[test]
+    fn indexed_lookup() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+        let a = m.new_directory(root_id, "a", user).unwrap();
+        let b = m.new_directory(a.id(), "b", user).unwrap();
+        let file = m.new_file(b.id(), "file").unwrap();
+        m.link_file(file.file_id, root_id, "link").unwrap();
+
+        // Moving b takes the file with it.
+        m.rename_entry(a.id(), "b", root_id, "c").unwrap();
+        assert_eq!(Path::new("/c"), m.path_from_dir_id(b.id()));
+        assert_eq!(Path::new("/c/file"), m.path_from_file_id(file.file_id));
+
+        // Removing a directory takes its special directories out of the index too.
+        let d = m.new_directory(root_id, "d", user).unwrap();
+        let d_wasm = d.id().new(dir::WASM_DIR);
+        assert!(m.index.path(d_wasm, root_id, 8).is_some());
+        m.remove_directory(root_id, "d").unwrap();
+        assert_eq!(None, m.index.path(d_wasm, root_id, 8));
+        assert_eq!(
+            TreeIndex::build(&m.root_directory, m.max_depth + 2),
+            m.index
+        );
+
+        // The index is rebuilt when the metadata is loaded, and files aren't in it.
+        let mut m = Metadata::deserialize(m.serialize().unwrap(), BlockSize::FiveTwelve).unwrap();
+        assert!(m.index.path(b.id(), root_id, 8).is_some());
+        assert_eq!(None, m.index.path(file.file_id, root_id, 8));
+        assert_eq!(
+            &[b.id(), root_id],
+            m.lookup_file(file.file_id).unwrap().link_dirs()
+        );
+
+        // Unlinking one name leaves the file in the other directory.
+        m.unlink_file(b.id(), "file").unwrap();
+        assert_eq!(root_id, m.lookup_file(file.file_id).unwrap().dir_id());
+        assert_eq!(Path::new("/link"), m.path_from_file_id(file.file_id));
+        assert!(m.lookup_file_mut(file.file_id).is_some());
+    }
+
+    #[test]
+    fn max_depth_is_kept() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+        m.set_max_depth(1);
+        let a = m.new_directory(root_id, "a", user).unwrap();
+        assert!(m.new_directory(a.id(), "b", user).is_err());
+
+        let mut m = Metadata::deserialize(m.serialize().unwrap(), BlockSize::FiveTwelve).unwrap();
+        assert_eq!(1, m.max_depth);
+        assert!(m.new_directory(a.id(), "b", user).is_err());
+    }
+
+    #[test]
+    fn hard_links_share_a_file() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+        let a = m.new_directory(root_id, "a", user).unwrap();
+        let file = m.new_file(a.id(), "file").unwrap();
+        m.link_file(file.file_id, root_id, "link").unwrap();
+        assert_eq!(2, m.lookup_file(file.file_id).unwrap().links());
+
+        // A change made through one name is seen through the other.
+        m.set_unix_permissions(file.file_id, 0o600);
+        let m = Metadata::deserialize(m.serialize().unwrap(), BlockSize::FiveTwelve).unwrap();
+        match m.get_directory(root_id).unwrap().entries().get("link") {
+            Some(DirectoryEntry::File(f)) => assert_eq!(0o600, f.unix_perms()),
+            _ => panic!("link is not a file"),
+        }
+
+        // The directories store a link to the file, rather than a copy of it.
+        assert_eq!(
+            Some(&DirectoryEntry::FileLink(file.file_id)),
+            m.root_directory().entries().get("link")
+        );
+        assert_eq!(
+            Some(&DirectoryEntry::FileLink(file.file_id)),
+            m.lookup_dir(a.id()).unwrap().entries().get("file")
+        );
+    }
+
+    #[test]
+    fn resolve_through_symlink() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+        let foo = m.new_directory(root_id, "foo", user).unwrap();
+        let bar = m.new_directory(foo.id(), "bar", user).unwrap();
+        let file = m.new_file(bar.id(), "baz").unwrap();
+
+        m.new_symlink(root_id, "abs", "/foo/bar").unwrap();
+        m.new_symlink(foo.id(), "rel", "bar").unwrap();
+        m.new_symlink(bar.id(), "up", "../bar/baz").unwrap();
+
+        assert_eq!(file.file_id, m.resolve_path("/abs/baz").unwrap());
+        assert_eq!(file.file_id, m.resolve_path("/foo/rel/baz").unwrap());
+        assert_eq!(file.file_id, m.resolve_path("/abs/up").unwrap());
+        assert_eq!(bar.id(), m.resolve_path("/abs").unwrap());
+
+        // Without resolution, the path stops at the link.
+        assert_eq!(None, m.id_from_path("/abs/baz"));
+        assert_ne!(Some(bar.id()), m.id_from_path("/abs"));
+
+        let err = m.resolve_path("/abs/nope").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NotFound),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
+    #[test]
+    fn symlink_cycle() {
+        init();
+
+        let user = UfsUuid::new_user("test");
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), user);
+        let root_id = m.root_directory().id();
+
+        m.new_symlink(root_id, "a", "/b").unwrap();
+        m.new_symlink(root_id, "b", "a").unwrap();
+
+        let err = m.resolve_path("/a/file").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::TooManySymlinks),
+            err.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+
     #[test]
     fn permissions() {
         let p755 = PermissionGroups {
+            special: SpecialBits::default(),
             user: Permission::ReadWriteExecute,
             group: Permission::ReadExecute,
             other: Permission::ReadExecute,
@@ -896,6 +2759,7 @@ pub mod test {
         assert_eq!(PermissionGroups::from(0o755), p755);
 
         let p644 = PermissionGroups {
+            special: SpecialBits::default(),
             user: Permission::ReadWrite,
             group: Permission::Read,
             other: Permission::Read,
@@ -904,11 +2768,139 @@ pub mod test {
         assert_eq!(PermissionGroups::from(0o644), p644);
 
         let p201 = PermissionGroups {
+            special: SpecialBits::default(),
             user: Permission::Write,
             group: Permission::Nada,
             other: Permission::Execute,
         };
         assert_eq!(0o201, p201.as_u16());
         assert_eq!(PermissionGroups::from(0o201), p201);
+
+        let p4755 = PermissionGroups {
+            special: SpecialBits {
+                setuid: true,
+                setgid: false,
+                sticky: false,
+            },
+            user: Permission::ReadWriteExecute,
+            group: Permission::ReadExecute,
+            other: Permission::ReadExecute,
+        };
+        assert_eq!(0o4755, p4755.as_u16());
+        assert_eq!(PermissionGroups::from(0o4755), p4755);
+
+        let p2755 = PermissionGroups {
+            special: SpecialBits {
+                setuid: false,
+                setgid: true,
+                sticky: false,
+            },
+            user: Permission::ReadWriteExecute,
+            group: Permission::ReadExecute,
+            other: Permission::ReadExecute,
+        };
+        assert_eq!(0o2755, p2755.as_u16());
+        assert_eq!(PermissionGroups::from(0o2755), p2755);
+
+        let p1777 = PermissionGroups {
+            special: SpecialBits {
+                setuid: false,
+                setgid: false,
+                sticky: true,
+            },
+            user: Permission::ReadWriteExecute,
+            group: Permission::ReadWriteExecute,
+            other: Permission::ReadWriteExecute,
+        };
+        assert_eq!(0o1777, p1777.as_u16());
+        assert_eq!(PermissionGroups::from(0o1777), p1777);
+
+        // Anything above the mode bits, e.g. the file type, is ignored.
+        assert_eq!(PermissionGroups::from(0o41777), p1777);
+    }
+
+    #[test]
+    fn format_version_1() {
+        init();
+
+        let fs = UfsUuid::new_root_fs("test");
+        let owner = UfsUuid::new_user("test");
+        let root = fs.new("/");
+        let file = root.new("file");
+        let time = UfsTime::now();
+
+        // As it was stored, less the `.wasm` and `.vers` directories.
+        let v1: MetadataV1 = serde_json::from_value(serde_json::json!({
+            "id": fs,
+            "root_directory": {
+                "id": root,
+                "parent_id": null,
+                "owner": owner,
+                "perms": {
+                    "user": "ReadWriteExecute",
+                    "group": "ReadExecute",
+                    "other": "ReadExecute",
+                },
+                "wasm_dir": false,
+                "vers_dir": false,
+                "birth_time": time,
+                "write_time": time,
+                "change_time": time,
+                "access_time": time,
+                "entries": {
+                    "file": { "File": {
+                        "id": file,
+                        "dir_id": root,
+                        "owner": owner,
+                        "perms": { "user": "ReadWrite", "group": "Read", "other": "Read" },
+                        "last_version": 0,
+                        "versions": { "0": {
+                            "id": file.random(),
+                            "file_id": file,
+                            "birth_time": time,
+                            "write_time": time,
+                            "change_time": time,
+                            "access_time": time,
+                            "size": 1100,
+                            "blocks": [7, 8, 9],
+                        }},
+                    }},
+                },
+            },
+            "users": { "inner": {} },
+            "grants": { "dirty": false, "inner": {} },
+        }))
+        .unwrap();
+
+        let bytes = bincode::serialize(&v1).unwrap();
+        let mut m = Metadata::deserialize(bytes, BlockSize::FiveTwelve).unwrap();
+        assert!(m.is_dirty(), "the conversion should be written");
+
+        let f = m.lookup_file(file).unwrap();
+        assert_eq!(0o644, f.unix_perms());
+        assert_eq!(1, f.links());
+        assert_eq!(time, f.times().birth);
+        let version = f.get_latest();
+        assert_eq!(1100, version.size());
+        assert_eq!(&vec![7, 8, 9], version.blocks());
+        assert_eq!(&vec![0, 512, 1024], version.offsets());
+
+        // Once converted, it's written in the current format.
+        let bytes = m.serialize().unwrap();
+        assert_eq!(METADATA_MAGIC, &bytes[..4]);
+        assert_eq!(
+            m,
+            Metadata::deserialize(bytes, BlockSize::FiveTwelve).unwrap()
+        );
+    }
+
+    #[test]
+    fn format_version() {
+        init();
+
+        let mut m = Metadata::new(UfsUuid::new_root_fs("test"), UfsUuid::new_user("test"));
+        let mut bytes = m.serialize().unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(Metadata::deserialize(bytes, BlockSize::FiveTwelve).is_err());
     }
 }
@@ -6,18 +6,51 @@
 //! FIXME: The directory data is not versioned. What happens to deleted files?  What do we do when
 //! a directory goes away?
 use {
-    crate::{time::UfsTime, uuid::UfsUuid, IOFSErrorKind},
+    crate::{
+        time::{Timestamps, UfsTime},
+        uuid::UfsUuid,
+        IOFSErrorKind,
+    },
     failure::format_err,
-    log::debug,
+    log::{debug, warn},
     serde_derive::{Deserialize, Serialize},
-    std::collections::HashMap,
+    std::{cmp::Reverse, collections::HashMap, path::PathBuf},
 };
 
 pub(crate) const WASM_DIR: &'static str = ".wasm";
 pub(crate) const WASM_EXT: &'static str = "wasm";
+pub(crate) const WASM_CONFIG_EXT: &'static str = "config";
 pub(crate) const VERS_DIR: &'static str = ".vers";
 
-use super::{DirectoryEntry, FileMetadata, Permission, PermissionGroups};
+use super::{
+    DirectoryEntry, DirectoryEntryV1, FileMetadata, FileSize, Permission, PermissionGroups,
+    PermissionGroupsV1, Quota, Retention, SpecialBits, SymlinkMetadata, Xattrs,
+};
+
+/// Directory Listing Order
+///
+/// The order in which a directory's entries are presented, both via FUSE and HTTP.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SortOrder {
+    /// By name, A to Z
+    ///
+    NameAscending,
+    /// By name, Z to A
+    ///
+    NameDescending,
+    /// Least recently modified first
+    ///
+    MtimeAscending,
+    /// Most recently modified first
+    ///
+    MtimeDescending,
+    /// Smallest first
+    ///
+    SizeAscending,
+    /// Largest first
+    ///
+    SizeDescending,
+}
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct DirectoryMetadata {
@@ -61,6 +94,21 @@ pub struct DirectoryMetadata {
     /// HashMap of directory contents, from name to `DirectoryEntry`
     ///
     entries: HashMap<String, DirectoryEntry>,
+    /// The order in which the entries are listed
+    ///
+    /// `None` means by name, ascending.
+    sort_order: Option<SortOrder>,
+    /// How many versions are kept of the files in this directory
+    ///
+    /// `None` means as many as the file system keeps.
+    retention: Option<Retention>,
+    /// The most that this directory, and those below it, may hold, and how much they do
+    ///
+    /// `None` means that there's no limit, and usage isn't tracked.
+    quota: Option<Quota>,
+    /// Extended attributes
+    ///
+    xattrs: Xattrs,
 }
 
 impl DirectoryMetadata {
@@ -73,6 +121,7 @@ impl DirectoryMetadata {
     pub(crate) fn new(id: UfsUuid, p_id: Option<UfsUuid>, owner: UfsUuid) -> Self {
         let time = UfsTime::now();
         let perms = PermissionGroups {
+            special: SpecialBits::default(),
             user: Permission::ReadWriteExecute,
             group: Permission::ReadExecute,
             other: Permission::ReadExecute,
@@ -90,6 +139,10 @@ impl DirectoryMetadata {
             change_time: time,
             access_time: time,
             entries: HashMap::new(),
+            sort_order: None,
+            retention: None,
+            quota: None,
+            xattrs: Xattrs::new(),
         };
         // Create the directory for WASM programs
         d.entries.insert(
@@ -107,6 +160,10 @@ impl DirectoryMetadata {
                 change_time: time,
                 access_time: time,
                 entries: HashMap::new(),
+                sort_order: None,
+                retention: None,
+                quota: None,
+                xattrs: Xattrs::new(),
             }),
         );
         // Create the directory for file versions
@@ -125,6 +182,10 @@ impl DirectoryMetadata {
                 change_time: time,
                 access_time: time,
                 entries: HashMap::new(),
+                sort_order: None,
+                retention: None,
+                quota: None,
+                xattrs: Xattrs::new(),
             }),
         );
         d
@@ -160,12 +221,29 @@ impl DirectoryMetadata {
         self.parent_id
     }
 
+    /// Set the parent UUID
+    ///
+    /// Used when the directory is moved.
+    pub(in crate::metadata) fn set_parent_id(&mut self, parent_id: UfsUuid) {
+        self.parent_id = Some(parent_id);
+        self.change_time = UfsTime::now();
+        self.dirty = true;
+    }
+
     /// Return the Owner
     ///
     pub(crate) fn owner(&self) -> UfsUuid {
         self.owner
     }
 
+    /// Give the directory to another user
+    ///
+    pub(in crate::metadata) fn set_owner(&mut self, owner: UfsUuid) {
+        self.owner = owner;
+        self.change_time = UfsTime::now();
+        self.dirty = true;
+    }
+
     /// Return the directory permissions, as a unix octal number
     ///
     pub(crate) fn unix_perms(&self) -> u16 {
@@ -177,6 +255,7 @@ impl DirectoryMetadata {
     pub(crate) fn set_unix_perms(&mut self, perms: u16) {
         self.dirty = true;
         self.perms = perms.into();
+        self.change_time = UfsTime::now();
     }
 
     /// Return the `write_time` timestamp
@@ -185,6 +264,124 @@ impl DirectoryMetadata {
         self.write_time
     }
 
+    /// Return the directory's time stamps
+    ///
+    pub(crate) fn times(&self) -> Timestamps {
+        Timestamps {
+            birth: self.birth_time,
+            write: self.write_time,
+            change: self.change_time,
+            access: self.access_time,
+        }
+    }
+
+    /// Set the access and modification times of the directory
+    ///
+    /// As with `utimensat`, the change time is updated as well.
+    pub(in crate::metadata) fn set_times(
+        &mut self,
+        access: Option<UfsTime>,
+        write: Option<UfsTime>,
+    ) {
+        if let Some(access) = access {
+            self.access_time = access;
+        }
+        if let Some(write) = write {
+            self.write_time = write;
+        }
+        self.change_time = UfsTime::now();
+        self.dirty = true;
+    }
+
+    /// Return the order in which entries are listed
+    ///
+    pub(crate) fn sort_order(&self) -> SortOrder {
+        self.sort_order.unwrap_or(SortOrder::NameAscending)
+    }
+
+    /// Set the order in which entries are listed
+    ///
+    pub(crate) fn set_sort_order(&mut self, order: SortOrder) {
+        self.dirty = true;
+        self.sort_order = Some(order);
+    }
+
+    /// Return the version retention policy for the files in this directory, if it has its own
+    ///
+    pub(crate) fn retention(&self) -> Option<Retention> {
+        self.retention
+    }
+
+    /// Set, or clear, the version retention policy for the files in this directory
+    ///
+    pub(crate) fn set_retention(&mut self, retention: Option<Retention>) {
+        self.dirty = true;
+        self.retention = retention;
+    }
+
+    /// Return this directory's quota, if it has one
+    ///
+    pub(crate) fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+
+    /// Set, or clear, this directory's quota
+    ///
+    pub(crate) fn set_quota(&mut self, quota: Option<Quota>) {
+        self.dirty = true;
+        self.quota = quota;
+    }
+
+    /// Count `delta` bytes against this directory's quota
+    ///
+    /// Nothing happens if it doesn't have a quota.
+    pub(in crate::metadata) fn charge(&mut self, delta: i64) {
+        if let Some(quota) = &mut self.quota {
+            self.dirty = true;
+            quota.charge(delta);
+        }
+    }
+
+    /// Hold `delta` bytes against this directory's quota, for an open file
+    ///
+    /// Holds aren't stored, so the directory isn't made dirty.
+    pub(in crate::metadata) fn hold(&mut self, delta: i64) {
+        if let Some(quota) = &mut self.quota {
+            quota.hold(delta);
+        }
+    }
+
+    /// Return the extended attributes of this directory
+    ///
+    pub(crate) fn xattrs(&self) -> &Xattrs {
+        &self.xattrs
+    }
+
+    /// Return the extended attributes of this directory, for changing
+    ///
+    pub(in crate::metadata) fn xattrs_mut(&mut self) -> &mut Xattrs {
+        &mut self.xattrs
+    }
+
+    /// Return the entries, in listing order
+    ///
+    /// Entries that compare equal, e.g. two files of the same size, are ordered by name.
+    pub(crate) fn sorted_entries(&self) -> Vec<(&String, &DirectoryEntry)> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        match self.sort_order() {
+            SortOrder::NameAscending => (),
+            SortOrder::NameDescending => entries.reverse(),
+            SortOrder::MtimeAscending => entries.sort_by_key(|(_, e)| e.write_time()),
+            SortOrder::MtimeDescending => entries.sort_by_key(|(_, e)| Reverse(e.write_time())),
+            SortOrder::SizeAscending => entries.sort_by_key(|(_, e)| e.size()),
+            SortOrder::SizeDescending => entries.sort_by_key(|(_, e)| Reverse(e.size())),
+        }
+
+        entries
+    }
+
     /// Return if this is a ".wasm" directory
     ///
     pub(crate) fn is_wasm_dir(&self) -> bool {
@@ -240,34 +437,101 @@ impl DirectoryMetadata {
 
     /// Create a new file in this directory
     ///
+    /// The directory holds a link to the file, and the file itself is returned, for the caller to
+    /// keep.
     pub(crate) fn new_file(&mut self, name: String) -> Result<FileMetadata, failure::Error> {
         debug!("--------");
         debug!("`new_file`: {:?}", name);
 
         if self.entries.contains_key(&name) {
-            Err(format_err!("file already exists"))
+            Err(IOFSErrorKind::FileExists.into())
         } else {
             let new_id = self.id.new(&name);
             let file = FileMetadata::new(new_id, self.id, self.owner);
+            match self.entries.insert(name, DirectoryEntry::FileLink(new_id)) {
+                None => {
+                    debug!("\tcreated file {:?}", new_id);
+                    Ok(file)
+                }
+                Some(_) => Err(format_err!("unable to store directory entry")),
+            }
+        }
+    }
+
+    /// Create a new symbolic link in this directory
+    ///
+    pub(crate) fn new_symlink(
+        &mut self,
+        name: String,
+        target: PathBuf,
+    ) -> Result<SymlinkMetadata, failure::Error> {
+        debug!("--------");
+        debug!("`new_symlink`: {:?} -> {:?}", name, target);
+
+        if self.entries.contains_key(&name) {
+            Err(IOFSErrorKind::FileExists.into())
+        } else {
+            let new_id = self.id.new(&name);
+            let link = SymlinkMetadata::new(new_id, self.id, self.owner, target);
             match self
                 .entries
-                .insert(name, DirectoryEntry::File(file.clone()))
+                .insert(name, DirectoryEntry::Symlink(link.clone()))
             {
                 None => {
-                    debug!("\tcreated file {:?}", new_id);
-                    Ok(file)
+                    debug!("\tcreated symlink {:?}", new_id);
+                    Ok(link)
                 }
                 Some(_) => Err(format_err!("unable to store directory entry")),
             }
         }
     }
 
+    /// Check if this directory is empty
+    ///
+    /// The special ".wasm" and ".vers" directories don't count, unless there's something in them.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.values().all(|e| match e {
+            DirectoryEntry::Directory(d) => {
+                (d.is_wasm_dir() || d.is_vers_dir()) && d.entries.is_empty()
+            }
+            _ => false,
+        })
+    }
+
+    /// Return the number of levels of directories below this one
+    ///
+    /// We stop counting at `limit`.
+    pub(in crate::metadata) fn height(&self, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+
+        self.entries
+            .values()
+            .filter_map(|e| match e {
+                DirectoryEntry::Directory(d) => Some(1 + d.height(limit - 1)),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
     /// Lookup a subdirectory by id, and return a reference to it.
     ///
-    pub(in crate::metadata) fn lookup_dir(&self, id: UfsUuid) -> Option<&DirectoryMetadata> {
+    /// The search descends at most `depth` levels.
+    pub(in crate::metadata) fn lookup_dir(
+        &self,
+        id: UfsUuid,
+        depth: usize,
+    ) -> Option<&DirectoryMetadata> {
         debug!("--------");
         debug!("`lookup_dir`: {:#?}, parent {:#?}", self.id, self.parent_id);
 
+        if depth == 0 {
+            warn!("giving up search for {:?} at {:?}: too deep", id, self.id);
+            return None;
+        }
+
         for e in self.entries.values() {
             if let DirectoryEntry::Directory(d) = e {
                 if d.id == id {
@@ -275,7 +539,7 @@ impl DirectoryMetadata {
                     return Some(d);
                 } else {
                     debug!("\tsearching {:#?}", d.id());
-                    if let Some(d) = DirectoryMetadata::lookup_dir(d, id) {
+                    if let Some(d) = DirectoryMetadata::lookup_dir(d, id, depth - 1) {
                         debug!("\treturning {:#?}", d.id());
                         return Some(d);
                     }
@@ -288,9 +552,11 @@ impl DirectoryMetadata {
 
     /// Lookup a subdirectory by id, and return a mutable reference to it.
     ///
+    /// The search descends at most `depth` levels.
     pub(in crate::metadata) fn lookup_dir_mut(
         &mut self,
         id: UfsUuid,
+        depth: usize,
     ) -> Option<&mut DirectoryMetadata> {
         debug!("--------");
         debug!(
@@ -305,11 +571,13 @@ impl DirectoryMetadata {
             self.dirty = true;
             debug!("\tfound {:#?}", self.id());
             return Some(self);
+        } else if depth == 0 {
+            warn!("giving up search for {:?} at {:?}: too deep", id, self.id);
         } else {
             for e in self.entries.values_mut() {
                 if let DirectoryEntry::Directory(ref mut d) = e {
                     debug!("\tsearching {:#?}", d.id());
-                    if let Some(d) = DirectoryMetadata::lookup_dir_mut(d, id) {
+                    if let Some(d) = DirectoryMetadata::lookup_dir_mut(d, id, depth - 1) {
                         d.dirty = true;
                         debug!("\treturning {:#?}", d.id());
                         return Some(d);
@@ -319,63 +587,55 @@ impl DirectoryMetadata {
         }
         None
     }
+}
 
-    /// Lookup a file by id, and return a reference to it.
-    ///
-    pub(in crate::metadata) fn lookup_file(&self, id: UfsUuid) -> Option<&FileMetadata> {
-        debug!("--------");
-        debug!(
-            "`lookup_file`: {:#?}, parent {:#?}",
-            self.id, self.parent_id
-        );
-
-        for e in self.entries.values() {
-            match e {
-                DirectoryEntry::File(f) => {
-                    if f.id() == id {
-                        return Some(f);
-                    }
-                }
-                DirectoryEntry::Directory(d) => {
-                    if let Some(f) = DirectoryMetadata::lookup_file(d, id) {
-                        return Some(f);
-                    }
-                }
-            }
-        }
-
-        None
-    }
-
-    /// Lookup a file by id, and return a mutable reference to it.
-    ///
-    pub(in crate::metadata) fn lookup_file_mut(
-        &mut self,
-        id: UfsUuid,
-    ) -> Option<&mut FileMetadata> {
-        debug!("--------");
-        debug!(
-            "`lookup_file_mut`: {:#?}, parent {:#?}",
-            self.id, self.parent_id
-        );
-
-        self.dirty = true;
+/// A `DirectoryMetadata`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct DirectoryMetadataV1 {
+    id: UfsUuid,
+    parent_id: Option<UfsUuid>,
+    owner: UfsUuid,
+    perms: PermissionGroupsV1,
+    wasm_dir: bool,
+    vers_dir: bool,
+    birth_time: UfsTime,
+    write_time: UfsTime,
+    change_time: UfsTime,
+    access_time: UfsTime,
+    entries: HashMap<String, DirectoryEntryV1>,
+}
 
-        for e in self.entries.values_mut() {
-            match e {
-                DirectoryEntry::File(f) => {
-                    if f.id() == id {
-                        return Some(f);
-                    }
-                }
-                DirectoryEntry::Directory(d) => {
-                    if let Some(f) = DirectoryMetadata::lookup_file_mut(d, id) {
-                        return Some(f);
-                    }
-                }
-            }
+impl DirectoryMetadata {
+    /// Convert a directory, and everything in it, from format version 1
+    ///
+    /// Files were kept in their directory, and now go in `files`.
+    pub(in crate::metadata) fn from_v1(
+        dir: DirectoryMetadataV1,
+        block_size: FileSize,
+        files: &mut HashMap<UfsUuid, FileMetadata>,
+    ) -> Self {
+        DirectoryMetadata {
+            dirty: false,
+            id: dir.id,
+            parent_id: dir.parent_id,
+            owner: dir.owner,
+            perms: dir.perms.into(),
+            wasm_dir: dir.wasm_dir,
+            vers_dir: dir.vers_dir,
+            birth_time: dir.birth_time,
+            write_time: dir.write_time,
+            change_time: dir.change_time,
+            access_time: dir.access_time,
+            entries: dir
+                .entries
+                .into_iter()
+                .map(|(name, entry)| (name, DirectoryEntry::from_v1(entry, block_size, files)))
+                .collect(),
+            sort_order: None,
+            retention: None,
+            quota: None,
+            xattrs: Xattrs::new(),
         }
-
-        None
     }
 }
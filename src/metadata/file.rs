@@ -3,7 +3,10 @@
 //! Files are just lists of blocks (data) with some metadata associated. In UFS, files are
 //! versioned, and so to must the metadata of each file. Thus, the top-level file structure is a
 //! list of [`FileVersion`]s.
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use failure::format_err;
 use log::{debug, error, trace};
@@ -11,11 +14,17 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::{
     block::{Block, BlockNumber},
-    time::UfsTime,
+    time::{Timestamps, UfsTime},
     uuid::UfsUuid,
 };
 
-use super::{FileSize, Permission, PermissionGroups};
+use super::{
+    FileSize, Permission, PermissionGroups, PermissionGroupsV1, Retention, SpecialBits, Xattrs,
+};
+
+/// How stale an access time may get before a read moves it on regardless
+///
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Data about Files
 ///
@@ -26,21 +35,32 @@ pub struct FileMetadata {
     /// The UUID of this file
     ///
     id: UfsUuid,
-    /// The UUID of the parent directory
-    ///
-    dir_id: UfsUuid,
     /// Owner of this file
     ///
     owner: UfsUuid,
     /// Permission Groups for this file
     ///
     perms: PermissionGroups,
+    /// Time the file was created (crtime)
+    ///
+    /// Each version has its own birth time, this is the birth time of the first.
+    birth_time: UfsTime,
+    /// The directory holding each of the file's hard links
+    ///
+    /// The file is kept once, by `Metadata`, however many links it has. There's an entry here for
+    /// each, so a directory holding two links to the file appears twice. The first is the file's
+    /// home directory, and there's always at least one.
+    links: Vec<UfsUuid>,
     /// The most recent version of this file
     ///
     last_version: usize,
     /// A map of all versions of this file
     ///
     versions: HashMap<usize, FileVersion>,
+    /// Extended attributes
+    ///
+    /// These belong to the file, rather than to any one version.
+    xattrs: Xattrs,
 }
 
 impl FileMetadata {
@@ -50,18 +70,23 @@ impl FileMetadata {
     /// that we capture a time stamp of when the file was created.
     pub(crate) fn new(id: UfsUuid, p_id: UfsUuid, owner: UfsUuid) -> Self {
         let mut versions = HashMap::new();
-        versions.insert(0, FileVersion::new(id.random(), &id));
+        let version = FileVersion::new(id.random(), &id);
+        let birth_time = version.birth_time;
+        versions.insert(0, version);
         FileMetadata {
             id,
-            dir_id: p_id,
             owner,
+            birth_time,
             perms: PermissionGroups {
+                special: SpecialBits::default(),
                 user: Permission::ReadWrite,
                 group: Permission::Read,
                 other: Permission::Read,
             },
+            links: vec![p_id],
             last_version: 0,
             versions,
+            xattrs: Xattrs::new(),
         }
     }
 
@@ -71,11 +96,13 @@ impl FileMetadata {
         versions.insert(0, v);
         FileMetadata {
             id,
-            dir_id: file.dir_id,
             owner: file.owner,
             perms: file.perms.clone(),
+            birth_time: file.birth_time,
+            links: file.links.clone(),
             last_version: 0,
             versions,
+            xattrs: file.xattrs.clone(),
         }
     }
 
@@ -87,8 +114,9 @@ impl FileMetadata {
 
     /// Return the directory id of this file
     ///
+    /// A file with several hard links is in several directories, and this is the first of them.
     pub(crate) fn dir_id(&self) -> UfsUuid {
-        self.dir_id
+        self.links[0]
     }
 
     /// Return the owner
@@ -97,6 +125,13 @@ impl FileMetadata {
         self.owner
     }
 
+    /// Give the file to another user
+    ///
+    pub(in crate::metadata) fn set_owner(&mut self, owner: UfsUuid) {
+        self.owner = owner;
+        self.latest_mut().change_time = UfsTime::now();
+    }
+
     /// Return the file permissions, as a unix octal number
     ///
     pub(crate) fn unix_perms(&self) -> u16 {
@@ -107,14 +142,122 @@ impl FileMetadata {
     ///
     pub(crate) fn set_unix_perms(&mut self, perms: u16) {
         self.perms = perms.into();
+        self.latest_mut().change_time = UfsTime::now();
+    }
+
+    /// Return the file's time stamps
+    ///
+    /// The times are those of the latest version, save for the birth time, which is that of the
+    /// file itself.
+    pub(crate) fn times(&self) -> Timestamps {
+        let latest = self.versions.get(&self.last_version).unwrap();
+        Timestamps {
+            birth: self.birth_time,
+            write: latest.write_time,
+            change: latest.change_time,
+            access: latest.access_time,
+        }
+    }
+
+    /// Note that the file was just read
+    ///
+    /// As with Linux's `relatime`, the access time is only moved on if it's no later than the
+    /// modification or change time, or it's over a day old. This returns true if it was, so that
+    /// reading a file over and over doesn't change the metadata each time.
+    pub(in crate::metadata) fn touch_access(&mut self) -> bool {
+        let times = self.times();
+        if times.access > times.write
+            && times.access > times.change
+            && times.access.age() < RELATIME_INTERVAL
+        {
+            return false;
+        }
+
+        self.latest_mut().access_time = UfsTime::now();
+        true
+    }
+
+    /// Set the access and modification times of the file
+    ///
+    /// As with `utimensat`, the change time is updated as well.
+    pub(in crate::metadata) fn set_times(
+        &mut self,
+        access: Option<UfsTime>,
+        write: Option<UfsTime>,
+    ) {
+        let latest = self.latest_mut();
+        if let Some(access) = access {
+            latest.access_time = access;
+        }
+        if let Some(write) = write {
+            latest.write_time = write;
+        }
+        latest.change_time = UfsTime::now();
+    }
+
+    /// Return the number of hard links to this file
+    ///
+    pub(crate) fn links(&self) -> u32 {
+        self.links.len() as u32
+    }
+
+    /// Return the directory holding each of the file's hard links
+    ///
+    pub(crate) fn link_dirs(&self) -> &[UfsUuid] {
+        &self.links
+    }
+
+    /// Note a new hard link to the file, in `dir_id`
+    ///
+    pub(in crate::metadata) fn add_link(&mut self, dir_id: UfsUuid) {
+        self.links.push(dir_id);
+    }
+
+    /// Note that a hard link to the file has moved from `from` to the directory `to`
+    ///
+    pub(in crate::metadata) fn move_link(&mut self, from: UfsUuid, to: UfsUuid) {
+        if let Some(dir_id) = self.links.iter_mut().find(|dir_id| **dir_id == from) {
+            *dir_id = to;
+        }
+    }
+
+    /// Note that the file's only hard link is now in `dir_id`
+    ///
+    /// This is for a file that's been recovered, and whose other links are lost.
+    pub(in crate::metadata) fn relink(&mut self, dir_id: UfsUuid) {
+        self.links = vec![dir_id];
+    }
+
+    /// Note that a hard link to the file, in `dir_id`, is gone
+    ///
+    /// The last link is never removed: once it's gone, so is the file.
+    pub(in crate::metadata) fn remove_link(&mut self, dir_id: UfsUuid) {
+        if self.links.len() > 1 {
+            if let Some(index) = self.links.iter().position(|d| *d == dir_id) {
+                self.links.remove(index);
+            }
+        }
+    }
+
+    /// Return the extended attributes of this file
+    ///
+    pub(crate) fn xattrs(&self) -> &Xattrs {
+        &self.xattrs
+    }
+
+    /// Return the extended attributes of this file, for changing
+    ///
+    pub(in crate::metadata) fn xattrs_mut(&mut self) -> &mut Xattrs {
+        &mut self.xattrs
     }
 
     pub(crate) fn new_version(&mut self) -> FileVersion {
+        // Opening a file to write isn't an access.
+        let access_time = self.latest_mut().access_time;
         self.last_version += 1;
-        self.versions.insert(
-            self.last_version,
-            FileVersion::new(self.id.new(self.last_version.to_string()), &self.id),
-        );
+        let mut version = FileVersion::new(self.id.new(self.last_version.to_string()), &self.id);
+        version.access_time = access_time;
+        self.versions.insert(self.last_version, version);
         self.get_latest()
     }
 
@@ -123,11 +266,77 @@ impl FileMetadata {
         version.clone()
     }
 
+    /// Return the size of the latest version, in bytes
+    ///
+    /// This saves cloning the version, as `get_latest` does.
+    pub(crate) fn latest_size(&self) -> FileSize {
+        self.versions[&self.last_version].size()
+    }
+
+    fn latest_mut(&mut self) -> &mut FileVersion {
+        self.versions.get_mut(&self.last_version).unwrap()
+    }
+
     /// Return a list of all of the versions of the file
     pub(crate) fn get_versions(&self) -> &HashMap<usize, FileVersion> {
         &self.versions
     }
 
+    /// Return the blocks of every version of the file
+    ///
+    /// Versions share blocks, and a version may use a block more than once, but each block is
+    /// listed only once.
+    pub(crate) fn all_blocks(&self) -> Vec<BlockNumber> {
+        let mut seen = HashSet::new();
+        self.versions
+            .values()
+            .flat_map(|v| v.blocks().iter())
+            .filter(|b| seen.insert(**b))
+            .cloned()
+            .collect()
+    }
+
+    /// Return the index of the latest version
+    ///
+    pub(crate) fn latest_version(&self) -> usize {
+        self.last_version
+    }
+
+    /// Remove the versions that `retention` doesn't keep
+    ///
+    /// The latest version is always kept. The caller is responsible for the removed versions'
+    /// blocks, some of which may be shared with the versions that remain.
+    pub(in crate::metadata) fn apply_retention(
+        &mut self,
+        retention: Retention,
+    ) -> Vec<FileVersion> {
+        let mut indices = self.versions.keys().cloned().collect::<Vec<_>>();
+        indices.sort_by(|a, b| b.cmp(a));
+
+        let mut removed = vec![];
+        for (rank, index) in indices.into_iter().enumerate() {
+            if !retention.keeps(rank, self.versions[&index].write_time()) {
+                if let Some(version) = self.remove_version(index) {
+                    removed.push(version);
+                }
+            }
+        }
+
+        removed
+    }
+
+    /// Remove a version of the file
+    ///
+    /// The latest version may not be removed. The caller is responsible for the version's blocks,
+    /// some of which may be shared with other versions.
+    pub(in crate::metadata) fn remove_version(&mut self, index: usize) -> Option<FileVersion> {
+        if index == self.last_version {
+            None
+        } else {
+            self.versions.remove(&index)
+        }
+    }
+
     pub(crate) fn commit_version(
         &mut self,
         mut version: FileVersion,
@@ -135,6 +344,10 @@ impl FileMetadata {
         debug!("--------");
         debug!("`commit_version`: {:?}", self);
         version.dirty = false;
+        // Reading the file doesn't touch the open copy of the version.
+        version.access_time = version
+            .access_time
+            .max(self.versions.get(&self.last_version).unwrap().access_time);
         self.last_version += 1;
         match self.versions.insert(self.last_version, version) {
             None => Ok(()),
@@ -181,6 +394,11 @@ pub struct FileVersion {
     /// The blocks that comprise the file
     ///
     blocks: Vec<BlockNumber>,
+    /// The offset in the file at which each of `blocks` starts
+    ///
+    /// Bytes between the end of one block and the start of the next, or the end of the file, are
+    /// a hole, and read as zeros.
+    offsets: Vec<FileSize>,
 }
 
 impl FileVersion {
@@ -201,6 +419,7 @@ impl FileVersion {
             access_time: time,
             size: 0,
             blocks: vec![],
+            offsets: vec![],
         }
     }
 
@@ -225,6 +444,13 @@ impl FileVersion {
         self.dirty
     }
 
+    /// Clear the dirty flag
+    ///
+    /// This is for an open copy of a version that has just been committed.
+    pub(crate) fn clean(&mut self) {
+        self.dirty = false;
+    }
+
     /// Return the UUID of this file version's file
     pub(crate) fn file_id(&self) -> &UfsUuid {
         &self.file_id
@@ -240,6 +466,24 @@ impl FileVersion {
         &self.blocks
     }
 
+    /// Return the offset in the file at which each block starts
+    pub(crate) fn offsets(&self) -> &Vec<FileSize> {
+        &self.offsets
+    }
+
+    /// Return the index of the first block that may hold the byte at `offset`
+    ///
+    /// `offsets` only ever grows at the end, and each block starts at or after the end of the one
+    /// before, so it's already sorted, and finding the block is a binary search rather than a walk
+    /// from the start of the file. The block returned is the last one starting at or before
+    /// `offset` -- whether it reaches that far, or `offset` is in a hole, is up to the caller.
+    pub(crate) fn block_index(&self, offset: FileSize) -> usize {
+        match self.offsets.binary_search(&offset) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        }
+    }
+
     /// Convert a copy of this FileVersion into a FileMetadata
     ///
     /// Note that the returned FileMetadata will contain only this version of the file
@@ -250,22 +494,119 @@ impl FileVersion {
     /// Append a block
     ///
     /// When a file is written to, it's done over time -- not all at once. Thus as blocks are
-    /// filled, they are added, one at a time, to the list of blocks.
-    pub(crate) fn append_block(&mut self, block: &Block) {
+    /// filled, they are added, one at a time, to the list of blocks. The block holds the bytes
+    /// starting at `offset`, and if that's past the end of the file, it leaves a hole. Blocks only
+    /// go on the end: `offset` must not be before the end of the file, which `write_file` makes
+    /// sure of.
+    pub(crate) fn append_block(&mut self, block: &Block, offset: FileSize) {
+        debug_assert!(
+            offset >= self.size,
+            "block appended before the end of the file"
+        );
         self.dirty = true;
+        self.write_time = UfsTime::now();
+        self.change_time = self.write_time;
         self.blocks.push(block.number());
-        debug!("adding block {} to blocklist", block.number());
-        self.size += block.size() as FileSize;
+        self.offsets.push(offset);
+        debug!("adding block {} to blocklist at {}", block.number(), offset);
+        self.size = offset + block.size() as FileSize;
         debug!("new size {}", self.size);
         trace!("{:?}", self);
     }
 
+    /// Truncate the list of blocks
+    ///
+    /// Only the first `count` blocks are kept, and the size of the file becomes `size`. The blocks
+    /// that were removed are returned. Anything past the end of the kept blocks is a hole.
+    pub(crate) fn truncate(&mut self, count: usize, size: FileSize) -> Vec<BlockNumber> {
+        self.dirty = true;
+        self.size = size;
+        self.write_time = UfsTime::now();
+        self.change_time = self.write_time;
+        self.offsets.truncate(count);
+        self.blocks.split_off(count.min(self.blocks.len()))
+    }
+
     /// Return the `write_time` timestamp
     pub(crate) fn write_time(&self) -> UfsTime {
         self.write_time
     }
 }
 
+/// A `FileMetadata`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct FileMetadataV1 {
+    id: UfsUuid,
+    dir_id: UfsUuid,
+    owner: UfsUuid,
+    perms: PermissionGroupsV1,
+    last_version: usize,
+    versions: HashMap<usize, FileVersionV1>,
+}
+
+impl FileMetadata {
+    /// Convert a file, and its versions, from format version 1
+    ///
+    pub(in crate::metadata) fn from_v1(file: FileMetadataV1, block_size: FileSize) -> Self {
+        let versions = file
+            .versions
+            .into_iter()
+            .map(|(n, v)| (n, FileVersion::from_v1(v, block_size)))
+            .collect::<HashMap<_, _>>();
+        let birth_time = versions
+            .values()
+            .map(|v| v.birth_time)
+            .min()
+            .unwrap_or_else(UfsTime::now);
+
+        FileMetadata {
+            id: file.id,
+            owner: file.owner,
+            perms: file.perms.into(),
+            birth_time,
+            links: vec![file.dir_id],
+            last_version: file.last_version,
+            versions,
+            xattrs: Xattrs::new(),
+        }
+    }
+}
+
+/// A `FileVersion`, as stored in format version 1
+///
+/// Blocks had no offsets: each was full, save the last, and they were laid end to end.
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct FileVersionV1 {
+    id: UfsUuid,
+    file_id: UfsUuid,
+    birth_time: UfsTime,
+    write_time: UfsTime,
+    change_time: UfsTime,
+    access_time: UfsTime,
+    size: FileSize,
+    blocks: Vec<BlockNumber>,
+}
+
+impl FileVersion {
+    fn from_v1(version: FileVersionV1, block_size: FileSize) -> Self {
+        FileVersion {
+            dirty: false,
+            id: version.id,
+            file_id: version.file_id,
+            birth_time: version.birth_time,
+            write_time: version.write_time,
+            change_time: version.change_time,
+            access_time: version.access_time,
+            size: version.size,
+            offsets: (0..version.blocks.len() as FileSize)
+                .map(|i| i * block_size)
+                .collect(),
+            blocks: version.blocks,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -287,4 +628,26 @@ mod test {
 
         assert_eq!(expected.to_vec(), version.nonce(), "incorrect nonce");
     }
+
+    #[test]
+    fn block_index() {
+        let root = UfsUuid::new_root_fs("test");
+        let mut version = FileVersion::new(root.new("test_version"), &root.new("test_file"));
+
+        assert_eq!(0, version.block_index(0), "empty file");
+        assert_eq!(0, version.block_index(4096), "empty file");
+
+        // Blocks of 10 bytes, with a hole between 30 and 100.
+        version.offsets = vec![0, 10, 20, 100, 110];
+        version.blocks = vec![1, 2, 3, 4, 5];
+
+        assert_eq!(0, version.block_index(0));
+        assert_eq!(0, version.block_index(9));
+        assert_eq!(1, version.block_index(10));
+        assert_eq!(2, version.block_index(25));
+        assert_eq!(2, version.block_index(50), "hole is after block 2");
+        assert_eq!(3, version.block_index(100));
+        assert_eq!(4, version.block_index(115));
+        assert_eq!(4, version.block_index(1000), "past the end");
+    }
 }
@@ -0,0 +1,224 @@
+//! Directory Tree Index
+//!
+//! Finding an entry by id used to mean searching the whole directory tree. The index remembers,
+//! for each id, the directory that holds it, and its name there. An entry is found by following
+//! parents up to the root, and then names back down, in time proportional to its depth. Moving a
+//! directory only changes its own place in the index, as everything in it keeps its name and
+//! parent.
+//!
+//! The tree remains the source of truth. The index isn't stored, but built when the metadata is
+//! loaded, and whatever it finds is checked against the tree. Files aren't indexed at all, as the
+//! metadata keeps them in a table of their own, and the directories only link to them.
+use {
+    super::{DirectoryEntry, DirectoryMetadata},
+    crate::uuid::UfsUuid,
+    std::collections::HashMap,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TreeIndex {
+    parents: HashMap<UfsUuid, (UfsUuid, String)>,
+}
+
+impl TreeIndex {
+    pub(crate) fn new() -> Self {
+        TreeIndex {
+            parents: HashMap::new(),
+        }
+    }
+
+    /// Index the directories and symlinks beneath `root`, descending at most `depth` levels
+    ///
+    pub(crate) fn build(root: &DirectoryMetadata, depth: usize) -> Self {
+        let mut index = TreeIndex::new();
+        index.index_dir(root, depth);
+        index
+    }
+
+    fn index_dir(&mut self, dir: &DirectoryMetadata, depth: usize) {
+        if depth == 0 {
+            return;
+        }
+        for (name, entry) in dir.entries() {
+            if entry.is_file() {
+                continue;
+            }
+            self.insert(entry.id(), dir.id(), name);
+            if let DirectoryEntry::Directory(d) = entry {
+                self.index_dir(d, depth - 1);
+            }
+        }
+    }
+
+    /// Record that the directory `dir` is called `name` in `parent`, along with what's in it
+    ///
+    /// This is for directories new to the tree, which come with their special directories. At most
+    /// `depth` levels beneath `dir` are indexed.
+    pub(crate) fn insert_dir(
+        &mut self,
+        dir: &DirectoryMetadata,
+        parent: UfsUuid,
+        name: &str,
+        depth: usize,
+    ) {
+        self.insert(dir.id(), parent, name);
+        self.index_dir(dir, depth);
+    }
+
+    /// Record that `id` is called `name` in the directory `parent`
+    ///
+    /// Any previous location of `id` is forgotten.
+    pub(crate) fn insert(&mut self, id: UfsUuid, parent: UfsUuid, name: &str) {
+        self.parents.insert(id, (parent, name.to_owned()));
+    }
+
+    /// Forget `id`, if it's indexed as `name` in `parent`
+    ///
+    /// True is returned if it was.
+    pub(crate) fn remove(&mut self, id: UfsUuid, parent: UfsUuid, name: &str) -> bool {
+        match self.parents.get(&id) {
+            Some((p, n)) if *p == parent && n == name => {
+                self.parents.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget `entry`, if it's indexed as `name` in `parent`, and everything beneath it
+    ///
+    /// True is returned if it was.
+    pub(crate) fn remove_entry(
+        &mut self,
+        entry: &DirectoryEntry,
+        parent: UfsUuid,
+        name: &str,
+    ) -> bool {
+        fn forget_dir(index: &mut TreeIndex, dir: &DirectoryMetadata) {
+            for (name, entry) in dir.entries() {
+                if index.remove(entry.id(), dir.id(), name) {
+                    if let DirectoryEntry::Directory(d) = entry {
+                        forget_dir(index, d);
+                    }
+                }
+            }
+        }
+
+        let removed = self.remove(entry.id(), parent, name);
+        if removed {
+            if let DirectoryEntry::Directory(d) = entry {
+                forget_dir(self, d);
+            }
+        }
+        removed
+    }
+
+    /// Return the names leading from the root, `root_id`, to `id`
+    ///
+    /// `None` is returned if `id` isn't indexed, or is more than `limit` levels down.
+    pub(crate) fn path(&self, id: UfsUuid, root_id: UfsUuid, limit: usize) -> Option<Vec<String>> {
+        let mut names = vec![];
+        let mut id = id;
+        while id != root_id {
+            if names.len() >= limit {
+                return None;
+            }
+            let (parent, name) = self.parents.get(&id)?;
+            names.push(name.clone());
+            id = *parent;
+        }
+
+        names.reverse();
+        Some(names)
+    }
+}
+
+/// Return the entry found by following `names` down from `root`
+///
+pub(crate) fn entry<'a>(
+    root: &'a DirectoryMetadata,
+    names: &[String],
+) -> Option<&'a DirectoryEntry> {
+    let (last, names) = names.split_last()?;
+    let mut dir = root;
+    for name in names {
+        match dir.entries().get(name) {
+            Some(DirectoryEntry::Directory(d)) => dir = d,
+            _ => return None,
+        }
+    }
+    dir.entries().get(last)
+}
+
+/// As `entry`, but mutable
+///
+/// The directories passed through are marked dirty, as whatever is returned may be changed.
+pub(crate) fn entry_mut<'a>(
+    root: &'a mut DirectoryMetadata,
+    names: &[String],
+) -> Option<&'a mut DirectoryEntry> {
+    let (last, names) = names.split_last()?;
+    let mut dir = root;
+    for name in names {
+        dir.dirty();
+        match dir.entries_mut().get_mut(name) {
+            Some(DirectoryEntry::Directory(d)) => dir = d,
+            _ => return None,
+        }
+    }
+    dir.dirty();
+    dir.entries_mut().get_mut(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn moves() {
+        let root_id = UfsUuid::new_root_fs("test");
+        let (a, b, l) = (root_id.new("a"), root_id.new("b"), root_id.new("l"));
+
+        let mut index = TreeIndex::new();
+        index.insert(a, root_id, "a");
+        index.insert(b, root_id, "b");
+        index.insert(l, a, "l");
+        assert_eq!(
+            Some(vec!["a".to_string(), "l".to_string()]),
+            index.path(l, root_id, 8)
+        );
+        assert_eq!(None, index.path(l, root_id, 1));
+
+        // Moving a directory carries its contents along.
+        index.insert(a, b, "c");
+        assert_eq!(
+            Some(vec!["b".to_string(), "c".to_string(), "l".to_string()]),
+            index.path(l, root_id, 8)
+        );
+
+        // Only the entry where it's indexed is removed.
+        assert!(!index.remove(l, root_id, "l"));
+        assert!(index.remove(l, a, "l"));
+        assert_eq!(None, index.path(l, root_id, 8));
+    }
+
+    #[test]
+    fn removing_a_directory_forgets_its_contents() {
+        let owner = UfsUuid::new_user("test");
+        let mut root = DirectoryMetadata::new(UfsUuid::new_root_fs("test"), None, owner);
+        let mut index = TreeIndex::build(&root, 8);
+
+        let mut a = root.new_subdirectory("a".to_string(), owner).unwrap();
+        index.insert_dir(&a, root.id(), "a", 8);
+        let b = a.new_subdirectory("b".to_string(), owner).unwrap();
+        index.insert_dir(&b, a.id(), "b", 8);
+        root.entries_mut()
+            .insert("a".to_string(), DirectoryEntry::Directory(a.clone()));
+        assert_eq!(TreeIndex::build(&root, 8), index);
+
+        let a = root.entries_mut().remove("a").unwrap();
+        assert!(index.remove_entry(&a, root.id(), "a"));
+        assert_eq!(None, index.path(b.id(), root.id(), 8));
+        assert_eq!(TreeIndex::build(&root, 8), index);
+    }
+}
@@ -14,7 +14,7 @@ use {
     std::{
         collections::HashMap,
         io::{self, Write},
-        path::PathBuf,
+        path::{Path, PathBuf},
     },
 };
 
@@ -67,6 +67,8 @@ pub(crate) enum GrantType {
     FileCloseEvent,
     FileReadEvent,
     FileWriteEvent,
+    FileRenameEvent,
+    DirRenameEvent,
     HttpGetEvent,
     HttpPostEvent,
     HttpPutEvent,
@@ -79,6 +81,11 @@ pub(crate) enum GrantType {
     CreateFileInvocation,
     CreateDirectoryInvocation,
     OpenDirectoryInvocation,
+    ReadDirInvocation,
+    StatInvocation,
+    ChmodInvocation,
+    KeyValueInvocation,
+    SendMessage,
 }
 
 impl GrantType {
@@ -92,6 +99,8 @@ impl GrantType {
             GrantType::FileCloseEvent => "receive file close events",
             GrantType::FileReadEvent => "receive file read events",
             GrantType::FileWriteEvent => "receive file write events",
+            GrantType::FileRenameEvent => "receive file rename events",
+            GrantType::DirRenameEvent => "receive directory rename events",
             GrantType::HttpGetEvent => "receive HTTP GET to",
             GrantType::HttpPostEvent => "receive HTTP POST to",
             GrantType::HttpPutEvent => "receive HTTP PUT to",
@@ -104,6 +113,11 @@ impl GrantType {
             GrantType::CreateFileInvocation => "create files",
             GrantType::CreateDirectoryInvocation => "create directories",
             GrantType::OpenDirectoryInvocation => "open directories",
+            GrantType::ReadDirInvocation => "read directories",
+            GrantType::StatInvocation => "read file metadata",
+            GrantType::ChmodInvocation => "change permissions",
+            GrantType::KeyValueInvocation => "store values across mounts",
+            GrantType::SendMessage => "send messages to other programs",
         }
     }
 }
@@ -119,6 +133,8 @@ pub(crate) struct ProgramPermissions {
     file_close: Grant,
     file_read: Grant,
     file_write: Grant,
+    file_rename: Grant,
+    dir_rename: Grant,
     // HTTP Events
     http_get: HttpGrant,
     http_post: HttpGrant,
@@ -133,6 +149,12 @@ pub(crate) struct ProgramPermissions {
     create_file: Grant,
     create_directory: Grant,
     open_directory: Grant,
+    read_directory: Grant,
+    stat: Grant,
+    chmod: Grant,
+    key_value: Grant,
+    // Inter-program messages
+    send_message: Grant,
 }
 
 impl ProgramPermissions {
@@ -146,6 +168,8 @@ impl ProgramPermissions {
             file_close: Grant::Unknown,
             file_read: Grant::Unknown,
             file_write: Grant::Unknown,
+            file_rename: Grant::Unknown,
+            dir_rename: Grant::Unknown,
             http_get: HttpGrant::new(),
             http_post: HttpGrant::new(),
             http_put: HttpGrant::new(),
@@ -158,6 +182,11 @@ impl ProgramPermissions {
             create_file: Grant::Unknown,
             create_directory: Grant::Unknown,
             open_directory: Grant::Unknown,
+            read_directory: Grant::Unknown,
+            stat: Grant::Unknown,
+            chmod: Grant::Unknown,
+            key_value: Grant::Unknown,
+            send_message: Grant::Unknown,
         }
     }
 
@@ -171,6 +200,8 @@ impl ProgramPermissions {
             GrantType::FileCloseEvent => self.file_close,
             GrantType::FileReadEvent => self.file_read,
             GrantType::FileWriteEvent => self.file_write,
+            GrantType::FileRenameEvent => self.file_rename,
+            GrantType::DirRenameEvent => self.dir_rename,
             GrantType::OpenFileInvocation => self.open_file,
             GrantType::CloseFileInvocation => self.close_file,
             GrantType::ReadFileInvocation => self.read_file,
@@ -178,6 +209,11 @@ impl ProgramPermissions {
             GrantType::CreateFileInvocation => self.create_file,
             GrantType::CreateDirectoryInvocation => self.create_directory,
             GrantType::OpenDirectoryInvocation => self.open_directory,
+            GrantType::ReadDirInvocation => self.read_directory,
+            GrantType::StatInvocation => self.stat,
+            GrantType::ChmodInvocation => self.chmod,
+            GrantType::KeyValueInvocation => self.key_value,
+            GrantType::SendMessage => self.send_message,
             _ => panic!("called get_grant with HTTP grant-type"),
         }
     }
@@ -227,6 +263,14 @@ impl ProgramPermissions {
                 self.file_write = grant;
                 grant
             }
+            GrantType::FileRenameEvent => {
+                self.file_rename = grant;
+                grant
+            }
+            GrantType::DirRenameEvent => {
+                self.dir_rename = grant;
+                grant
+            }
             GrantType::OpenFileInvocation => {
                 self.open_file = grant;
                 grant
@@ -255,6 +299,26 @@ impl ProgramPermissions {
                 self.open_directory = grant;
                 grant
             }
+            GrantType::ReadDirInvocation => {
+                self.read_directory = grant;
+                grant
+            }
+            GrantType::StatInvocation => {
+                self.stat = grant;
+                grant
+            }
+            GrantType::ChmodInvocation => {
+                self.chmod = grant;
+                grant
+            }
+            GrantType::KeyValueInvocation => {
+                self.key_value = grant;
+                grant
+            }
+            GrantType::SendMessage => {
+                self.send_message = grant;
+                grant
+            }
             _ => panic!("called set_grant with HTTP grant-type"),
         }
     }
@@ -316,6 +380,41 @@ impl WasmPermissions {
         }
     }
 
+    /// Look up a grant, without asking the user if it's not yet known
+    ///
+    pub(crate) fn peek_grant(&self, program: &Path, grant_type: GrantType) -> Option<Grant> {
+        self.inner.get(program).map(|p| p.get_grant(grant_type))
+    }
+
+    /// Return true if the program may change the file system
+    ///
+    /// That's any program with a grant to change the file system that hasn't been denied: the user
+    /// may yet allow one that's unknown. Unlike `check_grant`, this never asks the user.
+    pub(crate) fn may_write(&self, program: &Path) -> bool {
+        const WRITES: [GrantType; 5] = [
+            GrantType::WriteFileInvocation,
+            GrantType::CreateFileInvocation,
+            GrantType::CreateDirectoryInvocation,
+            GrantType::ChmodInvocation,
+            GrantType::KeyValueInvocation,
+        ];
+
+        match self.inner.get(program) {
+            Some(p) => WRITES.iter().any(|g| p.get_grant(*g) != Grant::Deny),
+            None => false,
+        }
+    }
+
+    /// Set a grant without asking the user
+    ///
+    #[cfg(test)]
+    pub(crate) fn set_grant(&mut self, program: &Path, grant_type: GrantType, grant: Grant) {
+        if let Some(p) = self.inner.get_mut(program) {
+            self.dirty = true;
+            p.set_grant(grant_type, grant);
+        }
+    }
+
     pub(crate) fn check_http_grant(
         &mut self,
         program: &PathBuf,
@@ -334,6 +433,81 @@ impl WasmPermissions {
     }
 }
 
+/// `ProgramPermissions`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+struct ProgramPermissionsV1 {
+    file_create: Grant,
+    dir_create: Grant,
+    file_delete: Grant,
+    dir_delete: Grant,
+    file_open: Grant,
+    file_close: Grant,
+    file_read: Grant,
+    file_write: Grant,
+    http_get: HttpGrant,
+    http_post: HttpGrant,
+    http_put: HttpGrant,
+    http_patch: HttpGrant,
+    http_delete: HttpGrant,
+    open_file: Grant,
+    close_file: Grant,
+    read_file: Grant,
+    write_file: Grant,
+    create_file: Grant,
+    create_directory: Grant,
+    open_directory: Grant,
+}
+
+impl From<ProgramPermissionsV1> for ProgramPermissions {
+    fn from(perms: ProgramPermissionsV1) -> Self {
+        ProgramPermissions {
+            file_create: perms.file_create,
+            dir_create: perms.dir_create,
+            file_delete: perms.file_delete,
+            dir_delete: perms.dir_delete,
+            file_open: perms.file_open,
+            file_close: perms.file_close,
+            file_read: perms.file_read,
+            file_write: perms.file_write,
+            http_get: perms.http_get,
+            http_post: perms.http_post,
+            http_put: perms.http_put,
+            http_patch: perms.http_patch,
+            http_delete: perms.http_delete,
+            open_file: perms.open_file,
+            close_file: perms.close_file,
+            read_file: perms.read_file,
+            write_file: perms.write_file,
+            create_file: perms.create_file,
+            create_directory: perms.create_directory,
+            open_directory: perms.open_directory,
+            ..ProgramPermissions::new()
+        }
+    }
+}
+
+/// `WasmPermissions`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct WasmPermissionsV1 {
+    dirty: bool,
+    inner: HashMap<PathBuf, ProgramPermissionsV1>,
+}
+
+impl From<WasmPermissionsV1> for WasmPermissions {
+    fn from(grants: WasmPermissionsV1) -> Self {
+        WasmPermissions {
+            dirty: grants.dirty,
+            inner: grants
+                .inner
+                .into_iter()
+                .map(|(program, perms)| (program, perms.into()))
+                .collect(),
+        }
+    }
+}
+
 fn query_user(prompt: String) -> bool {
     let mut buffer = String::new();
     print!("{}", prompt);
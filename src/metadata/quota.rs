@@ -0,0 +1,154 @@
+//! Space Quotas
+//!
+//! A quota caps the bytes that a user, or a directory and everything below it, may hold. What's
+//! counted is live data -- the latest version of each file -- which is what a writer has control
+//! over. History is left to the retention policy and the space reservation.
+//!
+//! Usage is only tracked for users and directories that have a quota. It's counted up when the
+//! quota is set, and from then on adjusted as files grow, shrink, move, and are removed. A file
+//! with several hard links counts once against each directory that holds any of them.
+//!
+//! Bytes written to an open file count as soon as their blocks are allocated, by being held
+//! against the quotas until the file is committed. That way, writers on several handles can't
+//! each pass the check and together go over. Holds aren't stored.
+use {
+    super::FileSize,
+    crate::uuid::UfsUuid,
+    serde_derive::{Deserialize, Serialize},
+    std::{collections::HashSet, path::PathBuf},
+};
+
+/// What a quota applies to
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuotaTarget {
+    /// The files owned by the named user
+    ///
+    User(String),
+    /// The files in a directory, and in the directories below it
+    ///
+    Directory(PathBuf),
+}
+
+/// A byte limit, and the bytes counted against it
+///
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Quota {
+    /// The most bytes that may be used
+    ///
+    limit: FileSize,
+    /// The bytes in use
+    ///
+    used: FileSize,
+    /// The bytes held for open files, which aren't yet in use
+    ///
+    #[serde(skip)]
+    held: FileSize,
+}
+
+impl Quota {
+    /// Create a new `Quota`
+    ///
+    pub(crate) fn new(limit: FileSize, used: FileSize) -> Self {
+        Quota {
+            limit,
+            used,
+            held: 0,
+        }
+    }
+
+    /// Return the most bytes that may be used
+    ///
+    pub(crate) fn limit(&self) -> FileSize {
+        self.limit
+    }
+
+    /// Return the bytes in use
+    ///
+    pub(crate) fn used(&self) -> FileSize {
+        self.used
+    }
+
+    /// Return the bytes held for open files
+    ///
+    pub(crate) fn held(&self) -> FileSize {
+        self.held
+    }
+
+    /// Return true if `bytes` more fit within the limit
+    ///
+    /// Held bytes count as though they were in use.
+    pub(crate) fn allows(&self, bytes: FileSize) -> bool {
+        self.used.saturating_add(self.held).saturating_add(bytes) <= self.limit
+    }
+
+    /// Count `delta` bytes against the quota
+    ///
+    /// A negative `delta` gives bytes back. Usage never goes below zero.
+    pub(crate) fn charge(&mut self, delta: i64) {
+        self.used = adjust(self.used, delta);
+    }
+
+    /// Hold `delta` bytes against the quota, for an open file
+    ///
+    /// A negative `delta` releases bytes.
+    pub(crate) fn hold(&mut self, delta: i64) {
+        self.held = adjust(self.held, delta);
+    }
+}
+
+/// Add `delta` to `bytes`, without going below zero
+///
+fn adjust(bytes: FileSize, delta: i64) -> FileSize {
+    if delta < 0 {
+        bytes.saturating_sub(delta.wrapping_neg() as FileSize)
+    } else {
+        bytes.saturating_add(delta as FileSize)
+    }
+}
+
+/// Bytes held against the quotas of a file's owner, and the directories holding the file
+///
+/// The quotas are remembered, so that the bytes are given back to the same ones, even if the file
+/// has since moved.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct QuotaHold {
+    pub(in crate::metadata) owner: UfsUuid,
+    pub(in crate::metadata) dirs: HashSet<UfsUuid>,
+    pub(in crate::metadata) bytes: FileSize,
+}
+
+impl QuotaHold {
+    /// Return the bytes held
+    ///
+    pub(crate) fn bytes(&self) -> FileSize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn charge() {
+        let mut q = Quota::new(100, 40);
+        assert!(q.allows(60));
+        assert!(!q.allows(61));
+
+        q.charge(50);
+        assert_eq!(90, q.used());
+        assert!(!q.allows(11));
+
+        q.charge(-100);
+        assert_eq!(0, q.used());
+        assert_eq!(100, q.limit());
+
+        q.hold(70);
+        assert_eq!(70, q.held());
+        assert!(q.allows(30));
+        assert!(!q.allows(31));
+        q.hold(-100);
+        assert_eq!(0, q.held());
+    }
+}
@@ -0,0 +1,68 @@
+//! Space Reservations
+//!
+//! Every write to a file keeps the previous version, so left alone, history will eventually fill
+//! the volume. A reservation sets aside a percentage of the volume for live data -- the latest
+//! version of each file. When a write would eat into the reserve, older versions are pruned,
+//! oldest first, to make room. The write only fails if there's no room for it even then.
+use {
+    crate::block::BlockCardinality,
+    serde_derive::{Deserialize, Serialize},
+};
+
+/// The share of a volume kept for the latest version of each file
+///
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Reservation {
+    /// The percentage of the volume's blocks that are reserved
+    ///
+    percent: u8,
+}
+
+impl Reservation {
+    /// Create a new `Reservation`
+    ///
+    /// Anything over 100% is taken as 100%.
+    pub(crate) fn new(percent: u8) -> Self {
+        Reservation {
+            percent: percent.min(100),
+        }
+    }
+
+    /// Return the percentage of the volume that is reserved
+    ///
+    pub(crate) fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    /// Return the number of reserved blocks on a volume of `total` blocks
+    ///
+    pub(crate) fn reserved_blocks(&self, total: BlockCardinality) -> BlockCardinality {
+        total * self.percent as BlockCardinality / 100
+    }
+
+    /// Return the number of blocks that must be freed before writing `needed` blocks
+    ///
+    /// This is how far the write would dip into the reserve, given `free` blocks.
+    pub(crate) fn shortfall(
+        &self,
+        total: BlockCardinality,
+        free: BlockCardinality,
+        needed: BlockCardinality,
+    ) -> BlockCardinality {
+        (needed + self.reserved_blocks(total)).saturating_sub(free)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shortfall() {
+        let r = Reservation::new(25);
+        assert_eq!(25, r.reserved_blocks(100));
+        assert_eq!(0, r.shortfall(100, 50, 25));
+        assert_eq!(5, r.shortfall(100, 50, 30));
+        assert_eq!(100, Reservation::new(200).percent());
+    }
+}
@@ -0,0 +1,55 @@
+//! Version Retention
+//!
+//! Every write to a file keeps the previous version. A retention policy bounds the history that's
+//! kept, either by count, or by age. Versions that fall outside of the policy are pruned as each
+//! new version is committed. The policy is set for the file system, and may be overridden by a
+//! directory, for the files within it. The latest version of a file is always kept.
+use {
+    crate::time::UfsTime,
+    serde_derive::{Deserialize, Serialize},
+    std::time::Duration,
+};
+
+/// How many versions of a file to keep
+///
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Retention {
+    /// Keep this many of the most recent versions
+    ///
+    Versions(usize),
+    /// Keep the versions written within this long
+    ///
+    Newer(Duration),
+}
+
+impl Retention {
+    /// Return true if a version is kept
+    ///
+    /// `rank` is the version's place in the history, counting from zero for the latest.
+    pub(crate) fn keeps(&self, rank: usize, written: UfsTime) -> bool {
+        rank == 0
+            || match self {
+                Retention::Versions(count) => rank < *count,
+                Retention::Newer(age) => written.age() <= *age,
+            }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps() {
+        let now = UfsTime::now();
+
+        assert!(Retention::Versions(0).keeps(0, now));
+        assert!(Retention::Versions(2).keeps(1, now));
+        assert!(!Retention::Versions(2).keeps(2, now));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(Retention::Newer(Duration::from_secs(60)).keeps(5, now));
+        assert!(!Retention::Newer(Duration::from_millis(10)).keeps(1, now));
+        assert!(Retention::Newer(Duration::from_millis(10)).keeps(0, now));
+    }
+}
@@ -0,0 +1,126 @@
+//! Wasm Program Storage
+//!
+//! Wasm programs keep their state in memory, and it's gone once the file system is unmounted. This
+//! is a small key/value store, kept with the file system metadata, where a program may put the
+//! things that it would like to remember the next time that it's started. Each program has a store
+//! of its own, keyed by the program's path.
+use {
+    crate::IOFSErrorKind,
+    serde_derive::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    },
+};
+
+/// The most space that a program's store may take, keys and values together
+///
+pub(crate) const STORE_SIZE_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct WasmStore {
+    inner: HashMap<PathBuf, HashMap<String, String>>,
+}
+
+impl WasmStore {
+    pub(crate) fn new() -> Self {
+        WasmStore {
+            inner: HashMap::new(),
+        }
+    }
+
+    /// Return the value stored under `key` by `program`
+    ///
+    pub(crate) fn get(&self, program: &Path, key: &str) -> Option<&str> {
+        self.inner
+            .get(program)
+            .and_then(|values| values.get(key))
+            .map(|value| value.as_str())
+    }
+
+    /// Store `value` under `key` for `program`
+    ///
+    /// Any previous value is replaced. The program's store may not grow beyond
+    /// `STORE_SIZE_LIMIT`.
+    pub(crate) fn put(
+        &mut self,
+        program: &Path,
+        key: String,
+        value: String,
+    ) -> Result<(), failure::Error> {
+        let values = self
+            .inner
+            .entry(program.to_path_buf())
+            .or_insert_with(HashMap::new);
+
+        let size = values.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+        let old = values.get(&key).map_or(0, |v| key.len() + v.len());
+        if size - old + key.len() + value.len() > STORE_SIZE_LIMIT {
+            return Err(IOFSErrorKind::StoreTooLarge.into());
+        }
+
+        values.insert(key, value);
+        Ok(())
+    }
+
+    /// Forget everything stored by `program`
+    ///
+    pub(crate) fn remove_program(&mut self, program: &Path) {
+        self.inner.remove(program);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scoped_by_program() {
+        let counter = PathBuf::from("/.wasm/word-count.wasm");
+        let other = PathBuf::from("/.wasm/other.wasm");
+
+        let mut store = WasmStore::new();
+        store
+            .put(&counter, "total".to_string(), "42".to_string())
+            .unwrap();
+
+        assert_eq!(Some("42"), store.get(&counter, "total"));
+        assert_eq!(None, store.get(&other, "total"));
+
+        let bytes = bincode::serialize(&store).unwrap();
+        let mut store: WasmStore = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(Some("42"), store.get(&counter, "total"));
+
+        store.remove_program(&counter);
+        assert_eq!(None, store.get(&counter, "total"));
+    }
+
+    #[test]
+    fn size_limit() {
+        let counter = PathBuf::from("/.wasm/word-count.wasm");
+        let other = PathBuf::from("/.wasm/other.wasm");
+
+        let mut store = WasmStore::new();
+        let big = "x".repeat(STORE_SIZE_LIMIT - "big".len());
+        store.put(&counter, "big".to_string(), big).unwrap();
+
+        let e = store
+            .put(&counter, "more".to_string(), "x".to_string())
+            .unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::StoreTooLarge),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Replacing a value only counts the difference, and each program has a store of its own.
+        store
+            .put(&counter, "big".to_string(), "small".to_string())
+            .unwrap();
+        store
+            .put(&counter, "more".to_string(), "x".to_string())
+            .unwrap();
+        store
+            .put(&other, "big".to_string(), "x".repeat(STORE_SIZE_LIMIT - 3))
+            .unwrap();
+    }
+}
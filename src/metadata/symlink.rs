@@ -0,0 +1,75 @@
+//! Metadata for Symbolic Links
+//!
+//! A symbolic link is just a path, stored in a directory under a name. The target is not checked
+//! when the link is created: it may be absolute, relative to the directory containing the link, or
+//! point at nothing at all.
+use {
+    crate::uuid::UfsUuid,
+    serde_derive::{Deserialize, Serialize},
+    std::path::{Path, PathBuf},
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SymlinkMetadata {
+    /// The UUID of this link
+    ///
+    id: UfsUuid,
+    /// The UUID of the directory containing this link
+    ///
+    dir_id: UfsUuid,
+    /// Owner of this link
+    ///
+    owner: UfsUuid,
+    /// The path that this link points at
+    ///
+    target: PathBuf,
+}
+
+impl SymlinkMetadata {
+    /// Create a new `SymlinkMetadata`
+    ///
+    pub(crate) fn new(id: UfsUuid, dir_id: UfsUuid, owner: UfsUuid, target: PathBuf) -> Self {
+        SymlinkMetadata {
+            id,
+            dir_id,
+            owner,
+            target,
+        }
+    }
+
+    /// Return the UUID of this link
+    ///
+    pub(crate) fn id(&self) -> UfsUuid {
+        self.id
+    }
+
+    /// Return the directory id of this link
+    ///
+    pub(crate) fn dir_id(&self) -> UfsUuid {
+        self.dir_id
+    }
+
+    /// Move the link to another directory
+    ///
+    pub(in crate::metadata) fn set_dir_id(&mut self, dir_id: UfsUuid) {
+        self.dir_id = dir_id;
+    }
+
+    /// Return the owner
+    ///
+    pub(crate) fn owner(&self) -> UfsUuid {
+        self.owner
+    }
+
+    /// Give the link to another user
+    ///
+    pub(in crate::metadata) fn set_owner(&mut self, owner: UfsUuid) {
+        self.owner = owner;
+    }
+
+    /// Return the path that this link points at
+    ///
+    pub(crate) fn target(&self) -> &Path {
+        &self.target
+    }
+}
@@ -2,8 +2,9 @@
 //!
 
 use {
+    super::Quota,
     crate::{
-        crypto::{decrypt, encrypt, hash_password},
+        crypto::{hash_password, unwrap_key, wrap_key, KdfParams},
         uuid::UfsUuid,
     },
     log::debug,
@@ -18,26 +19,64 @@ const VALIDATION_NONCE: &[u8; 24] = b"abcdefghijklmnopqrstuvwx";
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub(in crate::metadata) struct User {
     id: UfsUuid,
-    nonce: [u8; 16],
+    salt: [u8; 16],
+    /// The parameters used to derive the key from the password
+    ///
+    kdf: KdfParams,
     validation: Vec<u8>,
+    /// The user's key, encrypted with the key derived from their password
+    ///
+    /// Until the password is changed, the user's key is simply the derived key, and this is
+    /// `None`. Afterwards, the original key is kept here so that it outlives the old password.
+    wrapped_key: Option<Vec<u8>>,
+    /// The nonce that the user's key is wrapped with
+    ///
+    /// This is drawn afresh along with the salt, each time that the password is set.
+    key_nonce: [u8; 24],
+    /// The most that the user's files may hold, and how much they do
+    ///
+    /// `None` means that there's no limit, and usage isn't tracked.
+    quota: Option<Quota>,
 }
 
 impl User {
     pub(crate) fn new<S: AsRef<str>>(user_name: S, password: S) -> Self {
-        let mut nonce: [u8; 16] = [0; 16];
-        rand::thread_rng().fill_bytes(&mut nonce);
-
-        let key = hash_password(password.as_ref(), &nonce);
-        let mut validation = VALIDATION_STRING.to_owned().into_bytes();
-        encrypt(&key, &VALIDATION_NONCE.to_vec(), 0, &mut validation);
-
         let id = UfsUuid::new_user(user_name.as_ref());
 
-        User {
+        let mut user = User {
             id,
-            nonce,
-            validation: validation.to_vec(),
-        }
+            salt: [0; 16],
+            kdf: KdfParams::default(),
+            validation: vec![],
+            wrapped_key: None,
+            key_nonce: [0; 24],
+            quota: None,
+        };
+        user.set_password(password.as_ref(), None);
+        user
+    }
+
+    /// Store the user's credentials under a new password
+    ///
+    /// A fresh salt is drawn, and the validation string is encrypted with the key derived from
+    /// the password, using the current default parameters. If the user already has a `key`, it's
+    /// wrapped with the derived key, under a fresh nonce.
+    fn set_password(&mut self, password: &str, key: Option<[u8; 32]>) {
+        rand::thread_rng().fill_bytes(&mut self.salt);
+        rand::thread_rng().fill_bytes(&mut self.key_nonce);
+        self.kdf = KdfParams::default();
+
+        let derived = hash_password(password, &self.salt, &self.kdf)
+            .expect("the default key derivation parameters are valid");
+        let mut validation = VALIDATION_STRING.to_owned().into_bytes();
+        wrap_key(&derived, VALIDATION_NONCE, &mut validation);
+        self.validation = validation;
+
+        self.wrapped_key = key.map(|key| {
+            let mut wrapped = key.to_vec();
+            wrap_key(&derived, &self.key_nonce, &mut wrapped);
+            wrapped
+        });
     }
 }
 
@@ -62,6 +101,79 @@ impl UserMetadata {
         self.inner.keys().cloned().collect()
     }
 
+    /// Remove a user
+    ///
+    /// The user's id is returned, or `None` if there is no such user.
+    pub(crate) fn remove_user<S: AsRef<str>>(&mut self, id: S) -> Option<UfsUuid> {
+        self.inner.remove(id.as_ref()).map(|user| user.id)
+    }
+
+    /// Change a user's password
+    ///
+    /// The `old` password must be correct. The user keeps their key, so anything encrypted with it
+    /// remains readable. Returns the user's id, or `None` if the user or password is wrong.
+    pub(crate) fn change_password<S: AsRef<str>>(
+        &mut self,
+        id: S,
+        old: S,
+        new: S,
+    ) -> Option<UfsUuid> {
+        let (user_id, key) = self.get_user(id.as_ref(), old.as_ref())?;
+        let user = self.inner.get_mut(id.as_ref())?;
+        user.set_password(new.as_ref(), Some(key));
+        Some(user_id)
+    }
+
+    /// Set, or clear, a user's quota
+    ///
+    /// Returns the user's id, or `None` if there is no such user.
+    pub(crate) fn set_quota<S: AsRef<str>>(
+        &mut self,
+        id: S,
+        quota: Option<Quota>,
+    ) -> Option<UfsUuid> {
+        let user = self.inner.get_mut(id.as_ref())?;
+        user.quota = quota;
+        Some(user.id)
+    }
+
+    /// Return the quota of the user with the given id, if they have one
+    ///
+    pub(crate) fn quota(&self, id: UfsUuid) -> Option<Quota> {
+        self.inner
+            .values()
+            .find(|user| user.id == id)
+            .and_then(|user| user.quota)
+    }
+
+    /// Count `delta` bytes against the quota of the user with the given id
+    ///
+    /// Nothing happens if they don't have a quota.
+    pub(crate) fn charge(&mut self, id: UfsUuid, delta: i64) {
+        if let Some(quota) = self
+            .inner
+            .values_mut()
+            .find(|user| user.id == id)
+            .and_then(|user| user.quota.as_mut())
+        {
+            quota.charge(delta);
+        }
+    }
+
+    /// Hold `delta` bytes against the quota of the user with the given id, for an open file
+    ///
+    /// Nothing happens if they don't have a quota.
+    pub(crate) fn hold(&mut self, id: UfsUuid, delta: i64) {
+        if let Some(quota) = self
+            .inner
+            .values_mut()
+            .find(|user| user.id == id)
+            .and_then(|user| user.quota.as_mut())
+        {
+            quota.hold(delta);
+        }
+    }
+
     pub(crate) fn get_user<S: AsRef<str>>(
         &self,
         id: S,
@@ -72,12 +184,21 @@ impl UserMetadata {
 
         match self.inner.get(id.as_ref()) {
             Some(u) => {
-                let key = hash_password(password, &u.nonce);
+                let key = hash_password(password, &u.salt, &u.kdf).ok()?;
                 let mut validation = u.validation.clone();
-                decrypt(&key, &VALIDATION_NONCE.to_vec(), 0, &mut validation);
+                unwrap_key(&key, VALIDATION_NONCE, &mut validation);
 
                 if validation == VALIDATION_STRING.as_bytes() {
-                    Some((u.id, key))
+                    match &u.wrapped_key {
+                        Some(wrapped) => {
+                            let mut unwrapped = wrapped.clone();
+                            unwrap_key(&key, &u.key_nonce, &mut unwrapped);
+                            let mut user_key = [0; 32];
+                            user_key.copy_from_slice(&unwrapped);
+                            Some((u.id, user_key))
+                        }
+                        None => Some((u.id, key)),
+                    }
                 } else {
                     None
                 }
@@ -86,3 +207,136 @@ impl UserMetadata {
         }
     }
 }
+
+/// A `User`, as stored in format version 1
+///
+/// Keys were derived with PBKDF2, salted with the nonce.
+#[derive(Debug, Deserialize, Serialize)]
+struct UserV1 {
+    id: UfsUuid,
+    nonce: [u8; 16],
+    validation: Vec<u8>,
+}
+
+/// Users from version 1 have no wrapped key, but they're given a nonce for when they do
+///
+impl From<UserV1> for User {
+    fn from(user: UserV1) -> Self {
+        let mut key_nonce = [0; 24];
+        rand::thread_rng().fill_bytes(&mut key_nonce);
+
+        User {
+            id: user.id,
+            salt: user.nonce,
+            kdf: KdfParams::legacy(),
+            validation: user.validation,
+            wrapped_key: None,
+            key_nonce,
+            quota: None,
+        }
+    }
+}
+
+/// `UserMetadata`, as stored in format version 1
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub(in crate::metadata) struct UserMetadataV1 {
+    inner: HashMap<String, UserV1>,
+}
+
+impl From<UserMetadataV1> for UserMetadata {
+    fn from(users: UserMetadataV1) -> Self {
+        UserMetadata {
+            inner: users
+                .inner
+                .into_iter()
+                .map(|(name, user)| (name, user.into()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn change_password() {
+        let mut users = UserMetadata::new();
+        users.new_user("test".to_string(), "foobar".to_string());
+        let (id, key) = users.get_user("test", "foobar").unwrap();
+
+        assert_eq!(None, users.change_password("test", "wrong", "barfoo"));
+        assert_eq!(Some(id), users.change_password("test", "foobar", "barfoo"));
+        assert_eq!(None, users.get_user("test", "foobar"));
+        assert_eq!(Some((id, key)), users.get_user("test", "barfoo"));
+
+        // And again, now that the key is wrapped. It's wrapped under a new nonce each time.
+        let nonce = users.inner["test"].key_nonce;
+        assert_eq!(Some(id), users.change_password("test", "barfoo", "bazqux"));
+        assert_eq!(Some((id, key)), users.get_user("test", "bazqux"));
+        assert_ne!(nonce, users.inner["test"].key_nonce);
+    }
+
+    #[test]
+    fn quota() {
+        let mut users = UserMetadata::new();
+        users.new_user("test".to_string(), "foobar".to_string());
+        let id = UfsUuid::new_user("test");
+
+        assert_eq!(None, users.set_quota("nobody", Some(Quota::new(100, 0))));
+        assert_eq!(Some(id), users.set_quota("test", Some(Quota::new(100, 0))));
+        users.charge(id, 60);
+        assert_eq!(Some(Quota::new(100, 60)), users.quota(id));
+
+        users.set_quota("test", None);
+        users.charge(id, 60);
+        assert_eq!(None, users.quota(id));
+    }
+
+    #[test]
+    fn distinct_salts() {
+        let mut users = UserMetadata::new();
+        users.new_user("one".to_string(), "foobar".to_string());
+        users.new_user("two".to_string(), "foobar".to_string());
+
+        assert_eq!(None, users.get_user("one", "barfoo"));
+        let (_, one) = users.get_user("one", "foobar").unwrap();
+        let (_, two) = users.get_user("two", "foobar").unwrap();
+        assert_ne!(one, two);
+
+        let (one, two) = (&users.inner["one"], &users.inner["two"]);
+        assert_ne!(one.salt, two.salt);
+        assert_ne!(one.validation, two.validation);
+        assert_eq!(KdfParams::default(), one.kdf);
+    }
+
+    #[test]
+    fn format_version_1() {
+        let id = UfsUuid::new_user("test");
+        let mut nonce = [0; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let key = hash_password("foobar", &nonce, &KdfParams::legacy()).unwrap();
+        let mut validation = VALIDATION_STRING.to_owned().into_bytes();
+        wrap_key(&key, VALIDATION_NONCE, &mut validation);
+
+        let mut inner = HashMap::new();
+        inner.insert(
+            "test".to_string(),
+            UserV1 {
+                id,
+                nonce,
+                validation,
+            },
+        );
+        let mut users = UserMetadata::from(UserMetadataV1 { inner });
+
+        assert_eq!(None, users.get_user("test", "barfoo"));
+        assert_eq!(Some((id, key)), users.get_user("test", "foobar"));
+
+        // Changing the password moves the user to the current parameters, and keeps their key.
+        assert_eq!(Some(id), users.change_password("test", "foobar", "barfoo"));
+        assert_eq!(KdfParams::default(), users.inner["test"].kdf);
+        assert_eq!(Some((id, key)), users.get_user("test", "barfoo"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Extended Attributes
+//!
+//! Files and directories each carry a map of extended attributes, named byte strings that tools
+//! use to hang their own data off of an entry -- Finder tags, SELinux labels, and the like. They
+//! are stored with the rest of the metadata, so the total size of an entry's attributes is kept
+//! within `XATTR_SIZE_LIMIT`.
+use {
+    crate::IOFSErrorKind,
+    serde_derive::{Deserialize, Serialize},
+    std::collections::HashMap,
+};
+
+/// The most space that an entry's attributes may take, names and values together
+///
+pub(crate) const XATTR_SIZE_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Xattrs {
+    inner: HashMap<String, Vec<u8>>,
+}
+
+impl Xattrs {
+    pub(crate) fn new() -> Self {
+        Xattrs {
+            inner: HashMap::new(),
+        }
+    }
+
+    /// Return the value of the attribute `name`
+    ///
+    pub(crate) fn get(&self, name: &str) -> Option<&[u8]> {
+        self.inner.get(name).map(|value| value.as_slice())
+    }
+
+    /// Return the names of the attributes, in no particular order
+    ///
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.inner.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Set the attribute `name` to `value`
+    ///
+    /// Any previous value is replaced. An error is returned, and nothing is changed, if the
+    /// attributes would no longer fit within `XATTR_SIZE_LIMIT`.
+    pub(crate) fn set(&mut self, name: &str, value: &[u8]) -> Result<(), failure::Error> {
+        let old = self.inner.get(name).map_or(0, |v| name.len() + v.len());
+        if self.size() - old + name.len() + value.len() > XATTR_SIZE_LIMIT {
+            return Err(IOFSErrorKind::AttributesTooLarge.into());
+        }
+
+        self.inner.insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    /// Remove the attribute `name`
+    ///
+    pub(crate) fn remove(&mut self, name: &str) -> Result<(), failure::Error> {
+        match self.inner.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(IOFSErrorKind::NoAttribute.into()),
+        }
+    }
+
+    /// Return the space taken by the attributes
+    ///
+    fn size(&self) -> usize {
+        self.inner
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_limit() {
+        let mut xattrs = Xattrs::new();
+        xattrs.set("user.tag", b"red").unwrap();
+        assert_eq!(Some(&b"red"[..]), xattrs.get("user.tag"));
+
+        let big = vec![0; XATTR_SIZE_LIMIT - "user.big".len() - "user.tag".len() - 3];
+        xattrs.set("user.big", &big).unwrap();
+
+        let e = xattrs.set("user.more", b"x").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::AttributesTooLarge),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+
+        // Replacing a value only counts the difference.
+        xattrs.set("user.tag", b"blu").unwrap();
+        assert_eq!(Some(&b"blu"[..]), xattrs.get("user.tag"));
+
+        xattrs.remove("user.big").unwrap();
+        xattrs.set("user.more", b"x").unwrap();
+
+        let mut names = xattrs.names();
+        names.sort();
+        assert_eq!(vec!["user.more", "user.tag"], names);
+
+        let e = xattrs.remove("user.big").unwrap_err();
+        assert_eq!(
+            Some(&IOFSErrorKind::NoAttribute),
+            e.downcast_ref::<IOFSErrorKind>()
+        );
+    }
+}
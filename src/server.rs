@@ -3,23 +3,285 @@
 //! A mounted UFS may also act as a block server for remote connections. That is implemented herein.
 //!
 use {
-    crate::{uuid::UfsUuid, BlockNumber, BlockStorage, UberFileSystem},
+    crate::{
+        event::EventSink,
+        fsimpl::{read_file_shared, read_ufs, write_ufs},
+        metadata::FileHandle,
+        uuid::UfsUuid,
+        wasm::IofsMessage,
+        BlockNumber, BlockSize, BlockStorage, OpenFileMode, UberFileSystem,
+    },
+    bytes::Buf,
+    chrono::{DateTime, Utc},
     crossbeam::crossbeam_channel,
-    futures::{future::Future, sync::oneshot},
+    failure::format_err,
+    futures::{
+        future::{self, Future},
+        stream::{self, Stream},
+        sync::{mpsc, oneshot},
+        Async, Poll,
+    },
     handlebars::{Context, Handlebars, Helper, JsonRender, Output, RenderContext, RenderError},
-    log::debug,
+    log::{debug, error, warn},
     serde::{Deserialize, Serialize},
     serde_json::json,
     std::{
+        collections::{HashMap, VecDeque},
         error::Error,
+        fmt::{Display, Write},
+        fs, io,
         path::PathBuf,
-        sync::{Arc, Mutex},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex, RwLock,
+        },
         thread::{spawn, JoinHandle},
+        time::SystemTime,
     },
-    warp::{path, Filter},
+    warp::{
+        http::{
+            header::{HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+            StatusCode,
+        },
+        log::Info,
+        multipart::FormData,
+        path,
+        path::Tail,
+        reply::Response,
+        ws::{Message, WebSocket, Ws2},
+        Filter, Reply,
+    },
+    wasm_exports::HttpResponse,
 };
 
 const CONTENT_LENGTH: u64 = 1024 * 16;
+/// The largest file that may be uploaded through the web UI
+///
+/// Multipart forms are read in their entirety before they are parsed, so this bounds the memory
+/// that an upload may use.
+const UPLOAD_LENGTH: u64 = 1024 * 1024 * 64;
+/// The largest block that may be written through the raw block API
+///
+/// This is the largest block size, so that a block may be written to any volume. It only bounds
+/// the request body; the block itself is checked against the volume's own block size when it's
+/// written.
+const BLOCK_LENGTH: u64 = BlockSize::OneMeg as u64;
+const DEAD_LETTER_LIMIT: usize = 256;
+const PREVIEW_LENGTH: usize = 512;
+/// The number of entries on each page of a directory listing, unless asked otherwise
+const DIR_PAGE_LENGTH: usize = 100;
+
+/// An HTTP request that a WASM program never responded to
+///
+#[derive(Clone, Debug)]
+pub(crate) struct DeadLetter {
+    route: String,
+    time: SystemTime,
+}
+
+/// The most recent `DEAD_LETTER_LIMIT` dead letters
+///
+type DeadLetters = Arc<Mutex<VecDeque<DeadLetter>>>;
+
+/// A WASM program's response to an HTTP request
+///
+/// An `Err` is passed along to the client as the response status.
+type WasmResponse = Result<HttpResponse, StatusCode>;
+
+/// The number of HTTP requests served, and the time spent serving them, by route
+///
+/// The route is the first segment of the request's path, e.g., `block` or `wasm`, so that the
+/// number of routes stays small.
+#[derive(Clone, Default)]
+pub(crate) struct HttpMetrics {
+    routes: Arc<Mutex<HashMap<String, (u64, f64)>>>,
+}
+
+impl HttpMetrics {
+    /// Count a request
+    ///
+    fn record(&self, info: Info) {
+        let route = info
+            .path()
+            .split('/')
+            .find(|segment| !segment.is_empty())
+            .unwrap_or("index")
+            .to_string();
+        let elapsed = info.elapsed();
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+        let mut routes = self.routes.lock().expect("poisoned metrics lock");
+        let entry = routes.entry(route).or_insert((0, 0.0));
+        entry.0 += 1;
+        entry.1 += seconds;
+    }
+}
+
+/// The most events that may be waiting to be sent to a WebSocket client
+///
+/// A client that falls this far behind is dropped, rather than left to use ever more memory.
+const EVENT_BACKLOG: usize = 256;
+
+/// The clients watching the file system's events, over a WebSocket
+///
+/// This is registered with the runtime manager as an `EventSink`, and the server hands each new
+/// client its own channel. Events are serialized once, and sent down every channel.
+#[derive(Clone, Default)]
+pub(crate) struct EventSubscribers {
+    inner: Arc<Mutex<Subscribers>>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: usize,
+    clients: HashMap<usize, mpsc::Sender<Arc<WatchedEvent>>>,
+}
+
+/// A file or directory event, as it's sent to WebSocket clients
+///
+/// The subject of the event is kept alongside the JSON, so that each client may be sent only what
+/// its user is allowed to see.
+struct WatchedEvent {
+    target_id: UfsUuid,
+    parent_id: UfsUuid,
+    json: String,
+}
+
+impl EventSubscribers {
+    /// Add a client, returning its id, and the events that it will receive
+    ///
+    fn subscribe(&self) -> (usize, mpsc::Receiver<Arc<WatchedEvent>>) {
+        let (sender, receiver) = mpsc::channel(EVENT_BACKLOG);
+        let mut inner = self.inner.lock().expect("poisoned subscriber lock");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.clients.insert(id, sender);
+        (id, receiver)
+    }
+
+    /// Remove a client
+    ///
+    /// Dropping its sender ends the client's stream of events.
+    fn unsubscribe(&self, id: usize) {
+        self.inner
+            .lock()
+            .expect("poisoned subscriber lock")
+            .clients
+            .remove(&id);
+    }
+
+    /// Send events to a client, until it goes away
+    ///
+    /// Anything that the client sends is ignored. Once the connection closes, the client is
+    /// unsubscribed, which in turn stops the task forwarding its events.
+    fn serve<B>(
+        self,
+        socket: WebSocket,
+        token: String,
+        iofs: Arc<RwLock<UberFileSystem<B>>>,
+    ) -> impl Future<Item = (), Error = ()>
+    where
+        B: BlockStorage + Sync,
+    {
+        let (id, events) = self.subscribe();
+        debug!("event client {} connected", id);
+        let (sink, incoming) = socket.split();
+
+        hyper::rt::spawn(
+            client_events(events, token, iofs)
+                .chain(stream::once(Ok(Message::close())))
+                .forward(sink)
+                .map(|_| ())
+                .map_err(move |e| debug!("unable to send to event client {}: {}", id, e)),
+        );
+
+        incoming.for_each(|_| Ok(())).then(move |result| {
+            debug!("event client {} disconnected", id);
+            self.unsubscribe(id);
+            result.map_err(|e| debug!("event client {} error: {}", id, e))
+        })
+    }
+}
+
+/// The messages sent to a WebSocket client
+///
+/// The token that the client connected with is checked again before each event is sent, so that
+/// the stream ends once the user logs out, or the token expires. Events on anything that the user
+/// may not see are skipped.
+fn client_events<B>(
+    events: mpsc::Receiver<Arc<WatchedEvent>>,
+    token: String,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> impl Stream<Item = Message, Error = warp::Error>
+where
+    B: BlockStorage + Sync,
+{
+    events
+        .map_err(|()| -> warp::Error { unreachable!("receivers never fail") })
+        .take_while({
+            let iofs = iofs.clone();
+            let token = token.clone();
+            move |_| Ok(read_ufs(&iofs).check_token(&token).is_ok())
+        })
+        .filter(move |event| read_ufs(&iofs).may_watch(&token, event.target_id, event.parent_id))
+        .map(|event| Message::text(event.json.clone()))
+}
+
+impl EventSink for EventSubscribers {
+    /// Send file and directory events to every client
+    ///
+    /// System events, e.g., pings, are of no interest outside of the file system. A client that
+    /// has gone away, or that isn't keeping up, is dropped.
+    fn emit(&mut self, msg: &IofsMessage) {
+        let payload = match msg {
+            IofsMessage::SystemMessage(_) => return,
+            IofsMessage::FileMessage(msg) => msg.payload(),
+            IofsMessage::DirMessage(msg) => msg.payload(),
+        };
+
+        let mut inner = self.inner.lock().expect("poisoned subscriber lock");
+        if inner.clients.is_empty() {
+            return;
+        }
+
+        match serde_json::to_string(msg) {
+            Ok(json) => {
+                let event = Arc::new(WatchedEvent {
+                    target_id: payload.target_id,
+                    parent_id: payload.parent_id,
+                    json,
+                });
+                inner
+                    .clients
+                    .retain(|id, client| match client.try_send(event.clone()) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            if e.is_full() {
+                                warn!("dropping event client {}, which isn't keeping up", id);
+                            }
+                            false
+                        }
+                    })
+            }
+            Err(e) => error!("unable to serialize event {:?}: {}", msg, e),
+        }
+    }
+}
+
+/// A 200, with an HTML body
+///
+/// This is what a handler that returns a plain string gets.
+pub(crate) fn html_response<B: Into<Vec<u8>>>(body: B) -> HttpResponse {
+    html_status_response(200, body)
+}
+
+/// A response with the given status, and an HTML body
+///
+pub(crate) fn html_status_response<B: Into<Vec<u8>>>(status: u16, body: B) -> HttpResponse {
+    HttpResponse::new(status)
+        .with_header("content-type", "text/html; charset=utf-8")
+        .with_body(body)
+}
 
 #[derive(Debug)]
 pub(crate) enum IofsNetworkMessage {
@@ -28,6 +290,8 @@ pub(crate) enum IofsNetworkMessage {
     Put(IofsNetworkJsonValue),
     Patch(IofsNetworkJsonValue),
     Delete(IofsNetworkJsonValue),
+    /// A POST with an `application/octet-stream` body
+    PostBytes(IofsNetworkBytesValue),
 }
 
 impl IofsNetworkMessage {
@@ -38,6 +302,7 @@ impl IofsNetworkMessage {
             IofsNetworkMessage::Put(m) => &m.route,
             IofsNetworkMessage::Patch(m) => &m.route,
             IofsNetworkMessage::Delete(m) => &m.route,
+            IofsNetworkMessage::PostBytes(m) => &m.route,
         }
     }
 
@@ -48,36 +313,43 @@ impl IofsNetworkMessage {
             IofsNetworkMessage::Put(m) => &m.token,
             IofsNetworkMessage::Patch(m) => &m.token,
             IofsNetworkMessage::Delete(m) => &m.token,
+            IofsNetworkMessage::PostBytes(m) => &m.token,
         }
     }
 
     pub(crate) fn unauthorized(self) {
-        match self {
-            IofsNetworkMessage::Get(mut m) => m.respond("unauthorized".to_string()),
-            IofsNetworkMessage::Post(mut m) => m.respond("unauthorized".to_string()),
-            IofsNetworkMessage::Put(mut m) => m.respond("unauthorized".to_string()),
-            IofsNetworkMessage::Patch(mut m) => m.respond("unauthorized".to_string()),
-            IofsNetworkMessage::Delete(mut m) => m.respond("unauthorized".to_string()),
-        }
+        self.respond(html_status_response(401, "unauthorized"));
     }
 
     pub(crate) fn no_such_endpoint(self) {
+        self.respond(html_status_response(404, "no such endpoint"));
+    }
+
+    pub(crate) fn not_allowed(self) {
+        self.respond(html_status_response(403, "insufficient permissions"));
+    }
+
+    pub(crate) fn respond(self, value: HttpResponse) {
         match self {
-            IofsNetworkMessage::Get(mut m) => m.respond("no such endpoint".to_string()),
-            IofsNetworkMessage::Post(mut m) => m.respond("no such endpoint".to_string()),
-            IofsNetworkMessage::Put(mut m) => m.respond("no such endpoint".to_string()),
-            IofsNetworkMessage::Patch(mut m) => m.respond("no such endpoint".to_string()),
-            IofsNetworkMessage::Delete(mut m) => m.respond("no such endpoint".to_string()),
+            IofsNetworkMessage::Get(mut m) => m.respond(value),
+            IofsNetworkMessage::Post(mut m) => m.respond(value),
+            IofsNetworkMessage::Put(mut m) => m.respond(value),
+            IofsNetworkMessage::Patch(mut m) => m.respond(value),
+            IofsNetworkMessage::Delete(mut m) => m.respond(value),
+            IofsNetworkMessage::PostBytes(mut m) => m.respond(value),
         }
     }
 
-    pub(crate) fn not_allowed(self) {
+    /// The program handling the request is gone, e.g., it exceeded its execution limit
+    ///
+    pub(crate) fn unavailable(self) {
         match self {
-            IofsNetworkMessage::Get(mut m) => m.respond("insufficient permissions".to_string()),
-            IofsNetworkMessage::Post(mut m) => m.respond("insufficient permissions".to_string()),
-            IofsNetworkMessage::Put(mut m) => m.respond("insufficient permissions".to_string()),
-            IofsNetworkMessage::Patch(mut m) => m.respond("insufficient permissions".to_string()),
-            IofsNetworkMessage::Delete(mut m) => m.respond("insufficient permissions".to_string()),
+            IofsNetworkMessage::Get(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
+            IofsNetworkMessage::Post(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
+            IofsNetworkMessage::Put(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
+            IofsNetworkMessage::Patch(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
+            IofsNetworkMessage::Delete(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
+            IofsNetworkMessage::PostBytes(mut m) => m.fail(StatusCode::SERVICE_UNAVAILABLE),
         }
     }
 }
@@ -86,26 +358,50 @@ impl IofsNetworkMessage {
 pub(crate) struct IofsNetworkGetValue {
     route: String,
     token: String,
-    response_channel: Option<oneshot::Sender<String>>,
+    /// The rest of the path, following the route
+    path: String,
+    /// The decoded query parameters, sans token
+    query: HashMap<String, String>,
+    response_channel: Option<oneshot::Sender<WasmResponse>>,
 }
 
 impl IofsNetworkGetValue {
     pub(crate) fn new(
         route: String,
         token: String,
-        response_channel: oneshot::Sender<String>,
+        path: String,
+        query: HashMap<String, String>,
+        response_channel: oneshot::Sender<WasmResponse>,
     ) -> Self {
         IofsNetworkGetValue {
             route,
             token,
+            path,
+            query,
             response_channel: Some(response_channel),
         }
     }
 
-    pub(crate) fn respond(&mut self, value: String) {
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn query(&self) -> &HashMap<String, String> {
+        &self.query
+    }
+
+    pub(crate) fn respond(&mut self, value: HttpResponse) {
+        if let Some(channel) = self.response_channel.take() {
+            channel
+                .send(Ok(value))
+                .expect("unable to send on oneshot channel");
+        }
+    }
+
+    pub(crate) fn fail(&mut self, status: StatusCode) {
         if let Some(channel) = self.response_channel.take() {
             channel
-                .send(value)
+                .send(Err(status))
                 .expect("unable to send on oneshot channel");
         }
     }
@@ -120,7 +416,7 @@ pub(crate) struct IofsNetworkJsonValue {
     route: String,
     token: String,
     body: serde_json::Value,
-    response_channel: Option<oneshot::Sender<String>>,
+    response_channel: Option<oneshot::Sender<WasmResponse>>,
 }
 
 impl IofsNetworkJsonValue {
@@ -128,7 +424,7 @@ impl IofsNetworkJsonValue {
         route: String,
         token: String,
         body: serde_json::Value,
-        response_channel: oneshot::Sender<String>,
+        response_channel: oneshot::Sender<WasmResponse>,
     ) -> Self {
         IofsNetworkJsonValue {
             route,
@@ -146,13 +442,69 @@ impl IofsNetworkJsonValue {
         &self.body
     }
 
-    pub(crate) fn respond(&mut self, value: String) {
+    pub(crate) fn respond(&mut self, value: HttpResponse) {
         if let Some(channel) = self.response_channel.take() {
             channel
-                .send(value)
+                .send(Ok(value))
                 .expect("unable to send on onshot channel");
         }
     }
+
+    pub(crate) fn fail(&mut self, status: StatusCode) {
+        if let Some(channel) = self.response_channel.take() {
+            channel
+                .send(Err(status))
+                .expect("unable to send on onshot channel");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct IofsNetworkBytesValue {
+    route: String,
+    token: String,
+    body: Vec<u8>,
+    response_channel: Option<oneshot::Sender<WasmResponse>>,
+}
+
+impl IofsNetworkBytesValue {
+    pub(crate) fn new(
+        route: String,
+        token: String,
+        body: Vec<u8>,
+        response_channel: oneshot::Sender<WasmResponse>,
+    ) -> Self {
+        IofsNetworkBytesValue {
+            route,
+            token,
+            body,
+            response_channel: Some(response_channel),
+        }
+    }
+
+    pub(crate) fn route(&self) -> &str {
+        &self.route
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub(crate) fn respond(&mut self, value: HttpResponse) {
+        if let Some(channel) = self.response_channel.take() {
+            channel
+                .send(Ok(value))
+                .expect("unable to send on oneshot channel");
+        }
+    }
+
+    pub(crate) fn fail(&mut self, status: StatusCode) {
+        if let Some(channel) = self.response_channel.take() {
+            channel
+                .send(Err(status))
+                .expect("unable to send on oneshot channel");
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -166,21 +518,92 @@ struct Query {
     token: String,
 }
 
-pub(crate) struct UfsRemoteServer<B: BlockStorage + 'static> {
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+/// Which part of a directory listing to show
+///
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+struct Page {
+    offset: usize,
+    limit: usize,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            offset: 0,
+            limit: DIR_PAGE_LENGTH,
+        }
+    }
+}
+
+/// The token needed to see the start of a file
+///
+/// The file page is shown without one, but without the file's type or contents.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Preview {
+    token: Option<String>,
+}
+
+/// TLS Configuration for the Remote Server
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerTls {
+    /// Serve HTTPS, using the PEM encoded certificate and private key in these files
+    ///
+    Pem { cert: PathBuf, key: PathBuf },
+    /// Serve plain HTTP
+    ///
+    /// This is meant for local development: tokens, and everything else, cross the wire in the
+    /// clear.
+    Disabled,
+}
+
+impl ServerTls {
+    /// Make sure that the certificate and key are there to be used
+    ///
+    /// The server reads them once it's running, and failing to do so brings the whole thing down,
+    /// so it's best to find out about missing, or bogus, files up front.
+    pub(crate) fn check(&self) -> Result<(), failure::Error> {
+        if let ServerTls::Pem { cert, key } = self {
+            for (what, path) in &[("certificate", cert), ("private key", key)] {
+                let pem = fs::read_to_string(path)
+                    .map_err(|e| format_err!("unable to read TLS {} {:?}: {}", what, path, e))?;
+                if !pem.contains("-----BEGIN ") {
+                    return Err(format_err!("TLS {} {:?} is not PEM encoded", what, path));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) struct UfsRemoteServer<B: BlockStorage + Sync + 'static> {
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
     http_sender: crossbeam_channel::Sender<IofsNetworkMessage>,
     http_receiver: crossbeam_channel::Receiver<IofsNetworkMessage>,
+    dead_letters: DeadLetters,
+    subscribers: EventSubscribers,
+    metrics: HttpMetrics,
+    programs: Arc<AtomicUsize>,
     port: u16,
+    tls: ServerTls,
 }
 
-impl<B: BlockStorage> UfsRemoteServer<B> {
-    pub(crate) fn new(iofs: Arc<Mutex<UberFileSystem<B>>>, port: u16) -> Self {
+impl<B: BlockStorage + Sync> UfsRemoteServer<B> {
+    pub(crate) fn new(iofs: Arc<RwLock<UberFileSystem<B>>>, port: u16, tls: ServerTls) -> Self {
         let (http_sender, http_receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
         UfsRemoteServer {
             iofs,
             http_sender,
             http_receiver,
+            dead_letters: Arc::new(Mutex::new(VecDeque::new())),
+            subscribers: EventSubscribers::default(),
+            metrics: HttpMetrics::default(),
+            programs: Arc::new(AtomicUsize::new(0)),
             port,
+            tls,
         }
     }
 
@@ -188,6 +611,18 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
         self.http_receiver.clone()
     }
 
+    /// Report the number of running WASM programs from `count`
+    ///
+    pub(crate) fn set_program_count(&mut self, count: Arc<AtomicUsize>) {
+        self.programs = count;
+    }
+
+    /// The sink that feeds the `/events` WebSocket
+    ///
+    pub(crate) fn event_sink(&self) -> Box<dyn EventSink> {
+        Box::new(self.subscribers.clone())
+    }
+
     pub(crate) fn start(
         server: UfsRemoteServer<B>,
         stop_signal: oneshot::Receiver<()>,
@@ -229,54 +664,143 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
             let index_values = move || get_index_values(iofs.clone());
 
             let iofs = server.iofs.clone();
-            let dir_values = move |path| get_dir_values(path, iofs.clone());
+            let dir_values = move |path, page| get_dir_values(path, page, iofs.clone());
 
             let iofs = server.iofs.clone();
-            let file_values = move |path, name| get_file_values(path, name, iofs.clone());
+            let file_values =
+                move |path, name, preview| get_file_values(path, name, preview, iofs.clone());
 
             let iofs = server.iofs.clone();
             let block_values = move |number| get_block_values(number, iofs.clone());
 
+            // Lambdas for the file API
+            let iofs = server.iofs.clone();
+            let upload =
+                move |dir_id, name, token, body| put_file(dir_id, name, token, body, iofs.clone());
+
+            let iofs = server.iofs.clone();
+            let upload_form = move |dir_id, form| post_upload(dir_id, form, iofs.clone());
+
+            let iofs = server.iofs.clone();
+            let download = move |file_id, token| get_download(file_id, token, iofs.clone());
+
+            // Lambdas for the raw block API
+            let iofs = server.iofs.clone();
+            let read_block = move |number, token| get_raw_block(number, token, iofs.clone());
+
+            let iofs = server.iofs.clone();
+            let write_block =
+                move |number, token, body| post_raw_block(number, token, body, iofs.clone());
+
             // Lambdas for calling Wasm functions
             let channel = server.http_sender.clone();
             let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
             // These are reversed for some reason.
-            let to_wasm_get = move |receiver, token| {
-                send_get_filter(token, receiver, channel.clone(), iofs.clone())
+            let to_wasm_get = move |receiver, tail: Tail, token, query| {
+                send_get_filter(
+                    token,
+                    receiver,
+                    tail.as_str().to_owned(),
+                    query,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
             };
 
             let channel = server.http_sender.clone();
             let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
             // These are reversed for some reason.
             let to_wasm_post = move |receiver, token, json| {
-                send_post_to_wasm(token, receiver, json, channel.clone(), iofs.clone())
+                send_post_to_wasm(
+                    token,
+                    receiver,
+                    json,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
+            };
+
+            let channel = server.http_sender.clone();
+            let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
+            // These are reversed for some reason.
+            let to_wasm_post_bytes = move |receiver, token, body| {
+                send_post_bytes_to_wasm(
+                    token,
+                    receiver,
+                    body,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
             };
 
             let channel = server.http_sender.clone();
             let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
             // These are reversed for some reason.
             let to_wasm_put = move |receiver, token, json| {
-                send_put_to_wasm(token, receiver, json, channel.clone(), iofs.clone())
+                send_put_to_wasm(
+                    token,
+                    receiver,
+                    json,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
             };
 
             let channel = server.http_sender.clone();
             let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
             // These are reversed for some reason.
             let to_wasm_patch = move |receiver, token, json| {
-                send_patch_to_wasm(token, receiver, json, channel.clone(), iofs.clone())
+                send_patch_to_wasm(
+                    token,
+                    receiver,
+                    json,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
             };
 
             let channel = server.http_sender.clone();
             let iofs = server.iofs.clone();
+            let dead_letters = server.dead_letters.clone();
             // These are reversed for some reason.
             let to_wasm_delete = move |receiver, token, json| {
-                send_delete_to_wasm(token, receiver, json, channel.clone(), iofs.clone())
+                send_delete_to_wasm(
+                    token,
+                    receiver,
+                    json,
+                    channel.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
             };
 
             // Other lambdas
             let iofs = server.iofs.clone();
             let login = move |credentials| iofs_login(credentials, iofs.clone());
 
+            let iofs = server.iofs.clone();
+            let sessions = move |token| get_sessions(token, iofs.clone());
+
+            let iofs = server.iofs.clone();
+            let subscribers = server.subscribers.clone();
+            let watch = move |token, ws| watch_events(token, ws, subscribers.clone(), iofs.clone());
+
+            let iofs = server.iofs.clone();
+            let programs = server.programs.clone();
+            let metrics = server.metrics.clone();
+            let report =
+                move |token| get_metrics(token, iofs.clone(), programs.clone(), metrics.clone());
+
             // Paths that are part of the IOFS UI
             let index = warp::get2()
                 .and(warp::path::end())
@@ -296,6 +820,7 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .map(handlebars_block);
 
             let dir = path!("dir" / String)
+                .and(warp::query())
                 .map(dir_values)
                 .map(|a| WithTemplate {
                     name: "dir.html",
@@ -304,6 +829,7 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .map(handlebars_dir);
 
             let file = path!("file" / String / String)
+                .and(warp::query())
                 .map(file_values)
                 .map(|a| WithTemplate {
                     name: "file.html",
@@ -317,12 +843,83 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .and(warp::body::json())
                 .map(login);
 
+            let sessions = warp::get2()
+                .and(warp::path("sessions"))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .map(sessions)
+                .map(|value| warp::reply::json(&value));
+
+            let events = warp::get2()
+                .and(warp::path("events"))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .and(warp::ws2())
+                .map(watch);
+
+            let metrics = warp::get2()
+                .and(warp::path("metrics"))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .map(report);
+
+            // Paths that are part of the file API
+            let api_put_file = warp::put2()
+                .and(path!("api" / "dir" / String / "file" / String))
+                .and(warp::query().map(|q: Query| q.token))
+                .and(warp::body::stream())
+                .and_then(upload)
+                .map(|value| warp::reply::json(&value));
+
+            let upload_file = warp::post2()
+                .and(path!("upload" / String))
+                .and(warp::path::end())
+                .and(warp::multipart::form().max_length(UPLOAD_LENGTH))
+                .and_then(upload_form)
+                .map(|value| warp::reply::json(&value));
+
+            let download_file = warp::get2()
+                .and(path!("download" / String))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .map(download);
+
+            // Paths that serve blocks, as they are stored, to remote block storage
+            let api_read_block = warp::get2()
+                .and(path!("block" / BlockNumber / "raw"))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .map(read_block);
+
+            let api_write_block = warp::post2()
+                .and(path!("block" / BlockNumber / "raw"))
+                .and(warp::path::end())
+                .and(warp::query().map(|q: Query| q.token))
+                .and(warp::body::content_length_limit(BLOCK_LENGTH))
+                .and(warp::body::concat())
+                .map(write_block);
+
             // Paths that invoke Wasm callbacks.
             let wasm_get = warp::get2()
+                .and(warp::path("wasm"))
+                .and(warp::path::param())
+                .and(warp::path::tail())
+                .and(warp::query().map(|q: Query| q.token))
+                .and(warp::query::<HashMap<String, String>>())
+                .and_then(to_wasm_get);
+
+            // Binary bodies go to the program as is, rather than as JSON.
+            let wasm_post_bytes = warp::post2()
                 .and(warp::path("wasm"))
                 .and(warp::path::param())
                 .and(warp::query().map(|q: Query| q.token))
-                .map(to_wasm_get);
+                .and(warp::header::exact_ignore_case(
+                    "content-type",
+                    "application/octet-stream",
+                ))
+                .and(warp::body::content_length_limit(CONTENT_LENGTH))
+                .and(warp::body::concat())
+                .and_then(to_wasm_post_bytes);
 
             let wasm_post = warp::post2()
                 .and(warp::path("wasm"))
@@ -330,7 +927,7 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .and(warp::query().map(|q: Query| q.token))
                 .and(warp::body::content_length_limit(CONTENT_LENGTH))
                 .and(warp::body::json())
-                .map(to_wasm_post);
+                .and_then(to_wasm_post);
 
             let wasm_put = warp::put2()
                 .and(warp::path("wasm"))
@@ -338,7 +935,7 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .and(warp::query().map(|q: Query| q.token))
                 .and(warp::body::content_length_limit(CONTENT_LENGTH))
                 .and(warp::body::json())
-                .map(to_wasm_put);
+                .and_then(to_wasm_put);
 
             let wasm_patch = warp::patch()
                 .and(warp::path("wasm"))
@@ -346,7 +943,7 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .and(warp::query().map(|q: Query| q.token))
                 .and(warp::body::content_length_limit(CONTENT_LENGTH))
                 .and(warp::body::json())
-                .map(to_wasm_patch);
+                .and_then(to_wasm_patch);
 
             let wasm_delete = warp::delete2()
                 .and(warp::path("wasm"))
@@ -354,24 +951,47 @@ impl<B: BlockStorage> UfsRemoteServer<B> {
                 .and(warp::query().map(|q: Query| q.token))
                 .and(warp::body::content_length_limit(CONTENT_LENGTH))
                 .and(warp::body::json())
-                .map(to_wasm_delete);
+                .and_then(to_wasm_delete);
 
+            // The raw block paths go ahead of the block view, which would otherwise claim them.
             let routes = index
+                .or(api_read_block)
+                .or(api_write_block)
                 .or(block)
                 .or(dir)
                 .or(file)
                 .or(login)
+                .or(sessions)
+                .or(events)
+                .or(metrics)
+                .or(api_put_file)
+                .or(upload_file)
+                .or(download_file)
                 .or(wasm_get)
+                .or(wasm_post_bytes)
                 .or(wasm_post)
                 .or(wasm_put)
                 .or(wasm_patch)
                 .or(wasm_delete);
 
-            let (addr, warp) = warp::serve(routes)
-                .tls("src/certs/cert.pem", "src/certs/key.rsa")
-                .bind_with_graceful_shutdown(([0, 0, 0, 0], server.port), stop_signal);
+            let metrics = server.metrics.clone();
+            let routes = routes.with(warp::log::custom(move |info| metrics.record(info)));
 
-            hyper::rt::run(warp);
+            let addr = ([0, 0, 0, 0], server.port);
+            match server.tls {
+                ServerTls::Pem { cert, key } => {
+                    let (_, warp) = warp::serve(routes)
+                        .tls(cert, key)
+                        .bind_with_graceful_shutdown(addr, stop_signal);
+                    hyper::rt::run(warp);
+                }
+                ServerTls::Disabled => {
+                    warn!("serving plain HTTP on port {}", server.port);
+                    let (_, warp) =
+                        warp::serve(routes).bind_with_graceful_shutdown(addr, stop_signal);
+                    hyper::rt::run(warp);
+                }
+            }
 
             Ok(())
         })
@@ -439,14 +1059,29 @@ fn block_format(
     Ok(())
 }
 
-fn get_index_values<B>(iofs: Arc<Mutex<UberFileSystem<B>>>) -> serde_json::value::Value
+fn get_index_values<B>(iofs: Arc<RwLock<UberFileSystem<B>>>) -> serde_json::value::Value
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let guard = iofs.lock().expect("poisoned iofs lock");
+    let guard = read_ufs(&iofs);
     let manager = guard.block_manager();
+
+    let mut wasm_restarts = guard
+        .wasm_restarts()
+        .iter()
+        .map(|(path, status)| {
+            json!({
+                "path": path.to_string_lossy(),
+                "restarts": status.restarts,
+                "failed": status.failed,
+            })
+        })
+        .collect::<Vec<_>>();
+    wasm_restarts.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+
     json!({
         "iofs_id": format!("{}", manager.id()),
+        "label": guard.label(),
         "block_size": format!("{}", manager.block_size()),
         "block_count": manager.block_count(),
         "free_blocks": manager.free_block_count(),
@@ -454,62 +1089,67 @@ where
         "root_dir_id": manager.metadata().root_directory().id().to_string(),
         // "block_map": format!("{:?}", manager.map()),
         "metadata": format!("{:#?}", manager.metadata()),
+        "wasm_restarts": wasm_restarts,
     })
 }
 
+/// Return a page of a directory listing, with links to the pages either side
+///
 fn get_dir_values<B>(
     dir_id: String,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+    page: Page,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
 ) -> serde_json::value::Value
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    use std::cmp::Ordering;
-
-    let guard = iofs.lock().expect("poisoned iofs lock");
-    let metadata = guard.block_manager().metadata();
+    let guard = read_ufs(&iofs);
+
+    let mut dir_ufsid: UfsUuid = match dir_id.parse() {
+        Ok(id) => id,
+        Err(_) => {
+            return json!({
+                "name": "invalid directory id",
+                "id": dir_id,
+            })
+        }
+    };
 
-    let mut dir_ufsid: UfsUuid = dir_id.clone().into();
-    if let Ok(dir) = metadata.get_directory(dir_ufsid) {
-        let mut tree = vec![];
-        // Add files and directories under this one for display.
-        for (name, entry) in dir.entries() {
-            tree.push(json!({
-                "type": if entry.is_dir(){ "dir" } else { "file"},
-                "name": name,
-                "id": entry.id().to_string(),
-                "owner": entry.owner().to_string(),
-            }));
-        }
-
-        // Sort lexicographically, with directories first.
-        tree.sort_unstable_by(|a, b| {
-            if a["type"].as_str() == Some("dir") {
-                if b["type"].as_str() == Some("dir") {
-                    if a["name"].as_str() < b["name"].as_str() {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    }
-                } else {
-                    Ordering::Less
-                }
-            } else {
-                if b["type"].as_str() == Some("dir") {
-                    Ordering::Greater
-                } else {
-                    if a["name"].as_str() < b["name"].as_str() {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    }
-                }
-            }
-        });
+    // Add files and directories under this one for display.
+    let limit = page.limit.max(1);
+    if let Ok((entries, total)) = guard.list_directory_paged(dir_ufsid, page.offset, limit) {
+        let tree = entries
+            .into_iter()
+            .map(|(name, entry)| {
+                json!({
+                    "type": if entry.is_dir(){ "dir" } else { "file"},
+                    "name": name,
+                    "id": entry.id().to_string(),
+                    "owner": entry.owner().map(|owner| owner.to_string()),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let page_link = |offset| format!("/dir/{}?offset={}&limit={}", dir_id, offset, limit);
+        let prev = if page.offset > 0 {
+            Some(page_link(page.offset.saturating_sub(limit)))
+        } else {
+            None
+        };
+        // The offset and limit come straight from the query, so don't trust them not to overflow.
+        let next_offset = page.offset.saturating_add(limit);
+        let next = if next_offset < total {
+            Some(page_link(next_offset))
+        } else {
+            None
+        };
+
+        let last = page.offset.saturating_add(tree.len());
 
         // Build a path to this directory for display
+        let metadata = guard.block_manager().metadata();
         let mut dir_path_components = vec![];
-        let mut parent_id_option = dir.parent_id();
+        let mut parent_id_option = metadata.lookup_dir(dir_ufsid).and_then(|d| d.parent_id());
         while let Some(parent_id) = parent_id_option {
             if let Ok(parent_dir) = metadata.get_directory(parent_id) {
                 for (name, entry) in parent_dir.entries() {
@@ -530,10 +1170,16 @@ where
             "name": dir_path.to_str(),
             "id": dir_id,
             "files": tree,
+            "first": page.offset.saturating_add(1),
+            "last": last,
+            "total": total,
+            "prev": prev,
+            "next": next,
         })
     } else {
         json!({
-            "name": "invalid directory id"
+            "name": "directory not found",
+            "id": dir_id,
         })
     }
 }
@@ -541,39 +1187,83 @@ where
 fn get_file_values<B>(
     file_id: String,
     file_name: String,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+    preview: Preview,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
 ) -> serde_json::value::Value
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let guard = iofs.lock().expect("poisoned iofs lock");
+    let guard = read_ufs(&iofs);
     let metadata = guard.block_manager().metadata();
 
-    let file_ufsid: UfsUuid = file_id.clone().into();
+    let file_ufsid: UfsUuid = match file_id.parse() {
+        Ok(id) => id,
+        Err(_) => {
+            return json!({
+                "name": "invalid file id",
+                "id": file_id,
+            })
+        }
+    };
+
     if let Ok(file) = metadata.get_file_metadata(file_ufsid) {
         let latest = file.get_latest();
-
-        json!({
+        let mut values = json!({
             "name": file_name,
             "id": file_id,
             "size": latest.size(),
-            "blocks": latest.blocks()
-        })
+            "versions": file.get_versions().len(),
+            "blocks": latest.blocks(),
+        });
+
+        // The contents are only read for someone who's logged in.
+        match preview.token.map(|token| guard.check_token(&token)) {
+            Some(Ok(())) => {
+                let head = guard
+                    .read_head(file_ufsid, PREVIEW_LENGTH)
+                    .unwrap_or_default();
+                let content_type = content_type(&head);
+                values["type"] = json!(content_type);
+                if content_type == "text/plain" {
+                    values["preview"] = json!(String::from_utf8_lossy(&head));
+                }
+            }
+            Some(Err(e)) => values["error"] = json!(e.to_string()),
+            None => (),
+        }
+
+        values
     } else {
         json!({
-            "name": "invalid file id"
+            "name": "file not found",
+            "id": file_id,
         })
     }
 }
 
+/// Guess the type of a file from its first few bytes
+///
+/// Text is allowed to be cut off mid-character.
+fn content_type(head: &[u8]) -> &'static str {
+    if head.starts_with(b"\0asm") {
+        "application/wasm"
+    } else {
+        match std::str::from_utf8(head) {
+            Ok(_) => "text/plain",
+            Err(e) if e.error_len().is_none() => "text/plain",
+            Err(_) => "application/octet-stream",
+        }
+    }
+}
+
 fn get_block_values<B>(
     block: BlockNumber,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
 ) -> serde_json::value::Value
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let guard = iofs.lock().expect("poisoned iofs lock");
+    let guard = read_ufs(&iofs);
     match guard.block_manager().get_block(block) {
         Some(block) => json!({
             "block_number": block.number(),
@@ -585,45 +1275,567 @@ where
     }
 }
 
-fn iofs_login<B>(credentials: LoginCredentials, iofs: Arc<Mutex<UberFileSystem<B>>>) -> String
+/// Read a block, as it's stored, for a remote client
+///
+/// The bytes are encrypted, and are returned as `application/octet-stream`.
+fn get_raw_block<B>(
+    number: BlockNumber,
+    token: String,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> Response
+where
+    B: BlockStorage + Sync,
+{
+    let guard = read_ufs(&iofs);
+    if let Err(e) = guard.check_token(&token) {
+        return warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    match guard.block_manager().read_raw_block(number) {
+        Ok(bytes) => {
+            debug!("serving block 0x{:x?}", number);
+            bytes.into_response()
+        }
+        Err(e) => {
+            error!("unable to serve block 0x{:x?}: {}", number, e);
+            warp::reply::with_status(e.to_string(), StatusCode::NOT_FOUND).into_response()
+        }
+    }
+}
+
+/// Write a block, as it's to be stored, for a remote client
+///
+/// The body is expected to be encrypted already. As with the block server, the response is the
+/// number of bytes written. Only the owner of the file system may write blocks, and only blocks
+/// that aren't in use.
+fn post_raw_block<B, D>(
+    number: BlockNumber,
+    token: String,
+    body: D,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> Response
+where
+    B: BlockStorage + Sync,
+    D: Buf,
+{
+    let mut guard = write_ufs(&iofs);
+    if let Err(e) = guard.validate_token(token.clone()) {
+        return warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED).into_response();
+    }
+    if let Err(e) = guard.check_admin(&token) {
+        return warp::reply::with_status(e.to_string(), StatusCode::FORBIDDEN).into_response();
+    }
+
+    match guard
+        .block_manager_mut()
+        .write_raw_block(number, body.bytes())
+    {
+        Ok(count) => {
+            debug!("wrote {} bytes to block 0x{:x?}", count, number);
+            count.to_string().into_response()
+        }
+        Err(e) => {
+            error!("unable to write block 0x{:x?}: {}", number, e);
+            warp::reply::with_status(e.to_string(), StatusCode::BAD_REQUEST).into_response()
+        }
+    }
+}
+
+fn iofs_login<B>(credentials: LoginCredentials, iofs: Arc<RwLock<UberFileSystem<B>>>) -> String
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let mut guard = iofs.lock().expect("poisoned iofs lock");
+    let mut guard = write_ufs(&iofs);
     match guard.login(credentials.id, credentials.password) {
         Some(jwt) => jwt,
         None => "user not found, or password incorrect".to_string(),
     }
 }
 
+/// List the active sessions
+///
+/// Only the owner of the file system may see them.
+fn get_sessions<B>(token: String, iofs: Arc<RwLock<UberFileSystem<B>>>) -> serde_json::Value
+where
+    B: BlockStorage + Sync,
+{
+    let guard = read_ufs(&iofs);
+    match guard.check_admin(&token) {
+        Ok(_) => {
+            let sessions: Vec<_> = guard
+                .active_sessions()
+                .iter()
+                .map(|(user, jti, issued)| {
+                    json!({
+                        "user": user.to_string(),
+                        "jti": jti.to_string(),
+                        "issued": DateTime::<Utc>::from(*issued).to_rfc3339(),
+                    })
+                })
+                .collect();
+            json!(sessions)
+        }
+        Err(e) => json!({ "error": e.to_string() }),
+    }
+}
+
+/// Stream an HTTP request body into a new file
+///
+/// The file, `name`, is created in the directory `dir_id`. The body is written a block at a time
+/// as it arrives, rather than being buffered in its entirety.
+fn put_file<B, S>(
+    dir_id: String,
+    name: String,
+    token: String,
+    body: S,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> impl Future<Item = serde_json::Value, Error = warp::Rejection>
+where
+    B: BlockStorage + Sync,
+    S: Stream,
+    S::Item: Buf,
+    S::Error: Display,
+{
+    let created = {
+        let mut guard = write_ufs(&iofs);
+        guard
+            .validate_token(token)
+            .and_then(|_| {
+                dir_id
+                    .parse::<UfsUuid>()
+                    .map_err(|_| format_err!("invalid directory id"))
+            })
+            .and_then(|dir_id| guard.create_file(dir_id, &name))
+            .map(|(handle, file)| {
+                let block_size: usize = guard.block_manager().block_size().into();
+                (handle, file.file_id, block_size)
+            })
+    };
+
+    future::result(created)
+        .and_then(move |(handle, file_id, block_size)| {
+            let write_iofs = iofs.clone();
+            body.map_err(|e| format_err!("error reading request body: {}", e))
+                .fold(
+                    (Vec::new(), 0),
+                    move |(mut pending, offset): (Vec<u8>, u64),
+                          mut chunk|
+                          -> Result<_, failure::Error> {
+                        while chunk.has_remaining() {
+                            let len = {
+                                let bytes = chunk.bytes();
+                                pending.extend_from_slice(bytes);
+                                bytes.len()
+                            };
+                            chunk.advance(len);
+                        }
+
+                        // Only write whole blocks; the rest waits for more data.
+                        let whole = pending.len() - pending.len() % block_size;
+                        if whole == 0 {
+                            return Ok((pending, offset));
+                        }
+
+                        let mut guard = write_ufs(&write_iofs);
+                        let written = guard.write_file(handle, &pending[..whole], offset)?;
+                        pending.drain(..whole);
+                        Ok((pending, offset + written as u64))
+                    },
+                )
+                .then(move |result| {
+                    let mut guard = write_ufs(&iofs);
+                    let result = result.and_then(|(pending, offset)| {
+                        if pending.is_empty() {
+                            Ok(offset)
+                        } else {
+                            let written = guard.write_file(handle, &pending, offset)?;
+                            Ok(offset + written as u64)
+                        }
+                    });
+
+                    if guard.close_file(handle).is_err() {
+                        error!("unable to close uploaded file {:?}", file_id);
+                    }
+
+                    result.map(|size| {
+                        json!({
+                            "id": file_id.to_string(),
+                            "size": size,
+                        })
+                    })
+                })
+        })
+        .then(|result| -> Result<_, warp::Rejection> {
+            Ok(match result {
+                Ok(value) => value,
+                Err(e) => json!({ "error": e.to_string() }),
+            })
+        })
+}
+
+/// Create a new file from a multipart form
+///
+/// The form carries the session `token` and the `file` itself, which is created in the directory
+/// `dir_id` under its file name, less any leading path, be it Unix or Windows. The contents are
+/// written as they would be by `put_file`.
+fn post_upload<B>(
+    dir_id: String,
+    form: FormData,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> impl Future<Item = serde_json::Value, Error = warp::Rejection>
+where
+    B: BlockStorage + Sync,
+{
+    form.map_err(|e| format_err!("error reading form: {}", e))
+        .fold((None, None), |(token, file), part| match part.name() {
+            "token" => future::Either::A(
+                part.map_err(|e| format_err!("error reading form: {}", e))
+                    .fold(
+                        Vec::new(),
+                        |mut bytes, chunk| -> Result<_, failure::Error> {
+                            bytes.extend(chunk.collect::<Vec<u8>>());
+                            Ok(bytes)
+                        },
+                    )
+                    .map(move |bytes| (Some(String::from_utf8_lossy(&bytes).into_owned()), file)),
+            ),
+            "file" => future::Either::B(future::ok((token, Some(part)))),
+            _ => future::Either::B(future::ok((token, file))),
+        })
+        .and_then(|(token, file)| -> Result<_, failure::Error> {
+            let token = token.ok_or_else(|| format_err!("missing token"))?;
+            let file = file.ok_or_else(|| format_err!("missing file"))?;
+            let name = file
+                .filename()
+                .and_then(|name| name.rsplit(|c: char| c == '/' || c == '\\').next())
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string())
+                .ok_or_else(|| format_err!("missing file name"))?;
+            Ok((token, file, name))
+        })
+        .then(move |result| match result {
+            Ok((token, file, name)) => future::Either::A(put_file(dir_id, name, token, file, iofs)),
+            Err(e) => future::Either::B(future::ok(json!({ "error": e.to_string() }))),
+        })
+}
+
+/// A file's contents, read as they are sent
+///
+/// Each poll reads the next block's worth of the file, so that a download never holds more than
+/// that in memory. The file handle is closed when the download is dropped, whether or not it was
+/// sent in full.
+struct FileDownload<B: BlockStorage + Sync + 'static> {
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    handle: FileHandle,
+    offset: u64,
+    size: u64,
+    chunk: u64,
+}
+
+impl<B: BlockStorage + Sync> Stream for FileDownload<B> {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.offset >= self.size {
+            return Ok(Async::Ready(None));
+        }
+
+        let len = (self.size - self.offset).min(self.chunk);
+        match read_file_shared(&self.iofs, self.handle, self.offset, len as u32) {
+            Ok(ref bytes) if bytes.is_empty() => Ok(Async::Ready(None)),
+            Ok(bytes) => {
+                self.offset += bytes.len() as u64;
+                Ok(Async::Ready(Some(bytes)))
+            }
+            Err(e) => {
+                error!("unable to read download at {}: {}", self.offset, e);
+                Err(io::Error::new(io::ErrorKind::Other, e.to_string()))
+            }
+        }
+    }
+}
+
+impl<B: BlockStorage + Sync> Drop for FileDownload<B> {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = self.iofs.write() {
+            if guard.close_file(self.handle).is_err() {
+                error!("unable to close downloaded file {}", self.handle);
+            }
+        }
+    }
+}
+
+/// Download a file
+///
+/// The file is sent as an attachment, named for the file, and streamed a block at a time.
+fn get_download<B>(file_id: String, token: String, iofs: Arc<RwLock<UberFileSystem<B>>>) -> Response
+where
+    B: BlockStorage + Sync,
+{
+    let mut guard = write_ufs(&iofs);
+    if let Err(e) = guard.validate_token(token) {
+        return warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    let id = match file_id.parse::<UfsUuid>() {
+        Ok(id) => id,
+        Err(_) => {
+            return warp::reply::with_status("invalid file id", StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    let handle = match guard.open_file(id, OpenFileMode::Read) {
+        Ok(handle) => handle,
+        Err(e) => {
+            return warp::reply::with_status(e.to_string(), StatusCode::NOT_FOUND).into_response()
+        }
+    };
+
+    let size = guard.get_file_size(handle).unwrap_or_default();
+    let head = guard.read_head(id, PREVIEW_LENGTH).unwrap_or_default();
+    let name = guard
+        .block_manager()
+        .metadata()
+        .path_from_file_id(id)
+        .file_name()
+        .map(|name| name.to_string_lossy().replace('"', "\\\""))
+        .unwrap_or_default();
+    let block_size: usize = guard.block_manager().block_size().into();
+    drop(guard);
+
+    debug!("downloading {:?}, {} bytes", name, size);
+    let download = FileDownload {
+        iofs,
+        handle,
+        offset: 0,
+        size,
+        chunk: block_size as u64,
+    };
+
+    let mut response = Response::new(hyper::Body::wrap_stream(download));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(&head)));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", name))
+            .unwrap_or_else(|_| HeaderValue::from_static("attachment")),
+    );
+    response
+}
+
+/// Watch file system events
+///
+/// The connection is upgraded to a WebSocket, given a valid token, and each event is sent to the
+/// client as a JSON text message.
+fn watch_events<B>(
+    token: String,
+    ws: Ws2,
+    subscribers: EventSubscribers,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+) -> Response
+where
+    B: BlockStorage + Sync,
+{
+    if let Err(e) = read_ufs(&iofs).check_token(&token) {
+        return warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    ws.on_upgrade(move |socket| subscribers.serve(socket, token, iofs))
+        .into_response()
+}
+
+/// Report the file system's metrics, in the Prometheus text format
+///
+fn get_metrics<B>(
+    token: String,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    programs: Arc<AtomicUsize>,
+    metrics: HttpMetrics,
+) -> Response
+where
+    B: BlockStorage + Sync,
+{
+    let guard = read_ufs(&iofs);
+    if let Err(e) = guard.check_token(&token) {
+        return warp::reply::with_status(e.to_string(), StatusCode::UNAUTHORIZED).into_response();
+    }
+
+    let gauges = [
+        (
+            "ufs_block_count",
+            "The number of blocks in the file system",
+            guard.block_manager().block_count() as u64,
+        ),
+        (
+            "ufs_free_block_count",
+            "The number of blocks that are not in use",
+            guard.block_manager().free_block_count() as u64,
+        ),
+        (
+            "ufs_open_files",
+            "The number of open files",
+            guard.open_file_count() as u64,
+        ),
+        (
+            "ufs_wasm_programs",
+            "The number of running WASM programs",
+            programs.load(Ordering::Relaxed) as u64,
+        ),
+    ];
+    let dropped_events = guard.dropped_event_count();
+    drop(guard);
+
+    let mut body = String::new();
+    for (name, help, value) in &gauges {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} gauge", name);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+
+    body.push_str("# HELP ufs_dropped_events_total Events that never reached WASM programs\n");
+    body.push_str("# TYPE ufs_dropped_events_total counter\n");
+    let _ = writeln!(body, "ufs_dropped_events_total {}", dropped_events);
+
+    let mut routes = metrics
+        .routes
+        .lock()
+        .expect("poisoned metrics lock")
+        .clone()
+        .into_iter()
+        .collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    body.push_str("# HELP ufs_http_requests_total The number of HTTP requests served\n");
+    body.push_str("# TYPE ufs_http_requests_total counter\n");
+    for (route, (count, _)) in &routes {
+        let _ = writeln!(
+            body,
+            "ufs_http_requests_total{{route=\"{}\"}} {}",
+            route, count
+        );
+    }
+    body.push_str("# HELP ufs_http_request_seconds_total Time spent serving HTTP requests\n");
+    body.push_str("# TYPE ufs_http_request_seconds_total counter\n");
+    for (route, (_, seconds)) in &routes {
+        let _ = writeln!(
+            body,
+            "ufs_http_request_seconds_total{{route=\"{}\"}} {}",
+            route, seconds
+        );
+    }
+
+    let mut response = Response::new(body.into());
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+/// Turn a WASM program's response into a reply
+///
+/// A status that isn't, or a header that can't be, is logged. The former becomes a 500, and the
+/// latter is dropped.
+fn http_reply(response: HttpResponse) -> Response {
+    let mut reply = Response::new(response.body.into());
+    *reply.status_mut() = StatusCode::from_u16(response.status).unwrap_or_else(|_| {
+        error!("invalid status from WASM: {}", response.status);
+        StatusCode::INTERNAL_SERVER_ERROR
+    });
+
+    for (name, value) in response.headers {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                reply.headers_mut().append(name, value);
+            }
+            _ => error!("invalid header from WASM: {}: {}", name, value),
+        }
+    }
+
+    reply
+}
+
+/// The response to an HTTP request, once a WASM program gets around to it
+///
+/// The request is handled by the program's thread, and a warp worker isn't tied up waiting on it.
+/// Requests to different programs are handled concurrently; a program handles its own requests one
+/// at a time, in the order that they arrive.
+///
+/// If the program goes away without responding, e.g., it panicked, the request is recorded as a
+/// dead letter and the client gets a 500. If the program fails the request, e.g., it exceeded its
+/// execution limit, the client gets the status that it failed with.
+fn wasm_response(
+    route: String,
+    rx: oneshot::Receiver<WasmResponse>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection> {
+    rx.then(move |result| {
+        Ok::<_, warp::Rejection>(match result {
+            Ok(Ok(result)) => http_reply(result),
+            Ok(Err(status)) => {
+                error!("WASM program for route {} failed with {}", route, status);
+                warp::reply::with_status(warp::reply::html(status.to_string()), status)
+                    .into_response()
+            }
+            Err(_) => {
+                error!("dead letter: no response from WASM for route {}", route);
+                let mut dead_letters = dead_letters.lock().expect("poisoned dead letter lock");
+                if dead_letters.len() >= DEAD_LETTER_LIMIT {
+                    dead_letters.pop_front();
+                }
+                dead_letters.push_back(DeadLetter {
+                    route,
+                    time: SystemTime::now(),
+                });
+
+                warp::reply::with_status(
+                    warp::reply::html("no response".to_string()),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into_response()
+            }
+        })
+    })
+}
+
+/// Send a GET to a WASM program
+///
+/// The program gets the path following the route, and the query parameters. The token is removed
+/// from the latter, as it's none of the program's business.
 fn send_get_filter<B>(
     token: String,
     receiver: String,
+    path: String,
+    mut query: HashMap<String, String>,
     channel: crossbeam_channel::Sender<IofsNetworkMessage>,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
-) -> impl warp::Reply
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
     debug!("token: {}", token);
 
     debug!("calling get handler");
-    let (tx, rx) = oneshot::channel::<String>();
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
+    query.remove("token");
     channel
         .send(IofsNetworkMessage::Get(IofsNetworkGetValue::new(
-            receiver, token, tx,
+            receiver.clone(),
+            token,
+            path,
+            query,
+            tx,
         )))
         .expect("unable to send IofsNetworkMessage");
 
-    // let bar = rx.map(|result| warp::reply::html(result));
-    // let result = rx.wait().unwrap();
-    // let baz = warp::reply::html(result);
-    // warp::reply::reply()
-
-    rx.map(|result| warp::reply::html(result))
-        .wait()
-        .expect("error reading channel")
-    // rx.map(|result| warp::reply::html(result))
+    wasm_response(receiver, rx, dead_letters)
 }
 
 fn send_post_to_wasm<B>(
@@ -631,23 +1843,57 @@ fn send_post_to_wasm<B>(
     receiver: String,
     json: serde_json::Value,
     channel: crossbeam_channel::Sender<IofsNetworkMessage>,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
-) -> impl warp::Reply
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
     debug!("token: {}", token);
 
     debug!("calling post handler");
-    let (tx, rx) = oneshot::channel::<String>();
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
     channel
         .send(IofsNetworkMessage::Post(IofsNetworkJsonValue::new(
-            receiver, token, json, tx,
+            receiver.clone(),
+            token,
+            json,
+            tx,
         )))
         .expect("unable to send IofsNetworkMessage");
-    rx.map(|result| warp::reply::html(result))
-        .wait()
-        .expect("error reading channel")
+
+    wasm_response(receiver, rx, dead_letters)
+}
+
+/// Send a binary POST body to a WASM program
+///
+/// Unless the program says otherwise, its response is returned as `application/octet-stream`.
+fn send_post_bytes_to_wasm<B, D>(
+    token: String,
+    receiver: String,
+    body: D,
+    channel: crossbeam_channel::Sender<IofsNetworkMessage>,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
+where
+    B: BlockStorage + Sync,
+    D: Buf,
+{
+    debug!("token: {}", token);
+
+    debug!("calling post bytes handler");
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
+    channel
+        .send(IofsNetworkMessage::PostBytes(IofsNetworkBytesValue::new(
+            receiver.clone(),
+            token,
+            body.bytes().to_vec(),
+            tx,
+        )))
+        .expect("unable to send IofsNetworkMessage");
+
+    wasm_response(receiver, rx, dead_letters)
 }
 
 fn send_put_to_wasm<B>(
@@ -655,20 +1901,23 @@ fn send_put_to_wasm<B>(
     receiver: String,
     json: serde_json::Value,
     channel: crossbeam_channel::Sender<IofsNetworkMessage>,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
-) -> impl warp::Reply
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let (tx, rx) = oneshot::channel::<String>();
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
     channel
         .send(IofsNetworkMessage::Put(IofsNetworkJsonValue::new(
-            receiver, token, json, tx,
+            receiver.clone(),
+            token,
+            json,
+            tx,
         )))
         .expect("unable to send IofsNetworkMessage");
-    rx.map(|result| warp::reply::html(result))
-        .wait()
-        .expect("error reading channel")
+
+    wasm_response(receiver, rx, dead_letters)
 }
 
 fn send_patch_to_wasm<B>(
@@ -676,20 +1925,23 @@ fn send_patch_to_wasm<B>(
     receiver: String,
     json: serde_json::Value,
     channel: crossbeam_channel::Sender<IofsNetworkMessage>,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
-) -> impl warp::Reply
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let (tx, rx) = oneshot::channel::<String>();
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
     channel
         .send(IofsNetworkMessage::Patch(IofsNetworkJsonValue::new(
-            receiver, token, json, tx,
+            receiver.clone(),
+            token,
+            json,
+            tx,
         )))
         .expect("unable to send IofsNetworkMessage");
-    rx.map(|result| warp::reply::html(result))
-        .wait()
-        .expect("error reading channel")
+
+    wasm_response(receiver, rx, dead_letters)
 }
 
 fn send_delete_to_wasm<B>(
@@ -697,18 +1949,904 @@ fn send_delete_to_wasm<B>(
     receiver: String,
     json: serde_json::Value,
     channel: crossbeam_channel::Sender<IofsNetworkMessage>,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
-) -> impl warp::Reply
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
+    dead_letters: DeadLetters,
+) -> impl Future<Item = Response, Error = warp::Rejection>
 where
-    B: BlockStorage,
+    B: BlockStorage + Sync,
 {
-    let (tx, rx) = oneshot::channel::<String>();
+    let (tx, rx) = oneshot::channel::<WasmResponse>();
     channel
         .send(IofsNetworkMessage::Delete(IofsNetworkJsonValue::new(
-            receiver, token, json, tx,
+            receiver.clone(),
+            token,
+            json,
+            tx,
         )))
         .expect("unable to send IofsNetworkMessage");
-    rx.map(|result| warp::reply::html(result))
+
+    wasm_response(receiver, rx, dead_letters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use {
+        crate::{
+            block::BlockHash,
+            wasm::{IofsFileMessage, IofsMessagePayload, IofsSystemMessage},
+            BlockSize, OpenFileMode, SortOrder,
+        },
+        std::io::Cursor,
+    };
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn check_tls() {
+        init();
+
+        let tls = ServerTls::Pem {
+            cert: PathBuf::from("src/certs/cert.pem"),
+            key: PathBuf::from("src/certs/key.rsa"),
+        };
+        assert!(tls.check().is_ok());
+        assert!(ServerTls::Disabled.check().is_ok());
+
+        let tls = ServerTls::Pem {
+            cert: PathBuf::from("src/certs/missing.pem"),
+            key: PathBuf::from("src/certs/key.rsa"),
+        };
+        let e = tls.check().unwrap_err();
+        assert!(e.to_string().contains("certificate"), "{}", e);
+
+        let tls = ServerTls::Pem {
+            cert: PathBuf::from("src/certs/cert.pem"),
+            key: PathBuf::from("Cargo.toml"),
+        };
+        let e = tls.check().unwrap_err();
+        assert!(e.to_string().contains("not PEM encoded"), "{}", e);
+    }
+
+    #[test]
+    fn invalid_dir_id() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let values = get_dir_values("not-a-uuid".to_string(), Page::default(), iofs.clone());
+        assert_eq!("invalid directory id", values["name"]);
+
+        let values = get_dir_values(root_id.random().to_string(), Page::default(), iofs.clone());
+        assert_eq!("directory not found", values["name"]);
+
+        let values = get_dir_values(root_id.to_string(), Page::default(), iofs.clone());
+        assert_eq!("/", values["name"]);
+    }
+
+    #[test]
+    fn dir_sort_order() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+
+        for name in &["b", "c", "a"] {
+            let (h, _) = ufs.create_file(root_id, name).unwrap();
+            ufs.close_file(h).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        ufs.create_directory(root_id, "sub").unwrap();
+
+        ufs.set_sort_order("/", SortOrder::MtimeDescending).unwrap();
+
+        // The order that readdir sees
+        let h = ufs.open_directory(root_id).unwrap();
+        let (entries, _) = ufs.list_files_paged(h, 0, usize::max_value()).unwrap();
+        let names = entries
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|n| n.len() == 1)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "c", "b"], names);
+        ufs.close_directory(h);
+
+        // The HTTP view lists them in the same order, directories first
+        let iofs = Arc::new(RwLock::new(ufs));
+        let values = get_dir_values(root_id.to_string(), Page::default(), iofs.clone());
+        let files = values["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|f| f["type"] == "file")
+            .map(|f| f["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "c", "b"], files);
+        assert_eq!("dir", values["files"][0]["type"]);
+    }
+
+    #[test]
+    fn dir_paging() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let dir = ufs.create_directory(root_id, "many").unwrap();
+        for i in 0..25 {
+            let (h, _) = ufs.create_file(dir.id(), &format!("{:02}", i)).unwrap();
+            ufs.close_file(h).unwrap();
+        }
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let page = |offset, limit| {
+            get_dir_values(dir.id().to_string(), Page { offset, limit }, iofs.clone())
+        };
+
+        let values = page(0, 10);
+        assert_eq!(25, values["total"]);
+        assert_eq!(10, values["last"]);
+        assert_eq!(10, values["files"].as_array().unwrap().len());
+        assert_eq!("00", values["files"][0]["name"]);
+        assert!(values["prev"].is_null());
+        assert_eq!(
+            format!("/dir/{}?offset=10&limit=10", dir.id()),
+            values["next"]
+        );
+
+        let values = page(20, 10);
+        assert_eq!(5, values["files"].as_array().unwrap().len());
+        assert_eq!("20", values["files"][0]["name"]);
+        assert_eq!(
+            format!("/dir/{}?offset=10&limit=10", dir.id()),
+            values["prev"]
+        );
+        assert!(values["next"].is_null());
+
+        // Past the end is an empty page, not an error.
+        let values = page(30, 10);
+        assert!(values["files"].as_array().unwrap().is_empty());
+        assert_eq!(25, values["total"]);
+
+        // So is an offset, or a limit, as large as they come.
+        let values = page(usize::max_value(), usize::max_value());
+        assert!(values["files"].as_array().unwrap().is_empty());
+        assert!(values["next"].is_null());
+    }
+
+    #[test]
+    fn file_preview() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+
+        let (h, text) = ufs.create_file(root_id, "text").unwrap();
+        ufs.write_file(h, include_str!("lib.rs").as_bytes(), 0)
+            .unwrap();
+        ufs.close_file(h).unwrap();
+
+        let (h, wasm) = ufs.create_file(root_id, "wasm").unwrap();
+        ufs.write_file(h, b"\0asm\x01\0\0\0", 0).unwrap();
+        ufs.close_file(h).unwrap();
+
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+        let view = |id: UfsUuid, name: &str, token: Option<&str>| {
+            let preview = Preview {
+                token: token.map(str::to_owned),
+            };
+            get_file_values(id.to_string(), name.to_string(), preview, iofs.clone())
+        };
+
+        // Without a valid token, nothing is read from the file.
+        for token in &[None, Some("bogus")] {
+            let values = view(text.file_id, "text", *token);
+            assert_eq!(values["size"], include_str!("lib.rs").len());
+            assert!(values["type"].is_null());
+            assert!(values["preview"].is_null());
+        }
+
+        let values = view(text.file_id, "text", Some(token.as_str()));
+        assert_eq!("text/plain", values["type"]);
+        assert_eq!(
+            &include_str!("lib.rs")[..PREVIEW_LENGTH],
+            values["preview"].as_str().unwrap()
+        );
+
+        let values = view(wasm.file_id, "wasm", Some(token.as_str()));
+        assert_eq!("application/wasm", values["type"]);
+        assert!(values["preview"].is_null());
+    }
+
+    #[test]
+    fn invalid_file_id() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let values = get_file_values(
+            "not-a-uuid".to_string(),
+            "foo".to_string(),
+            Preview::default(),
+            iofs.clone(),
+        );
+        assert_eq!("invalid file id", values["name"]);
+
+        let values = get_file_values(
+            root_id.random().to_string(),
+            "foo".to_string(),
+            Preview::default(),
+            iofs.clone(),
+        );
+        assert_eq!("file not found", values["name"]);
+    }
+
+    #[test]
+    fn stream_upload() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let root_id = ufs.get_root_directory_id();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        // Chunks that don't line up with the blocks.
+        let data: Vec<u8> = (0..7000).map(|i| (i % 251) as u8).collect();
+        let body = futures::stream::iter_ok::<_, std::io::Error>(
+            data.chunks(999)
+                .map(|c| Cursor::new(c.to_vec()))
+                .collect::<Vec<_>>(),
+        );
+
+        let value = put_file(
+            root_id.to_string(),
+            "upload".to_string(),
+            token,
+            body,
+            iofs.clone(),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(7000, value["size"]);
+
+        let mut guard = iofs.write().unwrap();
+        let file_id: UfsUuid = value["id"].as_str().unwrap().parse().unwrap();
+        let h = guard.open_file(file_id, OpenFileMode::Read).unwrap();
+        assert_eq!(data, guard.read_file(h, 0, data.len() as u32).unwrap());
+    }
+
+    #[test]
+    fn form_upload() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let root_id = ufs.get_root_directory_id();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let form = |token: &str| {
+            let body = format!(
+                "--XYZ\r\n\
+                 Content-Disposition: form-data; name=\"token\"\r\n\r\n\
+                 {}\r\n\
+                 --XYZ\r\n\
+                 Content-Disposition: form-data; name=\"file\"; filename=\"C:\\tmp\\notes.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\n\
+                 hello, world\r\n\
+                 --XYZ--\r\n",
+                token
+            );
+            warp::test::request()
+                .method("POST")
+                .header("content-type", "multipart/form-data; boundary=XYZ")
+                .header("content-length", body.len())
+                .body(body)
+                .filter(&warp::multipart::form())
+                .unwrap()
+        };
+
+        let value = post_upload(root_id.to_string(), form("bogus"), iofs.clone())
+            .wait()
+            .unwrap();
+        assert!(value["error"].is_string());
+
+        let value = post_upload(root_id.to_string(), form(&token), iofs.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(12, value["size"]);
+
+        let mut guard = iofs.write().unwrap();
+        let file = guard
+            .block_manager()
+            .metadata()
+            .get_file_metadata_from_dir_and_name(root_id, "notes.txt")
+            .unwrap();
+        assert_eq!(value["id"], file.id().to_string());
+        let h = guard.open_file(file.id(), OpenFileMode::Read).unwrap();
+        assert_eq!(b"hello, world".to_vec(), guard.read_file(h, 0, 12).unwrap());
+    }
+
+    #[test]
+    fn download() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let data: Vec<u8> = (0..7000).map(|i| (i % 251) as u8).collect();
+        let h = ufs.create_path("/big.bin").unwrap();
+        ufs.write_file(h, &data, 0).unwrap();
+        ufs.close_file(h).unwrap();
+        let file_id = ufs
+            .block_manager()
+            .metadata()
+            .id_from_path("/big.bin")
+            .unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let response = get_download(file_id.to_string(), token.clone(), iofs.clone());
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("7000", response.headers()["content-length"]);
+        assert_eq!(
+            "attachment; filename=\"big.bin\"",
+            response.headers()["content-disposition"]
+        );
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(data, body.to_vec());
+
+        let response = get_download(file_id.random().to_string(), token, iofs.clone());
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = get_download(file_id.to_string(), "bogus".to_string(), iofs.clone());
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        // The handle went with the body, so removing the file frees its blocks straight away.
+        let mut guard = iofs.write().unwrap();
+        let free_blocks = guard.block_manager().free_block_count();
+        let root_id = guard.get_root_directory_id();
+        guard.remove_file(root_id, "big.bin").unwrap();
+        assert!(guard.block_manager().free_block_count() > free_blocks);
+    }
+
+    #[test]
+    fn stream_upload_bad_token() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let body = futures::stream::iter_ok::<_, std::io::Error>(vec![Cursor::new(vec![0; 10])]);
+        let value = put_file(
+            root_id.to_string(),
+            "upload".to_string(),
+            "bogus".to_string(),
+            body,
+            iofs.clone(),
+        )
+        .wait()
+        .unwrap();
+        assert!(value["error"].is_string());
+
+        let guard = iofs.read().unwrap();
+        assert!(guard
+            .block_manager()
+            .metadata()
+            .get_file_metadata_from_dir_and_name(root_id, "upload")
+            .is_err());
+    }
+
+    #[test]
+    fn raw_blocks() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+        let body = |response: Response| response.into_body().concat2().wait().unwrap().to_vec();
+
+        let data = (0..2048).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let response = post_raw_block(42, token.clone(), Cursor::new(data.clone()), iofs.clone());
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(b"2048", &body(response)[..]);
+
+        let response = get_raw_block(42, token.clone(), iofs.clone());
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "application/octet-stream",
+            response.headers()["content-type"]
+        );
+        assert_eq!(data, body(response));
+
+        // Blocks that don't exist, or don't fit
+        let response = get_raw_block(100, token.clone(), iofs.clone());
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = post_raw_block(42, token.clone(), Cursor::new(vec![0; 2049]), iofs.clone());
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // The map knows what was written.
+        {
+            let guard = iofs.read().unwrap();
+            let block = guard.block_manager().get_block(42).unwrap();
+            assert_eq!(Some(BlockHash::new(&data)), block.hash());
+        }
+
+        // Blocks that are in use are left alone.
+        let response = post_raw_block(0, token.clone(), Cursor::new(vec![0; 8]), iofs.clone());
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // Only the owner of the file system may write blocks.
+        let other = {
+            let mut guard = iofs.write().unwrap();
+            guard.add_user("other".to_string(), "bazqux".to_string());
+            guard
+                .login("other".to_string(), "bazqux".to_string())
+                .unwrap()
+        };
+        let response = post_raw_block(43, other, Cursor::new(vec![0; 8]), iofs.clone());
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        // Nothing happens without a valid token.
+        let response = get_raw_block(42, "bogus".to_string(), iofs.clone());
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = post_raw_block(42, "bogus".to_string(), Cursor::new(vec![0; 8]), iofs);
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[test]
+    fn dropped_wasm_response() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for a WASM program that goes away without responding.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || {
+            let msg = receiver.recv().unwrap();
+            assert_eq!("lost", msg.route());
+        });
+
+        let response = send_get_filter(
+            "token".to_string(),
+            "lost".to_string(),
+            String::new(),
+            HashMap::new(),
+            sender,
+            iofs,
+            dead_letters.clone(),
+        )
+        .wait()
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        let dead_letters = dead_letters.lock().unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!("lost", dead_letters[0].route);
+        assert!(dead_letters[0].time <= SystemTime::now());
+    }
+
+    #[test]
+    fn wasm_get_query() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for a WASM program that searches.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || match receiver.recv().unwrap() {
+            IofsNetworkMessage::Get(mut msg) => {
+                assert_eq!("search", msg.route());
+                assert_eq!("images/2019", msg.path());
+                assert_eq!(1, msg.query().len());
+                let q = msg.query()["q"].clone();
+                msg.respond(html_response(q));
+            }
+            _ => panic!("expected a GET"),
+        });
+
+        let mut query = HashMap::new();
+        query.insert("q".to_string(), "foo bar".to_string());
+        query.insert("token".to_string(), "token".to_string());
+
+        let response = send_get_filter(
+            "token".to_string(),
+            "search".to_string(),
+            "images/2019".to_string(),
+            query,
+            sender,
+            iofs,
+            dead_letters,
+        )
+        .wait()
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(b"foo bar", &body[..]);
+    }
+
+    #[test]
+    fn concurrent_wasm_requests() {
+        init();
+
+        const REQUESTS: usize = 8;
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Every request is sent before any of them is answered, so a handler that waited on its
+        // response would never get here.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let responses = (0..REQUESTS)
+            .map(|n| {
+                send_get_filter(
+                    "token".to_string(),
+                    format!("program-{}", n),
+                    String::new(),
+                    HashMap::new(),
+                    sender.clone(),
+                    iofs.clone(),
+                    dead_letters.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Stand in for WASM programs, answering in the opposite order from the requests.
+        let messages = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(REQUESTS, messages.len());
+        for msg in messages.into_iter().rev() {
+            let route = msg.route().to_string();
+            msg.respond(html_response(route));
+        }
+
+        let responses = future::join_all(responses).wait().unwrap();
+        for (n, response) in responses.into_iter().enumerate() {
+            assert_eq!(StatusCode::OK, response.status());
+            let body = response.into_body().concat2().wait().unwrap();
+            assert_eq!(format!("program-{}", n).as_bytes(), &body[..]);
+        }
+    }
+
+    #[test]
+    fn wasm_http_response() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for a WASM program that can't find what it's asked for.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || {
+            let msg = receiver.recv().unwrap();
+            msg.respond(
+                HttpResponse::new(404)
+                    .with_header("content-type", "application/json")
+                    .with_header("x-bogus\n", "dropped")
+                    .with_body(r#"{"error":"not found"}"#),
+            );
+        });
+
+        let response = send_get_filter(
+            "token".to_string(),
+            "missing".to_string(),
+            String::new(),
+            HashMap::new(),
+            sender,
+            iofs,
+            dead_letters,
+        )
+        .wait()
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("application/json", response.headers()["content-type"]);
+        assert_eq!(1, response.headers().len());
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(br#"{"error":"not found"}"#, &body[..]);
+    }
+
+    #[test]
+    fn rejected_wasm_request() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for the manager, finding no program to handle the request.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || {
+            let msg = receiver.recv().unwrap();
+            msg.no_such_endpoint();
+        });
+
+        let response = send_get_filter(
+            "token".to_string(),
+            "missing".to_string(),
+            String::new(),
+            HashMap::new(),
+            sender,
+            iofs,
+            dead_letters,
+        )
+        .wait()
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(b"no such endpoint", &body[..]);
+    }
+
+    #[test]
+    fn unavailable_wasm_response() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for a WASM program that ran out of time handling the request.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || {
+            let msg = receiver.recv().unwrap();
+            msg.unavailable();
+        });
+
+        let response = send_get_filter(
+            "token".to_string(),
+            "busy".to_string(),
+            String::new(),
+            HashMap::new(),
+            sender,
+            iofs,
+            dead_letters.clone(),
+        )
         .wait()
-        .expect("error reading channel")
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn binary_wasm_response() {
+        init();
+
+        let ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let iofs = Arc::new(RwLock::new(ufs));
+        let dead_letters = Arc::new(Mutex::new(VecDeque::new()));
+
+        // Stand in for a WASM program that reverses the bytes it's sent.
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsNetworkMessage>();
+        let program = spawn(move || match receiver.recv().unwrap() {
+            IofsNetworkMessage::PostBytes(mut msg) => {
+                assert_eq!("reverse", msg.route());
+                let mut bytes = msg.bytes().to_vec();
+                bytes.reverse();
+                msg.respond(
+                    HttpResponse::new(200)
+                        .with_header("content-type", "application/octet-stream")
+                        .with_body(bytes),
+                );
+            }
+            _ => panic!("expected a binary POST"),
+        });
+
+        let response = send_post_bytes_to_wasm(
+            "token".to_string(),
+            "reverse".to_string(),
+            Cursor::new(vec![0x00, 0xff, 0x42]),
+            sender,
+            iofs,
+            dead_letters.clone(),
+        )
+        .wait()
+        .unwrap();
+        program.join().unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "application/octet-stream",
+            response.headers()["content-type"]
+        );
+        assert!(dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn event_subscribers() {
+        init();
+
+        let root = UfsUuid::new_root_fs("test");
+        let payload = IofsMessagePayload {
+            target_id: root.new("foo"),
+            target_path: PathBuf::from("/foo"),
+            parent_id: root,
+        };
+
+        let mut subscribers = EventSubscribers::default();
+        let (id, events) = subscribers.subscribe();
+        let (_, gone) = subscribers.subscribe();
+        let (_, stalled) = subscribers.subscribe();
+        drop(gone);
+
+        subscribers.emit(&IofsMessage::SystemMessage(IofsSystemMessage::Ping));
+        subscribers.emit(&IofsMessage::FileMessage(IofsFileMessage::Create(
+            payload.clone(),
+        )));
+
+        // The client that went away is forgotten, and unsubscribing ends the other's stream.
+        assert_eq!(2, subscribers.inner.lock().unwrap().clients.len());
+        subscribers.unsubscribe(id);
+        assert_eq!(1, subscribers.inner.lock().unwrap().clients.len());
+
+        let events = events.collect().wait().unwrap();
+        assert_eq!(1, events.len());
+        let event: serde_json::Value = serde_json::from_str(&events[0].json).unwrap();
+        assert_eq!(
+            "/foo",
+            event["FileMessage"]["Create"]["target_path"]
+                .as_str()
+                .unwrap()
+        );
+
+        // A client that never reads is dropped once its backlog is full.
+        for _ in 0..EVENT_BACKLOG + 1 {
+            subscribers.emit(&IofsMessage::FileMessage(IofsFileMessage::Write(
+                payload.clone(),
+            )));
+        }
+        assert!(subscribers.inner.lock().unwrap().clients.is_empty());
+        assert_eq!(EVENT_BACKLOG + 1, stalled.collect().wait().unwrap().len());
+    }
+
+    #[test]
+    fn sessions_for_admin() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.add_user("other".to_string(), "barfoo".to_string());
+        let admin = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let other = ufs
+            .login("other".to_string(), "barfoo".to_string())
+            .unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let sessions = get_sessions(admin, iofs.clone());
+        let sessions = sessions.as_array().unwrap();
+        assert_eq!(2, sessions.len());
+        for session in sessions {
+            let issued = session["issued"].as_str().unwrap();
+            assert!(DateTime::parse_from_rfc3339(issued).is_ok());
+        }
+
+        let denied = get_sessions(other, iofs);
+        assert!(denied["error"].is_string());
+    }
+
+    #[test]
+    fn event_client_filter() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        ufs.add_user("other".to_string(), "barfoo".to_string());
+        let root_id = ufs.get_root_directory_id();
+        let (h, mine) = ufs.create_file(root_id, "mine").unwrap();
+        ufs.close_file(h).unwrap();
+        let theirs = ufs
+            .block_manager_mut()
+            .metadata_mut()
+            .new_directory(root_id, "theirs", UfsUuid::new_user("other"))
+            .unwrap();
+
+        let admin = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        let other = ufs
+            .login("other".to_string(), "barfoo".to_string())
+            .unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+
+        let created = |token: &str, logout: bool| {
+            let mut subscribers = EventSubscribers::default();
+            let (id, events) = subscribers.subscribe();
+            for (target_id, path) in &[(mine.file_id, "/mine"), (theirs.id(), "/theirs")] {
+                subscribers.emit(&IofsMessage::FileMessage(IofsFileMessage::Create(
+                    IofsMessagePayload {
+                        target_id: *target_id,
+                        target_path: PathBuf::from(path),
+                        parent_id: root_id,
+                    },
+                )));
+            }
+            subscribers.unsubscribe(id);
+            if logout {
+                write_ufs(&iofs).logout(token.to_string()).unwrap();
+            }
+
+            client_events(events, token.to_string(), iofs.clone())
+                .map(|msg| {
+                    let event: serde_json::Value =
+                        serde_json::from_str(msg.to_str().unwrap()).expect("event isn't JSON");
+                    event["FileMessage"]["Create"]["target_path"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+                .wait()
+                .unwrap()
+        };
+
+        // The owner of the file system sees everything, and other users only what's theirs.
+        assert_eq!(vec!["/mine", "/theirs"], created(&admin, false));
+        assert_eq!(vec!["/theirs"], created(&other, false));
+        // Once the token is gone, so are the events.
+        assert!(created(&other, true).is_empty());
+    }
+
+    #[test]
+    fn metrics() {
+        init();
+
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let token = ufs.login("test".to_string(), "foobar".to_string()).unwrap();
+        ufs.create_path("/foo").unwrap();
+        let iofs = Arc::new(RwLock::new(ufs));
+        let programs = Arc::new(AtomicUsize::new(2));
+        let metrics = HttpMetrics::default();
+        metrics
+            .routes
+            .lock()
+            .unwrap()
+            .insert("block".to_string(), (3, 0.5));
+
+        let response = get_metrics(token, iofs.clone(), programs.clone(), metrics.clone());
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.into_body().concat2().wait().unwrap().to_vec();
+        let body = String::from_utf8(body).unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"ufs_block_count 100"));
+        assert!(lines.contains(&"ufs_open_files 1"));
+        assert!(lines.contains(&"ufs_wasm_programs 2"));
+        assert!(lines.contains(&"ufs_dropped_events_total 0"));
+        assert!(lines.contains(&"ufs_http_requests_total{route=\"block\"} 3"));
+        assert!(lines.contains(&"ufs_http_request_seconds_total{route=\"block\"} 0.5"));
+
+        let response = get_metrics("bogus".to_string(), iofs, programs, metrics);
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
 }
@@ -1,8 +1,10 @@
-use chrono::prelude::*;
+use chrono::{offset::LocalResult, prelude::*};
+use failure::format_err;
 use serde_derive::{Deserialize, Serialize};
+use std::{convert::TryFrom, time::Duration};
 use time::Timespec;
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub(crate) struct UfsTime {
     inner: DateTime<Utc>,
 }
@@ -11,13 +13,87 @@ impl UfsTime {
     pub fn now() -> Self {
         UfsTime { inner: Utc::now() }
     }
+
+    /// How long ago this was
+    ///
+    /// A time in the future was no time ago.
+    pub(crate) fn age(&self) -> Duration {
+        (Utc::now() - self.inner).to_std().unwrap_or_default()
+    }
 }
 
 impl From<UfsTime> for Timespec {
     fn from(t: UfsTime) -> Self {
         Timespec {
             sec: t.inner.timestamp(),
-            nsec: t.inner.timestamp_nanos() as i32,
+            nsec: t.inner.timestamp_subsec_nanos() as i32,
+        }
+    }
+}
+
+/// A time sent by the kernel
+///
+/// Anything that `chrono` can't represent, e.g. a nanosecond count out of range, is an error.
+impl TryFrom<Timespec> for UfsTime {
+    type Error = failure::Error;
+
+    fn try_from(t: Timespec) -> Result<Self, Self::Error> {
+        match Utc.timestamp_opt(t.sec, t.nsec as u32) {
+            LocalResult::Single(inner) => Ok(UfsTime { inner }),
+            _ => Err(format_err!("time {}.{:09} is out of range", t.sec, t.nsec)),
+        }
+    }
+}
+
+/// The time stamps of a file or directory
+///
+/// This is what `stat` reports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Timestamps {
+    /// Creation time (crtime)
+    ///
+    pub(crate) birth: UfsTime,
+    /// Modification time (mtime)
+    ///
+    pub(crate) write: UfsTime,
+    /// Change time (ctime)
+    ///
+    pub(crate) change: UfsTime,
+    /// Access time (atime)
+    ///
+    pub(crate) access: UfsTime,
+}
+
+impl Timestamps {
+    /// All four time stamps set to `time`
+    ///
+    pub(crate) fn new(time: UfsTime) -> Self {
+        Timestamps {
+            birth: time,
+            write: time,
+            change: time,
+            access: time,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timespec() {
+        let t = Timespec {
+            sec: 1_234_567_890,
+            nsec: 987_654_321,
+        };
+        assert_eq!(t, Timespec::from(UfsTime::try_from(t).unwrap()));
+
+        assert!(UfsTime::try_from(Timespec {
+            sec: i64::max_value(),
+            nsec: 0
+        })
+        .is_err());
+        assert!(UfsTime::try_from(Timespec { sec: 0, nsec: -1 }).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use {
     lazy_static::lazy_static,
     rand::{distributions::Alphanumeric, thread_rng, Rng},
     serde_derive::{Deserialize, Serialize},
-    std::fmt,
+    std::{fmt, str::FromStr},
     uuid::Uuid,
 };
 
@@ -101,6 +101,16 @@ impl From<String> for UfsUuid {
     }
 }
 
+impl FromStr for UfsUuid {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(UfsUuid {
+            inner: Uuid::parse_str(s)?,
+        })
+    }
+}
+
 impl From<UfsUuid> for Uuid {
     fn from(uuid: UfsUuid) -> Self {
         uuid.inner
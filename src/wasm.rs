@@ -7,21 +7,27 @@ pub(crate) mod manager;
 pub(crate) mod message;
 
 pub(crate) use {
-    manager::{IofsEventRegistration, ProtoWasmProgram, RuntimeManager, RuntimeManagerMsg},
-    message::{
-        IofsDirMessage, IofsFileMessage, IofsMessage, IofsMessagePayload, IofsSystemMessage,
-        WasmMessageSender,
+    manager::{
+        IofsEventRegistration, ProtoWasmProgram, RestartStatus, RuntimeManager, RuntimeManagerMsg,
     },
+    message::WasmMessageSender,
+};
+
+pub use message::{
+    IofsDirMessage, IofsFileMessage, IofsMessage, IofsMessagePayload, IofsSystemMessage,
 };
 
 use {
     self::callbacks::*,
     crate::{
-        block::BlockStorage,
-        metadata::{DirectoryMetadata, File, FileHandle, Grant, GrantType},
-        server::IofsNetworkMessage,
-        OpenFileMode, UberFileSystem, UfsUuid,
+        block::{BlockCardinality, BlockSize, BlockStorage},
+        fsimpl::{read_ufs, write_ufs},
+        metadata::{DirectoryMetadata, File, FileHandle, FileSize, Grant, GrantType},
+        server::{html_status_response, IofsNetworkMessage},
+        time::Timestamps,
+        IOFSErrorKind, OpenFileMode, UberFileSystem, UfsUuid,
     },
+    chrono::Utc,
     crossbeam::crossbeam_channel,
     failure::{Backtrace, Context, Fail},
     log::{debug, error, info},
@@ -29,17 +35,35 @@ use {
         collections::HashMap,
         ffi::c_void,
         fmt::{self, Display},
-        path::PathBuf,
+        path::{Path, PathBuf},
         str,
-        sync::{Arc, Mutex},
+        sync::{Arc, RwLock},
         thread::{spawn, JoinHandle},
     },
     wasm_exports::WasmMessage,
-    wasmer_runtime::{func, imports, instantiate},
+    wasmer_runtime::{error::CompileResult, func, imports, Module},
+};
+
+#[cfg(target_arch = "x86_64")]
+use {
+    wasmer_middleware_common::metering::Metering,
+    wasmer_runtime::compile_with,
+    wasmer_runtime_core::{
+        backend::Compiler,
+        codegen::{MiddlewareChain, StreamingCompiler},
+    },
+    wasmer_singlepass_backend::ModuleCodeGenerator as SinglePassMCG,
 };
 
+#[cfg(not(target_arch = "x86_64"))]
+use {log::warn, wasmer_runtime::compile};
+
 const WRITE_BUF_SIZE: usize = 2048;
 
+/// How much printed output is gathered before it's written to the program's log file
+///
+const LOG_BUF_SIZE: usize = 4096;
+
 struct FileWriteBuffer {
     buffer: [u8; WRITE_BUF_SIZE],
     len: usize,
@@ -49,6 +73,10 @@ struct FileWriteBuffer {
 pub(crate) enum WasmProcessMessage {
     IofsEvent(IofsMessage),
     NetworkEvent(IofsNetworkMessage),
+    /// A message from another program: the sender's path, and the payload
+    ProgramMessage(PathBuf, Vec<u8>),
+    /// An event published by another program: the name, and the payload
+    ProgramEvent(String, Vec<u8>),
 }
 
 /// The main interface between the file system and WASM
@@ -57,11 +85,16 @@ pub(crate) enum WasmProcessMessage {
 /// channel which the file system uses to send file system events to the WASM program. The WASM
 /// program itself is started in the `start` associated function. Messages are received there and
 /// forwarded to the executing WASM program.
-pub(crate) struct WasmProcess<B: BlockStorage + 'static> {
+///
+/// Messages, including HTTP requests, are handled in the order they arrive, one at a time. Other
+/// programs run on their own threads, so a slow handler only holds up requests for this program.
+pub(crate) struct WasmProcess<B: BlockStorage + Sync + 'static> {
     /// A unique identifier for the WASM program -- it's the path, and there can be only one.
     path: PathBuf,
     /// The bytes that comprise the program.
     program: Vec<u8>,
+    /// Configuration for the program, read from a `.config` file next to the program.
+    config: Option<String>,
     /// The file system sends messages with sender...
     sender: crossbeam_channel::Sender<WasmProcessMessage>,
     /// we receive them using this.
@@ -70,31 +103,40 @@ pub(crate) struct WasmProcess<B: BlockStorage + 'static> {
     /// invocations to the file system -- we can filter notifications with these.
     sync_func_ids: Vec<UfsUuid>,
     /// IOFS access
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
     /// Write buffers for write_file
     write_buffers: HashMap<FileHandle, FileWriteBuffer>,
+    /// Printed lines that have yet to be written to the log file
+    log_buffer: String,
     /// Message registration channel sender
     message_registration_sender: crossbeam_channel::Sender<IofsEventRegistration>,
+    /// The number of points the program may spend on each invocation
+    execution_limit: u64,
 }
 
-impl<B: BlockStorage> WasmProcess<B> {
+impl<B: BlockStorage + Sync> WasmProcess<B> {
     pub(in crate::wasm) fn new(
         path: PathBuf,
         program: Vec<u8>,
+        config: Option<String>,
         message_registration_sender: crossbeam_channel::Sender<IofsEventRegistration>,
-        iofs: Arc<Mutex<UberFileSystem<B>>>,
+        iofs: Arc<RwLock<UberFileSystem<B>>>,
+        execution_limit: u64,
     ) -> Self {
         let (sender, receiver) = crossbeam_channel::unbounded::<WasmProcessMessage>();
 
         WasmProcess {
             path,
             program,
+            config,
             sender,
             receiver,
             sync_func_ids: vec![],
             iofs,
             write_buffers: HashMap::new(),
+            log_buffer: String::new(),
             message_registration_sender,
+            execution_limit,
         }
     }
 
@@ -110,9 +152,15 @@ impl<B: BlockStorage> WasmProcess<B> {
         self.sender.clone()
     }
 
-    pub(crate) fn set_handles_message(&mut self, msg: WasmMessage) {
+    pub(crate) fn set_handles_message(&mut self, msg: WasmMessage, scope: Option<UfsUuid>) {
+        self.message_registration_sender
+            .send(IofsEventRegistration::Register(msg, scope))
+            .unwrap();
+    }
+
+    pub(crate) fn register_for_event(&mut self, name: String) {
         self.message_registration_sender
-            .send(IofsEventRegistration::Register(msg))
+            .send(IofsEventRegistration::RegisterEvent(name))
             .unwrap();
     }
 
@@ -177,7 +225,7 @@ impl<B: BlockStorage> WasmProcess<B> {
         mode: OpenFileMode,
     ) -> Result<FileHandle, failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
 
         match guard
             .block_manager_mut()
@@ -195,9 +243,39 @@ impl<B: BlockStorage> WasmProcess<B> {
         }
     }
 
+    /// Open the file at `path`, relative to the root of the file system
+    ///
+    /// The grant is checked before the path is resolved, so that a program without permission to
+    /// open files can't use this to learn what exists.
+    pub(crate) fn open_path(
+        &mut self,
+        path: &str,
+    ) -> Result<(FileHandle, UfsUuid), failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+
+        match guard
+            .block_manager_mut()
+            .metadata_mut()
+            .check_wasm_program_grant(&self.path, GrantType::OpenFileInvocation)
+        {
+            Some(Grant::Allow) => {
+                let id = guard
+                    .block_manager()
+                    .metadata()
+                    .id_from_path(Path::new("/").join(path))
+                    .ok_or(IOFSErrorKind::NotFound)?;
+                let handle = guard.open_file(id, OpenFileMode::Read)?;
+                self.sync_func_ids.push(id);
+                Ok((handle, id))
+            }
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
     pub(crate) fn close_file(&mut self, id: UfsUuid, handle: FileHandle) {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
 
         // Flush the write buffer if necessary before closing the file.
         if let Some(buffer) = self.write_buffers.remove(&handle) {
@@ -233,7 +311,7 @@ impl<B: BlockStorage> WasmProcess<B> {
         size: u32,
     ) -> Result<Vec<u8>, failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
 
         match guard
             .block_manager_mut()
@@ -258,7 +336,8 @@ impl<B: BlockStorage> WasmProcess<B> {
         bytes: T,
     ) -> Result<usize, failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
 
         match guard
             .block_manager_mut()
@@ -284,9 +363,7 @@ impl<B: BlockStorage> WasmProcess<B> {
                     bytes_written += write_len;
 
                     if buffer.len == WRITE_BUF_SIZE {
-                        guard
-                            .write_file(handle, &buffer.buffer, buffer.file_offset)
-                            .expect("error writing bytes in WasmProcess::write_file");
+                        guard.write_file(handle, &buffer.buffer, buffer.file_offset)?;
                         buffer.file_offset += WRITE_BUF_SIZE as u64;
                         buffer.len = 0;
 
@@ -307,7 +384,8 @@ impl<B: BlockStorage> WasmProcess<B> {
         name: &str,
     ) -> Result<(FileHandle, File), failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
 
         match guard
             .block_manager_mut()
@@ -325,13 +403,43 @@ impl<B: BlockStorage> WasmProcess<B> {
         }
     }
 
+    /// Copy a file, sharing its blocks
+    ///
+    /// A copy both reads the original, and creates a file, so the program needs to be allowed to
+    /// do both.
+    pub(crate) fn copy_file(
+        &mut self,
+        id: UfsUuid,
+        dir_id: UfsUuid,
+        name: &str,
+    ) -> Result<File, failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
+
+        let metadata = guard.block_manager_mut().metadata_mut();
+        let read = metadata.check_wasm_program_grant(&self.path, GrantType::ReadFileInvocation);
+        let create = metadata.check_wasm_program_grant(&self.path, GrantType::CreateFileInvocation);
+        match (read, create) {
+            (Some(Grant::Allow), Some(Grant::Allow)) => match guard.copy_file(id, dir_id, name) {
+                Ok(f) => {
+                    self.sync_func_ids.push(dir_id);
+                    Ok(f)
+                }
+                Err(e) => Err(e),
+            },
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
     pub(crate) fn create_directory(
         &mut self,
         dir_id: UfsUuid,
         name: &str,
     ) -> Result<DirectoryMetadata, failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
 
         match guard
             .block_manager_mut()
@@ -355,7 +463,7 @@ impl<B: BlockStorage> WasmProcess<B> {
         name: &str,
     ) -> Result<UfsUuid, failure::Error> {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
 
         match guard
             .block_manager_mut()
@@ -366,171 +474,460 @@ impl<B: BlockStorage> WasmProcess<B> {
             _ => Err(RuntimeErrorKind::IofsPermission.into()),
         }
     }
+
+    pub(crate) fn read_directory(
+        &mut self,
+        dir_id: UfsUuid,
+    ) -> Result<Vec<(String, UfsUuid, bool)>, failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+
+        match guard
+            .block_manager_mut()
+            .metadata_mut()
+            .check_wasm_program_grant(&self.path, GrantType::ReadDirInvocation)
+        {
+            Some(Grant::Allow) => guard.read_sub_directory(dir_id),
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
+    /// Append a message to the program's log file
+    ///
+    /// Messages are buffered, and written out once the buffer fills, or the program has finished
+    /// handling the current message.
+    pub(crate) fn log(&mut self, message: &str) {
+        self.log_buffer
+            .push_str(&format!("{} {}\n", Utc::now().to_rfc3339(), message));
+        if self.log_buffer.len() >= LOG_BUF_SIZE {
+            self.flush_log();
+        }
+    }
+
+    /// Write buffered messages to the program's log file
+    ///
+    fn flush_log(&mut self) {
+        if self.log_buffer.is_empty() {
+            return;
+        }
+
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+
+        if let Err(e) = guard.append_wasm_log(&self.path, &self.log_buffer) {
+            error!("unable to write to log for {:?}: {}", self.path, e);
+        }
+        self.log_buffer.clear();
+    }
+
+    pub(crate) fn stat(
+        &mut self,
+        id: UfsUuid,
+    ) -> Result<(FileSize, u16, usize, Timestamps), failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+
+        match guard
+            .block_manager_mut()
+            .metadata_mut()
+            .check_wasm_program_grant(&self.path, GrantType::StatInvocation)
+        {
+            Some(Grant::Allow) => guard.stat_file(id),
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
+    /// File system statistics
+    ///
+    /// These don't reveal anything about any file, so no grant is needed.
+    pub(crate) fn statfs(&self) -> (BlockCardinality, BlockCardinality, BlockSize, usize) {
+        read_ufs(&self.iofs).statfs()
+    }
+
+    pub(crate) fn chmod(&mut self, id: UfsUuid, mode: u16) -> Result<(), failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
+
+        match guard
+            .block_manager_mut()
+            .metadata_mut()
+            .check_wasm_program_grant(&self.path, GrantType::ChmodInvocation)
+        {
+            Some(Grant::Allow) => match guard.get_permissions(id) {
+                Some(_) => guard.set_permissions(id, mode & 0o7777),
+                None => Err(RuntimeErrorKind::IofsInvocation.into()),
+            },
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
+    pub(crate) fn get_value(&mut self, key: &str) -> Result<Option<String>, failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+        let metadata = guard.block_manager_mut().metadata_mut();
+
+        match metadata.check_wasm_program_grant(&self.path, GrantType::KeyValueInvocation) {
+            Some(Grant::Allow) => Ok(metadata.get_wasm_program_value(&self.path, key)),
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
+    pub(crate) fn put_value(&mut self, key: String, value: String) -> Result<(), failure::Error> {
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+        guard.check_writable()?;
+        let metadata = guard.block_manager_mut().metadata_mut();
+
+        match metadata.check_wasm_program_grant(&self.path, GrantType::KeyValueInvocation) {
+            Some(Grant::Allow) => metadata.put_wasm_program_value(&self.path, key, value),
+            _ => Err(RuntimeErrorKind::IofsPermission.into()),
+        }
+    }
+
+    pub(crate) fn send_message(
+        &mut self,
+        target: PathBuf,
+        payload: Vec<u8>,
+    ) -> Result<(), failure::Error> {
+        if self.may_send_messages() {
+            self.message_registration_sender
+                .send(IofsEventRegistration::SendMessage(target, payload))
+                .unwrap();
+            Ok(())
+        } else {
+            Err(RuntimeErrorKind::IofsPermission.into())
+        }
+    }
+
+    /// Publish an event to the programs that subscribe to it
+    ///
+    /// Publishing is just another way of sending messages to other programs, and so it needs the
+    /// same grant.
+    pub(crate) fn publish_event(
+        &mut self,
+        name: String,
+        payload: Vec<u8>,
+    ) -> Result<(), failure::Error> {
+        if self.may_send_messages() {
+            self.message_registration_sender
+                .send(IofsEventRegistration::PublishEvent(name, payload))
+                .unwrap();
+            Ok(())
+        } else {
+            Err(RuntimeErrorKind::IofsPermission.into())
+        }
+    }
+
+    /// Check the grant to send messages, and publish events
+    ///
+    /// Programs may send messages often, and once the grant is settled checking it only needs the
+    /// read lock. The write lock is only taken to ask the user, the first time around.
+    fn may_send_messages(&self) -> bool {
+        let grant = read_ufs(&self.iofs)
+            .block_manager()
+            .metadata()
+            .peek_wasm_program_grant(&self.path, GrantType::SendMessage);
+
+        match grant {
+            Some(Grant::Unknown) => {
+                let mut guard = write_ufs(&self.iofs);
+                guard
+                    .block_manager_mut()
+                    .metadata_mut()
+                    .check_wasm_program_grant(&self.path, GrantType::SendMessage)
+                    == Some(Grant::Allow)
+            }
+            grant => grant == Some(Grant::Allow),
+        }
+    }
 }
 
-impl<B: BlockStorage> WasmProcess<B> {
-    pub(crate) fn start(mut process: WasmProcess<B>) -> JoinHandle<Result<(), failure::Error>> {
+impl<B: BlockStorage + Sync> WasmProcess<B> {
+    pub(crate) fn start(process: WasmProcess<B>) -> JoinHandle<Result<(), failure::Error>> {
         debug!("--------");
         debug!("start {:?}", process.path);
         spawn(move || {
-            // This is the mapping of functions imported to the WASM interpreter.
-            let import_object = imports! {
-                "env" => {
-                    "__register_for_callback" => func!(__register_for_callback<B>),
-                    "__register_get_handler" => func!(__register_get_handler<B>),
-                    "__register_post_handler" => func!(__register_post_handler<B>),
-                    "__register_put_handler" => func!(__register_put_handler<B>),
-                    "__register_patch_handler" => func!(__register_patch_handler<B>),
-                    "__register_delete_handler" => func!(__register_delete_handler<B>),
-                    "__print" => func!(__print<B>),
-                    "__open_file" => func!(__open_file<B>),
-                    "__close_file" => func!(__close_file<B>),
-                    "__read_file" => func!(__read_file<B>),
-                    "__write_file" => func!(__write_file<B>),
-                    "__create_file" => func!(__create_file<B>),
-                    "__create_directory" => func!(__create_directory<B>),
-                    "__open_directory" => func!(__open_directory<B>),
-                    "pong" => func!(pong),
-                },
-            };
-
-            let mut instance = match instantiate(process.program.as_slice(), &import_object) {
-                Ok(i) => {
-                    info!("Instantiated WASM program {}", process.name());
-                    i
-                }
-                Err(e) => {
-                    error!(
-                        "Error {} -- unable to instantiate WASM program: {}",
-                        e,
-                        process.path()
-                    );
-                    return Err(RuntimeErrorKind::ProgramInstantiation.into());
+            let path = process.path.clone();
+            let registration_sender = process.message_registration_sender.clone();
+
+            match WasmProcess::run(process) {
+                // A runaway program is terminated, and the RuntimeManager told to forget about it.
+                Err(ref e) if exceeded_limit(e) => {
+                    error!("WASM program {:?} exceeded its execution limit", path);
+                    let _ = registration_sender.send(IofsEventRegistration::Terminated);
+                    Ok(())
                 }
-            };
+                result => result,
+            }
+        })
+    }
 
-            // Clear the program buffer, and save a little memory?
-            process.program = vec![];
+    /// Run a WASM program
+    ///
+    /// The program is instantiated, and then fed messages until it's told to shut down.
+    fn run(mut process: WasmProcess<B>) -> Result<(), failure::Error> {
+        // This is the mapping of functions imported to the WASM interpreter.
+        let import_object = imports! {
+            "env" => {
+                "__register_for_callback" => func!(__register_for_callback<B>),
+                "__register_for_scoped_callback" => func!(__register_for_scoped_callback<B>),
+                "__register_for_event" => func!(__register_for_event<B>),
+                "__register_get_handler" => func!(__register_get_handler<B>),
+                "__register_post_handler" => func!(__register_post_handler<B>),
+                "__register_put_handler" => func!(__register_put_handler<B>),
+                "__register_patch_handler" => func!(__register_patch_handler<B>),
+                "__register_delete_handler" => func!(__register_delete_handler<B>),
+                "__print" => func!(__print<B>),
+                "__open_file" => func!(__open_file<B>),
+                "__open_path" => func!(__open_path<B>),
+                "__close_file" => func!(__close_file<B>),
+                "__read_file" => func!(__read_file<B>),
+                "__write_file" => func!(__write_file<B>),
+                "__create_file" => func!(__create_file<B>),
+                "__copy_file" => func!(__copy_file<B>),
+                "__create_directory" => func!(__create_directory<B>),
+                "__open_directory" => func!(__open_directory<B>),
+                "__read_dir" => func!(__read_dir<B>),
+                "__stat" => func!(__stat<B>),
+                "__statfs" => func!(__statfs<B>),
+                "__chmod" => func!(__chmod<B>),
+                "__kv_get" => func!(__kv_get<B>),
+                "__kv_put" => func!(__kv_put<B>),
+                "__send_message" => func!(__send_message<B>),
+                "__publish_event" => func!(__publish_event<B>),
+                "pong" => func!(pong),
+            },
+        };
 
-            instance.context_mut().data = &mut process as *mut _ as *mut c_void;
+        let module = match compile_program(process.program.as_slice(), process.execution_limit) {
+            Ok(m) => m,
+            Err(e) => {
+                error!(
+                    "Error {} -- unable to compile WASM program: {}",
+                    e,
+                    process.path()
+                );
+                return Err(RuntimeErrorKind::ProgramInstantiation.into());
+            }
+        };
 
-            let root_id;
-            {
-                let guard = process.iofs.clone();
-                let guard = guard.lock().expect("poisoned iofs lock");
-                root_id = guard.get_root_directory_id();
+        let mut instance = match module.instantiate(&import_object) {
+            Ok(i) => {
+                info!("Instantiated WASM program {}", process.name());
+                i
+            }
+            Err(e) => {
+                error!(
+                    "Error {} -- unable to instantiate WASM program: {}",
+                    e,
+                    process.path()
+                );
+                return Err(RuntimeErrorKind::ProgramInstantiation.into());
             }
+        };
 
-            let mut msg_sender = WasmMessageSender::new(&mut instance, root_id);
-
-            loop {
-                let message = process.receiver.recv().unwrap();
-                match message {
-                    WasmProcessMessage::IofsEvent(message) => {
-                        debug!(
-                            "{:?} dispatching file system message {:#?}",
-                            process.path, message
-                        );
-                        match &message {
-                            IofsMessage::SystemMessage(m) => match m {
-                                IofsSystemMessage::Shutdown => {
-                                    msg_sender.send_shutdown()?;
-                                }
-                                IofsSystemMessage::Ping => {
-                                    msg_sender.send_ping()?;
+        // Clear the program buffer, and save a little memory?
+        process.program = vec![];
+
+        instance.context_mut().data = &mut process as *mut _ as *mut c_void;
+
+        let root_id;
+        {
+            let guard = process.iofs.clone();
+            let guard = read_ufs(&guard);
+            root_id = guard.get_root_directory_id();
+        }
+
+        let config = process.config.take();
+        let mut msg_sender = WasmMessageSender::new(&mut instance, root_id, config)?;
+
+        loop {
+            let message = process.receiver.recv().unwrap();
+            match message {
+                WasmProcessMessage::IofsEvent(message) => {
+                    debug!(
+                        "{:?} dispatching file system message {:#?}",
+                        process.path, message
+                    );
+                    match &message {
+                        IofsMessage::SystemMessage(m) => match m {
+                            IofsSystemMessage::Shutdown => {
+                                msg_sender.send_shutdown()?;
+                            }
+                            IofsSystemMessage::Ping => {
+                                msg_sender.send_ping()?;
+                            }
+                        },
+                        IofsMessage::FileMessage(m) => match m {
+                            IofsFileMessage::Create(payload) => {
+                                if process.should_send_notification(&payload.parent_id) {
+                                    msg_sender.send_file_create(&payload)?;
                                 }
-                            },
-                            IofsMessage::FileMessage(m) => match m {
-                                IofsFileMessage::Create(payload) => {
-                                    if process.should_send_notification(&payload.parent_id) {
-                                        msg_sender.send_file_create(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Delete(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_delete(&payload)?;
                                 }
-                                IofsFileMessage::Delete(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_file_delete(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Open(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_open(&payload)?;
                                 }
-                                IofsFileMessage::Open(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_file_open(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Close(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_close(&payload)?;
                                 }
-                                IofsFileMessage::Close(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_file_close(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Write(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_write(&payload)?;
                                 }
-                                IofsFileMessage::Write(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_file_write(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Read(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_read(&payload)?;
                                 }
-                                IofsFileMessage::Read(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_file_read(&payload)?;
-                                    }
+                            }
+                            IofsFileMessage::Rename(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_file_rename(&payload)?;
                                 }
-                            },
-                            IofsMessage::DirMessage(m) => match m {
-                                IofsDirMessage::Create(payload) => {
-                                    if process.should_send_notification(&payload.parent_id) {
-                                        msg_sender.send_dir_create(&payload)?;
-                                    }
+                            }
+                        },
+                        IofsMessage::DirMessage(m) => match m {
+                            IofsDirMessage::Create(payload) => {
+                                if process.should_send_notification(&payload.parent_id) {
+                                    msg_sender.send_dir_create(&payload)?;
                                 }
-                                IofsDirMessage::Delete(payload) => {
-                                    if process.should_send_notification(&payload.target_id) {
-                                        msg_sender.send_dir_delete(&payload)?;
-                                    }
+                            }
+                            IofsDirMessage::Delete(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_dir_delete(&payload)?;
                                 }
-                            },
-                        };
-                        if let IofsMessage::SystemMessage(IofsSystemMessage::Shutdown) = message {
-                            info!("WASM program {} shutting down", process.name());
-                            break;
-                        }
-                    }
-                    WasmProcessMessage::NetworkEvent(mut message) => {
-                        debug!(
-                            "{:?} dispatching network message {:#?}",
-                            process.path, message
-                        );
-                        match &mut message {
-                            IofsNetworkMessage::Get(msg) => {
-                                match msg_sender.send_http_get(msg) {
-                                    Ok(response) => msg.respond(response),
-                                    Err(e) => msg.respond(e.to_string()),
-                                };
                             }
-                            IofsNetworkMessage::Post(msg) => match msg_sender.send_http_post(msg) {
-                                Ok(response) => msg.respond(response),
-                                Err(e) => msg.respond(e.to_string()),
-                            },
-                            IofsNetworkMessage::Put(msg) => match msg_sender.send_http_put(msg) {
-                                Ok(response) => msg.respond(response),
-                                Err(e) => msg.respond(e.to_string()),
-                            },
-                            IofsNetworkMessage::Patch(msg) => match msg_sender.send_http_patch(msg)
-                            {
-                                Ok(response) => msg.respond(response),
-                                Err(e) => msg.respond(e.to_string()),
-                            },
-                            IofsNetworkMessage::Delete(msg) => {
-                                match msg_sender.send_http_delete(msg) {
-                                    Ok(response) => msg.respond(response),
-                                    Err(e) => msg.respond(e.to_string()),
+                            IofsDirMessage::Rename(payload) => {
+                                if process.should_send_notification(&payload.target_id) {
+                                    msg_sender.send_dir_rename(&payload)?;
                                 }
                             }
+                        },
+                    };
+                    if let IofsMessage::SystemMessage(IofsSystemMessage::Shutdown) = message {
+                        info!("WASM program {} shutting down", process.name());
+                        break;
+                    }
+                }
+                WasmProcessMessage::NetworkEvent(message) => {
+                    debug!(
+                        "{:?} dispatching network message {:#?}",
+                        process.path, message
+                    );
+                    let response = match &message {
+                        IofsNetworkMessage::Get(msg) => msg_sender.send_http_get(msg),
+                        IofsNetworkMessage::Post(msg) => msg_sender.send_http_post(msg),
+                        IofsNetworkMessage::Put(msg) => msg_sender.send_http_put(msg),
+                        IofsNetworkMessage::Patch(msg) => msg_sender.send_http_patch(msg),
+                        IofsNetworkMessage::Delete(msg) => msg_sender.send_http_delete(msg),
+                        IofsNetworkMessage::PostBytes(msg) => msg_sender.send_http_post_bytes(msg),
+                    };
+                    match response {
+                        Ok(response) => message.respond(response),
+                        Err(e) => {
+                            if exceeded_limit(&e) {
+                                message.unavailable();
+                                return Err(e);
+                            }
+                            message.respond(html_status_response(500, e.to_string()));
                         }
                     }
                 }
+                WasmProcessMessage::ProgramMessage(from, payload) => {
+                    debug!("{:?} dispatching message from {:?}", process.path, from);
+                    msg_sender.send_program_message(&from, &payload)?;
+                }
+                WasmProcessMessage::ProgramEvent(name, payload) => {
+                    debug!("{:?} dispatching event {}", process.path, name);
+                    msg_sender.send_program_event(&name, &payload)?;
+                }
             }
+            process.flush_log();
+        }
 
-            Ok(())
-        })
+        Ok(())
+    }
+}
+
+/// A hand assembled WASM program whose `__init` never returns
+///
+#[cfg(all(test, target_arch = "x86_64"))]
+#[rustfmt::skip]
+pub(in crate::wasm) const LOOP_PROGRAM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+    0x01, 0x06, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x00, // type section: (i32, i32) -> ()
+    0x03, 0x02, 0x01, 0x00, // function section
+    0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+    0x07, 0x13, 0x02, // export section
+    0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, // "memory"
+    0x06, 0x5f, 0x5f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, // "__init"
+    0x0a, 0x09, 0x01, // code section
+    0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // loop br 0 end
+];
+
+/// Whichever way the program stops, its remaining output is written, and its log file closed
+///
+impl<B: BlockStorage + Sync + 'static> Drop for WasmProcess<B> {
+    fn drop(&mut self) {
+        self.flush_log();
+
+        let guard = self.iofs.clone();
+        let mut guard = write_ufs(&guard);
+
+        if let Err(e) = guard.close_wasm_log(&self.path) {
+            error!("unable to close log for {:?}: {}", self.path, e);
+        }
     }
 }
 
+/// Was the program terminated for exceeding its execution limit?
+///
+fn exceeded_limit(error: &failure::Error) -> bool {
+    error.downcast_ref::<RuntimeErrorKind>() == Some(&RuntimeErrorKind::ExecutionLimitExceeded)
+}
+
+/// Compile a WASM program, metered so that it may spend at most `limit` points per invocation
+///
+#[cfg(target_arch = "x86_64")]
+fn compile_program(program: &[u8], limit: u64) -> CompileResult<Module> {
+    compile_with(program, &metered_compiler(limit))
+}
+
+/// Compile a WASM program with the default backend
+///
+/// Metering needs the single pass backend, which only targets x86_64. Elsewhere programs aren't
+/// metered, and `limit` isn't enforced.
+#[cfg(not(target_arch = "x86_64"))]
+fn compile_program(program: &[u8], _limit: u64) -> CompileResult<Module> {
+    warn!("WASM programs aren't metered on this architecture");
+    compile(program)
+}
+
+/// A compiler that meters the program's execution
+///
+/// Every function invoked from the file system may spend at most `limit` points before it's
+/// interrupted. Metering is only supported by the single pass backend.
+#[cfg(target_arch = "x86_64")]
+pub(in crate::wasm) fn metered_compiler(limit: u64) -> impl Compiler {
+    let compiler: StreamingCompiler<SinglePassMCG, _, _, _, _> =
+        StreamingCompiler::new(move || {
+            let mut chain = MiddlewareChain::new();
+            chain.push(Metering::new(limit));
+            chain
+        });
+    compiler
+}
+
 #[derive(Debug)]
 struct RuntimeError {
     inner: Context<RuntimeErrorKind>,
@@ -568,6 +965,10 @@ enum RuntimeErrorKind {
     IofsInvocation,
     #[fail(display = "Insufficient permissions to execute function.")]
     IofsPermission,
+    #[fail(display = "WASM program exceeded its execution limit.")]
+    ExecutionLimitExceeded,
+    #[fail(display = "Access outside of WASM program memory.")]
+    MemoryAccess,
 }
 
 impl From<RuntimeErrorKind> for RuntimeError {
@@ -3,73 +3,134 @@
 //! Functions that are declared in the WASM program as `extern` are resolved here.
 //!
 use {
-    crate::{block::BlockStorage, wasm::WasmProcess, OpenFileMode},
+    crate::{
+        block::{BlockSizeType, BlockStorage},
+        fsimpl::read_ufs,
+        wasm::{message::FIXED_MESSAGE_LIMIT, RuntimeErrorKind, WasmProcess},
+        OpenFileMode,
+    },
     colored::*,
     log::{debug, error, info},
-    std::{convert::TryInto, str},
+    serde::Serialize,
+    std::{convert::TryInto, path::PathBuf},
+    time::Timespec,
     uuid::Uuid,
-    wasm_exports::{FileHandle, WasmMessage},
+    wasm_exports::{FileHandle, FileStat, FsStats, WasmMessage},
     wasmer_runtime::Ctx,
 };
 
 pub(crate) fn __register_for_callback<B>(ctx: &mut Ctx, message_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let message = unbox_message(ctx, message_ptr);
-    info!("register notification {:?}", message);
-    wc.set_handles_message(message);
+    match unbox_message(ctx, message_ptr) {
+        Ok(message) => {
+            info!("register notification {:?}", message);
+            wc.set_handles_message(message, None);
+        }
+        Err(e) => error!("unable to register notification {}", e),
+    }
+}
+
+pub(crate) fn __register_for_scoped_callback<B>(ctx: &mut Ctx, message_ptr: u32, id_ptr: u32)
+where
+    B: BlockStorage + Sync + 'static,
+{
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let registration =
+        unbox_message(ctx, message_ptr).and_then(|message| Ok((message, unbox_id(ctx, id_ptr)?)));
+    match registration {
+        Ok((message, id)) => {
+            info!("register notification {:?} beneath {}", message, id);
+            wc.set_handles_message(message, Some(id.into()));
+        }
+        Err(e) => error!("unable to register scoped notification {}", e),
+    }
+}
+
+pub(crate) fn __register_for_event<B>(ctx: &mut Ctx, name_ptr: u32)
+where
+    B: BlockStorage + Sync + 'static,
+{
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    match unbox_str(ctx, name_ptr) {
+        Ok(name) => {
+            info!("register event {:?}", name);
+            wc.register_for_event(name);
+        }
+        Err(e) => error!("unable to register event {}", e),
+    }
 }
 
 pub(crate) fn __register_get_handler<B>(ctx: &mut Ctx, get_route_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let route = unbox_str(ctx, get_route_ptr);
-    info!("register GET handler {:?}", route);
-    wc.register_get_callback(route);
+    match unbox_str(ctx, get_route_ptr) {
+        Ok(route) => {
+            info!("register GET handler {:?}", route);
+            wc.register_get_callback(route);
+        }
+        Err(e) => error!("unable to register GET handler {}", e),
+    }
 }
 
 pub(crate) fn __register_post_handler<B>(ctx: &mut Ctx, post_route_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let route = unbox_str(ctx, post_route_ptr);
-    info!("register POST handler {:?}", route);
-    wc.register_post_callback(route);
+    match unbox_str(ctx, post_route_ptr) {
+        Ok(route) => {
+            info!("register POST handler {:?}", route);
+            wc.register_post_callback(route);
+        }
+        Err(e) => error!("unable to register POST handler {}", e),
+    }
 }
 
 pub(crate) fn __register_put_handler<B>(ctx: &mut Ctx, put_route_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let route = unbox_str(ctx, put_route_ptr);
-    info!("register PUT handler {:?}", route);
-    wc.register_put_callback(route);
+    match unbox_str(ctx, put_route_ptr) {
+        Ok(route) => {
+            info!("register PUT handler {:?}", route);
+            wc.register_put_callback(route);
+        }
+        Err(e) => error!("unable to register PUT handler {}", e),
+    }
 }
 
 pub(crate) fn __register_patch_handler<B>(ctx: &mut Ctx, patch_route_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let route = unbox_str(ctx, patch_route_ptr);
-    info!("register PATCH handler {:?}", route);
-    wc.register_patch_callback(route);
+    match unbox_str(ctx, patch_route_ptr) {
+        Ok(route) => {
+            info!("register PATCH handler {:?}", route);
+            wc.register_patch_callback(route);
+        }
+        Err(e) => error!("unable to register PATCH handler {}", e),
+    }
 }
 
 pub(crate) fn __register_delete_handler<B>(ctx: &mut Ctx, delete_route_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let route = unbox_str(ctx, delete_route_ptr);
-    info!("register DELETE handler {:?}", route);
-    wc.register_delete_callback(route);
+    match unbox_str(ctx, delete_route_ptr) {
+        Ok(route) => {
+            info!("register DELETE handler {:?}", route);
+            wc.register_delete_callback(route);
+        }
+        Err(e) => error!("unable to register DELETE handler {}", e),
+    }
 }
 
 pub(crate) fn pong(_ctx: &mut Ctx) {
@@ -78,31 +139,35 @@ pub(crate) fn pong(_ctx: &mut Ctx) {
 
 pub(crate) fn __print<B>(ctx: &mut Ctx, str_ptr: u32)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let payload = unbox_str(ctx, str_ptr);
-    println!(
-        " {}  {} 🔎  {}",
-        "WASM".yellow(),
-        wc.name().cyan().underline(),
-        payload
-    );
+    match unbox_str(ctx, str_ptr) {
+        Ok(payload) => {
+            println!(
+                " {}  {} 🔎  {}",
+                "WASM".yellow(),
+                wc.name().cyan().underline(),
+                payload
+            );
+            wc.log(&payload);
+        }
+        Err(e) => error!("unable to print from {} {}", wc.name(), e),
+    }
 }
 
 pub(crate) fn __open_file<B>(ctx: &mut Ctx, id_ptr: u32) -> u64
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!("__open_file: id_ptr: {}", id_ptr);
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, id_ptr);
-    let id: Uuid = serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tid: {}", id);
-
-    let file = wc.open_file(id.into(), OpenFileMode::Read);
+    let file = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
+        wc.open_file(id.into(), OpenFileMode::Read)
+    });
 
     match file {
         Ok(handle) => handle,
@@ -113,19 +178,57 @@ where
     }
 }
 
+pub(crate) fn __open_path<B>(ctx: &mut Ctx, path_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__open_path: path_ptr: {}", path_ptr);
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_str(ctx, path_ptr).and_then(|path| {
+        debug!("\tpath: {}", path);
+
+        let (handle, id) = wc.open_path(&path)?;
+        debug!("opened {:?}, handle: {}, id: {}", path, handle, id);
+
+        return_json(
+            ctx,
+            &FileHandle {
+                handle,
+                id: id.into(),
+            },
+        )
+        .map_err(|e| {
+            wc.close_file(id, handle);
+            e
+        })
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to open path {}", e);
+            -1
+        }
+    }
+}
+
 pub(crate) fn __close_file<B>(ctx: &mut Ctx, id_ptr: u32, handle: u64)
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!("__close_file: id_ptr: {}, handle: {}", id_ptr, handle);
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, id_ptr);
-    let id: Uuid = serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tid: {}", id);
-
-    wc.close_file(id.into(), handle);
+    match unbox_id(ctx, id_ptr) {
+        Ok(id) => {
+            debug!("\tid: {}", id);
+            wc.close_file(id.into(), handle);
+        }
+        Err(e) => error!("unable to close file {}", e),
+    }
 }
 
 pub(crate) fn __read_file<B>(
@@ -137,7 +240,7 @@ pub(crate) fn __read_file<B>(
     data_len: u32,
 ) -> u32
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!(
@@ -146,32 +249,27 @@ where
     );
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, id_ptr);
-    let id: Uuid = serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tid: {}", id);
-
-    let file_size = {
-        let guard = wc.iofs.clone();
-        let guard = guard.lock().expect("poisoned iofs lock");
-        guard
-            .get_file_size(handle)
-            .expect("tried to read invalid file handle")
-    };
-    let read_len = std::cmp::min(data_len as u64, file_size - offset as u64);
-    let bytes = wc.read_file(id.into(), handle, offset as _, read_len as _);
-
-    match bytes {
-        Ok(bytes) => {
-            let memory = ctx.memory(0);
-            for (i, cell) in memory.view()[data_ptr as _..data_ptr as usize + bytes.len()]
-                .iter()
-                .enumerate()
-            {
-                cell.set(bytes[i]);
-            }
-            bytes.len() as _
+    let result = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
+
+        let file_size = {
+            let guard = wc.iofs.clone();
+            let guard = read_ufs(&guard);
+            guard.get_file_size(handle)?
+        };
+        let read_len = std::cmp::min(data_len as u64, file_size.saturating_sub(offset as u64));
+        let bytes = wc.read_file(id.into(), handle, offset as _, read_len as _)?;
+
+        write_memory(ctx, data_ptr, &bytes)?;
+        Ok(bytes.len())
+    });
+
+    match result {
+        Ok(len) => len as _,
+        Err(e) => {
+            error!("unable to read file {}", e);
+            0
         }
-        Err(_) => 0,
     }
 }
 
@@ -183,7 +281,7 @@ pub(crate) fn __write_file<B>(
     data_len: u32,
 ) -> u32
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!(
@@ -192,71 +290,56 @@ where
     );
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, id_ptr);
-    let id: Uuid = serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tid: {}", id);
+    let bytes_written = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
 
-    let memory = ctx.memory(0);
-    let bytes: Vec<u8> = memory.view()[data_ptr as usize..(data_ptr + data_len) as usize]
-        .iter()
-        .map(|cell| cell.get())
-        .collect();
-
-    let bytes_written = wc.write_file(id.into(), handle, &bytes);
+        let bytes = read_memory(ctx, data_ptr, data_len)?;
+        wc.write_file(id.into(), handle, &bytes)
+    });
     debug!("\twrote {:?} bytes", bytes_written);
 
     match bytes_written {
         Ok(i) => i as u32,
-        Err(_) => 0,
+        Err(e) => {
+            error!("unable to write file {}", e);
+            0
+        }
     }
 }
 
 pub(crate) fn __create_file<B>(ctx: &mut Ctx, parent_id_ptr: u32, name_ptr: u32) -> i32
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!("__create_file: name_ptr: {}", name_ptr);
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, parent_id_ptr);
-    let parent_id: Uuid =
-        serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tparent_id: {}", parent_id);
-
-    let name = unbox_str(ctx, name_ptr);
-    let file = wc.create_file(parent_id.into(), &name);
-
-    match file {
-        Ok((handle, file)) => {
-            debug!(
-                "created file {:?}, handle: {}, id: {}",
-                name, handle, file.file_id
-            );
-
-            // Pass the JSON result to Wasm-land by putting the string length at memory address 0,
-            // and then putting the string itself at memory address 8.
-            let json_str = serde_json::to_string(&FileHandle {
+    let result = unbox_id(ctx, parent_id_ptr).and_then(|parent_id| {
+        debug!("\tparent_id: {}", parent_id);
+
+        let name = unbox_str(ctx, name_ptr)?;
+        let (handle, file) = wc.create_file(parent_id.into(), &name)?;
+        debug!(
+            "created file {:?}, handle: {}, id: {}",
+            name, handle, file.file_id
+        );
+
+        return_json(
+            ctx,
+            &FileHandle {
                 handle,
                 id: file.file_id.into(),
-            })
-            .expect("unable to serialize JSON in __create_file");
-
-            let memory = ctx.memory(0);
-            let len = (json_str.len() as u64).to_le_bytes();
-            for (i, cell) in memory.view()[0..len.len()].iter().enumerate() {
-                cell.set(len[i]);
-            }
-
-            for (byte, cell) in json_str
-                .bytes()
-                .zip(memory.view()[8..8 + json_str.len()].iter())
-            {
-                cell.set(byte);
-            }
-
-            0
-        }
+            },
+        )
+        .map_err(|e| {
+            wc.close_file(file.file_id, handle);
+            e
+        })
+    });
+
+    match result {
+        Ok(_) => 0,
         Err(e) => {
             error!("unable to create file {}", e);
             -1
@@ -266,7 +349,7 @@ where
 
 pub(crate) fn __create_directory<B>(ctx: &mut Ctx, parent_id_ptr: u32, name_ptr: u32) -> i32
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!(
@@ -275,40 +358,57 @@ where
     );
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, parent_id_ptr);
-    let parent_id: Uuid =
-        serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tparent_id: {}", parent_id);
+    let result = unbox_id(ctx, parent_id_ptr).and_then(|parent_id| {
+        debug!("\tparent_id: {}", parent_id);
 
-    let name = unbox_str(ctx, name_ptr);
-    debug!("\tid: {}", parent_id);
+        let name = unbox_str(ctx, name_ptr)?;
+        let dir = wc.create_directory(parent_id.into(), &name)?;
+        debug!("created directory {:?} with id {}", name, dir.id());
 
-    let dir = wc.create_directory(parent_id.into(), &name);
+        return_json(ctx, &Uuid::from(dir.id()))
+    });
 
-    match dir {
-        Ok(dir) => {
-            debug!("created directory {:?} with id {}", name, dir.id());
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to create directory {}", e);
+            -1
+        }
+    }
+}
 
-            // Pass the JSON result to Wasm-land by putting the string length at memory address 0,
-            // and then putting the string itself at memory address 8.
-            let json_str = serde_json::to_string(&Uuid::from(dir.id()))
-                .expect("unable to serialize JSON in __create_directory");
+/// Copy a file, sharing its blocks
+///
+/// The ID of the copy is passed back using the same convention as `__open_directory`. Returns -1
+/// if the copy couldn't be made, or if this program may not make it.
+pub(crate) fn __copy_file<B>(ctx: &mut Ctx, id_ptr: u32, parent_id_ptr: u32, name_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!(
+        "__copy_file: id_ptr: {}, parent_id_ptr: {}, name_ptr: {}",
+        id_ptr, parent_id_ptr, name_ptr
+    );
 
-            let memory = ctx.memory(0);
-            let len = (json_str.len() as u64).to_le_bytes();
-            for (i, cell) in memory.view()[0..len.len()].iter().enumerate() {
-                cell.set(len[i]);
-            }
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_id(ctx, id_ptr).and_then(|id| {
+        let parent_id = unbox_id(ctx, parent_id_ptr)?;
+        debug!("\tid: {}, parent_id: {}", id, parent_id);
 
-            for (byte, cell) in json_str
-                .bytes()
-                .zip(memory.view()[8..8 + json_str.len()].iter())
-            {
-                cell.set(byte);
-            }
-            0
+        let name = unbox_str(ctx, name_ptr)?;
+        let file = wc.copy_file(id.into(), parent_id.into(), &name)?;
+        debug!("copied file {} to {:?}, id: {}", id, name, file.file_id);
+
+        return_json(ctx, &Uuid::from(file.file_id))
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to copy file {}", e);
+            -1
         }
-        Err(_) => -1,
     }
 }
 
@@ -322,7 +422,7 @@ where
 /// to the Wasm programs. Therefore, there's no need to route this through WasmProgram.
 pub(crate) fn __open_directory<B>(ctx: &mut Ctx, parent_id_ptr: u32, name_ptr: u32) -> i32
 where
-    B: BlockStorage + 'static,
+    B: BlockStorage + Sync + 'static,
 {
     debug!("--------");
     debug!(
@@ -331,99 +431,389 @@ where
     );
 
     let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
-    let id_json = unbox_str(ctx, parent_id_ptr);
-    let parent_id: Uuid =
-        serde_json::from_str(&id_json).expect("unable to deserialize id in __open_file");
-    debug!("\tparent_id: {}", parent_id);
+    let result = unbox_id(ctx, parent_id_ptr).and_then(|parent_id| {
+        debug!("\tparent_id: {}", parent_id);
+
+        let name = unbox_str(ctx, name_ptr)?;
+        let dir = wc.open_directory(parent_id.into(), &name)?;
+        debug!("found directory {:?} with id {}", name, dir);
 
-    let name = unbox_str(ctx, name_ptr);
+        return_json(ctx, &Uuid::from(dir))
+    });
 
-    debug!("\tid: {}", parent_id);
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            debug!("unable to open directory {}", e);
+            -1
+        }
+    }
+}
 
-    let dir = wc.open_directory(parent_id.into(), &name);
+/// List the entries of a directory
+///
+/// The entries are passed back as a JSON array of `(name, id, is_dir)` tuples, using the same
+/// convention as `__open_directory`.
+pub(crate) fn __read_dir<B>(ctx: &mut Ctx, id_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__read_dir: id_ptr: {}", id_ptr);
 
-    match dir {
-        Ok(dir) => {
-            debug!("found directory {:?} with id {}", name, dir);
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
 
-            // Pass the JSON result to Wasm-land by putting the string length at memory address 0,
-            // and then putting the string itself at memory address 8.
-            let json_str = serde_json::to_string(&Uuid::from(dir))
-                .expect("unable to serialize JSON in __create_directory");
+        let entries = wc.read_directory(id.into())?;
+        debug!("found {} entries in directory {}", entries.len(), id);
 
-            let memory = ctx.memory(0);
-            let len = (json_str.len() as u64).to_le_bytes();
-            for (i, cell) in memory.view()[0..len.len()].iter().enumerate() {
-                cell.set(len[i]);
-            }
+        let entries = entries
+            .into_iter()
+            .map(|(name, id, is_dir)| (name, Uuid::from(id), is_dir))
+            .collect::<Vec<_>>();
 
-            for (byte, cell) in json_str
-                .bytes()
-                .zip(memory.view()[8..8 + json_str.len()].iter())
-            {
-                cell.set(byte);
-            }
-            0
+        return_json(ctx, &entries)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to read directory {}", e);
+            -1
+        }
+    }
+}
+
+/// Fetch a file's metadata
+///
+/// The metadata is passed back as a JSON `FileStat`, placed in memory just as it is for
+/// `__read_dir`. Returns -1 if the file doesn't exist, or if this program may not
+/// read file metadata.
+pub(crate) fn __stat<B>(ctx: &mut Ctx, id_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__stat: id_ptr: {}", id_ptr);
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
+
+        let (size, perms, versions, times) = wc.stat(id.into())?;
+        let stat = FileStat {
+            size,
+            perms,
+            versions,
+            created: Timespec::from(times.birth).sec,
+            modified: Timespec::from(times.write).sec,
+        };
+
+        return_json(ctx, &stat)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to stat file {}", e);
+            -1
         }
-        Err(_) => -1,
     }
 }
 
-fn unbox_message(ctx: &Ctx, msg_ptr: u32) -> WasmMessage {
-    let memory = ctx.memory(0);
-    let ptr_vec: Vec<_> = memory.view()[msg_ptr as usize..(msg_ptr + 4) as usize]
-        .iter()
-        .map(|cell| cell.get())
-        .collect();
-    let ptr = u32::from_le_bytes(
-        ptr_vec
-            .as_slice()
-            .try_into()
-            .expect("error unboxing message"),
+/// Fetch file system statistics
+///
+/// The statistics are passed back as a JSON `FsStats`, placed in memory just as they are for
+/// `__stat`. No grant is needed, so this returns 0 unless they don't fit in the program's memory.
+pub(crate) fn __statfs<B>(ctx: &mut Ctx) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__statfs");
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let (block_count, free_blocks, block_size, open_files) = wc.statfs();
+    let stats = FsStats {
+        block_count,
+        free_blocks,
+        block_size: BlockSizeType::from(block_size),
+        open_files,
+    };
+
+    match return_json(ctx, &stats) {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to return file system statistics {}", e);
+            -1
+        }
+    }
+}
+
+/// Change the permissions of a file or directory
+///
+/// Returns 0 if the permissions were changed, and -1 if there's no such file or directory, or if
+/// this program may not change permissions.
+pub(crate) fn __chmod<B>(ctx: &mut Ctx, id_ptr: u32, mode: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__chmod: id_ptr: {}, mode: {:#05o}", id_ptr, mode);
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_id(ctx, id_ptr).and_then(|id| {
+        debug!("\tid: {}", id);
+        wc.chmod(id.into(), mode as u16)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to change permissions {}", e);
+            -1
+        }
+    }
+}
+
+/// Fetch a value that this program stored previously
+///
+/// The value is passed back as the string length at memory address 0, followed by the string at
+/// memory address 8. Returns -1 if there is no such value, or if this program may not use the
+/// store.
+pub(crate) fn __kv_get<B>(ctx: &mut Ctx, key_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__kv_get: key_ptr: {}", key_ptr);
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_str(ctx, key_ptr).and_then(|key| {
+        debug!("\tkey: {}", key);
+
+        wc.get_value(&key)?
+            .map(|value| return_bytes(ctx, value.as_bytes()))
+            .transpose()
+    });
+
+    match result {
+        Ok(Some(_)) => 0,
+        Ok(None) => -1,
+        Err(e) => {
+            error!("unable to get value {}", e);
+            -1
+        }
+    }
+}
+
+/// Store a value, to be fetched by this program the next time it runs
+///
+/// Returns 0 if the value was stored, and -1 if this program may not use the store.
+pub(crate) fn __kv_put<B>(ctx: &mut Ctx, key_ptr: u32, value_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!("__kv_put: key_ptr: {}, value_ptr: {}", key_ptr, value_ptr);
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_str(ctx, key_ptr).and_then(|key| {
+        let value = unbox_str(ctx, value_ptr)?;
+        debug!("\tkey: {}", key);
+
+        wc.put_value(key, value)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to put value {}", e);
+            -1
+        }
+    }
+}
+
+/// Send a message to another Wasm program
+///
+/// The message is handed to the `RuntimeManager`, which delivers it to the target program, if it's
+/// running. Returns 0 if the message was sent, and -1 if this program may not send messages, or if
+/// the target or payload aren't within its memory.
+pub(crate) fn __send_message<B>(
+    ctx: &mut Ctx,
+    target_ptr: u32,
+    payload_ptr: u32,
+    payload_len: u32,
+) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!(
+        "__send_message: target_ptr: {}, payload_ptr: {}, payload_len: {}",
+        target_ptr, payload_ptr, payload_len
     );
 
-    match ptr {
-        0 => WasmMessage::Shutdown,
-        1 => WasmMessage::Ping,
-        2 => WasmMessage::FileCreate,
-        3 => WasmMessage::DirCreate,
-        4 => WasmMessage::FileDelete,
-        5 => WasmMessage::DirDelete,
-        6 => WasmMessage::FileOpen,
-        7 => WasmMessage::FileClose,
-        8 => WasmMessage::FileRead,
-        9 => WasmMessage::FileWrite,
-        _ => panic!("Invalid value decoding WasmMessage"),
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_str(ctx, target_ptr).and_then(|target| {
+        debug!("\ttarget: {}", target);
+
+        let payload = read_memory(ctx, payload_ptr, payload_len)?;
+        wc.send_message(PathBuf::from(target), payload)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to send message {}", e);
+            -1
+        }
     }
 }
 
-fn unbox_str(ctx: &Ctx, str_ptr: u32) -> String {
+/// Publish an event to the Wasm programs that subscribe to it
+///
+/// The event is handed to the `RuntimeManager`, which delivers it to every other program that
+/// registered for the name. Returns 0 if the event was published, and -1 if this program may not
+/// send messages.
+pub(crate) fn __publish_event<B>(ctx: &mut Ctx, name_ptr: u32, payload_ptr: u32) -> i32
+where
+    B: BlockStorage + Sync + 'static,
+{
+    debug!("--------");
+    debug!(
+        "__publish_event: name_ptr: {}, payload_ptr: {}",
+        name_ptr, payload_ptr
+    );
+
+    let wc: &mut WasmProcess<B> = unsafe { &mut *(ctx.data as *mut WasmProcess<B>) };
+    let result = unbox_str(ctx, name_ptr).and_then(|name| {
+        debug!("\tname: {}", name);
+
+        let payload = unbox_bytes(ctx, payload_ptr)?;
+        wc.publish_event(name, payload)
+    });
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            error!("unable to publish event {}", e);
+            -1
+        }
+    }
+}
+
+fn unbox_message(ctx: &Ctx, msg_ptr: u32) -> Result<WasmMessage, failure::Error> {
+    match read_u32(ctx, msg_ptr)? {
+        0 => Ok(WasmMessage::Shutdown),
+        1 => Ok(WasmMessage::Ping),
+        2 => Ok(WasmMessage::FileCreate),
+        3 => Ok(WasmMessage::DirCreate),
+        4 => Ok(WasmMessage::FileDelete),
+        5 => Ok(WasmMessage::DirDelete),
+        6 => Ok(WasmMessage::FileOpen),
+        7 => Ok(WasmMessage::FileClose),
+        8 => Ok(WasmMessage::FileRead),
+        9 => Ok(WasmMessage::FileWrite),
+        10 => Ok(WasmMessage::FileRename),
+        11 => Ok(WasmMessage::DirRename),
+        12 => Ok(WasmMessage::ProgramMessage),
+        n => {
+            error!("Invalid value decoding WasmMessage: {}", n);
+            Err(RuntimeErrorKind::IofsInvocation.into())
+        }
+    }
+}
+
+/// Read an ID, passed as a JSON string
+///
+fn unbox_id(ctx: &Ctx, id_ptr: u32) -> Result<Uuid, failure::Error> {
+    let id_json = unbox_str(ctx, id_ptr)?;
+    Ok(serde_json::from_str(&id_json)?)
+}
+
+fn unbox_str(ctx: &Ctx, str_ptr: u32) -> Result<String, failure::Error> {
     debug!("unbox_str: str_ptr {}", str_ptr);
-    let memory = ctx.memory(0);
-
-    // The &str is stored as a u32 pointer, followed by a length. We first extract the pointer
-    // from memory.
-    let ptr_vec: Vec<u8> = memory.view()[str_ptr as usize..(str_ptr + 4) as usize]
-        .iter()
-        .map(|cell| cell.get())
-        .collect();
-    let ptr = u32::from_le_bytes(ptr_vec.as_slice().try_into().unwrap());
-    debug!("\tptr: {}", ptr);
+    let bytes = unbox_bytes(ctx, str_ptr)?;
+
+    // The bytes had better be UTF-8, and if they aren't it's the program's mistake, not ours.
+    Ok(String::from_utf8(bytes)?)
+}
 
-    // And then we extract the length.
-    let len_vec: Vec<u8> = memory.view()[(str_ptr + 4) as usize..(str_ptr + 8) as usize]
-        .iter()
-        .map(|cell| cell.get())
-        .collect();
-    let len = u32::from_le_bytes(len_vec.as_slice().try_into().unwrap());
+fn unbox_bytes(ctx: &Ctx, str_ptr: u32) -> Result<Vec<u8>, failure::Error> {
+    // The &str, or &[u8], is stored as a u32 pointer, followed by a length.
+    let header = read_memory(ctx, str_ptr, 8)?;
+    let ptr = u32::from_le_bytes(header[0..4].try_into()?);
+    debug!("\tptr: {}", ptr);
+    let len = u32::from_le_bytes(header[4..8].try_into()?);
     debug!("\tlen: {}", len);
 
     // Now we dereference the pointer, and read len bytes.
-    let bytes: Vec<u8> = memory.view()[ptr as usize..(ptr + len) as usize]
-        .iter()
-        .map(|cell| cell.get())
-        .collect();
+    read_memory(ctx, ptr, len)
+}
 
-    // And finally turn it into a String.
-    str::from_utf8(&bytes).unwrap().to_owned()
+/// Read a little-endian u32 from the program's memory
+///
+fn read_u32(ctx: &Ctx, ptr: u32) -> Result<u32, failure::Error> {
+    let bytes = read_memory(ctx, ptr, 4)?;
+    Ok(u32::from_le_bytes(bytes.as_slice().try_into()?))
+}
+
+/// Copy `len` bytes out of the program's memory, starting at `ptr`
+///
+/// It's an error, rather than a panic, for any of them to be outside of the memory.
+fn read_memory(ctx: &Ctx, ptr: u32, len: u32) -> Result<Vec<u8>, failure::Error> {
+    let view = ctx.memory(0).view::<u8>();
+    let start = ptr as usize;
+
+    match start.checked_add(len as usize) {
+        Some(end) if end <= view.len() => {
+            Ok(view[start..end].iter().map(|cell| cell.get()).collect())
+        }
+        _ => {
+            error!("{} bytes at {} is outside of WASM memory", len, ptr);
+            Err(RuntimeErrorKind::MemoryAccess.into())
+        }
+    }
+}
+
+/// Pass a result back to Wasm-land
+///
+/// The length goes at memory address 0, as a u64, and the bytes themselves at memory address 8.
+/// It's an error for the result to run past `FIXED_MESSAGE_LIMIT`, or out of the program's memory.
+fn return_bytes(ctx: &Ctx, bytes: &[u8]) -> Result<(), failure::Error> {
+    if 8 + bytes.len() > FIXED_MESSAGE_LIMIT {
+        error!("{} byte result is too large to return", bytes.len());
+        return Err(RuntimeErrorKind::MemoryAccess.into());
+    }
+
+    write_memory(ctx, 0, &(bytes.len() as u64).to_le_bytes())?;
+    write_memory(ctx, 8, bytes)
+}
+
+/// Pass a result back to Wasm-land, serialized as JSON
+///
+fn return_json<T: Serialize>(ctx: &Ctx, value: &T) -> Result<(), failure::Error> {
+    return_bytes(ctx, serde_json::to_string(value)?.as_bytes())
+}
+
+/// Copy `bytes` into the program's memory, starting at `ptr`
+///
+fn write_memory(ctx: &Ctx, ptr: u32, bytes: &[u8]) -> Result<(), failure::Error> {
+    let view = ctx.memory(0).view::<u8>();
+    let start = ptr as usize;
+
+    match start.checked_add(bytes.len()) {
+        Some(end) if end <= view.len() => {
+            for (byte, cell) in bytes.iter().zip(view[start..end].iter()) {
+                cell.set(*byte);
+            }
+            Ok(())
+        }
+        _ => {
+            error!("{} bytes at {} is outside of WASM memory", bytes.len(), ptr);
+            Err(RuntimeErrorKind::MemoryAccess.into())
+        }
+    }
 }
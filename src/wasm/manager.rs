@@ -3,21 +3,27 @@
 use {
     crate::{
         block::BlockStorage,
-        metadata::{Grant, GrantType},
+        event::EventSink,
+        fsimpl::{read_ufs, write_ufs},
+        metadata::{Grant, GrantType, Metadata},
         server::IofsNetworkMessage,
         wasm::{
-            IofsDirMessage, IofsFileMessage, IofsMessage, IofsSystemMessage, WasmProcess,
-            WasmProcessMessage,
+            IofsDirMessage, IofsFileMessage, IofsMessage, IofsMessagePayload, IofsSystemMessage,
+            WasmProcess, WasmProcessMessage,
         },
-        UberFileSystem,
+        UberFileSystem, UfsUuid,
     },
     crossbeam::{crossbeam_channel, RecvError, Select},
-    log::{error, info},
+    log::{debug, error, info, warn},
     std::{
         collections::{HashMap, HashSet},
-        path::PathBuf,
-        sync::{Arc, Mutex},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, RwLock,
+        },
         thread::{spawn, JoinHandle},
+        time::{Duration, Instant},
     },
     wasm_exports::WasmMessage,
 };
@@ -39,60 +45,91 @@ pub(crate) enum RuntimeManagerMsg {
     ///
     /// There is a running program that must needs be stopped.
     Stop(PathBuf),
-    /// Send a message to running WASM programs
+    /// Register an EventSink
     ///
-    IofsMessage(IofsMessage),
+    /// Subsequent file system events are forwarded to the sink, in addition to any WASM programs.
+    AddEventSink(Box<dyn EventSink>),
 }
 
 /// Information necessary to start running a WASM program
 ///
 /// This is the contents of the RuntimeManagerMsg::Start message.
+#[derive(Clone)]
 pub(crate) struct ProtoWasmProgram {
     /// A unique identifier for the WASM program -- it's the path, and there can be only one.
     pub(in crate::wasm) name: PathBuf,
     /// The bytes that comprise the program.
     pub(in crate::wasm) program: Vec<u8>,
+    /// Optional configuration, passed to the program's `__init_with_config` function.
+    pub(in crate::wasm) config: Option<String>,
 }
 
 impl ProtoWasmProgram {
-    pub(crate) fn new(name: PathBuf, program: Vec<u8>) -> Self {
-        ProtoWasmProgram { name, program }
+    pub(crate) fn new(name: PathBuf, program: Vec<u8>, config: Option<String>) -> Self {
+        ProtoWasmProgram {
+            name,
+            program,
+            config,
+        }
+    }
+
+    /// The path of the program
+    ///
+    #[cfg(test)]
+    pub(crate) fn name(&self) -> &Path {
+        &self.name
     }
 }
 
-struct RuntimeProcess<B: BlockStorage> {
+struct RuntimeProcess<B: BlockStorage + Sync> {
     path: PathBuf,
-    iofs: Arc<Mutex<UberFileSystem<B>>>,
+    iofs: Arc<RwLock<UberFileSystem<B>>>,
     sender: crossbeam_channel::Sender<WasmProcessMessage>,
     handle: JoinHandle<Result<(), failure::Error>>,
     handled_messages: HashSet<WasmMessage>,
+    /// The directories beneath which the program wishes to receive events, by message
+    scopes: HashMap<WasmMessage, UfsUuid>,
+    /// The names of the events, published by other programs, that the program subscribes to
+    events: HashSet<String>,
     receiver: crossbeam_channel::Receiver<IofsEventRegistration>,
+    /// The program, kept so that it may be restarted should it crash
+    program: ProtoWasmProgram,
+    /// When the program was started
+    started: Instant,
+    /// The number of consecutive restarts that led to this one
+    attempts: u32,
 }
 
-impl<B: BlockStorage> RuntimeProcess<B> {
+impl<B: BlockStorage + Sync> RuntimeProcess<B> {
     fn new(
-        path: PathBuf,
-        iofs: Arc<Mutex<UberFileSystem<B>>>,
+        program: ProtoWasmProgram,
+        attempts: u32,
+        iofs: Arc<RwLock<UberFileSystem<B>>>,
         process: WasmProcess<B>,
         receiver: crossbeam_channel::Receiver<IofsEventRegistration>,
     ) -> Self {
         RuntimeProcess {
-            path,
+            path: program.name.clone(),
             iofs,
             sender: process.get_sender(),
             handle: WasmProcess::start(process),
             handled_messages: HashSet::new(),
+            scopes: HashMap::new(),
+            events: HashSet::new(),
             receiver,
+            program,
+            started: Instant::now(),
+            attempts,
         }
     }
 
     fn does_handle_message(&self, iofs_msg: &IofsMessage) -> bool {
         let guard = self.iofs.clone();
-        let mut guard = guard.lock().expect("poisoned iofs lock");
+        let mut guard = write_ufs(&guard);
 
         // The following code first checks to see if the wasm program is interested in the event. If
         // so, it then checks that there is a grant to allow the wasm program to receive the eevent.
-        match iofs_msg {
+        let handles = match iofs_msg {
             // Always dispatch the shutdown in order for the wasm program to be terminated.
             IofsMessage::SystemMessage(IofsSystemMessage::Shutdown) => true,
             IofsMessage::SystemMessage(IofsSystemMessage::Ping)
@@ -196,28 +233,100 @@ impl<B: BlockStorage> RuntimeProcess<B> {
                     _ => false,
                 }
             }
+            IofsMessage::FileMessage(IofsFileMessage::Rename(_))
+                if self.handled_messages.contains(&WasmMessage::FileRename) =>
+            {
+                match guard
+                    .block_manager_mut()
+                    .metadata_mut()
+                    .check_wasm_program_grant(&self.path, GrantType::FileRenameEvent)
+                {
+                    Some(Grant::Allow) => true,
+                    _ => false,
+                }
+            }
+            IofsMessage::DirMessage(IofsDirMessage::Rename(_))
+                if self.handled_messages.contains(&WasmMessage::DirRename) =>
+            {
+                match guard
+                    .block_manager_mut()
+                    .metadata_mut()
+                    .check_wasm_program_grant(&self.path, GrantType::DirRenameEvent)
+                {
+                    Some(Grant::Allow) => true,
+                    _ => false,
+                }
+            }
             _ => false,
+        };
+
+        handles && self.in_scope(iofs_msg, guard.block_manager().metadata())
+    }
+
+    /// Does the event fall within the directory to which the program scoped its registration?
+    ///
+    /// An event is in scope if it happens to the directory itself, or anywhere beneath it. Events
+    /// for which the program didn't specify a directory are always in scope.
+    fn in_scope(&self, iofs_msg: &IofsMessage, metadata: &Metadata) -> bool {
+        let (event, payload) = match iofs_msg {
+            IofsMessage::FileMessage(m) => match m {
+                IofsFileMessage::Create(p) => (WasmMessage::FileCreate, p),
+                IofsFileMessage::Delete(p) => (WasmMessage::FileDelete, p),
+                IofsFileMessage::Open(p) => (WasmMessage::FileOpen, p),
+                IofsFileMessage::Close(p) => (WasmMessage::FileClose, p),
+                IofsFileMessage::Read(p) => (WasmMessage::FileRead, p),
+                IofsFileMessage::Write(p) => (WasmMessage::FileWrite, p),
+                IofsFileMessage::Rename(p) => (WasmMessage::FileRename, p),
+            },
+            IofsMessage::DirMessage(m) => match m {
+                IofsDirMessage::Create(p) => (WasmMessage::DirCreate, p),
+                IofsDirMessage::Delete(p) => (WasmMessage::DirDelete, p),
+                IofsDirMessage::Rename(p) => (WasmMessage::DirRename, p),
+            },
+            IofsMessage::SystemMessage(_) => return true,
+        };
+
+        match self.scopes.get(&event) {
+            Some(dir_id) => is_beneath(*dir_id, payload, metadata),
+            None => true,
         }
     }
 
-    fn register_for_event(&mut self, event: WasmMessage) {
+    /// Register for an event, optionally scoped to a directory
+    ///
+    /// Registering again replaces the previous scope.
+    fn register_for_event(&mut self, event: WasmMessage, scope: Option<UfsUuid>) {
+        match scope {
+            Some(dir_id) => self.scopes.insert(event.clone(), dir_id),
+            None => self.scopes.remove(&event),
+        };
         self.handled_messages.insert(event);
     }
 
     fn unregister_for_event(&mut self, event: WasmMessage) {
+        self.scopes.remove(&event);
         self.handled_messages.remove(&event);
     }
 }
 
 #[derive(Debug)]
 pub(crate) enum IofsEventRegistration {
-    Register(WasmMessage),
+    /// Register for an event, optionally only for those beneath a directory
+    Register(WasmMessage, Option<UfsUuid>),
     UnRegister(WasmMessage),
     RegisterHttpGet(String),
     RegisterHttpPost(String),
     RegisterHttpPut(String),
     RegisterHttpPatch(String),
     RegisterHttpDelete(String),
+    /// Send a message to another program: the target's path, and the payload
+    SendMessage(PathBuf, Vec<u8>),
+    /// Subscribe to events, published by other programs, with the name
+    RegisterEvent(String),
+    /// Publish an event to the subscribed programs: the name, and the payload
+    PublishEvent(String, Vec<u8>),
+    /// The program exceeded its execution limit, and has stopped running
+    Terminated,
 }
 
 #[derive(Debug, Eq, Hash, PartialEq)]
@@ -229,6 +338,167 @@ enum HttpEndPoint {
     DELETE(String),
 }
 
+/// Is the subject of the event the directory, or something beneath it?
+///
+fn is_beneath(dir_id: UfsUuid, payload: &IofsMessagePayload, metadata: &Metadata) -> bool {
+    payload.target_id == dir_id
+        || payload.parent_id == dir_id
+        || (metadata.lookup_dir(dir_id).is_some()
+            && payload
+                .target_path
+                .starts_with(metadata.path_from_dir_id(dir_id)))
+}
+
+/// Restart bookkeeping for a WASM program that crashed
+///
+/// This is kept by the `UberFileSystem`, so that it may be reported on the dashboard.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RestartStatus {
+    /// The number of times that the program has been restarted
+    pub(crate) restarts: u32,
+    /// The program failed on every restart attempt, and has been given up on
+    pub(crate) failed: bool,
+}
+
+/// A crashed program, waiting out its backoff before being restarted
+///
+struct PendingRestart {
+    program: ProtoWasmProgram,
+    attempts: u32,
+    due: Instant,
+}
+
+/// The delay before the first restart of a crashed program
+///
+/// Each subsequent attempt waits twice as long as the one before it.
+const RESTART_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest that we'll wait before restarting a crashed program
+///
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a program must run before a crash no longer counts as a failed restart
+///
+const RESTART_RESET_PERIOD: Duration = Duration::from_secs(300);
+
+/// The default window within which rapid read and write events are coalesced
+///
+pub(crate) const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CoalescedEvent {
+    Read,
+    Write,
+}
+
+/// Reads, or writes, of a file in quick succession
+///
+struct CoalescedRun {
+    event: CoalescedEvent,
+    /// When the window closes
+    due: Instant,
+    /// The latest event in the run, which is delivered when the window closes
+    pending: Option<IofsMessage>,
+}
+
+/// File event coalescing
+///
+/// A program writing in a tight loop generates a `Write` event for every call to `write_file`.
+/// Rather than flood the listeners with what amounts to the same notification, we forward the
+/// first `Read` or `Write` for a given file, and hold back the rest until the window has elapsed.
+/// The last one held back is then delivered, so that listeners always see the final state.
+///
+/// Any other event on the file breaks the run, so a `Close` following a bunch of writes is
+/// delivered after the last of the writes, and the next write starts afresh. `Create`, `Delete`,
+/// `Open`, `Close`, and `Rename` are never coalesced.
+struct EventCoalescer {
+    window: Duration,
+    runs: HashMap<UfsUuid, CoalescedRun>,
+}
+
+impl EventCoalescer {
+    fn new(window: Duration) -> Self {
+        EventCoalescer {
+            window,
+            runs: HashMap::new(),
+        }
+    }
+
+    /// Take an event, returning those that are to be delivered now
+    ///
+    fn coalesce(&mut self, msg: IofsMessage, now: Instant) -> Vec<IofsMessage> {
+        let (event, id) = match &msg {
+            IofsMessage::FileMessage(IofsFileMessage::Write(payload)) => {
+                (Some(CoalescedEvent::Write), payload.target_id)
+            }
+            IofsMessage::FileMessage(IofsFileMessage::Read(payload)) => {
+                (Some(CoalescedEvent::Read), payload.target_id)
+            }
+            IofsMessage::FileMessage(msg) => (None, msg.payload().target_id),
+            _ => return vec![msg],
+        };
+
+        if let Some(run) = self.runs.get_mut(&id) {
+            if Some(run.event) == event && now < run.due {
+                run.pending = Some(msg);
+                return vec![];
+            }
+        }
+
+        // Whatever the run held back goes before the event that ended it.
+        let mut deliver = self
+            .runs
+            .remove(&id)
+            .and_then(|run| run.pending)
+            .into_iter()
+            .collect::<Vec<_>>();
+        if let Some(event) = event {
+            if self.window != Duration::from_secs(0) {
+                self.runs.insert(
+                    id,
+                    CoalescedRun {
+                        event,
+                        due: now + self.window,
+                        pending: None,
+                    },
+                );
+            }
+        }
+        deliver.push(msg);
+        deliver
+    }
+
+    /// When the next window closes
+    ///
+    fn next_due(&self) -> Option<Instant> {
+        self.runs.values().map(|run| run.due).min()
+    }
+
+    /// End the runs whose windows have closed, returning the events that they held back
+    ///
+    fn due(&mut self, now: Instant) -> Vec<IofsMessage> {
+        let closed = self
+            .runs
+            .iter()
+            .filter(|(_, run)| run.due <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        closed
+            .into_iter()
+            .filter_map(|id| self.runs.remove(&id).and_then(|run| run.pending))
+            .collect()
+    }
+
+    /// End every run, returning the events that they held back
+    ///
+    fn drain(&mut self) -> Vec<IofsMessage> {
+        self.runs
+            .drain()
+            .filter_map(|(_, run)| run.pending)
+            .collect()
+    }
+}
+
 /// WASM Thread Management
 ///
 /// This struct is the interface between the `IOFileSystem` and Wasm programs running inside of the
@@ -237,30 +507,53 @@ enum HttpEndPoint {
 ///
 /// The `UfsMounter` will also send a shutdown message, on the same channel, when the file system is
 /// going away. Here, we use that message to nicely stop the WASM programs before exiting.
-pub(crate) struct RuntimeManager<B: BlockStorage + 'static> {
-    ufs: Arc<Mutex<UberFileSystem<B>>>,
+///
+/// HTTP requests are handed off to the target program's channel, and the manager never waits on
+/// the response. Requests for different programs are therefore serviced concurrently, while each
+/// program works through its own requests one at a time. A WASM instance is single threaded and
+/// carries state between calls, so running several instances of one program is not supported.
+pub(crate) struct RuntimeManager<B: BlockStorage + Sync + 'static> {
+    ufs: Arc<RwLock<UberFileSystem<B>>>,
     http_receiver: Option<crossbeam_channel::Receiver<IofsNetworkMessage>>,
     receiver: crossbeam_channel::Receiver<RuntimeManagerMsg>,
+    events: crossbeam_channel::Receiver<IofsMessage>,
     http_endpoints: HashMap<HttpEndPoint, usize>,
     threads_table: HashMap<PathBuf, usize>,
     threads: Vec<RuntimeProcess<B>>,
+    running: Arc<AtomicUsize>,
+    restarts: HashMap<PathBuf, PendingRestart>,
+    coalescer: EventCoalescer,
+    sinks: Vec<Box<dyn EventSink>>,
 }
 
-impl<B: BlockStorage> RuntimeManager<B> {
+impl<B: BlockStorage + Sync> RuntimeManager<B> {
     pub(crate) fn new(
-        ufs: Arc<Mutex<UberFileSystem<B>>>,
+        ufs: Arc<RwLock<UberFileSystem<B>>>,
         receiver: crossbeam_channel::Receiver<RuntimeManagerMsg>,
+        events: crossbeam_channel::Receiver<IofsMessage>,
     ) -> Self {
         RuntimeManager {
             ufs,
             http_receiver: None,
             receiver,
+            events,
             http_endpoints: HashMap::new(),
             threads_table: HashMap::new(),
             threads: Vec::new(),
+            running: Arc::new(AtomicUsize::new(0)),
+            restarts: HashMap::new(),
+            coalescer: EventCoalescer::new(DEFAULT_COALESCE_WINDOW),
+            sinks: Vec::new(),
         }
     }
 
+    /// Set the window within which consecutive read and write events are coalesced
+    ///
+    /// A zero duration disables coalescing altogether.
+    pub(crate) fn set_coalesce_window(&mut self, window: Duration) {
+        self.coalescer.window = window;
+    }
+
     pub(crate) fn set_http_receiver(
         &mut self,
         sender: crossbeam_channel::Receiver<IofsNetworkMessage>,
@@ -268,7 +561,38 @@ impl<B: BlockStorage> RuntimeManager<B> {
         self.http_receiver.replace(sender);
     }
 
+    pub(crate) fn add_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// The number of running programs
+    ///
+    /// This is kept up to date as programs come and go, for the HTTP server's metrics.
+    pub(crate) fn program_count(&self) -> Arc<AtomicUsize> {
+        self.running.clone()
+    }
+
     fn notify_listeners(&mut self, msg: IofsMessage) {
+        for msg in self.coalescer.coalesce(msg, Instant::now()) {
+            self.deliver(msg);
+        }
+    }
+
+    /// Deliver the events held back by the coalescer, whose windows have closed
+    ///
+    fn deliver_coalesced(&mut self) {
+        for msg in self.coalescer.due(Instant::now()) {
+            self.deliver(msg);
+        }
+    }
+
+    /// Send an event to the sinks, and to the programs that have asked for it
+    ///
+    fn deliver(&mut self, msg: IofsMessage) {
+        for sink in &mut self.sinks {
+            sink.emit(&msg);
+        }
+
         let mut dead_programs = vec![];
         for (id, idx) in &self.threads_table {
             let listener = &self.threads[*idx];
@@ -287,8 +611,184 @@ impl<B: BlockStorage> RuntimeManager<B> {
         }
 
         for id in dead_programs {
-            let idx = self.threads_table.remove(&id).unwrap();
-            self.threads.remove(idx);
+            if let Some(idx) = self.threads_table.get(&id).cloned() {
+                self.program_exited(idx);
+            }
+        }
+    }
+
+    /// Forget about a program
+    ///
+    /// The program's HTTP endpoints go with it, and the indices of the programs following it are
+    /// adjusted to account for it's absence.
+    fn remove_program(&mut self, idx: usize) -> RuntimeProcess<B> {
+        let thread = self.threads.remove(idx);
+        self.running.store(self.threads.len(), Ordering::Relaxed);
+        self.threads_table.remove(&thread.path);
+        self.http_endpoints.retain(|_, i| *i != idx);
+
+        for i in self
+            .threads_table
+            .values_mut()
+            .chain(self.http_endpoints.values_mut())
+        {
+            if *i > idx {
+                *i -= 1;
+            }
+        }
+
+        thread
+    }
+
+    /// Start running a WASM program
+    ///
+    /// `attempts` is the number of consecutive restarts that led to this one.
+    fn start_program(&mut self, wasm: ProtoWasmProgram, attempts: u32) {
+        let (sender, receiver) = crossbeam_channel::unbounded::<IofsEventRegistration>();
+        let execution_limit = {
+            let guard = read_ufs(&self.ufs);
+            guard.wasm_execution_limit()
+        };
+        let process = WasmProcess::new(
+            wasm.name.clone(),
+            wasm.program.clone(),
+            wasm.config.clone(),
+            sender,
+            self.ufs.clone(),
+            execution_limit,
+        );
+        self.threads_table
+            .insert(wasm.name.clone(), self.threads.len());
+        self.threads.push(RuntimeProcess::new(
+            wasm,
+            attempts,
+            self.ufs.clone(),
+            process,
+            receiver,
+        ));
+        self.running.store(self.threads.len(), Ordering::Relaxed);
+    }
+
+    /// Forget everything we know about a program's restarts
+    ///
+    fn clear_restarts(&mut self, path: &Path) {
+        self.restarts.remove(path);
+        let mut guard = write_ufs(&self.ufs);
+        guard.wasm_restarts_mut().remove(path);
+    }
+
+    /// Deal with a program that stopped running without being told to
+    ///
+    /// If restarts are enabled, the program is restarted after a delay that doubles with each
+    /// consecutive attempt. A program that crashes after the last attempt is marked as failed, and
+    /// is left alone until it's started anew.
+    fn program_exited(&mut self, idx: usize) {
+        let thread = self.remove_program(idx);
+        match thread.handle.join() {
+            Ok(Ok(())) => warn!("WASM program {:?} exited unexpectedly", thread.path),
+            Ok(Err(e)) => error!("WASM program {:?} failed: {}", thread.path, e),
+            Err(_) => error!("WASM program {:?} panicked", thread.path),
+        }
+
+        let mut guard = write_ufs(&self.ufs);
+        let limit = guard.wasm_restart_limit();
+        if limit == 0 {
+            return;
+        }
+
+        // A program that ran for a good while before crashing starts over with a full set of
+        // attempts.
+        let attempts = if thread.started.elapsed() < RESTART_RESET_PERIOD {
+            thread.attempts
+        } else {
+            0
+        };
+
+        let status = guard
+            .wasm_restarts_mut()
+            .entry(thread.path.clone())
+            .or_insert_with(RestartStatus::default);
+
+        if attempts >= limit {
+            error!(
+                "WASM program {:?} failed after {} restart attempts, giving up",
+                thread.path, attempts
+            );
+            status.failed = true;
+        } else {
+            let delay = (RESTART_BACKOFF * 2u32.saturating_pow(attempts)).min(MAX_RESTART_BACKOFF);
+            info!(
+                "Restarting WASM program {:?} in {:?}, attempt {} of {}",
+                thread.path,
+                delay,
+                attempts + 1,
+                limit
+            );
+            self.restarts.insert(
+                thread.path,
+                PendingRestart {
+                    program: thread.program,
+                    attempts: attempts + 1,
+                    due: Instant::now() + delay,
+                },
+            );
+        }
+    }
+
+    /// Deliver a message from one program to another
+    ///
+    /// The grant to send was checked by the sender. The message is dropped if the target isn't
+    /// running, or hasn't registered a message handler.
+    fn send_program_message(&self, from: usize, target: PathBuf, payload: Vec<u8>) {
+        let from = self.threads[from].path.clone();
+        match self.threads_table.get(&target) {
+            Some(idx) => {
+                let listener = &self.threads[*idx];
+                if listener
+                    .handled_messages
+                    .contains(&WasmMessage::ProgramMessage)
+                {
+                    if let Err(e) = listener
+                        .sender
+                        .send(WasmProcessMessage::ProgramMessage(from, payload))
+                    {
+                        error!("unable to send on channel {}", e);
+                    }
+                } else {
+                    debug!("{:?} does not handle messages from {:?}", target, from);
+                }
+            }
+            None => error!("{:?} sent a message to unknown program {:?}", from, target),
+        }
+    }
+
+    /// Deliver an event published by a program to every program that subscribes to it
+    ///
+    /// The grant to publish was checked by the publisher. A program isn't sent the events that it
+    /// publishes itself, so that it may not feed itself in a loop. The event is dropped if nobody
+    /// subscribes to it.
+    fn publish_event(&mut self, from: usize, name: String, payload: Vec<u8>) {
+        let from = self.threads[from].path.clone();
+
+        let mut dead_programs = vec![];
+        for (id, idx) in &self.threads_table {
+            let listener = &self.threads[*idx];
+            if *id != from && listener.events.contains(&name) {
+                debug!("{:?} dispatching event {} to {:?}", from, name, id);
+                if let Err(e) = listener.sender.send(WasmProcessMessage::ProgramEvent(
+                    name.clone(),
+                    payload.clone(),
+                )) {
+                    error!("unable to send on channel {}", e);
+                    dead_programs.push(id.clone());
+                }
+            }
+        }
+
+        for id in dead_programs {
+            if let Some(idx) = self.threads_table.get(&id).cloned() {
+                self.program_exited(idx);
+            }
         }
     }
 
@@ -305,14 +805,25 @@ impl<B: BlockStorage> RuntimeManager<B> {
                         // Shutdown comes from the UfsMounter, thus we need to shutdown the running
                         // programs (via our UberFileSystem reference) before joining the threads,
                         // see below.
-                        RuntimeManagerMsg::Shutdown => break,
-                        // Forward an IofsMessage to listeners
-                        RuntimeManagerMsg::IofsMessage(msg) => runtime.notify_listeners(msg),
+                        // Events that were queued before the shutdown are delivered first.
+                        RuntimeManagerMsg::Shutdown => {
+                            let pending = runtime.events.try_iter().collect::<Vec<_>>();
+                            for msg in pending {
+                                runtime.notify_listeners(msg);
+                            }
+                            for msg in runtime.coalescer.drain() {
+                                runtime.deliver(msg);
+                            }
+                            break;
+                        }
+                        // Forward subsequent IofsMessages to the sink as well.
+                        RuntimeManagerMsg::AddEventSink(sink) => runtime.sinks.push(sink),
                         // Stop the WASM program and remove it from the listeners map.
                         RuntimeManagerMsg::Stop(name) => {
                             info!("Stopping WASM program {:?}", name);
-                            if let Some(thread_idx) = runtime.threads_table.remove(&name) {
-                                let thread = runtime.threads.remove(thread_idx);
+                            runtime.clear_restarts(&name);
+                            if let Some(thread_idx) = runtime.threads_table.get(&name).cloned() {
+                                let thread = runtime.remove_program(thread_idx);
                                 thread
                                     .sender
                                     .send(WasmProcessMessage::IofsEvent(
@@ -329,32 +840,20 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                     .expect("error during WasmProcess execution");
                             }
                         }
-                        // Start the WASM program and add it to the listeners map.
+                        // Start the WASM program and add it to the listeners map. A new program
+                        // supersedes any pending restart, and starts with a clean slate.
                         RuntimeManagerMsg::Start(wasm) => {
                             info!("Starting WASM program {:?}", wasm.name);
-                            let (sender, receiver) =
-                                crossbeam_channel::unbounded::<IofsEventRegistration>();
-                            let process = WasmProcess::new(
-                                wasm.name.clone(),
-                                wasm.program,
-                                sender,
-                                runtime.ufs.clone(),
-                            );
-                            runtime
-                                .threads_table
-                                .insert(wasm.name.clone(), runtime.threads.len());
-                            runtime.threads.push(RuntimeProcess::new(
-                                wasm.name,
-                                runtime.ufs.clone(),
-                                process,
-                                receiver,
-                            ));
+                            runtime.clear_restarts(&wasm.name);
+                            runtime.start_program(wasm, 0);
                         }
                     },
+                    // Forward an IofsMessage to listeners
+                    RuntimeMessage::Event(msg) => runtime.notify_listeners(msg),
                     RuntimeMessage::Registration((index, msg)) => {
                         match msg {
-                            IofsEventRegistration::Register(m) => {
-                                runtime.threads[index].register_for_event(m)
+                            IofsEventRegistration::Register(m, scope) => {
+                                runtime.threads[index].register_for_event(m, scope)
                             }
                             IofsEventRegistration::UnRegister(m) => {
                                 runtime.threads[index].unregister_for_event(m)
@@ -389,11 +888,46 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                     .entry(HttpEndPoint::DELETE(r))
                                     .or_insert(index);
                             }
+                            IofsEventRegistration::SendMessage(target, payload) => {
+                                runtime.send_program_message(index, target, payload)
+                            }
+                            IofsEventRegistration::RegisterEvent(name) => {
+                                runtime.threads[index].events.insert(name);
+                            }
+                            IofsEventRegistration::PublishEvent(name, payload) => {
+                                runtime.publish_event(index, name, payload)
+                            }
+                            // The thread has already exited, so joining is immediate.
+                            IofsEventRegistration::Terminated => {
+                                let thread = runtime.remove_program(index);
+                                info!("Removed WASM program {:?}", thread.path);
+                                thread
+                                    .handle
+                                    .join()
+                                    .expect("unable to join WasmProcess")
+                                    .expect("error during WasmProcess execution");
+                            }
                         };
                     }
+                    RuntimeMessage::Exited(index) => runtime.program_exited(index),
+                    RuntimeMessage::Coalesced => runtime.deliver_coalesced(),
+                    RuntimeMessage::Restart(path) => {
+                        if let Some(restart) = runtime.restarts.remove(&path) {
+                            info!("Restarting WASM program {:?}", path);
+                            {
+                                let mut guard = write_ufs(&runtime.ufs);
+                                guard
+                                    .wasm_restarts_mut()
+                                    .entry(path)
+                                    .or_insert_with(RestartStatus::default)
+                                    .restarts += 1;
+                            }
+                            runtime.start_program(restart.program, restart.attempts);
+                        }
+                    }
                     RuntimeMessage::Network(msg) => {
                         let guard = runtime.ufs.clone();
-                        let mut guard = guard.lock().expect("poisoned iofs lock");
+                        let mut guard = write_ufs(&guard);
 
                         if let Ok(()) = guard.validate_token(msg.token().to_owned()) {
                             match msg {
@@ -413,10 +947,16 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                                 route,
                                             )
                                         {
-                                            runtime.threads[*endpoint]
+                                            if let Err(e) = runtime.threads[*endpoint]
                                                 .sender
                                                 .send(WasmProcessMessage::NetworkEvent(get))
-                                                .unwrap();
+                                            {
+                                                if let WasmProcessMessage::NetworkEvent(msg) =
+                                                    e.into_inner()
+                                                {
+                                                    msg.unavailable();
+                                                }
+                                            }
                                         } else {
                                             get.not_allowed();
                                         }
@@ -424,7 +964,9 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                         get.no_such_endpoint();
                                     }
                                 }
-                                post @ IofsNetworkMessage::Post(_) => {
+                                // Binary POSTs go to the same endpoint as the JSON ones.
+                                post @ IofsNetworkMessage::Post(_)
+                                | post @ IofsNetworkMessage::PostBytes(_) => {
                                     let route = post.route();
                                     if let Some(endpoint) = runtime
                                         .http_endpoints
@@ -440,10 +982,16 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                                 route,
                                             )
                                         {
-                                            runtime.threads[*endpoint]
+                                            if let Err(e) = runtime.threads[*endpoint]
                                                 .sender
                                                 .send(WasmProcessMessage::NetworkEvent(post))
-                                                .unwrap();
+                                            {
+                                                if let WasmProcessMessage::NetworkEvent(msg) =
+                                                    e.into_inner()
+                                                {
+                                                    msg.unavailable();
+                                                }
+                                            }
                                         } else {
                                             post.not_allowed();
                                         }
@@ -467,10 +1015,16 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                                 route,
                                             )
                                         {
-                                            runtime.threads[*endpoint]
+                                            if let Err(e) = runtime.threads[*endpoint]
                                                 .sender
                                                 .send(WasmProcessMessage::NetworkEvent(put))
-                                                .unwrap();
+                                            {
+                                                if let WasmProcessMessage::NetworkEvent(msg) =
+                                                    e.into_inner()
+                                                {
+                                                    msg.unavailable();
+                                                }
+                                            }
                                         } else {
                                             put.not_allowed();
                                         }
@@ -494,10 +1048,16 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                                 route,
                                             )
                                         {
-                                            runtime.threads[*endpoint]
+                                            if let Err(e) = runtime.threads[*endpoint]
                                                 .sender
                                                 .send(WasmProcessMessage::NetworkEvent(patch))
-                                                .unwrap();
+                                            {
+                                                if let WasmProcessMessage::NetworkEvent(msg) =
+                                                    e.into_inner()
+                                                {
+                                                    msg.unavailable();
+                                                }
+                                            }
                                         } else {
                                             patch.not_allowed();
                                         }
@@ -521,10 +1081,16 @@ impl<B: BlockStorage> RuntimeManager<B> {
                                                 route,
                                             )
                                         {
-                                            runtime.threads[*endpoint]
+                                            if let Err(e) = runtime.threads[*endpoint]
                                                 .sender
                                                 .send(WasmProcessMessage::NetworkEvent(delete))
-                                                .unwrap();
+                                            {
+                                                if let WasmProcessMessage::NetworkEvent(msg) =
+                                                    e.into_inner()
+                                                {
+                                                    msg.unavailable();
+                                                }
+                                            }
                                         } else {
                                             delete.not_allowed();
                                         }
@@ -558,30 +1124,69 @@ impl<B: BlockStorage> RuntimeManager<B> {
 
 enum RuntimeMessage {
     Runtime(RuntimeManagerMsg),
+    Event(IofsMessage),
     Network(IofsNetworkMessage),
     Registration((usize, IofsEventRegistration)),
+    /// The program at the index has stopped running
+    Exited(usize),
+    /// It's time to restart the crashed program
+    Restart(PathBuf),
+    /// The window for a run of coalesced events has closed
+    Coalesced,
 }
 
-fn receive_message<B: BlockStorage>(
+fn receive_message<B: BlockStorage + Sync>(
     runtime: &RuntimeManager<B>,
 ) -> Result<RuntimeMessage, RecvError> {
     let mut select = Select::new();
 
     select.recv(&runtime.receiver);
+    select.recv(&runtime.events);
 
     let thread_offset = if let Some(http_receiver) = &runtime.http_receiver {
         select.recv(http_receiver);
-        2
+        3
     } else {
-        1
+        2
     };
 
     for t in &runtime.threads {
         select.recv(&t.receiver);
     }
 
+    // Wait no longer than it takes for the next crashed program to be due for a restart, or for
+    // the next run of coalesced events to end.
+    let next_restart = runtime.restarts.values().min_by_key(|r| r.due);
+    let next_coalesced = runtime.coalescer.next_due();
+    let deadline = next_restart
+        .map(|r| r.due)
+        .into_iter()
+        .chain(next_coalesced)
+        .min();
+
     loop {
-        let index = select.ready();
+        let index = match deadline {
+            Some(due) => {
+                let now = Instant::now();
+                let ready = if due > now {
+                    select.ready_timeout(due - now).ok()
+                } else {
+                    None
+                };
+                match ready {
+                    Some(index) => index,
+                    None => {
+                        return Ok(match next_restart {
+                            Some(restart) if restart.due == due => {
+                                RuntimeMessage::Restart(restart.program.name.clone())
+                            }
+                            _ => RuntimeMessage::Coalesced,
+                        })
+                    }
+                }
+            }
+            None => select.ready(),
+        };
         if index == 0 {
             let msg = runtime.receiver.try_recv();
             if let Err(e) = msg {
@@ -593,7 +1198,16 @@ fn receive_message<B: BlockStorage>(
             return msg
                 .map(|m| RuntimeMessage::Runtime(m))
                 .map_err(|_| RecvError);
-        } else if index == 1 && thread_offset == 2 {
+        } else if index == 1 {
+            let msg = runtime.events.try_recv();
+            if let Err(e) = msg {
+                if e.is_empty() {
+                    continue;
+                }
+            }
+
+            return msg.map(|m| RuntimeMessage::Event(m)).map_err(|_| RecvError);
+        } else if index == 2 && thread_offset == 3 {
             let msg = runtime.http_receiver.as_ref().unwrap().try_recv();
             if let Err(e) = msg {
                 if e.is_empty() {
@@ -612,9 +1226,579 @@ fn receive_message<B: BlockStorage>(
                 }
             }
 
-            return msg
-                .map(|m| RuntimeMessage::Registration((thread_offset - 2, m)))
-                .map_err(|_| RecvError);
+            // The program's end of the channel goes away when the program does.
+            return Ok(msg
+                .map(|m| RuntimeMessage::Registration((index - thread_offset, m)))
+                .unwrap_or(RuntimeMessage::Exited(index - thread_offset)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::block::{BlockSize, MemoryStore},
+        std::thread::sleep,
+    };
+
+    fn payload(id: UfsUuid) -> IofsMessagePayload {
+        IofsMessagePayload {
+            target_id: id,
+            target_path: PathBuf::from("/foo"),
+            parent_id: UfsUuid::new_root_fs("test"),
+        }
+    }
+
+    #[test]
+    fn coalesce_rapid_writes() {
+        let window = Duration::from_millis(100);
+        let mut coalescer = EventCoalescer::new(window);
+        let id = UfsUuid::new_root_fs("test").new("foo");
+        let write = |n: u64| {
+            IofsMessage::FileMessage(IofsFileMessage::Write(IofsMessagePayload {
+                target_path: PathBuf::from(format!("/foo/{}", n)),
+                ..payload(id)
+            }))
+        };
+
+        // The first write goes straight through, and the rest are held back.
+        let start = Instant::now();
+        let delivered = (0..1000)
+            .flat_map(|n| coalescer.coalesce(write(n), start + Duration::from_micros(n)))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![write(0)], delivered);
+        assert_eq!(Some(start + window), coalescer.next_due());
+        assert!(coalescer.due(start + window / 2).is_empty());
+
+        // Once the window closes, the last of them is delivered, and the next write goes through.
+        assert_eq!(vec![write(999)], coalescer.due(start + window));
+        assert_eq!(None, coalescer.next_due());
+        assert_eq!(
+            vec![write(1000)],
+            coalescer.coalesce(write(1000), start + window * 2)
+        );
+
+        // Without a window, nothing is held back.
+        let mut coalescer = EventCoalescer::new(Duration::from_secs(0));
+        for n in 0..10 {
+            assert_eq!(vec![write(n)], coalescer.coalesce(write(n), start));
         }
+        assert_eq!(None, coalescer.next_due());
+    }
+
+    #[test]
+    fn do_not_coalesce_other_events() {
+        let mut coalescer = EventCoalescer::new(Duration::from_secs(60));
+        let id = UfsUuid::new_root_fs("test").new("foo");
+        let other = UfsUuid::new_root_fs("test").new("bar");
+        let now = Instant::now();
+
+        let create = IofsMessage::FileMessage(IofsFileMessage::Create(payload(id)));
+        let open = IofsMessage::FileMessage(IofsFileMessage::Open(payload(id)));
+        let write = IofsMessage::FileMessage(IofsFileMessage::Write(payload(id)));
+        let read = IofsMessage::FileMessage(IofsFileMessage::Read(payload(id)));
+        let close = IofsMessage::FileMessage(IofsFileMessage::Close(payload(id)));
+        let other_write = IofsMessage::FileMessage(IofsFileMessage::Write(payload(other)));
+
+        for _ in 0..10 {
+            assert_eq!(
+                vec![create.clone()],
+                coalescer.coalesce(create.clone(), now)
+            );
+            assert_eq!(vec![open.clone()], coalescer.coalesce(open.clone(), now));
+        }
+
+        assert_eq!(vec![write.clone()], coalescer.coalesce(write.clone(), now));
+        assert!(coalescer.coalesce(write.clone(), now).is_empty());
+        // A read is a different event, and the write that it held back goes first.
+        assert_eq!(
+            vec![write.clone(), read.clone()],
+            coalescer.coalesce(read.clone(), now)
+        );
+        // A write to another file is a run of its own.
+        assert_eq!(
+            vec![other_write.clone()],
+            coalescer.coalesce(other_write.clone(), now)
+        );
+        // A close breaks the run of reads.
+        assert!(coalescer.coalesce(read.clone(), now).is_empty());
+        assert_eq!(
+            vec![read.clone(), close.clone()],
+            coalescer.coalesce(close.clone(), now)
+        );
+        assert_eq!(vec![write.clone()], coalescer.coalesce(write.clone(), now));
+
+        // Whatever is held back is delivered at shut down.
+        assert!(coalescer.coalesce(write.clone(), now).is_empty());
+        assert_eq!(vec![write], coalescer.drain());
+    }
+
+    #[test]
+    fn scoped_events() {
+        let mut ufs =
+            UberFileSystem::new_memory("test", "foobar", "test", BlockSize::TwentyFortyEight, 100);
+        let root_id = ufs.get_root_directory_id();
+        let docs = ufs.create_directory(root_id, "docs").unwrap();
+        let drafts = ufs.create_directory(docs.id(), "drafts").unwrap();
+        let music = ufs.create_directory(root_id, "music").unwrap();
+        let (_, draft) = ufs.create_file(drafts.id(), "draft.txt").unwrap();
+        let (_, song) = ufs.create_file(music.id(), "song.mp3").unwrap();
+
+        let metadata = ufs.block_manager().metadata();
+        let draft = IofsMessagePayload {
+            target_id: draft.file_id,
+            target_path: PathBuf::from("/docs/drafts/draft.txt"),
+            parent_id: drafts.id(),
+        };
+        let song = IofsMessagePayload {
+            target_id: song.file_id,
+            target_path: PathBuf::from("/music/song.mp3"),
+            parent_id: music.id(),
+        };
+        let docs_itself = IofsMessagePayload {
+            target_id: docs.id(),
+            target_path: PathBuf::from("/docs"),
+            parent_id: root_id,
+        };
+
+        assert!(is_beneath(docs.id(), &draft, metadata));
+        assert!(is_beneath(docs.id(), &docs_itself, metadata));
+        assert!(!is_beneath(docs.id(), &song, metadata));
+        assert!(is_beneath(root_id, &song, metadata));
+    }
+
+    // A hand assembled WASM program that, from `__init`, sends "pipeline" to
+    // "/.wasm/receiver.wasm".
+    #[rustfmt::skip]
+    const SENDER_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x10, 0x03, 0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00,
+        0x60, 0x00, 0x00, // type section
+        0x02, 0x16, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0e, 0x5f, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x5f,
+        0x6d, 0x65, 0x73, 0x73, 0x61, 0x67, 0x65, 0x00, 0x00, // import section
+        0x03, 0x03, 0x02, 0x01, 0x02, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x27, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x00, 0x01, 0x11, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65,
+        0x5f, 0x73, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77, 0x6e, 0x00, 0x02, // export section
+        0x0a, 0x12, 0x02, 0x0d, 0x00, 0x41, 0x80, 0x08, 0x41, 0xb0, 0x09, 0x41, 0x08, 0x10, 0x00,
+        0x1a, 0x0b, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x37, 0x03, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x08, 0x4c, 0x04, 0x00, 0x00, 0x14, 0x00,
+        0x00, 0x00, 0x00, 0x41, 0xcc, 0x08, 0x0b, 0x14, 0x2f, 0x2e, 0x77, 0x61, 0x73, 0x6d, 0x2f,
+        0x72, 0x65, 0x63, 0x65, 0x69, 0x76, 0x65, 0x72, 0x2e, 0x77, 0x61, 0x73, 0x6d, 0x00, 0x41,
+        0xb0, 0x09, 0x0b, 0x08, 0x70, 0x69, 0x70, 0x65, 0x6c, 0x69, 0x6e, 0x65, // data section
+    ];
+
+    // A hand assembled WASM program that registers for program messages from `__init`, after
+    // copying the root directory id to memory location 2048. When a message arrives it creates a
+    // directory, named for the payload, in the root directory.
+    #[rustfmt::skip]
+    const RECEIVER_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x1a, 0x05, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02,
+        0x7f, 0x7f, 0x00, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00, // type section
+        0x02, 0x38, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x17, 0x5f, 0x5f, 0x72, 0x65, 0x67, 0x69, 0x73,
+        0x74, 0x65, 0x72, 0x5f, 0x66, 0x6f, 0x72, 0x5f, 0x63, 0x61, 0x6c, 0x6c, 0x62, 0x61, 0x63,
+        0x6b, 0x00, 0x00, 0x03, 0x65, 0x6e, 0x76, 0x12, 0x5f, 0x5f, 0x63, 0x72, 0x65, 0x61, 0x74,
+        0x65, 0x5f, 0x64, 0x69, 0x72, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x79, 0x00, 0x01, // import section
+        0x03, 0x04, 0x03, 0x02, 0x03, 0x04, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x42, 0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x00, 0x02, 0x18, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65,
+        0x5f, 0x70, 0x72, 0x6f, 0x67, 0x72, 0x61, 0x6d, 0x5f, 0x6d, 0x65, 0x73, 0x73, 0x61, 0x67,
+        0x65, 0x00, 0x03, 0x11, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x5f, 0x73, 0x68,
+        0x75, 0x74, 0x64, 0x6f, 0x77, 0x6e, 0x00, 0x04, // export section
+        0x0a, 0x62, 0x03, 0x41, 0x01, 0x01, 0x7f, 0x02, 0x40, 0x03, 0x40, 0x20, 0x02, 0x20, 0x01,
+        0x4f, 0x0d, 0x01, 0x41, 0x80, 0x10, 0x20, 0x02, 0x6a, 0x20, 0x00, 0x20, 0x02, 0x6a, 0x2d,
+        0x00, 0x00, 0x3a, 0x00, 0x00, 0x20, 0x02, 0x41, 0x01, 0x6a, 0x21, 0x02, 0x0c, 0x00, 0x0b,
+        0x0b, 0x41, 0x80, 0x08, 0x41, 0x80, 0x10, 0x36, 0x02, 0x00, 0x41, 0x84, 0x08, 0x20, 0x01,
+        0x36, 0x02, 0x00, 0x41, 0x90, 0x08, 0x10, 0x00, 0x0b, 0x1b, 0x00, 0x41, 0x88, 0x08, 0x20,
+        0x02, 0x36, 0x02, 0x00, 0x41, 0x8c, 0x08, 0x20, 0x03, 0x36, 0x02, 0x00, 0x41, 0x80, 0x08,
+        0x41, 0x88, 0x08, 0x10, 0x01, 0x1a, 0x0b, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x0b, 0x01, 0x00, 0x41, 0x90, 0x08, 0x0b, 0x04, 0x0c, 0x00, 0x00, 0x00, // data section
+    ];
+
+    // A hand assembled WASM program that, from `__init`, sends "/.wasm/receiver.wasm" a message
+    // whose payload runs off the end of its memory. If that's refused with -1 it goes on to send
+    // "pipeline", as `SENDER_PROGRAM` does.
+    #[rustfmt::skip]
+    const STRAY_SENDER_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x10, 0x03, 0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00,
+        0x60, 0x00, 0x00, // type section
+        0x02, 0x16, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0e, 0x5f, 0x5f, 0x73, 0x65, 0x6e, 0x64, 0x5f,
+        0x6d, 0x65, 0x73, 0x73, 0x61, 0x67, 0x65, 0x00, 0x00, // import section
+        0x03, 0x03, 0x02, 0x01, 0x02, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x27, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x00, 0x01, 0x11, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65,
+        0x5f, 0x73, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77, 0x6e, 0x00, 0x02, // export section
+        0x0a, 0x22, 0x02, 0x1d, 0x00, 0x41, 0x80, 0x10, 0x41, 0x70, 0x41, 0x80, 0x02, 0x10, 0x00,
+        0x41, 0x7f, 0x46, 0x04, 0x40, 0x41, 0x80, 0x10, 0x41, 0xb0, 0x13, 0x41, 0x08, 0x10, 0x00,
+        0x1a, 0x0b, 0x0b, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x37, 0x03, 0x00, 0x41, 0x80, 0x10, 0x0b, 0x08, 0x4c, 0x08, 0x00, 0x00, 0x14, 0x00,
+        0x00, 0x00, 0x00, 0x41, 0xcc, 0x10, 0x0b, 0x14, 0x2f, 0x2e, 0x77, 0x61, 0x73, 0x6d, 0x2f,
+        0x72, 0x65, 0x63, 0x65, 0x69, 0x76, 0x65, 0x72, 0x2e, 0x77, 0x61, 0x73, 0x6d, 0x00, 0x41,
+        0xb0, 0x13, 0x0b, 0x08, 0x70, 0x69, 0x70, 0x65, 0x6c, 0x69, 0x6e, 0x65, // data section
+    ];
+
+    #[test]
+    fn program_messages() {
+        send_pipeline(SENDER_PROGRAM);
+    }
+
+    #[test]
+    fn program_message_outside_memory() {
+        send_pipeline(STRAY_SENDER_PROGRAM);
+    }
+
+    /// Run `RECEIVER_PROGRAM`, and then `sender_program`, and wait for "pipeline" to arrive
+    ///
+    fn send_pipeline(sender_program: &[u8]) {
+        let sender_path = PathBuf::from("/.wasm/sender.wasm");
+        let receiver_path = PathBuf::from("/.wasm/receiver.wasm");
+
+        let ufs = Arc::new(RwLock::new(UberFileSystem::new_memory(
+            "test",
+            "foobar",
+            "test",
+            BlockSize::TwentyFortyEight,
+            100,
+        )));
+
+        let root_id = {
+            let mut guard = ufs.write().unwrap();
+            let metadata = guard.block_manager_mut().metadata_mut();
+            metadata.add_wasm_program_grants(sender_path.clone());
+            metadata.add_wasm_program_grants(receiver_path.clone());
+            metadata.set_wasm_program_grant(&sender_path, GrantType::SendMessage, Grant::Allow);
+            metadata.set_wasm_program_grant(
+                &receiver_path,
+                GrantType::CreateDirectoryInvocation,
+                Grant::Allow,
+            );
+            guard.get_root_directory_id()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        let (_events, event_receiver) = crossbeam_channel::unbounded::<IofsMessage>();
+        let runtime =
+            RuntimeManager::start(RuntimeManager::new(ufs.clone(), receiver, event_receiver));
+
+        sender
+            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                receiver_path,
+                RECEIVER_PROGRAM.to_vec(),
+                None,
+            )))
+            .unwrap();
+        start_until_pipeline(&sender, &ufs, root_id, sender_program);
+
+        sender.send(RuntimeManagerMsg::Shutdown).unwrap();
+        runtime.join().unwrap().unwrap();
+    }
+
+    /// Start `sender_program` until the "pipeline" directory shows up
+    ///
+    /// The receiver registers for messages from its `__init`, and a message that reaches it
+    /// before then is dropped. So, should the sender's message go astray, the sender is started
+    /// again.
+    fn start_until_pipeline(
+        sender: &crossbeam_channel::Sender<RuntimeManagerMsg>,
+        ufs: &Arc<RwLock<UberFileSystem<MemoryStore>>>,
+        root_id: UfsUuid,
+        sender_program: &[u8],
+    ) {
+        let arrived = || {
+            let mut guard = ufs.write().unwrap();
+            guard.open_sub_directory(root_id, "pipeline").is_ok()
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !arrived() {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for the pipeline"
+            );
+            sender
+                .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                    PathBuf::from("/.wasm/sender.wasm"),
+                    sender_program.to_vec(),
+                    None,
+                )))
+                .unwrap();
+
+            let retry = Instant::now() + Duration::from_secs(1);
+            while !arrived() && Instant::now() < retry {
+                sleep(Duration::from_millis(20));
+            }
+        }
+    }
+
+    // A hand assembled WASM program that registers for file writes from `__init`, and publishes
+    // "thumbnails" as the "thumbnail-ready" event whenever a file is written.
+    #[rustfmt::skip]
+    const PUBLISHER_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x13, 0x04, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02,
+        0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00, // type section
+        0x02, 0x35, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x17, 0x5f, 0x5f, 0x72, 0x65, 0x67, 0x69, 0x73,
+        0x74, 0x65, 0x72, 0x5f, 0x66, 0x6f, 0x72, 0x5f, 0x63, 0x61, 0x6c, 0x6c, 0x62, 0x61, 0x63,
+        0x6b, 0x00, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0f, 0x5f, 0x5f, 0x70, 0x75, 0x62, 0x6c, 0x69,
+        0x73, 0x68, 0x5f, 0x65, 0x76, 0x65, 0x6e, 0x74, 0x00, 0x00, // import section
+        0x03, 0x04, 0x03, 0x02, 0x02, 0x03, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x3d, 0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x00, 0x02, 0x13, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65,
+        0x5f, 0x66, 0x69, 0x6c, 0x65, 0x5f, 0x77, 0x72, 0x69, 0x74, 0x65, 0x00, 0x03, 0x11, 0x5f,
+        0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x5f, 0x73, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77,
+        0x6e, 0x00, 0x04, // export section
+        0x0a, 0x18, 0x03, 0x07, 0x00, 0x41, 0x80, 0x10, 0x10, 0x00, 0x0b, 0x0b, 0x00, 0x41, 0x90,
+        0x10, 0x41, 0x98, 0x10, 0x10, 0x01, 0x1a, 0x0b, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x46, 0x04, 0x00, 0x41, 0x80, 0x10, 0x0b, 0x04, 0x09, 0x00, 0x00, 0x00, 0x00, 0x41,
+        0x90, 0x10, 0x0b, 0x10, 0x20, 0x08, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x30, 0x08, 0x00,
+        0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x41, 0xa0, 0x10, 0x0b, 0x0f, 0x74, 0x68, 0x75, 0x6d,
+        0x62, 0x6e, 0x61, 0x69, 0x6c, 0x2d, 0x72, 0x65, 0x61, 0x64, 0x79, 0x00, 0x41, 0xb0, 0x10,
+        0x0b, 0x0a, 0x74, 0x68, 0x75, 0x6d, 0x62, 0x6e, 0x61, 0x69, 0x6c, 0x73, // data section
+    ];
+
+    // A hand assembled WASM program that copies the root directory id, and its configuration, to
+    // memory from `__init_with_config`, and registers for "thumbnail-ready". When the event
+    // arrives it creates a directory in the root directory, named for the configuration followed
+    // by the payload.
+    #[rustfmt::skip]
+    const CONSUMER_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x1b, 0x05, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x04,
+        0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00, 0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x00, // type section
+        0x02, 0x35, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x14, 0x5f, 0x5f, 0x72, 0x65, 0x67, 0x69, 0x73,
+        0x74, 0x65, 0x72, 0x5f, 0x66, 0x6f, 0x72, 0x5f, 0x65, 0x76, 0x65, 0x6e, 0x74, 0x00, 0x00,
+        0x03, 0x65, 0x6e, 0x76, 0x12, 0x5f, 0x5f, 0x63, 0x72, 0x65, 0x61, 0x74, 0x65, 0x5f, 0x64,
+        0x69, 0x72, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x79, 0x00, 0x01, // import section
+        0x03, 0x05, 0x04, 0x04, 0x02, 0x02, 0x03, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x4c, 0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x12, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x5f, 0x77, 0x69, 0x74, 0x68, 0x5f, 0x63, 0x6f, 0x6e, 0x66, 0x69,
+        0x67, 0x00, 0x03, 0x16, 0x5f, 0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x5f, 0x70, 0x72,
+        0x6f, 0x67, 0x72, 0x61, 0x6d, 0x5f, 0x65, 0x76, 0x65, 0x6e, 0x74, 0x00, 0x04, 0x11, 0x5f,
+        0x5f, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x5f, 0x73, 0x68, 0x75, 0x74, 0x64, 0x6f, 0x77,
+        0x6e, 0x00, 0x05, // export section
+        0x0a, 0x84, 0x01, 0x04, 0x2a, 0x01, 0x01, 0x7f, 0x02, 0x40, 0x03, 0x40, 0x20, 0x03, 0x20,
+        0x02, 0x4f, 0x0d, 0x01, 0x20, 0x00, 0x20, 0x03, 0x6a, 0x20, 0x01, 0x20, 0x03, 0x6a, 0x2d,
+        0x00, 0x00, 0x3a, 0x00, 0x00, 0x20, 0x03, 0x41, 0x01, 0x6a, 0x21, 0x03, 0x0c, 0x00, 0x0b,
+        0x0b, 0x0b, 0x29, 0x00, 0x41, 0x80, 0x20, 0x20, 0x00, 0x20, 0x01, 0x10, 0x02, 0x41, 0x84,
+        0x10, 0x20, 0x01, 0x36, 0x02, 0x00, 0x41, 0x80, 0x28, 0x20, 0x02, 0x20, 0x03, 0x10, 0x02,
+        0x41, 0x8c, 0x10, 0x20, 0x03, 0x36, 0x02, 0x00, 0x41, 0x90, 0x10, 0x10, 0x00, 0x0b, 0x2a,
+        0x00, 0x41, 0x80, 0x28, 0x41, 0x8c, 0x10, 0x28, 0x02, 0x00, 0x6a, 0x20, 0x02, 0x20, 0x03,
+        0x10, 0x02, 0x41, 0x9c, 0x10, 0x41, 0x8c, 0x10, 0x28, 0x02, 0x00, 0x20, 0x03, 0x6a, 0x36,
+        0x02, 0x00, 0x41, 0x80, 0x10, 0x41, 0x98, 0x10, 0x10, 0x01, 0x1a, 0x0b, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x32, 0x03, 0x00, 0x41, 0x80, 0x10, 0x0b, 0x04, 0x00, 0x10, 0x00, 0x00, 0x00, 0x41,
+        0x90, 0x10, 0x0b, 0x0c, 0x20, 0x08, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00,
+        0x00, 0x00, 0x41, 0xa0, 0x10, 0x0b, 0x0f, 0x74, 0x68, 0x75, 0x6d, 0x62, 0x6e, 0x61, 0x69,
+        0x6c, 0x2d, 0x72, 0x65, 0x61, 0x64, 0x79, // data section
+    ];
+
+    #[test]
+    fn program_events() {
+        let publisher_path = PathBuf::from("/.wasm/publisher.wasm");
+        let consumer_paths = vec![
+            (PathBuf::from("/.wasm/consumer-a.wasm"), "a-"),
+            (PathBuf::from("/.wasm/consumer-b.wasm"), "b-"),
+        ];
+
+        let ufs = Arc::new(RwLock::new(UberFileSystem::new_memory(
+            "test",
+            "foobar",
+            "test",
+            BlockSize::TwentyFortyEight,
+            100,
+        )));
+
+        let root_id = {
+            let mut guard = ufs.write().unwrap();
+            let metadata = guard.block_manager_mut().metadata_mut();
+            metadata.add_wasm_program_grants(publisher_path.clone());
+            metadata.set_wasm_program_grant(
+                &publisher_path,
+                GrantType::FileWriteEvent,
+                Grant::Allow,
+            );
+            metadata.set_wasm_program_grant(&publisher_path, GrantType::SendMessage, Grant::Allow);
+            for (path, _) in &consumer_paths {
+                metadata.add_wasm_program_grants(path.clone());
+                metadata.set_wasm_program_grant(
+                    path,
+                    GrantType::CreateDirectoryInvocation,
+                    Grant::Allow,
+                );
+            }
+            guard.get_root_directory_id()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        let (events, event_receiver) = crossbeam_channel::unbounded::<IofsMessage>();
+        let runtime =
+            RuntimeManager::start(RuntimeManager::new(ufs.clone(), receiver, event_receiver));
+
+        for (path, config) in consumer_paths {
+            sender
+                .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                    path,
+                    CONSUMER_PROGRAM.to_vec(),
+                    Some(config.to_string()),
+                )))
+                .unwrap();
+        }
+        sender
+            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                publisher_path,
+                PUBLISHER_PROGRAM.to_vec(),
+                None,
+            )))
+            .unwrap();
+
+        // A write is what sets the pipeline going. The event is dropped by any consumer that
+        // hasn't registered for it yet, so writes to new files are sent until both have seen one.
+        let mut writes = 0;
+        wait_for(|| {
+            writes += 1;
+            events
+                .send(IofsMessage::FileMessage(IofsFileMessage::Write(payload(
+                    UfsUuid::new_root_fs("test").new(format!("foo-{}", writes)),
+                ))))
+                .unwrap();
+
+            let mut guard = ufs.write().unwrap();
+            guard.open_sub_directory(root_id, "a-thumbnails").is_ok()
+                && guard.open_sub_directory(root_id, "b-thumbnails").is_ok()
+        });
+
+        sender.send(RuntimeManagerMsg::Shutdown).unwrap();
+        runtime.join().unwrap().unwrap();
+    }
+
+    /// Poll until `condition` holds, failing the test if it doesn't within a few seconds
+    ///
+    fn wait_for<F: FnMut() -> bool>(mut condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for condition");
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn runaway_program() {
+        use crate::wasm::LOOP_PROGRAM;
+
+        let loop_path = PathBuf::from("/.wasm/loop.wasm");
+        let sender_path = PathBuf::from("/.wasm/sender.wasm");
+        let receiver_path = PathBuf::from("/.wasm/receiver.wasm");
+
+        let ufs = Arc::new(RwLock::new(UberFileSystem::new_memory(
+            "test",
+            "foobar",
+            "test",
+            BlockSize::TwentyFortyEight,
+            100,
+        )));
+
+        let root_id = {
+            let mut guard = ufs.write().unwrap();
+            guard.set_wasm_execution_limit(10_000_000);
+            let metadata = guard.block_manager_mut().metadata_mut();
+            metadata.add_wasm_program_grants(sender_path.clone());
+            metadata.add_wasm_program_grants(receiver_path.clone());
+            metadata.set_wasm_program_grant(&sender_path, GrantType::SendMessage, Grant::Allow);
+            metadata.set_wasm_program_grant(
+                &receiver_path,
+                GrantType::CreateDirectoryInvocation,
+                Grant::Allow,
+            );
+            guard.get_root_directory_id()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        let (_events, event_receiver) = crossbeam_channel::unbounded::<IofsMessage>();
+        let runtime =
+            RuntimeManager::start(RuntimeManager::new(ufs.clone(), receiver, event_receiver));
+
+        // The looping program is terminated, and the programs started alongside it carry on.
+        sender
+            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                loop_path,
+                LOOP_PROGRAM.to_vec(),
+                None,
+            )))
+            .unwrap();
+        sender
+            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                receiver_path,
+                RECEIVER_PROGRAM.to_vec(),
+                None,
+            )))
+            .unwrap();
+        start_until_pipeline(&sender, &ufs, root_id, SENDER_PROGRAM);
+
+        // Were the looping program still around, this would never return.
+        sender.send(RuntimeManagerMsg::Shutdown).unwrap();
+        runtime.join().unwrap().unwrap();
+    }
+
+    // A hand assembled WASM program whose `__init` traps.
+    #[rustfmt::skip]
+    const CRASH_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x06, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x13, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x06, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, // export section
+        0x0a, 0x05, 0x01, 0x03, 0x00, 0x00, 0x0b, // code section
+    ];
+
+    #[test]
+    fn restart_crashed_program() {
+        let crash_path = PathBuf::from("/.wasm/crash.wasm");
+
+        let ufs = Arc::new(RwLock::new(UberFileSystem::new_memory(
+            "test",
+            "foobar",
+            "test",
+            BlockSize::TwentyFortyEight,
+            100,
+        )));
+        ufs.write().unwrap().set_wasm_restart_limit(3);
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<RuntimeManagerMsg>();
+        let (_events, event_receiver) = crossbeam_channel::unbounded::<IofsMessage>();
+        let runtime =
+            RuntimeManager::start(RuntimeManager::new(ufs.clone(), receiver, event_receiver));
+
+        // The backoff is 100, 200, and then 400ms, after which the program is given up on.
+        sender
+            .send(RuntimeManagerMsg::Start(ProtoWasmProgram::new(
+                crash_path.clone(),
+                CRASH_PROGRAM.to_vec(),
+                None,
+            )))
+            .unwrap();
+        wait_for(|| {
+            let guard = ufs.read().unwrap();
+            guard
+                .wasm_restarts()
+                .get(&crash_path)
+                .map_or(false, |status| status.failed)
+        });
+
+        sender.send(RuntimeManagerMsg::Shutdown).unwrap();
+        runtime.join().unwrap().unwrap();
+
+        let guard = ufs.read().unwrap();
+        let status = guard.wasm_restarts().get(&crash_path).unwrap();
+        assert_eq!(3, status.restarts);
+        assert!(status.failed);
     }
 }
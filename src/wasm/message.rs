@@ -4,7 +4,7 @@
 //!
 use {
     crate::{
-        server::{IofsNetworkGetValue, IofsNetworkJsonValue},
+        server::{html_response, IofsNetworkBytesValue, IofsNetworkGetValue, IofsNetworkJsonValue},
         uuid::UfsUuid,
         wasm::RuntimeErrorKind,
     },
@@ -12,46 +12,135 @@ use {
     log::error,
     serde_derive::Serialize,
     serde_json,
-    std::{convert::TryInto, path::PathBuf, str},
+    std::{
+        convert::TryInto,
+        path::{Path, PathBuf},
+        str,
+    },
     uuid::Uuid,
-    wasm_exports::MessagePayload,
-    wasmer_runtime::{Instance, Value},
+    wasm_exports::{HttpRequest, HttpResponse, MessagePayload},
+    wasmer_runtime::{
+        error::{CallError, RuntimeError},
+        Instance, Value,
+    },
 };
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub(crate) enum IofsMessage {
+#[cfg(target_arch = "x86_64")]
+use wasmer_middleware_common::metering::{set_points_used, ExecutionLimitExceededError};
+
+/// File System Events
+///
+/// These are sent to WASM programs that have registered for them, and to any `EventSink`s.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum IofsMessage {
+    /// System-level event
+    ///
     SystemMessage(IofsSystemMessage),
+    /// File event
+    ///
     FileMessage(IofsFileMessage),
+    /// Directory event
+    ///
     DirMessage(IofsDirMessage),
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub(crate) enum IofsSystemMessage {
+/// System Events
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum IofsSystemMessage {
+    /// The file system is shutting down
+    ///
     Shutdown,
+    /// Are you there?
+    ///
     Ping,
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub(crate) enum IofsFileMessage {
+/// File Events
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum IofsFileMessage {
+    /// A file was created
+    ///
     Create(IofsMessagePayload),
+    /// A file was deleted
+    ///
     Delete(IofsMessagePayload),
+    /// A file was opened
+    ///
     Open(IofsMessagePayload),
+    /// A file was closed
+    ///
     Close(IofsMessagePayload),
+    /// A file was written
+    ///
     Write(IofsMessagePayload),
+    /// A file was read
+    ///
     Read(IofsMessagePayload),
+    /// A file was moved, and/or renamed
+    ///
+    /// The payload contains the new path.
+    Rename(IofsMessagePayload),
+}
+
+impl IofsFileMessage {
+    /// The subject of the event
+    ///
+    pub fn payload(&self) -> &IofsMessagePayload {
+        match self {
+            IofsFileMessage::Create(payload)
+            | IofsFileMessage::Delete(payload)
+            | IofsFileMessage::Open(payload)
+            | IofsFileMessage::Close(payload)
+            | IofsFileMessage::Write(payload)
+            | IofsFileMessage::Read(payload)
+            | IofsFileMessage::Rename(payload) => payload,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub(crate) enum IofsDirMessage {
+/// Directory Events
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum IofsDirMessage {
+    /// A directory was created
+    ///
     Create(IofsMessagePayload),
+    /// A directory was deleted
+    ///
     Delete(IofsMessagePayload),
+    /// A directory was moved, and/or renamed
+    ///
+    /// The payload contains the new path.
+    Rename(IofsMessagePayload),
 }
 
+impl IofsDirMessage {
+    /// The subject of the event
+    ///
+    pub fn payload(&self) -> &IofsMessagePayload {
+        match self {
+            IofsDirMessage::Create(payload)
+            | IofsDirMessage::Delete(payload)
+            | IofsDirMessage::Rename(payload) => payload,
+        }
+    }
+}
+
+/// The Subject of a File or Directory Event
+///
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
-pub(crate) struct IofsMessagePayload {
-    pub(crate) target_id: UfsUuid,
-    pub(crate) target_path: PathBuf,
-    pub(crate) parent_id: UfsUuid,
+pub struct IofsMessagePayload {
+    /// The ID of the file or directory
+    ///
+    pub target_id: UfsUuid,
+    /// The path of the file or directory
+    ///
+    pub target_path: PathBuf,
+    /// The ID of the containing directory
+    ///
+    pub parent_id: UfsUuid,
 }
 
 impl From<&IofsMessagePayload> for MessagePayload {
@@ -64,26 +153,106 @@ impl From<&IofsMessagePayload> for MessagePayload {
     }
 }
 
+/// The most that's written at the start of the memory of a program without `__alloc`
+///
+/// Programs built before `wasm_exports` had `__alloc` expect their messages at a fixed address, at
+/// the bottom of their stack. A larger message would run over whatever the stack is using. Results
+/// passed back from the callbacks go in the same place, and are held to the same limit.
+pub(crate) const FIXED_MESSAGE_LIMIT: usize = 64 * 1024;
+
+/// A message, copied into a WASM program's memory
+///
+struct WasmBuffer {
+    ptr: u32,
+    len: u32,
+    /// The program allocated the buffer with `__alloc`, and wants it back.
+    allocated: bool,
+}
+
+/// The length of the fixed part of an HTTP response frame
+///
+const HTTP_FRAME_HEADER_LEN: usize = 10;
+
+/// Unpack an HTTP response framed by `wasm_exports`
+///
+/// The frame is its own length, the status, the length of the headers, the headers as JSON, and
+/// then the body. The lengths are u32, and the status a u16, all little endian.
+fn parse_http_frame(frame: &[u8]) -> Result<HttpResponse, failure::Error> {
+    if frame.len() < HTTP_FRAME_HEADER_LEN {
+        error!("a {} byte HTTP response frame is too short", frame.len());
+        return Err(RuntimeErrorKind::IofsInvocation.into());
+    }
+
+    let status = u16::from_le_bytes(frame[4..6].try_into()?);
+    let headers_len = u32::from_le_bytes(frame[6..10].try_into()?) as usize;
+    let headers_end = match HTTP_FRAME_HEADER_LEN.checked_add(headers_len) {
+        Some(end) if end <= frame.len() => end,
+        _ => {
+            error!("HTTP response headers run past the end of the frame");
+            return Err(RuntimeErrorKind::IofsInvocation.into());
+        }
+    };
+
+    Ok(HttpResponse {
+        status,
+        headers: serde_json::from_slice(&frame[HTTP_FRAME_HEADER_LEN..headers_end])?,
+        body: frame[headers_end..].to_vec(),
+    })
+}
+
 pub(crate) struct WasmMessageSender<'a> {
     instance: &'a mut Instance,
 }
 
 impl<'a> WasmMessageSender<'a> {
-    pub(crate) fn new(instance: &'a mut Instance, root_id: UfsUuid) -> Self {
+    /// Initialize the WASM program
+    ///
+    /// If the program has a configuration, and it was built with a version of `wasm_exports` that
+    /// understands configurations, then `__init_with_config` is called. Otherwise we fall back to
+    /// plain old `__init`.
+    pub(crate) fn new(
+        instance: &'a mut Instance,
+        root_id: UfsUuid,
+        config: Option<String>,
+    ) -> Result<Self, failure::Error> {
         let mut wms = WasmMessageSender { instance };
 
         let root_id: Uuid = root_id.into();
-        let id_str = serde_json::to_string(&root_id).expect("unable to serialize JSON in new");
-        wms.write_wasm_memory(0, &id_str);
+        let id_str = serde_json::to_string(&root_id)?;
+        let id = wms.write_message(0, id_str.as_bytes())?;
+
+        match config {
+            Some(config) if wms.instance.dyn_func("__init_with_config").is_ok() => {
+                // Without `__alloc`, the config goes immediately after the root id.
+                let config = wms.write_message(id_str.len(), config.as_bytes())?;
+
+                wms.call_wasm_func(
+                    "__init_with_config",
+                    Some(&[
+                        Value::I32(id.ptr as _),
+                        Value::I32(id.len as _),
+                        Value::I32(config.ptr as _),
+                        Value::I32(config.len as _),
+                    ]),
+                )?;
+                wms.free_message(config)?;
+            }
+            _ => {
+                wms.call_wasm_func(
+                    "__init",
+                    Some(&[Value::I32(id.ptr as _), Value::I32(id.len as _)]),
+                )?;
+            }
+        }
+        wms.free_message(id)?;
 
-        wms.call_wasm_func(
-            "__init",
-            Some(&[Value::I32(0), Value::I32(id_str.len() as _)]),
-        )
-        .expect("error calling init function");
-        wms
+        Ok(wms)
     }
 
+    /// Invoke a function in the WASM program
+    ///
+    /// The program's points are reset before each invocation, so that the execution limit applies
+    /// to each call, rather than to the lifetime of the program.
     fn call_wasm_func(
         &mut self,
         name: &str,
@@ -94,8 +263,15 @@ impl<'a> WasmMessageSender<'a> {
             None => &[],
         };
 
+        #[cfg(target_arch = "x86_64")]
+        set_points_used(self.instance, 0);
+
         match self.instance.call(name, args) {
             Ok(v) => Ok(v),
+            Err(ref e) if exceeded_limit(e) => {
+                error!("Execution limit exceeded in wasm function {}", name);
+                Err(RuntimeErrorKind::ExecutionLimitExceeded.into())
+            }
             Err(e) => {
                 error!("Error invoking wasm function {}", e);
                 Err(RuntimeErrorKind::FunctionInvocation.into())
@@ -103,27 +279,185 @@ impl<'a> WasmMessageSender<'a> {
         }
     }
 
-    fn write_wasm_memory(&mut self, offset: usize, s: &str) {
+    /// Copy a message into the program's memory
+    ///
+    /// Programs that export `__alloc` are asked for a buffer to hold the message, which is handed
+    /// back to `__dealloc` by `free_message`. Older programs get the message at `fixed_offset`, so
+    /// long as it ends within `FIXED_MESSAGE_LIMIT`.
+    fn write_message(
+        &mut self,
+        fixed_offset: usize,
+        bytes: &[u8],
+    ) -> Result<WasmBuffer, failure::Error> {
+        let len = bytes.len() as u32;
+        let buffer = if self.instance.dyn_func("__alloc").is_ok() {
+            let values = self.call_wasm_func("__alloc", Some(&[Value::I32(len as i32)]))?;
+            match values.first() {
+                Some(Value::I32(ptr)) => WasmBuffer {
+                    ptr: *ptr as u32,
+                    len,
+                    allocated: true,
+                },
+                _ => return Err(RuntimeErrorKind::FunctionInvocation.into()),
+            }
+        } else if fixed_offset + bytes.len() <= FIXED_MESSAGE_LIMIT {
+            WasmBuffer {
+                ptr: fixed_offset as u32,
+                len,
+                allocated: false,
+            }
+        } else {
+            error!(
+                "a {} byte message is too large for a program without __alloc",
+                bytes.len()
+            );
+            return Err(RuntimeErrorKind::MemoryAccess.into());
+        };
+
+        self.write_wasm_memory(buffer.ptr as usize, bytes)?;
+        Ok(buffer)
+    }
+
+    /// Return a message's buffer to the program, if it was allocated
+    ///
+    fn free_message(&mut self, buffer: WasmBuffer) -> Result<(), failure::Error> {
+        if buffer.allocated {
+            self.call_wasm_func(
+                "__dealloc",
+                Some(&[Value::I32(buffer.ptr as i32), Value::I32(buffer.len as i32)]),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Copy bytes into the program's memory, starting at `offset`
+    ///
+    /// The bytes must fit in the memory as it is: it's not grown to fit.
+    fn write_wasm_memory<T: AsRef<[u8]>>(
+        &mut self,
+        offset: usize,
+        bytes: T,
+    ) -> Result<(), failure::Error> {
+        let bytes = bytes.as_ref();
         let memory = self.instance.context_mut().memory(0);
+        let view = memory.view::<u8>();
 
-        for (byte, cell) in s
-            .bytes()
-            .zip(memory.view()[offset..(offset + s.len()) as usize].iter())
-        {
-            cell.set(byte);
+        match offset.checked_add(bytes.len()) {
+            Some(end) if end <= view.len() => {
+                for (byte, cell) in bytes.iter().zip(view[offset..end].iter()) {
+                    cell.set(*byte);
+                }
+                Ok(())
+            }
+            _ => {
+                error!(
+                    "{} bytes at {} is outside of WASM memory",
+                    bytes.len(),
+                    offset
+                );
+                Err(RuntimeErrorKind::MemoryAccess.into())
+            }
         }
     }
 
-    fn unbox_wasm_string(&self, str_ptr: usize) -> String {
-        let memory = self.instance.context().memory(0);
-        let len_vec: Vec<u8> = memory.view()[1..5].iter().map(|cell| cell.get()).collect();
-        let len = u32::from_le_bytes(len_vec.as_slice().try_into().unwrap());
-        let bytes: Vec<u8> = memory.view()[str_ptr..str_ptr + len as usize]
-            .iter()
-            .map(|cell| cell.get())
-            .collect();
+    /// Read an HTTP response returned from WASM
+    ///
+    /// Programs that export `__alloc` return a frame holding the whole response, which is handed
+    /// back to `__dealloc` once it's read. Older programs return the body of a plain response, as
+    /// built by `plain`.
+    fn unbox_http_response<F>(
+        &mut self,
+        ptr: usize,
+        plain: F,
+    ) -> Result<HttpResponse, failure::Error>
+    where
+        F: FnOnce(Vec<u8>) -> HttpResponse,
+    {
+        if self.instance.dyn_func("__alloc").is_ok() {
+            let len = self.read_wasm_memory(ptr, 4)?;
+            let len = u32::from_le_bytes(len.as_slice().try_into()?);
+            let frame = self.read_wasm_memory(ptr, len as usize)?;
+            self.free_message(WasmBuffer {
+                ptr: ptr as u32,
+                len,
+                allocated: true,
+            })?;
+            parse_http_frame(&frame)
+        } else {
+            Ok(plain(self.unbox_wasm_bytes(ptr)?))
+        }
+    }
+
+    /// Read bytes returned from WASM
+    ///
+    /// The length is found at memory location 1.
+    fn unbox_wasm_bytes(&self, ptr: usize) -> Result<Vec<u8>, failure::Error> {
+        let len = self.read_wasm_memory(1, 4)?;
+        let len = u32::from_le_bytes(len.as_slice().try_into()?);
+        self.read_wasm_memory(ptr, len as usize)
+    }
 
-        String::from_utf8_lossy(&bytes).to_string()
+    /// Copy `len` bytes out of the program's memory, starting at `offset`
+    ///
+    fn read_wasm_memory(&self, offset: usize, len: usize) -> Result<Vec<u8>, failure::Error> {
+        let view = self.instance.context().memory(0).view::<u8>();
+
+        match offset.checked_add(len) {
+            Some(end) if end <= view.len() => {
+                Ok(view[offset..end].iter().map(|cell| cell.get()).collect())
+            }
+            _ => {
+                error!("{} bytes at {} is outside of WASM memory", len, offset);
+                Err(RuntimeErrorKind::MemoryAccess.into())
+            }
+        }
+    }
+
+    /// Send an HTTP request to the handler, `func`, and return a pointer to its response
+    ///
+    /// The route goes first, followed immediately by the body. Programs without `__alloc` get the
+    /// request just past where they store the length of the response, at memory location 1.
+    fn send_http_request(
+        &mut self,
+        func: &str,
+        route: &str,
+        body: &[u8],
+    ) -> Result<usize, failure::Error> {
+        let buffer = self.write_message(5, &[route.as_bytes(), body].concat())?;
+
+        let values = self.call_wasm_func(
+            func,
+            Some(&[
+                Value::I32(buffer.ptr as i32),
+                Value::I32(route.len() as i32),
+                Value::I32((buffer.ptr as usize + route.len()) as i32),
+                Value::I32(body.len() as i32),
+            ]),
+        )?;
+        self.free_message(buffer)?;
+
+        match values.first() {
+            Some(Value::I32(ptr)) => Ok(*ptr as u32 as usize),
+            _ => Err(RuntimeErrorKind::IofsInvocation.into()),
+        }
+    }
+
+    /// Send a file or directory event to the handler, `func`
+    ///
+    fn send_payload(
+        &mut self,
+        func: &str,
+        payload: &IofsMessagePayload,
+    ) -> Result<(), failure::Error> {
+        let payload: MessagePayload = payload.into();
+        let json_str = serde_json::to_string(&payload)?;
+        let buffer = self.write_message(0, json_str.as_bytes())?;
+
+        self.call_wasm_func(
+            func,
+            Some(&[Value::I32(buffer.ptr as i32), Value::I32(buffer.len as i32)]),
+        )?;
+        self.free_message(buffer)
     }
 
     pub(crate) fn send_shutdown(&mut self) -> Result<(), failure::Error> {
@@ -140,268 +474,412 @@ impl<'a> WasmMessageSender<'a> {
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_file_create");
-
-        self.write_wasm_memory(0, &json_str);
-
-        self.call_wasm_func(
-            "__handle_file_create",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+        self.send_payload("__handle_file_create", payload)
     }
 
     pub(crate) fn send_dir_create(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_dir_create");
-
-        self.write_wasm_memory(0, &json_str);
-
-        self.call_wasm_func(
-            "__handle_dir_create",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+        self.send_payload("__handle_dir_create", payload)
     }
 
     pub(crate) fn send_file_delete(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_file_delete");
-
-        self.write_wasm_memory(0, &json_str);
-
-        self.call_wasm_func(
-            "__handle_file_delete",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+        self.send_payload("__handle_file_delete", payload)
     }
 
     pub(crate) fn send_dir_delete(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_dir_delete");
-
-        self.write_wasm_memory(0, &json_str);
-
-        self.call_wasm_func(
-            "__handle_dir_delete",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+        self.send_payload("__handle_dir_delete", payload)
     }
 
     pub(crate) fn send_file_open(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_file_open");
-
-        self.write_wasm_memory(0, &json_str);
-
-        self.call_wasm_func(
-            "__handle_file_open",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+        self.send_payload("__handle_file_open", payload)
     }
 
     pub(crate) fn send_file_close(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str = serde_json::to_string(&payload)
-            .expect("unable to serialize JSON in send_http_post send_file_close");
+        self.send_payload("__handle_file_close", payload)
+    }
 
-        self.write_wasm_memory(0, &json_str);
+    pub(crate) fn send_file_write(
+        &mut self,
+        payload: &IofsMessagePayload,
+    ) -> Result<(), failure::Error> {
+        self.send_payload("__handle_file_write", payload)
+    }
 
-        self.call_wasm_func(
-            "__handle_file_close",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
-        )?;
-        Ok(())
+    pub(crate) fn send_file_read(
+        &mut self,
+        payload: &IofsMessagePayload,
+    ) -> Result<(), failure::Error> {
+        self.send_payload("__handle_file_read", payload)
     }
 
-    pub(crate) fn send_file_write(
+    pub(crate) fn send_file_rename(
         &mut self,
         payload: &IofsMessagePayload,
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_file_write");
+        self.send_payload("__handle_file_rename", payload)
+    }
 
-        self.write_wasm_memory(0, &json_str);
+    pub(crate) fn send_dir_rename(
+        &mut self,
+        payload: &IofsMessagePayload,
+    ) -> Result<(), failure::Error> {
+        self.send_payload("__handle_dir_rename", payload)
+    }
+
+    pub(crate) fn send_program_message(
+        &mut self,
+        from: &Path,
+        payload: &[u8],
+    ) -> Result<(), failure::Error> {
+        let from = from.to_str().expect("program path is not valid UTF-8");
+
+        // The sender's path goes first, followed immediately by the payload.
+        let buffer = self.write_message(0, &[from.as_bytes(), payload].concat())?;
 
         self.call_wasm_func(
-            "__handle_file_write",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
+            "__handle_program_message",
+            Some(&[
+                Value::I32(buffer.ptr as i32),
+                Value::I32(from.len() as i32),
+                Value::I32((buffer.ptr as usize + from.len()) as i32),
+                Value::I32(payload.len() as i32),
+            ]),
         )?;
-        Ok(())
+        self.free_message(buffer)
     }
 
-    pub(crate) fn send_file_read(
+    pub(crate) fn send_program_event(
         &mut self,
-        payload: &IofsMessagePayload,
+        name: &str,
+        payload: &[u8],
     ) -> Result<(), failure::Error> {
-        let payload: MessagePayload = payload.into();
-        let json_str =
-            serde_json::to_string(&payload).expect("unable to serialize JSON in send_file_read");
-
-        self.write_wasm_memory(0, &json_str);
+        // The event's name goes first, followed immediately by the payload.
+        let buffer = self.write_message(0, &[name.as_bytes(), payload].concat())?;
 
         self.call_wasm_func(
-            "__handle_file_read",
-            Some(&[Value::I32(0), Value::I32(json_str.len() as i32)]),
+            "__handle_program_event",
+            Some(&[
+                Value::I32(buffer.ptr as i32),
+                Value::I32(name.len() as i32),
+                Value::I32((buffer.ptr as usize + name.len()) as i32),
+                Value::I32(payload.len() as i32),
+            ]),
         )?;
-        Ok(())
+        self.free_message(buffer)
     }
 
+    /// Send a GET
+    ///
+    /// The handler is passed an `HttpRequest`, as JSON, with the path and query parameters.
     pub(crate) fn send_http_get(
         &mut self,
         msg: &IofsNetworkGetValue,
-    ) -> Result<String, failure::Error> {
-        self.write_wasm_memory(5, &msg.route());
-        match self.call_wasm_func(
-            "__handle_http_get",
-            Some(&[Value::I32(5), Value::I32(msg.route().len() as i32)]),
-        ) {
-            Ok(value) => {
-                if let Value::I32(v) = value[0] {
-                    Ok(self.unbox_wasm_string(v as usize))
-                } else {
-                    Err(RuntimeErrorKind::IofsInvocation.into())
-                }
-            }
-            Err(e) => Err(e),
-        }
+    ) -> Result<HttpResponse, failure::Error> {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            path: msg.path().to_owned(),
+            query: msg.query().clone(),
+        };
+        let json_str = serde_json::to_string(&request)?;
+        let ptr = self.send_http_request("__handle_http_get", msg.route(), json_str.as_bytes())?;
+        self.unbox_http_response(ptr, html_response)
     }
 
     pub(crate) fn send_http_post(
         &mut self,
         msg: &IofsNetworkJsonValue,
-    ) -> Result<String, failure::Error> {
-        let json_str =
-            serde_json::to_string(msg.json()).expect("unable to serialize JSON in send_http_post");
-        self.write_wasm_memory(5, &msg.route());
-        self.write_wasm_memory(5 + msg.route().len(), &json_str);
-        match self.call_wasm_func(
-            "__handle_http_post",
-            Some(&[
-                Value::I32(5),
-                Value::I32(msg.route().len() as i32),
-                Value::I32(5 + msg.route().len() as i32),
-                Value::I32(json_str.len() as i32),
-            ]),
-        ) {
-            Ok(value) => {
-                if let Value::I32(v) = value[0] {
-                    Ok(self.unbox_wasm_string(v as usize))
-                } else {
-                    Err(RuntimeErrorKind::IofsInvocation.into())
-                }
-            }
-            Err(e) => Err(e),
-        }
+    ) -> Result<HttpResponse, failure::Error> {
+        let json_str = serde_json::to_string(msg.json())?;
+        let ptr = self.send_http_request("__handle_http_post", msg.route(), json_str.as_bytes())?;
+        self.unbox_http_response(ptr, html_response)
+    }
+
+    /// Send a binary POST body
+    ///
+    /// The program's handler gets the bytes as is. Unless it says otherwise, its response is
+    /// `application/octet-stream`.
+    pub(crate) fn send_http_post_bytes(
+        &mut self,
+        msg: &IofsNetworkBytesValue,
+    ) -> Result<HttpResponse, failure::Error> {
+        let ptr = self.send_http_request("__handle_http_post_bytes", msg.route(), msg.bytes())?;
+        self.unbox_http_response(ptr, |body| {
+            HttpResponse::new(200)
+                .with_header("content-type", "application/octet-stream")
+                .with_body(body)
+        })
     }
 
     pub(crate) fn send_http_put(
         &mut self,
         msg: &IofsNetworkJsonValue,
-    ) -> Result<String, failure::Error> {
-        let json_str =
-            serde_json::to_string(msg.json()).expect("unable to serialize JSON in send_http_put");
-        self.write_wasm_memory(5, &msg.route());
-        self.write_wasm_memory(5 + msg.route().len(), &json_str);
-        match self.call_wasm_func(
-            "__handle_http_put",
-            Some(&[
-                Value::I32(5),
-                Value::I32(msg.route().len() as i32),
-                Value::I32(5 + msg.route().len() as i32),
-                Value::I32(json_str.len() as i32),
-            ]),
-        ) {
-            Ok(value) => {
-                if let Value::I32(v) = value[0] {
-                    Ok(self.unbox_wasm_string(v as usize))
-                } else {
-                    Err(RuntimeErrorKind::IofsInvocation.into())
-                }
-            }
-            Err(e) => Err(e),
-        }
+    ) -> Result<HttpResponse, failure::Error> {
+        let json_str = serde_json::to_string(msg.json())?;
+        let ptr = self.send_http_request("__handle_http_put", msg.route(), json_str.as_bytes())?;
+        self.unbox_http_response(ptr, html_response)
     }
 
     pub(crate) fn send_http_patch(
         &mut self,
         msg: &IofsNetworkJsonValue,
-    ) -> Result<String, failure::Error> {
-        let json_str =
-            serde_json::to_string(msg.json()).expect("unable to serialize JSON in send_http_patch");
-        self.write_wasm_memory(5, &msg.route());
-        self.write_wasm_memory(5 + msg.route().len(), &json_str);
-        match self.call_wasm_func(
-            "__handle_http_patch",
-            Some(&[
-                Value::I32(5),
-                Value::I32(msg.route().len() as i32),
-                Value::I32(5 + msg.route().len() as i32),
-                Value::I32(json_str.len() as i32),
-            ]),
-        ) {
-            Ok(value) => {
-                if let Value::I32(v) = value[0] {
-                    Ok(self.unbox_wasm_string(v as usize))
-                } else {
-                    Err(RuntimeErrorKind::IofsInvocation.into())
-                }
-            }
-            Err(e) => Err(e),
-        }
+    ) -> Result<HttpResponse, failure::Error> {
+        let json_str = serde_json::to_string(msg.json())?;
+        let ptr =
+            self.send_http_request("__handle_http_patch", msg.route(), json_str.as_bytes())?;
+        self.unbox_http_response(ptr, html_response)
     }
 
     pub(crate) fn send_http_delete(
         &mut self,
         msg: &IofsNetworkJsonValue,
-    ) -> Result<String, failure::Error> {
-        let json_str = serde_json::to_string(msg.json())
-            .expect("unable to serialize JSON in send_http_delete");
-        self.write_wasm_memory(5, &msg.route());
-        self.write_wasm_memory(5 + msg.route().len(), &json_str);
-        match self.call_wasm_func(
-            "__handle_http_delete",
-            Some(&[
-                Value::I32(5),
-                Value::I32(msg.route().len() as i32),
-                Value::I32(5 + msg.route().len() as i32),
-                Value::I32(json_str.len() as i32),
-            ]),
-        ) {
-            Ok(value) => {
-                if let Value::I32(v) = value[0] {
-                    Ok(self.unbox_wasm_string(v as usize))
-                } else {
-                    Err(RuntimeErrorKind::IofsInvocation.into())
-                }
-            }
-            Err(e) => Err(e),
+    ) -> Result<HttpResponse, failure::Error> {
+        let json_str = serde_json::to_string(msg.json())?;
+        let ptr =
+            self.send_http_request("__handle_http_delete", msg.route(), json_str.as_bytes())?;
+        self.unbox_http_response(ptr, html_response)
+    }
+}
+
+/// Did the call fail because the program ran out of points?
+///
+#[cfg(target_arch = "x86_64")]
+fn exceeded_limit(error: &CallError) -> bool {
+    match error {
+        CallError::Runtime(RuntimeError::Error { data }) => {
+            data.downcast_ref::<ExecutionLimitExceededError>().is_some()
         }
+        _ => false,
+    }
+}
+
+/// Programs aren't metered off x86_64, so they never run out of points
+///
+#[cfg(not(target_arch = "x86_64"))]
+fn exceeded_limit(_error: &CallError) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        wasmer_runtime::{imports, instantiate},
+    };
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // A hand assembled WASM program that exports `__init` and `__init_with_config`. The latter
+    // stores the config pointer and length at memory locations 1024 and 1028, respectively.
+    const CONFIG_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x0d, 0x02, // type section
+        0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, // (i32, i32, i32, i32) -> ()
+        0x60, 0x02, 0x7f, 0x7f, 0x00, // (i32, i32) -> ()
+        0x03, 0x03, 0x02, 0x00, 0x01, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x28, 0x03, // export section
+        0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, // "memory"
+        0x12, 0x5f, 0x5f, 0x69, 0x6e, 0x69, 0x74, 0x5f, 0x77, 0x69, 0x74, 0x68, 0x5f, 0x63, 0x6f,
+        0x6e, 0x66, 0x69, 0x67, 0x00, 0x00, // "__init_with_config"
+        0x06, 0x5f, 0x5f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x01, // "__init"
+        0x0a, 0x17, 0x02, // code section
+        0x12, 0x00, // __init_with_config
+        0x41, 0x80, 0x08, 0x20, 0x02, 0x36, 0x02, 0x00, // store config_ptr @ 1024
+        0x41, 0x84, 0x08, 0x20, 0x03, 0x36, 0x02, 0x00, // store config_len @ 1028
+        0x0b, // end
+        0x02, 0x00, 0x0b, // __init
+    ];
+
+    // A hand assembled WASM program like `CONFIG_PROGRAM`, that also exports `__alloc`, a bump
+    // allocator starting at 4096, and `__dealloc`, which counts the buffers it's given at 1032.
+    const ALLOC_PROGRAM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic & version
+        0x01, 0x12, 0x03, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x00,
+        0x60, 0x01, 0x7f, 0x01, 0x7f, // type section
+        0x03, 0x05, 0x04, 0x00, 0x01, 0x02, 0x01, // function section
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory section
+        0x07, 0x3e, 0x05, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x12, 0x5f, 0x5f,
+        0x69, 0x6e, 0x69, 0x74, 0x5f, 0x77, 0x69, 0x74, 0x68, 0x5f, 0x63, 0x6f, 0x6e, 0x66, 0x69,
+        0x67, 0x00, 0x00, 0x06, 0x5f, 0x5f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x01, 0x07, 0x5f, 0x5f,
+        0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x00, 0x02, 0x09, 0x5f, 0x5f, 0x64, 0x65, 0x61, 0x6c, 0x6c,
+        0x6f, 0x63, 0x00, 0x03, // export section
+        0x0a, 0x43, 0x04, 0x12, 0x00, 0x41, 0x80, 0x08, 0x20, 0x02, 0x36, 0x02, 0x00, 0x41, 0x84,
+        0x08, 0x20, 0x03, 0x36, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b, 0x19, 0x01, 0x01, 0x7f, 0x41,
+        0x8c, 0x08, 0x28, 0x02, 0x00, 0x21, 0x01, 0x41, 0x8c, 0x08, 0x20, 0x01, 0x20, 0x00, 0x6a,
+        0x36, 0x02, 0x00, 0x20, 0x01, 0x0b, 0x11, 0x00, 0x41, 0x88, 0x08, 0x41, 0x88, 0x08, 0x28,
+        0x02, 0x00, 0x41, 0x01, 0x6a, 0x36, 0x02, 0x00, 0x0b, // code section
+        0x0b, 0x0b, 0x01, 0x00, 0x41, 0x8c, 0x08, 0x0b, 0x04, 0x00, 0x10, 0x00,
+        0x00, // data section
+    ];
+
+    #[test]
+    fn init_with_config() {
+        init();
+
+        let config = r#"{"watch":"/foo/bar"}"#.to_string();
+        let mut instance = instantiate(CONFIG_PROGRAM, &imports! {}).unwrap();
+        WasmMessageSender::new(
+            &mut instance,
+            UfsUuid::new_root_fs("test"),
+            Some(config.clone()),
+        )
+        .unwrap();
+
+        let memory = instance.context().memory(0);
+        let bytes: Vec<u8> = memory.view::<u8>()[1024..1032]
+            .iter()
+            .map(|cell| cell.get())
+            .collect();
+        let ptr = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(config.len(), len);
+
+        let received: Vec<u8> = memory.view::<u8>()[ptr..ptr + len]
+            .iter()
+            .map(|cell| cell.get())
+            .collect();
+        assert_eq!(config.as_bytes(), received.as_slice());
+    }
+
+    #[test]
+    fn init_with_allocated_config() {
+        init();
+
+        let config = r#"{"watch":"/foo/bar"}"#.to_string();
+        let mut instance = instantiate(ALLOC_PROGRAM, &imports! {}).unwrap();
+        WasmMessageSender::new(
+            &mut instance,
+            UfsUuid::new_root_fs("test"),
+            Some(config.clone()),
+        )
+        .unwrap();
+
+        let memory = instance.context().memory(0);
+        let word = |offset: usize| {
+            let bytes: Vec<u8> = memory.view::<u8>()[offset..offset + 4]
+                .iter()
+                .map(|cell| cell.get())
+                .collect();
+            u32::from_le_bytes(bytes.as_slice().try_into().unwrap()) as usize
+        };
+
+        // The config was put in an allocated buffer, after the one for the root id.
+        let ptr = word(1024);
+        let len = word(1028);
+        assert!(ptr > 4096);
+        assert_eq!(config.len(), len);
+
+        let received: Vec<u8> = memory.view::<u8>()[ptr..ptr + len]
+            .iter()
+            .map(|cell| cell.get())
+            .collect();
+        assert_eq!(config.as_bytes(), received.as_slice());
+
+        // Both buffers were handed back.
+        assert_eq!(2, word(1032));
+    }
+
+    #[test]
+    fn http_frame() {
+        init();
+
+        let headers = br#"[["content-type","application/json"]]"#;
+        let body = br#"{"error":"not found"}"#;
+        let len = (HTTP_FRAME_HEADER_LEN + headers.len() + body.len()) as u32;
+        let frame = [
+            &len.to_le_bytes()[..],
+            &404u16.to_le_bytes(),
+            &(headers.len() as u32).to_le_bytes(),
+            headers,
+            body,
+        ]
+        .concat();
+
+        let response = parse_http_frame(&frame).unwrap();
+        assert_eq!(404, response.status);
+        assert_eq!(
+            vec![("content-type".to_string(), "application/json".to_string())],
+            response.headers
+        );
+        assert_eq!(&body[..], response.body.as_slice());
+
+        // Headers that run past the end of the frame
+        let e = parse_http_frame(&frame[..HTTP_FRAME_HEADER_LEN + 4])
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&RuntimeErrorKind::IofsInvocation),
+            e.downcast_ref::<RuntimeErrorKind>()
+        );
+
+        // Too short to be a frame at all
+        assert!(parse_http_frame(&frame[..6]).is_err());
+    }
+
+    #[test]
+    fn oversized_config() {
+        init();
+
+        // Without `__alloc` there's only so much room.
+        let config = "x".repeat(FIXED_MESSAGE_LIMIT);
+        let mut instance = instantiate(CONFIG_PROGRAM, &imports! {}).unwrap();
+        let e = WasmMessageSender::new(&mut instance, UfsUuid::new_root_fs("test"), Some(config))
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&RuntimeErrorKind::MemoryAccess),
+            e.downcast_ref::<RuntimeErrorKind>()
+        );
+    }
+
+    #[test]
+    fn init_without_config() {
+        init();
+
+        let mut instance = instantiate(CONFIG_PROGRAM, &imports! {}).unwrap();
+        WasmMessageSender::new(&mut instance, UfsUuid::new_root_fs("test"), None).unwrap();
+
+        // `__init` was called, so nothing was written by `__init_with_config`.
+        let memory = instance.context().memory(0);
+        assert!(memory.view::<u8>()[1024..1032]
+            .iter()
+            .all(|cell| cell.get() == 0));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn execution_limit() {
+        use {
+            crate::wasm::{metered_compiler, LOOP_PROGRAM},
+            wasmer_runtime::compile_with,
+        };
+
+        init();
+
+        let module = compile_with(LOOP_PROGRAM, &metered_compiler(100_000)).unwrap();
+        let mut instance = module.instantiate(&imports! {}).unwrap();
+        let e = WasmMessageSender::new(&mut instance, UfsUuid::new_root_fs("test"), None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&RuntimeErrorKind::ExecutionLimitExceeded),
+            e.downcast_ref::<RuntimeErrorKind>()
+        );
     }
 }
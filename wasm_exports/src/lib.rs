@@ -7,10 +7,11 @@
 use {
     lazy_static::lazy_static,
     mut_static::MutStatic,
+    serde::de::DeserializeOwned,
     serde_derive::{Deserialize, Serialize},
     std::{
         path::PathBuf,
-        {collections::HashMap, convert::TryInto, slice, str},
+        {collections::HashMap, convert::TryInto, mem, slice, str},
     },
     uuid::Uuid,
 };
@@ -24,11 +25,23 @@ lazy_static! {
     #[doc(hidden)]
     static ref POST_HANDLERS: MutStatic<PostCallbacks> = { MutStatic::from(PostCallbacks::new()) };
     #[doc(hidden)]
+    static ref POST_BYTES_HANDLERS: MutStatic<PostBytesCallbacks> =
+        { MutStatic::from(PostBytesCallbacks::new()) };
+    #[doc(hidden)]
     static ref PUT_HANDLERS: MutStatic<PutCallbacks> = { MutStatic::from(PutCallbacks::new()) };
     #[doc(hidden)]
     static ref PATCH_HANDLERS: MutStatic<PatchCallbacks> = { MutStatic::from(PatchCallbacks::new()) };
     #[doc(hidden)]
     static ref DELETE_HANDLERS: MutStatic<DeleteCallbacks> = { MutStatic::from(DeleteCallbacks::new()) };
+    #[doc(hidden)]
+    static ref PROGRAM_CONFIG: MutStatic<Option<String>> = { MutStatic::from(None) };
+    #[doc(hidden)]
+    static ref PROGRAM_MESSAGE_HANDLER: MutStatic<Option<extern "C" fn(&str, &[u8])>> =
+        { MutStatic::from(None) };
+    #[doc(hidden)]
+    static ref EVENT_HANDLERS: MutStatic<EventCallbacks> = { MutStatic::from(EventCallbacks::new()) };
+    #[doc(hidden)]
+    static ref PENDING_RESPONSE: MutStatic<Option<HttpResponse>> = { MutStatic::from(None) };
 }
 
 /// These are exports that are available to be called by the WASM program.
@@ -42,6 +55,10 @@ extern "C" {
     #[doc(hidden)]
     pub fn __register_for_callback(message: u32);
     #[doc(hidden)]
+    pub fn __register_for_scoped_callback(message: u32, id_ptr: u32);
+    #[doc(hidden)]
+    pub fn __register_for_event(name_ptr: u32);
+    #[doc(hidden)]
     pub fn __register_get_handler(route: u32);
     #[doc(hidden)]
     pub fn __register_post_handler(route: u32);
@@ -56,6 +73,8 @@ extern "C" {
     #[doc(hidden)]
     pub fn __open_file(id_ptr: u32) -> u64;
     #[doc(hidden)]
+    pub fn __open_path(path_ptr: u32) -> i32;
+    #[doc(hidden)]
     pub fn __close_file(id_ptr: u32, handle: u64);
     #[doc(hidden)]
     pub fn __read_file(id_ptr: u32, handle: u64, offset: u32, data_ptr: u32, data_len: u32) -> u32;
@@ -64,9 +83,27 @@ extern "C" {
     #[doc(hidden)]
     pub fn __create_file(id_ptr: u32, name_ptr: u32) -> i32;
     #[doc(hidden)]
+    pub fn __copy_file(id_ptr: u32, parent_id_ptr: u32, name_ptr: u32) -> i32;
+    #[doc(hidden)]
     pub fn __create_directory(id_ptr: u32, name_ptr: u32) -> i32;
     #[doc(hidden)]
     pub fn __open_directory(id_ptr: u32, name_ptr: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __read_dir(id_ptr: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __stat(id_ptr: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __statfs() -> i32;
+    #[doc(hidden)]
+    pub fn __chmod(id_ptr: u32, mode: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __kv_get(key_ptr: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __kv_put(key_ptr: u32, value_ptr: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __send_message(target_ptr: u32, payload_ptr: u32, payload_len: u32) -> i32;
+    #[doc(hidden)]
+    pub fn __publish_event(name_ptr: u32, payload_ptr: u32) -> i32;
 }
 
 /// Wasm Program init function declaration
@@ -106,6 +143,19 @@ pub enum WasmMessage {
     FileRead,
     /// A file is being written to.
     FileWrite,
+    /// A file has been moved, and/or renamed.
+    ///
+    /// The payload contains the file's new path.
+    FileRename,
+    /// A directory has been moved, and/or renamed.
+    ///
+    /// The payload contains the directory's new path.
+    DirRename,
+    /// Another program sent this program a message.
+    ///
+    /// Handlers for this message are registered with `register_message_handler`, and not
+    /// `register_callback`.
+    ProgramMessage,
 }
 
 /// Local storage for mapping file system events to message handlers.
@@ -131,7 +181,7 @@ impl MessageHandlers {
 ///
 #[doc(hidden)]
 struct GetCallbacks {
-    callbacks: HashMap<String, extern "C" fn() -> String>,
+    callbacks: HashMap<String, extern "C" fn(&str) -> String>,
 }
 
 impl GetCallbacks {
@@ -141,7 +191,7 @@ impl GetCallbacks {
         }
     }
 
-    fn lookup(&self, route: &String) -> Option<&extern "C" fn() -> String> {
+    fn lookup(&self, route: &String) -> Option<&extern "C" fn(&str) -> String> {
         self.callbacks.get(route)
     }
 }
@@ -165,6 +215,25 @@ impl PostCallbacks {
     }
 }
 
+/// Local storage for mapping binary HTTP POST routes to callbacks.
+///
+#[doc(hidden)]
+struct PostBytesCallbacks {
+    callbacks: HashMap<String, extern "C" fn(&[u8]) -> Vec<u8>>,
+}
+
+impl PostBytesCallbacks {
+    fn new() -> Self {
+        PostBytesCallbacks {
+            callbacks: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, route: &String) -> Option<&extern "C" fn(&[u8]) -> Vec<u8>> {
+        self.callbacks.get(route)
+    }
+}
+
 /// Local storage for mapping HTTP PUT routes to callbacks.
 ///
 #[doc(hidden)]
@@ -222,6 +291,25 @@ impl DeleteCallbacks {
     }
 }
 
+/// Local storage for mapping the names of events published by other programs to callbacks.
+///
+#[doc(hidden)]
+struct EventCallbacks {
+    callbacks: HashMap<String, extern "C" fn(&str, &[u8])>,
+}
+
+impl EventCallbacks {
+    fn new() -> Self {
+        EventCallbacks {
+            callbacks: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, name: &String) -> Option<&extern "C" fn(&str, &[u8])> {
+        self.callbacks.get(name)
+    }
+}
+
 /// Returned from the `create_file` function
 ///
 /// This structure must be used in subsequent file operations on the opened file.
@@ -233,6 +321,121 @@ pub struct FileHandle {
     pub id: Uuid,
 }
 
+/// Returned from the `read_directory` function
+///
+/// One of these exists for each entry in the directory.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DirEntry {
+    /// The name of the entry.
+    pub name: String,
+    /// The UUID of the entry.
+    pub id: Uuid,
+    /// Whether or not the entry is a directory.
+    pub is_dir: bool,
+}
+
+/// Returned from the `stat` function
+///
+/// Times are in seconds since the Unix epoch.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileStat {
+    /// The size of the latest version of the file, in bytes.
+    pub size: u64,
+    /// The unix permissions of the file.
+    pub perms: u16,
+    /// The number of versions of the file that are kept.
+    pub versions: usize,
+    /// When the file was created.
+    pub created: i64,
+    /// When the file was last modified.
+    pub modified: i64,
+}
+
+/// Returned from the `statfs` function
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FsStats {
+    /// The number of blocks in the file system.
+    pub block_count: u64,
+    /// The number of blocks that may yet be written with file data.
+    pub free_blocks: u64,
+    /// The size of each block, in bytes.
+    pub block_size: u32,
+    /// The number of files that are open.
+    pub open_files: usize,
+}
+
+/// An HTTP request
+///
+/// This is the JSON payload passed to handlers registered with `register_get_route`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HttpRequest {
+    /// The HTTP method, e.g., "GET".
+    pub method: String,
+    /// The path following the route, e.g., "bar/baz" for `/wasm/foo/bar/baz`.
+    pub path: String,
+    /// The decoded query parameters. The authentication token is not included.
+    pub query: HashMap<String, String>,
+}
+
+/// An HTTP response
+///
+/// HTTP handlers that return a plain string get a 200. Handlers that need to return some other
+/// status, or set headers, build one of these and return it with `into()`.
+///
+/// ```ignore
+/// HttpResponse::new(404).with_body("no such thing").into()
+/// ```
+///
+/// The conversion sets the response aside, to be sent once the handler returns, and yields an
+/// empty body. It's meant to be the handler's result, and not something to build on.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HttpResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The headers, as name/value pairs, e.g., `("content-type", "application/json")`.
+    pub headers: Vec<(String, String)>,
+    /// The response body.
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// A response with the given status, no headers, and an empty body
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    /// Add a header to the response
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the body of the response
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+impl From<HttpResponse> for String {
+    fn from(response: HttpResponse) -> Self {
+        PENDING_RESPONSE.write().unwrap().replace(response);
+        String::new()
+    }
+}
+
+impl From<HttpResponse> for Vec<u8> {
+    fn from(response: HttpResponse) -> Self {
+        PENDING_RESPONSE.write().unwrap().replace(response);
+        vec![]
+    }
+}
+
 /// File System Function Call Return Type
 ///
 /// We wrap the return types in a MessagePayload to simplify handler callback registration.
@@ -252,11 +455,37 @@ pub struct MessagePayload {
 
 /// Print a string to the IOFS output console
 ///
+/// The string is also appended to the program's log file, e.g., `/.wasm/foo.log` for
+/// `/.wasm/foo.wasm`. Output is buffered, and written to the log once the current handler
+/// returns.
 pub fn print(msg: &str) {
     let msg = Box::into_raw(Box::new(msg));
     unsafe { __print(msg as u32) };
 }
 
+/// Fetch the program's configuration
+///
+/// A program may be configured by placing a file next to it in the `.wasm` directory, with the
+/// same name, and a `.config` extension. The contents are expected to be JSON, and are deserialized
+/// into `T`. `None` is returned if there is no configuration, or if it fails to deserialize.
+///
+/// The configuration is available from within `init`.
+pub fn get_config<T: DeserializeOwned>() -> Option<T> {
+    let config = PROGRAM_CONFIG.read().unwrap();
+    match &*config {
+        Some(json) => serde_json::from_str(json).ok(),
+        None => None,
+    }
+}
+
+/// Fetch the program's configuration as a raw string
+///
+/// This is for those configurations that aren't JSON.
+pub fn get_config_string() -> Option<String> {
+    let config = PROGRAM_CONFIG.read().unwrap();
+    (*config).clone()
+}
+
 /// Register a file system message callback
 ///
 pub fn register_callback(msg: WasmMessage, func: extern "C" fn(Option<MessagePayload>)) {
@@ -267,11 +496,54 @@ pub fn register_callback(msg: WasmMessage, func: extern "C" fn(Option<MessagePay
     unsafe { __register_for_callback(msg as u32) };
 }
 
+/// Register a file system message callback for events beneath a directory
+///
+/// The callback is only invoked for events that happen to the directory, or to something within
+/// it, no matter how deeply nested. Registering again for the same message replaces the directory.
+pub fn register_scoped_callback(
+    msg: WasmMessage,
+    dir_id: &Uuid,
+    func: extern "C" fn(Option<MessagePayload>),
+) {
+    let mut lookup = CALLBACK_HANDLERS.write().unwrap();
+    lookup.callbacks.entry(msg.clone()).or_insert(func);
+
+    let json_str = serde_json::to_string(dir_id).unwrap();
+    let json_box = Box::into_raw(Box::new(json_str.as_str()));
+    let msg = Box::into_raw(Box::new(msg));
+    unsafe { __register_for_scoped_callback(msg as u32, json_box as u32) };
+}
+
+/// Register a handler for messages sent from other programs
+///
+/// The handler is passed the path of the sending program, and the bytes that it sent.
+pub fn register_message_handler(func: extern "C" fn(&str, &[u8])) {
+    PROGRAM_MESSAGE_HANDLER.write().unwrap().replace(func);
+
+    let msg = Box::into_raw(Box::new(WasmMessage::ProgramMessage));
+    unsafe { __register_for_callback(msg as u32) };
+}
+
+/// Register a handler for an event published by other programs
+///
+/// Any number of programs may register for the same event, e.g., `thumbnail-ready`, and each of
+/// them is passed the name of the event, and the bytes that were published with it. A program
+/// isn't passed the events that it publishes itself. Registering the same name again replaces the
+/// handler.
+pub fn register_event_handler<S: AsRef<str>>(name: S, func: extern "C" fn(&str, &[u8])) {
+    let mut lookup = EVENT_HANDLERS.write().unwrap();
+    lookup.callbacks.insert(name.as_ref().to_owned(), func);
+
+    let name = Box::into_raw(Box::new(name.as_ref()));
+    unsafe { __register_for_event(name as u32) };
+}
+
 /// Register an HTTP GET route
 ///
 /// HTTP GET requests sent to http://hostname/wasm/<route> will be routed to this function. The
-/// <route> is a single string, and not a path.
-pub fn register_get_route<S: AsRef<str>>(route: S, func: extern "C" fn() -> String) {
+/// <route> is a single string, and not a path. Anything following the route, along with the query
+/// parameters, is passed to the function as an `HttpRequest`, serialized to JSON.
+pub fn register_get_route<S: AsRef<str>>(route: S, func: extern "C" fn(&str) -> String) {
     let mut lookup = GET_HANDLERS.write().unwrap();
     lookup
         .callbacks
@@ -297,6 +569,23 @@ pub fn register_post_route<S: AsRef<str>>(route: S, func: extern "C" fn(&str) ->
     unsafe { __register_post_handler(route as u32) };
 }
 
+/// Register a binary HTTP POST route
+///
+/// HTTP POST requests sent to http://hostname/wasm/<route> with a content type of
+/// `application/octet-stream` will be routed to this function. The body is passed along as is, and
+/// the bytes returned are sent back as `application/octet-stream`. A route may have both a binary
+/// handler, and one registered with `register_post_route` for JSON requests.
+pub fn register_post_route_bytes<S: AsRef<str>>(route: S, func: extern "C" fn(&[u8]) -> Vec<u8>) {
+    let mut lookup = POST_BYTES_HANDLERS.write().unwrap();
+    lookup
+        .callbacks
+        .entry(route.as_ref().to_owned())
+        .or_insert(func);
+
+    let route = Box::into_raw(Box::new(route.as_ref()));
+    unsafe { __register_post_handler(route as u32) };
+}
+
 /// Register an HTTP PUT route
 ///
 /// HTTP PUT requests sent to http://hostname/wasm/<route> will be routed to this function. The
@@ -359,6 +648,30 @@ pub fn open_file(id: &Uuid) -> Option<FileHandle> {
     }
 }
 
+/// Open a file by path
+///
+/// The path is taken relative to the root of the file system, with or without a leading `/`.
+/// `None` is returned if there's no file at `path`, or if this program isn't allowed to open
+/// files.
+pub fn open_path(path: &str) -> Option<FileHandle> {
+    let path = Box::into_raw(Box::new(path));
+    let file_handle_ptr = unsafe { __open_path(path as u32) };
+
+    if file_handle_ptr != -1 {
+        // The JSON string is returned as a length at memory location 0, and the string's bytes
+        // located at memory location 8.
+        let len_buf = unsafe { slice::from_raw_parts(file_handle_ptr as *const u8, 8) };
+        let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+        let json_str = unbox_slice(file_handle_ptr + 8, len as _);
+        let payload: FileHandle = serde_json::from_slice(json_str).unwrap();
+
+        Some(payload)
+    } else {
+        None
+    }
+}
+
 /// Close an open file
 ///
 /// This function takes a FileHandle, returned by a previous call to open_file.
@@ -383,8 +696,8 @@ pub fn read_file(handle: &FileHandle, offset: u32, data: &[u8]) -> u32 {
 
 /// Write bytes to a file
 ///
-/// This function takes a FileHandle, returned by a previous call to open_file, or create_file, and
-/// a `&[u8]` buffer of bytes.
+/// This function takes a FileHandle, returned by a previous call to create_file, and a `&[u8]`
+/// buffer of bytes. Note that open_file returns a read-only handle, which may not be written.
 pub fn write_file(handle: &FileHandle, data: &[u8]) -> u32 {
     let json_str = serde_json::to_string(&handle.id).unwrap();
     let json_box = Box::into_raw(Box::new(json_str.as_str()));
@@ -418,6 +731,36 @@ pub fn create_file(parent_id: &Uuid, name: &str) -> Option<FileHandle> {
     }
 }
 
+/// Copy a file
+///
+/// The file identified by `id` is copied to `name`, under the directory identified by `parent_id`.
+/// The copy shares the original's blocks, so it's cheap, however large the file. Writing to one
+/// doesn't change the other. The ID of the copy is returned, or `None` if the copy couldn't be
+/// made, or if this program isn't allowed to both read and create files.
+pub fn copy_file(id: &Uuid, parent_id: &Uuid, name: &str) -> Option<Uuid> {
+    let json_str = serde_json::to_string(id).unwrap();
+    let json_box = Box::into_raw(Box::new(json_str.as_str()));
+    let parent_str = serde_json::to_string(parent_id).unwrap();
+    let parent_box = Box::into_raw(Box::new(parent_str.as_str()));
+
+    let name = Box::into_raw(Box::new(name));
+    let id_ptr = unsafe { __copy_file(json_box as u32, parent_box as u32, name as u32) };
+
+    if id_ptr != -1 {
+        // The JSON string is returned as a length at memory location 0, and the string's bytes
+        // located at memory location 8.
+        let len_buf = unsafe { slice::from_raw_parts(id_ptr as *const u8, 8) };
+        let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+        let json_str = unbox_slice(id_ptr + 8, len as _);
+        let id: Uuid = serde_json::from_slice(json_str).unwrap();
+
+        Some(id)
+    } else {
+        None
+    }
+}
+
 /// Create a new directory
 ///
 /// This function takes the `UfsUuid` of a directory, and a name. A new directory will be created
@@ -472,6 +815,141 @@ pub fn open_directory(parent_id: &Uuid, name: &str) -> Option<Uuid> {
     }
 }
 
+/// Read a directory
+///
+/// This function takes the `UfsUuid` of a directory, and returns the entries contained within it.
+/// The `Vec` is empty if the directory doesn't exist, or if this program isn't allowed to read
+/// directories.
+pub fn read_directory(id: &Uuid) -> Vec<DirEntry> {
+    let json_str = serde_json::to_string(id).unwrap();
+    let json_box = Box::into_raw(Box::new(json_str.as_str()));
+
+    let entries_ptr = unsafe { __read_dir(json_box as u32) };
+
+    if entries_ptr != -1 {
+        // The JSON string is returned as a length at memory location 0, and the string's bytes
+        // located at memory location 8.
+        let len_buf = unsafe { slice::from_raw_parts(entries_ptr as *const u8, 8) };
+        let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+        let json_str = unbox_slice(entries_ptr + 8, len as _);
+        let entries: Vec<(String, Uuid, bool)> = serde_json::from_slice(json_str).unwrap();
+
+        entries
+            .into_iter()
+            .map(|(name, id, is_dir)| DirEntry { name, id, is_dir })
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Fetch a file's metadata
+///
+/// This is a cheap way to learn about a file without reading it. `None` is returned if the file
+/// doesn't exist, or if this program isn't allowed to read file metadata.
+pub fn stat(id: &Uuid) -> Option<FileStat> {
+    let json_str = serde_json::to_string(id).unwrap();
+    let json_box = Box::into_raw(Box::new(json_str.as_str()));
+
+    let stat_ptr = unsafe { __stat(json_box as u32) };
+
+    if stat_ptr != -1 {
+        // The JSON string is returned as a length at memory location 0, and the string's bytes
+        // located at memory location 8.
+        let len_buf = unsafe { slice::from_raw_parts(stat_ptr as *const u8, 8) };
+        let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+        let json_str = unbox_slice(stat_ptr + 8, len as _);
+        Some(serde_json::from_slice(json_str).unwrap())
+    } else {
+        None
+    }
+}
+
+/// Fetch file system statistics
+///
+/// A housekeeping program might use these to prune old file versions when free space runs low.
+pub fn statfs() -> FsStats {
+    let stats_ptr = unsafe { __statfs() };
+
+    // The JSON string is returned as a length at memory location 0, and the string's bytes
+    // located at memory location 8.
+    let len_buf = unsafe { slice::from_raw_parts(stats_ptr as *const u8, 8) };
+    let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+    let json_str = unbox_slice(stats_ptr + 8, len as _);
+    serde_json::from_slice(json_str).unwrap()
+}
+
+/// Change the permissions of a file or directory
+///
+/// `mode` is a unix mode, e.g., `0o444` to make a file read-only. `false` is returned if there's
+/// no such file or directory, or if this program isn't allowed to change permissions.
+pub fn chmod(id: &Uuid, mode: u16) -> bool {
+    let json_str = serde_json::to_string(id).unwrap();
+    let json_box = Box::into_raw(Box::new(json_str.as_str()));
+    unsafe { __chmod(json_box as u32, mode as u32) == 0 }
+}
+
+/// Fetch a stored value
+///
+/// Values put with `kv_put` are kept with the file system, so they are still around the next time
+/// the program is started, even after the file system has been unmounted. `None` is returned if
+/// there's nothing stored under `key`, or if this program isn't allowed to use the store.
+pub fn kv_get(key: &str) -> Option<String> {
+    let key = Box::into_raw(Box::new(key));
+
+    let value_ptr = unsafe { __kv_get(key as u32) };
+
+    if value_ptr != -1 {
+        // The value is returned as a length at memory location 0, and the string's bytes located
+        // at memory location 8.
+        let len_buf = unsafe { slice::from_raw_parts(value_ptr as *const u8, 8) };
+        let len = u64::from_le_bytes(len_buf.try_into().unwrap());
+
+        let value = unbox_slice(value_ptr + 8, len as _);
+        Some(String::from_utf8_lossy(value).into_owned())
+    } else {
+        None
+    }
+}
+
+/// Store a value
+///
+/// The value replaces anything previously stored under `key`, and may be fetched with `kv_get`.
+/// Each program has a store of its own, of at most 64KiB, keys and values together. `false` is
+/// returned if this program isn't allowed to use the store, or if the value doesn't fit.
+pub fn kv_put(key: &str, value: &str) -> bool {
+    let key = Box::into_raw(Box::new(key));
+    let value = Box::into_raw(Box::new(value));
+    unsafe { __kv_put(key as u32, value as u32) == 0 }
+}
+
+/// Send a message to another program
+///
+/// The target is the path of the receiving program, e.g., `/.wasm/consumer.wasm`. The payload is
+/// delivered to the target's message handler, see `register_message_handler`. `false` is returned
+/// if this program is not allowed to send messages. Messages to programs that aren't running, or
+/// that don't handle messages, are dropped.
+pub fn send_message(target: &str, payload: &[u8]) -> bool {
+    let target = Box::into_raw(Box::new(target));
+    let ptr = payload.as_ptr();
+    let len = payload.len();
+    unsafe { __send_message(target as u32, ptr as _, len as _) == 0 }
+}
+
+/// Publish an event to the programs that handle it
+///
+/// The payload is delivered to every other program that registered a handler for the event, see
+/// `register_event_handler`. Publishing needs the same permission as `send_message`, and `false`
+/// is returned if this program doesn't have it. An event that nobody handles is dropped.
+pub fn publish_event(name: &str, payload: &[u8]) -> bool {
+    let name = Box::into_raw(Box::new(name));
+    let payload = Box::into_raw(Box::new(payload));
+    unsafe { __publish_event(name as u32, payload as u32) == 0 }
+}
+
 //
 // Helpers
 //
@@ -496,6 +974,26 @@ fn unbox_slice<'a>(ptr: i32, len: i32) -> &'a [u8] {
 // The following functions are called from Rust. They manipulate data coming across the WASM
 // boundary, and make things nicer for the person writing a WASM program.
 //
+/// Allocate a buffer for a message from the file system
+///
+/// The file system copies each message into a buffer allocated here, rather than at a fixed
+/// address, so that a message can't overwrite anything the program is using. The buffer is handed
+/// back to `__dealloc` once the message is handled.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __alloc(len: i32) -> i32 {
+    let mut buffer = Vec::<u8>::with_capacity(len as usize);
+    let ptr = buffer.as_mut_ptr();
+    mem::forget(buffer);
+    ptr as i32
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __dealloc(ptr: i32, len: i32) {
+    unsafe { drop(Vec::from_raw_parts(ptr as *mut u8, 0, len as usize)) };
+}
+
 #[doc(hidden)]
 #[no_mangle]
 pub extern "C" fn __init(ptr: i32, len: i32) {
@@ -504,6 +1002,19 @@ pub extern "C" fn __init(ptr: i32, len: i32) {
     unsafe { init(root_id) };
 }
 
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __init_with_config(
+    root_ptr: i32,
+    root_len: i32,
+    config_ptr: i32,
+    config_len: i32,
+) {
+    let config = unbox_string(config_ptr, config_len);
+    PROGRAM_CONFIG.write().unwrap().replace(config);
+    __init(root_ptr, root_len);
+}
+
 #[doc(hidden)]
 #[no_mangle]
 pub extern "C" fn __handle_shutdown() {
@@ -612,20 +1123,111 @@ pub extern "C" fn __handle_file_read(payload_ptr: i32, payload_len: i32) {
 
 #[doc(hidden)]
 #[no_mangle]
-pub extern "C" fn __handle_http_get(route_ptr: i32, route_len: i32) -> i32 {
+pub extern "C" fn __handle_file_rename(payload_ptr: i32, payload_len: i32) {
+    let lookup = CALLBACK_HANDLERS.read().unwrap();
+    if let Some(func) = lookup.lookup(&WasmMessage::FileRename) {
+        let json_str = unbox_slice(payload_ptr, payload_len);
+        let payload: MessagePayload = serde_json::from_slice(json_str).unwrap();
+        func(Some(payload));
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __handle_dir_rename(payload_ptr: i32, payload_len: i32) {
+    let lookup = CALLBACK_HANDLERS.read().unwrap();
+    if let Some(func) = lookup.lookup(&WasmMessage::DirRename) {
+        let json_str = unbox_slice(payload_ptr, payload_len);
+        let payload: MessagePayload = serde_json::from_slice(json_str).unwrap();
+        func(Some(payload));
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __handle_program_message(
+    from_ptr: i32,
+    from_len: i32,
+    payload_ptr: i32,
+    payload_len: i32,
+) {
+    let handler = PROGRAM_MESSAGE_HANDLER.read().unwrap();
+    if let Some(func) = &*handler {
+        let from = unbox_str(from_ptr, from_len);
+        let payload = unbox_slice(payload_ptr, payload_len);
+        func(from, payload);
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __handle_program_event(
+    name_ptr: i32,
+    name_len: i32,
+    payload_ptr: i32,
+    payload_len: i32,
+) {
+    let lookup = EVENT_HANDLERS.read().unwrap();
+    let name = unbox_string(name_ptr, name_len);
+    if let Some(func) = lookup.lookup(&name) {
+        let payload = unbox_slice(payload_ptr, payload_len);
+        func(&name, payload);
+    }
+}
+
+/// The response to an HTTP request, once its handler has returned `body`
+///
+/// If the handler returned an `HttpResponse`, that's the response. Otherwise `body` is sent with a
+/// 200, as `content_type`.
+fn handler_response(body: Vec<u8>, content_type: &str) -> HttpResponse {
+    PENDING_RESPONSE.write().unwrap().take().unwrap_or_else(|| {
+        HttpResponse::new(200)
+            .with_header("content-type", content_type)
+            .with_body(body)
+    })
+}
+
+/// Hand an HTTP response back to the file system
+///
+/// The response is framed in a buffer that the file system hands back to `__dealloc` once it's
+/// read. The frame is its own length, the status, the length of the headers, the headers as JSON,
+/// and then the body. The lengths are u32, and the status a u16, all little endian.
+fn frame_response(response: HttpResponse) -> i32 {
+    let headers = serde_json::to_vec(&response.headers).unwrap();
+
+    let len = 10 + headers.len() + response.body.len();
+    let mut frame = Vec::with_capacity(len);
+    frame.extend_from_slice(&(len as u32).to_le_bytes());
+    frame.extend_from_slice(&response.status.to_le_bytes());
+    frame.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&headers);
+    frame.extend_from_slice(&response.body);
+
+    // A boxed slice is exactly as large as it needs to be, as `__dealloc` expects.
+    Box::into_raw(frame.into_boxed_slice()) as *mut u8 as i32
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __handle_http_get(
+    route_ptr: i32,
+    route_len: i32,
+    json_ptr: i32,
+    json_len: i32,
+) -> i32 {
     let route = unbox_string(route_ptr, route_len);
 
     let lookup = GET_HANDLERS.read().unwrap();
-    let result = if let Some(func) = lookup.lookup(&route) {
-        func()
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
+        let slice = unbox_str(json_ptr, json_len);
+        frame_response(handler_response(
+            func(slice).into_bytes(),
+            "text/html; charset=utf-8",
+        ))
     } else {
-        "function not found in lookup table".to_string()
-    };
-    // Store the length of the string at the bottom of the stack
-    unsafe {
-        ::std::ptr::write(1 as _, result.len());
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
     }
-    result.as_ptr() as i32
 }
 
 #[doc(hidden)]
@@ -639,17 +1241,36 @@ pub extern "C" fn __handle_http_post(
     let route = unbox_string(route_ptr, route_len);
 
     let lookup = POST_HANDLERS.read().unwrap();
-    let result = if let Some(func) = lookup.lookup(&route) {
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
         let slice = unbox_str(json_ptr, json_len);
-        func(slice)
+        frame_response(handler_response(
+            func(slice).into_bytes(),
+            "text/html; charset=utf-8",
+        ))
+    } else {
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __handle_http_post_bytes(
+    route_ptr: i32,
+    route_len: i32,
+    body_ptr: i32,
+    body_len: i32,
+) -> i32 {
+    let route = unbox_string(route_ptr, route_len);
+
+    let lookup = POST_BYTES_HANDLERS.read().unwrap();
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
+        let slice = unbox_slice(body_ptr, body_len);
+        frame_response(handler_response(func(slice), "application/octet-stream"))
     } else {
-        "function not found in lookup table".to_string()
-    };
-    // Store the length of the string at the bottom of the stack
-    unsafe {
-        ::std::ptr::write(1 as _, result.len());
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
     }
-    result.as_ptr() as i32
 }
 
 #[doc(hidden)]
@@ -663,17 +1284,16 @@ pub extern "C" fn __handle_http_put(
     let route = unbox_string(route_ptr, route_len);
 
     let lookup = PUT_HANDLERS.read().unwrap();
-    let result = if let Some(func) = lookup.lookup(&route) {
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
         let slice = unbox_str(json_ptr, json_len);
-        func(slice)
+        frame_response(handler_response(
+            func(slice).into_bytes(),
+            "text/html; charset=utf-8",
+        ))
     } else {
-        "function not found in lookup table".to_string()
-    };
-    // Store the length of the string at the bottom of the stack
-    unsafe {
-        ::std::ptr::write(1 as _, result.len());
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
     }
-    result.as_ptr() as i32
 }
 
 #[doc(hidden)]
@@ -687,17 +1307,16 @@ pub extern "C" fn __handle_http_patch(
     let route = unbox_string(route_ptr, route_len);
 
     let lookup = PATCH_HANDLERS.read().unwrap();
-    let result = if let Some(func) = lookup.lookup(&route) {
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
         let slice = unbox_str(json_ptr, json_len);
-        func(slice)
+        frame_response(handler_response(
+            func(slice).into_bytes(),
+            "text/html; charset=utf-8",
+        ))
     } else {
-        "function not found in lookup table".to_string()
-    };
-    // Store the length of the string at the bottom of the stack
-    unsafe {
-        ::std::ptr::write(1 as _, result.len());
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
     }
-    result.as_ptr() as i32
 }
 
 #[doc(hidden)]
@@ -711,15 +1330,14 @@ pub extern "C" fn __handle_http_delete(
     let route = unbox_string(route_ptr, route_len);
 
     let lookup = DELETE_HANDLERS.read().unwrap();
-    let result = if let Some(func) = lookup.lookup(&route) {
+    if let Some(func) = lookup.lookup(&route) {
+        PENDING_RESPONSE.write().unwrap().take();
         let slice = unbox_str(json_ptr, json_len);
-        func(slice)
+        frame_response(handler_response(
+            func(slice).into_bytes(),
+            "text/html; charset=utf-8",
+        ))
     } else {
-        "function not found in lookup table".to_string()
-    };
-    // Store the length of the string at the bottom of the stack
-    unsafe {
-        ::std::ptr::write(1 as _, result.len());
+        frame_response(HttpResponse::new(404).with_body("no such endpoint"))
     }
-    result.as_ptr() as i32
 }
@@ -62,8 +62,8 @@ struct Fib {
 }
 
 #[no_mangle]
-pub extern "C" fn get() -> String {
-    print("get called");
+pub extern "C" fn get(json: &str) -> String {
+    print(&format!("get called with {:#?}", json));
     let fib = fib(42);
     let result = Fib {
         index: 42,
@@ -52,8 +52,10 @@ pub extern "C" fn handle_file_create(path: &str) {
             // print(&format!("created file {:?}", words_path));
             print(&format!("created file {:?}", dir));
             wc.handles.insert(dir_str.to_string(), h);
-            wc.word_hash
-                .insert(file_path.to_str().unwrap().to_string(), 0);
+            // Pick up where we left off, if we've counted this file before.
+            let path = file_path.to_str().unwrap().to_string();
+            let count = kv_get(&path).and_then(|c| c.parse().ok()).unwrap_or(0);
+            wc.word_hash.insert(path, count);
         }
     }
 }
@@ -121,6 +123,8 @@ pub extern "C" fn handle_file_write(path: &str, data: &[u8]) {
             .fold(0, |n, _| n + 1);
 
         *words = count;
+        // Remember the count, so that it survives the file system being unmounted.
+        kv_put(path, &count.to_string());
     }
 }
 